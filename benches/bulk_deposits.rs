@@ -0,0 +1,34 @@
+//! Deposits applied one at a time, as account tasks do outside of batched apply, against the
+//! same deposits applied in bulk. Run with `cargo bench --bench bulk_deposits`.
+
+use std::time::{Duration, Instant};
+
+use trp::message::Message;
+
+const DEPOSITS: u32 = 1_000_000;
+const BATCH: usize = 100;
+
+fn main() {
+    let deposits: Vec<Message> = (1..=DEPOSITS)
+        .map(|tx| Message::Deposit {
+            client: 42,
+            tx,
+            amount: 0.5,
+            timestamp: None,
+            effective_date: None,
+        })
+        .collect();
+    let time = |bulk: bool| -> Duration {
+        let started = Instant::now();
+        let available = trp::apply_deposits(&deposits, BATCH, bulk);
+        let elapsed = started.elapsed();
+        assert_eq!(available, 0.5 * DEPOSITS as f32);
+        elapsed
+    };
+
+    let (one, bulk) = (time(false), time(true));
+    println!(
+        "{DEPOSITS} deposits in batches of {BATCH}: one at a time {one:?}, in bulk {bulk:?}, {:.2}x",
+        one.as_secs_f64() / bulk.as_secs_f64()
+    );
+}
@@ -0,0 +1,24 @@
+//! Arbitrary records converted into messages, which must keep client and transaction ids, and
+//! timestamps.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use trp::{message::Message, parser::Record};
+
+fuzz_target!(|input: (String, u16, u32, Option<f32>, Option<u64>)| {
+    let (kind, client, tx, amount, timestamp) = input;
+    let record = Record {
+        kind,
+        client,
+        tx,
+        amount,
+        timestamp,
+        effective_date: None,
+        signature: None,
+    };
+    if let Ok(message) = Message::try_from(&record) {
+        assert_eq!(message.client_id(), client);
+        assert_eq!(message.transaction_id(), tx);
+        assert_eq!(message.timestamp(), timestamp);
+    }
+});
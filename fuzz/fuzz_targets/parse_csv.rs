@@ -0,0 +1,18 @@
+//! Arbitrary bytes read as csv input, the way `trp process` ingests a file.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use trp::{
+    format::{CsvSource, Source},
+    message::Message,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let mut source = CsvSource::new(data);
+    while let Some(record) = source.next_record() {
+        if let Ok(record) = record {
+            let _ = Message::try_from(&record);
+        }
+        assert!(source.position() <= data.len() as u64);
+    }
+});
@@ -0,0 +1,71 @@
+/* C ABI of the trp engine, built with the `ffi` feature, see src/ffi.rs. */
+
+#ifndef TRP_H
+#define TRP_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Kinds of transactions. */
+#define TRP_DEPOSIT 0
+#define TRP_WITHDRAWAL 1
+#define TRP_DISPUTE 2
+#define TRP_RESOLVE 3
+#define TRP_CHARGEBACK 4
+#define TRP_SETTLE 5
+
+/* Statuses returned by trp_engine_push. */
+#define TRP_OK 0
+#define TRP_INVALID (-1)
+#define TRP_INSUFFICIENT_FUNDS 1
+#define TRP_BELOW_MINIMUM 2
+#define TRP_OVER_LIMIT 3
+#define TRP_ACCOUNT_LOCKED 4
+#define TRP_NO_ACCOUNT 5
+#define TRP_CLIENT_MISMATCH 6
+
+typedef struct trp_engine trp_engine;
+
+typedef struct trp_transaction {
+    uint32_t kind;
+    uint16_t client;
+    uint32_t tx;
+    /* Ignored unless kind is TRP_DEPOSIT or TRP_WITHDRAWAL. */
+    float amount;
+    bool has_timestamp;
+    /* Milliseconds since unix epoch. */
+    uint64_t timestamp;
+    /* Only deposits can be value-dated. */
+    bool has_effective_date;
+    uint64_t effective_date;
+} trp_transaction;
+
+typedef struct trp_account {
+    uint16_t client;
+    float available;
+    float held;
+    float total;
+    bool locked;
+} trp_account;
+
+/* Engine without accounts, released with trp_engine_free. */
+trp_engine *trp_engine_new(void);
+
+/* Applies transaction to the account of its client, returns one of the statuses above. */
+int32_t trp_engine_push(trp_engine *engine, const trp_transaction *transaction);
+
+/* Writes up to len accounts to out, ordered by client, returns the number of accounts. */
+size_t trp_engine_snapshot(const trp_engine *engine, trp_account *out, size_t len);
+
+void trp_engine_free(trp_engine *engine);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
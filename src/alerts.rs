@@ -0,0 +1,60 @@
+//! Alerts of [`velocity`](crate::velocity) rules, written with `--alerts`.
+//!
+//! The file is csv, a row for every time a client breached a rule: the client, transaction
+//! which breached it, the rule, and withdrawals of the client in the window at that point. It
+//! is recreated by every run. Alerts of [`reserve`](crate::reserve) go to the same file, with
+//! no withdrawals.
+
+use serde::{Serialize, Serializer};
+use std::{fs::File, io::BufWriter, path::Path, sync::Mutex};
+
+use crate::log;
+
+static ALERTS: Mutex<Option<csv::Writer<BufWriter<File>>>> = Mutex::new(None);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Alert {
+    #[serde(serialize_with = "client")]
+    pub client: u16,
+    #[serde(serialize_with = "tx")]
+    pub tx: u32,
+    pub rule: &'static str,
+    /// Number of withdrawals in the window, for velocity rules.
+    pub withdrawals: Option<u64>,
+    /// Amount withdrawn in the window, for velocity rules.
+    pub withdrawn: Option<f32>,
+}
+
+/// Ids are redacted like in logs.
+fn client<S: Serializer>(client: &u16, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&log::id("client", client))
+}
+
+fn tx<S: Serializer>(tx: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&log::id("tx", tx))
+}
+
+/// Starts writing alerts passed to [`append`] to `path`, replacing its contents.
+pub fn open(path: &Path) -> Result<(), anyhow::Error> {
+    let out = csv::Writer::from_writer(BufWriter::new(File::create(path)?));
+    *ALERTS.lock().unwrap_or_else(|err| err.into_inner()) = Some(out);
+    Ok(())
+}
+
+/// Appends `alert`, if the file is open.
+pub fn append(alert: &Alert) -> Result<(), anyhow::Error> {
+    let mut alerts = ALERTS.lock().unwrap_or_else(|err| err.into_inner());
+    let Some(out) = alerts.as_mut() else {
+        return Ok(());
+    };
+    out.serialize(alert)?;
+    Ok(())
+}
+
+/// Flushes and closes the file.
+pub fn close() -> Result<(), anyhow::Error> {
+    if let Some(mut out) = ALERTS.lock().unwrap_or_else(|err| err.into_inner()).take() {
+        out.flush()?;
+    }
+    Ok(())
+}
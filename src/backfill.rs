@@ -0,0 +1,90 @@
+//! Backfill of `trp serve`, enabled with `--backfill`: history is read from a file before any
+//! message of live connections is applied, so a daemon can start from where a previous one
+//! stopped.
+//!
+//! Connections are accepted during backfill, but their parsers [`wait`] until it is over, so
+//! producers are held back by TCP rather than buffered. With a `--cutover` timestamp, the seam
+//! between the two is exact: backfill takes messages up to and including the cutover, live
+//! connections take messages past it, and the rest are skipped on either side. Messages
+//! without a timestamp can't be placed against the cutover, and are taken from both.
+
+use std::sync::{Condvar, Mutex, OnceLock};
+
+use crate::Message;
+
+/// Which side of the seam messages are read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Backfill,
+    Live,
+}
+
+static CUTOVER: OnceLock<Option<u64>> = OnceLock::new();
+/// Whether backfill is over.
+static DONE: (Mutex<bool>, Condvar) = (Mutex::new(false), Condvar::new());
+
+/// Holds live messages back until [`finish`] is called, and splits the two at `cutover`, in
+/// milliseconds since unix epoch, when there is one. Only the first call has effect.
+pub fn enable(cutover: Option<u64>) {
+    let _ = CUTOVER.set(cutover);
+}
+
+/// Releases live messages.
+pub fn finish() {
+    let (done, released) = &DONE;
+    *done.lock().unwrap_or_else(|err| err.into_inner()) = true;
+    released.notify_all();
+}
+
+/// Blocks until backfill is over, returns immediately when there is none.
+pub fn wait() {
+    if CUTOVER.get().is_none() {
+        return;
+    }
+    let (done, released) = &DONE;
+    let mut done = done.lock().unwrap_or_else(|err| err.into_inner());
+    while !*done {
+        done = released.wait(done).unwrap_or_else(|err| err.into_inner());
+    }
+}
+
+/// Whether `message` read from `side` is applied, always the case without a cutover.
+pub fn takes(side: Side, message: &Message) -> bool {
+    let cutover = CUTOVER.get().copied().flatten();
+    takes_at(cutover, side, message)
+}
+
+fn takes_at(cutover: Option<u64>, side: Side, message: &Message) -> bool {
+    match (cutover, message.timestamp()) {
+        (Some(cutover), Some(timestamp)) => (timestamp <= cutover) == (side == Side::Backfill),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{takes_at, Side};
+    use crate::Message;
+
+    fn deposit(timestamp: Option<u64>) -> Message {
+        Message::Deposit {
+            client: 1,
+            tx: 1,
+            amount: 1.0,
+            timestamp,
+            effective_date: None,
+        }
+    }
+
+    #[test]
+    fn cutover_splits_sides() {
+        let sides = |timestamp| {
+            [Side::Backfill, Side::Live].map(|side| takes_at(Some(100), side, &deposit(timestamp)))
+        };
+        assert_eq!(sides(Some(99)), [true, false]);
+        assert_eq!(sides(Some(100)), [true, false]);
+        assert_eq!(sides(Some(101)), [false, true]);
+        assert_eq!(sides(None), [true, true]);
+        assert!(takes_at(None, Side::Live, &deposit(Some(1))));
+    }
+}
@@ -0,0 +1,91 @@
+//! Fault injection enabled with `--chaos`: the router drops some of the messages and delays
+//! sends to account tasks, account tasks get killed by a panic while applying a message, and
+//! have to be restarted by supervision in [`processor`](crate::processor).
+//!
+//! Injected faults are counted as rejects with their own codes, so the summary shows how much
+//! of the input they cost. Faults are drawn with the seed of the run, see [`rng`](crate::rng):
+//! the router draws drops and delays from a generator of its own, and every account task
+//! draws kills from one of its client, so the same seed injects the same faults into the same
+//! input, however tasks are scheduled.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use crate::{cli::Chaos, rng::Rng};
+
+/// Error code of messages dropped by the router.
+pub const DROPPED: &str = "CH_DROP";
+/// Error code of messages whose account task was killed.
+pub const KILLED: &str = "CH_KILL";
+
+/// Stream of the router's generator, past streams of clients.
+const ROUTER: u64 = 1 << 16;
+
+static CHAOS: OnceLock<Faults> = OnceLock::new();
+
+struct Faults {
+    chaos: Chaos,
+    seed: u64,
+    router: Mutex<Rng>,
+    /// Generator of every client, created on its first draw.
+    clients: Mutex<HashMap<u16, Rng>>,
+}
+
+/// Starts injecting faults, drawn with `seed`.
+pub fn enable(chaos: Chaos, seed: u64) {
+    let _ = CHAOS.set(Faults {
+        chaos,
+        seed,
+        router: Mutex::new(Rng::derive(seed, ROUTER)),
+        clients: Mutex::default(),
+    });
+}
+
+/// Whether faults are injected.
+pub fn enabled() -> bool {
+    CHAOS.get().is_some()
+}
+
+/// Draws with `f` from the generator of the router, `None` when chaos is off.
+fn draw<T>(f: impl FnOnce(&Chaos, &mut Rng) -> T) -> Option<T> {
+    let faults = CHAOS.get()?;
+    let mut rng = faults.router.lock().unwrap_or_else(|err| err.into_inner());
+    Some(f(&faults.chaos, &mut rng))
+}
+
+/// Returns `true` if the router should drop the next message.
+pub fn should_drop() -> bool {
+    draw(|chaos, rng| rng.chance(chaos.drop_rate)).unwrap_or(false)
+}
+
+/// Returns `true` if the account task of `client` should be killed before applying the next
+/// message.
+pub fn should_kill(client: u16) -> bool {
+    let Some(faults) = CHAOS.get() else {
+        return false;
+    };
+    let mut clients = faults.clients.lock().unwrap_or_else(|err| err.into_inner());
+    clients
+        .entry(client)
+        .or_insert_with(|| Rng::derive(faults.seed, u64::from(client)))
+        .chance(faults.chaos.kill_rate)
+}
+
+/// Sleeps for a random time up to the configured maximum, returns at once when chaos is off.
+pub async fn delay() {
+    let micros = draw(|chaos, rng| {
+        let max = chaos.max_delay.as_micros() as u64;
+        if max == 0 {
+            0
+        } else {
+            rng.below(max + 1)
+        }
+    })
+    .unwrap_or(0);
+    if micros > 0 {
+        tokio::time::sleep(Duration::from_micros(micros)).await;
+    }
+}
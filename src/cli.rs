@@ -0,0 +1,2620 @@
+//! Command line arguments.
+//!
+//! `trp <COMMAND> [OPTIONS]`, where running `trp <INFILE>` without a command is kept as a
+//! shorthand for `trp process <INFILE>`. Global options are accepted anywhere on the line.
+//! Values of [`Config`], loaded with `--config` and from `TRP_*` environment variables,
+//! serve as defaults of the options.
+
+use std::{path::PathBuf, time::Duration};
+
+use crate::{
+    config::{self, Config},
+    format::{Format, Mapping},
+    interest::{Posting, Schedule},
+    log, memory, ordering, pacing,
+    parse_errors::Policy,
+    parser::{self, AmountUnit},
+    redis,
+    report::Period,
+    reserve::Minimums,
+    sample,
+    schema::Schema,
+    settlement::Settlement,
+    state, top,
+    writer::Shards,
+    Message,
+};
+
+const USAGE: &str = "\
+Toy transaction processing engine.
+
+Usage: trp [OPTIONS] <COMMAND>
+
+Commands:
+  process  Process a transactions csv and print final account states
+  serve    Accept transactions csv over TCP until interrupted
+  replica  Follow the event log of a serve, serving its accounts read-only
+  merge    Combine account snapshots of partitioned runs
+  diff     Compare two account snapshots
+  query    Inspect state persisted by a previous run
+  rollback Put state persisted by previous runs back to a savepoint
+  compact  Prune settled transaction history of state persisted by previous runs
+  state    Export state persisted by previous runs to a single file, or import one
+  apply-corrections
+           Apply a corrections csv to state persisted by previous runs
+  convert  Translate a transactions file to another format
+  replay   Rebuild account states from an event log
+  statement Print statement of an account from an event log
+  validate Check a transactions csv without processing it
+  inspect  Sniff layout of a csv from another system and write a mapping for process
+  generate Write a randomized transactions csv
+  bench    Process the same input under several configurations and compare the runs
+  help     Print this message, or help of the given command
+
+Global options:
+  -q, --quiet              Only log errors, don't print summary
+  -v, --verbose            Raise log level, repeat for more (-vv, -vvv)
+      --log-format <FMT>   Log format, text or json [default: text]
+      --log-redact <MODE>  Write client and tx ids to logs, dlq, review and alerts files as
+                           they are, or hash or truncate them [default: off]
+      --config <PATH>      Read defaults of options from TOML file
+  -h, --help               Print help
+";
+
+const PROCESS_USAGE: &str = "\
+Process a transactions file and print final account states to stdout. Input format is
+picked by file extension: csv, ndjson (or jsonl) and bin, see `trp help convert`.
+
+Usage: trp process [OPTIONS] <INFILE>
+
+Options:
+      --progress               Redraw a progress line on stderr
+      --dashboard              Redraw a full-screen dashboard on stderr
+      --rate <N>               Hand messages on to the processor at no more than N per second
+      --two-pass               Read input twice, rejecting reused transaction ids and references
+                               to transactions which come later in the input
+      --seed <N>               Seed of randomness of the run, such as faults of --chaos, logged
+                               and kept in inputs of --state [default: random]
+      --sample <SHARE>         Process only SHARE of clients, e.g. 1%, picked by their id and
+                               --seed, for a quick check of input, can't be used with --state
+      --max-memory <SIZE>      Stop reading input and exit with non-zero code once accounts and
+                               their history take an estimated SIZE, e.g. 512MiB
+      --extended               Add pending funds, first and last activity timestamps, counts
+                               of applied and rejected messages of clients to output, and
+                               flag zombie accounts
+      --stream                 Also write accounts as messages lock or unlock them, ahead of
+                               their final state, which comes last
+      --stream-threshold <AMOUNT>
+                               With --stream, also write accounts as their total crosses
+                               AMOUNT
+      --shards <N>             Split accounts between N files by client id modulo N, ordered
+                               by client, instead of printing them, requires --shard-dir
+      --shard-dir <DIR>        Write files of --shards along with their manifest to DIR
+      --metrics-addr <ADDR>    Serve /metrics and /health on ADDR during the run
+      --metrics-file <PATH>    Write metrics to PATH once the run is over
+      --state <DIR>            Resume accounts and transaction history kept in DIR, and persist
+                               them there once the run is over
+      --force                  Process input even if its contents were already applied to the
+                               state in --state
+      --as-of <LABEL>          Also keep state in --state as a snapshot labeled LABEL, e.g.
+                               2024-06-30, replacing one with the same label only with --force
+      --savepoint <NAME>       Keep state in --state as it was before the run in a savepoint
+                               NAME, for trp rollback, replacing one only with --force
+      --lenient-amounts        Accept amounts with currency symbols and thousands separators,
+                               e.g. $1,234.56
+      --amount-unit <UNIT>     Unit of amounts: major, decimal numbers, or minor, integers of
+                               cents [default: major]
+      --on-parse-error <POLICY>
+                               What to do with rows which fail to parse: skip, quarantine,
+                               abort, or abort-after:N to skip up to N of them [default: skip]
+      --quarantine <PATH>      Write rows which fail to parse to PATH, with --on-parse-error
+                               quarantine
+      --event-log <PATH>       Log every valid message to PATH, for trp replay
+      --dlq <PATH>             Write messages of failed account tasks and tampered records
+                               to PATH
+      --unwritten <PATH>       Write accounts which could not be written to the output once
+                               retries were used up to PATH, instead of failing
+      --signature-key-file <PATH>
+                               Reject records whose signature column is not their HMAC-SHA256
+                               with key read from PATH
+      --alerts <PATH>          Write alerts of velocity rules to PATH
+      --watchlist <PATH>       Reject messages of clients blocked or flagged for review in PATH
+      --review <PATH>          Write messages of clients flagged for review to PATH
+      --reorder-lateness <MS>  Apply messages of every client in timestamp order, holding them
+                               back for MS milliseconds, reject messages later than that
+      --ordering <POLICY>      Order messages of a client from several sources are applied in,
+                               strict-per-client, best-effort or timestamp-merge
+                               [default: best-effort, timestamp-merge with --reorder-lateness]
+      --report <PATH>          Write totals of applied messages per period of their timestamps
+                               to PATH once the run is over
+      --report-period <PERIOD> Period of the report, hour or day [default: day]
+      --top <PATH>             Write top accounts by volume, held funds and rejects to PATH
+                               once the run is over
+      --top-n <N>              Accounts in every ranking of --top [default: 10]
+      --pending-withdrawals    Hold funds of withdrawals until a settle message settles them
+      --settle-after <MS>      Settle pending withdrawals once their client has seen a
+                               timestamp MS milliseconds past them, implies
+                               --pending-withdrawals
+      --min-balance <MINIMUMS> Reject withdrawals which would leave less than the minimum
+                               balance available, comma separated [TIER:]AMOUNT
+      --tiers <PATH>           Read tiers of clients from PATH, csv of client,tier
+      --tier-rules <PATH>      Read withdrawal limits, fees and overdrafts of tiers from PATH
+      --seed-accounts <PATH>   Read clients which get accounts from PATH, csv of client, with
+                               account_creation seeded
+      --interest-rates <SCHEDULE>
+                               Accrue daily interest on available funds at annual rates of
+                               SCHEDULE, comma separated [FROM:]RATE
+      --interest-posting <PERIOD>
+                               Post accrued interest every day or month [default: month]
+      --max-rejects <N>        Exit with non-zero code when more than N rows are rejected
+      --max-reject-rate <R>    Exit with non-zero code when more than R of rows are rejected
+      --strict                 Stop and exit with non-zero code when a message or result can't
+                               be sent to the next stage of the pipeline
+      --otlp-endpoint <URL>    Export traces and metrics over OTLP/HTTP (otel feature)
+      --reference              Use the sequential reference engine, options other than
+                               settlement of withdrawals, minimum balances and tiers are
+                               ignored
+
+Chaos options, for testing how the engine copes with faults. Any of them enables chaos:
+      --chaos                  Inject faults with the defaults below
+      --chaos-drop-rate <R>    Share of messages dropped by the router [default: 0.01]
+      --chaos-max-delay <MS>   Delay sends to account tasks by up to MS milliseconds [default: 1]
+      --chaos-kill-rate <R>    Chance of an account task being killed before applying a message
+                               [default: 0.001]
+      --chaos-seed <N>         Same as --seed
+
+Velocity rules, alerting on clients which withdraw often or much. Alerts don't block messages:
+      --max-withdrawals <N>    Alert when a client has more than N withdrawals in the window
+      --max-withdrawn <AMOUNT> Alert when a client withdraws more than AMOUNT in the window
+      --velocity-window <N>    Last N messages of a client the rules look at [default: 100]
+";
+
+const SERVE_USAGE: &str = "\
+Accept transactions csv over TCP, one csv stream with header per connection. Stops accepting
+connections on Ctrl-C, and prints final account states to stdout once open connections close.
+
+Usage: trp serve [OPTIONS] --listen <ADDR>
+
+Options:
+      --listen <ADDR>          Address to accept transactions on
+      --extended               Add pending funds, first and last activity timestamps, counts
+                               of applied and rejected messages of clients to output, and
+                               flag zombie accounts
+      --stream                 Also write accounts as messages lock or unlock them, ahead of
+                               their final state, which comes last
+      --stream-threshold <AMOUNT>
+                               With --stream, also write accounts as their total crosses
+                               AMOUNT
+      --metrics-addr <ADDR>    Serve /metrics and /health on ADDR
+      --redis <ADDR>           Keep balances of every client in a Redis hash at ADDR, updated
+                               as messages are applied
+      --redis-prefix <PREFIX>  Prefix of keys of Redis hashes [default: trp:client:]
+      --outbox                 Hold balances back from --redis until they are saved to
+                               --state, delivering them from an outbox in DIR, so none are
+                               lost or sent ahead of the state across crashes
+      --grpc-addr <ADDR>       Stream balance changes and locks of accounts to gRPC
+                               subscribers on ADDR
+      --flight-addr <ADDR>     Serve balances of every client over Arrow Flight on ADDR
+      --lease <PATH>           Hold the lease of ingestion in PATH, renewed every second, for a
+                               standby trp replica to take over once it expires; fails if
+                               another instance holds it
+      --lease-ttl <MS>         Milliseconds a lease lasts without being renewed [default: 5000]
+      --backfill <PATH>        Apply messages of PATH before messages of connections
+      --cutover <TIMESTAMP>    Take messages up to TIMESTAMP from --backfill and past it from
+                               connections, in milliseconds since unix epoch
+      --state <DIR>            Resume accounts and transaction history kept in DIR, and persist
+                               them there once the run is over
+      --evict-after <MS>       Park accounts which got no message for MS milliseconds in DIR of
+                               --state, reloading them on their next message
+      --max-memory <SIZE>      Once accounts and their history take an estimated SIZE, e.g.
+                               512MiB, park the least recently active half of accounts with
+                               --evict-after, or stop accepting transactions and exit with
+                               non-zero code
+      --lenient-amounts        Accept amounts with currency symbols and thousands separators,
+                               e.g. $1,234.56
+      --amount-unit <UNIT>     Unit of amounts: major, decimal numbers, or minor, integers of
+                               cents [default: major]
+      --on-parse-error <POLICY>
+                               What to do with rows which fail to parse: skip, quarantine,
+                               abort, or abort-after:N to skip up to N of them [default: skip]
+      --quarantine <PATH>      Write rows which fail to parse to PATH, with --on-parse-error
+                               quarantine
+      --event-log <PATH>       Log every valid message to PATH, for trp replay, flushed every
+                               second for trp replica
+      --dlq <PATH>             Write messages of failed account tasks and tampered records
+                               to PATH
+      --unwritten <PATH>       Write accounts which could not be written to the output once
+                               retries were used up to PATH, instead of failing
+      --signature-key-file <PATH>
+                               Reject records whose signature column is not their HMAC-SHA256
+                               with key read from PATH
+      --alerts <PATH>          Write alerts of velocity rules to PATH
+      --watchlist <PATH>       Reject messages of clients blocked or flagged for review in PATH
+      --review <PATH>          Write messages of clients flagged for review to PATH
+      --reorder-lateness <MS>  Apply messages of every client in timestamp order, holding them
+                               back for MS milliseconds, reject messages later than that
+      --ordering <POLICY>      Order messages of a client from several sources are applied in,
+                               strict-per-client, best-effort or timestamp-merge
+                               [default: best-effort, timestamp-merge with --reorder-lateness]
+      --report <PATH>          Write totals of applied messages per period of their timestamps
+                               to PATH once the run is over
+      --report-period <PERIOD> Period of the report, hour or day [default: day]
+      --top <PATH>             Write top accounts by volume, held funds and rejects to PATH
+                               once the run is over
+      --top-n <N>              Accounts in every ranking of --top [default: 10]
+      --pending-withdrawals    Hold funds of withdrawals until a settle message settles them
+      --settle-after <MS>      Settle pending withdrawals once their client has seen a
+                               timestamp MS milliseconds past them, implies
+                               --pending-withdrawals
+      --min-balance <MINIMUMS> Reject withdrawals which would leave less than the minimum
+                               balance available, comma separated [TIER:]AMOUNT
+      --tiers <PATH>           Read tiers of clients from PATH, csv of client,tier
+      --tier-rules <PATH>      Read withdrawal limits, fees and overdrafts of tiers from PATH
+      --seed-accounts <PATH>   Read clients which get accounts from PATH, csv of client, with
+                               account_creation seeded
+      --interest-rates <SCHEDULE>
+                               Accrue daily interest on available funds at annual rates of
+                               SCHEDULE, comma separated [FROM:]RATE
+      --interest-posting <PERIOD>
+                               Post accrued interest every day or month [default: month]
+      --max-rejects <N>        Exit with non-zero code when more than N rows are rejected
+      --max-reject-rate <R>    Exit with non-zero code when more than R of rows are rejected
+      --strict                 Stop and exit with non-zero code when a message or result can't
+                               be sent to the next stage of the pipeline
+      --max-withdrawals <N>    Alert when a client has more than N withdrawals in the window
+      --max-withdrawn <AMOUNT> Alert when a client withdraws more than AMOUNT in the window
+      --velocity-window <N>    Last N messages of a client the rules look at [default: 100]
+";
+
+const REPLICA_USAGE: &str = "\
+Follow an event log a trp serve writes with --event-log, applying its messages as they are
+appended, to serve metrics, gRPC and Flight off the primary. A replica accepts no
+transactions and writes no state, dead letters or Redis keys, so it can't change anything
+the primary owns. Rules of the engine, such as settlement, reserve, tiers, interest and
+ordering, are read from the configuration, which should be the one of the primary. Stops on
+Ctrl-C, and prints account states to stdout once what was appended is applied. Fails once
+the primary starts over with a new log.
+
+With --lease, the replica is a hot standby: once the primary fails to renew the lease, it
+applies the rest of the log, takes the lease over and accepts transactions on --listen like
+trp serve, appending them to the same log. The last line of every source the primary
+applied is logged, for feeders to pick up from there.
+
+Usage: trp replica [OPTIONS] <EVENT_LOG>
+
+Options:
+      --extended               Add pending funds, first and last activity timestamps, counts
+                               of applied and rejected messages of clients to output
+      --metrics-addr <ADDR>    Expose Prometheus metrics on ADDR
+      --grpc-addr <ADDR>       Stream account events to gRPC subscribers on ADDR
+      --flight-addr <ADDR>     Serve balances over Arrow Flight on ADDR
+      --lease <PATH>           Stand by for the primary holding the lease of ingestion in
+                               PATH, and take over ingestion on --listen once the lease
+                               expires, appending to the event log
+      --lease-ttl <MS>         Milliseconds a lease lasts without being renewed [default: 5000]
+      --listen <ADDR>          Address to accept transactions on once taken over, from
+                               configuration by default
+";
+
+const MERGE_USAGE: &str = "\
+Combine account snapshots of partitioned runs into a single snapshot on stdout, ordered by
+client. Every client must appear in exactly one snapshot.
+
+Usage: trp merge <SNAPSHOT>...
+";
+
+const DIFF_USAGE: &str = "\
+Compare two account snapshots. Prints a csv row for every client which differs, with its
+status (appeared, disappeared, locked, unlocked or changed) and balance deltas from OLD to NEW.
+
+Usage: trp diff <OLD> <NEW>
+       trp diff --state <DIR> <OLD_LABEL> [NEW_LABEL]
+
+Options:
+      --state <DIR>      Compare snapshots labeled with --as-of in DIR instead of files, NEW
+                         is the latest state when there is no NEW_LABEL
+";
+
+const QUERY_USAGE: &str = "\
+Inspect state persisted with --state by a previous run, as csv on stdout.
+
+Usage: trp query --state <DIR> [--as-of <LABEL>]
+                 (--client <ID> [--history] | --tx <ID> | --simulate <ROW>)
+
+Options:
+      --state <DIR>      State directory of the run
+      --as-of <LABEL>    Inspect the snapshot labeled LABEL with --as-of instead of the
+                         latest state
+      --client <ID>      Print balances of the client
+      --history          With --client, print states of the client's deposits instead
+      --tx <ID>          Print state of the deposit
+      --simulate <ROW>   Print whether the transaction, a csv row of input such as
+                         withdrawal,7,42,50.0, would be accepted, along with balances it
+                         would leave the client with. Applies the rules without tiers,
+                         minimum balances or settlement, and changes nothing
+";
+
+const ROLLBACK_USAGE: &str = "\
+Put state persisted with --state back to a savepoint kept with --savepoint, discarding runs
+since. The savepoint is removed, snapshots labeled with --as-of are kept.
+
+Usage: trp rollback --state <DIR> <NAME>
+
+Options:
+      --state <DIR>      State directory of the runs
+";
+
+const COMPACT_USAGE: &str = "\
+Prune transaction history of state persisted with --state, so that it doesn't grow forever.
+Charged back deposits and settled withdrawals are pruned, as is what retention of the engine
+configuration doesn't keep. Disputed deposits, pending funds and withdrawals pending
+settlement are kept. Accounts parked by serve --evict-after are pruned too, balances,
+snapshots and savepoints are left as they are. Must not run along with a run using DIR.
+
+Usage: trp compact [OPTIONS] --state <DIR>
+
+Options:
+      --state <DIR>      State directory of the runs
+      --keep-days <N>    Prune deposits not disputed more than N days before the latest
+                         timestamp of the state, instead of as retention says
+      --dry-run          Only print how many transactions would be pruned
+";
+
+const STATE_USAGE: &str = "\
+Move state persisted with --state elsewhere, e.g. to debug production state locally: export
+writes every file of DIR, snapshots, savepoints and parked accounts included, to a single
+FILE, import writes them back to DIR. Imports are checked against a checksum, nothing is
+written from a truncated or altered FILE. The state directory is the only backend state is
+kept in, exports carry it as it is.
+
+Usage: trp state export --state <DIR> <FILE>
+       trp state import [--force] --state <DIR> <FILE>
+
+Arguments:
+  <FILE>                 Export, - for stdout or stdin
+
+Options:
+      --state <DIR>      State directory of the runs
+      --force            Import into DIR even if it has state, replacing files of the export
+";
+
+const CORRECTIONS_USAGE: &str = "\
+Apply a corrections csv to state persisted with --state, and print the difference it makes
+to accounts, as trp diff does. Rows have kind (adjustment, unlock or reversal), client, tx,
+amount and reason: adjustments move available and total funds by a signed amount, unlocks
+unlock a locked account, reversals reverse a deposit tx, disputed or not. Every row must
+have a reason, and is kept in audit.csv of DIR. Nothing is applied if any row doesn't apply.
+
+Usage: trp apply-corrections [OPTIONS] --state <DIR> <CORRECTIONS>
+
+Options:
+      --state <DIR>      State directory of the runs
+      --dry-run          Only print the difference, leaving the state as it is
+      --savepoint <NAME> Keep state as it was before the corrections in a savepoint NAME, for
+                         trp rollback, replacing one only with --force
+      --force            Apply corrections even if their contents were already applied to
+                         the state
+";
+
+const CONVERT_USAGE: &str = "\
+Translate a transactions file to another format. Formats are picked by file extension: csv,
+ndjson (or jsonl) and bin.
+
+Usage: trp convert [OPTIONS] <INFILE> <OUTFILE>
+
+Options:
+      --from <FORMAT>    Format of INFILE, instead of its extension
+      --to <FORMAT>      Format of OUTFILE, instead of its extension
+";
+
+const REPLAY_USAGE: &str = "\
+Rebuild account states from an event log written with --event-log, printing them to stdout as
+the engine would. Replays all of the log unless limited with --offset or --until. Messages are
+taken as of their timestamps, or as of when they were logged if they have none.
+
+Usage: trp replay [OPTIONS] <EVENT_LOG>
+
+Options:
+      --offset <N>       Last offset to replay, offsets count messages from 0
+      --until <MS>       Last moment to replay, in milliseconds since unix epoch
+      --rate <N>         Replay no more than N messages per second
+      --max-rejects <N>        Exit with non-zero code when more than N rows are rejected
+      --max-reject-rate <R>    Exit with non-zero code when more than R of rows are rejected
+      --strict                 Stop and exit with non-zero code when a message or result can't
+                               be sent to the next stage of the pipeline
+";
+
+const STATEMENT_USAGE: &str = "\
+Print statement of an account from an event log written with --event-log, as csv on stdout:
+opening balances, every message which changed the account with balances once it was applied,
+and closing balances. Messages are taken as of their timestamps, or as of when they were
+logged if they have none.
+
+Usage: trp statement [OPTIONS] --client <ID> <EVENT_LOG>
+
+Options:
+      --client <ID>      Client of the account
+      --from <MS>        First moment of the statement, in milliseconds since unix epoch
+      --to <MS>          Last moment of the statement, in milliseconds since unix epoch
+";
+
+const VALIDATE_USAGE: &str = "\
+Check a transactions file, csv, ndjson or binary as its extension says, without processing
+it. Reports rows which can't be parsed, amounts which are not positive, reused transaction
+ids, and disputes, resolves and chargebacks which don't reference an earlier transaction of
+the same client. Exits with non-zero code when any are found.
+
+Usage: trp validate [OPTIONS] <INFILE>
+
+Options:
+      --lenient-amounts  Accept amounts with currency symbols and thousands separators,
+                         e.g. $1,234.56
+      --amount-unit <UNIT>
+                         Unit of amounts: major, decimal numbers, or minor, integers of
+                         cents [default: major]
+      --simulate         Also apply rows to balances, and report those the rules would
+                         reject, such as withdrawals of more than the client has, by their
+                         rejection code. Accounts open as account_creation says, rules
+                         apply without tiers, minimum balances or settlement
+      --seed-accounts <PATH>
+                         Read clients which get accounts from PATH, csv of client, with
+                         account_creation seeded
+";
+
+const INSPECT_USAGE: &str = "\
+Sniff delimiter, headers and the columns they hold of a csv from another system, and print
+the mapping trp process needs to read it, as TOML to pass with --config. Candidates of every
+column are printed to stderr. Exits with non-zero code when type, client or tx is not found.
+
+Usage: trp inspect [OPTIONS] <INFILE>
+
+Options:
+      --rows <N>         Number of rows to sample [default: 100]
+  -o, --output <PATH>    Write the mapping to PATH instead of stdout
+";
+
+const GENERATE_USAGE: &str = "\
+Write a randomized transactions csv to stdout, for benchmarks and test fixtures.
+
+Usage: trp generate [OPTIONS]
+
+Options:
+      --rows <N>                 Number of rows [default: 1000]
+      --clients <N>              Number of clients [default: 10]
+      --dispute-ratio <R>        Share of rows which are disputes, and of rows settling them
+                                 [default: 0.05]
+      --chargeback-ratio <R>     Share of settled disputes which are charged back [default: 0.1]
+      --seed <N>                 Seed, the same seed generates the same file [default: random]
+      --consistent               Only generate rows the engine accepts: disputes reference
+                                 earlier deposits of the client, withdrawals never overdraw
+";
+
+const BENCH_USAGE: &str = "\
+Process the same input under several configurations, one run after another, and print how
+fast every run went and how much memory it took, side by side, as csv.
+
+Usage: trp bench [OPTIONS] --input <INFILE> [-- <PROCESS OPTIONS>...]
+
+Options:
+      --config <PATH>            Configuration of runs, repeat to compare several
+                                 [default: none]
+      --input <INFILE>           Transactions file every run processes
+      --runs <N>                 Runs of every configuration, the fastest one is reported
+                                 [default: 1]
+      --seed <N>                 Seed of every run [default: random]
+
+Options after -- are passed on to trp process in every run, e.g. -- --shards 4 --shard-dir out.
+";
+
+/// Options shared by every command.
+#[derive(Debug, Default)]
+pub struct Global {
+    pub log_format: log::Format,
+    pub redact: log::Redact,
+    /// `-q` lowers log level to errors only, every `-v` raises it by one level.
+    pub verbosity: i8,
+    /// Pipeline tunables, only set from configuration file.
+    pub engine: config::Engine,
+    /// Layout of csv input, only set from configuration file.
+    pub mapping: Mapping,
+    /// Columns of output, only set from configuration file.
+    pub schema: Schema,
+}
+
+impl Global {
+    /// Log level requested with `-q`/`-v`, if any.
+    pub fn log_level(&self) -> Option<log::Level> {
+        match self.verbosity {
+            i8::MIN..=-1 => Some(log::Level::Error),
+            0 => None,
+            1 => Some(log::Level::Info),
+            2 => Some(log::Level::Debug),
+            _ => Some(log::Level::Trace),
+        }
+    }
+
+    pub fn quiet(&self) -> bool {
+        self.verbosity < 0
+    }
+
+    fn from_config(config: &Config) -> Self {
+        Global {
+            log_format: config.log_format.unwrap_or_default(),
+            redact: config.redact.unwrap_or_default(),
+            verbosity: match config.log_level {
+                Some(log::Level::Error) => -1,
+                None | Some(log::Level::Warn) => 0,
+                Some(log::Level::Info) => 1,
+                Some(log::Level::Debug) => 2,
+                Some(log::Level::Trace) => 3,
+            },
+            engine: config.engine,
+            mapping: config.mapping.clone(),
+            schema: config.schema.clone(),
+        }
+    }
+}
+
+/// Limits of rejected rows, over which a run exits with non-zero code.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Thresholds {
+    pub max_rejects: Option<u64>,
+    /// Share of rows, within `0..=1`.
+    pub max_reject_rate: Option<f64>,
+    /// Stop at the first failure to send, see [`send_errors`](crate::send_errors).
+    pub strict: bool,
+}
+
+/// What the parser does with rows which fail to parse, see
+/// [`parse_errors`](crate::parse_errors).
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ParseErrors {
+    pub policy: Policy,
+    /// Required with [`Policy::Quarantine`].
+    pub quarantine: Option<PathBuf>,
+}
+
+/// Faults injected with `--chaos`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chaos {
+    /// Share of messages dropped by the router instead of being forwarded, within `0..=1`.
+    pub drop_rate: f64,
+    /// Upper bound of random delay before every send to an account task.
+    pub max_delay: Duration,
+    /// Chance of an account task being killed before applying a message, within `0..=1`.
+    pub kill_rate: f64,
+    pub seed: Option<u64>,
+}
+
+/// Rules of [`velocity`](crate::velocity) alerts, checked over a window of the most recent
+/// messages of every client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Velocity {
+    /// Number of messages in the window.
+    pub window: usize,
+    /// Alert when a client has more withdrawals in the window.
+    pub max_withdrawals: Option<u64>,
+    /// Alert when a client withdraws more in the window.
+    pub max_withdrawn: Option<f64>,
+}
+
+impl Velocity {
+    /// Returns `true` if any rule is set.
+    pub fn enabled(&self) -> bool {
+        self.max_withdrawals.is_some() || self.max_withdrawn.is_some()
+    }
+}
+
+impl Default for Velocity {
+    fn default() -> Self {
+        Velocity {
+            window: 100,
+            max_withdrawals: None,
+            max_withdrawn: None,
+        }
+    }
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Chaos {
+            drop_rate: 0.01,
+            max_delay: Duration::from_millis(1),
+            kill_rate: 0.001,
+            seed: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ProcessArgs {
+    /// Transactions csv to process.
+    pub input: PathBuf,
+    /// When set, metrics are written to this file once the run is over.
+    pub metrics_file: Option<PathBuf>,
+    /// When set, metrics are served on `http://<ADDR>/metrics` for the duration of the run.
+    pub metrics_addr: Option<String>,
+    /// Redraw a progress line on stderr while input is being read.
+    pub progress: bool,
+    /// Redraw a full-screen dashboard on stderr for the duration of the run.
+    pub dashboard: bool,
+    /// When set, messages are paced at this many per second, see [`pacing`](crate::pacing).
+    pub rate: Option<f64>,
+    /// Index input in a first pass, see [`two_pass`](crate::two_pass).
+    pub two_pass: bool,
+    /// Seed of the run, see [`rng`](crate::rng), random when not set.
+    pub seed: Option<u64>,
+    /// When set, only this share of clients is processed, see [`sample`](crate::sample).
+    pub sample: Option<f64>,
+    /// When set, the run stops once it takes this many bytes, see [`memory`](crate::memory).
+    pub max_memory: Option<u64>,
+    /// Add pending funds and activity timestamps to output, see [`writer`](crate::writer).
+    pub extended: bool,
+    /// Write accounts as messages make significant changes to them, see
+    /// [`snapshots`](crate::snapshots).
+    pub stream: bool,
+    /// When set, accounts are also written as their total crosses this amount.
+    pub stream_threshold: Option<f32>,
+    /// When set, accounts are written to a file per shard instead of stdout.
+    pub shards: Option<Shards>,
+    /// OTLP/HTTP collector to export traces and metrics to, e.g. `http://localhost:4318`.
+    #[cfg(feature = "otel")]
+    pub otlp_endpoint: Option<String>,
+    /// When set, final state is persisted to this directory.
+    pub state: Option<PathBuf>,
+    /// Process input even if it was already applied to `state`.
+    pub force: bool,
+    /// When set, state is also kept as a snapshot with this label.
+    pub as_of: Option<String>,
+    /// When set, state as it was before the run is kept in a savepoint with this name.
+    pub savepoint: Option<String>,
+    /// When set, valid messages are logged to this file.
+    pub event_log: Option<PathBuf>,
+    /// When set, messages of failed account tasks are written to this file.
+    pub dlq: Option<PathBuf>,
+    /// When set, accounts the writer gave up on are written to this file.
+    pub unwritten: Option<PathBuf>,
+    /// When set, records are verified with key read from this file.
+    pub signature_key: Option<PathBuf>,
+    pub velocity: Velocity,
+    pub parse_errors: ParseErrors,
+    /// Accept amounts with currency symbols and thousands separators.
+    pub lenient_amounts: bool,
+    pub amount_unit: AmountUnit,
+    /// When set, alerts of velocity rules are written to this file.
+    pub alerts: Option<PathBuf>,
+    /// When set, messages are screened with the watchlist in this file.
+    pub watchlist: Option<PathBuf>,
+    /// When set, messages held for review are written to this file.
+    pub review: Option<PathBuf>,
+    /// When set, messages are reordered by timestamps within this many milliseconds.
+    pub reorder: Option<u64>,
+    /// Order messages of a client are applied in, see [`ordering`](crate::ordering).
+    pub ordering: ordering::Policy,
+    /// When set, time-windowed report is written to this file once the run is over.
+    pub report: Option<PathBuf>,
+    pub report_period: Period,
+    /// When set, top accounts are written to this file once the run is over.
+    pub top: Option<PathBuf>,
+    pub top_n: usize,
+    /// When set, withdrawals are pending until they are settled.
+    pub settlement: Option<Settlement>,
+    /// When set, withdrawals can't go below these minimum balances.
+    pub min_balance: Option<Minimums>,
+    /// When set, tiers of clients, and rules of tiers, are read from these files.
+    pub tiers: Option<PathBuf>,
+    pub tier_rules: Option<PathBuf>,
+    pub seed_accounts: Option<PathBuf>,
+    /// When set, interest accrues at these rates.
+    pub interest_rates: Option<Schedule>,
+    pub interest_posting: Posting,
+    pub thresholds: Thresholds,
+    /// Process with the sequential reference engine instead, to check results of the
+    /// sharded one.
+    pub reference: bool,
+    /// When set, faults are injected into the run.
+    pub chaos: Option<Chaos>,
+}
+
+#[derive(Debug, Default)]
+pub struct ReplicaArgs {
+    /// Event log of the primary.
+    pub event_log: PathBuf,
+    pub extended: bool,
+    pub metrics_addr: Option<String>,
+    pub grpc_addr: Option<String>,
+    pub flight_addr: Option<String>,
+    /// When set, the replica is a standby taking over ingestion on `listen` once this lease
+    /// expires.
+    pub lease: Option<PathBuf>,
+    pub lease_ttl: Option<u64>,
+    pub listen: Option<String>,
+    /// Rules of the engine, from configuration only.
+    pub reorder: Option<u64>,
+    pub ordering: ordering::Policy,
+    pub settlement: Option<Settlement>,
+    pub min_balance: Option<Minimums>,
+    pub tiers: Option<PathBuf>,
+    pub tier_rules: Option<PathBuf>,
+    pub seed_accounts: Option<PathBuf>,
+    pub interest_rates: Option<Schedule>,
+    pub interest_posting: Posting,
+}
+
+#[derive(Debug, Default)]
+pub struct ServeArgs {
+    /// Address to accept transaction streams on.
+    pub listen: String,
+    pub extended: bool,
+    pub stream: bool,
+    pub stream_threshold: Option<f32>,
+    pub metrics_addr: Option<String>,
+    /// When set, balances are kept in Redis at this address.
+    pub redis: Option<String>,
+    pub redis_prefix: String,
+    /// When set, balances are only written to Redis once saved to the state, see
+    /// [`outbox`](crate::outbox).
+    pub outbox: bool,
+    /// When set, account events are streamed to gRPC subscribers on this address.
+    pub grpc_addr: Option<String>,
+    /// When set, balances are served over Arrow Flight on this address.
+    pub flight_addr: Option<String>,
+    /// When set, the lease of ingestion is held in this file.
+    pub lease: Option<PathBuf>,
+    pub lease_ttl: Option<u64>,
+    /// When set, messages of this file are applied before those of connections.
+    pub backfill: Option<PathBuf>,
+    /// Milliseconds since unix epoch splitting messages of backfill and connections.
+    pub cutover: Option<u64>,
+    pub state: Option<PathBuf>,
+    /// Milliseconds accounts may be idle before they are evicted to the state directory.
+    pub evict_after: Option<u64>,
+    /// When set, accounts are spilled or the run stops once it takes this many bytes, see
+    /// [`memory`](crate::memory).
+    pub max_memory: Option<u64>,
+    pub event_log: Option<PathBuf>,
+    pub dlq: Option<PathBuf>,
+    pub unwritten: Option<PathBuf>,
+    pub signature_key: Option<PathBuf>,
+    pub velocity: Velocity,
+    pub parse_errors: ParseErrors,
+    /// Accept amounts with currency symbols and thousands separators.
+    pub lenient_amounts: bool,
+    pub amount_unit: AmountUnit,
+    pub alerts: Option<PathBuf>,
+    pub watchlist: Option<PathBuf>,
+    pub review: Option<PathBuf>,
+    pub reorder: Option<u64>,
+    pub ordering: ordering::Policy,
+    pub report: Option<PathBuf>,
+    pub report_period: Period,
+    pub top: Option<PathBuf>,
+    pub top_n: usize,
+    pub settlement: Option<Settlement>,
+    pub min_balance: Option<Minimums>,
+    pub tiers: Option<PathBuf>,
+    pub tier_rules: Option<PathBuf>,
+    pub seed_accounts: Option<PathBuf>,
+    pub interest_rates: Option<Schedule>,
+    pub interest_posting: Posting,
+    pub thresholds: Thresholds,
+}
+
+#[derive(Debug, Default)]
+pub struct MergeArgs {
+    /// Account snapshots to combine.
+    pub inputs: Vec<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+pub struct DiffArgs {
+    pub old: PathBuf,
+    pub new: PathBuf,
+}
+
+#[derive(Debug)]
+pub enum Query {
+    /// Balances of a client.
+    Account(u16),
+    /// Deposits of a client.
+    History(u16),
+    Transaction(u32),
+    /// Outcome of a transaction, were it applied.
+    Simulate(Message),
+}
+
+#[derive(Debug)]
+pub struct QueryArgs {
+    /// State directory, as passed to `--state` of the run.
+    pub state: PathBuf,
+    /// When set, the snapshot with this label is inspected instead of the latest state.
+    pub as_of: Option<String>,
+    pub query: Query,
+}
+
+#[derive(Debug)]
+pub struct RollbackArgs {
+    /// State directory, as passed to `--state` of the runs.
+    pub state: PathBuf,
+    pub savepoint: String,
+}
+
+#[derive(Debug)]
+pub struct CompactArgs {
+    /// State directory, as passed to `--state` of the runs.
+    pub state: PathBuf,
+    /// Days deposits which are not disputed are kept for, instead of as configured
+    /// retention says.
+    pub keep_days: Option<u64>,
+    /// Print how much would be pruned without pruning it.
+    pub dry_run: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateAction {
+    Export,
+    Import {
+        /// Import even if the directory has state.
+        force: bool,
+    },
+}
+
+#[derive(Debug)]
+pub struct StateArgs {
+    /// State directory, as passed to `--state` of the runs.
+    pub state: PathBuf,
+    /// Export written or read, `-` for stdout or stdin.
+    pub file: PathBuf,
+    pub action: StateAction,
+}
+
+#[derive(Debug, Default)]
+pub struct CorrectionsArgs {
+    /// Corrections csv to apply.
+    pub input: PathBuf,
+    /// State directory, as passed to `--state` of the runs.
+    pub state: PathBuf,
+    /// Print the difference corrections make without applying them.
+    pub dry_run: bool,
+    /// When set, state as it was before the corrections is kept in a savepoint with this name.
+    pub savepoint: Option<String>,
+    /// Apply corrections even if they were already applied to `state`.
+    pub force: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct ConvertArgs {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub from: Option<Format>,
+    pub to: Option<Format>,
+}
+
+#[derive(Debug, Default)]
+pub struct ReplayArgs {
+    pub event_log: PathBuf,
+    pub offset: Option<u64>,
+    /// Milliseconds since unix epoch.
+    pub until: Option<u64>,
+    pub rate: Option<f64>,
+    pub thresholds: Thresholds,
+}
+
+#[derive(Debug, Default)]
+pub struct StatementArgs {
+    pub event_log: PathBuf,
+    pub client: u16,
+    /// Milliseconds since unix epoch, statement starts with the log when not set.
+    pub from: Option<u64>,
+    /// Milliseconds since unix epoch, statement ends with the log when not set.
+    pub to: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct ValidateArgs {
+    /// Transactions to check, of the format its extension says.
+    pub input: PathBuf,
+    /// Accept amounts with currency symbols and thousands separators.
+    pub lenient_amounts: bool,
+    pub amount_unit: AmountUnit,
+    /// Report rows the rules would reject, see [`validate`](crate::commands::validate).
+    pub simulate: bool,
+    /// Clients which get accounts when simulating, see [`creation`](crate::creation).
+    pub seed_accounts: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct InspectArgs {
+    /// Csv to inspect.
+    pub input: PathBuf,
+    /// Rows sampled after headers.
+    pub rows: usize,
+    /// Mapping is printed to stdout when not set.
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+pub struct BenchArgs {
+    /// Configuration of every run to compare, runs with no configuration when empty.
+    pub configs: Vec<PathBuf>,
+    pub input: PathBuf,
+    /// Runs of every configuration, at least one.
+    pub runs: usize,
+    /// Same for every run, random when not set.
+    pub seed: Option<u64>,
+    /// Options passed on to `trp process` in every run.
+    pub process: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct GenerateArgs {
+    pub rows: u64,
+    pub clients: u16,
+    pub dispute_ratio: f64,
+    pub chargeback_ratio: f64,
+    /// Random seed is used when not set.
+    pub seed: Option<u64>,
+    /// Guarantee that references are valid and funds are sufficient.
+    pub consistent: bool,
+}
+
+impl Default for GenerateArgs {
+    fn default() -> Self {
+        GenerateArgs {
+            rows: 1000,
+            clients: 10,
+            dispute_ratio: 0.05,
+            chargeback_ratio: 0.1,
+            seed: None,
+            consistent: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Process(ProcessArgs),
+    Serve(ServeArgs),
+    Replica(ReplicaArgs),
+    Merge(MergeArgs),
+    Diff(DiffArgs),
+    Query(QueryArgs),
+    Rollback(RollbackArgs),
+    Compact(CompactArgs),
+    State(StateArgs),
+    Corrections(CorrectionsArgs),
+    Convert(ConvertArgs),
+    Replay(ReplayArgs),
+    Statement(StatementArgs),
+    Validate(ValidateArgs),
+    Inspect(InspectArgs),
+    Generate(GenerateArgs),
+    Bench(BenchArgs),
+    /// Help was requested, holds the text to print.
+    Help(&'static str),
+}
+
+#[derive(Debug)]
+pub struct Cli {
+    pub global: Global,
+    pub command: Command,
+}
+
+/// Remaining arguments, with access to flag values.
+struct Args<I> {
+    inner: I,
+    usage: &'static str,
+}
+
+impl<I: Iterator<Item = String>> Args<I> {
+    fn value(&mut self, flag: &str) -> Result<String, anyhow::Error> {
+        self.inner
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("{flag} requires a value\n\n{}", self.usage))
+    }
+
+    fn unexpected(&self, arg: &str) -> anyhow::Error {
+        if arg.starts_with('-') {
+            anyhow::anyhow!("Unknown option {arg}\n\n{}", self.usage)
+        } else {
+            anyhow::anyhow!("Unexpected argument {arg}\n\n{}", self.usage)
+        }
+    }
+
+    /// Handles `arg` if it is one of [`Thresholds`] options. Returns `false` when it's not.
+    fn thresholds(
+        &mut self,
+        thresholds: &mut Thresholds,
+        arg: &str,
+    ) -> Result<bool, anyhow::Error> {
+        match arg {
+            "--max-rejects" => thresholds.max_rejects = Some(self.value(arg)?.parse()?),
+            "--max-reject-rate" => {
+                let rate: f64 = self.value(arg)?.parse()?;
+                if !(0.0..=1.0).contains(&rate) {
+                    return Err(anyhow::anyhow!(
+                        "--max-reject-rate must be within 0..=1\n\n{}",
+                        self.usage
+                    ));
+                }
+                thresholds.max_reject_rate = Some(rate);
+            }
+            "--strict" => thresholds.strict = true,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Handles `arg` if it is one of [`Velocity`] options. Returns `false` when it's not.
+    fn velocity(&mut self, velocity: &mut Velocity, arg: &str) -> Result<bool, anyhow::Error> {
+        match arg {
+            "--max-withdrawals" => velocity.max_withdrawals = Some(self.value(arg)?.parse()?),
+            "--max-withdrawn" => velocity.max_withdrawn = Some(self.value(arg)?.parse()?),
+            "--velocity-window" => match self.value(arg)?.parse()? {
+                0 => {
+                    return Err(anyhow::anyhow!(
+                        "--velocity-window must be positive\n\n{}",
+                        self.usage
+                    ))
+                }
+                window => velocity.window = window,
+            },
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Handles `arg` if it is one of [`ParseErrors`] options. Returns `false` when it's not.
+    fn parse_errors(
+        &mut self,
+        parse_errors: &mut ParseErrors,
+        arg: &str,
+    ) -> Result<bool, anyhow::Error> {
+        match arg {
+            "--on-parse-error" => parse_errors.policy = self.value(arg)?.parse()?,
+            "--quarantine" => parse_errors.quarantine = Some(self.value(arg)?.into()),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Ordering policy given with `--ordering`, implied by `--reorder-lateness` when not
+    /// given. Adds to `problems` when the two contradict each other.
+    fn ordering(
+        &self,
+        ordering: Option<ordering::Policy>,
+        reorder: Option<u64>,
+        problems: &mut Vec<String>,
+    ) -> ordering::Policy {
+        match (ordering, reorder) {
+            (Some(policy), Some(_)) if policy != ordering::Policy::TimestampMerge => {
+                problems.push(format!(
+                    "--reorder-lateness requires --ordering timestamp-merge, not {policy}"
+                ));
+                policy
+            }
+            (Some(policy), _) => policy,
+            (None, Some(_)) => ordering::Policy::TimestampMerge,
+            (None, None) => ordering::Policy::BestEffort,
+        }
+    }
+
+    /// Checks [`ParseErrors`] options make sense together.
+    fn check_parse_errors(&self, parse_errors: &ParseErrors, problems: &mut Vec<String>) {
+        if parse_errors.policy == Policy::Quarantine && parse_errors.quarantine.is_none() {
+            problems.push("--on-parse-error quarantine requires --quarantine".to_string());
+        }
+    }
+
+    /// Checks every address given is a `host:port` one.
+    fn check_addresses(&self, addresses: &[(&str, Option<&str>)], problems: &mut Vec<String>) {
+        for (flag, addr) in addresses {
+            if let Some(problem) = addr.and_then(|addr| config::address(flag, addr)) {
+                problems.push(problem);
+            }
+        }
+    }
+
+    /// Fails with every one of `problems` at once, along with usage of the command.
+    fn check(&self, problems: Vec<String>) -> Result<(), anyhow::Error> {
+        config::aggregate(problems).map_err(|err| anyhow::anyhow!("{err}\n\n{}", self.usage))
+    }
+
+    /// Handles `arg` if it is one of [`Chaos`] options, enabling chaos. Returns `false` when
+    /// it's not.
+    fn chaos(&mut self, chaos: &mut Option<Chaos>, arg: &str) -> Result<bool, anyhow::Error> {
+        let rate = |args: &mut Self| -> Result<f64, anyhow::Error> {
+            let rate: f64 = args.value(arg)?.parse()?;
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(anyhow::anyhow!(
+                    "{arg} must be within 0..=1\n\n{}",
+                    args.usage
+                ));
+            }
+            Ok(rate)
+        };
+        match arg {
+            "--chaos" => {
+                chaos.get_or_insert_with(Chaos::default);
+            }
+            "--chaos-drop-rate" => chaos.get_or_insert_with(Chaos::default).drop_rate = rate(self)?,
+            "--chaos-max-delay" => {
+                let millis = self.value(arg)?.parse()?;
+                chaos.get_or_insert_with(Chaos::default).max_delay = Duration::from_millis(millis);
+            }
+            "--chaos-kill-rate" => chaos.get_or_insert_with(Chaos::default).kill_rate = rate(self)?,
+            "--chaos-seed" => {
+                let seed = self.value(arg)?.parse()?;
+                chaos.get_or_insert_with(Chaos::default).seed = Some(seed);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Handles `arg` if it is one of global options. Returns `false` when it's not.
+    fn global(&mut self, global: &mut Global, arg: &str) -> Result<bool, anyhow::Error> {
+        match arg {
+            "-q" | "--quiet" => global.verbosity = -1,
+            "--verbose" => global.verbosity = global.verbosity.max(0).saturating_add(1),
+            flag if is_verbose(flag) => {
+                let count = i8::try_from(flag.len() - 1).unwrap_or(i8::MAX);
+                global.verbosity = global.verbosity.max(0).saturating_add(count)
+            }
+            "--log-format" => global.log_format = self.value(arg)?.parse()?,
+            "--log-redact" => global.redact = self.value(arg)?.parse()?,
+            // Already loaded before parsing the rest.
+            "--config" => {
+                self.value(arg)?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+/// Whether `arg` is `-v`, `-vv` and so on.
+fn is_verbose(arg: &str) -> bool {
+    arg.strip_prefix('-')
+        .is_some_and(|flag| !flag.is_empty() && flag.chars().all(|c| c == 'v'))
+}
+
+impl Cli {
+    pub fn parse() -> Result<Self, anyhow::Error> {
+        Self::parse_from(std::env::args().skip(1), std::env::vars())
+    }
+
+    fn parse_from<I, E>(args: I, env: E) -> Result<Self, anyhow::Error>
+    where
+        I: IntoIterator<Item = String>,
+        E: IntoIterator<Item = (String, String)>,
+    {
+        let args: Vec<String> = args.into_iter().collect();
+        // Configurations given after `bench` are those of its runs.
+        let mut config = match args
+            .iter()
+            .take_while(|arg| *arg != "bench")
+            .position(|arg| arg == "--config")
+        {
+            Some(i) => Config::load(
+                args.get(i + 1)
+                    .ok_or_else(|| anyhow::anyhow!("--config requires a value\n\n{USAGE}"))?,
+            )?,
+            None => Config::default(),
+        };
+        config.merge_env(env)?;
+        config.validate()?;
+
+        let mut args = Args {
+            inner: args.into_iter(),
+            usage: USAGE,
+        };
+        let mut global = Global::from_config(&config);
+
+        let command = loop {
+            let Some(arg) = args.inner.next() else {
+                return Err(anyhow::anyhow!("Must provide a command\n\n{USAGE}"));
+            };
+            if args.global(&mut global, &arg)? {
+                continue;
+            }
+
+            break match arg.as_str() {
+                "-h" | "--help" => Command::Help(USAGE),
+                "help" => Command::Help(match args.inner.next().as_deref() {
+                    Some("process") => PROCESS_USAGE,
+                    Some("serve") => SERVE_USAGE,
+                    Some("replica") => REPLICA_USAGE,
+                    Some("merge") => MERGE_USAGE,
+                    Some("diff") => DIFF_USAGE,
+                    Some("query") => QUERY_USAGE,
+                    Some("rollback") => ROLLBACK_USAGE,
+                    Some("compact") => COMPACT_USAGE,
+                    Some("state") => STATE_USAGE,
+                    Some("apply-corrections") => CORRECTIONS_USAGE,
+                    Some("convert") => CONVERT_USAGE,
+                    Some("replay") => REPLAY_USAGE,
+                    Some("statement") => STATEMENT_USAGE,
+                    Some("validate") => VALIDATE_USAGE,
+                    Some("inspect") => INSPECT_USAGE,
+                    Some("generate") => GENERATE_USAGE,
+                    Some("bench") => BENCH_USAGE,
+                    _ => USAGE,
+                }),
+                "process" => Self::process(&mut args, &mut global, &config, None)?,
+                "serve" => Self::serve(&mut args, &mut global, &config)?,
+                "replica" => Self::replica(&mut args, &mut global, &config)?,
+                "merge" => Self::merge(&mut args, &mut global)?,
+                "diff" => Self::diff(&mut args, &mut global)?,
+                "query" => Self::query(&mut args, &mut global, &config)?,
+                "rollback" => Self::rollback(&mut args, &mut global, &config)?,
+                "compact" => Self::compact(&mut args, &mut global, &config)?,
+                "state" => Self::state(&mut args, &mut global, &config)?,
+                "apply-corrections" => Self::corrections(&mut args, &mut global, &config)?,
+                "convert" => Self::convert(&mut args, &mut global)?,
+                "replay" => Self::replay(&mut args, &mut global, &config)?,
+                "statement" => Self::statement(&mut args, &mut global)?,
+                "validate" => Self::validate(&mut args, &mut global, &config)?,
+                "inspect" => Self::inspect(&mut args, &mut global)?,
+                "generate" => Self::generate(&mut args, &mut global)?,
+                "bench" => Self::bench(&mut args, &mut global)?,
+                // `trp <INFILE>`, as before commands were introduced.
+                input if !input.starts_with('-') => {
+                    Self::process(&mut args, &mut global, &config, Some(input.into()))?
+                }
+                other => return Err(args.unexpected(other)),
+            };
+        };
+
+        if global.quiet() {
+            if let Command::Process(ProcessArgs { progress: true, .. })
+            | Command::Process(ProcessArgs {
+                dashboard: true, ..
+            }) = command
+            {
+                return Err(anyhow::anyhow!(
+                    "--quiet can't be combined with --progress or --dashboard\n\n{PROCESS_USAGE}"
+                ));
+            }
+        }
+
+        Ok(Cli { global, command })
+    }
+
+    fn process<I: Iterator<Item = String>>(
+        args: &mut Args<I>,
+        global: &mut Global,
+        config: &Config,
+        mut input: Option<PathBuf>,
+    ) -> Result<Command, anyhow::Error> {
+        args.usage = PROCESS_USAGE;
+        let mut parsed = ProcessArgs {
+            rate: config.rate,
+            metrics_file: config.metrics_file.clone(),
+            metrics_addr: config.metrics_addr.clone(),
+            #[cfg(feature = "otel")]
+            otlp_endpoint: config.otlp_endpoint.clone(),
+            state: config.state.clone(),
+            event_log: config.event_log.clone(),
+            dlq: config.dlq.clone(),
+            unwritten: config.unwritten.clone(),
+            signature_key: config.signature_key.clone(),
+            velocity: config.velocity,
+            parse_errors: config.parse_errors.clone(),
+            lenient_amounts: config.lenient_amounts,
+            amount_unit: config.amount_unit,
+            alerts: config.alerts.clone(),
+            watchlist: config.watchlist.clone(),
+            review: config.review.clone(),
+            reorder: config.reorder,
+            report: config.report.clone(),
+            report_period: config.report_period.unwrap_or_default(),
+            top: config.top.clone(),
+            top_n: config.top_n.unwrap_or(top::DEFAULT_N),
+            settlement: config.settlement,
+            min_balance: config.min_balance.clone(),
+            tiers: config.tiers.clone(),
+            tier_rules: config.tier_rules.clone(),
+            seed_accounts: config.seed_accounts.clone(),
+            interest_rates: config.interest_rates.clone(),
+            interest_posting: config.interest_posting.unwrap_or_default(),
+            thresholds: config.thresholds,
+            ..Default::default()
+        };
+        let (mut shards, mut shard_dir) = (None, None);
+        let mut ordering = config.ordering;
+
+        while let Some(arg) = args.inner.next() {
+            if args.global(global, &arg)?
+                || args.thresholds(&mut parsed.thresholds, &arg)?
+                || args.chaos(&mut parsed.chaos, &arg)?
+                || args.velocity(&mut parsed.velocity, &arg)?
+                || args.parse_errors(&mut parsed.parse_errors, &arg)?
+            {
+                continue;
+            }
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help(PROCESS_USAGE)),
+                "--progress" => parsed.progress = true,
+                "--dashboard" => parsed.dashboard = true,
+                "--two-pass" => parsed.two_pass = true,
+                "--sample" => parsed.sample = Some(sample::share(&args.value(&arg)?)?),
+                "--max-memory" => parsed.max_memory = Some(memory::size(&args.value(&arg)?)?),
+                "--seed" => parsed.seed = Some(args.value(&arg)?.parse()?),
+                "--rate" => parsed.rate = Some(pacing::rate(&args.value(&arg)?)?),
+                "--extended" => parsed.extended = true,
+                "--stream" => parsed.stream = true,
+                "--stream-threshold" => parsed.stream_threshold = Some(args.value(&arg)?.parse()?),
+                "--lenient-amounts" => parsed.lenient_amounts = true,
+                "--amount-unit" => parsed.amount_unit = args.value(&arg)?.parse()?,
+                "--shards" => match args.value(&arg)?.parse()? {
+                    0 => {
+                        return Err(anyhow::anyhow!(
+                            "--shards must be positive\n\n{PROCESS_USAGE}"
+                        ))
+                    }
+                    count => shards = Some(count),
+                },
+                "--shard-dir" => shard_dir = Some(PathBuf::from(args.value(&arg)?)),
+                "--reference" => parsed.reference = true,
+                #[cfg(feature = "otel")]
+                "--otlp-endpoint" => parsed.otlp_endpoint = Some(args.value(&arg)?),
+                "--metrics-file" => parsed.metrics_file = Some(args.value(&arg)?.into()),
+                "--metrics-addr" => parsed.metrics_addr = Some(args.value(&arg)?),
+                "--state" => parsed.state = Some(args.value(&arg)?.into()),
+                "--force" => parsed.force = true,
+                "--as-of" => parsed.as_of = Some(state::label(&args.value(&arg)?)?),
+                "--savepoint" => parsed.savepoint = Some(state::label(&args.value(&arg)?)?),
+                "--event-log" => parsed.event_log = Some(args.value(&arg)?.into()),
+                "--dlq" => parsed.dlq = Some(args.value(&arg)?.into()),
+                "--unwritten" => parsed.unwritten = Some(args.value(&arg)?.into()),
+                "--signature-key-file" => parsed.signature_key = Some(args.value(&arg)?.into()),
+                "--alerts" => parsed.alerts = Some(args.value(&arg)?.into()),
+                "--watchlist" => parsed.watchlist = Some(args.value(&arg)?.into()),
+                "--review" => parsed.review = Some(args.value(&arg)?.into()),
+                "--reorder-lateness" => parsed.reorder = Some(args.value(&arg)?.parse()?),
+                "--ordering" => ordering = Some(args.value(&arg)?.parse()?),
+                "--report" => parsed.report = Some(args.value(&arg)?.into()),
+                "--report-period" => parsed.report_period = args.value(&arg)?.parse()?,
+                "--top" => parsed.top = Some(args.value(&arg)?.into()),
+                "--top-n" => parsed.top_n = args.value(&arg)?.parse()?,
+                "--pending-withdrawals" => {
+                    parsed.settlement.get_or_insert_default();
+                }
+                "--settle-after" => {
+                    parsed.settlement.get_or_insert_default().after =
+                        Some(args.value(&arg)?.parse()?);
+                }
+                "--min-balance" => parsed.min_balance = Some(args.value(&arg)?.parse()?),
+                "--tiers" => parsed.tiers = Some(args.value(&arg)?.into()),
+                "--tier-rules" => parsed.tier_rules = Some(args.value(&arg)?.into()),
+                "--seed-accounts" => parsed.seed_accounts = Some(args.value(&arg)?.into()),
+                "--interest-rates" => parsed.interest_rates = Some(args.value(&arg)?.parse()?),
+                "--interest-posting" => parsed.interest_posting = args.value(&arg)?.parse()?,
+                path if input.is_none() && !path.starts_with('-') => input = Some(path.into()),
+                other => return Err(args.unexpected(other)),
+            }
+        }
+
+        parsed.input = input
+            .or_else(|| config.input.clone())
+            .ok_or_else(|| anyhow::anyhow!("Must provide input file to read\n\n{PROCESS_USAGE}"))?;
+        let mut problems = Vec::new();
+        if parsed.stream_threshold.is_some() && !parsed.stream {
+            problems.push("--stream-threshold requires --stream".to_string());
+        }
+        if parsed.as_of.is_some() && parsed.state.is_none() {
+            problems.push("--as-of requires --state".to_string());
+        }
+        if parsed.sample.is_some() && parsed.state.is_some() {
+            problems.push(
+                "--sample can't be used with --state, state would only keep the sample".to_string(),
+            );
+        }
+        if parsed.savepoint.is_some() && parsed.state.is_none() {
+            problems.push("--savepoint requires --state".to_string());
+        }
+        args.check_parse_errors(&parsed.parse_errors, &mut problems);
+        parsed.ordering = args.ordering(ordering, parsed.reorder, &mut problems);
+        parsed.shards = match (shards, shard_dir) {
+            (Some(count), Some(dir)) => Some(Shards { count, dir }),
+            (None, None) => None,
+            _ => {
+                problems.push("--shards and --shard-dir must be given together".to_string());
+                None
+            }
+        };
+        args.check_addresses(
+            &[("--metrics-addr", parsed.metrics_addr.as_deref())],
+            &mut problems,
+        );
+        #[cfg(feature = "otel")]
+        if let Some(endpoint) = &parsed.otlp_endpoint {
+            if !endpoint.starts_with("http://") {
+                problems.push(format!(
+                    "--otlp-endpoint must start with http://, not {endpoint}"
+                ));
+            }
+        }
+        args.check(problems)?;
+        Ok(Command::Process(parsed))
+    }
+
+    fn serve<I: Iterator<Item = String>>(
+        args: &mut Args<I>,
+        global: &mut Global,
+        config: &Config,
+    ) -> Result<Command, anyhow::Error> {
+        args.usage = SERVE_USAGE;
+        let mut parsed = ServeArgs {
+            metrics_addr: config.metrics_addr.clone(),
+            redis: config.redis.clone(),
+            redis_prefix: config
+                .redis_prefix
+                .clone()
+                .unwrap_or_else(|| redis::DEFAULT_PREFIX.to_string()),
+            grpc_addr: config.grpc_addr.clone(),
+            flight_addr: config.flight_addr.clone(),
+            state: config.state.clone(),
+            evict_after: config.evict_after,
+            event_log: config.event_log.clone(),
+            dlq: config.dlq.clone(),
+            unwritten: config.unwritten.clone(),
+            signature_key: config.signature_key.clone(),
+            velocity: config.velocity,
+            parse_errors: config.parse_errors.clone(),
+            lenient_amounts: config.lenient_amounts,
+            amount_unit: config.amount_unit,
+            alerts: config.alerts.clone(),
+            watchlist: config.watchlist.clone(),
+            review: config.review.clone(),
+            reorder: config.reorder,
+            report: config.report.clone(),
+            report_period: config.report_period.unwrap_or_default(),
+            top: config.top.clone(),
+            top_n: config.top_n.unwrap_or(top::DEFAULT_N),
+            settlement: config.settlement,
+            min_balance: config.min_balance.clone(),
+            tiers: config.tiers.clone(),
+            tier_rules: config.tier_rules.clone(),
+            seed_accounts: config.seed_accounts.clone(),
+            interest_rates: config.interest_rates.clone(),
+            interest_posting: config.interest_posting.unwrap_or_default(),
+            thresholds: config.thresholds,
+            ..Default::default()
+        };
+        let mut listen = config.listen.clone();
+        let mut ordering = config.ordering;
+
+        while let Some(arg) = args.inner.next() {
+            if args.global(global, &arg)?
+                || args.thresholds(&mut parsed.thresholds, &arg)?
+                || args.velocity(&mut parsed.velocity, &arg)?
+                || args.parse_errors(&mut parsed.parse_errors, &arg)?
+            {
+                continue;
+            }
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help(SERVE_USAGE)),
+                "--listen" => listen = Some(args.value(&arg)?),
+                "--redis" => parsed.redis = Some(args.value(&arg)?),
+                "--redis-prefix" => parsed.redis_prefix = args.value(&arg)?,
+                "--outbox" => parsed.outbox = true,
+                "--grpc-addr" => parsed.grpc_addr = Some(args.value(&arg)?),
+                "--flight-addr" => parsed.flight_addr = Some(args.value(&arg)?),
+                "--lease" => parsed.lease = Some(args.value(&arg)?.into()),
+                "--lease-ttl" => parsed.lease_ttl = Some(args.value(&arg)?.parse()?),
+                "--backfill" => parsed.backfill = Some(args.value(&arg)?.into()),
+                "--cutover" => parsed.cutover = Some(args.value(&arg)?.parse()?),
+                "--extended" => parsed.extended = true,
+                "--stream" => parsed.stream = true,
+                "--stream-threshold" => parsed.stream_threshold = Some(args.value(&arg)?.parse()?),
+                "--lenient-amounts" => parsed.lenient_amounts = true,
+                "--amount-unit" => parsed.amount_unit = args.value(&arg)?.parse()?,
+                "--metrics-addr" => parsed.metrics_addr = Some(args.value(&arg)?),
+                "--state" => parsed.state = Some(args.value(&arg)?.into()),
+                "--evict-after" => parsed.evict_after = Some(args.value(&arg)?.parse()?),
+                "--max-memory" => parsed.max_memory = Some(memory::size(&args.value(&arg)?)?),
+                "--event-log" => parsed.event_log = Some(args.value(&arg)?.into()),
+                "--dlq" => parsed.dlq = Some(args.value(&arg)?.into()),
+                "--unwritten" => parsed.unwritten = Some(args.value(&arg)?.into()),
+                "--signature-key-file" => parsed.signature_key = Some(args.value(&arg)?.into()),
+                "--alerts" => parsed.alerts = Some(args.value(&arg)?.into()),
+                "--watchlist" => parsed.watchlist = Some(args.value(&arg)?.into()),
+                "--review" => parsed.review = Some(args.value(&arg)?.into()),
+                "--reorder-lateness" => parsed.reorder = Some(args.value(&arg)?.parse()?),
+                "--ordering" => ordering = Some(args.value(&arg)?.parse()?),
+                "--report" => parsed.report = Some(args.value(&arg)?.into()),
+                "--report-period" => parsed.report_period = args.value(&arg)?.parse()?,
+                "--top" => parsed.top = Some(args.value(&arg)?.into()),
+                "--top-n" => parsed.top_n = args.value(&arg)?.parse()?,
+                "--pending-withdrawals" => {
+                    parsed.settlement.get_or_insert_default();
+                }
+                "--settle-after" => {
+                    parsed.settlement.get_or_insert_default().after =
+                        Some(args.value(&arg)?.parse()?);
+                }
+                "--min-balance" => parsed.min_balance = Some(args.value(&arg)?.parse()?),
+                "--tiers" => parsed.tiers = Some(args.value(&arg)?.into()),
+                "--tier-rules" => parsed.tier_rules = Some(args.value(&arg)?.into()),
+                "--seed-accounts" => parsed.seed_accounts = Some(args.value(&arg)?.into()),
+                "--interest-rates" => parsed.interest_rates = Some(args.value(&arg)?.parse()?),
+                "--interest-posting" => parsed.interest_posting = args.value(&arg)?.parse()?,
+                other => return Err(args.unexpected(other)),
+            }
+        }
+
+        let mut problems = Vec::new();
+        if parsed.stream_threshold.is_some() && !parsed.stream {
+            problems.push("--stream-threshold requires --stream".to_string());
+        }
+        if parsed.cutover.is_some() && parsed.backfill.is_none() {
+            problems.push("--cutover requires --backfill".to_string());
+        }
+        if parsed.evict_after.is_some() && parsed.state.is_none() {
+            problems.push("--evict-after requires --state".to_string());
+        }
+        if parsed.evict_after.is_some() && parsed.interest_rates.is_some() {
+            problems.push(
+                "--evict-after can't be used with --interest-rates, interest accrues on resident accounts only"
+                    .to_string(),
+            );
+        }
+        args.check_parse_errors(&parsed.parse_errors, &mut problems);
+        parsed.ordering = args.ordering(ordering, parsed.reorder, &mut problems);
+        if parsed.lease_ttl.is_some() && parsed.lease.is_none() {
+            problems.push("--lease-ttl requires --lease".to_string());
+        }
+        if parsed.outbox && (parsed.state.is_none() || parsed.redis.is_none()) {
+            problems.push("--outbox requires --state and --redis".to_string());
+        }
+        match listen {
+            Some(listen) => parsed.listen = listen,
+            None => problems.push("Must provide --listen".to_string()),
+        }
+        args.check_addresses(
+            &[
+                (
+                    "--listen",
+                    Some(parsed.listen.as_str()).filter(|addr| !addr.is_empty()),
+                ),
+                ("--metrics-addr", parsed.metrics_addr.as_deref()),
+                ("--redis", parsed.redis.as_deref()),
+                ("--grpc-addr", parsed.grpc_addr.as_deref()),
+                ("--flight-addr", parsed.flight_addr.as_deref()),
+            ],
+            &mut problems,
+        );
+        args.check(problems)?;
+        Ok(Command::Serve(parsed))
+    }
+
+    fn replica<I: Iterator<Item = String>>(
+        args: &mut Args<I>,
+        global: &mut Global,
+        config: &Config,
+    ) -> Result<Command, anyhow::Error> {
+        args.usage = REPLICA_USAGE;
+        let mut parsed = ReplicaArgs {
+            metrics_addr: config.metrics_addr.clone(),
+            grpc_addr: config.grpc_addr.clone(),
+            flight_addr: config.flight_addr.clone(),
+            reorder: config.reorder,
+            settlement: config.settlement,
+            min_balance: config.min_balance.clone(),
+            tiers: config.tiers.clone(),
+            tier_rules: config.tier_rules.clone(),
+            seed_accounts: config.seed_accounts.clone(),
+            interest_rates: config.interest_rates.clone(),
+            interest_posting: config.interest_posting.unwrap_or_default(),
+            ..Default::default()
+        };
+        let mut input = None;
+
+        while let Some(arg) = args.inner.next() {
+            if args.global(global, &arg)? {
+                continue;
+            }
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help(REPLICA_USAGE)),
+                "--extended" => parsed.extended = true,
+                "--metrics-addr" => parsed.metrics_addr = Some(args.value(&arg)?),
+                "--grpc-addr" => parsed.grpc_addr = Some(args.value(&arg)?),
+                "--flight-addr" => parsed.flight_addr = Some(args.value(&arg)?),
+                "--lease" => parsed.lease = Some(args.value(&arg)?.into()),
+                "--lease-ttl" => parsed.lease_ttl = Some(args.value(&arg)?.parse()?),
+                "--listen" => parsed.listen = Some(args.value(&arg)?),
+                path if input.is_none() && !path.starts_with('-') => input = Some(path.into()),
+                other => return Err(args.unexpected(other)),
+            }
+        }
+
+        let mut problems = Vec::new();
+        parsed.ordering = args.ordering(config.ordering, parsed.reorder, &mut problems);
+        match (&parsed.lease, &parsed.listen) {
+            (Some(_), None) => parsed.listen = config.listen.clone(),
+            (None, Some(_)) => problems.push("--listen requires --lease".to_string()),
+            _ => {}
+        }
+        if parsed.lease.is_some() && parsed.listen.is_none() {
+            problems.push("--lease requires --listen".to_string());
+        }
+        if parsed.lease_ttl.is_some() && parsed.lease.is_none() {
+            problems.push("--lease-ttl requires --lease".to_string());
+        }
+        match input {
+            Some(input) => parsed.event_log = input,
+            None => problems.push("Must provide event log to follow".to_string()),
+        }
+        args.check_addresses(
+            &[
+                ("--listen", parsed.listen.as_deref()),
+                ("--metrics-addr", parsed.metrics_addr.as_deref()),
+                ("--grpc-addr", parsed.grpc_addr.as_deref()),
+                ("--flight-addr", parsed.flight_addr.as_deref()),
+            ],
+            &mut problems,
+        );
+        args.check(problems)?;
+        Ok(Command::Replica(parsed))
+    }
+
+    fn merge<I: Iterator<Item = String>>(
+        args: &mut Args<I>,
+        global: &mut Global,
+    ) -> Result<Command, anyhow::Error> {
+        args.usage = MERGE_USAGE;
+        let mut parsed = MergeArgs::default();
+
+        while let Some(arg) = args.inner.next() {
+            if args.global(global, &arg)? {
+                continue;
+            }
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help(MERGE_USAGE)),
+                path if !path.starts_with('-') => parsed.inputs.push(path.into()),
+                other => return Err(args.unexpected(other)),
+            }
+        }
+
+        if parsed.inputs.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Must provide at least one snapshot\n\n{MERGE_USAGE}"
+            ));
+        }
+        Ok(Command::Merge(parsed))
+    }
+
+    fn diff<I: Iterator<Item = String>>(
+        args: &mut Args<I>,
+        global: &mut Global,
+    ) -> Result<Command, anyhow::Error> {
+        args.usage = DIFF_USAGE;
+        let mut paths: Vec<String> = Vec::new();
+        let mut dir: Option<PathBuf> = None;
+
+        while let Some(arg) = args.inner.next() {
+            if args.global(global, &arg)? {
+                continue;
+            }
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help(DIFF_USAGE)),
+                "--state" => dir = Some(args.value(&arg)?.into()),
+                path if paths.len() < 2 && !path.starts_with('-') => paths.push(path.into()),
+                other => return Err(args.unexpected(other)),
+            }
+        }
+
+        let (old, new) = match (dir, paths.as_slice()) {
+            (None, [old, new]) => (old.into(), new.into()),
+            (Some(dir), [old]) => (
+                state::accounts_file(&dir, Some(&state::label(old)?)),
+                state::accounts_file(&dir, None),
+            ),
+            (Some(dir), [old, new]) => (
+                state::accounts_file(&dir, Some(&state::label(old)?)),
+                state::accounts_file(&dir, Some(&state::label(new)?)),
+            ),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Must provide two snapshots to compare\n\n{DIFF_USAGE}"
+                ))
+            }
+        };
+        Ok(Command::Diff(DiffArgs { old, new }))
+    }
+
+    fn query<I: Iterator<Item = String>>(
+        args: &mut Args<I>,
+        global: &mut Global,
+        config: &Config,
+    ) -> Result<Command, anyhow::Error> {
+        args.usage = QUERY_USAGE;
+        let mut state = config.state.clone();
+        let mut as_of = None;
+        let (mut client, mut tx, mut history) = (None, None, false);
+        let mut simulate = None;
+
+        while let Some(arg) = args.inner.next() {
+            if args.global(global, &arg)? {
+                continue;
+            }
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help(QUERY_USAGE)),
+                "--state" => state = Some(args.value(&arg)?.into()),
+                "--as-of" => as_of = Some(state::label(&args.value(&arg)?)?),
+                "--client" => client = Some(args.value(&arg)?.parse()?),
+                "--tx" => tx = Some(args.value(&arg)?.parse()?),
+                "--history" => history = true,
+                "--simulate" => simulate = Some(parser::message(&args.value(&arg)?)?),
+                other => return Err(args.unexpected(other)),
+            }
+        }
+
+        let state =
+            state.ok_or_else(|| anyhow::anyhow!("Must provide --state\n\n{QUERY_USAGE}"))?;
+        let query = match (client, tx, history, simulate) {
+            (Some(client), None, false, None) => Query::Account(client),
+            (Some(client), None, true, None) => Query::History(client),
+            (None, Some(tx), false, None) => Query::Transaction(tx),
+            (None, None, false, Some(message)) => Query::Simulate(message),
+            _ => {
+                return Err(anyhow::anyhow!(
+                "Must provide either --client, optionally with --history, --tx or --simulate\n\n{QUERY_USAGE}"
+            ))
+            }
+        };
+        Ok(Command::Query(QueryArgs {
+            state,
+            as_of,
+            query,
+        }))
+    }
+
+    fn rollback<I: Iterator<Item = String>>(
+        args: &mut Args<I>,
+        global: &mut Global,
+        config: &Config,
+    ) -> Result<Command, anyhow::Error> {
+        args.usage = ROLLBACK_USAGE;
+        let mut state = config.state.clone();
+        let mut savepoint = None;
+
+        while let Some(arg) = args.inner.next() {
+            if args.global(global, &arg)? {
+                continue;
+            }
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help(ROLLBACK_USAGE)),
+                "--state" => state = Some(args.value(&arg)?.into()),
+                name if savepoint.is_none() && !name.starts_with('-') => {
+                    savepoint = Some(state::label(name)?)
+                }
+                other => return Err(args.unexpected(other)),
+            }
+        }
+
+        let state =
+            state.ok_or_else(|| anyhow::anyhow!("Must provide --state\n\n{ROLLBACK_USAGE}"))?;
+        let savepoint = savepoint
+            .ok_or_else(|| anyhow::anyhow!("Must provide savepoint\n\n{ROLLBACK_USAGE}"))?;
+        Ok(Command::Rollback(RollbackArgs { state, savepoint }))
+    }
+
+    fn compact<I: Iterator<Item = String>>(
+        args: &mut Args<I>,
+        global: &mut Global,
+        config: &Config,
+    ) -> Result<Command, anyhow::Error> {
+        args.usage = COMPACT_USAGE;
+        let mut state = config.state.clone();
+        let mut keep_days = None;
+        let mut dry_run = false;
+
+        while let Some(arg) = args.inner.next() {
+            if args.global(global, &arg)? {
+                continue;
+            }
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help(COMPACT_USAGE)),
+                "--state" => state = Some(args.value(&arg)?.into()),
+                "--keep-days" => keep_days = Some(args.value(&arg)?.parse()?),
+                "--dry-run" => dry_run = true,
+                other => return Err(args.unexpected(other)),
+            }
+        }
+
+        let state =
+            state.ok_or_else(|| anyhow::anyhow!("Must provide --state\n\n{COMPACT_USAGE}"))?;
+        Ok(Command::Compact(CompactArgs {
+            state,
+            keep_days,
+            dry_run,
+        }))
+    }
+
+    fn state<I: Iterator<Item = String>>(
+        args: &mut Args<I>,
+        global: &mut Global,
+        config: &Config,
+    ) -> Result<Command, anyhow::Error> {
+        args.usage = STATE_USAGE;
+        let mut state = config.state.clone();
+        let mut action = None;
+        let mut file = None;
+        let mut force = false;
+
+        while let Some(arg) = args.inner.next() {
+            if args.global(global, &arg)? {
+                continue;
+            }
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help(STATE_USAGE)),
+                "--state" => state = Some(args.value(&arg)?.into()),
+                "--force" => force = true,
+                "export" if action.is_none() => action = Some(StateAction::Export),
+                "import" if action.is_none() => action = Some(StateAction::Import { force: false }),
+                path if action.is_some()
+                    && file.is_none()
+                    && (path == "-" || !path.starts_with('-')) =>
+                {
+                    file = Some(path.into())
+                }
+                other => return Err(args.unexpected(other)),
+            }
+        }
+
+        let action = match action {
+            Some(StateAction::Import { .. }) => StateAction::Import { force },
+            Some(StateAction::Export) if !force => StateAction::Export,
+            Some(StateAction::Export) => {
+                return Err(anyhow::anyhow!(
+                    "--force only applies to import\n\n{STATE_USAGE}"
+                ))
+            }
+            None => {
+                return Err(anyhow::anyhow!(
+                    "Must provide export or import\n\n{STATE_USAGE}"
+                ))
+            }
+        };
+        let state =
+            state.ok_or_else(|| anyhow::anyhow!("Must provide --state\n\n{STATE_USAGE}"))?;
+        let file = file.ok_or_else(|| anyhow::anyhow!("Must provide FILE\n\n{STATE_USAGE}"))?;
+        Ok(Command::State(StateArgs {
+            state,
+            file,
+            action,
+        }))
+    }
+
+    fn corrections<I: Iterator<Item = String>>(
+        args: &mut Args<I>,
+        global: &mut Global,
+        config: &Config,
+    ) -> Result<Command, anyhow::Error> {
+        args.usage = CORRECTIONS_USAGE;
+        let mut parsed = CorrectionsArgs::default();
+        let mut state = config.state.clone();
+        let mut input = None;
+
+        while let Some(arg) = args.inner.next() {
+            if args.global(global, &arg)? {
+                continue;
+            }
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help(CORRECTIONS_USAGE)),
+                "--state" => state = Some(args.value(&arg)?.into()),
+                "--dry-run" => parsed.dry_run = true,
+                "--savepoint" => parsed.savepoint = Some(state::label(&args.value(&arg)?)?),
+                "--force" => parsed.force = true,
+                path if input.is_none() && !path.starts_with('-') => input = Some(path.into()),
+                other => return Err(args.unexpected(other)),
+            }
+        }
+
+        parsed.state =
+            state.ok_or_else(|| anyhow::anyhow!("Must provide --state\n\n{CORRECTIONS_USAGE}"))?;
+        parsed.input = input.ok_or_else(|| {
+            anyhow::anyhow!("Must provide corrections file\n\n{CORRECTIONS_USAGE}")
+        })?;
+        Ok(Command::Corrections(parsed))
+    }
+
+    fn convert<I: Iterator<Item = String>>(
+        args: &mut Args<I>,
+        global: &mut Global,
+    ) -> Result<Command, anyhow::Error> {
+        args.usage = CONVERT_USAGE;
+        let mut parsed = ConvertArgs::default();
+        let mut paths = Vec::new();
+
+        while let Some(arg) = args.inner.next() {
+            if args.global(global, &arg)? {
+                continue;
+            }
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help(CONVERT_USAGE)),
+                "--from" => parsed.from = Some(args.value(&arg)?.parse()?),
+                "--to" => parsed.to = Some(args.value(&arg)?.parse()?),
+                path if paths.len() < 2 && !path.starts_with('-') => paths.push(path.into()),
+                other => return Err(args.unexpected(other)),
+            }
+        }
+
+        let (Some(output), Some(input)) = (paths.pop(), paths.pop()) else {
+            return Err(anyhow::anyhow!(
+                "Must provide input and output files\n\n{CONVERT_USAGE}"
+            ));
+        };
+        parsed.input = input;
+        parsed.output = output;
+        Ok(Command::Convert(parsed))
+    }
+
+    fn replay<I: Iterator<Item = String>>(
+        args: &mut Args<I>,
+        global: &mut Global,
+        config: &Config,
+    ) -> Result<Command, anyhow::Error> {
+        args.usage = REPLAY_USAGE;
+        let mut parsed = ReplayArgs {
+            rate: config.rate,
+            thresholds: config.thresholds,
+            ..Default::default()
+        };
+        let mut input = None;
+
+        while let Some(arg) = args.inner.next() {
+            if args.global(global, &arg)? || args.thresholds(&mut parsed.thresholds, &arg)? {
+                continue;
+            }
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help(REPLAY_USAGE)),
+                "--offset" => parsed.offset = Some(args.value(&arg)?.parse()?),
+                "--until" => parsed.until = Some(args.value(&arg)?.parse()?),
+                "--rate" => parsed.rate = Some(pacing::rate(&args.value(&arg)?)?),
+                path if input.is_none() && !path.starts_with('-') => input = Some(path.into()),
+                other => return Err(args.unexpected(other)),
+            }
+        }
+
+        parsed.event_log = input
+            .ok_or_else(|| anyhow::anyhow!("Must provide event log to replay\n\n{REPLAY_USAGE}"))?;
+        Ok(Command::Replay(parsed))
+    }
+
+    fn statement<I: Iterator<Item = String>>(
+        args: &mut Args<I>,
+        global: &mut Global,
+    ) -> Result<Command, anyhow::Error> {
+        args.usage = STATEMENT_USAGE;
+        let mut parsed = StatementArgs::default();
+        let (mut client, mut input) = (None, None);
+
+        while let Some(arg) = args.inner.next() {
+            if args.global(global, &arg)? {
+                continue;
+            }
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help(STATEMENT_USAGE)),
+                "--client" => client = Some(args.value(&arg)?.parse()?),
+                "--from" => parsed.from = Some(args.value(&arg)?.parse()?),
+                "--to" => parsed.to = Some(args.value(&arg)?.parse()?),
+                path if input.is_none() && !path.starts_with('-') => input = Some(path.into()),
+                other => return Err(args.unexpected(other)),
+            }
+        }
+
+        parsed.client =
+            client.ok_or_else(|| anyhow::anyhow!("Must provide --client\n\n{STATEMENT_USAGE}"))?;
+        parsed.event_log = input.ok_or_else(|| {
+            anyhow::anyhow!("Must provide event log to read\n\n{STATEMENT_USAGE}")
+        })?;
+        Ok(Command::Statement(parsed))
+    }
+
+    fn validate<I: Iterator<Item = String>>(
+        args: &mut Args<I>,
+        global: &mut Global,
+        config: &Config,
+    ) -> Result<Command, anyhow::Error> {
+        args.usage = VALIDATE_USAGE;
+        let mut input = None;
+        let mut lenient_amounts = config.lenient_amounts;
+        let mut amount_unit = config.amount_unit;
+        let mut simulate = false;
+        let mut seed_accounts = config.seed_accounts.clone();
+
+        while let Some(arg) = args.inner.next() {
+            if args.global(global, &arg)? {
+                continue;
+            }
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help(VALIDATE_USAGE)),
+                "--lenient-amounts" => lenient_amounts = true,
+                "--amount-unit" => amount_unit = args.value(&arg)?.parse()?,
+                "--simulate" => simulate = true,
+                "--seed-accounts" => seed_accounts = Some(args.value(&arg)?.into()),
+                path if input.is_none() && !path.starts_with('-') => input = Some(path.into()),
+                other => return Err(args.unexpected(other)),
+            }
+        }
+
+        let input = input.ok_or_else(|| {
+            anyhow::anyhow!("Must provide input file to check\n\n{VALIDATE_USAGE}")
+        })?;
+        Ok(Command::Validate(ValidateArgs {
+            input,
+            lenient_amounts,
+            amount_unit,
+            simulate,
+            seed_accounts,
+        }))
+    }
+
+    fn inspect<I: Iterator<Item = String>>(
+        args: &mut Args<I>,
+        global: &mut Global,
+    ) -> Result<Command, anyhow::Error> {
+        args.usage = INSPECT_USAGE;
+        let mut input = None;
+        let mut rows = 100;
+        let mut output = None;
+
+        while let Some(arg) = args.inner.next() {
+            if args.global(global, &arg)? {
+                continue;
+            }
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help(INSPECT_USAGE)),
+                "--rows" => rows = args.value(&arg)?.parse()?,
+                "-o" | "--output" => output = Some(args.value(&arg)?.into()),
+                path if input.is_none() && !path.starts_with('-') => input = Some(path.into()),
+                other => return Err(args.unexpected(other)),
+            }
+        }
+
+        if rows == 0 {
+            return Err(anyhow::anyhow!(
+                "--rows must be at least 1\n\n{INSPECT_USAGE}"
+            ));
+        }
+        let input = input.ok_or_else(|| {
+            anyhow::anyhow!("Must provide input file to inspect\n\n{INSPECT_USAGE}")
+        })?;
+        Ok(Command::Inspect(InspectArgs {
+            input,
+            rows,
+            output,
+        }))
+    }
+
+    fn generate<I: Iterator<Item = String>>(
+        args: &mut Args<I>,
+        global: &mut Global,
+    ) -> Result<Command, anyhow::Error> {
+        args.usage = GENERATE_USAGE;
+        let mut parsed = GenerateArgs::default();
+
+        while let Some(arg) = args.inner.next() {
+            if args.global(global, &arg)? {
+                continue;
+            }
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help(GENERATE_USAGE)),
+                "--rows" => parsed.rows = args.value(&arg)?.parse()?,
+                "--clients" => parsed.clients = args.value(&arg)?.parse()?,
+                "--dispute-ratio" => parsed.dispute_ratio = args.value(&arg)?.parse()?,
+                "--chargeback-ratio" => parsed.chargeback_ratio = args.value(&arg)?.parse()?,
+                "--seed" => parsed.seed = Some(args.value(&arg)?.parse()?),
+                "--consistent" => parsed.consistent = true,
+                other => return Err(args.unexpected(other)),
+            }
+        }
+
+        if parsed.clients == 0 {
+            return Err(anyhow::anyhow!(
+                "--clients must be at least 1\n\n{GENERATE_USAGE}"
+            ));
+        }
+        if !(0.0..=0.5).contains(&parsed.dispute_ratio)
+            || !(0.0..=1.0).contains(&parsed.chargeback_ratio)
+        {
+            return Err(anyhow::anyhow!(
+                "--dispute-ratio must be within 0..=0.5, --chargeback-ratio within 0..=1\n\n{GENERATE_USAGE}"
+            ));
+        }
+        Ok(Command::Generate(parsed))
+    }
+
+    fn bench<I: Iterator<Item = String>>(
+        args: &mut Args<I>,
+        global: &mut Global,
+    ) -> Result<Command, anyhow::Error> {
+        args.usage = BENCH_USAGE;
+        let mut parsed = BenchArgs {
+            runs: 1,
+            ..BenchArgs::default()
+        };
+        let mut input = None;
+
+        while let Some(arg) = args.inner.next() {
+            // Configurations are those of runs, rather than global.
+            if arg == "--config" {
+                parsed.configs.push(args.value(&arg)?.into());
+                continue;
+            }
+            if args.global(global, &arg)? {
+                continue;
+            }
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help(BENCH_USAGE)),
+                "--input" => input = Some(PathBuf::from(args.value(&arg)?)),
+                "--runs" => match args.value(&arg)?.parse()? {
+                    0 => return Err(anyhow::anyhow!("--runs must be positive\n\n{BENCH_USAGE}")),
+                    runs => parsed.runs = runs,
+                },
+                "--seed" => parsed.seed = Some(args.value(&arg)?.parse()?),
+                "--" => parsed.process.extend(args.inner.by_ref()),
+                other => return Err(args.unexpected(other)),
+            }
+        }
+
+        parsed.input =
+            input.ok_or_else(|| anyhow::anyhow!("Must provide --input\n\n{BENCH_USAGE}"))?;
+        Ok(Command::Bench(parsed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Chaos, Cli, Command, Policy, ProcessArgs, Query, QueryArgs, StateAction, Velocity,
+    };
+    use crate::format::Format;
+    use crate::log::Level;
+    use crate::ordering;
+    use crate::Message;
+
+    fn parse(args: &[&str]) -> Result<Cli, anyhow::Error> {
+        Cli::parse_from(args.iter().map(|arg| arg.to_string()), [])
+    }
+
+    #[test]
+    fn verbosity_flags_set_log_level() {
+        let level = |args: &[&str]| parse(args).unwrap().global.log_level();
+        assert_eq!(level(&["in.csv"]), None);
+        assert_eq!(level(&["-q", "in.csv"]), Some(Level::Error));
+        assert_eq!(level(&["process", "-v", "in.csv"]), Some(Level::Info));
+        assert_eq!(level(&["-vv", "process", "in.csv"]), Some(Level::Debug));
+        assert_eq!(
+            level(&["-v", "process", "--verbose", "in.csv", "-v"]),
+            Some(Level::Trace)
+        );
+        let long = format!("-{}", "v".repeat(300));
+        assert_eq!(level(&[&long, "in.csv"]), Some(Level::Trace));
+    }
+
+    #[test]
+    fn inputs_are_not_taken_for_verbosity_flags() {
+        for input in ["xv", "é.csv", "vv"] {
+            let cli = parse(&["process", "--quiet", input]).unwrap();
+            assert!(
+                matches!(&cli.command, Command::Process(args) if args.input.to_str() == Some(input)),
+                "{input}"
+            );
+            assert!(cli.global.quiet());
+        }
+    }
+
+    #[test]
+    fn quiet_conflicts_with_progress() {
+        assert!(parse(&["-q", "--progress", "in.csv"]).is_err());
+        assert!(parse(&["process", "--dashboard", "in.csv", "-q"]).is_err());
+    }
+
+    #[test]
+    fn bare_input_is_processed() {
+        let cli = parse(&["in.csv", "--progress"]).unwrap();
+        assert!(
+            matches!(cli.command, Command::Process(args) if args.progress && args.input.to_str() == Some("in.csv"))
+        );
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        let cli = parse(&["serve", "--listen", "127.0.0.1:7878"]).unwrap();
+        assert!(matches!(cli.command, Command::Serve(args) if args.listen == "127.0.0.1:7878"));
+        let cli = parse(&[
+            "serve",
+            "--listen",
+            ":7878",
+            "--backfill",
+            "history.csv",
+            "--cutover",
+            "1700000000000",
+        ])
+        .unwrap();
+        assert!(
+            matches!(cli.command, Command::Serve(args) if args.backfill.is_some() && args.cutover == Some(1_700_000_000_000))
+        );
+        assert!(parse(&["serve", "--listen", ":7878", "--cutover", "1700000000000"]).is_err());
+        let cli = parse(&[
+            "serve",
+            "--listen",
+            ":7878",
+            "--state",
+            "run",
+            "--evict-after",
+            "60000",
+        ])
+        .unwrap();
+        assert!(matches!(cli.command, Command::Serve(args) if args.evict_after == Some(60_000)));
+        assert!(parse(&["serve", "--listen", ":7878", "--evict-after", "60000"]).is_err());
+        assert!(parse(&[
+            "serve",
+            "--listen",
+            ":7878",
+            "--state",
+            "run",
+            "--evict-after",
+            "60000",
+            "--interest-rates",
+            "0.05"
+        ])
+        .is_err());
+        let cli = parse(&[
+            "serve",
+            "--listen",
+            ":7878",
+            "--stream",
+            "--stream-threshold",
+            "10000",
+        ])
+        .unwrap();
+        assert!(
+            matches!(cli.command, Command::Serve(args) if args.stream && args.stream_threshold == Some(10_000.0))
+        );
+        assert!(parse(&["serve", "--listen", ":7878", "--stream-threshold", "10000"]).is_err());
+
+        let cli = parse(&["process", "--sample", "1%", "in.csv"]).unwrap();
+        assert!(matches!(cli.command, Command::Process(args) if args.sample == Some(0.01)));
+        assert!(parse(&["process", "--sample", "1%", "--state", "run", "in.csv"]).is_err());
+
+        let cli = parse(&["process", "--max-memory", "512MiB", "in.csv"]).unwrap();
+        assert!(
+            matches!(cli.command, Command::Process(args) if args.max_memory == Some(512 << 20))
+        );
+        assert!(parse(&["process", "--max-memory", "lots", "in.csv"]).is_err());
+
+        let cli = parse(&[
+            "bench", "--config", "a.toml", "--config", "b.toml", "--input", "big.csv", "--",
+            "--shards", "4",
+        ])
+        .unwrap();
+        assert!(
+            matches!(cli.command, Command::Bench(args) if args.configs.len() == 2 && args.runs == 1 && args.process == ["--shards", "4"])
+        );
+        assert!(parse(&["bench", "--config", "a.toml"]).is_err());
+
+        let cli = parse(&["merge", "a.csv", "b.csv"]).unwrap();
+        assert!(matches!(cli.command, Command::Merge(args) if args.inputs.len() == 2));
+
+        let cli = parse(&["diff", "old.csv", "new.csv"]).unwrap();
+        assert!(
+            matches!(cli.command, Command::Diff(args) if args.old.to_str() == Some("old.csv") && args.new.to_str() == Some("new.csv"))
+        );
+
+        let cli = parse(&["diff", "--state", "run", "2024-06-30"]).unwrap();
+        assert!(
+            matches!(cli.command, Command::Diff(args) if args.old.ends_with("run/as-of/2024-06-30/accounts.csv") && args.new.ends_with("run/accounts.csv"))
+        );
+        assert!(parse(&["diff", "--state", "run", "../run"]).is_err());
+
+        let cli = parse(&["query", "--state", "run", "--as-of", "q2", "--client", "42"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::Query(QueryArgs {
+                query: Query::Account(42),
+                as_of: Some(label),
+                ..
+            }) if label == "q2"
+        ));
+        assert!(parse(&["process", "--as-of", "q2", "in.csv"]).is_err());
+        assert!(parse(&["process", "--savepoint", "fix", "in.csv"]).is_err());
+        let cli = parse(&["process", "--shards", "4", "--shard-dir", "out", "in.csv"]).unwrap();
+        assert!(
+            matches!(cli.command, Command::Process(args) if args.shards.as_ref().is_some_and(|shards| shards.count == 4))
+        );
+        assert!(parse(&["process", "--shards", "4", "in.csv"]).is_err());
+        let cli = parse(&["process", "--on-parse-error", "abort-after:5", "in.csv"]).unwrap();
+        assert!(
+            matches!(cli.command, Command::Process(args) if args.parse_errors.policy == Policy::AbortAfter(5))
+        );
+        assert!(parse(&["process", "--on-parse-error", "quarantine", "in.csv"]).is_err());
+        assert!(parse(&["process", "--shards", "0", "--shard-dir", "out", "in.csv"]).is_err());
+        let cli = parse(&["process", "--reorder-lateness", "5", "in.csv"]).unwrap();
+        assert!(
+            matches!(cli.command, Command::Process(args) if args.ordering == ordering::Policy::TimestampMerge)
+        );
+        let cli = parse(&[
+            "serve",
+            "--listen",
+            "a:1",
+            "--ordering",
+            "strict-per-client",
+        ])
+        .unwrap();
+        assert!(
+            matches!(cli.command, Command::Serve(args) if args.ordering == ordering::Policy::StrictPerClient)
+        );
+        assert!(parse(&[
+            "process",
+            "--ordering",
+            "best-effort",
+            "--reorder-lateness",
+            "5",
+            "in.csv"
+        ])
+        .is_err());
+
+        let cli = parse(&["rollback", "--state", "run", "fix"]).unwrap();
+        assert!(
+            matches!(cli.command, Command::Rollback(args) if args.state.to_str() == Some("run") && args.savepoint == "fix")
+        );
+        assert!(parse(&["rollback", "--state", "run"]).is_err());
+
+        let cli = parse(&["replica", "--grpc-addr", ":50051", "events.csv"]).unwrap();
+        assert!(
+            matches!(cli.command, Command::Replica(args) if args.event_log.to_str() == Some("events.csv") && args.grpc_addr.as_deref() == Some(":50051"))
+        );
+        assert!(parse(&["replica", "--grpc-addr", ":50051"]).is_err());
+        assert!(parse(&["replica", "--listen", ":7878", "events.csv"]).is_err());
+        assert!(parse(&["replica", "--state", "run", "events.csv"]).is_err());
+        let cli = parse(&[
+            "replica",
+            "--lease",
+            "lease",
+            "--listen",
+            ":7878",
+            "events.csv",
+        ])
+        .unwrap();
+        assert!(
+            matches!(cli.command, Command::Replica(args) if args.lease.is_some() && args.listen.as_deref() == Some(":7878"))
+        );
+        assert!(parse(&["replica", "--lease", "lease", "events.csv"]).is_err());
+        assert!(parse(&["replica", "--lease-ttl", "1000", "events.csv"]).is_err());
+        assert!(parse(&["serve", "--listen", ":7878", "--lease-ttl", "1000"]).is_err());
+        let cli = parse(&[
+            "serve", "--listen", ":7878", "--state", "run", "--redis", ":6379", "--outbox",
+        ])
+        .unwrap();
+        assert!(matches!(cli.command, Command::Serve(args) if args.outbox));
+        assert!(parse(&["serve", "--listen", ":7878", "--redis", ":6379", "--outbox"]).is_err());
+
+        let cli = parse(&["compact", "--state", "run", "--keep-days", "90"]).unwrap();
+        assert!(
+            matches!(cli.command, Command::Compact(args) if args.state.to_str() == Some("run") && args.keep_days == Some(90) && !args.dry_run)
+        );
+        assert!(parse(&["compact", "--keep-days", "90"]).is_err());
+
+        let cli = parse(&["state", "import", "--force", "--state", "local", "-"]).unwrap();
+        assert!(
+            matches!(cli.command, Command::State(args) if args.state.to_str() == Some("local") && args.file.to_str() == Some("-") && args.action == StateAction::Import { force: true })
+        );
+        let cli = parse(&["state", "export", "--state", "run", "state.trp"]).unwrap();
+        assert!(matches!(cli.command, Command::State(args) if args.action == StateAction::Export));
+        assert!(parse(&["state", "--state", "run", "state.trp"]).is_err());
+        assert!(parse(&["state", "export", "--state", "run"]).is_err());
+        assert!(parse(&["state", "export", "--force", "--state", "run", "state.trp"]).is_err());
+        assert!(parse(&["compact", "--state", "run", "--keep-days", "-1"]).is_err());
+
+        let cli = parse(&[
+            "apply-corrections",
+            "--state",
+            "run",
+            "--dry-run",
+            "fixes.csv",
+        ])
+        .unwrap();
+        assert!(
+            matches!(cli.command, Command::Corrections(args) if args.dry_run && args.input.to_str() == Some("fixes.csv"))
+        );
+        assert!(parse(&["apply-corrections", "fixes.csv"]).is_err());
+
+        let cli = parse(&["query", "--state", "run", "--client", "42", "--history"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::Query(QueryArgs {
+                query: Query::History(42),
+                ..
+            })
+        ));
+
+        let cli = parse(&[
+            "query",
+            "--state",
+            "run",
+            "--simulate",
+            "withdrawal, 7, 42, 50.0",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::Query(QueryArgs {
+                query: Query::Simulate(Message::Withdraw { client: 7, tx: 42, amount, .. }),
+                ..
+            }) if amount == 50.0
+        ));
+
+        let cli = parse(&["convert", "in.csv", "out.dat", "--to", "ndjson"]).unwrap();
+        assert!(
+            matches!(cli.command, Command::Convert(args) if args.to == Some(Format::Ndjson) && args.from.is_none())
+        );
+        assert!(parse(&["convert", "in.csv", "out.parquet", "--to", "parquet"]).is_err());
+
+        let cli = parse(&[
+            "replay",
+            "events.csv",
+            "--offset",
+            "10",
+            "--max-rejects",
+            "0",
+        ])
+        .unwrap();
+        assert!(
+            matches!(cli.command, Command::Replay(args) if args.offset == Some(10) && args.thresholds.max_rejects == Some(0))
+        );
+        assert!(parse(&["in.csv", "--max-reject-rate", "1.5"]).is_err());
+        let cli = parse(&["replay", "--strict", "events.csv"]).unwrap();
+        assert!(matches!(cli.command, Command::Replay(args) if args.thresholds.strict));
+        let cli = parse(&["replay", "--rate", "500", "events.csv"]).unwrap();
+        assert!(matches!(cli.command, Command::Replay(args) if args.rate == Some(500.0)));
+        assert!(parse(&["in.csv", "--rate", "0"]).is_err());
+
+        let cli = parse(&["process", "--reference", "in.csv"]).unwrap();
+        assert!(matches!(cli.command, Command::Process(args) if args.reference));
+        let cli = parse(&["process", "--two-pass", "in.csv"]).unwrap();
+        assert!(matches!(cli.command, Command::Process(args) if args.two_pass));
+
+        let cli = parse(&["in.csv", "--chaos-drop-rate", "0.5", "--chaos-seed", "3"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::Process(ProcessArgs {
+                chaos: Some(Chaos {
+                    drop_rate,
+                    seed: Some(3),
+                    ..
+                }),
+                ..
+            }) if drop_rate == 0.5
+        ));
+        assert!(parse(&["in.csv", "--chaos-kill-rate", "2"]).is_err());
+
+        let cli = parse(&[
+            "in.csv",
+            "--max-withdrawals",
+            "5",
+            "--velocity-window",
+            "20",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::Process(ProcessArgs {
+                velocity: Velocity {
+                    window: 20,
+                    max_withdrawals: Some(5),
+                    max_withdrawn: None,
+                },
+                ..
+            })
+        ));
+        assert!(parse(&["in.csv", "--velocity-window", "0"]).is_err());
+
+        let cli = parse(&["validate", "in.csv"]).unwrap();
+        assert!(
+            matches!(cli.command, Command::Validate(args) if args.input.to_str() == Some("in.csv"))
+        );
+
+        let cli = parse(&["inspect", "--rows", "10", "-o", "map.toml", "in.csv"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::Inspect(args) if args.rows == 10 && args.output.is_some()
+        ));
+        assert!(parse(&["inspect", "--rows", "0", "in.csv"]).is_err());
+
+        assert!(parse(&["serve"]).is_err());
+        assert!(parse(&["merge"]).is_err());
+        assert!(parse(&["diff", "old.csv"]).is_err());
+        assert!(parse(&["query", "--state", "run", "--client", "1", "--tx", "2"]).is_err());
+        assert!(parse(&["query", "--client", "1"]).is_err());
+        assert!(parse(&["query", "--state", "run", "--simulate", "dispute,7,42,1.0"]).is_err());
+        assert!(parse(&["validate", "a.csv", "b.csv"]).is_err());
+        assert!(parse(&["generate", "--clients", "0"]).is_err());
+        assert!(parse(&["generate", "--dispute-ratio", "0.9"]).is_err());
+        assert!(parse(&["process", "--bogus", "in.csv"]).is_err());
+    }
+
+    #[test]
+    fn problems_with_options_are_reported_together() {
+        let err = parse(&[
+            "serve",
+            "--listen",
+            ":7878",
+            "--redis",
+            "localhost",
+            "--outbox",
+            "--cutover",
+            "1700000000000",
+        ])
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.starts_with("3 problems:\n  - --cutover requires --backfill\n  - --outbox requires --state and --redis\n  - --redis must be host:port, not localhost\n\n"),
+            "{err}"
+        );
+        // A single problem reads as it always did.
+        let err = parse(&["process", "--as-of", "q2", "in.csv"])
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("--as-of requires --state\n\n"), "{err}");
+        assert!(parse(&["replica", "--metrics-addr", "9100", "events.csv"]).is_err());
+    }
+
+    #[test]
+    fn flags_override_config() {
+        let path = std::env::temp_dir().join(format!("trp-cli-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[log]\nlevel = \"info\"\n[source]\npath = \"in.csv\"\n[metrics]\naddr = \"a:1\"\nfile = \"m.prom\"\n[engine]\naccount_channel_size = 8\n",
+        )
+        .unwrap();
+        let config = &path.to_str().unwrap().to_string();
+
+        let cli = parse(&["process", "--config", config, "--metrics-addr", "b:2"]).unwrap();
+        assert_eq!(cli.global.log_level(), Some(Level::Info));
+        assert_eq!(cli.global.engine.account_channel_size, 8);
+        assert!(matches!(cli.command, Command::Process(args)
+            if args.input.to_str() == Some("in.csv")
+                && args.metrics_addr.as_deref() == Some("b:2")
+                && args.metrics_file.is_some()));
+
+        let cli = parse(&["-q", "--config", config, "other.csv"]).unwrap();
+        assert_eq!(cli.global.log_level(), Some(Level::Error));
+        assert!(
+            matches!(cli.command, Command::Process(args) if args.input.to_str() == Some("other.csv"))
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(parse(&["--config", config, "in.csv"]).is_err());
+
+        let env = [
+            ("TRP_SOURCE_PATH", "env.csv"),
+            ("TRP_METRICS_ADDR", "c:3"),
+            ("TRP_LOG_LEVEL", "debug"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let args = ["process", "--metrics-addr", "b:2"].map(String::from);
+        let cli = Cli::parse_from(args, env).unwrap();
+        assert_eq!(cli.global.log_level(), Some(Level::Debug));
+        assert!(matches!(cli.command, Command::Process(args)
+            if args.input.to_str() == Some("env.csv") && args.metrics_addr.as_deref() == Some("b:2")));
+    }
+
+    #[test]
+    fn help_is_command_specific() {
+        assert!(
+            matches!(parse(&["help", "serve"]).unwrap().command, Command::Help(usage) if usage.contains("--listen"))
+        );
+        assert!(
+            matches!(parse(&["merge", "--help"]).unwrap().command, Command::Help(usage) if usage.contains("SNAPSHOT"))
+        );
+        assert!(
+            matches!(parse(&["-h"]).unwrap().command, Command::Help(usage) if usage.contains("Commands:"))
+        );
+    }
+}
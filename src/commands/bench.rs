@@ -0,0 +1,207 @@
+//! `trp bench`: processes the same input under several configurations, for comparing tunables
+//! of the engine.
+//!
+//! Configuration of the engine is set once per process, so every run is a `trp process` of
+//! its own, one after another, given the same seed, see [`rng`](crate::rng). A run is timed
+//! from spawn to exit, messages are counted from its metrics file, and its peak resident
+//! memory is sampled from `/proc` while it runs, so it's only known on Linux.
+
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cli::{BenchArgs, Global},
+    log, rng,
+};
+
+/// How often memory of a run is sampled.
+const POLL: Duration = Duration::from_millis(10);
+
+/// Directory of files of the runs, removed once benchmarking is over, however it ends.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn create() -> Result<Self, anyhow::Error> {
+        let dir = std::env::temp_dir().join(format!("trp-bench-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        Ok(Scratch(dir))
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Fastest run of a configuration.
+#[derive(Debug, Serialize)]
+struct Row {
+    config: String,
+    seconds: String,
+    messages: u64,
+    messages_per_second: String,
+    peak_rss_kib: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Measurement {
+    elapsed: Duration,
+    messages: u64,
+    peak_rss_kib: Option<u64>,
+}
+
+pub fn run(global: &Global, args: BenchArgs) -> Result<(), anyhow::Error> {
+    let seed = rng::seed(args.seed);
+    let span = log::Span::new("bench");
+    log::info!(span, seed = seed, runs = args.runs; "Benchmarking {}", args.input.display());
+    let exe = std::env::current_exe()?;
+    let scratch = Scratch::create()?;
+    let metrics = scratch.0.join("metrics.prom");
+
+    let configs: Vec<Option<&Path>> = if args.configs.is_empty() {
+        vec![None]
+    } else {
+        args.configs
+            .iter()
+            .map(|config| Some(config.as_path()))
+            .collect()
+    };
+    let mut rows = Vec::new();
+    for config in configs {
+        let mut fastest: Option<Measurement> = None;
+        for _ in 0..args.runs {
+            let measurement = measure(&exe, config, &args, seed, &metrics)?;
+            if fastest.is_none_or(|fastest| measurement.elapsed < fastest.elapsed) {
+                fastest = Some(measurement);
+            }
+        }
+        let Some(fastest) = fastest else {
+            continue;
+        };
+        let seconds = fastest.elapsed.as_secs_f64();
+        rows.push(Row {
+            config: name(config),
+            seconds: format!("{seconds:.3}"),
+            messages: fastest.messages,
+            messages_per_second: format!("{:.0}", fastest.messages as f64 / seconds),
+            peak_rss_kib: fastest.peak_rss_kib,
+        });
+    }
+    drop(scratch);
+
+    let mut out = csv::Writer::from_writer(std::io::stdout());
+    for row in &rows {
+        out.serialize(row)?;
+    }
+    out.flush()?;
+    if !global.quiet() {
+        eprintln!("Runs seeded with {seed}, reproduce one with trp process --seed {seed}");
+    }
+    Ok(())
+}
+
+/// Runs `trp process` over input of `args` with `config`, writing metrics to `metrics`.
+fn measure(
+    exe: &Path,
+    config: Option<&Path>,
+    args: &BenchArgs,
+    seed: u64,
+    metrics: &Path,
+) -> Result<Measurement, anyhow::Error> {
+    let mut command = Command::new(exe);
+    command.arg("process").arg("--quiet");
+    if let Some(config) = config {
+        command.arg("--config").arg(config);
+    }
+    command
+        .arg("--seed")
+        .arg(seed.to_string())
+        .arg("--metrics-file")
+        .arg(metrics)
+        .args(&args.process)
+        .arg(&args.input)
+        .stdout(Stdio::null());
+
+    let started = Instant::now();
+    let mut child = command.spawn()?;
+    let mut peak_rss_kib = None;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        peak_rss_kib = peak_rss_kib.max(peak_rss(child.id()));
+        thread::sleep(POLL);
+    };
+    let elapsed = started.elapsed();
+    if !status.success() {
+        return Err(anyhow::anyhow!(
+            "Run with configuration {} failed, {status}",
+            name(config)
+        ));
+    }
+    Ok(Measurement {
+        elapsed,
+        messages: messages(&std::fs::read_to_string(metrics)?),
+        peak_rss_kib,
+    })
+}
+
+/// Name of `config` in output, `-` for runs without configuration.
+fn name(config: Option<&Path>) -> String {
+    config.map_or_else(|| "-".to_string(), |config| config.display().to_string())
+}
+
+/// Peak resident memory of process `pid` so far, in KiB, `None` when it can't be told.
+fn peak_rss(pid: u32) -> Option<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Parsed messages of every kind, counted in metrics `rendered` in Prometheus text format.
+fn messages(rendered: &str) -> u64 {
+    rendered
+        .lines()
+        .filter(|line| line.starts_with("trp_messages_total{"))
+        .filter_map(|line| line.rsplit(' ').next()?.parse::<u64>().ok())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{messages, Scratch};
+
+    #[test]
+    fn messages_are_counted_from_metrics() {
+        let rendered = "# TYPE trp_messages_total counter\n\
+                        trp_messages_total{kind=\"deposit\"} 7\n\
+                        trp_messages_total{kind=\"withdrawal\"} 3\n\
+                        trp_rejects_total{code=\"PE_INSF\"} 1\n";
+        assert_eq!(messages(rendered), 10);
+    }
+
+    #[test]
+    fn scratch_is_removed_when_dropped() {
+        let scratch = Scratch::create().unwrap();
+        let dir = scratch.0.clone();
+        std::fs::write(dir.join("metrics.prom"), "").unwrap();
+        let failed = || -> Result<(), anyhow::Error> {
+            let _scratch = scratch;
+            anyhow::bail!("run failed")
+        };
+        assert!(failed().is_err());
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,87 @@
+//! `trp compact`: prunes transaction history of state persisted by previous runs. Charged back
+//! deposits and settled withdrawals are pruned, along with whatever else
+//! [`retention`](crate::retention) of the engine configuration doesn't keep, or deposits not
+//! disputed for `--keep-days`.
+//!
+//! Both the state and accounts parked in it by `trp serve --evict-after` are compacted, and
+//! the latest timestamp of either is the one deposits are aged from. Balances are left as they
+//! are, as are snapshots and savepoints, which are copies kept on purpose.
+
+use crate::{
+    cli::{CompactArgs, Global},
+    config, dormant, log,
+    retention::{self, Retention},
+    state::{self, TransactionRecord, TransactionState},
+};
+
+pub fn run(global: &Global, args: CompactArgs) -> Result<(), anyhow::Error> {
+    let dir = &args.state;
+    let span = log::Span::new("compact");
+    let retention = args
+        .keep_days
+        .map_or(config::engine().retention, Retention::Undisputed);
+    let mut accounts = state::accounts(dir)?;
+    let mut transactions = state::all_transactions(dir)?;
+    let mut parked = dormant::histories(dir)?;
+    let now = retention::now(
+        transactions
+            .iter()
+            .chain(parked.iter().flat_map(|(_, history)| history)),
+    );
+
+    let total = transactions.len()
+        + parked
+            .iter()
+            .map(|(_, history)| history.len())
+            .sum::<usize>();
+    let mut pruned = prune(&mut transactions, retention, now).len();
+    let mut rewritten = Vec::new();
+    for (path, history) in &mut parked {
+        let gone = prune(history, retention, now);
+        if !gone.is_empty() {
+            pruned += gone.len();
+            rewritten.push((path, history, gone));
+        }
+    }
+
+    if !args.dry_run {
+        state::replace(dir, &mut accounts, &mut transactions)?;
+        for (path, history, gone) in rewritten {
+            dormant::rewrite(path, history, &gone)?;
+        }
+        log::info!(span, pruned = pruned, kept = total - pruned; "Compacted {}", dir.display());
+    }
+    if !global.quiet() {
+        let verb = if args.dry_run {
+            "Would prune"
+        } else {
+            "Pruned"
+        };
+        eprintln!(
+            "{verb} {pruned} of {total} transactions, {} kept",
+            total - pruned
+        );
+    }
+    Ok(())
+}
+
+/// Drops from `transactions` what compaction doesn't keep with `retention` as of `now`,
+/// returns ids of those dropped.
+fn prune(
+    transactions: &mut Vec<TransactionRecord>,
+    retention: Retention,
+    now: Option<u64>,
+) -> Vec<u32> {
+    let mut pruned = Vec::new();
+    transactions.retain(|transaction| {
+        let kept = !matches!(
+            transaction.state,
+            TransactionState::Reversed | TransactionState::Withdrawn
+        ) && retention.keeps(transaction.state, transaction.timestamp, now);
+        if !kept {
+            pruned.push(transaction.tx);
+        }
+        kept
+    });
+    pruned
+}
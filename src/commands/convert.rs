@@ -0,0 +1,27 @@
+//! `trp convert`: translates transaction files between [`Format`]s.
+
+use crate::{
+    cli::ConvertArgs,
+    config,
+    format::{self, Format},
+    log,
+};
+
+pub fn run(args: ConvertArgs) -> Result<(), anyhow::Error> {
+    let from = args.from.map_or_else(|| Format::of(&args.input), Ok)?;
+    let to = args.to.map_or_else(|| Format::of(&args.output), Ok)?;
+    let span = log::Span::new("convert").with("input", args.input.display());
+
+    let mut source = format::source(&args.input, from, config::engine())?;
+    let mut sink = format::sink(&args.output, to)?;
+    let mut rows = 0;
+    while let Some(record) = source.next_record() {
+        rows += 1;
+        let record = record.map_err(|err| anyhow::anyhow!("Failed to read row {rows}: {err}"))?;
+        sink.write(&record)?;
+    }
+    sink.flush()?;
+
+    log::info!(span, rows = rows; "Converted {from:?} to {to:?}");
+    Ok(())
+}
@@ -0,0 +1,267 @@
+//! `trp apply-corrections`: applies manual corrections to state persisted by previous runs,
+//! with an audit entry for every one of them.
+//!
+//! Corrections are read from a csv of `kind`, `client`, `tx`, `amount` and `reason`, where
+//! `reason` is mandatory:
+//! - `adjustment` moves available and total funds of the client by `amount`, which may be
+//!   negative.
+//! - `unlock` unlocks the account of the client.
+//! - `reversal` reverses deposit `tx` of the client, whether it's disputed or not.
+//!
+//! Either all corrections of the file apply, or none of them do.
+
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    cli::{CorrectionsArgs, Global},
+    state::{self, AccountRecord, AuditRecord, InputRecord, TransactionRecord, TransactionState},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Adjustment,
+    Unlock,
+    Reversal,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Adjustment => "adjustment",
+            Kind::Unlock => "unlock",
+            Kind::Reversal => "reversal",
+        }
+    }
+}
+
+/// Row of a corrections file.
+#[derive(Debug, Clone, Deserialize)]
+struct Correction {
+    kind: Kind,
+    client: u16,
+    tx: Option<u32>,
+    amount: Option<f32>,
+    reason: String,
+}
+
+pub fn run(global: &Global, args: CorrectionsArgs) -> Result<(), anyhow::Error> {
+    let dir = &args.state;
+    let hash = state::digest(&args.input)?;
+    if let Some(input) = state::input(dir, &hash)? {
+        if !args.force {
+            return Err(anyhow::anyhow!(
+                "{} was already applied to state in {}, as {} at {}ms, use --force to apply it again",
+                args.input.display(),
+                dir.display(),
+                input.path,
+                input.applied
+            ));
+        }
+    }
+    if let Some(name) = &args.savepoint {
+        if state::savepoint_dir(dir, name).exists() && !args.force {
+            return Err(anyhow::anyhow!(
+                "Savepoint {name} already exists in {}, use --force to replace it",
+                dir.display()
+            ));
+        }
+    }
+
+    let corrections = read(&args.input)?;
+    let before: BTreeMap<u16, AccountRecord> = state::accounts(dir)?
+        .into_iter()
+        .map(|account| (account.client, account))
+        .collect();
+    let mut accounts = before.clone();
+    let mut transactions: BTreeMap<u32, TransactionRecord> = state::all_transactions(dir)?
+        .into_iter()
+        .map(|transaction| (transaction.tx, transaction))
+        .collect();
+    for (line, correction) in &corrections {
+        apply(correction, &mut accounts, &mut transactions)
+            .map_err(|err| anyhow::anyhow!("{} line {line}: {err}", args.input.display()))?;
+    }
+
+    super::diff::print(global, &super::diff::compare(&before, &accounts))?;
+    if args.dry_run {
+        return Ok(());
+    }
+
+    let applied = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64);
+    let source = args.input.display().to_string();
+    if let Some(name) = &args.savepoint {
+        state::savepoint(dir, name)?;
+    }
+    state::replace(
+        dir,
+        &mut accounts.into_values().collect::<Vec<_>>(),
+        &mut transactions.into_values().collect::<Vec<_>>(),
+    )?;
+    let trail: Vec<AuditRecord> = corrections
+        .into_iter()
+        .map(|(line, correction)| AuditRecord {
+            applied,
+            source: source.clone(),
+            line,
+            kind: correction.kind.name().to_string(),
+            client: correction.client,
+            tx: correction.tx,
+            amount: correction.amount,
+            reason: correction.reason,
+        })
+        .collect();
+    state::audit(dir, &trail)?;
+    state::applied(
+        dir,
+        InputRecord {
+            hash,
+            path: source,
+            applied,
+            seed: None,
+        },
+    )
+}
+
+/// Every correction of the file at `path`, along with its line.
+fn read(path: &Path) -> Result<Vec<(u64, Correction)>, anyhow::Error> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .map_err(|err| anyhow::anyhow!("Failed to read {}: {err}", path.display()))?;
+    let headers = rdr.headers()?.clone();
+    let mut corrections = Vec::new();
+    for row in rdr.records() {
+        let row = row?;
+        let line = row.position().map_or(0, |position| position.line());
+        let correction: Correction = row
+            .deserialize(Some(&headers))
+            .map_err(|err| anyhow::anyhow!("{} line {line}: {err}", path.display()))?;
+        if correction.reason.is_empty() {
+            return Err(anyhow::anyhow!(
+                "{} line {line}: correction has no reason",
+                path.display()
+            ));
+        }
+        corrections.push((line, correction));
+    }
+    Ok(corrections)
+}
+
+/// Applies `correction` to `accounts` and `transactions`. Fails when it doesn't apply, e.g.
+/// for a client without an account.
+fn apply(
+    correction: &Correction,
+    accounts: &mut BTreeMap<u16, AccountRecord>,
+    transactions: &mut BTreeMap<u32, TransactionRecord>,
+) -> Result<(), anyhow::Error> {
+    let client = correction.client;
+    let account = accounts
+        .get_mut(&client)
+        .ok_or_else(|| anyhow::anyhow!("client {client} has no account"))?;
+    match correction.kind {
+        Kind::Adjustment => {
+            let amount = correction
+                .amount
+                .filter(|amount| amount.is_finite())
+                .ok_or_else(|| anyhow::anyhow!("adjustment has no amount"))?;
+            account.available += amount;
+            account.total += amount;
+        }
+        Kind::Unlock => {
+            if !account.locked {
+                return Err(anyhow::anyhow!("account of client {client} is not locked"));
+            }
+            account.locked = false;
+        }
+        Kind::Reversal => {
+            let tx = correction
+                .tx
+                .ok_or_else(|| anyhow::anyhow!("reversal has no tx"))?;
+            let transaction = transactions
+                .get_mut(&tx)
+                .filter(|transaction| transaction.client == client)
+                .ok_or_else(|| anyhow::anyhow!("client {client} has no transaction {tx}"))?;
+            match transaction.state {
+                TransactionState::Deposited => account.available -= transaction.amount,
+                TransactionState::Disputed => account.held -= transaction.amount,
+                state => {
+                    return Err(anyhow::anyhow!(
+                        "transaction {tx} is {state:?}, only deposits are reversed"
+                    ))
+                }
+            }
+            account.total -= transaction.amount;
+            transaction.state = TransactionState::Reversed;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, Correction, Kind};
+    use crate::state::{AccountRecord, TransactionRecord, TransactionState};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn corrections_apply_to_balances() {
+        let mut accounts = BTreeMap::from([(
+            1,
+            AccountRecord {
+                client: 1,
+                available: 5.0,
+                held: 2.0,
+                total: 7.0,
+                locked: true,
+            },
+        )]);
+        let deposit = |tx, state| TransactionRecord {
+            tx,
+            client: 1,
+            state,
+            amount: 2.0,
+            timestamp: None,
+        };
+        let mut transactions = BTreeMap::from([
+            (1, deposit(1, TransactionState::Deposited)),
+            (2, deposit(2, TransactionState::Disputed)),
+            (3, deposit(3, TransactionState::Reversed)),
+        ]);
+        let correction = |kind, tx, amount| Correction {
+            kind,
+            client: 1,
+            tx,
+            amount,
+            reason: "ticket 1".to_string(),
+        };
+        let mut fix = |correction| apply(&correction, &mut accounts, &mut transactions);
+
+        fix(correction(Kind::Adjustment, None, Some(-1.5))).unwrap();
+        fix(correction(Kind::Unlock, None, None)).unwrap();
+        assert!(fix(correction(Kind::Unlock, None, None)).is_err());
+        fix(correction(Kind::Reversal, Some(1), None)).unwrap();
+        fix(correction(Kind::Reversal, Some(2), None)).unwrap();
+        assert!(fix(correction(Kind::Reversal, Some(3), None)).is_err());
+        assert!(fix(correction(Kind::Adjustment, None, None)).is_err());
+
+        assert_eq!(
+            accounts[&1],
+            AccountRecord {
+                client: 1,
+                available: 1.5,
+                held: 0.0,
+                total: 1.5,
+                locked: false,
+            }
+        );
+        assert_eq!(transactions[&2].state, TransactionState::Reversed);
+    }
+}
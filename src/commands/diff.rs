@@ -0,0 +1,188 @@
+//! `trp diff`: compares two account snapshots, for reconciliation.
+
+use serde::Serialize;
+use std::{collections::BTreeMap, path::Path};
+
+use crate::{
+    cli::{DiffArgs, Global},
+    state::AccountRecord,
+};
+
+/// Balances are printed with four decimal places, smaller deltas are float noise.
+const EPSILON: f32 = 0.00005;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum Status {
+    /// Client is only in the new snapshot.
+    Appeared,
+    /// Client is only in the old snapshot.
+    Disappeared,
+    /// Account was not locked in the old snapshot, but is in the new one.
+    Locked,
+    /// Account was locked in the old snapshot, but is not in the new one.
+    Unlocked,
+    /// Balances differ.
+    Changed,
+}
+
+/// Difference of a single client, balances are deltas from old to new.
+#[derive(Debug, Serialize)]
+pub(super) struct Delta {
+    client: u16,
+    status: Status,
+    available: String,
+    held: String,
+    total: String,
+}
+
+pub fn run(global: &Global, args: DiffArgs) -> Result<(), anyhow::Error> {
+    let deltas = compare(&read(&args.old)?, &read(&args.new)?);
+    print(global, &deltas)
+}
+
+/// Writes `deltas` to stdout as csv, and counts of clients by status to stderr.
+pub(super) fn print(global: &Global, deltas: &[Delta]) -> Result<(), anyhow::Error> {
+    let mut out = csv::Writer::from_writer(std::io::stdout());
+    for delta in deltas {
+        out.serialize(delta)?;
+    }
+    out.flush()?;
+
+    if !global.quiet() {
+        let count = |status| deltas.iter().filter(|delta| delta.status == status).count();
+        eprintln!(
+            "Clients: {} changed, {} appeared, {} disappeared, {} newly locked, {} unlocked",
+            count(Status::Changed),
+            count(Status::Appeared),
+            count(Status::Disappeared),
+            count(Status::Locked),
+            count(Status::Unlocked),
+        );
+    }
+
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<BTreeMap<u16, AccountRecord>, anyhow::Error> {
+    let mut rows = BTreeMap::new();
+    let mut rdr = csv::Reader::from_path(path)
+        .map_err(|err| anyhow::anyhow!("Failed to read {}: {err}", path.display()))?;
+    for row in rdr.deserialize() {
+        let row: AccountRecord = row?;
+        rows.insert(row.client, row);
+    }
+    Ok(rows)
+}
+
+/// Deltas of clients which differ between `old` and `new`, ordered by client.
+pub(super) fn compare(
+    old: &BTreeMap<u16, AccountRecord>,
+    new: &BTreeMap<u16, AccountRecord>,
+) -> Vec<Delta> {
+    let zero = |client| AccountRecord {
+        client,
+        available: 0.0,
+        held: 0.0,
+        total: 0.0,
+        locked: false,
+    };
+
+    let mut clients: Vec<u16> = old.keys().chain(new.keys()).copied().collect();
+    clients.sort_unstable();
+    clients.dedup();
+
+    clients
+        .into_iter()
+        .filter_map(|client| {
+            let (before, after, status) = match (old.get(&client), new.get(&client)) {
+                (Some(before), Some(after)) => {
+                    let status = match (before.locked, after.locked) {
+                        (false, true) => Status::Locked,
+                        (true, false) => Status::Unlocked,
+                        _ => Status::Changed,
+                    };
+                    (before, after, status)
+                }
+                (None, Some(after)) => (&zero(client), after, Status::Appeared),
+                (Some(before), None) => (before, &zero(client), Status::Disappeared),
+                (None, None) => unreachable!("client is taken from one of snapshots"),
+            };
+
+            let deltas = [
+                after.available - before.available,
+                after.held - before.held,
+                after.total - before.total,
+            ];
+            if status == Status::Changed && deltas.iter().all(|delta| delta.abs() < EPSILON) {
+                return None;
+            }
+
+            let [available, held, total] = deltas.map(|delta| format!("{delta:.4}"));
+            Some(Delta {
+                client,
+                status,
+                available,
+                held,
+                total,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare, AccountRecord, Status};
+    use std::collections::BTreeMap;
+
+    fn snapshot(rows: &[(u16, f32, f32, bool)]) -> BTreeMap<u16, AccountRecord> {
+        rows.iter()
+            .map(|&(client, available, held, locked)| {
+                let row = AccountRecord {
+                    client,
+                    available,
+                    held,
+                    total: available + held,
+                    locked,
+                };
+                (client, row)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn differences_are_reported() {
+        let old = snapshot(&[
+            (1, 1.0, 0.0, false),
+            (2, 2.0, 0.0, false),
+            (3, 1.1, 0.2, false),
+        ]);
+        let new = snapshot(&[
+            (2, 2.0, 0.0, true),
+            (3, 1.1, 0.0, false),
+            (4, 4.0, 0.5, false),
+        ]);
+
+        let deltas = compare(&old, &new);
+        let summary: Vec<_> = deltas
+            .iter()
+            .map(|delta| (delta.client, delta.status, delta.held.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, Status::Disappeared, "0.0000"),
+                (2, Status::Locked, "0.0000"),
+                (3, Status::Changed, "-0.2000"),
+                (4, Status::Appeared, "0.5000"),
+            ]
+        );
+        assert_eq!(deltas[0].available, "-1.0000");
+    }
+
+    #[test]
+    fn equal_snapshots_have_no_deltas() {
+        let rows = [(1, 0.1 + 0.2, 0.0, false), (2, 5.0, 1.0, true)];
+        assert!(compare(&snapshot(&rows), &snapshot(&rows)).is_empty());
+    }
+}
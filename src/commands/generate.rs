@@ -0,0 +1,175 @@
+//! `trp generate`: writes a randomized transactions csv, for benchmarks and test fixtures.
+
+use std::io::Write;
+
+use crate::{
+    cli::GenerateArgs,
+    log,
+    rng::{self, Rng},
+};
+
+/// Deposits are up to this many ten-thousandths, i.e. `100.0000`.
+const MAX_DEPOSIT: u64 = 1_000_000;
+/// Share of rows moving funds which are withdrawals rather than deposits.
+const WITHDRAWAL_RATIO: f64 = 0.3;
+
+/// What the generator knows about a client, amounts are in ten-thousandths.
+#[derive(Debug, Default)]
+struct Client {
+    available: u64,
+    /// Deposits which are not disputed at the moment.
+    deposits: Vec<(u32, u64)>,
+    disputed: Vec<(u32, u64)>,
+}
+
+pub fn run(args: GenerateArgs) -> Result<(), anyhow::Error> {
+    let seed = rng::seed(args.seed);
+    log::info!(log::Span::new("generate"), seed = seed, rows = args.rows; "Generating transactions");
+
+    let stdout = std::io::stdout();
+    generate(&args, seed, stdout.lock())
+}
+
+fn amount(ten_thousandths: u64) -> String {
+    format!(
+        "{}.{:04}",
+        ten_thousandths / 10_000,
+        ten_thousandths % 10_000
+    )
+}
+
+/// Writes `args.rows` rows to `out`. When `args.consistent` is set, every row is accepted
+/// by the engine: withdrawals and disputes never exceed available funds, disputes reference
+/// earlier deposits of the same client, resolves and chargebacks reference open disputes,
+/// and nothing is generated for clients locked by a chargeback. Otherwise references are
+/// picked from all transaction ids issued so far, and withdrawals may overdraw.
+fn generate<W: Write>(args: &GenerateArgs, seed: u64, out: W) -> Result<(), anyhow::Error> {
+    let mut rng = Rng::new(seed);
+    let mut out = csv::Writer::from_writer(out);
+    out.write_record(["type", "client", "tx", "amount"])?;
+
+    let mut clients: Vec<Client> = (0..args.clients).map(|_| Client::default()).collect();
+    // Clients transactions can still be generated for.
+    let mut open: Vec<u16> = (1..=args.clients).collect();
+    let mut last_tx: u32 = 0;
+
+    for _ in 0..args.rows {
+        if open.is_empty() {
+            log::warn!(
+                log::Span::new("generate"),
+                "All clients are locked, stopping early"
+            );
+            break;
+        }
+        let slot = rng.below(open.len() as u64) as usize;
+        let id = open[slot];
+        let client = &mut clients[usize::from(id) - 1];
+        let roll = rng.chance(args.dispute_ratio);
+        let settle = !roll && rng.chance(args.dispute_ratio);
+
+        if args.consistent {
+            if roll && !client.deposits.is_empty() {
+                let i = rng.below(client.deposits.len() as u64) as usize;
+                let (tx, value) = client.deposits[i];
+                if value <= client.available {
+                    client.deposits.swap_remove(i);
+                    client.available -= value;
+                    client.disputed.push((tx, value));
+                    out.write_record(["dispute", &id.to_string(), &tx.to_string(), ""])?;
+                    continue;
+                }
+            }
+            if settle && !client.disputed.is_empty() {
+                let i = rng.below(client.disputed.len() as u64) as usize;
+                let (tx, value) = client.disputed.swap_remove(i);
+                if rng.chance(args.chargeback_ratio) {
+                    open.swap_remove(slot);
+                    out.write_record(["chargeback", &id.to_string(), &tx.to_string(), ""])?;
+                } else {
+                    client.available += value;
+                    client.deposits.push((tx, value));
+                    out.write_record(["resolve", &id.to_string(), &tx.to_string(), ""])?;
+                }
+                continue;
+            }
+        } else if (roll || settle) && last_tx > 0 {
+            let kind = if roll {
+                "dispute"
+            } else if rng.chance(args.chargeback_ratio) {
+                "chargeback"
+            } else {
+                "resolve"
+            };
+            let tx = rng.below(u64::from(last_tx)) as u32 + 1;
+            out.write_record([kind, &id.to_string(), &tx.to_string(), ""])?;
+            continue;
+        }
+
+        last_tx += 1;
+        if client.available > 0 && rng.chance(WITHDRAWAL_RATIO) {
+            let value = if args.consistent {
+                rng.below(client.available) + 1
+            } else {
+                rng.below(MAX_DEPOSIT) + 1
+            };
+            client.available = client.available.saturating_sub(value);
+            out.write_record([
+                "withdrawal",
+                &id.to_string(),
+                &last_tx.to_string(),
+                &amount(value),
+            ])?;
+        } else {
+            let value = rng.below(MAX_DEPOSIT) + 1;
+            client.available += value;
+            client.deposits.push((last_tx, value));
+            out.write_record([
+                "deposit",
+                &id.to_string(),
+                &last_tx.to_string(),
+                &amount(value),
+            ])?;
+        }
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::generate;
+    use crate::{cli::GenerateArgs, commands::validate, format::CsvSource};
+
+    fn args(consistent: bool) -> GenerateArgs {
+        GenerateArgs {
+            rows: 2_000,
+            clients: 20,
+            dispute_ratio: 0.2,
+            chargeback_ratio: 0.2,
+            consistent,
+            ..Default::default()
+        }
+    }
+
+    fn output(args: &GenerateArgs, seed: u64) -> String {
+        let mut out = Vec::new();
+        generate(args, seed, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn seed_reproduces_output() {
+        assert_eq!(output(&args(false), 7), output(&args(false), 7));
+        assert_ne!(output(&args(false), 7), output(&args(false), 8));
+    }
+
+    #[test]
+    fn consistent_output_passes_validation() {
+        let csv = output(&args(true), 42);
+        let report = validate::check(&mut CsvSource::new(csv.as_bytes()), false);
+        assert!(report.rows > 100);
+        assert!(csv.contains("dispute") && csv.contains("resolve"));
+        assert_eq!(report.findings.len(), 0, "{:?}", report.findings);
+    }
+}
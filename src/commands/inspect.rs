@@ -0,0 +1,308 @@
+//! `trp inspect`: sniffs layout of a csv from another system, and writes the mapping
+//! `trp process` needs to read it, see [`Mapping`].
+//!
+//! The delimiter is the one of `,`, `;`, tab and `|` which splits every sampled row into the
+//! same number of fields, more than one. Headers are matched to columns by name, ignoring case
+//! and punctuation, e.g. `Customer ID` holds `client`. Required columns which no header names
+//! are matched by sampled values instead, e.g. the only column of small integers holds
+//! `client`.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs::File,
+    io::{BufRead, BufReader},
+};
+
+use crate::{
+    cli::{Global, InspectArgs},
+    format::{Mapping, COLUMNS},
+    parser,
+};
+
+const DELIMITERS: [u8; 4] = [b',', b';', b'\t', b'|'];
+/// Columns a record can't do without.
+const REQUIRED: [&str; 3] = ["type", "client", "tx"];
+
+/// Normalized header names of every column, in order of preference.
+const SYNONYMS: [(&str, &[&str]); 8] = [
+    (
+        "type",
+        &[
+            "type",
+            "kind",
+            "transactiontype",
+            "txtype",
+            "txntype",
+            "operation",
+            "action",
+        ],
+    ),
+    (
+        "client",
+        &[
+            "client",
+            "clientid",
+            "customer",
+            "customerid",
+            "account",
+            "accountid",
+            "user",
+            "userid",
+        ],
+    ),
+    (
+        "tx",
+        &[
+            "tx",
+            "txid",
+            "transaction",
+            "transactionid",
+            "txn",
+            "txnid",
+            "id",
+            "reference",
+        ],
+    ),
+    ("amount", &["amount", "value", "sum", "quantity"]),
+    (
+        "timestamp",
+        &["timestamp", "ts", "time", "datetime", "createdat"],
+    ),
+    (
+        "effective_date",
+        &[
+            "effectivedate",
+            "effective",
+            "valuedate",
+            "bookingdate",
+            "date",
+        ],
+    ),
+    ("signature", &["signature", "sig", "hmac"]),
+    (
+        "correlation_id",
+        &["correlationid", "correlation", "requestid", "xrequestid"],
+    ),
+];
+
+/// What was learned of the input.
+#[derive(Debug, PartialEq)]
+pub struct Inspection {
+    pub delimiter: u8,
+    pub headers: Vec<String>,
+    /// Sampled rows, without headers.
+    pub rows: usize,
+    /// Headers which could hold every column, best first.
+    pub candidates: BTreeMap<&'static str, Vec<String>>,
+}
+
+impl Inspection {
+    /// Mapping of every column to its best candidate, leaving out columns without one.
+    pub fn mapping(&self) -> Mapping {
+        let mut mapping = Mapping {
+            delimiter: self.delimiter,
+            ..Mapping::default()
+        };
+        for (column, candidates) in &self.candidates {
+            let free = candidates
+                .iter()
+                .find(|header| !mapping.columns.values().any(|taken| taken == *header));
+            if let Some(header) = free {
+                mapping.columns.insert(column.to_string(), header.clone());
+            }
+        }
+        mapping
+    }
+}
+
+pub fn run(global: &Global, args: InspectArgs) -> Result<(), anyhow::Error> {
+    let reader = BufReader::new(
+        File::open(&args.input)
+            .map_err(|err| anyhow::anyhow!("Failed to read {}: {err}", args.input.display()))?,
+    );
+    let mut sample = String::new();
+    for line in reader.lines().take(args.rows + 1) {
+        sample.push_str(&line?);
+        sample.push('\n');
+    }
+    let inspection = inspect(&sample)?;
+    let mapping = inspection.mapping();
+
+    if !global.quiet() {
+        eprintln!(
+            "{}: {} columns delimited by {}, {} rows sampled",
+            args.input.display(),
+            inspection.headers.len(),
+            escape(&char::from(inspection.delimiter).to_string()),
+            inspection.rows
+        );
+        for column in COLUMNS {
+            match inspection.candidates.get(column) {
+                Some(candidates) => eprintln!("  {column}: {}", candidates.join(", ")),
+                None => eprintln!("  {column}: -"),
+            }
+        }
+    }
+    let config = config(&mapping);
+    match &args.output {
+        Some(path) => std::fs::write(path, config)
+            .map_err(|err| anyhow::anyhow!("Failed to write {}: {err}", path.display()))?,
+        None => print!("{config}"),
+    }
+
+    let missing: Vec<&str> = REQUIRED
+        .into_iter()
+        .filter(|column| !mapping.columns.contains_key(*column))
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "No column of {} holds {}",
+            args.input.display(),
+            missing.join(", ")
+        ))
+    }
+}
+
+/// Inspects `sample`, i.e. headers followed by some rows of the input.
+pub fn inspect(sample: &str) -> Result<Inspection, anyhow::Error> {
+    let (delimiter, mut rows) = DELIMITERS
+        .into_iter()
+        .filter_map(|delimiter| {
+            let rows = split(sample, delimiter).ok()?;
+            let width = rows.first()?.len();
+            (width > 1 && rows.iter().all(|row| row.len() == width)).then_some((delimiter, rows))
+        })
+        .max_by_key(|(_, rows)| rows[0].len())
+        .ok_or_else(|| anyhow::anyhow!("Rows are not delimited by any of , ; tab |"))?;
+    let headers: Vec<String> = rows.remove(0);
+
+    let mut candidates: BTreeMap<&'static str, Vec<String>> = BTreeMap::new();
+    for (column, synonyms) in SYNONYMS {
+        let mut named: Vec<(usize, &String)> = headers
+            .iter()
+            .filter_map(|header| {
+                let name = normalize(header);
+                let rank = synonyms.iter().position(|synonym| *synonym == name)?;
+                Some((rank, header))
+            })
+            .collect();
+        named.sort_by_key(|(rank, _)| *rank);
+        let mut found: Vec<String> = named
+            .into_iter()
+            .map(|(_, header)| header.clone())
+            .collect();
+        if found.is_empty() && REQUIRED.contains(&column) && !rows.is_empty() {
+            found = (0..headers.len())
+                .filter(|index| rows.iter().all(|row| holds(column, row[*index].trim())))
+                .map(|index| headers[index].clone())
+                .collect();
+        }
+        if !found.is_empty() {
+            candidates.insert(column, found);
+        }
+    }
+
+    Ok(Inspection {
+        delimiter,
+        headers,
+        rows: rows.len(),
+        candidates,
+    })
+}
+
+/// Every row of `sample` split by `delimiter`.
+fn split(sample: &str, delimiter: u8) -> Result<Vec<Vec<String>>, csv::Error> {
+    csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(sample.as_bytes())
+        .records()
+        .map(|row| Ok(row?.iter().map(str::to_string).collect()))
+        .collect()
+}
+
+/// Header lowercased, without anything but letters and digits.
+fn normalize(header: &str) -> String {
+    header
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Whether `value` could be one of `column`.
+fn holds(column: &str, value: &str) -> bool {
+    match column {
+        "type" => parser::kind(value).is_ok(),
+        "client" => value.parse::<u16>().is_ok(),
+        "tx" => value.parse::<u32>().is_ok(),
+        _ => false,
+    }
+}
+
+/// `mapping` as configuration `trp process --config` reads.
+fn config(mapping: &Mapping) -> String {
+    let mut config = String::from("[input]\n");
+    let _ = writeln!(
+        config,
+        "delimiter = \"{}\"",
+        escape(&char::from(mapping.delimiter).to_string())
+    );
+    config.push_str("\n[columns]\n");
+    for column in COLUMNS {
+        match mapping.columns.get(column) {
+            Some(header) => {
+                let _ = writeln!(config, "{column} = \"{}\"", escape(header));
+            }
+            None if REQUIRED.contains(&column) => {
+                let _ = writeln!(config, "# {column} = \"\"  # no column found");
+            }
+            None => {}
+        }
+    }
+    config
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\t', "\\t")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{config, inspect};
+
+    #[test]
+    fn layout_is_sniffed() {
+        let inspection = inspect(
+            "Kind;Customer ID;Reference;Value\n\
+             deposit;1;10;1.5\n\
+             Withdrawal;2;11;0.5\n",
+        )
+        .unwrap();
+        assert_eq!(inspection.delimiter, b';');
+        assert_eq!(inspection.rows, 2);
+        let mapping = inspection.mapping();
+        assert_eq!(mapping.columns["type"], "Kind");
+        assert_eq!(mapping.columns["client"], "Customer ID");
+        assert_eq!(mapping.columns["tx"], "Reference");
+        assert_eq!(mapping.columns["amount"], "Value");
+        assert!(config(&mapping).starts_with("[input]\ndelimiter = \";\"\n"));
+    }
+
+    #[test]
+    fn unnamed_columns_are_matched_by_values() {
+        let inspection = inspect("a\tb\tc\ndeposit\t70000\t1\ndispute\t80000\t2\n").unwrap();
+        assert_eq!(inspection.delimiter, b'\t');
+        let mapping = inspection.mapping();
+        assert_eq!(mapping.columns["type"], "a");
+        assert_eq!(mapping.columns["tx"], "b");
+        assert_eq!(mapping.columns["client"], "c");
+        assert!(inspect("one\ntwo\n").is_err());
+    }
+}
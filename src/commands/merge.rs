@@ -0,0 +1,42 @@
+//! `trp merge`: combines account snapshots of partitioned runs.
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use crate::{cli::MergeArgs, state::AccountRecord};
+
+pub fn run(args: MergeArgs) -> Result<(), anyhow::Error> {
+    let rows = combine(&args.inputs)?;
+
+    let mut out = csv::Writer::from_writer(std::io::stdout());
+    for row in rows.values() {
+        out.serialize(row)?;
+    }
+    out.flush()?;
+
+    Ok(())
+}
+
+/// Reads every snapshot, failing when a client appears in more than one of them, since its
+/// transactions were then split between partitions.
+fn combine(inputs: &[PathBuf]) -> Result<BTreeMap<u16, AccountRecord>, anyhow::Error> {
+    let mut rows = BTreeMap::new();
+    let mut sources = BTreeMap::new();
+
+    for input in inputs {
+        let mut rdr = csv::Reader::from_path(input)?;
+        for row in rdr.deserialize() {
+            let row: AccountRecord = row?;
+            if let Some(previous) = sources.insert(row.client, input) {
+                return Err(anyhow::anyhow!(
+                    "Client {} appears in both {} and {}",
+                    row.client,
+                    previous.display(),
+                    input.display()
+                ));
+            }
+            rows.insert(row.client, row);
+        }
+    }
+
+    Ok(rows)
+}
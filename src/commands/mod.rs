@@ -0,0 +1,100 @@
+//! Entry points of `trp` commands, see [`cli`](crate::cli) for their arguments.
+
+pub mod bench;
+pub mod compact;
+pub mod convert;
+pub mod corrections;
+pub mod diff;
+pub mod generate;
+pub mod inspect;
+pub mod merge;
+pub mod process;
+pub mod query;
+pub mod replay;
+pub mod replica;
+pub mod rollback;
+pub mod serve;
+pub mod state;
+pub mod statement;
+pub mod validate;
+
+use std::future::Future;
+
+use crate::{cli::Thresholds, event_log, follow, lease::Lease, log, metrics};
+
+/// Spawns metrics endpoint on `addr`, when given. Must be called within runtime context.
+fn serve_metrics(addr: Option<String>) {
+    if let Some(addr) = addr {
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(addr).await {
+                log::error!(log::Span::new("metrics"), "Metrics endpoint failed: {err}");
+            }
+        });
+    }
+}
+
+/// Flushes the event log every [`FLUSH`](follow::FLUSH), so that replicas following it see
+/// entries soon after they are logged. Must be called within runtime context.
+fn flush_event_log() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(follow::FLUSH);
+        loop {
+            interval.tick().await;
+            if let Err(err) = event_log::flush() {
+                log::error!(log::Span::new("events"), "Failed to flush event log: {err}");
+            }
+        }
+    });
+}
+
+/// Renews `lease`, when given, every [`FLUSH`](follow::FLUSH), until someone else takes it
+/// over. The future returned resolves to who that is, and never does without a lease. Must
+/// be called within runtime context.
+fn hold(lease: Option<Lease>) -> impl Future<Output = Option<String>> {
+    let renewing = lease.map(|lease| {
+        tokio::spawn(async move {
+            let span = log::Span::new("lease");
+            let mut interval = tokio::time::interval(follow::FLUSH);
+            loop {
+                interval.tick().await;
+                match lease.held() {
+                    Ok(Some((holder, _))) => return holder,
+                    Ok(None) => {
+                        if let Err(err) = lease.renew() {
+                            log::error!(span, "Failed to renew lease: {err}");
+                        }
+                    }
+                    Err(err) => log::error!(span, "Failed to renew lease: {err}"),
+                }
+            }
+        })
+    });
+    async move {
+        match renewing {
+            Some(renewing) => renewing.await.ok(),
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Fails when the run went over any of `thresholds`, so that the process exits with non-zero
+/// code.
+fn check(thresholds: &Thresholds, summary: &metrics::Summary) -> Result<(), anyhow::Error> {
+    if let Some(max) = thresholds.max_rejects {
+        if summary.rejects() > max {
+            return Err(anyhow::anyhow!(
+                "{} rejects exceed --max-rejects {max}",
+                summary.rejects()
+            ));
+        }
+    }
+    if let Some(max) = thresholds.max_reject_rate {
+        if summary.reject_rate() > max {
+            return Err(anyhow::anyhow!(
+                "Reject rate {:.4} exceeds --max-reject-rate {max}",
+                summary.reject_rate()
+            ));
+        }
+    }
+    Ok(())
+}
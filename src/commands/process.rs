@@ -0,0 +1,259 @@
+//! `trp process`: batch run over a single transactions file.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "otel")]
+use crate::otel;
+use crate::{
+    alerts, chaos,
+    cli::Global,
+    cli::ProcessArgs,
+    config, creation, dashboard, dlq, event_log,
+    interest::{self, Interest},
+    log, memory, metrics, ordering, pacing, parse_errors, parser, processor, progress, reference,
+    report, reserve, rng, sample,
+    screening::{self, Watchlist},
+    send_errors, settlement, signature, snapshots,
+    state::{self, InputRecord, Saved},
+    tiers::{self, Tiers},
+    top, two_pass, velocity, writer,
+};
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+const DASHBOARD_INTERVAL: Duration = Duration::from_millis(500);
+
+pub fn run(global: &Global, args: ProcessArgs) -> Result<(), anyhow::Error> {
+    writer::check_schema(args.extended && !args.reference)?;
+    // Not a problem of the configuration, as `Config::validate` reports them: the same file
+    // runs on hosts with any number of CPUs, and extra threads only slow parsing down.
+    let cpus = std::thread::available_parallelism().map_or(1, usize::from);
+    let engine = config::engine();
+    let threads = engine.parser_threads;
+    if threads > cpus {
+        log::warn!(log::Span::new("run"), parser_threads = threads, cpus = cpus; "More parser threads than CPUs, they will compete for them");
+    }
+    // Rules of both engines.
+    if let Some(settings) = args.settlement {
+        settlement::enable(settings);
+    }
+    if args.tiers.is_some() || args.tier_rules.is_some() {
+        tiers::enable(Tiers::load(
+            args.tiers.as_deref(),
+            args.tier_rules.as_deref(),
+        )?);
+    }
+    if let Some(minimums) = args.min_balance.clone() {
+        reserve::enable(minimums);
+    }
+    creation::enable(args.seed_accounts.as_deref())?;
+    if args.reference {
+        return reference::run(&args.input);
+    }
+    if let Some(schedule) = args.interest_rates.clone() {
+        interest::enable(Interest {
+            schedule,
+            posting: args.interest_posting,
+        });
+    }
+
+    #[cfg(feature = "otel")]
+    let run_started = std::time::SystemTime::now();
+    #[cfg(feature = "otel")]
+    let run_span = {
+        if args.otlp_endpoint.is_some() {
+            otel::enable();
+        }
+        let mut span = otel::Span::start("trp.run", otel::TraceContext::root());
+        span.attribute("input", args.input.display());
+        span
+    };
+
+    let seed = rng::seed(args.seed.or(args.chaos.and_then(|chaos| chaos.seed)));
+    log::info!(log::Span::new("run"), seed = seed; "Seeded run");
+    if let Some(share) = args.sample {
+        sample::enable(share, seed);
+        log::warn!(log::Span::new("run"), share = share; "Processing only a sample of clients");
+    }
+    if let Some(limit) = args.max_memory {
+        memory::enable(limit);
+    }
+    if let Some(settings) = args.chaos {
+        chaos::enable(settings, seed);
+        log::warn!(log::Span::new("chaos"), seed = seed; "Injecting faults, {settings:?}");
+    }
+    // Refused before anything of the run starts, so a repeated run leaves no trace.
+    let (hash, saved) = match &args.state {
+        Some(dir) => {
+            let hash = state::digest(&args.input)?;
+            if let Some(input) = state::input(dir, &hash)? {
+                if !args.force {
+                    return Err(anyhow::anyhow!(
+                        "{} was already applied to state in {}, as {} at {}ms, use --force to process it again",
+                        args.input.display(),
+                        dir.display(),
+                        input.path,
+                        input.applied
+                    ));
+                }
+                log::warn!(log::Span::new("state"), file = args.input.display(), applied = input.applied; "Processing input applied to state before");
+            }
+            if let Some(name) = &args.savepoint {
+                if state::savepoint_dir(dir, name).exists() && !args.force {
+                    return Err(anyhow::anyhow!(
+                        "Savepoint {name} already exists in {}, use --force to replace it",
+                        dir.display()
+                    ));
+                }
+            }
+            if let Some(label) = &args.as_of {
+                if state::snapshot(dir, Some(label)).exists() && !args.force {
+                    return Err(anyhow::anyhow!(
+                        "Snapshot as of {label} already exists in {}, use --force to replace it",
+                        dir.display()
+                    ));
+                }
+            }
+            state::enable();
+            (Some(hash), state::load(dir)?)
+        }
+        None => (None, Saved::default()),
+    };
+    if let Some(path) = &args.event_log {
+        event_log::open(path)?;
+    }
+    if let Some(path) = &args.dlq {
+        dlq::open(path)?;
+    }
+    if let Some(path) = &args.signature_key {
+        signature::enable(path)?;
+    }
+    velocity::enable(args.velocity);
+    if args.thresholds.strict {
+        send_errors::strict();
+    }
+    if args.lenient_amounts {
+        parser::lenient_amounts();
+    }
+    parser::amount_unit(args.amount_unit);
+    if let Some(rate) = args.rate {
+        pacing::enable(rate);
+    }
+    parse_errors::enable(
+        args.parse_errors.policy,
+        args.parse_errors.quarantine.as_deref(),
+    )?;
+    if let Some(path) = &args.alerts {
+        alerts::open(path)?;
+    }
+    if let Some(path) = &args.watchlist {
+        screening::enable(Watchlist::load(path)?);
+    }
+    if let Some(path) = &args.review {
+        dlq::REVIEW.open(path)?;
+    }
+    ordering::enable(args.ordering, args.reorder);
+    if args.report.is_some() {
+        report::enable(args.report_period);
+    }
+    if args.stream {
+        snapshots::enable(args.stream_threshold);
+    }
+    if args.top.is_some() {
+        top::enable(args.top_n);
+    }
+    if args.two_pass {
+        let transactions = two_pass::enable(&args.input)?;
+        log::info!(log::Span::new("parse").with("file", args.input.display()), transactions = transactions; "Indexed input in first pass");
+    }
+    let rx = parser::start(&args.input)?;
+    // Dashboard already includes progress line, so the two are not drawn together.
+    let dashboard_handle = args.dashboard.then(|| {
+        dashboard::enable();
+        dashboard::run(DASHBOARD_INTERVAL)
+    });
+    let progress_handle =
+        (args.progress && !args.dashboard).then(|| progress::report(PROGRESS_INTERVAL));
+    let (done_tx, done_rx) = writer::channel(engine);
+    let writer_handle = writer::start(
+        done_rx,
+        args.extended,
+        writer::sink(args.shards.clone(), args.unwritten.as_deref())?,
+        engine,
+    );
+
+    let rt = tokio::runtime::Runtime::new()?;
+    let metrics_addr = args.metrics_addr.clone();
+    rt.block_on(async move {
+        super::serve_metrics(metrics_addr);
+        processor::start(rx, done_tx, processor::Settings::current(), saved).await;
+    });
+
+    writer::join(writer_handle)?;
+    event_log::close()?;
+    dlq::close()?;
+    alerts::close()?;
+    dlq::REVIEW.close()?;
+    parse_errors::close()?;
+
+    if let Some(handle) = progress_handle {
+        let _ = handle.join();
+    }
+
+    if let Some(handle) = dashboard_handle {
+        dashboard::stop();
+        let _ = handle.join();
+    }
+
+    let summary = metrics::summary();
+    if !global.quiet() {
+        eprintln!("{summary}");
+    }
+    // Input was not read in full, so neither is the state.
+    parse_errors::check()?;
+    send_errors::check()?;
+    memory::check()?;
+
+    if let (Some(dir), Some(hash)) = (&args.state, hash) {
+        // State is only saved here, so up to now it is as it was before the run.
+        if let Some(name) = &args.savepoint {
+            state::savepoint(dir, name)?;
+        }
+        state::save(dir)?;
+        state::applied(
+            dir,
+            InputRecord {
+                hash,
+                path: args.input.display().to_string(),
+                applied: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_millis() as u64),
+                seed: Some(seed),
+            },
+        )?;
+        if let Some(label) = &args.as_of {
+            state::tag(dir, label)?;
+        }
+    }
+
+    if let Some(path) = &args.report {
+        report::write(path)?;
+    }
+
+    if let Some(path) = &args.top {
+        top::write(path)?;
+    }
+
+    if let Some(path) = args.metrics_file {
+        metrics::write_textfile(path)?;
+    }
+
+    #[cfg(feature = "otel")]
+    {
+        run_span.end();
+        if let Some(endpoint) = &args.otlp_endpoint {
+            otel::export(endpoint, run_started);
+        }
+    }
+
+    super::check(&args.thresholds, &summary)
+}
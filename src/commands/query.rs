@@ -0,0 +1,85 @@
+//! `trp query`: inspects state persisted by a previous run, without running the engine.
+
+use serde::Serialize;
+
+use crate::{
+    cli::{Query, QueryArgs},
+    engine::{Registry, Rejection},
+    processor::{self, Account, ProcessingError},
+    state::{self, AccountRecord},
+};
+
+/// Outcome of a simulated transaction, along with balances it would leave the client with.
+#[derive(Debug, Serialize)]
+struct Outcome {
+    client: u16,
+    accepted: bool,
+    /// Code of the rejection, empty when accepted.
+    code: Option<&'static str>,
+    available: f32,
+    held: f32,
+    total: f32,
+    locked: bool,
+}
+
+pub fn run(args: QueryArgs) -> Result<(), anyhow::Error> {
+    let dir = &state::snapshot(&args.state, args.as_of.as_deref());
+    let mut out = csv::Writer::from_writer(std::io::stdout());
+
+    match args.query {
+        Query::Account(client) => {
+            let account = state::account(dir, client)?
+                .ok_or_else(|| anyhow::anyhow!("Client {client} not found in {}", dir.display()))?;
+            out.serialize(account)?;
+        }
+        Query::History(client) => {
+            for transaction in state::transactions(dir, client)? {
+                out.serialize(transaction)?;
+            }
+        }
+        Query::Transaction(tx) => {
+            let transaction = state::transaction(dir, tx)?.ok_or_else(|| {
+                anyhow::anyhow!("Transaction {tx} not found in {}", dir.display())
+            })?;
+            out.serialize(transaction)?;
+        }
+        Query::Simulate(message) => {
+            let client = message.client_id();
+            let account = state::account(dir, client)?;
+            // Transactions of other clients are off limits, as they are to the router.
+            let mut registry = Registry::default();
+            for transaction in state::all_transactions(dir)? {
+                registry.register(transaction.tx, transaction.client);
+            }
+            let simulated = match (registry.check(&message), &account) {
+                (Err(rejection), _) => Err(ProcessingError::Rejected(rejection)),
+                (Ok(()), Some(record)) => {
+                    let transactions = state::transactions(dir, client)?;
+                    let (account, history) = Account::restored(record, transactions);
+                    account.simulate(&message, &history)
+                }
+                (Ok(()), None) if processor::should_create_account(&message) => {
+                    Account::new(client).simulate(&message, &Default::default())
+                }
+                (Ok(()), None) => Err(ProcessingError::Rejected(Rejection::NoAccount)),
+            };
+            let account = account.unwrap_or(AccountRecord {
+                client,
+                ..AccountRecord::default()
+            });
+            let effect = simulated.as_ref().copied().unwrap_or_default();
+            out.serialize(Outcome {
+                client,
+                accepted: simulated.is_ok(),
+                code: simulated.as_ref().err().map(ProcessingError::code),
+                available: account.available + effect.available,
+                held: account.held + effect.held,
+                total: account.total + effect.total,
+                locked: account.locked || effect.locks,
+            })?;
+        }
+    }
+
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,57 @@
+//! `trp replay`: rebuilds account state from an event log, up to a point in time.
+
+use std::path::Path;
+
+use crate::{
+    cli::{Global, ReplayArgs},
+    config, event_log, log, metrics, pacing, parser, processor, reference, send_errors,
+    state::{AccountRecord, Saved},
+    writer,
+};
+
+/// Balances of every account as of `until`, in milliseconds since unix epoch, rebuilt from
+/// `event_log` with the [`reference`] engine, ordered by client. Messages are taken as of
+/// their timestamps, or as of when they were logged if they have none.
+pub fn snapshot(event_log: &Path, until: u64) -> Result<Vec<AccountRecord>, anyhow::Error> {
+    let mut reader = event_log::Reader::open(event_log, None, Some(until))?;
+    Ok(reference::process(&mut reader))
+}
+
+pub fn run(global: &Global, args: ReplayArgs) -> Result<(), anyhow::Error> {
+    writer::check_schema(false)?;
+    let mut reader = event_log::Reader::open(&args.event_log, args.offset, args.until)?;
+    if args.thresholds.strict {
+        send_errors::strict();
+    }
+    if let Some(rate) = args.rate {
+        pacing::enable(rate);
+    }
+    let engine = config::engine();
+    let (tx, rx) = parser::channel(engine);
+    let span = log::Span::new("parse").with("file", args.event_log.display());
+    let origin = args.event_log.display().to_string();
+    std::thread::spawn(move || {
+        parser::read(&mut reader, &origin, None, &span, &tx, engine);
+        log::info!(span, "Finished reading event log");
+    });
+
+    let (done_tx, done_rx) = writer::channel(engine);
+    let writer_handle = writer::start(done_rx, false, writer::sink(None, None)?, engine);
+    // Account tasks outlive the router, so runtime must be kept until writer is done.
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(processor::start(
+        rx,
+        done_tx,
+        processor::Settings::current(),
+        Saved::default(),
+    ));
+    writer::join(writer_handle)?;
+
+    let summary = metrics::summary();
+    if !global.quiet() {
+        eprintln!("{summary}");
+    }
+
+    send_errors::check()?;
+    super::check(&args.thresholds, &summary)
+}
@@ -0,0 +1,172 @@
+//! `trp replica`: read-only replica of a `trp serve`, following its event log, see
+//! [`follow`](crate::follow).
+//!
+//! Messages of the log are applied by the same engine, with rules of the configuration, so
+//! metrics, gRPC and Flight of the replica show what the primary has. Nothing a run may write
+//! is enabled: no state, event log, dead letters or Redis keys.
+//!
+//! With `--lease`, the replica is a hot standby, watching the [`lease`] the primary renews.
+//! Once it expires, the standby reads the log to its end, takes the lease and
+//! [`resume`](event_log::resume)s the log where the primary left it, then accepts
+//! transactions as `trp serve` does. Other features of serve, state among them, are not
+//! taken over.
+
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+use crate::{
+    cli::{Global, ReplicaArgs},
+    config, creation, event_log, flight, follow,
+    format::Source,
+    grpc,
+    interest::{self, Interest},
+    lease::{self, Lease},
+    log, metrics, ordering, parser, processor, reserve, send_errors, settlement,
+    state::Saved,
+    tiers::{self, Tiers},
+    writer,
+};
+
+pub fn run(global: &Global, args: ReplicaArgs) -> Result<(), anyhow::Error> {
+    writer::check_schema(args.extended)?;
+    let mut reader = event_log::Reader::follow(&args.event_log)?;
+    ordering::enable(args.ordering, args.reorder);
+    if let Some(settings) = args.settlement {
+        settlement::enable(settings);
+    }
+    if args.tiers.is_some() || args.tier_rules.is_some() {
+        tiers::enable(Tiers::load(
+            args.tiers.as_deref(),
+            args.tier_rules.as_deref(),
+        )?);
+    }
+    if let Some(minimums) = args.min_balance.clone() {
+        reserve::enable(minimums);
+    }
+    creation::enable(args.seed_accounts.as_deref())?;
+    if let Some(schedule) = args.interest_rates.clone() {
+        interest::enable(Interest {
+            schedule,
+            posting: args.interest_posting,
+        });
+    }
+    let lease = match (&args.lease, &args.listen) {
+        (Some(path), Some(listen)) => Some(Lease::new(
+            path,
+            listen,
+            args.lease_ttl.map_or(lease::TTL, Duration::from_millis),
+        )),
+        _ => None,
+    };
+
+    let engine = config::engine();
+    let (tx, rx) = parser::channel(engine);
+    let (done_tx, done_rx) = writer::channel(engine);
+    let writer_handle = writer::start(done_rx, args.extended, writer::sink(None, None)?, engine);
+    let rt = tokio::runtime::Runtime::new()?;
+    let accepted = rt.block_on(async move {
+        super::serve_metrics(args.metrics_addr);
+        if let Some(addr) = args.grpc_addr {
+            grpc::enable();
+            tokio::spawn(async move {
+                if let Err(err) = grpc::serve(addr).await {
+                    log::error!(log::Span::new("grpc"), "gRPC endpoint failed: {err}");
+                }
+            });
+        }
+        if let Some(addr) = args.flight_addr {
+            flight::enable();
+            tokio::spawn(async move {
+                if let Err(err) = flight::serve(addr).await {
+                    log::error!(log::Span::new("flight"), "Flight endpoint failed: {err}");
+                }
+            });
+        }
+        let span = log::Span::new("replica");
+        tokio::spawn({
+            let span = span.clone();
+            async move {
+                if tokio::signal::ctrl_c().await.is_ok() && !follow::stopped() {
+                    log::info!(span, "Interrupted, applying what was appended");
+                    follow::stop();
+                }
+            }
+        });
+        let watching = lease.clone().map(|lease| tokio::spawn(watch(lease, span.clone())));
+        let processor = tokio::spawn(processor::start(rx, done_tx, processor::Settings::current(), Saved::default()));
+
+        let reading = tokio::task::spawn_blocking({
+            let span = log::Span::new("parse").with("file", args.event_log.display());
+            let origin = args.event_log.display().to_string();
+            move || {
+                log::info!(span, "Following event log");
+                parser::read(&mut reader, &origin, None, &span, &tx, engine);
+                log::info!(span, "Stopped following event log");
+                // Also once the log was recreated, so the lease isn't watched any further.
+                follow::stop();
+                (reader, tx)
+            }
+        });
+        let (reader, tx) = reading.await?;
+        let took_over = match watching {
+            Some(watching) => watching.await?,
+            None => false,
+        };
+
+        let mut accepted = Ok(());
+        if let (true, Some(lease), Some(listen)) = (took_over, lease, args.listen) {
+            follow::check()?;
+            event_log::resume(&args.event_log, reader.position(), reader.next_offset())?;
+            let mut lines = reader.lines().iter().collect::<Vec<_>>();
+            lines.sort();
+            for (source, line) in lines {
+                log::info!(span, source = source, line = line; "Primary applied {source} up to line {line}");
+            }
+            super::flush_event_log();
+            let lost = super::hold(Some(lease.clone()));
+            let span = log::Span::new("serve").with("addr", &listen);
+            let listener = TcpListener::bind(&listen).await?;
+            accepted = super::serve::accept(listener, &tx, &span, lost).await;
+            drop(tx);
+            processor.await?;
+            event_log::close()?;
+            lease.release()?;
+        } else {
+            drop(tx);
+            processor.await?;
+        }
+        grpc::stop().await;
+        Ok::<_, anyhow::Error>(accepted)
+    })?;
+    writer::join(writer_handle)?;
+
+    let summary = metrics::summary();
+    if !global.quiet() {
+        eprintln!("{summary}");
+    }
+
+    send_errors::check()?;
+    follow::check()?;
+    accepted
+}
+
+/// Waits for `lease` to expire, and takes it over, stopping the follow of the log. Returns
+/// whether it did, rather than the replica being interrupted first.
+async fn watch(lease: Lease, span: log::Span) -> bool {
+    let mut interval = tokio::time::interval(follow::FLUSH);
+    loop {
+        interval.tick().await;
+        if follow::stopped() {
+            return false;
+        }
+        match lease.acquire() {
+            Ok(true) => {
+                log::info!(span, "Lease expired, taking over ingestion");
+                follow::stop();
+                return true;
+            }
+            Ok(false) => {}
+            Err(err) => log::error!(span, "Failed to read lease: {err}"),
+        }
+    }
+}
@@ -0,0 +1,7 @@
+//! `trp rollback`: puts state persisted by previous runs back to a savepoint.
+
+use crate::{cli::RollbackArgs, state};
+
+pub fn run(args: RollbackArgs) -> Result<(), anyhow::Error> {
+    state::rollback(&args.state, &args.savepoint)
+}
@@ -0,0 +1,295 @@
+//! `trp serve`: long-running mode, accepting transaction streams over TCP.
+
+use std::{future::Future, time::Duration};
+use tokio::{net::TcpListener, sync::mpsc::Sender};
+
+use crate::{
+    alerts,
+    backfill::{self, Side},
+    cli::{Global, ServeArgs},
+    config, creation, dlq, dormant, event_log, flight,
+    format::{self, CsvSource, Format},
+    grpc,
+    interest::{self, Interest},
+    lease::{self, Lease},
+    log, memory, metrics, ordering, outbox, parse_errors,
+    parser::{self, Parsed},
+    processor, redis, report, reserve,
+    screening::{self, Watchlist},
+    send_errors, settlement, signature, snapshots, state,
+    tiers::{self, Tiers},
+    top, velocity, writer,
+};
+
+pub fn run(global: &Global, args: ServeArgs) -> Result<(), anyhow::Error> {
+    writer::check_schema(args.extended)?;
+    let saved = match &args.state {
+        Some(dir) => {
+            state::enable();
+            if let Some(ttl) = args.evict_after {
+                dormant::enable(Duration::from_millis(ttl), dir)?;
+            }
+            state::load(dir)?
+        }
+        None => state::Saved::default(),
+    };
+    if args.stream {
+        snapshots::enable(args.stream_threshold);
+    }
+    if let Some(limit) = args.max_memory {
+        memory::enable(limit);
+    }
+    let lease = match &args.lease {
+        Some(path) => {
+            let ttl = args.lease_ttl.map_or(lease::TTL, Duration::from_millis);
+            let lease = Lease::new(path, &args.listen, ttl);
+            if let Some((holder, age)) = lease.held()? {
+                anyhow::bail!(
+                    "Lease {} is held by {holder}, renewed {}ms ago",
+                    path.display(),
+                    age.as_millis()
+                );
+            }
+            lease.renew()?;
+            Some(lease)
+        }
+        None => None,
+    };
+    if let Some(path) = &args.event_log {
+        event_log::open(path)?;
+    }
+    if let Some(path) = &args.dlq {
+        dlq::open(path)?;
+    }
+    if let Some(path) = &args.signature_key {
+        signature::enable(path)?;
+    }
+    velocity::enable(args.velocity);
+    if args.thresholds.strict {
+        send_errors::strict();
+    }
+    if args.lenient_amounts {
+        parser::lenient_amounts();
+    }
+    parser::amount_unit(args.amount_unit);
+    parse_errors::enable(
+        args.parse_errors.policy,
+        args.parse_errors.quarantine.as_deref(),
+    )?;
+    if let Some(path) = &args.alerts {
+        alerts::open(path)?;
+    }
+    if let Some(path) = &args.watchlist {
+        screening::enable(Watchlist::load(path)?);
+    }
+    if let Some(path) = &args.review {
+        dlq::REVIEW.open(path)?;
+    }
+    ordering::enable(args.ordering, args.reorder);
+    if args.report.is_some() {
+        report::enable(args.report_period);
+    }
+    if args.top.is_some() {
+        top::enable(args.top_n);
+    }
+    if let Some(settings) = args.settlement {
+        settlement::enable(settings);
+    }
+    if args.tiers.is_some() || args.tier_rules.is_some() {
+        tiers::enable(Tiers::load(
+            args.tiers.as_deref(),
+            args.tier_rules.as_deref(),
+        )?);
+    }
+    if let Some(minimums) = args.min_balance.clone() {
+        reserve::enable(minimums);
+    }
+    creation::enable(args.seed_accounts.as_deref())?;
+    if let Some(schedule) = args.interest_rates.clone() {
+        interest::enable(Interest {
+            schedule,
+            posting: args.interest_posting,
+        });
+    }
+    let redis_handle = args.redis.clone().map(|addr| {
+        redis::enable();
+        redis::run(addr, args.redis_prefix.clone())
+    });
+    if let (true, Some(dir)) = (args.outbox, &args.state) {
+        outbox::enable();
+        // Left by an earlier run, which could not deliver them.
+        redis::deliver(outbox::pending(dir)?);
+    }
+    let engine = config::engine();
+    let backfill = match &args.backfill {
+        Some(path) => {
+            backfill::enable(args.cutover);
+            Some((
+                path.clone(),
+                format::source(path, Format::of(path)?, engine)?,
+            ))
+        }
+        None => None,
+    };
+    let (tx, rx) = parser::channel(engine);
+    let (done_tx, done_rx) = writer::channel(engine);
+    let writer_handle = writer::start(
+        done_rx,
+        args.extended,
+        writer::sink(None, args.unwritten.as_deref())?,
+        engine,
+    );
+
+    let rt = tokio::runtime::Runtime::new()?;
+    let held = lease.clone();
+    let accepted = rt.block_on(async move {
+        let span = log::Span::new("serve").with("addr", &args.listen);
+        let listener = TcpListener::bind(&args.listen).await?;
+        super::serve_metrics(args.metrics_addr);
+        if let Some(addr) = args.grpc_addr {
+            grpc::enable();
+            tokio::spawn(async move {
+                if let Err(err) = grpc::serve(addr).await {
+                    log::error!(log::Span::new("grpc"), "gRPC endpoint failed: {err}");
+                }
+            });
+        }
+        if let Some(addr) = args.flight_addr {
+            flight::enable();
+            tokio::spawn(async move {
+                if let Err(err) = flight::serve(addr).await {
+                    log::error!(log::Span::new("flight"), "Flight endpoint failed: {err}");
+                }
+            });
+        }
+        if args.event_log.is_some() {
+            super::flush_event_log();
+        }
+        let lost = super::hold(held);
+        // Connections may go quiet for good, held messages must not wait for them.
+        let settings = processor::Settings {
+            release_idle: true,
+            ..processor::Settings::current()
+        };
+        let processor = tokio::spawn(processor::start(rx, done_tx, settings, saved));
+        if let Some((path, mut source)) = backfill {
+            let tx = tx.clone();
+            let span = log::Span::new("parse").with("backfill", path.display());
+            std::thread::spawn(move || {
+                log::info!(span, "Backfill started");
+                let origin = path.display().to_string();
+                parser::read(
+                    source.as_mut(),
+                    &origin,
+                    Some(Side::Backfill),
+                    &span,
+                    &tx,
+                    engine,
+                );
+                backfill::finish();
+                log::info!(span, "Backfill finished, applying messages of connections");
+            });
+        }
+        let accepted = accept(listener, &tx, &span, lost).await;
+
+        drop(tx);
+        processor.await?;
+        grpc::stop().await;
+        Ok::<_, anyhow::Error>(accepted)
+    })?;
+
+    writer::join(writer_handle)?;
+    let redis_handle = match redis_handle {
+        Some(handle) if !outbox::enabled() => {
+            redis::stop();
+            let _ = handle.join();
+            None
+        }
+        handle => handle,
+    };
+    event_log::close()?;
+    if let Some(lease) = &lease {
+        lease.release()?;
+    }
+    dlq::close()?;
+    alerts::close()?;
+    dlq::REVIEW.close()?;
+    parse_errors::close()?;
+    // Input was not read in full, so neither is the state.
+    parse_errors::check()?;
+    send_errors::check()?;
+    memory::check()?;
+
+    if let Some(dir) = &args.state {
+        state::save(dir)?;
+        if let Some(handle) = redis_handle {
+            redis::deliver(outbox::pending(dir)?);
+            redis::stop();
+            let _ = handle.join();
+            outbox::settle(dir, &redis::undelivered())?;
+        }
+    }
+
+    if let Some(path) = &args.report {
+        report::write(path)?;
+    }
+
+    if let Some(path) = &args.top {
+        top::write(path)?;
+    }
+
+    let summary = metrics::summary();
+    if !global.quiet() {
+        eprintln!("{summary}");
+    }
+
+    accepted?;
+    super::check(&args.thresholds, &summary)
+}
+
+/// Accepts transactions on `listener` and hands them on to `tx`, until interrupted or `lost`
+/// the [`lease`], which fails.
+pub(super) async fn accept(
+    listener: TcpListener,
+    tx: &Sender<Parsed>,
+    span: &log::Span,
+    lost: impl Future<Output = Option<String>>,
+) -> Result<(), anyhow::Error> {
+    log::info!(span, "Accepting transactions");
+    let engine = config::engine();
+    tokio::pin!(lost);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        log::error!(span, "Failed to accept connection: {err}");
+                        continue;
+                    }
+                };
+                // Parser is blocking, so every connection gets a thread of its own.
+                let stream = stream.into_std()?;
+                stream.set_nonblocking(false)?;
+                let tx = tx.clone();
+                let span = log::Span::new("parse").with("peer", peer);
+                std::thread::spawn(move || {
+                    log::info!(span, "Connection opened");
+                    backfill::wait();
+                    parser::read(&mut CsvSource::new(stream), &peer.to_string(), Some(Side::Live), &span, &tx, engine);
+                    log::info!(span, "Connection closed");
+                });
+            }
+            _ = tokio::signal::ctrl_c() => {
+                log::info!(span, "Interrupted, waiting for open connections to close");
+                return Ok(());
+            }
+            Some(holder) = &mut lost => {
+                // Whatever comes next is the business of the new holder, and so is the log.
+                event_log::close()?;
+                log::error!(span, "Lease was taken over by {holder}, waiting for open connections to close");
+                anyhow::bail!("Lease was taken over by {holder}");
+            }
+        }
+    }
+}
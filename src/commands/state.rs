@@ -0,0 +1,43 @@
+//! `trp state`: exports state persisted by previous runs to a single file, or imports one,
+//! see [`state`](crate::state) for the layout of exports.
+
+use std::io::Read;
+
+use crate::{
+    cli::{Global, StateAction, StateArgs},
+    state,
+};
+
+pub fn run(global: &Global, args: StateArgs) -> Result<(), anyhow::Error> {
+    let dir = &args.state;
+    let stdio = args.file.as_os_str() == "-";
+    let files = match args.action {
+        StateAction::Export if stdio => state::export(dir, std::io::stdout().lock())?,
+        StateAction::Export => state::export(dir, std::fs::File::create(&args.file)?)?,
+        StateAction::Import { force } => {
+            if state::accounts_file(dir, None).exists() && !force {
+                return Err(anyhow::anyhow!(
+                    "{} already has state, use --force to import into it anyway",
+                    dir.display()
+                ));
+            }
+            let mut export = Vec::new();
+            if stdio {
+                std::io::stdin().lock().read_to_end(&mut export)?;
+            } else {
+                export = std::fs::read(&args.file).map_err(|err| {
+                    anyhow::anyhow!("Failed to read {}: {err}", args.file.display())
+                })?;
+            }
+            state::import(dir, &export)?
+        }
+    };
+    if !global.quiet() {
+        let verb = match args.action {
+            StateAction::Export => "Exported",
+            StateAction::Import { .. } => "Imported",
+        };
+        eprintln!("{verb} {files} files of state in {}", dir.display());
+    }
+    Ok(())
+}
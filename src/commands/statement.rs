@@ -0,0 +1,172 @@
+//! `trp statement`: statement of a single account over a period, rebuilt from an event log.
+
+use serde::Serialize;
+use std::path::Path;
+
+use crate::{
+    cli::StatementArgs, event_log, parser::Record, reference::Accounts, state::AccountRecord,
+    Message,
+};
+
+/// Balances of an account at the start and end of a period, along with every message which
+/// changed the account in between.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub opening: AccountRecord,
+    pub lines: Vec<Line>,
+    pub closing: AccountRecord,
+}
+
+/// Message of a statement, with balances of the account once it was applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    /// Moment of the message, in milliseconds since unix epoch.
+    pub timestamp: u64,
+    /// Type of the message, as in the input.
+    pub kind: &'static str,
+    pub tx: u32,
+    pub amount: Option<f32>,
+    pub available: f32,
+    pub held: f32,
+    pub total: f32,
+    pub locked: bool,
+}
+
+/// Row of the printed statement, opening and closing rows have no transaction.
+#[derive(Debug, Serialize)]
+struct Row {
+    timestamp: Option<u64>,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    tx: Option<u32>,
+    amount: Option<f32>,
+    available: f32,
+    held: f32,
+    total: f32,
+    locked: bool,
+}
+
+impl Row {
+    fn balance(kind: &'static str, timestamp: Option<u64>, account: &AccountRecord) -> Self {
+        Row {
+            timestamp,
+            kind,
+            tx: None,
+            amount: None,
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.locked,
+        }
+    }
+}
+
+impl From<&Line> for Row {
+    fn from(line: &Line) -> Self {
+        Row {
+            timestamp: Some(line.timestamp),
+            kind: line.kind,
+            tx: Some(line.tx),
+            amount: line.amount,
+            available: line.available,
+            held: line.held,
+            total: line.total,
+            locked: line.locked,
+        }
+    }
+}
+
+/// Statement of `client` between `from` and `to`, both included, rebuilt from `event_log`
+/// with the [`reference`](crate::reference) engine. Messages are taken as of their
+/// timestamps, or as of when they were logged if they have none, like
+/// [`replay::snapshot`](super::replay::snapshot) does. Opening balances include every message
+/// before `from`, messages which were rejected or took no effect are left out.
+pub fn statement(
+    event_log: &Path,
+    client: u16,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> Result<Statement, anyhow::Error> {
+    let mut accounts = Accounts::default();
+
+    // Timestamps of messages need not be ordered, so the log is read once for opening
+    // balances and once more for the period.
+    if let Some(before) = from.and_then(|from| from.checked_sub(1)) {
+        let mut reader = event_log::Reader::open(event_log, None, Some(before))?;
+        while let Some(entry) = reader.next_entry() {
+            if let Some((_, message)) = message_of(entry, client) {
+                accounts.apply(&message);
+            }
+        }
+    }
+    let opening = accounts.get(client).unwrap_or(AccountRecord {
+        client,
+        available: 0.0,
+        held: 0.0,
+        total: 0.0,
+        locked: false,
+    });
+
+    let mut lines = Vec::new();
+    let mut reader = event_log::Reader::open(event_log, None, to)?;
+    while let Some(entry) = reader.next_entry() {
+        let Some((timestamp, message)) = message_of(entry, client) else {
+            continue;
+        };
+        if from.is_some_and(|from| timestamp < from) {
+            continue;
+        }
+        let before = accounts.get(client);
+        accounts.apply(&message);
+        let Some(after) = accounts
+            .get(client)
+            .filter(|after| Some(after) != before.as_ref())
+        else {
+            continue;
+        };
+        lines.push(Line {
+            timestamp,
+            kind: message.kind(),
+            tx: message.transaction_id(),
+            amount: message.amount(),
+            available: after.available,
+            held: after.held,
+            total: after.total,
+            locked: after.locked,
+        });
+    }
+    let closing = accounts
+        .get(client)
+        .ok_or_else(|| anyhow::anyhow!("Client {client} not found in {}", event_log.display()))?;
+
+    Ok(Statement {
+        opening,
+        lines,
+        closing,
+    })
+}
+
+/// Message of `client` in `entry` of the log, along with its moment. Entries which are not
+/// valid messages are skipped, as [`replay`](super::replay) would.
+fn message_of(entry: Result<(u64, Record), anyhow::Error>, client: u16) -> Option<(u64, Message)> {
+    let (moment, record) = entry.ok()?;
+    let message = Message::try_from(&record).ok()?;
+    (message.client_id() == client).then_some((moment, message))
+}
+
+pub fn run(args: StatementArgs) -> Result<(), anyhow::Error> {
+    let Statement {
+        opening,
+        lines,
+        closing,
+    } = statement(&args.event_log, args.client, args.from, args.to)?;
+
+    let mut out = csv::Writer::from_writer(std::io::stdout());
+    out.serialize(Row::balance("opening", args.from, &opening))?;
+    for line in &lines {
+        out.serialize(Row::from(line))?;
+    }
+    out.serialize(Row::balance("closing", args.to, &closing))?;
+    out.flush()?;
+    Ok(())
+}
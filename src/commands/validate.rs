@@ -0,0 +1,258 @@
+//! `trp validate`: checks input without processing it, as a pre-flight gate.
+//!
+//! With `--simulate`, rows without problems are also applied to an [`Engine`] holding
+//! balances of the rows before them, so that transactions the rules would reject, e.g.
+//! withdrawals of more than the client has, are reported by their rejection code. Accounts
+//! are opened as the [account creation](crate::creation) policy of the configuration says.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt::Display,
+};
+
+use crate::{
+    cli::ValidateArgs,
+    config, creation,
+    engine::Engine,
+    format::{self, Format, Source},
+    parser::{self, CSV_ERROR, INVALID_RECORD},
+    Message,
+};
+
+/// Error code of deposits and withdrawals with an amount which is not a positive number.
+const INVALID_AMOUNT: &str = "VL_AMT";
+/// Error code of deposits and withdrawals reusing an id of an earlier transaction.
+const DUPLICATE_TX: &str = "VL_DUPTX";
+/// Error code of disputes, resolves and chargebacks referencing no earlier transaction.
+const UNKNOWN_TX: &str = "VL_NOTX";
+/// Error code of disputes, resolves and chargebacks referencing transaction of another client.
+const FOREIGN_TX: &str = "VL_CLIENT";
+
+/// Problem with a single row of the input.
+#[derive(Debug)]
+pub struct Finding {
+    /// Line of the row, header being line 1.
+    line: u64,
+    code: &'static str,
+    message: String,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {} {}", self.line, self.code, self.message)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub rows: u64,
+    pub findings: Vec<Finding>,
+}
+
+pub fn run(args: ValidateArgs) -> Result<(), anyhow::Error> {
+    if args.lenient_amounts {
+        parser::lenient_amounts();
+    }
+    parser::amount_unit(args.amount_unit);
+    creation::enable(args.seed_accounts.as_deref())?;
+    let mut source = format::source(&args.input, Format::of(&args.input)?, config::engine())?;
+    let report = check(source.as_mut(), args.simulate);
+
+    for finding in &report.findings {
+        println!("{finding}");
+    }
+    println!(
+        "Checked {} rows of {}, found {} problems",
+        report.rows,
+        args.input.display(),
+        report.findings.len()
+    );
+
+    if report.findings.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "{} failed validation",
+            args.input.display()
+        ))
+    }
+}
+
+/// Reads all of `source`, collecting problems which would cause rows to be dropped or
+/// rejected when processed, along with rejections of the rules when `simulate`.
+pub fn check(source: &mut dyn Source, simulate: bool) -> Report {
+    let mut report = Report::default();
+    // Deposits and withdrawals seen so far, with their client and line.
+    let mut transactions: HashMap<u32, (u16, u64)> = HashMap::new();
+    // Accounts as of the rows so far, when simulating.
+    let mut engine = Engine::default().creating(creation::creates);
+
+    while let Some(result) = source.next_record() {
+        let line = source.line();
+        report.rows += 1;
+        let found = report.findings.len();
+        let mut finding = |code, message: String| {
+            report.findings.push(Finding {
+                line,
+                code,
+                message,
+            })
+        };
+
+        let record = match result {
+            Ok(record) => record,
+            Err(err) => {
+                finding(CSV_ERROR, format!("Failed to parse record: {err}"));
+                continue;
+            }
+        };
+        let message = match Message::try_from(&record) {
+            Ok(message) => message,
+            Err(err) => {
+                finding(INVALID_RECORD, format!("{err}: {record:?}"));
+                continue;
+            }
+        };
+
+        let client = message.client_id();
+        let tx = message.transaction_id();
+        match message {
+            Message::Deposit { amount, .. } | Message::Withdraw { amount, .. } => {
+                if !amount.is_finite() || amount <= 0.0 {
+                    finding(INVALID_AMOUNT, format!("Amount {amount} is not positive"));
+                }
+                match transactions.entry(tx) {
+                    Entry::Occupied(entry) => finding(
+                        DUPLICATE_TX,
+                        format!("Transaction {tx} is already used on line {}", entry.get().1),
+                    ),
+                    Entry::Vacant(entry) => {
+                        entry.insert((client, line));
+                    }
+                }
+            }
+            Message::Dispute { .. }
+            | Message::Resolve { .. }
+            | Message::Chargeback { .. }
+            | Message::Settle { .. } => match transactions.get(&tx) {
+                None => finding(
+                    UNKNOWN_TX,
+                    format!("Transaction {tx} does not precede the {}", message.kind()),
+                ),
+                Some((owner, _)) if *owner != client => finding(
+                    FOREIGN_TX,
+                    format!("Transaction {tx} belongs to client {owner}, not {client}"),
+                ),
+                Some(_) => {}
+            },
+        }
+
+        if !simulate || report.findings.len() > found {
+            continue;
+        }
+        if let Err(rejection) = engine.apply(&message) {
+            report.findings.push(Finding {
+                line,
+                code: rejection.code(),
+                message: format!("The {} would be rejected: {rejection}", message.kind()),
+            });
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+    use crate::format::{CsvSource, NdjsonSource};
+
+    fn codes(input: &str) -> Vec<(u64, &'static str)> {
+        simulated(input, false)
+    }
+
+    fn simulated(input: &str, simulate: bool) -> Vec<(u64, &'static str)> {
+        check(&mut CsvSource::new(input.as_bytes()), simulate)
+            .findings
+            .iter()
+            .map(|finding| (finding.line, finding.code))
+            .collect()
+    }
+
+    #[test]
+    fn valid_input_has_no_findings() {
+        let input = "type,client,tx,amount
+deposit,1,1,1.0
+withdrawal,1,2,0.5
+dispute,1,1,
+resolve,1,1,
+";
+        assert_eq!(codes(input), vec![]);
+        assert_eq!(simulated(input, true), vec![]);
+    }
+
+    #[test]
+    fn rejections_of_the_rules_are_reported_when_simulating() {
+        let input = "type,client,tx,amount
+deposit,1,1,1.0
+withdrawal,1,2,1.5
+withdrawal,2,3,1.0
+dispute,1,1,
+chargeback,1,1,
+deposit,1,4,1.0
+withdrawal,1,5,-1.0
+";
+        assert_eq!(codes(input), vec![(8, "VL_AMT")]);
+        assert_eq!(
+            simulated(input, true),
+            vec![
+                (3, "PE_INSF"),
+                (4, "RT_NOACC"),
+                (7, "PE_ACCLCK"),
+                (8, "VL_AMT")
+            ]
+        );
+    }
+
+    #[test]
+    fn problems_are_reported_by_line() {
+        let input = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,1,1.0
+withdrawal,1,2,-1.0
+dispute,1,3,
+chargeback,2,2,
+deposit,x,4,1.0
+resolve,1,1,2.0
+";
+        assert_eq!(
+            codes(input),
+            vec![
+                (3, "VL_DUPTX"),
+                (4, "VL_AMT"),
+                (5, "VL_NOTX"),
+                (6, "VL_CLIENT"),
+                (7, "PR_CSV"),
+                (8, "PR_INVLD"),
+            ]
+        );
+    }
+
+    #[test]
+    fn ndjson_input_is_checked() {
+        let input = r#"{"type":"deposit","client":1,"tx":1,"amount":1.0}
+{"type":"withdrawal","client":1,"tx":1,"amount":0.5}
+not json
+{"type":"dispute","client":2,"tx":1}
+"#;
+        let findings: Vec<_> = check(&mut NdjsonSource::new(input.as_bytes()), false)
+            .findings
+            .iter()
+            .map(|finding| (finding.line, finding.code))
+            .collect();
+        assert_eq!(
+            findings,
+            vec![(2, "VL_DUPTX"), (3, "PR_CSV"), (4, "VL_CLIENT")]
+        );
+    }
+}
@@ -0,0 +1,879 @@
+//! Configuration file, passed with `--config trp.toml`.
+//!
+//! Only the subset of TOML the file needs is supported: `[tables]` holding `key = value`
+//! pairs, with string, integer, float and boolean values.
+//!
+//! Every value can also be set with a `TRP_<TABLE>_<KEY>` environment variable, e.g.
+//! `TRP_ENGINE_PARSER_CHANNEL_SIZE=1000`, which takes precedence over the file. Options given
+//! on the command line take precedence over both.
+//!
+//! ```toml
+//! [log]
+//! format = "json"
+//! level = "info"
+//! redact = "hash"
+//!
+//! [engine]
+//! parser_channel_size = 1000
+//! parser_threads = 1
+//! account_channel_size = 100
+//! result_channel_size = 100
+//! writer_batch_size = 1024
+//! writer_flush_interval_ms = 1000
+//! writer_retry_budget_ms = 30000
+//! writer_retry_backoff_ms = 100
+//! unknown_disputes = "ignore"
+//! input_backend = "buffered"
+//! retention = "all"
+//! account_creation = "deposit"
+//!
+//! [source]
+//! path = "transactions.csv"  # trp process
+//! rate = 1000                # messages per second, trp process and trp replay
+//! listen = "0.0.0.0:7878"    # trp serve
+//!
+//! [metrics]
+//! addr = "0.0.0.0:9100"
+//! file = "/var/lib/node_exporter/trp.prom"
+//!
+//! [redis]
+//! addr = "127.0.0.1:6379"
+//! prefix = "trp:client:"
+//!
+//! [grpc]
+//! addr = "0.0.0.0:50051"
+//!
+//! [flight]
+//! addr = "0.0.0.0:50052"
+//!
+//! [otel]
+//! endpoint = "http://localhost:4318"
+//!
+//! [state]
+//! dir = "/var/lib/trp"
+//! evict_after = 3600000  # evict accounts idle this long, milliseconds
+//!
+//! [events]
+//! log = "/var/lib/trp/events.csv"
+//!
+//! [dlq]
+//! path = "/var/lib/trp/dlq.csv"
+//! unwritten = "/var/lib/trp/unwritten.csv"
+//!
+//! [signature]
+//! key_file = "/etc/trp/signature.key"
+//!
+//! [velocity]
+//! window = 100
+//! max_withdrawals = 20
+//! max_withdrawn = 10000.0
+//!
+//! [input]
+//! delimiter = ";"
+//!
+//! [columns]  # header of the input holding each column, see `trp inspect`
+//! type = "Kind"
+//! client = "Customer ID"
+//!
+//! [output]
+//! columns = "client,available,held,total,currency"
+//!
+//! [output.names]
+//! client = "client_id"
+//!
+//! [output.values]
+//! currency = "EUR"
+//!
+//! [parse]
+//! on_error = "quarantine"
+//! lenient_amounts = true
+//! amount_unit = "minor"  # amounts are integers of cents
+//! quarantine = "/var/lib/trp/quarantine.csv"
+//!
+//! [alerts]
+//! path = "/var/lib/trp/alerts.csv"
+//!
+//! [screening]
+//! watchlist = "/etc/trp/watchlist.csv"
+//! review = "/var/lib/trp/review.csv"
+//!
+//! [reorder]
+//! lateness = 5000
+//! ordering = "timestamp-merge"
+//!
+//! [report]
+//! path = "/var/lib/trp/report.csv"
+//! period = "hour"
+//!
+//! [top]
+//! path = "/var/lib/trp/top.csv"
+//! n = 10
+//!
+//! [settlement]
+//! pending = true
+//! after = 86400000
+//!
+//! [tiers]
+//! clients = "/etc/trp/tiers.csv"
+//! rules = "/etc/trp/tier-rules.csv"
+//!
+//! [accounts]
+//! seed = "/etc/trp/accounts.csv"  # with account_creation = "seeded"
+//!
+//! [reserve]
+//! min_balance = "100.0,gold:1000.0"
+//!
+//! [interest]
+//! rates = "0.02,1798761600000:0.025"
+//! posting = "month"
+//!
+//! [exit]
+//! max_rejects = 1000
+//! max_reject_rate = 0.01
+//! strict = true
+//! ```
+
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+use crate::{
+    cli::{ParseErrors, Thresholds, Velocity},
+    creation,
+    format::{Mapping, COLUMNS},
+    interest::{Posting, Schedule},
+    log, ordering, orphans,
+    parser::AmountUnit,
+    readahead,
+    report::Period,
+    reserve::Minimums,
+    retention::Retention,
+    schema::Schema,
+    settlement::Settlement,
+};
+
+const ENV_PREFIX: &str = "TRP_";
+
+/// Tables of the file, which names of environment variables start with after the prefix,
+/// nested ones with `_` in place of `.`.
+const TABLES: &[&str] = &[
+    "log",
+    "engine",
+    "source",
+    "metrics",
+    "redis",
+    "grpc",
+    "flight",
+    "otel",
+    "state",
+    "events",
+    "dlq",
+    "signature",
+    "velocity",
+    "input",
+    "columns",
+    "output",
+    "output.names",
+    "output.values",
+    "parse",
+    "alerts",
+    "screening",
+    "reorder",
+    "report",
+    "top",
+    "settlement",
+    "tiers",
+    "accounts",
+    "reserve",
+    "interest",
+    "exit",
+];
+
+static ENGINE: OnceLock<Engine> = OnceLock::new();
+
+/// Tunables of the processing pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Engine {
+    /// Messages parsed ahead of the router.
+    pub parser_channel_size: usize,
+    /// Threads deserializing csv input, see [`parallel`](crate::parallel).
+    pub parser_threads: usize,
+    /// Messages queued for every account task.
+    pub account_channel_size: usize,
+    /// Final account states queued for the writer.
+    pub result_channel_size: usize,
+    /// Rows the writer hands to its sink at once.
+    pub writer_batch_size: usize,
+    /// Longest time rows wait in a batch of the writer, as long as more rows come.
+    pub writer_flush_interval_ms: u64,
+    /// Longest time a failed write of the writer is retried for.
+    pub writer_retry_budget_ms: u64,
+    /// Wait before the first retry of a failed write, doubled for every next one.
+    pub writer_retry_backoff_ms: u64,
+    /// What happens to disputes of transactions unknown to their account, see [`orphans`].
+    pub unknown_disputes: orphans::Policy,
+    /// How input files are read, see [`readahead`].
+    pub input_backend: readahead::Backend,
+    /// Transaction history kept, see [`retention`](crate::retention).
+    pub retention: Retention,
+    /// Which messages create accounts, see [`creation`].
+    pub account_creation: creation::Policy,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine {
+            parser_channel_size: 100,
+            parser_threads: 1,
+            account_channel_size: 100,
+            result_channel_size: 100,
+            writer_batch_size: 1024,
+            writer_flush_interval_ms: 1000,
+            writer_retry_budget_ms: 0,
+            writer_retry_backoff_ms: 100,
+            unknown_disputes: orphans::Policy::Ignore,
+            input_backend: readahead::Backend::Buffered,
+            retention: Retention::All,
+            account_creation: creation::Policy::Deposit,
+        }
+    }
+}
+
+/// Sets tunables for the rest of the run. Only the first call has effect, later ones fail
+/// unless they set the same tunables.
+pub fn set_engine(engine: Engine) -> Result<(), anyhow::Error> {
+    match ENGINE.get_or_init(|| engine) {
+        set if *set == engine => Ok(()),
+        set => Err(anyhow::anyhow!(
+            "Engine configuration is already set for this process: {set:?}"
+        )),
+    }
+}
+
+/// Tunables set with [`set_engine`], or defaults when it wasn't called.
+pub fn engine() -> Engine {
+    ENGINE.get().copied().unwrap_or_default()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+}
+
+/// Values read from configuration file, `None` when the file does not set them.
+#[derive(Debug, Default)]
+pub struct Config {
+    pub log_format: Option<log::Format>,
+    pub log_level: Option<log::Level>,
+    pub redact: Option<log::Redact>,
+    pub engine: Engine,
+    /// Input of `trp process`.
+    pub input: Option<PathBuf>,
+    /// Address `trp serve` accepts transactions on.
+    pub listen: Option<String>,
+    /// Messages per second, see [`pacing`](crate::pacing).
+    pub rate: Option<f64>,
+    pub metrics_addr: Option<String>,
+    pub metrics_file: Option<PathBuf>,
+    /// Redis `trp serve` keeps balances in, see [`redis`](crate::redis).
+    pub redis: Option<String>,
+    pub redis_prefix: Option<String>,
+    /// Address `trp serve` streams account events on, see [`grpc`](crate::grpc).
+    pub grpc_addr: Option<String>,
+    /// Address `trp serve` serves balances on, see [`flight`](crate::flight).
+    pub flight_addr: Option<String>,
+    #[cfg(feature = "otel")]
+    pub otlp_endpoint: Option<String>,
+    /// Directory state is persisted to, see [`state`](crate::state).
+    pub state: Option<PathBuf>,
+    /// Idle time after which `trp serve` evicts accounts, see [`dormant`](crate::dormant).
+    pub evict_after: Option<u64>,
+    /// See [`event_log`](crate::event_log).
+    pub event_log: Option<PathBuf>,
+    /// See [`dlq`](crate::dlq).
+    pub dlq: Option<PathBuf>,
+    /// Rows the writer gave up on, see [`writer`](crate::writer).
+    pub unwritten: Option<PathBuf>,
+    /// See [`signature`](crate::signature).
+    pub signature_key: Option<PathBuf>,
+    pub velocity: Velocity,
+    /// Layout of csv input, see [`Mapping`].
+    pub mapping: Mapping,
+    /// Columns of output, see [`schema`](crate::schema).
+    pub schema: Schema,
+    /// See [`parse_errors`](crate::parse_errors).
+    pub parse_errors: ParseErrors,
+    /// See [`parser::amount`](crate::parser::amount).
+    pub lenient_amounts: bool,
+    /// See [`parser::amount`](crate::parser::amount).
+    pub amount_unit: AmountUnit,
+    /// See [`alerts`](crate::alerts).
+    pub alerts: Option<PathBuf>,
+    /// See [`screening`](crate::screening).
+    pub watchlist: Option<PathBuf>,
+    /// Queue of messages held for review, see [`dlq`](crate::dlq).
+    pub review: Option<PathBuf>,
+    /// Lateness of reordered messages, see [`reorder`](crate::reorder).
+    pub reorder: Option<u64>,
+    /// See [`ordering`](crate::ordering).
+    pub ordering: Option<ordering::Policy>,
+    /// See [`report`](crate::report).
+    pub report: Option<PathBuf>,
+    pub report_period: Option<Period>,
+    /// See [`top`](crate::top).
+    pub top: Option<PathBuf>,
+    pub top_n: Option<usize>,
+    /// Pending settlement of withdrawals, see [`settlement`](crate::settlement).
+    pub settlement: Option<Settlement>,
+    /// See [`tiers`](crate::tiers).
+    pub tiers: Option<PathBuf>,
+    pub tier_rules: Option<PathBuf>,
+    /// Clients accounts are created for, see [`creation`].
+    pub seed_accounts: Option<PathBuf>,
+    /// Minimum balances, see [`reserve`](crate::reserve).
+    pub min_balance: Option<Minimums>,
+    /// Interest accrual, see [`interest`](crate::interest).
+    pub interest_rates: Option<Schedule>,
+    pub interest_posting: Option<Posting>,
+    pub thresholds: Thresholds,
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
+        std::fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| text.parse())
+            .map_err(|err| anyhow::anyhow!("Failed to load {}: {err}", path.display()))
+    }
+
+    /// Overrides values with `TRP_<TABLE>_<KEY>` variables of `vars`, e.g.
+    /// `TRP_OUTPUT_NAMES_AVAILABLE` for `available` of `[output.names]`. Unlike keys of the
+    /// file, unknown variables are ignored, since `TRP_` prefix is shared with other settings,
+    /// such as `TRP_LOG`.
+    pub fn merge_env<I>(&mut self, vars: I) -> Result<(), anyhow::Error>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut problems = Vec::new();
+        for (name, value) in vars {
+            let Some((table, key)) = name.strip_prefix(ENV_PREFIX).and_then(split_env) else {
+                continue;
+            };
+            if let Err(err) = self.set(table, &key.to_ascii_lowercase(), Value::String(value)) {
+                problems.push(format!("Invalid {name}: {err}"));
+            }
+        }
+        aggregate(problems)
+    }
+
+    /// Fails when values which are valid on their own contradict each other, or would only
+    /// fail the run once it's under way, with every such problem at once.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        let mut problems = Vec::new();
+        if self.seed_accounts.is_some() && self.engine.account_creation != creation::Policy::Seeded
+        {
+            problems.push(format!(
+                "accounts.seed requires engine.account_creation = \"seeded\", not \"{}\"",
+                self.engine.account_creation
+            ));
+        }
+        if self.engine.writer_retry_budget_ms > 0 && self.engine.writer_retry_backoff_ms == 0 {
+            problems.push(
+                "engine.writer_retry_backoff_ms must be above 0 when engine.writer_retry_budget_ms is"
+                    .to_string(),
+            );
+        }
+        for (key, addr) in [
+            ("source.listen", &self.listen),
+            ("metrics.addr", &self.metrics_addr),
+            ("redis.addr", &self.redis),
+            ("grpc.addr", &self.grpc_addr),
+            ("flight.addr", &self.flight_addr),
+        ] {
+            if let Some(problem) = addr.as_deref().and_then(|addr| address(key, addr)) {
+                problems.push(problem);
+            }
+        }
+        #[cfg(feature = "otel")]
+        if let Some(endpoint) = &self.otlp_endpoint {
+            if !endpoint.starts_with("http://") {
+                problems.push(format!(
+                    "otel.endpoint must start with http://, not {endpoint}"
+                ));
+            }
+        }
+        aggregate(problems)
+    }
+
+    /// Sets the value of `table.key`. Returns `false` when the key is not known.
+    fn set(&mut self, table: &str, key: &str, value: Value) -> Result<bool, anyhow::Error> {
+        let invalid = |expected: &str| anyhow::anyhow!("{table}.{key} must be {expected}");
+        let string = |value: Value| match value {
+            Value::String(value) => Ok(value),
+            _ => Err(invalid("a string")),
+        };
+        // Environment variables are always strings.
+        let size = |value: Value| match value {
+            Value::Integer(value) if value > 0 => Ok(value as usize),
+            Value::String(value) => match value.parse() {
+                Ok(value) if value > 0 => Ok(value),
+                _ => Err(invalid("a positive integer")),
+            },
+            _ => Err(invalid("a positive integer")),
+        };
+        let count = |value: Value| match value {
+            Value::Integer(value) if value >= 0 => Ok(value as u64),
+            Value::String(value) => value.parse().map_err(|_| invalid("a count")),
+            _ => Err(invalid("a count")),
+        };
+        let flag = |value: Value| match value {
+            Value::Boolean(value) => Ok(value),
+            Value::String(value) => value.parse().map_err(|_| invalid("a boolean")),
+            _ => Err(invalid("a boolean")),
+        };
+        let rate = |value: Value| {
+            let rate = match value {
+                Value::Float(value) => Some(value),
+                Value::Integer(value) => Some(value as f64),
+                Value::String(value) => value.parse().ok(),
+                Value::Boolean(_) => None,
+            };
+            rate.filter(|rate| (0.0..=1.0).contains(rate))
+                .ok_or_else(|| invalid("a number within 0..=1"))
+        };
+        let amount = |value: Value| {
+            let amount = match value {
+                Value::Float(value) => Some(value),
+                Value::Integer(value) => Some(value as f64),
+                Value::String(value) => value.parse().ok(),
+                Value::Boolean(_) => None,
+            };
+            amount
+                .filter(|amount| *amount >= 0.0)
+                .ok_or_else(|| invalid("a non-negative amount"))
+        };
+
+        match (table, key) {
+            ("log", "format") => self.log_format = Some(string(value)?.parse()?),
+            ("log", "level") => self.log_level = Some(string(value)?.parse()?),
+            ("log", "redact") => self.redact = Some(string(value)?.parse()?),
+            ("engine", "parser_channel_size") => self.engine.parser_channel_size = size(value)?,
+            ("engine", "parser_threads") => self.engine.parser_threads = size(value)?,
+            ("engine", "account_channel_size") => self.engine.account_channel_size = size(value)?,
+            ("engine", "result_channel_size") => self.engine.result_channel_size = size(value)?,
+            ("engine", "writer_batch_size") => self.engine.writer_batch_size = size(value)?,
+            ("engine", "writer_flush_interval_ms") => {
+                self.engine.writer_flush_interval_ms = count(value)?
+            }
+            ("engine", "writer_retry_budget_ms") => {
+                self.engine.writer_retry_budget_ms = count(value)?
+            }
+            ("engine", "writer_retry_backoff_ms") => {
+                self.engine.writer_retry_backoff_ms = count(value)?
+            }
+            ("engine", "unknown_disputes") => {
+                self.engine.unknown_disputes = string(value)?.parse()?
+            }
+            ("engine", "input_backend") => self.engine.input_backend = string(value)?.parse()?,
+            ("engine", "retention") => self.engine.retention = string(value)?.parse()?,
+            ("engine", "account_creation") => {
+                self.engine.account_creation = string(value)?.parse()?
+            }
+            ("source", "path") => self.input = Some(string(value)?.into()),
+            ("source", "listen") => self.listen = Some(string(value)?),
+            ("source", "rate") => {
+                let rate = amount(value)?;
+                if rate == 0.0 {
+                    return Err(invalid("a rate above 0"));
+                }
+                self.rate = Some(rate);
+            }
+            ("metrics", "addr") => self.metrics_addr = Some(string(value)?),
+            ("metrics", "file") => self.metrics_file = Some(string(value)?.into()),
+            ("redis", "addr") => self.redis = Some(string(value)?),
+            ("redis", "prefix") => self.redis_prefix = Some(string(value)?),
+            ("grpc", "addr") => self.grpc_addr = Some(string(value)?),
+            ("flight", "addr") => self.flight_addr = Some(string(value)?),
+            #[cfg(feature = "otel")]
+            ("otel", "endpoint") => self.otlp_endpoint = Some(string(value)?),
+            ("state", "dir") => self.state = Some(string(value)?.into()),
+            ("state", "evict_after") => self.evict_after = Some(count(value)?),
+            ("events", "log") => self.event_log = Some(string(value)?.into()),
+            ("dlq", "path") => self.dlq = Some(string(value)?.into()),
+            ("dlq", "unwritten") => self.unwritten = Some(string(value)?.into()),
+            ("signature", "key_file") => self.signature_key = Some(string(value)?.into()),
+            ("velocity", "window") => self.velocity.window = size(value)?,
+            ("velocity", "max_withdrawals") => self.velocity.max_withdrawals = Some(count(value)?),
+            ("velocity", "max_withdrawn") => self.velocity.max_withdrawn = Some(amount(value)?),
+            ("input", "delimiter") => {
+                self.mapping.delimiter = match string(value)?.as_bytes() {
+                    [delimiter] => *delimiter,
+                    _ => return Err(invalid("a single ascii character")),
+                }
+            }
+            ("columns", column) if COLUMNS.contains(&column) => {
+                self.mapping
+                    .columns
+                    .insert(column.to_string(), string(value)?);
+            }
+            ("output", "columns") => {
+                self.schema.columns = string(value)?
+                    .split(',')
+                    .map(|column| column.trim().to_string())
+                    .filter(|column| !column.is_empty())
+                    .collect();
+            }
+            ("output.names", column) => {
+                self.schema.names.insert(column.to_string(), string(value)?);
+            }
+            ("output.values", column) => {
+                self.schema
+                    .values
+                    .insert(column.to_string(), string(value)?);
+            }
+            ("parse", "on_error") => self.parse_errors.policy = string(value)?.parse()?,
+            ("parse", "lenient_amounts") => self.lenient_amounts = flag(value)?,
+            ("parse", "amount_unit") => self.amount_unit = string(value)?.parse()?,
+            ("parse", "quarantine") => self.parse_errors.quarantine = Some(string(value)?.into()),
+            ("alerts", "path") => self.alerts = Some(string(value)?.into()),
+            ("screening", "watchlist") => self.watchlist = Some(string(value)?.into()),
+            ("screening", "review") => self.review = Some(string(value)?.into()),
+            ("reorder", "lateness") => self.reorder = Some(count(value)?),
+            ("reorder", "ordering") => self.ordering = Some(string(value)?.parse()?),
+            ("report", "path") => self.report = Some(string(value)?.into()),
+            ("report", "period") => self.report_period = Some(string(value)?.parse()?),
+            ("top", "path") => self.top = Some(string(value)?.into()),
+            ("top", "n") => self.top_n = Some(size(value)?),
+            ("settlement", "pending") => {
+                self.settlement = flag(value)?.then(|| self.settlement.unwrap_or_default());
+            }
+            ("settlement", "after") => {
+                self.settlement.get_or_insert_default().after = Some(count(value)?);
+            }
+            ("tiers", "clients") => self.tiers = Some(string(value)?.into()),
+            ("tiers", "rules") => self.tier_rules = Some(string(value)?.into()),
+            ("accounts", "seed") => self.seed_accounts = Some(string(value)?.into()),
+            ("reserve", "min_balance") => self.min_balance = Some(string(value)?.parse()?),
+            ("interest", "rates") => self.interest_rates = Some(string(value)?.parse()?),
+            ("interest", "posting") => self.interest_posting = Some(string(value)?.parse()?),
+            ("exit", "max_rejects") => self.thresholds.max_rejects = Some(count(value)?),
+            ("exit", "max_reject_rate") => self.thresholds.max_reject_rate = Some(rate(value)?),
+            ("exit", "strict") => self.thresholds.strict = flag(value)?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut config = Config::default();
+        let mut problems = Vec::new();
+        // Typos should not go unnoticed.
+        for (table, key, value) in parse(text)? {
+            match config.set(&table, &key, value) {
+                Ok(true) => {}
+                Ok(false) => problems.push(format!("Unknown option {table}.{key}")),
+                Err(err) => problems.push(err.to_string()),
+            }
+        }
+        aggregate(problems)?;
+        Ok(config)
+    }
+}
+
+/// Table and key of `name`, a variable without the prefix, matching the longest table so that
+/// keys of nested tables don't end up as keys of their parent, and keys may have `_` in them.
+fn split_env(name: &str) -> Option<(&'static str, &str)> {
+    TABLES
+        .iter()
+        .filter_map(|table| {
+            let prefix = table.to_ascii_uppercase().replace('.', "_");
+            let key = name.strip_prefix(&prefix)?.strip_prefix('_')?;
+            Some((prefix.len(), *table, key))
+        })
+        .max_by_key(|(len, _, _)| *len)
+        .map(|(_, table, key)| (table, key))
+}
+
+/// Fails with every one of `problems` at once, so that they can all be fixed in one go, rather
+/// than one run at a time.
+pub fn aggregate(problems: Vec<String>) -> Result<(), anyhow::Error> {
+    match problems.as_slice() {
+        [] => Ok(()),
+        [problem] => Err(anyhow::anyhow!("{problem}")),
+        problems => Err(anyhow::anyhow!(
+            "{} problems:\n{}",
+            problems.len(),
+            problems
+                .iter()
+                .map(|problem| format!("  - {problem}"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )),
+    }
+}
+
+/// Problem with `addr` of `name`, unless it's a `host:port` address.
+pub fn address(name: &str, addr: &str) -> Option<String> {
+    match addr.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => None,
+        _ => Some(format!("{name} must be host:port, not {addr}")),
+    }
+}
+
+/// Parses `text` into `(table, key, value)` triples, in order of appearance. Keys before the
+/// first table have empty table name.
+pub fn parse(text: &str) -> Result<Vec<(String, String, Value)>, anyhow::Error> {
+    let mut table = String::new();
+    let mut entries = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let error = |reason: &str| anyhow::anyhow!("line {}: {reason}", number + 1);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(rest) = line.strip_prefix('[') {
+            let (name, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| error("unclosed table"))?;
+            if !is_comment(rest) {
+                return Err(error("unexpected text after table"));
+            }
+            table = name.trim().to_string();
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| error("expected key = value"))?;
+        let key = key.trim();
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(error("invalid key"));
+        }
+        let value = parse_value(value.trim()).map_err(|reason| error(&reason))?;
+        entries.push((table.clone(), key.to_string(), value));
+    }
+
+    Ok(entries)
+}
+
+fn is_comment(rest: &str) -> bool {
+    let rest = rest.trim();
+    rest.is_empty() || rest.starts_with('#')
+}
+
+fn parse_value(text: &str) -> Result<Value, String> {
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' if is_comment(&rest[i + 1..]) => return Ok(Value::String(value)),
+                '"' => return Err("unexpected text after string".into()),
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, c @ ('"' | '\\'))) => value.push(c),
+                    _ => return Err("unsupported escape".into()),
+                },
+                c => value.push(c),
+            }
+        }
+        return Err("unclosed string".into());
+    }
+
+    let text = text.split('#').next().unwrap_or_default().trim();
+    match text {
+        "true" => return Ok(Value::Boolean(true)),
+        "false" => return Ok(Value::Boolean(false)),
+        _ => {}
+    }
+    let number = text.replace('_', "");
+    if let Ok(value) = number.parse() {
+        return Ok(Value::Integer(value));
+    }
+    match number.parse() {
+        Ok(value) if !text.is_empty() => Ok(Value::Float(value)),
+        _ => Err(format!("unsupported value {text}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, set_engine, Config, Engine, Value};
+    use crate::{log, parser::AmountUnit};
+
+    #[test]
+    fn values_are_parsed() {
+        let text = r#"
+top = 1
+# comment
+[engine]
+size = 1_000 # trailing comment
+ratio = 0.5
+on = true
+name = "a \"b\" # c"
+"#;
+        let entries = parse(text).unwrap();
+        let entry = |table: &str, key: &str, value| (table.to_string(), key.to_string(), value);
+        assert_eq!(
+            entries,
+            vec![
+                entry("", "top", Value::Integer(1)),
+                entry("engine", "size", Value::Integer(1000)),
+                entry("engine", "ratio", Value::Float(0.5)),
+                entry("engine", "on", Value::Boolean(true)),
+                entry("engine", "name", Value::String(r#"a "b" # c"#.into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        assert!(parse("[engine").is_err());
+        assert!(parse("key").is_err());
+        assert!(parse("key = \"open").is_err());
+        assert!(parse("key = nope").is_err());
+        assert!(parse("key = \"a\" b").is_err());
+    }
+
+    #[test]
+    fn config_is_read() {
+        let config: Config = "[log]\nformat = \"json\"\n[engine]\naccount_channel_size = 8\n[metrics]\naddr = \"127.0.0.1:9100\""
+            .parse()
+            .unwrap();
+        assert_eq!(config.log_format, Some(log::Format::Json));
+        assert_eq!(config.engine.account_channel_size, 8);
+        assert_eq!(config.engine.parser_channel_size, 100);
+        assert_eq!(config.metrics_addr.as_deref(), Some("127.0.0.1:9100"));
+
+        assert!("[engine]\nbogus = 1".parse::<Config>().is_err());
+        assert!("[engine]\nparser_channel_size = 0"
+            .parse::<Config>()
+            .is_err());
+        assert!("[engine]\nwriter_batch_size = 0".parse::<Config>().is_err());
+        let config: Config = "[engine]\nwriter_flush_interval_ms = 0".parse().unwrap();
+        assert_eq!(config.engine.writer_flush_interval_ms, 0);
+        assert!("[log]\nlevel = 3".parse::<Config>().is_err());
+        assert!("[exit]\nmax_reject_rate = 2".parse::<Config>().is_err());
+
+        let config: Config = "[input]\ndelimiter = \"\\t\"\n[columns]\nclient = \"Customer\""
+            .parse()
+            .unwrap();
+        assert_eq!(config.mapping.delimiter, b'\t');
+        assert_eq!(config.mapping.columns["client"], "Customer");
+        assert!("[input]\ndelimiter = \";;\"".parse::<Config>().is_err());
+        assert!("[columns]\nbalance = \"b\"".parse::<Config>().is_err());
+
+        let config: Config = "[output]\ncolumns = \"client, total,currency\"\n[output.names]\nclient = \"client_id\"\n[output.values]\ncurrency = \"EUR\""
+            .parse()
+            .unwrap();
+        assert_eq!(config.schema.columns, ["client", "total", "currency"]);
+        assert_eq!(config.schema.names["client"], "client_id");
+        assert_eq!(config.schema.values["currency"], "EUR");
+
+        let config: Config =
+            "[parse]\non_error = \"quarantine\"\nlenient_amounts = true\namount_unit = \"minor\""
+                .parse()
+                .unwrap();
+        assert!(config.lenient_amounts);
+        assert_eq!(config.amount_unit, AmountUnit::Minor);
+        assert!("[parse]\nlenient_amounts = 1".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn engine_is_set_once() {
+        // Other tests of the crate run with the defaults.
+        assert!(set_engine(Engine::default()).is_ok());
+        assert!(set_engine(Engine::default()).is_ok());
+        let other = Engine {
+            parser_channel_size: 7,
+            ..Engine::default()
+        };
+        assert!(set_engine(other).is_err());
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let mut config: Config = "[engine]\nparser_channel_size = 8\n[metrics]\naddr = \"a:1\""
+            .parse()
+            .unwrap();
+        let vars = |vars: &[(&str, &str)]| {
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+        config
+            .merge_env(vars(&[
+                ("TRP_ENGINE_PARSER_CHANNEL_SIZE", "16"),
+                ("TRP_LOG_FORMAT", "json"),
+                ("TRP_LOG", "warn,apply=debug"),
+                ("TRP_UNKNOWN_KEY", "ignored"),
+                ("PATH", "/bin"),
+            ]))
+            .unwrap();
+        assert_eq!(config.engine.parser_channel_size, 16);
+        assert_eq!(config.log_format, Some(log::Format::Json));
+        assert_eq!(config.metrics_addr.as_deref(), Some("a:1"));
+
+        assert!(config
+            .merge_env(vars(&[("TRP_ENGINE_RESULT_CHANNEL_SIZE", "0")]))
+            .is_err());
+    }
+
+    #[test]
+    fn env_reaches_nested_tables() {
+        let mut config = Config::default();
+        config
+            .merge_env([
+                ("TRP_OUTPUT_COLUMNS".to_string(), "client,total".to_string()),
+                ("TRP_OUTPUT_NAMES_TOTAL".to_string(), "balance".to_string()),
+                ("TRP_OUTPUT_VALUES_LOCKED".to_string(), "yes".to_string()),
+                ("TRP_COLUMNS_CORRELATION_ID".to_string(), "req".to_string()),
+            ])
+            .unwrap();
+        assert_eq!(config.schema.columns, ["client", "total"]);
+        assert_eq!(config.schema.names["total"], "balance");
+        assert_eq!(config.schema.values["locked"], "yes");
+        assert_eq!(config.mapping.columns["correlation_id"], "req");
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let err = "[engine]\nparser_channel_size = 0\nresult_channel_sise = 8\n[redis]\naddr = 1"
+            .parse::<Config>()
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "3 problems:\n  - engine.parser_channel_size must be a positive integer\n  - Unknown option engine.result_channel_sise\n  - redis.addr must be a string"
+        );
+
+        let config: Config = "[engine]\nwriter_retry_budget_ms = 1000\nwriter_retry_backoff_ms = 0\n[accounts]\nseed = \"seed.csv\"\n[redis]\naddr = \"localhost\""
+            .parse()
+            .unwrap();
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.starts_with("3 problems:"), "{err}");
+        assert!(err.contains("accounts.seed requires"), "{err}");
+        assert!(
+            err.contains("redis.addr must be host:port, not localhost"),
+            "{err}"
+        );
+        assert!(Config::default().validate().is_ok());
+    }
+}
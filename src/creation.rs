@@ -0,0 +1,153 @@
+//! Which messages create the account of a client which has none yet, as `account_creation` of
+//! the engine [configuration](crate::config) says, since simulations may need accounts to
+//! exist before their first deposit:
+//!
+//! - `deposit`, the default: only deposits create accounts, anything else is rejected with
+//!   [`NO_ACCOUNT`](crate::engine::NO_ACCOUNT), since it could not succeed on an account
+//!   which has nothing yet.
+//! - `any`: any message creates the account, with zero balances, and is then applied to it
+//!   as it would be to any other account. A withdrawal is rejected for lack of funds, but
+//!   the account stays.
+//! - `seeded`: only clients of the seed file, read with `--seed-accounts`, get accounts, on
+//!   their first message whatever it is. Messages of other clients are rejected with
+//!   [`NO_ACCOUNT`](crate::engine::NO_ACCOUNT), deposits included.
+//!
+//! Seed files are csv with a `client` column, other columns are ignored:
+//!
+//! ```csv
+//! client
+//! 1
+//! 42
+//! ```
+
+use serde::Deserialize;
+use std::{collections::HashSet, fmt::Display, path::Path, str::FromStr, sync::OnceLock};
+
+use crate::{config, Message};
+
+static SEED: OnceLock<HashSet<u16>> = OnceLock::new();
+
+/// Which messages create accounts, see [module](self).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    #[default]
+    Deposit,
+    Any,
+    Seeded,
+}
+
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deposit" => Ok(Policy::Deposit),
+            "any" => Ok(Policy::Any),
+            "seeded" => Ok(Policy::Seeded),
+            other => Err(anyhow::anyhow!(
+                "Unknown account creation policy {other}, expected deposit, any or seeded"
+            )),
+        }
+    }
+}
+
+impl Display for Policy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Policy::Deposit => "deposit",
+            Policy::Any => "any",
+            Policy::Seeded => "seeded",
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Row {
+    client: u16,
+}
+
+/// Clients of the seed file at `path`.
+fn load(path: &Path) -> Result<HashSet<u16>, anyhow::Error> {
+    let read = || -> Result<HashSet<u16>, anyhow::Error> {
+        let mut clients = HashSet::new();
+        for row in csv::Reader::from_path(path)?.deserialize() {
+            let Row { client } = row?;
+            clients.insert(client);
+        }
+        Ok(clients)
+    };
+    read().map_err(|err| anyhow::anyhow!("Failed to load {}: {err}", path.display()))
+}
+
+/// Loads the seed file at `path`, which `seeded` requires, and other policies have no use
+/// for. Only the first call has effect.
+pub fn enable(path: Option<&Path>) -> Result<(), anyhow::Error> {
+    match (config::engine().account_creation, path) {
+        (Policy::Seeded, Some(path)) => {
+            let _ = SEED.set(load(path)?);
+        }
+        (Policy::Seeded, None) => {
+            anyhow::bail!("account_creation = \"seeded\" requires --seed-accounts")
+        }
+        (_, Some(_)) => {
+            anyhow::bail!("--seed-accounts requires account_creation = \"seeded\"")
+        }
+        (_, None) => {}
+    }
+    Ok(())
+}
+
+/// Whether `message` creates the account of its client, when it has none yet.
+pub fn creates(message: &Message) -> bool {
+    creates_under(config::engine().account_creation, message)
+}
+
+/// Whether `message` creates the account of its client under `policy`, rather than the one
+/// the configuration sets.
+pub fn creates_under(policy: Policy, message: &Message) -> bool {
+    creates_with(policy, SEED.get(), message)
+}
+
+fn creates_with(policy: Policy, seed: Option<&HashSet<u16>>, message: &Message) -> bool {
+    match policy {
+        Policy::Deposit => message.is_deposit(),
+        Policy::Any => true,
+        Policy::Seeded => seed.is_some_and(|seed| seed.contains(&message.client_id())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{creates_with, Policy};
+    use crate::Message;
+    use std::collections::HashSet;
+
+    #[test]
+    fn accounts_are_created_as_policy_says() {
+        let deposit = Message::Deposit {
+            client: 1,
+            tx: 1,
+            amount: 1.0,
+            timestamp: None,
+            effective_date: None,
+        };
+        let withdrawal = Message::Withdraw {
+            client: 2,
+            tx: 2,
+            amount: 1.0,
+            timestamp: None,
+        };
+        let seed = HashSet::from([2]);
+        let created = |policy: &str| {
+            let policy: Policy = policy.parse().unwrap();
+            assert_eq!(policy.to_string().parse::<Policy>().unwrap(), policy);
+            [&deposit, &withdrawal].map(|message| creates_with(policy, Some(&seed), message))
+        };
+
+        assert_eq!(created("deposit"), [true, false]);
+        assert_eq!(created("any"), [true, true]);
+        assert_eq!(created("seeded"), [false, true]);
+        assert!(!creates_with(Policy::Seeded, None, &withdrawal));
+        assert!("withdrawal".parse::<Policy>().is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//! Live terminal dashboard for long runs: throughput, channel depths, top accounts by held
+//! funds and most recent rejects, redrawn in place on stderr.
+//!
+//! Account tasks only report to the dashboard once it is [`enable`]d, so runs without
+//! `--dashboard` don't pay for the shared state.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write as _,
+    io::Write as _,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crate::{metrics, progress};
+
+const TOP_ACCOUNTS: usize = 5;
+const RECENT_REJECTS: usize = 10;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STOPPED: AtomicBool = AtomicBool::new(false);
+static STATE: OnceLock<Mutex<State>> = OnceLock::new();
+
+#[derive(Debug, Default)]
+struct State {
+    held: HashMap<u16, f32>,
+    rejects: VecDeque<Reject>,
+}
+
+#[derive(Debug)]
+struct Reject {
+    code: &'static str,
+    client: u16,
+    tx: u32,
+}
+
+fn state() -> &'static Mutex<State> {
+    STATE.get_or_init(Default::default)
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Records current held funds of `client`.
+pub fn held(client: u16, held: f32) {
+    if !enabled() {
+        return;
+    }
+    let mut state = state().lock().unwrap_or_else(|err| err.into_inner());
+    if held > 0.0 {
+        state.held.insert(client, held);
+    } else {
+        state.held.remove(&client);
+    }
+}
+
+/// Records a rejected message, keeping only the most recent ones.
+pub fn rejected(code: &'static str, client: u16, tx: u32) {
+    if !enabled() {
+        return;
+    }
+    let mut state = state().lock().unwrap_or_else(|err| err.into_inner());
+    if state.rejects.len() == RECENT_REJECTS {
+        state.rejects.pop_front();
+    }
+    state.rejects.push_back(Reject { code, client, tx });
+}
+
+fn render() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "trp - {}", progress::snapshot());
+    let _ = writeln!(out);
+
+    let _ = writeln!(out, "Channel depth (current / max)");
+    for (channel, current, max) in metrics::channel_depths() {
+        let _ = writeln!(out, "  {channel:<8} {current:>6} / {max}");
+    }
+    let _ = writeln!(out);
+
+    let state = state().lock().unwrap_or_else(|err| err.into_inner());
+    let mut top: Vec<_> = state.held.iter().collect();
+    top.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    let _ = writeln!(out, "Top accounts by held funds");
+    for (client, held) in top.into_iter().take(TOP_ACCOUNTS) {
+        let _ = writeln!(out, "  client {client:<6} {held:>14.4}");
+    }
+    let _ = writeln!(out);
+
+    let _ = writeln!(out, "Recent rejects");
+    for Reject { code, client, tx } in state.rejects.iter().rev() {
+        let _ = writeln!(out, "  {code:<10} client {client:<6} tx {tx}");
+    }
+
+    out
+}
+
+/// Spawns a thread redrawing the dashboard every `interval`, until [`stop`] is called.
+pub fn run(interval: Duration) -> JoinHandle<()> {
+    std::thread::spawn(move || loop {
+        let stopped = STOPPED.load(Ordering::Acquire);
+        // Move to top-left and clear the screen before every frame.
+        let _ = write!(std::io::stderr().lock(), "\x1b[H\x1b[2J{}", render());
+        if stopped {
+            break;
+        }
+        std::thread::sleep(interval);
+    })
+}
+
+/// Draws the final frame and stops the dashboard thread.
+pub fn stop() {
+    STOPPED.store(true, Ordering::Release);
+}
@@ -0,0 +1,110 @@
+//! Dead letter queue, written with `--dlq`: messages the engine gave up on because their
+//! account task failed, or because their record failed [`signature`](crate::signature)
+//! verification, so they can be inspected and processed again.
+//!
+//! The queue is csv with the columns of the input, followed by `reason`, the error code the
+//! message was dead-lettered with, and `source`, `line`, `correlation_id` and `reference` of
+//! its [`provenance`](crate::provenance). The extra columns but `correlation_id` and
+//! `reference` are ignored when the queue is passed back to `trp process`. It is recreated by
+//! every run.
+//!
+//! Messages [`screening`](crate::screening) holds for review are written with `--review` to
+//! a [`REVIEW`] queue of the same format.
+//!
+//! With `--log-redact`, ids are redacted like in logs, and the queues can't be processed
+//! again.
+
+use serde::Serialize;
+use std::{fs::File, io::BufWriter, path::Path, sync::Mutex};
+
+use crate::{log, provenance::Provenance, Message};
+
+static DLQ: Queue = Queue::new();
+
+/// Messages held for review.
+pub static REVIEW: Queue = Queue::new();
+
+#[derive(Debug, Serialize)]
+struct Letter<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    client: String,
+    tx: String,
+    amount: Option<f32>,
+    reason: &'static str,
+    source: &'a str,
+    line: u64,
+    correlation_id: Option<&'a str>,
+    reference: Option<&'a str>,
+}
+
+/// Csv file of messages, written to once opened.
+pub struct Queue(Mutex<Option<csv::Writer<BufWriter<File>>>>);
+
+impl Queue {
+    const fn new() -> Self {
+        Queue(Mutex::new(None))
+    }
+
+    /// Starts writing messages passed to [`append`](Self::append) to `path`, replacing its
+    /// contents.
+    pub fn open(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let out = csv::Writer::from_writer(BufWriter::new(File::create(path)?));
+        *self.0.lock().unwrap_or_else(|err| err.into_inner()) = Some(out);
+        Ok(())
+    }
+
+    /// Appends `message` read from `provenance` with the code it was given up on with, if the
+    /// queue is open.
+    pub fn append(
+        &self,
+        message: &Message,
+        provenance: &Provenance,
+        reason: &'static str,
+    ) -> Result<(), anyhow::Error> {
+        let mut queue = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        let Some(out) = queue.as_mut() else {
+            return Ok(());
+        };
+        out.serialize(Letter {
+            kind: message.kind(),
+            client: log::id("client", message.client_id()),
+            tx: log::id("tx", message.transaction_id()),
+            amount: message.amount(),
+            reason,
+            source: &provenance.source,
+            line: provenance.line,
+            correlation_id: provenance.correlation_id.as_deref(),
+            reference: provenance.reference.as_deref(),
+        })?;
+        Ok(())
+    }
+
+    /// Flushes and closes the queue.
+    pub fn close(&self) -> Result<(), anyhow::Error> {
+        if let Some(mut out) = self.0.lock().unwrap_or_else(|err| err.into_inner()).take() {
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Starts writing messages passed to [`append`] to `path`, replacing its contents.
+pub fn open(path: &Path) -> Result<(), anyhow::Error> {
+    DLQ.open(path)
+}
+
+/// Appends `message` read from `provenance` with the code of the failure, if the queue is
+/// open.
+pub fn append(
+    message: &Message,
+    provenance: &Provenance,
+    reason: &'static str,
+) -> Result<(), anyhow::Error> {
+    DLQ.append(message, provenance, reason)
+}
+
+/// Flushes and closes the queue.
+pub fn close() -> Result<(), anyhow::Error> {
+    DLQ.close()
+}
@@ -0,0 +1,249 @@
+//! Eviction of dormant accounts of `trp serve`, enabled with `--evict-after <MS>` along with
+//! `--state`, so that accounts of clients which come and go don't stay resident for the
+//! lifetime of the daemon.
+//!
+//! As messages come, the router evicts accounts which got no message for that long: it closes
+//! their inbox, and once their task applied what is left, it parks the account in `dormant/`
+//! of the state directory instead of reporting it, see [`park`]. The next message of the
+//! client reloads the account, deleting its files, and carries on as if it was never gone.
+//! Once the run is over, accounts still parked are reloaded too, so they are written to the
+//! output and to the state like any other.
+//!
+//! Balances, transaction history, activity, counters, value dates of pending funds and ids of
+//! transactions pruned by [`retention`](crate::retention) are parked. Messages held back by `--reorder-lateness` are applied before the account is
+//! parked, as they are at the end of the run, and windows of velocity rules start over once
+//! it's reloaded. Interest accrues on accounts only while they are resident, so eviction
+//! can't be used along with interest.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+use crate::state::TransactionRecord;
+
+const DIR: &str = "dormant";
+
+static SETTINGS: OnceLock<(Duration, PathBuf)> = OnceLock::new();
+
+/// Evicts accounts idle for `ttl` to `dormant/` of state directory `dir` from now on. Only the
+/// first call has effect.
+pub fn enable(ttl: Duration, dir: &Path) -> Result<(), anyhow::Error> {
+    let dir = dir.join(DIR);
+    std::fs::create_dir_all(&dir)?;
+    let _ = SETTINGS.set((ttl, dir));
+    Ok(())
+}
+
+/// How long accounts may be idle before they are evicted, `None` unless [`enable`]d.
+pub fn ttl() -> Option<Duration> {
+    SETTINGS.get().map(|(ttl, _)| *ttl)
+}
+
+/// Eviction of an account, shared by the router and the task of the account.
+#[derive(Debug, Default)]
+pub struct Eviction {
+    /// Set by the router before it closes the inbox of the account.
+    evicting: AtomicBool,
+    /// Set by the task once it's done with the account, one way or another.
+    done: AtomicBool,
+    /// Set by the task when the account could not be parked, and was reported instead.
+    failed: AtomicBool,
+    notify: Notify,
+}
+
+impl Eviction {
+    /// Marks the account as being evicted, to be called before its inbox is closed.
+    pub fn start(&self) {
+        self.evicting.store(true, Ordering::Release);
+    }
+
+    /// Whether the inbox of the account was closed to evict it, rather than because the run
+    /// is over.
+    pub fn evicting(&self) -> bool {
+        self.evicting.load(Ordering::Acquire)
+    }
+
+    /// Marks the account as parked, or as failed to park when `parked` isn't set.
+    pub fn finish(&self, parked: bool) {
+        self.failed.store(!parked, Ordering::Release);
+        self.done.store(true, Ordering::Release);
+        self.notify.notify_waiters();
+    }
+
+    /// Waits for the task to finish with the account, returns `false` when it could not be
+    /// parked.
+    pub async fn wait(&self) -> bool {
+        let notified = self.notify.notified();
+        if !self.done.load(Ordering::Acquire) {
+            notified.await;
+        }
+        !self.failed.load(Ordering::Acquire)
+    }
+}
+
+/// Account as it's parked, along with its transactions in a file of their own.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Parked {
+    pub client: u16,
+    pub available: f32,
+    pub held: f32,
+    pub total: f32,
+    pub locked: bool,
+    pub pending: f32,
+    pub authorized: f32,
+    /// Latest timestamp applied to the account, value dates up to it are due.
+    pub clock: Option<u64>,
+    /// Value dates of pending funds along with their transaction, as `date:tx` separated by
+    /// spaces.
+    pub due: String,
+    pub first_activity: Option<u64>,
+    pub last_activity: Option<u64>,
+    pub applied: u64,
+    pub rejected: u64,
+    pub transfers: u64,
+    /// Transactions pruned from history by [`retention`](crate::retention), separated by
+    /// spaces. Missing from accounts parked before history was pruned.
+    #[serde(default)]
+    pub pruned: String,
+}
+
+fn files(client: u16) -> Result<(PathBuf, PathBuf), anyhow::Error> {
+    let (_, dir) = SETTINGS
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("Eviction is not enabled"))?;
+    Ok((
+        dir.join(format!("{client}.csv")),
+        dir.join(format!("{client}-transactions.csv")),
+    ))
+}
+
+/// Writes `account` and its `transactions` to the dormant directory.
+pub fn park(account: &Parked, transactions: &[TransactionRecord]) -> Result<(), anyhow::Error> {
+    let (account_file, transactions_file) = files(account.client)?;
+    let mut out = csv::Writer::from_path(transactions_file)?;
+    for transaction in transactions {
+        out.serialize(transaction)?;
+    }
+    out.flush()?;
+    // Written last, so that an account is only found once it's complete.
+    let mut out = csv::Writer::from_path(account_file)?;
+    out.serialize(account)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Reads account of `client` and its transactions back, deleting their files.
+pub fn unpark(client: u16) -> Result<(Parked, Vec<TransactionRecord>), anyhow::Error> {
+    let (account_file, transactions_file) = files(client)?;
+    let account = csv::Reader::from_path(&account_file)?
+        .deserialize()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("{} is empty", account_file.display()))??;
+    let transactions = csv::Reader::from_path(&transactions_file)?
+        .deserialize()
+        .collect::<Result<Vec<TransactionRecord>, _>>()?;
+    std::fs::remove_file(account_file)?;
+    std::fs::remove_file(transactions_file)?;
+    Ok((account, transactions))
+}
+
+/// Transactions of accounts parked in state directory `dir` by earlier runs, along with the
+/// file each of them is kept in, for [`compact`](crate::commands::compact).
+pub fn histories(dir: &Path) -> Result<Vec<(PathBuf, Vec<TransactionRecord>)>, anyhow::Error> {
+    let dir = dir.join(DIR);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut histories = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.to_string_lossy().ends_with("-transactions.csv") {
+            continue;
+        }
+        let transactions = csv::Reader::from_path(&path)?
+            .deserialize()
+            .collect::<Result<Vec<TransactionRecord>, _>>()?;
+        histories.push((path, transactions));
+    }
+    histories.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(histories)
+}
+
+/// Replaces history of a parked account kept in `path` with `transactions`, adding `pruned`
+/// to transactions the account remembers were pruned.
+pub fn rewrite(
+    path: &Path,
+    transactions: &[TransactionRecord],
+    pruned: &[u32],
+) -> Result<(), anyhow::Error> {
+    let account_file = path.with_file_name(
+        path.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .replace("-transactions.csv", ".csv"),
+    );
+    let mut account: Parked = csv::Reader::from_path(&account_file)?
+        .deserialize()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("{} is empty", account_file.display()))??;
+    for tx in pruned {
+        if !account.pruned.is_empty() {
+            account.pruned.push(' ');
+        }
+        account.pruned.push_str(&tx.to_string());
+    }
+
+    let tmp = path.with_extension("csv.tmp");
+    let mut out = csv::Writer::from_path(&tmp)?;
+    for transaction in transactions {
+        out.serialize(transaction)?;
+    }
+    out.flush()?;
+    std::fs::rename(&tmp, path)?;
+    let tmp = account_file.with_extension("csv.tmp");
+    let mut out = csv::Writer::from_path(&tmp)?;
+    out.serialize(&account)?;
+    out.flush()?;
+    std::fs::rename(&tmp, account_file)?;
+    Ok(())
+}
+
+/// Value dates of `due` as [`Parked`] keeps them.
+pub fn format_due(due: impl Iterator<Item = (u64, u32)>) -> String {
+    due.map(|(date, tx)| format!("{date}:{tx}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Value dates kept by [`Parked`].
+pub fn parse_due(due: &str) -> Result<Vec<(u64, u32)>, anyhow::Error> {
+    due.split_whitespace()
+        .map(|entry| {
+            let (date, tx) = entry
+                .split_once(':')
+                .ok_or_else(|| anyhow::anyhow!("Invalid due date {entry}"))?;
+            Ok((date.parse()?, tx.parse()?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_due, parse_due};
+
+    #[test]
+    fn due_dates_round_trip() {
+        let due = [(1000, 7), (2000, 3)];
+        assert_eq!(format_due(due.into_iter()), "1000:7 2000:3");
+        assert_eq!(parse_due("1000:7 2000:3").unwrap(), due);
+        assert!(parse_due("").unwrap().is_empty());
+        assert!(parse_due("1000").is_err());
+    }
+}
@@ -0,0 +1,773 @@
+//! Pure processing core: balances of an account, history of its transactions and the rules
+//! which apply messages to them.
+//!
+//! The module depends on nothing but `std` and [`Message`]. There is no runtime, I/O, clock
+//! or global state in here, time only passes with timestamps of messages. That keeps it
+//! buildable on its own for `wasm32` targets, which is checked by the crate in `wasm/`, so
+//! the engine can run in a browser or a WASM sandbox. Everything else, account tasks,
+//! supervision, screening, interest and the rest, is layered on top by the
+//! `processor`.
+//!
+//! [`Engine`] applies messages to accounts of every client one at a time, for embedding.
+//! Embedders add kinds of transactions of their own, such as bonuses or clawbacks, by
+//! implementing [`Apply`] for them, rather than patching [`Message`] and every match on it.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Display,
+    mem::size_of,
+};
+
+use crate::message::Message;
+
+/// Error code of withdrawals over available funds.
+pub const INSUFFICIENT_FUNDS: &str = "PE_INSF";
+/// Error code of withdrawals which would leave less than the minimum balance.
+pub const BELOW_MINIMUM: &str = "PE_MINBAL";
+/// Error code of withdrawals over the limit of the tier.
+pub const OVER_LIMIT: &str = "PE_LIMIT";
+/// Error code of messages for locked accounts.
+pub const ACCOUNT_LOCKED: &str = "PE_ACCLCK";
+/// Error code of messages for clients without an account, which can't create one.
+pub const NO_ACCOUNT: &str = "RT_NOACC";
+/// Error code of disputes, resolves, chargebacks and settles referencing a transaction of
+/// another client.
+pub const CLIENT_MISMATCH: &str = "RT_CLIENT";
+
+/// How withdrawals are settled, once they are pending.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Settlement {
+    /// Milliseconds after which withdrawals settle without a `settle` message, if they do.
+    pub after: Option<u64>,
+}
+
+/// Rules of a tier, for withdrawals.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Rules {
+    /// Largest amount of a single withdrawal.
+    pub max_withdrawal: Option<f32>,
+    /// Charged on top of every withdrawal, out of available funds.
+    pub withdrawal_fee: f32,
+    /// How far below zero withdrawals may take available funds.
+    pub overdraft: f32,
+}
+
+/// State of transaction in transaction history.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transaction<T = f32> {
+    /// Value-dated deposit, whose funds are not available yet.
+    Pending(T),
+    Deposited(T),
+    Disputed(T),
+    Reversed(T),
+    /// Withdrawal pending settlement.
+    Authorized(T),
+    /// Settled withdrawal.
+    Withdrawn(T),
+}
+
+impl<T> Transaction<T> {
+    /// Returns `true` if the transaction is [`Deposited`].
+    ///
+    /// [`Deposited`]: Transaction::Deposited
+    #[must_use]
+    pub fn is_deposited(&self) -> bool {
+        matches!(self, Self::Deposited(..))
+    }
+
+    /// Returns `true` if the transaction is [`Disputed`].
+    ///
+    /// [`Disputed`]: Transaction::Disputed
+    #[must_use]
+    pub fn is_disputed(&self) -> bool {
+        matches!(self, Self::Disputed(..))
+    }
+
+    /// Returns `true` if the transaction is [`Pending`].
+    ///
+    /// [`Pending`]: Transaction::Pending
+    #[must_use]
+    pub fn is_pending(&self) -> bool {
+        matches!(self, Self::Pending(..))
+    }
+
+    /// Returns `true` if the transaction is [`Authorized`].
+    ///
+    /// [`Authorized`]: Transaction::Authorized
+    #[must_use]
+    pub fn is_authorized(&self) -> bool {
+        matches!(self, Self::Authorized(..))
+    }
+}
+
+impl<T: Copy> Transaction<T> {
+    pub fn amount(&self) -> T {
+        match self {
+            Transaction::Pending(x) => *x,
+            Transaction::Deposited(x) => *x,
+            Transaction::Disputed(x) => *x,
+            Transaction::Reversed(x) => *x,
+            Transaction::Authorized(x) => *x,
+            Transaction::Withdrawn(x) => *x,
+        }
+    }
+}
+
+/// Simple in-memory storage for transaction history, along with timestamps of deposits.
+/// Used to lookup [`Message::Deposit`] amounts, and amounts of [`Message::Withdraw`] when
+/// withdrawals are pending settlement.
+/// In a real world situation this could also be a remote store.
+pub type History = HashMap<u32, (Transaction, Option<u64>)>;
+
+/// Message rejected by the rules of the engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    InsufficientFunds,
+    /// Withdrawal would leave less than the minimum balance.
+    BelowMinimum,
+    /// Withdrawal is over the limit of the tier.
+    OverLimit,
+    AccountLocked,
+    /// Message for a client without an account, which only deposits create.
+    NoAccount,
+    /// Message referencing a transaction of another client, see [`Registry`].
+    ClientMismatch,
+}
+
+impl Rejection {
+    /// Short stable code, used in logs and as a metrics label.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Rejection::InsufficientFunds => INSUFFICIENT_FUNDS,
+            Rejection::BelowMinimum => BELOW_MINIMUM,
+            Rejection::OverLimit => OVER_LIMIT,
+            Rejection::AccountLocked => ACCOUNT_LOCKED,
+            Rejection::NoAccount => NO_ACCOUNT,
+            Rejection::ClientMismatch => CLIENT_MISMATCH,
+        }
+    }
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// Value-dated deposits and pending withdrawals of an account, waiting to become due.
+#[derive(Debug, Default, Clone)]
+pub struct Maturing {
+    /// Latest timestamp of messages applied to the account, dates up to it are due.
+    pub(crate) clock: Option<u64>,
+    /// Value dates of pending deposits and settlement dates of pending withdrawals, along with
+    /// their ids, earliest first.
+    pub(crate) due: BTreeSet<(u64, u32)>,
+    /// Settlement of withdrawals, `None` when they are settled as they are applied.
+    pub(crate) settlement: Option<Settlement>,
+}
+
+/// Change a message would make to balances of an account, see [`Book::simulate`].
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Effect {
+    pub available: f32,
+    pub held: f32,
+    pub total: f32,
+    /// Whether the message locks the account.
+    pub locks: bool,
+}
+
+/// Balances of an account, along with what the rules need to apply messages to it.
+#[derive(Debug, Default, Clone)]
+pub struct Book {
+    pub available: f32,
+    pub held: f32,
+    pub total: f32,
+    pub locked: bool,
+    /// Funds of value-dated deposits, counted in `total` until they become available.
+    pub pending: f32,
+    /// Funds of pending withdrawals, counted in `held` and `total` until they are settled.
+    pub authorized: f32,
+    pub(crate) maturing: Maturing,
+    /// Available funds withdrawals can't go below.
+    pub(crate) minimum: f32,
+    /// Rules of the tier of the client.
+    pub(crate) rules: Rules,
+}
+
+impl Book {
+    /// Empty account, with withdrawals settled with `settlement`, none of them going below
+    /// `minimum`, and `rules` of its tier.
+    pub fn new(settlement: Option<Settlement>, minimum: f32, rules: Rules) -> Self {
+        Book {
+            maturing: Maturing {
+                settlement,
+                ..Maturing::default()
+            },
+            minimum,
+            rules,
+            ..Book::default()
+        }
+    }
+
+    /// Available funds withdrawals can't go below.
+    pub fn minimum(&self) -> f32 {
+        self.minimum
+    }
+
+    /// Advances clock of the account to `timestamp`, if there is one, making funds of deposits
+    /// due by then available, and settling withdrawals due by then. Both are due regardless of
+    /// the account being locked.
+    pub fn mature(&mut self, timestamp: Option<u64>, tx_history: &mut History) {
+        if let Some(timestamp) = timestamp {
+            self.maturing.clock = Some(
+                self.maturing
+                    .clock
+                    .map_or(timestamp, |clock| clock.max(timestamp)),
+            );
+        }
+        let Some(clock) = self.maturing.clock else {
+            return;
+        };
+        while let Some(&(date, tx)) = self.maturing.due.first() {
+            if date > clock {
+                break;
+            }
+            self.maturing.due.pop_first();
+            let Some((existing, _)) = tx_history.get_mut(&tx) else {
+                continue;
+            };
+            if existing.is_pending() {
+                let amount = existing.amount();
+                self.pending -= amount;
+                self.available += amount;
+                *existing = Transaction::Deposited(amount);
+            } else if existing.is_authorized() {
+                self.settle(existing);
+            }
+        }
+    }
+
+    /// Settles pending withdrawal `existing`, its funds leave the account.
+    fn settle(&mut self, existing: &mut Transaction) {
+        let amount = existing.amount();
+        self.held -= amount;
+        self.total -= amount;
+        self.authorized -= amount;
+        *existing = Transaction::Withdrawn(amount);
+    }
+
+    /// What applying `message` would do to the account, which is left as it is, along with
+    /// `tx_history`.
+    pub fn simulate(&self, message: &Message, tx_history: &History) -> Result<Effect, Rejection> {
+        let mut book = self.clone();
+        // Messages only ever touch their own transaction.
+        let tx = message.transaction_id();
+        let mut history: History = tx_history
+            .get(&tx)
+            .map(|entry| (tx, *entry))
+            .into_iter()
+            .collect();
+        book.apply(message, &mut history)?;
+        Ok(Effect {
+            available: book.available - self.available,
+            held: book.held - self.held,
+            total: book.total - self.total,
+            locks: book.locked && !self.locked,
+        })
+    }
+
+    /// Applies `message` to the account. Rejected messages leave it as it was.
+    pub fn apply(&mut self, message: &Message, tx_history: &mut History) -> Result<(), Rejection> {
+        // Pending withdrawals were authorized before the account got locked.
+        if self.locked && !message.is_settle() {
+            return Err(Rejection::AccountLocked);
+        }
+        match message {
+            Message::Deposit {
+                tx,
+                amount,
+                timestamp,
+                effective_date,
+                ..
+            } => {
+                let clock = self.maturing.clock;
+                match effective_date.filter(|date| clock.is_none_or(|clock| clock < *date)) {
+                    Some(date) => {
+                        self.pending += amount;
+                        self.total += amount;
+                        self.maturing.due.insert((date, *tx));
+                        tx_history.insert(*tx, (Transaction::Pending(*amount), *timestamp));
+                    }
+                    None => {
+                        self.available += amount;
+                        self.total += amount;
+                        tx_history.insert(*tx, (Transaction::Deposited(*amount), *timestamp));
+                    }
+                }
+            }
+            Message::Withdraw {
+                tx,
+                amount,
+                timestamp,
+                ..
+            } => {
+                let Rules {
+                    max_withdrawal,
+                    withdrawal_fee: fee,
+                    overdraft,
+                } = self.rules;
+                if max_withdrawal.is_some_and(|max| *amount > max) {
+                    return Err(Rejection::OverLimit);
+                }
+                if self.available + overdraft < amount + fee {
+                    return Err(Rejection::InsufficientFunds);
+                }
+                // Overdrafts are allowed below a minimum of none.
+                if self.minimum > 0.0 && self.available - amount - fee < self.minimum {
+                    return Err(Rejection::BelowMinimum);
+                }
+                self.available -= amount;
+                // Fees leave the account right away, even when the withdrawal is pending.
+                self.available -= fee;
+                self.total -= fee;
+                match self.maturing.settlement {
+                    None => self.total -= amount,
+                    Some(settlement) => {
+                        self.held += amount;
+                        self.authorized += amount;
+                        if let Some(due) = timestamp
+                            .zip(settlement.after)
+                            .map(|(timestamp, after)| timestamp.saturating_add(after))
+                        {
+                            self.maturing.due.insert((due, *tx));
+                        }
+                        tx_history.insert(*tx, (Transaction::Authorized(*amount), *timestamp));
+                    }
+                }
+            }
+            Message::Dispute { tx, .. } => {
+                if let Some((existing, _)) = tx_history
+                    .get_mut(tx)
+                    .filter(|(existing, _)| existing.is_deposited())
+                    .filter(|(existing, _)| self.available >= existing.amount())
+                {
+                    let amount = existing.amount();
+                    self.available -= amount;
+                    self.held += amount;
+                    *existing = Transaction::Disputed(amount);
+                }
+            }
+            Message::Resolve { tx, .. } => {
+                if let Some((existing, _)) = tx_history
+                    .get_mut(tx)
+                    .filter(|(existing, _)| existing.is_disputed())
+                {
+                    let amount = existing.amount();
+                    self.available += amount;
+                    self.held -= amount;
+                    *existing = Transaction::Deposited(amount);
+                }
+            }
+            Message::Chargeback { tx, .. } => {
+                if let Some((existing, _)) = tx_history
+                    .get_mut(tx)
+                    .filter(|(existing, _)| existing.is_disputed())
+                {
+                    let amount = existing.amount();
+                    self.held -= amount;
+                    self.total -= amount;
+                    self.locked = true;
+                    *existing = Transaction::Reversed(amount);
+                }
+            }
+            Message::Settle { tx, .. } => {
+                if let Some((existing, _)) = tx_history
+                    .get_mut(tx)
+                    .filter(|(existing, _)| existing.is_authorized())
+                {
+                    self.settle(existing);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Message the [`Engine`] applies: a [`Message`], or a kind of transaction of an embedder.
+///
+/// Kinds of an embedder apply to accounts which exist already: they don't open accounts, and
+/// their transactions are not checked against the [`Registry`]. They also apply to locked
+/// accounts, unless they check [`Book::locked`] themselves.
+pub trait Apply {
+    /// Client whose account it applies to.
+    fn client_id(&self) -> u16;
+
+    /// Timestamp, which makes whatever is due by then mature ahead of applying it.
+    fn timestamp(&self) -> Option<u64> {
+        None
+    }
+
+    /// Itself, when it's a [`Message`].
+    fn message(&self) -> Option<&Message> {
+        None
+    }
+
+    /// Applies it to the account of its client. Rejected messages leave it as it was.
+    fn apply_to(&self, book: &mut Book, history: &mut History) -> Result<(), Rejection>;
+}
+
+impl Apply for Message {
+    fn client_id(&self) -> u16 {
+        Message::client_id(self)
+    }
+
+    fn timestamp(&self) -> Option<u64> {
+        Message::timestamp(self)
+    }
+
+    fn message(&self) -> Option<&Message> {
+        Some(self)
+    }
+
+    fn apply_to(&self, book: &mut Book, history: &mut History) -> Result<(), Rejection> {
+        book.apply(self, history)
+    }
+}
+
+/// Clients owning every deposit and withdrawal seen so far, across accounts. History of an
+/// account only knows transactions of its own client, so a message referencing a
+/// transaction of another client would find nothing to apply to.
+#[derive(Debug, Default)]
+pub struct Registry {
+    /// Client of the first transaction with every id.
+    owners: HashMap<u32, u16>,
+    /// Clients of later transactions reusing an id.
+    reused: HashSet<(u32, u16)>,
+}
+
+impl Registry {
+    /// Registers transaction of `message` to its client when it's a deposit or withdrawal.
+    /// Fails when `message` references a transaction which only other clients have.
+    pub fn check(&mut self, message: &Message) -> Result<(), Rejection> {
+        let client = message.client_id();
+        let tx = message.transaction_id();
+        let owner = self.owners.get(&tx).copied();
+        match message {
+            Message::Deposit { .. } | Message::Withdraw { .. } => {
+                self.register(tx, client);
+                Ok(())
+            }
+            Message::Dispute { .. }
+            | Message::Resolve { .. }
+            | Message::Chargeback { .. }
+            | Message::Settle { .. } => match owner {
+                Some(owner) if owner != client && !self.reused.contains(&(tx, client)) => {
+                    Err(Rejection::ClientMismatch)
+                }
+                _ => Ok(()),
+            },
+        }
+    }
+
+    /// Registers transaction `tx` to `client`, as a deposit or withdrawal would, e.g. one
+    /// kept by an earlier run.
+    pub fn register(&mut self, tx: u32, client: u16) {
+        match self.owners.get(&tx) {
+            None => {
+                self.owners.insert(tx, client);
+            }
+            Some(owner) if *owner != client => {
+                self.reused.insert((tx, client));
+            }
+            Some(_) => {}
+        }
+    }
+
+    /// Estimated bytes the registry holds, along with the control byte of every bucket, as
+    /// `memory` of trp estimates maps.
+    pub fn footprint(&self) -> u64 {
+        ((self.owners.capacity() + self.reused.capacity()) * (size_of::<(u32, u16)>() + 1)) as u64
+    }
+}
+
+/// Accounts of every client, applying messages in the order they come, the way account tasks
+/// do for a single client. Only deposits open accounts, unless told otherwise with
+/// [`creating`](Engine::creating).
+#[derive(Debug)]
+pub struct Engine {
+    settlement: Option<Settlement>,
+    accounts: BTreeMap<u16, (Book, History)>,
+    registry: Registry,
+    /// Whether a message opens the account of its client, when it has none yet.
+    creates: fn(&Message) -> bool,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine::new(None)
+    }
+}
+
+impl Engine {
+    /// No accounts yet, with withdrawals settled with `settlement`.
+    pub fn new(settlement: Option<Settlement>) -> Self {
+        Engine {
+            settlement,
+            accounts: BTreeMap::new(),
+            registry: Registry::default(),
+            creates: Message::is_deposit,
+        }
+    }
+
+    /// Opens accounts with messages `creates` says do, rather than with deposits only.
+    pub fn creating(self, creates: fn(&Message) -> bool) -> Self {
+        Engine { creates, ..self }
+    }
+
+    /// Applies `message`, a [`Message`] or a kind of an embedder, to the account of its
+    /// client.
+    pub fn apply<M: Apply + ?Sized>(&mut self, message: &M) -> Result<(), Rejection> {
+        let client = message.client_id();
+        let creates = match message.message() {
+            Some(message) => {
+                self.registry.check(message)?;
+                (self.creates)(message)
+            }
+            None => false,
+        };
+        if !self.accounts.contains_key(&client) && !creates {
+            return Err(Rejection::NoAccount);
+        }
+        let settlement = self.settlement;
+        let (book, history) = self
+            .accounts
+            .entry(client)
+            .or_insert_with(|| (Book::new(settlement, 0.0, Rules::default()), History::new()));
+        book.mature(message.timestamp(), history);
+        message.apply_to(book, history)
+    }
+
+    /// Account of `client`, if it has one.
+    pub fn account(&self, client: u16) -> Option<&Book> {
+        self.accounts.get(&client).map(|(book, _)| book)
+    }
+
+    /// Accounts of every client, ordered by client.
+    pub fn accounts(&self) -> impl Iterator<Item = (u16, &Book)> {
+        self.accounts
+            .iter()
+            .map(|(client, (book, _))| (*client, book))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Apply, Book, Effect, Engine, History, Rejection, Settlement};
+    use crate::message::Message;
+
+    #[test]
+    fn engine_opens_accounts_with_deposits() {
+        let mut engine = Engine::new(Some(Settlement { after: Some(10) }));
+        let withdraw = |tx, timestamp| Message::Withdraw {
+            client: 2,
+            tx,
+            amount: 1.0,
+            timestamp: Some(timestamp),
+        };
+        assert_eq!(engine.apply(&withdraw(1, 0)), Err(Rejection::NoAccount));
+        assert!(engine.account(2).is_none());
+
+        let deposit = Message::Deposit {
+            client: 2,
+            tx: 2,
+            amount: 3.0,
+            timestamp: Some(0),
+            effective_date: None,
+        };
+        engine.apply(&deposit).unwrap();
+        engine.apply(&withdraw(3, 5)).unwrap();
+        // Pending until 10ms past the withdrawal.
+        assert_eq!(engine.account(2).unwrap().held, 1.0);
+        engine.apply(&withdraw(4, 15)).unwrap();
+        let account = engine.account(2).unwrap();
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (1.0, 1.0, 2.0)
+        );
+        engine.apply(&withdraw(5, 15)).unwrap();
+        assert_eq!(
+            engine.apply(&withdraw(6, 15)),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert_eq!(engine.accounts().count(), 1);
+    }
+
+    #[test]
+    fn references_to_transactions_of_other_clients_are_rejected() {
+        let mut engine = Engine::new(None);
+        let deposit = |client, tx| Message::Deposit {
+            client,
+            tx,
+            amount: 2.0,
+            timestamp: None,
+            effective_date: None,
+        };
+        let dispute = |client, tx| Message::Dispute {
+            client,
+            tx,
+            timestamp: None,
+        };
+        engine.apply(&deposit(1, 1)).unwrap();
+        engine.apply(&deposit(2, 2)).unwrap();
+        assert_eq!(engine.apply(&dispute(2, 1)), Err(Rejection::ClientMismatch));
+        assert_eq!(engine.account(1).unwrap().held, 0.0);
+        // Unknown transactions are left to the account, which ignores them.
+        engine.apply(&dispute(2, 3)).unwrap();
+        engine.apply(&dispute(1, 1)).unwrap();
+        assert_eq!(engine.account(1).unwrap().held, 2.0);
+        // Clients reusing an id reference their own transaction.
+        engine.apply(&deposit(2, 1)).unwrap();
+        engine.apply(&dispute(2, 1)).unwrap();
+        assert_eq!(engine.account(2).unwrap().held, 2.0);
+    }
+
+    #[test]
+    fn simulated_messages_leave_the_account_as_it_was() {
+        let mut book = Book::default();
+        let mut history = History::new();
+        let deposit = Message::Deposit {
+            client: 1,
+            tx: 1,
+            amount: 3.0,
+            timestamp: None,
+            effective_date: None,
+        };
+        book.apply(&deposit, &mut history).unwrap();
+        let withdraw = |amount| Message::Withdraw {
+            client: 1,
+            tx: 2,
+            amount,
+            timestamp: None,
+        };
+        let chargeback = Message::Chargeback {
+            client: 1,
+            tx: 1,
+            timestamp: None,
+        };
+
+        assert_eq!(
+            book.simulate(&withdraw(4.0), &history),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert_eq!(
+            book.simulate(&withdraw(1.0), &history),
+            Ok(Effect {
+                available: -1.0,
+                total: -1.0,
+                ..Effect::default()
+            })
+        );
+        // Not disputed, so there is nothing to charge back.
+        assert_eq!(book.simulate(&chargeback, &history), Ok(Effect::default()));
+        let dispute = Message::Dispute {
+            client: 1,
+            tx: 1,
+            timestamp: None,
+        };
+        book.apply(&dispute, &mut history).unwrap();
+        assert_eq!(
+            book.simulate(&chargeback, &history),
+            Ok(Effect {
+                held: -3.0,
+                total: -3.0,
+                locks: true,
+                ..Effect::default()
+            })
+        );
+        assert_eq!((book.available, book.held, book.total), (0.0, 3.0, 3.0));
+        assert!(!book.locked);
+        assert!(history[&1].0.is_disputed());
+    }
+
+    /// Kinds of transactions of an embedder, along with those of trp.
+    enum Custom {
+        Core(Message),
+        Bonus { client: u16, amount: f32 },
+        Clawback { client: u16, amount: f32 },
+    }
+
+    impl Apply for Custom {
+        fn client_id(&self) -> u16 {
+            match self {
+                Custom::Core(message) => message.client_id(),
+                Custom::Bonus { client, .. } | Custom::Clawback { client, .. } => *client,
+            }
+        }
+
+        fn message(&self) -> Option<&Message> {
+            match self {
+                Custom::Core(message) => Some(message),
+                _ => None,
+            }
+        }
+
+        fn apply_to(&self, book: &mut Book, history: &mut History) -> Result<(), Rejection> {
+            match self {
+                Custom::Core(message) => book.apply(message, history),
+                _ if book.locked => Err(Rejection::AccountLocked),
+                Custom::Bonus { amount, .. } => {
+                    book.available += amount;
+                    book.total += amount;
+                    Ok(())
+                }
+                Custom::Clawback { amount, .. } if book.available < *amount => {
+                    Err(Rejection::InsufficientFunds)
+                }
+                Custom::Clawback { amount, .. } => {
+                    book.available -= amount;
+                    book.total -= amount;
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn kinds_of_embedders_apply_to_existing_accounts() {
+        let mut engine = Engine::new(None);
+        let bonus = |client| Custom::Bonus {
+            client,
+            amount: 5.0,
+        };
+        assert_eq!(engine.apply(&bonus(1)), Err(Rejection::NoAccount));
+
+        engine
+            .apply(&Custom::Core(Message::Deposit {
+                client: 1,
+                tx: 1,
+                amount: 2.0,
+                timestamp: None,
+                effective_date: None,
+            }))
+            .unwrap();
+        engine.apply(&bonus(1)).unwrap();
+        engine
+            .apply(&Custom::Clawback {
+                client: 1,
+                amount: 4.0,
+            })
+            .unwrap();
+        assert_eq!(
+            engine.apply(&Custom::Clawback {
+                client: 1,
+                amount: 4.0,
+            }),
+            Err(Rejection::InsufficientFunds)
+        );
+        let account = engine.account(1).unwrap();
+        assert_eq!((account.available, account.total), (3.0, 3.0));
+    }
+}
@@ -0,0 +1,428 @@
+//! Event log of a run, written with `--event-log`: every valid message in the order it was
+//! passed on to the processor, so account state can be rebuilt with `trp replay`.
+//!
+//! The log is csv with
+//! `offset,timestamp,type,client,tx,amount,message_timestamp,effective_date,source,line,correlation_id,reference`
+//! columns, where offset counts messages from 0, timestamp is when the message was logged,
+//! message_timestamp the one of its record, if any, and effective_date the value date of a
+//! deposit, if any, all in milliseconds since unix epoch. Source, line, correlation id and
+//! reference are the
+//! [`provenance`](crate::provenance) of the message. It is recreated by every run, except by a
+//! standby taking over from its primary, which [`resume`]s the log of the primary instead.
+//!
+//! Account tasks add [`Lifecycle`] entries to the log as accounts change, e.g. an
+//! `account_locked` entry once a chargeback locks an account, with `tx`, `message_timestamp`,
+//! `source` and `line` of the message which triggered the change, and without `amount`.
+//! Their offset is the one of the next message logged, since they don't count as messages,
+//! and they are skipped when the log is replayed. trp has no message closing accounts, so
+//! accounts are never closed. A `dispute_of_pruned` entry tells a dispute which referenced
+//! history pruned by [`retention`](crate::retention), and a `message_late` entry a message
+//! rejected by [`reorder`](crate::reorder) for coming too late, for audit.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Read, Seek, SeekFrom},
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{follow::Follow, format::Source, parser::Record, provenance::Provenance, Message};
+
+static LOG: Mutex<Option<Writer>> = Mutex::new(None);
+
+struct Writer {
+    out: csv::Writer<BufWriter<File>>,
+    offset: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    offset: u64,
+    timestamp: u64,
+    #[serde(rename = "type")]
+    kind: String,
+    client: u16,
+    tx: u32,
+    amount: Option<f32>,
+    /// Missing from logs written before messages had timestamps.
+    #[serde(default)]
+    message_timestamp: Option<u64>,
+    /// Missing from logs written before deposits could be value-dated.
+    #[serde(default)]
+    effective_date: Option<u64>,
+    /// Missing from logs written before messages had provenance.
+    #[serde(default)]
+    source: Option<String>,
+    #[serde(default)]
+    line: Option<u64>,
+    /// Missing from logs written before records had correlation ids.
+    #[serde(default)]
+    correlation_id: Option<String>,
+    /// Missing from logs written before records had references.
+    #[serde(default)]
+    reference: Option<String>,
+}
+
+/// Changes of the lifecycle of an account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lifecycle {
+    /// The first message of the client reached its account.
+    Created,
+    Locked,
+    Unlocked,
+    /// A dispute referenced a transaction pruned from history.
+    DisputeOfPruned,
+    /// A message came too late to be applied in order, see [`reorder`](crate::reorder).
+    Late,
+}
+
+impl Lifecycle {
+    const KINDS: [&str; 5] = [
+        "account_created",
+        "account_locked",
+        "account_unlocked",
+        "dispute_of_pruned",
+        "message_late",
+    ];
+
+    pub fn kind(&self) -> &'static str {
+        Self::KINDS[*self as usize]
+    }
+}
+
+/// Starts logging messages passed to [`append`] to `path`, replacing its contents.
+pub fn open(path: &Path) -> Result<(), anyhow::Error> {
+    let out = csv::Writer::from_writer(BufWriter::new(File::create(path)?));
+    *LOG.lock().unwrap_or_else(|err| err.into_inner()) = Some(Writer { out, offset: 0 });
+    Ok(())
+}
+
+/// Starts logging messages passed to [`append`] to the end of `path`, cut to its first `len`
+/// bytes, with offsets counted from `offset`, as a standby taking over from its primary does:
+/// whatever comes past `len` was not read by the standby, and the entry the primary left
+/// halfway through, if any, is dropped.
+pub fn resume(path: &Path, len: u64, offset: u64) -> Result<(), anyhow::Error> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(len)?;
+    file.seek(SeekFrom::End(0))?;
+    let out = csv::WriterBuilder::new()
+        .has_headers(len == 0)
+        .from_writer(BufWriter::new(file));
+    *LOG.lock().unwrap_or_else(|err| err.into_inner()) = Some(Writer { out, offset });
+    Ok(())
+}
+
+/// Appends `message` read from `provenance` to the log, if one is open.
+pub fn append(message: &Message, provenance: &Provenance) -> Result<(), anyhow::Error> {
+    write(message.kind(), message, provenance, true)
+}
+
+/// Appends `change` of the account of the client of `message` read from `provenance`, which
+/// triggered it, to the log, if one is open.
+pub fn lifecycle(
+    change: Lifecycle,
+    message: &Message,
+    provenance: &Provenance,
+) -> Result<(), anyhow::Error> {
+    write(change.kind(), message, provenance, false)
+}
+
+/// Appends entry of `kind` for `message`, counting it towards offsets if it's a `message`
+/// itself.
+fn write(
+    kind: &str,
+    message: &Message,
+    provenance: &Provenance,
+    counted: bool,
+) -> Result<(), anyhow::Error> {
+    let mut log = LOG.lock().unwrap_or_else(|err| err.into_inner());
+    let Some(writer) = log.as_mut() else {
+        return Ok(());
+    };
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    writer.out.serialize(Entry {
+        offset: writer.offset,
+        timestamp,
+        kind: kind.to_string(),
+        client: message.client_id(),
+        tx: message.transaction_id(),
+        amount: if counted { message.amount() } else { None },
+        message_timestamp: message.timestamp(),
+        effective_date: if counted {
+            message.effective_date()
+        } else {
+            None
+        },
+        source: Some(provenance.source.to_string()),
+        line: Some(provenance.line),
+        correlation_id: provenance.correlation_id.as_deref().map(str::to_string),
+        reference: provenance.reference.as_deref().map(str::to_string),
+    })?;
+    if counted {
+        writer.offset += 1;
+    }
+    Ok(())
+}
+
+/// Flushes entries appended so far, so that replicas following the log see them, see
+/// [`follow`](crate::follow).
+pub fn flush() -> Result<(), anyhow::Error> {
+    if let Some(writer) = LOG.lock().unwrap_or_else(|err| err.into_inner()).as_mut() {
+        writer.out.flush()?;
+    }
+    Ok(())
+}
+
+/// Flushes and closes the log.
+pub fn close() -> Result<(), anyhow::Error> {
+    if let Some(mut writer) = LOG.lock().unwrap_or_else(|err| err.into_inner()).take() {
+        writer.out.flush()?;
+    }
+    Ok(())
+}
+
+/// Reads messages of an event log back as [`Record`]s, stopping at the first entry past
+/// `offset`, and skipping entries past `until` as well as [`Lifecycle`] entries.
+///
+/// The moment of an entry is the timestamp of its message, or when it was logged if the
+/// message has none. Unlike offsets, timestamps of messages need not be ordered.
+pub struct Reader {
+    entries: csv::DeserializeRecordsIntoIter<Box<dyn Read + Send>, Entry>,
+    /// Last offset to include.
+    offset: Option<u64>,
+    /// Last moment to include.
+    until: Option<u64>,
+    /// Line of the last entry read.
+    line: u64,
+    /// Offset of the message after the last one read.
+    next_offset: u64,
+    /// Last line of every source of messages read.
+    lines: HashMap<String, u64>,
+}
+
+impl Reader {
+    pub fn open(
+        path: &Path,
+        offset: Option<u64>,
+        until: Option<u64>,
+    ) -> Result<Self, anyhow::Error> {
+        let file = File::open(path)
+            .map_err(|err| anyhow::anyhow!("Failed to read {}: {err}", path.display()))?;
+        Ok(Reader::new(Box::new(file), offset, until))
+    }
+
+    /// Reads the log at `path` as it grows, until [`follow::stop`](crate::follow::stop).
+    pub fn follow(path: &Path) -> Result<Self, anyhow::Error> {
+        Ok(Reader::new(Box::new(Follow::open(path)?), None, None))
+    }
+
+    fn new(entries: Box<dyn Read + Send>, offset: Option<u64>, until: Option<u64>) -> Self {
+        Reader {
+            entries: csv::Reader::from_reader(entries).into_deserialize(),
+            offset,
+            until,
+            line: 0,
+            next_offset: 0,
+            lines: HashMap::new(),
+        }
+    }
+
+    /// Offset a message logged after those read so far gets.
+    pub fn next_offset(&self) -> u64 {
+        self.next_offset
+    }
+
+    /// Last line of every source of messages read so far, as logged by the run which wrote
+    /// the log.
+    pub fn lines(&self) -> &HashMap<String, u64> {
+        &self.lines
+    }
+}
+
+impl Reader {
+    /// Next record along with its moment, see [`Reader`].
+    pub fn next_entry(&mut self) -> Option<Result<(u64, Record), anyhow::Error>> {
+        let (moment, entry) = loop {
+            self.line = self.entries.reader().position().line();
+            let entry = match self.entries.next()? {
+                Ok(entry) => entry,
+                Err(err) => return Some(Err(err.into())),
+            };
+            if self.offset.is_some_and(|offset| entry.offset > offset) {
+                return None;
+            }
+            if Lifecycle::KINDS.contains(&entry.kind.as_str()) {
+                continue;
+            }
+            self.next_offset = entry.offset + 1;
+            if let (Some(source), Some(line)) = (&entry.source, entry.line) {
+                match self.lines.get_mut(source) {
+                    Some(last) => *last = line,
+                    None => {
+                        self.lines.insert(source.clone(), line);
+                    }
+                }
+            }
+            let moment = entry.message_timestamp.unwrap_or(entry.timestamp);
+            if self.until.is_none_or(|until| moment <= until) {
+                break (moment, entry);
+            }
+        };
+
+        let record = Record {
+            kind: entry.kind,
+            client: entry.client,
+            tx: entry.tx,
+            amount: entry.amount,
+            timestamp: entry.message_timestamp,
+            effective_date: entry.effective_date,
+            signature: None,
+            signed: None,
+            reference: entry.reference,
+            correlation_id: entry.correlation_id,
+            #[cfg(feature = "otel")]
+            traceparent: None,
+        };
+        Some(Ok((moment, record)))
+    }
+}
+
+impl Source for Reader {
+    fn next_record(&mut self) -> Option<Result<Record, anyhow::Error>> {
+        self.next_entry()
+            .map(|entry| entry.map(|(_, record)| record))
+    }
+
+    fn position(&self) -> u64 {
+        self.entries.reader().position().byte()
+    }
+
+    fn line(&self) -> u64 {
+        self.line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{append, close, lifecycle, open, resume, Lifecycle, Reader};
+    use crate::{format::Source, provenance::Provenance, Message};
+
+    fn provenance(line: u64) -> Provenance {
+        Provenance {
+            source: "input.csv".into(),
+            line,
+            reference: None,
+            correlation_id: None,
+        }
+    }
+
+    #[test]
+    fn log_is_replayed_up_to_offset() {
+        let path = std::env::temp_dir().join(format!("trp-events-{}.csv", std::process::id()));
+        open(&path).unwrap();
+        let deposit = Message::Deposit {
+            client: 1,
+            tx: 1,
+            amount: 2.5,
+            timestamp: None,
+            effective_date: None,
+        };
+        append(&deposit, &provenance(2)).unwrap();
+        lifecycle(Lifecycle::Created, &deposit, &provenance(2)).unwrap();
+        append(
+            &Message::Dispute {
+                client: 1,
+                tx: 1,
+                timestamp: None,
+            },
+            &provenance(3),
+        )
+        .unwrap();
+        let chargeback = Message::Chargeback {
+            client: 1,
+            tx: 1,
+            timestamp: None,
+        };
+        append(&chargeback, &provenance(4)).unwrap();
+        lifecycle(Lifecycle::Locked, &chargeback, &provenance(4)).unwrap();
+        close().unwrap();
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert!(log.lines().nth(2).unwrap().starts_with("1,"));
+        assert!(log
+            .lines()
+            .nth(2)
+            .unwrap()
+            .contains(",account_created,1,1,,"));
+        assert!(log
+            .lines()
+            .nth(5)
+            .unwrap()
+            .contains(",account_locked,1,1,,"));
+
+        let mut reader = Reader::open(&path, Some(1), None).unwrap();
+        let deposit = reader.next_record().unwrap().unwrap();
+        assert_eq!(
+            (deposit.kind.as_str(), deposit.amount),
+            ("deposit", Some(2.5))
+        );
+        let dispute = reader.next_record().unwrap().unwrap();
+        assert_eq!((dispute.kind.as_str(), dispute.amount), ("dispute", None));
+        // Lines of the log, rather than of the input.
+        assert_eq!(reader.line(), 4);
+        assert!(reader.next_record().is_none());
+        assert_eq!(reader.next_offset(), 2);
+
+        let mut reader = Reader::open(&path, None, Some(0)).unwrap();
+        assert!(reader.next_record().is_none());
+
+        // A standby picks up where it stopped reading, dropping what came after.
+        let len = log.lines().take(4).map(|line| line.len() as u64 + 1).sum();
+        resume(&path, len, 2).unwrap();
+        append(&chargeback, &provenance(9)).unwrap();
+        close().unwrap();
+        let mut reader = Reader::open(&path, None, None).unwrap();
+        let replayed: Vec<_> = std::iter::from_fn(|| reader.next_record())
+            .map(|record| record.unwrap().kind)
+            .collect();
+        assert_eq!(replayed, ["deposit", "dispute", "chargeback"]);
+        assert_eq!(reader.next_offset(), 3);
+        assert_eq!(reader.lines().get("input.csv"), Some(&9));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn log_is_replayed_up_to_timestamps_of_messages() {
+        let path = std::env::temp_dir().join(format!("trp-events-at-{}.csv", std::process::id()));
+        open(&path).unwrap();
+        for (tx, timestamp) in [(1, 100), (2, 300), (3, 200)] {
+            append(
+                &Message::Deposit {
+                    client: 1,
+                    tx,
+                    amount: 1.0,
+                    timestamp: Some(timestamp),
+                    effective_date: None,
+                },
+                &provenance(u64::from(tx) + 1),
+            )
+            .unwrap();
+        }
+        close().unwrap();
+
+        let mut reader = Reader::open(&path, None, Some(200)).unwrap();
+        let replayed: Vec<_> = std::iter::from_fn(|| reader.next_record())
+            .map(|record| record.unwrap().tx)
+            .collect();
+        assert_eq!(replayed, vec![1, 3]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,268 @@
+//! C ABI of the [`engine`](crate::engine), enabled with the `ffi` feature, for embedding the
+//! processing core in other languages without spawning `trp`. Declarations are in
+//! `include/trp.h`, and the library is built with:
+//!
+//! `cargo rustc --release --features ffi --lib --crate-type cdylib`
+//!
+//! An engine is created with [`trp_engine_new`], fed with [`trp_engine_push`], read with
+//! [`trp_engine_snapshot`] and released with [`trp_engine_free`]. It is not thread safe,
+//! calls for one engine must not overlap.
+
+use crate::{
+    creation,
+    engine::{Engine, Rejection},
+    message::Message,
+};
+
+/// Returned by [`trp_engine_push`] for applied transactions.
+pub const TRP_OK: i32 = 0;
+/// Returned by [`trp_engine_push`] for transactions which are not valid messages, or null
+/// pointers.
+pub const TRP_INVALID: i32 = -1;
+
+pub const TRP_DEPOSIT: u32 = 0;
+pub const TRP_WITHDRAWAL: u32 = 1;
+pub const TRP_DISPUTE: u32 = 2;
+pub const TRP_RESOLVE: u32 = 3;
+pub const TRP_CHARGEBACK: u32 = 4;
+pub const TRP_SETTLE: u32 = 5;
+
+/// Row of the input, as `trp_transaction`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Transaction {
+    /// One of `TRP_DEPOSIT`, `TRP_WITHDRAWAL` and the rest.
+    pub kind: u32,
+    pub client: u16,
+    pub tx: u32,
+    /// Amount of deposits and withdrawals, ignored for other kinds.
+    pub amount: f32,
+    pub has_timestamp: bool,
+    /// Milliseconds since unix epoch, when `has_timestamp` is set.
+    pub timestamp: u64,
+    pub has_effective_date: bool,
+    /// Milliseconds since unix epoch when funds of a deposit become available, when
+    /// `has_effective_date` is set. Only deposits can be value-dated.
+    pub effective_date: u64,
+}
+
+/// Balances of an account, as `trp_account`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Account {
+    pub client: u16,
+    pub available: f32,
+    pub held: f32,
+    pub total: f32,
+    pub locked: bool,
+}
+
+impl TryFrom<&Transaction> for Message {
+    type Error = ();
+
+    fn try_from(transaction: &Transaction) -> Result<Self, Self::Error> {
+        let Transaction {
+            kind,
+            client,
+            tx,
+            amount,
+            ..
+        } = *transaction;
+        let timestamp = transaction.has_timestamp.then_some(transaction.timestamp);
+        let effective_date = transaction
+            .has_effective_date
+            .then_some(transaction.effective_date);
+
+        match kind {
+            TRP_DEPOSIT => Ok(Message::Deposit {
+                client,
+                tx,
+                amount,
+                timestamp,
+                effective_date,
+            }),
+            // Only deposits can be value-dated.
+            _ if effective_date.is_some() => Err(()),
+            TRP_WITHDRAWAL => Ok(Message::Withdraw {
+                client,
+                tx,
+                amount,
+                timestamp,
+            }),
+            TRP_DISPUTE => Ok(Message::Dispute {
+                client,
+                tx,
+                timestamp,
+            }),
+            TRP_RESOLVE => Ok(Message::Resolve {
+                client,
+                tx,
+                timestamp,
+            }),
+            TRP_CHARGEBACK => Ok(Message::Chargeback {
+                client,
+                tx,
+                timestamp,
+            }),
+            TRP_SETTLE => Ok(Message::Settle {
+                client,
+                tx,
+                timestamp,
+            }),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Status of rejected transactions, as returned by [`trp_engine_push`].
+fn status(rejection: Rejection) -> i32 {
+    match rejection {
+        Rejection::InsufficientFunds => 1,
+        Rejection::BelowMinimum => 2,
+        Rejection::OverLimit => 3,
+        Rejection::AccountLocked => 4,
+        Rejection::NoAccount => 5,
+        Rejection::ClientMismatch => 6,
+    }
+}
+
+/// Creates an engine without accounts, whose withdrawals are settled as they are applied, and
+/// whose accounts are opened as `account_creation` says. Released with [`trp_engine_free`].
+#[no_mangle]
+pub extern "C" fn trp_engine_new() -> *mut Engine {
+    Box::into_raw(Box::new(Engine::new(None).creating(creation::creates)))
+}
+
+/// Applies `transaction` to the account of its client. Returns [`TRP_OK`] when it was
+/// applied, a positive status when it was rejected by the rules, and [`TRP_INVALID`] when it
+/// is not a valid message.
+///
+/// # Safety
+///
+/// `engine` must come from [`trp_engine_new`] and not be freed yet, `transaction` must point
+/// to a valid `trp_transaction`. Either may be null.
+#[no_mangle]
+pub unsafe extern "C" fn trp_engine_push(
+    engine: *mut Engine,
+    transaction: *const Transaction,
+) -> i32 {
+    let (Some(engine), Some(transaction)) = (engine.as_mut(), transaction.as_ref()) else {
+        return TRP_INVALID;
+    };
+    let Ok(message) = Message::try_from(transaction) else {
+        return TRP_INVALID;
+    };
+    match engine.apply(&message) {
+        Ok(()) => TRP_OK,
+        Err(rejection) => status(rejection),
+    }
+}
+
+/// Writes balances of up to `len` accounts to `out`, ordered by client. Returns the number of
+/// accounts of the engine, which may be more than `len`: calling with a `len` of 0 returns
+/// the size `out` needs to be.
+///
+/// # Safety
+///
+/// `engine` must come from [`trp_engine_new`] and not be freed yet, and may be null. `out`
+/// must point to at least `len` writable `trp_account`s, unless `len` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn trp_engine_snapshot(
+    engine: *const Engine,
+    out: *mut Account,
+    len: usize,
+) -> usize {
+    let Some(engine) = engine.as_ref() else {
+        return 0;
+    };
+    let mut count = 0;
+    for (client, book) in engine.accounts() {
+        if count < len {
+            out.add(count).write(Account {
+                client,
+                available: book.available,
+                held: book.held,
+                total: book.total,
+                locked: book.locked,
+            });
+        }
+        count += 1;
+    }
+    count
+}
+
+/// Releases `engine`.
+///
+/// # Safety
+///
+/// `engine` must come from [`trp_engine_new`] and not be freed yet, and may be null. It can't
+/// be used after.
+#[no_mangle]
+pub unsafe extern "C" fn trp_engine_free(engine: *mut Engine) {
+    if !engine.is_null() {
+        drop(Box::from_raw(engine));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        trp_engine_free, trp_engine_new, trp_engine_push, trp_engine_snapshot, Account,
+        Transaction, TRP_DEPOSIT, TRP_DISPUTE, TRP_INVALID, TRP_OK, TRP_WITHDRAWAL,
+    };
+
+    fn transaction(kind: u32, tx: u32, amount: f32) -> Transaction {
+        Transaction {
+            kind,
+            client: 3,
+            tx,
+            amount,
+            has_timestamp: false,
+            timestamp: 0,
+            has_effective_date: false,
+            effective_date: 0,
+        }
+    }
+
+    #[test]
+    fn engine_is_driven_through_c_abi() {
+        let engine = trp_engine_new();
+        unsafe {
+            assert_eq!(
+                trp_engine_push(engine, &transaction(TRP_DEPOSIT, 1, 5.0)),
+                TRP_OK
+            );
+            // Insufficient funds.
+            assert_eq!(
+                trp_engine_push(engine, &transaction(TRP_WITHDRAWAL, 2, 6.0)),
+                1
+            );
+            let mut dated = transaction(TRP_DISPUTE, 1, 0.0);
+            dated.has_effective_date = true;
+            assert_eq!(trp_engine_push(engine, &dated), TRP_INVALID);
+            assert_eq!(
+                trp_engine_push(engine, &transaction(7, 3, 0.0)),
+                TRP_INVALID
+            );
+            assert_eq!(
+                trp_engine_push(engine, &transaction(TRP_DISPUTE, 1, 0.0)),
+                TRP_OK
+            );
+
+            assert_eq!(trp_engine_snapshot(engine, std::ptr::null_mut(), 0), 1);
+            let mut accounts = [Account::default(); 2];
+            assert_eq!(trp_engine_snapshot(engine, accounts.as_mut_ptr(), 2), 1);
+            assert_eq!(
+                accounts[0],
+                Account {
+                    client: 3,
+                    available: 0.0,
+                    held: 5.0,
+                    total: 5.0,
+                    locked: false,
+                }
+            );
+            trp_engine_free(engine);
+        }
+    }
+}
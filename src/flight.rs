@@ -0,0 +1,435 @@
+//! Accounts over Arrow Flight, enabled with `trp serve --flight-addr`: analytics tools pull
+//! current balances of every client as Arrow record batches, instead of reading csv output.
+//!
+//! `DoGet` with ticket [`TICKET`] streams a schema of `client` (uint16), `available`, `held`
+//! and `total` (float32) and `locked` (bool) columns, the same as output, followed by record
+//! batches of up to [`BATCH`] accounts ordered by client. Balances are the ones after the
+//! latest message applied to each account when the call is made.
+//!
+//! Calls are served by [`grpc`](crate::grpc), which does not look at methods, so there is no
+//! `GetFlightInfo`, clients have to know the ticket.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{
+    grpc::{self, Reply},
+    state::AccountRecord,
+};
+
+/// Ticket of the snapshot of accounts.
+pub const TICKET: &str = "accounts";
+/// Most accounts in a record batch.
+pub const BATCH: usize = 65_536;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static ACCOUNTS: Mutex<BTreeMap<u16, AccountRecord>> = Mutex::new(BTreeMap::new());
+
+/// Starts keeping balances reported with [`update`].
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Keeps `record`, the latest balances of its client.
+pub fn update(record: AccountRecord) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    ACCOUNTS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(record.client, record);
+}
+
+/// Accepts calls on `addr` until the runtime shuts down.
+pub async fn serve(addr: String) -> Result<(), anyhow::Error> {
+    grpc::listen(addr, "flight", answer).await
+}
+
+/// Answers `DoGet` of [`TICKET`] with `FlightData` of the schema and every record batch.
+fn answer(request: &[u8]) -> Result<Reply, anyhow::Error> {
+    // Ticket is a single `bytes ticket = 1` field.
+    let expected = [&[0x0a, TICKET.len() as u8][..], TICKET.as_bytes()].concat();
+    if grpc::message(request)? != expected {
+        return Err(anyhow::anyhow!(
+            "Only DoGet with ticket {TICKET} is supported"
+        ));
+    }
+    let accounts: Vec<_> = ACCOUNTS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .values()
+        .cloned()
+        .collect();
+    let mut messages = vec![flight_data(&schema(), &[])];
+    for batch in accounts.chunks(BATCH) {
+        let (header, body) = record_batch(batch);
+        messages.push(flight_data(&header, &body));
+    }
+    Ok(Reply::Messages(messages))
+}
+
+/// `FlightData` with IPC message `header` and its `body`.
+fn flight_data(header: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    // data_header = 2
+    out.push(0x12);
+    grpc::varint(&mut out, header.len() as u64);
+    out.extend_from_slice(header);
+    if !body.is_empty() {
+        // data_body = 1000
+        out.extend_from_slice(&[0xc2, 0x3e]);
+        grpc::varint(&mut out, body.len() as u64);
+        out.extend_from_slice(body);
+    }
+    out
+}
+
+/// `MetadataVersion` of IPC messages, V5.
+const VERSION: i16 = 4;
+/// `MessageHeader` union types.
+const SCHEMA: u8 = 1;
+const RECORD_BATCH: u8 = 3;
+/// `Type` union types.
+const INT: u8 = 2;
+const FLOATING_POINT: u8 = 3;
+const BOOL: u8 = 6;
+/// `Precision` of floats.
+const SINGLE: i16 = 1;
+
+/// IPC message of the schema of accounts.
+fn schema() -> Vec<u8> {
+    let field = |name, kind, details| {
+        Value::Table(vec![
+            Some(Value::String(name)),
+            // Not nullable.
+            Some(Value::Bool(false)),
+            Some(Value::U8(kind)),
+            Some(Value::Table(details)),
+            None,
+            // Readers insist on children, even when there are none.
+            Some(Value::Tables(Vec::new())),
+        ])
+    };
+    let float = || vec![Some(Value::I16(SINGLE))];
+    let fields = vec![
+        // Unsigned, 16 bits.
+        field(
+            "client",
+            INT,
+            vec![Some(Value::I32(16)), Some(Value::Bool(false))],
+        ),
+        field("available", FLOATING_POINT, float()),
+        field("held", FLOATING_POINT, float()),
+        field("total", FLOATING_POINT, float()),
+        field("locked", BOOL, Vec::new()),
+    ];
+    let schema = vec![
+        // Little endian.
+        Some(Value::I16(0)),
+        Some(Value::Tables(fields)),
+    ];
+    message(SCHEMA, schema, 0)
+}
+
+/// IPC message of a record batch of `accounts`, along with its body.
+fn record_batch(accounts: &[AccountRecord]) -> (Vec<u8>, Vec<u8>) {
+    let mut body = Vec::new();
+    let mut buffers = Vec::new();
+    let columns: [Vec<u8>; 5] = [
+        accounts
+            .iter()
+            .flat_map(|account| account.client.to_le_bytes())
+            .collect(),
+        accounts
+            .iter()
+            .flat_map(|account| account.available.to_le_bytes())
+            .collect(),
+        accounts
+            .iter()
+            .flat_map(|account| account.held.to_le_bytes())
+            .collect(),
+        accounts
+            .iter()
+            .flat_map(|account| account.total.to_le_bytes())
+            .collect(),
+        accounts
+            .chunks(8)
+            .map(|accounts| {
+                accounts.iter().enumerate().fold(0u8, |bits, (i, account)| {
+                    bits | u8::from(account.locked) << i
+                })
+            })
+            .collect(),
+    ];
+    for column in columns {
+        // No validity bitmap, nothing is null.
+        buffers.extend_from_slice(&(body.len() as i64).to_le_bytes());
+        buffers.extend_from_slice(&0i64.to_le_bytes());
+        buffers.extend_from_slice(&(body.len() as i64).to_le_bytes());
+        buffers.extend_from_slice(&(column.len() as i64).to_le_bytes());
+        body.extend_from_slice(&column);
+        body.resize(body.len().next_multiple_of(8), 0);
+    }
+    let mut nodes = Vec::new();
+    for _ in 0..5 {
+        nodes.extend_from_slice(&(accounts.len() as i64).to_le_bytes());
+        nodes.extend_from_slice(&0i64.to_le_bytes());
+    }
+    let batch = vec![
+        Some(Value::I64(accounts.len() as i64)),
+        Some(Value::Structs(nodes)),
+        Some(Value::Structs(buffers)),
+    ];
+    (message(RECORD_BATCH, batch, body.len()), body)
+}
+
+/// IPC `Message` with `header` of `kind`, followed by `body_length` bytes of body.
+fn message(kind: u8, header: Vec<Option<Value>>, body_length: usize) -> Vec<u8> {
+    let message = vec![
+        Some(Value::I16(VERSION)),
+        Some(Value::U8(kind)),
+        Some(Value::Table(header)),
+        Some(Value::I64(body_length as i64)),
+    ];
+    let mut out = vec![0; 4];
+    let root = write(&mut out, &Value::Table(message));
+    out[..4].copy_from_slice(&(root as u32).to_le_bytes());
+    out
+}
+
+/// Flatbuffers value, just the kinds IPC messages need. Tables have their fields in slot
+/// order, left out when `None`.
+#[derive(Debug)]
+enum Value {
+    Bool(bool),
+    U8(u8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    String(&'static str),
+    Table(Vec<Option<Value>>),
+    Tables(Vec<Value>),
+    /// Vector of 16 bytes structs, two `i64` each.
+    Structs(Vec<u8>),
+}
+
+impl Value {
+    /// Size of the value inline in a table, offsets for everything which is not a scalar.
+    fn size(&self) -> usize {
+        match self {
+            Value::Bool(_) | Value::U8(_) => 1,
+            Value::I16(_) => 2,
+            Value::I64(_) => 8,
+            _ => 4,
+        }
+    }
+}
+
+fn align(out: &mut Vec<u8>, to: usize, shift: usize) {
+    while !(out.len() + shift).is_multiple_of(to) {
+        out.push(0);
+    }
+}
+
+/// Writes `value` at the end of `out`, children after their parents, so every offset points
+/// forward. Returns where the value starts.
+fn write(out: &mut Vec<u8>, value: &Value) -> usize {
+    match value {
+        Value::Table(fields) => {
+            // Layout of the table, after its offset to the vtable.
+            let mut slots = Vec::new();
+            let mut size: usize = 4;
+            for field in fields {
+                slots.push(field.as_ref().map(|field| {
+                    size = size.next_multiple_of(field.size());
+                    let at = size;
+                    size += field.size();
+                    at
+                }));
+            }
+
+            align(out, 2, 0);
+            let vtable = out.len();
+            out.extend_from_slice(&(4 + 2 * slots.len() as u16).to_le_bytes());
+            out.extend_from_slice(&(size as u16).to_le_bytes());
+            for slot in &slots {
+                out.extend_from_slice(&(slot.unwrap_or(0) as u16).to_le_bytes());
+            }
+
+            align(out, 8, 0);
+            let table = out.len();
+            out.resize(table + size, 0);
+            out[table..table + 4].copy_from_slice(&((table - vtable) as i32).to_le_bytes());
+            let mut children = Vec::new();
+            for (field, at) in fields.iter().zip(slots) {
+                let (Some(field), Some(at)) = (field, at) else {
+                    continue;
+                };
+                let at = table + at;
+                match field {
+                    Value::Bool(value) => out[at] = u8::from(*value),
+                    Value::U8(value) => out[at] = *value,
+                    Value::I16(value) => out[at..at + 2].copy_from_slice(&value.to_le_bytes()),
+                    Value::I32(value) => out[at..at + 4].copy_from_slice(&value.to_le_bytes()),
+                    Value::I64(value) => out[at..at + 8].copy_from_slice(&value.to_le_bytes()),
+                    child => children.push((at, child)),
+                }
+            }
+            for (at, child) in children {
+                let start = write(out, child);
+                out[at..at + 4].copy_from_slice(&((start - at) as u32).to_le_bytes());
+            }
+            table
+        }
+        Value::Tables(tables) => {
+            align(out, 4, 0);
+            let start = out.len();
+            out.extend_from_slice(&(tables.len() as u32).to_le_bytes());
+            out.resize(start + 4 + 4 * tables.len(), 0);
+            for (i, table) in tables.iter().enumerate() {
+                let at = start + 4 + 4 * i;
+                let table = write(out, table);
+                out[at..at + 4].copy_from_slice(&((table - at) as u32).to_le_bytes());
+            }
+            start
+        }
+        Value::String(value) => {
+            align(out, 4, 0);
+            let start = out.len();
+            out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            out.extend_from_slice(value.as_bytes());
+            out.push(0);
+            start
+        }
+        Value::Structs(bytes) => {
+            // Structs are aligned to 8, right after the length.
+            align(out, 8, 4);
+            let start = out.len();
+            out.extend_from_slice(&((bytes.len() / 16) as u32).to_le_bytes());
+            out.extend_from_slice(bytes);
+            start
+        }
+        scalar => unreachable!("{scalar:?} is written inline"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{record_batch, schema, INT, RECORD_BATCH, SCHEMA};
+    use crate::state::AccountRecord;
+
+    fn scalar<const N: usize>(buf: &[u8], at: usize) -> [u8; N] {
+        buf[at..at + N].try_into().unwrap()
+    }
+
+    fn offset(buf: &[u8], at: usize) -> usize {
+        at + u32::from_le_bytes(scalar(buf, at)) as usize
+    }
+
+    /// Where field `slot` of the table at `table` is, following its vtable.
+    fn field(buf: &[u8], table: usize, slot: usize) -> Option<usize> {
+        let vtable = table - i32::from_le_bytes(scalar(buf, table)) as usize;
+        let length = u16::from_le_bytes(scalar(buf, vtable)) as usize;
+        let at = (4 + 2 * slot < length)
+            .then(|| u16::from_le_bytes(scalar(buf, vtable + 4 + 2 * slot)) as usize)?;
+        (at != 0).then_some(table + at)
+    }
+
+    fn string(buf: &[u8], at: usize) -> &str {
+        let length = u32::from_le_bytes(scalar(buf, at)) as usize;
+        std::str::from_utf8(&buf[at + 4..at + 4 + length]).unwrap()
+    }
+
+    /// Vector at `at`, along with where its elements start.
+    fn vector(buf: &[u8], at: usize) -> (usize, usize) {
+        (u32::from_le_bytes(scalar(buf, at)) as usize, at + 4)
+    }
+
+    #[test]
+    fn schema_has_a_field_per_column_of_output() {
+        let buf = schema();
+        let message = offset(&buf, 0);
+        assert_eq!(message % 8, 0);
+        assert_eq!(buf[field(&buf, message, 1).unwrap()], SCHEMA);
+        let schema = offset(&buf, field(&buf, message, 2).unwrap());
+        let (count, fields) = vector(&buf, offset(&buf, field(&buf, schema, 1).unwrap()));
+        let columns: Vec<_> = (0..count)
+            .map(|i| {
+                let field_at = offset(&buf, fields + 4 * i);
+                let name = string(&buf, offset(&buf, field(&buf, field_at, 0).unwrap()));
+                let kind = buf[field(&buf, field_at, 2).unwrap()];
+                let children = offset(&buf, field(&buf, field_at, 5).unwrap());
+                assert_eq!(vector(&buf, children).0, 0);
+                (name, kind)
+            })
+            .collect();
+        assert_eq!(
+            columns,
+            vec![
+                ("client", INT),
+                ("available", 3),
+                ("held", 3),
+                ("total", 3),
+                ("locked", 6)
+            ]
+        );
+        // Client ids are unsigned 16 bits integers.
+        let client = offset(&buf, fields);
+        let int = offset(&buf, field(&buf, client, 3).unwrap());
+        assert_eq!(
+            i32::from_le_bytes(scalar(&buf, field(&buf, int, 0).unwrap())),
+            16
+        );
+        assert_eq!(buf[field(&buf, int, 1).unwrap()], 0);
+    }
+
+    #[test]
+    fn record_batch_has_columns_of_accounts() {
+        let accounts: Vec<_> = (1..=9)
+            .map(|client| AccountRecord {
+                client,
+                available: f32::from(client),
+                held: 0.5,
+                total: f32::from(client) + 0.5,
+                locked: client % 8 == 1,
+            })
+            .collect();
+        let (buf, body) = record_batch(&accounts);
+        let message = offset(&buf, 0);
+        assert_eq!(buf[field(&buf, message, 1).unwrap()], RECORD_BATCH);
+        let body_length = field(&buf, message, 3).unwrap();
+        assert_eq!(body_length % 8, 0);
+        assert_eq!(
+            i64::from_le_bytes(scalar(&buf, body_length)),
+            body.len() as i64
+        );
+
+        let batch = offset(&buf, field(&buf, message, 2).unwrap());
+        assert_eq!(
+            i64::from_le_bytes(scalar(&buf, field(&buf, batch, 0).unwrap())),
+            9
+        );
+        let (nodes, _) = vector(&buf, offset(&buf, field(&buf, batch, 1).unwrap()));
+        assert_eq!(nodes, 5);
+        let (count, buffers) = vector(&buf, offset(&buf, field(&buf, batch, 2).unwrap()));
+        assert_eq!((count, buffers % 8), (10, 0));
+        let buffer = |i: usize| {
+            let at = buffers + 16 * i;
+            let start = i64::from_le_bytes(scalar(&buf, at)) as usize;
+            let length = i64::from_le_bytes(scalar(&buf, at + 8)) as usize;
+            assert_eq!(start % 8, 0);
+            &body[start..start + length]
+        };
+        assert!(buffer(0).is_empty());
+        assert_eq!(buffer(1)[16..18], 9u16.to_le_bytes());
+        assert_eq!(buffer(3)[..4], 1.0f32.to_le_bytes());
+        // Clients 1 and 9 are locked.
+        assert_eq!(buffer(9), &[0b0000_0001, 0b0000_0001]);
+    }
+}
@@ -0,0 +1,170 @@
+//! Following of an event log another `trp serve` writes with `--event-log`, for read-only
+//! replicas started with `trp replica`, which serve metrics, gRPC and Flight off the primary.
+//!
+//! [`Follow`] reads the log as it grows: once it's read to the end, it waits for the primary to
+//! append more instead of ending, until [`stop`] is called. Primaries flush their log every
+//! [`FLUSH`], see [`event_log::flush`](crate::event_log::flush), so replicas are that far
+//! behind at most, along with however long it takes them to apply what was appended.
+//!
+//! Only complete lines are read, so an entry the primary was halfway through writing when it
+//! stopped or died is left out, rather than read cut short.
+//!
+//! Every run of the primary recreates its log. A replica can't tell where the new log picks
+//! up from the old one, so it fails once the log shrinks, and has to be started over.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+/// How often primaries flush their event log.
+pub const FLUSH: Duration = Duration::from_secs(1);
+/// How often the end of the log is looked at for more entries.
+const POLL: Duration = Duration::from_millis(50);
+
+static STOPPED: AtomicBool = AtomicBool::new(false);
+/// Set once a followed log was recreated.
+static RECREATED: AtomicBool = AtomicBool::new(false);
+
+/// Stops every [`Follow`] once it's read what was appended so far.
+pub fn stop() {
+    STOPPED.store(true, Ordering::Relaxed);
+}
+
+/// Whether [`stop`] was called.
+pub fn stopped() -> bool {
+    STOPPED.load(Ordering::Relaxed)
+}
+
+/// Fails if a followed log was recreated, so the replica was stopped short of it.
+pub fn check() -> Result<(), anyhow::Error> {
+    if RECREATED.load(Ordering::Relaxed) {
+        anyhow::bail!(
+            "Event log was recreated by another run of the primary, start the replica over"
+        );
+    }
+    Ok(())
+}
+
+/// File read as it grows, see [module](self).
+pub struct Follow {
+    file: File,
+    path: PathBuf,
+    /// Bytes read so far.
+    read: u64,
+    /// Bytes read but not handed out, and how many of them are complete lines.
+    pending: Vec<u8>,
+    complete: usize,
+    poll: Duration,
+    recreated: bool,
+}
+
+impl Follow {
+    pub fn open(path: &Path) -> Result<Self, anyhow::Error> {
+        let file = File::open(path)
+            .map_err(|err| anyhow::anyhow!("Failed to read {}: {err}", path.display()))?;
+        Ok(Follow {
+            file,
+            path: path.to_path_buf(),
+            read: 0,
+            pending: Vec::new(),
+            complete: 0,
+            poll: POLL,
+            recreated: false,
+        })
+    }
+}
+
+impl Read for Follow {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Reported once, after which the log ends.
+        if self.recreated {
+            return Ok(0);
+        }
+        let mut chunk = [0; 8 * 1024];
+        loop {
+            if self.complete > 0 {
+                let len = self.complete.min(buf.len());
+                buf[..len].copy_from_slice(&self.pending[..len]);
+                self.pending.drain(..len);
+                self.complete -= len;
+                return Ok(len);
+            }
+            // Checked ahead of reading, so that whatever was appended before is read in full.
+            let stopping = stopped();
+            let read = self.file.read(&mut chunk)?;
+            if read > 0 {
+                self.read += read as u64;
+                self.pending.extend_from_slice(&chunk[..read]);
+                self.complete = self
+                    .pending
+                    .iter()
+                    .rposition(|byte| *byte == b'\n')
+                    .map_or(0, |last| last + 1);
+                continue;
+            }
+            if std::fs::metadata(&self.path)?.len() < self.read {
+                self.recreated = true;
+                RECREATED.store(true, Ordering::Relaxed);
+                return Err(io::Error::other(format!(
+                    "{} was recreated by another run, start the replica over",
+                    self.path.display()
+                )));
+            }
+            if stopping {
+                return Ok(0);
+            }
+            thread::sleep(self.poll);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, stop, Follow};
+    use std::{io::Read, io::Write, thread, time::Duration};
+
+    #[test]
+    fn appended_contents_are_read_until_stopped() {
+        let dir = std::env::temp_dir().join(format!("trp-follow-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("events.csv");
+        std::fs::write(&path, "first\n").unwrap();
+        let mut follow = Follow::open(&path).unwrap();
+        follow.poll = Duration::from_millis(1);
+        let mut line = [0; 6];
+        follow.read_exact(&mut line).unwrap();
+        assert_eq!(&line, b"first\n");
+
+        let appending = {
+            let path = path.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+                file.write_all(b"second\nthi").unwrap();
+                thread::sleep(Duration::from_millis(20));
+                file.write_all(b"rd\nfour").unwrap();
+                stop();
+            })
+        };
+        let mut rest = String::new();
+        follow.read_to_string(&mut rest).unwrap();
+        appending.join().unwrap();
+        // The line cut short is left out.
+        assert_eq!(rest, "second\nthird\n");
+
+        // A log recreated by another run is not followed any further.
+        let mut follow = Follow::open(&path).unwrap();
+        follow.read_to_string(&mut String::new()).unwrap();
+        std::fs::write(&path, "new\n").unwrap();
+        assert!(follow.read(&mut [0; 8]).is_err());
+        assert_eq!(follow.read(&mut [0; 8]).unwrap(), 0);
+        assert!(check().is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,782 @@
+//! Formats transaction files can be stored in, with a [`Source`] reading and a [`Sink`]
+//! writing [`Record`]s of each. Format is picked by file extension:
+//! - `.csv` - the default, columns `type,client,tx,amount`, and optionally `timestamp`,
+//!   `effective_date`, `signature`, `reference` and `correlation_id`. All of them are written,
+//!   empty when a record has none.
+//! - `.ndjson` / `.jsonl` - one flat JSON object per line, with the same keys as csv columns.
+//!   `amount` is omitted for disputes, resolves and chargebacks.
+//! - `.bin` - `TRP1` magic followed by fixed-size little-endian rows: kind `u8`, client `u16`,
+//!   tx `u32`, flag `u8` telling whether amount is present, amount `f32`. Timestamps,
+//!   effective dates, signatures, references and correlation ids are not stored.
+//!
+//! Csv input with another delimiter or other headers is read with a [`Mapping`], set from the
+//! `[input]` and `[columns]` tables of the configuration, which `trp inspect` writes.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+    str::FromStr,
+    sync::OnceLock,
+};
+
+use crate::{
+    config::Engine,
+    log::json_string,
+    parallel::ParallelCsvSource,
+    parser::{self, Record},
+    readahead, signature,
+};
+
+const BINARY_MAGIC: &[u8; 4] = b"TRP1";
+const BINARY_ROW_SIZE: usize = 12;
+/// Kinds in order of their binary tag.
+const KINDS: [&str; 6] = [
+    "deposit",
+    "withdrawal",
+    "dispute",
+    "resolve",
+    "chargeback",
+    "settle",
+];
+
+/// Columns of csv input, as [`Record`] names them, and the ones [`CsvSink`] writes.
+pub const COLUMNS: [&str; 9] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "timestamp",
+    "effective_date",
+    "signature",
+    "reference",
+    "correlation_id",
+];
+
+static MAPPING: OnceLock<Mapping> = OnceLock::new();
+
+/// Layout of csv input which differs from the one trp writes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub delimiter: u8,
+    /// Header of the input by the column of [`COLUMNS`] it holds. Columns which are not
+    /// mapped are found by their own name.
+    pub columns: BTreeMap<String, String>,
+}
+
+impl Default for Mapping {
+    fn default() -> Self {
+        Mapping {
+            delimiter: b',',
+            columns: BTreeMap::new(),
+        }
+    }
+}
+
+/// Reads csv sources with `mapping` from now on. Only the first call has effect.
+pub fn set_mapping(mapping: Mapping) {
+    let _ = MAPPING.set(mapping);
+}
+
+/// Csv reader of `reader` with the [`Mapping`] set, whose headers are renamed to the columns
+/// they hold.
+pub fn csv_reader<R: Read>(reader: R) -> csv::Reader<R> {
+    let mapping = MAPPING.get().cloned().unwrap_or_default();
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(mapping.delimiter)
+        .from_reader(reader);
+    if mapping.columns.is_empty() {
+        return rdr;
+    }
+    // Broken headers are reported along with the first record.
+    if let Ok(headers) = rdr.headers() {
+        let renamed: csv::StringRecord = headers
+            .iter()
+            .map(|header| {
+                mapping
+                    .columns
+                    .iter()
+                    .find(|(_, mapped)| mapped.trim() == header.trim())
+                    .map_or(header, |(column, _)| column.as_str())
+            })
+            .collect();
+        rdr.set_headers(renamed);
+    }
+    rdr
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Csv,
+    Ndjson,
+    Binary,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "ndjson" | "jsonl" => Ok(Format::Ndjson),
+            "bin" | "binary" => Ok(Format::Binary),
+            "parquet" => Err(anyhow::anyhow!("Parquet is not supported")),
+            other => Err(anyhow::anyhow!(
+                "Unknown format {other}, expected csv, ndjson or bin"
+            )),
+        }
+    }
+}
+
+impl Format {
+    /// Format of `path` by its extension, csv when it has none.
+    pub fn of(path: &Path) -> Result<Self, anyhow::Error> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) => extension.to_ascii_lowercase().parse(),
+            None => Ok(Format::Csv),
+        }
+    }
+}
+
+/// Reads records one by one.
+pub trait Source {
+    /// Next record, `None` once input is exhausted. Failing to decode a record does not end
+    /// the input, unless the source can't recover from it.
+    fn next_record(&mut self) -> Option<Result<Record, anyhow::Error>>;
+
+    /// Bytes of input consumed so far.
+    fn position(&self) -> u64;
+
+    /// Line the last record started on, or its position among records when input has no
+    /// lines, counting from 1.
+    fn line(&self) -> u64;
+}
+
+/// Writes records one by one.
+pub trait Sink {
+    fn write(&mut self, record: &Record) -> Result<(), anyhow::Error>;
+
+    /// Must be called once all records are written.
+    fn flush(&mut self) -> Result<(), anyhow::Error>;
+}
+
+/// Opens `path` as a source of its format, read as `engine` says.
+pub fn source(
+    path: &Path,
+    format: Format,
+    engine: Engine,
+) -> Result<Box<dyn Source + Send>, anyhow::Error> {
+    let file = readahead::open(File::open(path)?, engine.input_backend);
+    Ok(match format {
+        Format::Csv if engine.parser_threads > 1 => {
+            Box::new(ParallelCsvSource::new(file, engine.parser_threads))
+        }
+        Format::Csv => Box::new(CsvSource::new(file)),
+        Format::Ndjson => Box::new(NdjsonSource::new(file)),
+        Format::Binary => Box::new(BinarySource::new(file)),
+    })
+}
+
+/// Creates `path` as a sink of its format.
+pub fn sink(path: &Path, format: Format) -> Result<Box<dyn Sink>, anyhow::Error> {
+    let file = File::create(path)?;
+    Ok(match format {
+        Format::Csv => Box::new(CsvSink::new(file)),
+        Format::Ndjson => Box::new(NdjsonSink::new(file)),
+        Format::Binary => Box::new(BinarySink::new(file)),
+    })
+}
+
+pub struct CsvSource<R> {
+    reader: csv::Reader<R>,
+    /// Broken headers are reported as the first record, and nothing else is read.
+    headers: Result<csv::ByteRecord, Option<csv::Error>>,
+    row: csv::ByteRecord,
+    line: u64,
+}
+
+impl<R: Read> CsvSource<R> {
+    pub fn new(reader: R) -> Self {
+        let mut reader = csv_reader(reader);
+        let headers = reader.byte_headers().cloned().map_err(Some);
+        CsvSource {
+            reader,
+            headers,
+            row: csv::ByteRecord::new(),
+            line: 0,
+        }
+    }
+}
+
+impl<R: Read> Source for CsvSource<R> {
+    fn next_record(&mut self) -> Option<Result<Record, anyhow::Error>> {
+        let headers = match &mut self.headers {
+            Ok(headers) => headers,
+            Err(err) => return err.take().map(|err| Err(err.into())),
+        };
+        // Header is read along with the reader, so next record starts where it stopped.
+        self.line = self.reader.position().line();
+        match self.reader.read_byte_record(&mut self.row) {
+            Ok(false) => None,
+            Ok(true) => Some(deserialize(headers, &self.row)),
+            Err(err) => Some(Err(err.into())),
+        }
+    }
+
+    fn position(&self) -> u64 {
+        self.reader.position().byte()
+    }
+
+    fn line(&self) -> u64 {
+        self.line
+    }
+}
+
+/// Record of csv `row` with columns named by `headers`, along with the text its signature
+/// covers, when signatures are verified.
+pub fn deserialize(
+    headers: &csv::ByteRecord,
+    row: &csv::ByteRecord,
+) -> Result<Record, anyhow::Error> {
+    let mut record: Record = row.deserialize(Some(headers))?;
+    if signature::enabled() {
+        record.signed = Some(signature::csv_text(headers, row));
+    }
+    Ok(record)
+}
+
+pub struct CsvSink<W: Write> {
+    out: csv::Writer<W>,
+    started: bool,
+}
+
+impl<W: Write> CsvSink<W> {
+    pub fn new(writer: W) -> Self {
+        CsvSink {
+            out: csv::Writer::from_writer(writer),
+            started: false,
+        }
+    }
+
+    /// Writes the header, with every column records can fill whether or not the first one
+    /// does.
+    fn start(&mut self) -> Result<(), anyhow::Error> {
+        if !self.started {
+            self.out.write_record(COLUMNS)?;
+            self.started = true;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Sink for CsvSink<W> {
+    fn write(&mut self, record: &Record) -> Result<(), anyhow::Error> {
+        self.start()?;
+        let amount = record.amount.map(|amount| amount.to_string());
+        self.out.write_field(&record.kind)?;
+        self.out.write_field(record.client.to_string())?;
+        self.out.write_field(record.tx.to_string())?;
+        self.out.write_field(amount.unwrap_or_default())?;
+        let timestamp = record.timestamp.map(|timestamp| timestamp.to_string());
+        self.out.write_field(timestamp.unwrap_or_default())?;
+        let effective_date = record.effective_date.map(|date| date.to_string());
+        self.out.write_field(effective_date.unwrap_or_default())?;
+        self.out
+            .write_field(record.signature.as_deref().unwrap_or_default())?;
+        self.out
+            .write_field(record.reference.as_deref().unwrap_or_default())?;
+        self.out
+            .write_field(record.correlation_id.as_deref().unwrap_or_default())?;
+        self.out.write_record(None::<&[u8]>)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), anyhow::Error> {
+        self.start()?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+pub struct NdjsonSource<R> {
+    lines: std::io::Lines<BufReader<R>>,
+    position: u64,
+    line: u64,
+}
+
+impl<R: Read> NdjsonSource<R> {
+    pub fn new(reader: R) -> Self {
+        NdjsonSource {
+            lines: BufReader::new(reader).lines(),
+            position: 0,
+            line: 0,
+        }
+    }
+}
+
+impl<R: Read> Source for NdjsonSource<R> {
+    fn next_record(&mut self) -> Option<Result<Record, anyhow::Error>> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(err) => return Some(Err(err.into())),
+            };
+            self.position += line.len() as u64 + 1;
+            self.line += 1;
+            if !line.trim().is_empty() {
+                return Some(parse_json(&line));
+            }
+        }
+    }
+
+    fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn line(&self) -> u64 {
+        self.line
+    }
+}
+
+/// Parses a flat JSON object of string, number and null values.
+fn parse_json(line: &str) -> Result<Record, anyhow::Error> {
+    let invalid = || anyhow::anyhow!("Invalid JSON record: {line}");
+    let mut rest = line
+        .trim()
+        .strip_prefix('{')
+        .and_then(|rest| rest.strip_suffix('}'))
+        .ok_or_else(invalid)?
+        .trim();
+
+    let (mut kind, mut client, mut tx, mut amount) = (None, None, None, None);
+    let (mut timestamp, mut effective_date, mut signature, mut reference) =
+        (None, None, None, None);
+    let mut correlation_id = None;
+    // Text of the columns signatures cover, as the line has them.
+    let mut signed: Vec<(String, String)> = Vec::new();
+    while !rest.is_empty() {
+        let (key, after) = json_str(rest).ok_or_else(invalid)?;
+        let after = after
+            .trim_start()
+            .strip_prefix(':')
+            .ok_or_else(invalid)?
+            .trim_start();
+        let (value, after) = if after.starts_with('"') {
+            let (value, after) = json_str(after).ok_or_else(invalid)?;
+            (Some(value), after)
+        } else {
+            let end = after.find(',').unwrap_or(after.len());
+            let value = after[..end].trim();
+            ((value != "null").then(|| value.to_string()), &after[end..])
+        };
+
+        if let Some(value) = value
+            .as_ref()
+            .filter(|_| signature::COLUMNS.contains(&key.as_str()))
+        {
+            signed.push((key.clone(), value.clone()));
+        }
+        match key.as_str() {
+            "type" => kind = value,
+            "client" => client = value.and_then(|value| value.parse().ok()),
+            "tx" => tx = value.and_then(|value| value.parse().ok()),
+            "amount" => amount = value.map(|value| parser::amount(&value)).transpose()?,
+            "timestamp" => timestamp = value.map(|value| value.parse()).transpose()?,
+            "effective_date" => effective_date = value.map(|value| value.parse()).transpose()?,
+            "signature" => signature = value,
+            "reference" => reference = value,
+            "correlation_id" => correlation_id = value,
+            _ => {}
+        }
+
+        rest = after.trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+    }
+
+    Ok(Record {
+        kind: kind.ok_or_else(invalid)?,
+        client: client.ok_or_else(invalid)?,
+        tx: tx.ok_or_else(invalid)?,
+        amount,
+        timestamp,
+        effective_date,
+        signature,
+        signed: signature::enabled().then(|| {
+            signature::text(|column| {
+                signed
+                    .iter()
+                    .find(|(key, _)| key == column)
+                    .map(|(_, value)| value.as_str())
+            })
+        }),
+        reference,
+        correlation_id,
+        #[cfg(feature = "otel")]
+        traceparent: None,
+    })
+}
+
+/// Splits JSON string at the start of `text` from the rest.
+fn json_str(text: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = text.strip_prefix('"')?.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &text[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                'r' => value.push('\r'),
+                'u' => {
+                    let code: String = (0..4)
+                        .filter_map(|_| chars.next())
+                        .map(|(_, c)| c)
+                        .collect();
+                    value.push(char::from_u32(u32::from_str_radix(&code, 16).ok()?)?);
+                }
+                c => value.push(c),
+            },
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+pub struct NdjsonSink<W: Write> {
+    out: BufWriter<W>,
+}
+
+impl<W: Write> NdjsonSink<W> {
+    pub fn new(writer: W) -> Self {
+        NdjsonSink {
+            out: BufWriter::new(writer),
+        }
+    }
+}
+
+impl<W: Write> Sink for NdjsonSink<W> {
+    fn write(&mut self, record: &Record) -> Result<(), anyhow::Error> {
+        write!(
+            self.out,
+            "{{\"type\":{},\"client\":{},\"tx\":{}",
+            json_string(&record.kind),
+            record.client,
+            record.tx
+        )?;
+        if let Some(amount) = record.amount {
+            write!(self.out, ",\"amount\":{amount}")?;
+        }
+        if let Some(timestamp) = record.timestamp {
+            write!(self.out, ",\"timestamp\":{timestamp}")?;
+        }
+        if let Some(effective_date) = record.effective_date {
+            write!(self.out, ",\"effective_date\":{effective_date}")?;
+        }
+        if let Some(signature) = &record.signature {
+            write!(self.out, ",\"signature\":{}", json_string(signature))?;
+        }
+        if let Some(reference) = &record.reference {
+            write!(self.out, ",\"reference\":{}", json_string(reference))?;
+        }
+        if let Some(correlation_id) = &record.correlation_id {
+            let correlation_id = json_string(correlation_id);
+            write!(self.out, ",\"correlation_id\":{correlation_id}")?;
+        }
+        writeln!(self.out, "}}")?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), anyhow::Error> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+pub struct BinarySource<R> {
+    reader: BufReader<R>,
+    position: u64,
+    /// Set once the header turns out to be invalid, nothing past it is read.
+    invalid: bool,
+}
+
+impl<R: Read> BinarySource<R> {
+    pub fn new(reader: R) -> Self {
+        BinarySource {
+            reader: BufReader::new(reader),
+            position: 0,
+            invalid: false,
+        }
+    }
+}
+
+impl<R: Read> Source for BinarySource<R> {
+    fn next_record(&mut self) -> Option<Result<Record, anyhow::Error>> {
+        if self.invalid {
+            return None;
+        }
+        if self.position == 0 {
+            let mut magic = [0; 4];
+            let header = self.reader.read_exact(&mut magic);
+            if header.is_err() || &magic != BINARY_MAGIC {
+                self.invalid = true;
+                return Some(Err(anyhow::anyhow!("Not a trp binary file")));
+            }
+            self.position = magic.len() as u64;
+        }
+
+        let mut row = [0; BINARY_ROW_SIZE];
+        match self.reader.read_exact(&mut row) {
+            Ok(()) => self.position += BINARY_ROW_SIZE as u64,
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return None,
+            Err(err) => return Some(Err(err.into())),
+        }
+
+        let Some(kind) = KINDS.get(usize::from(row[0])) else {
+            return Some(Err(anyhow::anyhow!("Unknown kind tag {}", row[0])));
+        };
+        let amount = f32::from_le_bytes([row[8], row[9], row[10], row[11]]);
+        Some(Ok(Record {
+            kind: kind.to_string(),
+            client: u16::from_le_bytes([row[1], row[2]]),
+            tx: u32::from_le_bytes([row[3], row[4], row[5], row[6]]),
+            amount: (row[7] != 0).then_some(amount),
+            timestamp: None,
+            effective_date: None,
+            signature: None,
+            signed: None,
+            reference: None,
+            correlation_id: None,
+            #[cfg(feature = "otel")]
+            traceparent: None,
+        }))
+    }
+
+    fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn line(&self) -> u64 {
+        self.position.saturating_sub(BINARY_MAGIC.len() as u64) / BINARY_ROW_SIZE as u64
+    }
+}
+
+pub struct BinarySink<W: Write> {
+    out: BufWriter<W>,
+    started: bool,
+}
+
+impl<W: Write> BinarySink<W> {
+    pub fn new(writer: W) -> Self {
+        BinarySink {
+            out: BufWriter::new(writer),
+            started: false,
+        }
+    }
+
+    fn start(&mut self) -> Result<(), anyhow::Error> {
+        if !self.started {
+            self.out.write_all(BINARY_MAGIC)?;
+            self.started = true;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Sink for BinarySink<W> {
+    fn write(&mut self, record: &Record) -> Result<(), anyhow::Error> {
+        self.start()?;
+        let kind = KINDS
+            .iter()
+            .position(|kind| *kind == record.kind)
+            .ok_or_else(|| anyhow::anyhow!("Kind {:?} can't be stored as binary", record.kind))?;
+
+        let mut row = [0; BINARY_ROW_SIZE];
+        row[0] = kind as u8;
+        row[1..3].copy_from_slice(&record.client.to_le_bytes());
+        row[3..7].copy_from_slice(&record.tx.to_le_bytes());
+        row[7] = u8::from(record.amount.is_some());
+        row[8..].copy_from_slice(&record.amount.unwrap_or_default().to_le_bytes());
+        self.out.write_all(&row)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), anyhow::Error> {
+        self.start()?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BinarySink, BinarySource, CsvSink, CsvSource, NdjsonSink, NdjsonSource, Sink, Source,
+    };
+    use crate::parser::Record;
+
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,1.5
+withdrawal,2,2,0.25
+dispute,1,1,
+";
+
+    fn drain(mut source: impl Source) -> Vec<Record> {
+        std::iter::from_fn(|| source.next_record())
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    fn fields(records: &[Record]) -> Vec<(String, u16, u32, Option<f32>)> {
+        records
+            .iter()
+            .map(|record| (record.kind.clone(), record.client, record.tx, record.amount))
+            .collect()
+    }
+
+    #[test]
+    fn formats_round_trip() {
+        let records = drain(CsvSource::new(INPUT.as_bytes()));
+        assert_eq!(records.len(), 3);
+
+        let mut csv = Vec::new();
+        let mut ndjson = Vec::new();
+        let mut binary = Vec::new();
+        {
+            let mut sinks: [Box<dyn Sink>; 3] = [
+                Box::new(CsvSink::new(&mut csv)),
+                Box::new(NdjsonSink::new(&mut ndjson)),
+                Box::new(BinarySink::new(&mut binary)),
+            ];
+            for sink in &mut sinks {
+                records
+                    .iter()
+                    .for_each(|record| sink.write(record).unwrap());
+                sink.flush().unwrap();
+            }
+        }
+
+        assert_eq!(
+            fields(&drain(CsvSource::new(csv.as_slice()))),
+            fields(&records)
+        );
+        assert_eq!(
+            String::from_utf8(ndjson.clone()).unwrap().lines().last(),
+            Some(r#"{"type":"dispute","client":1,"tx":1}"#)
+        );
+        assert_eq!(binary.len(), 4 + 3 * 12);
+        assert_eq!(
+            fields(&drain(NdjsonSource::new(ndjson.as_slice()))),
+            fields(&records)
+        );
+        assert_eq!(
+            fields(&drain(BinarySource::new(binary.as_slice()))),
+            fields(&records)
+        );
+    }
+
+    #[test]
+    fn json_records_are_parsed() {
+        let input = "{ \"client\": 3, \"type\": \"deposit\", \"amount\": 2.0, \"tx\": 7, \"memo\": \"a,\\\"b\" }\n\n{\"type\":\"resolve\",\"client\":3,\"tx\":7,\"amount\":null}\n{\"type\":\"deposit\"}\n";
+        let mut source = NdjsonSource::new(input.as_bytes());
+        let first = source.next_record().unwrap().unwrap();
+        assert_eq!(
+            fields(&[first]),
+            vec![("deposit".to_string(), 3, 7, Some(2.0))]
+        );
+        let second = source.next_record().unwrap().unwrap();
+        assert_eq!(second.amount, None);
+        assert!(source.next_record().unwrap().is_err());
+        assert!(source.next_record().is_none());
+        assert_eq!(source.position(), input.len() as u64);
+    }
+
+    #[test]
+    fn timestamps_are_kept() {
+        let input =
+            "type,client,tx,amount,timestamp,effective_date,signature,reference,correlation_id\n\
+            deposit,1,1,1.5,1700000000000,1700086400000,c0ffee,\"BANK, 1\",req-1\n\
+            dispute,1,1,,,,,,\n";
+        let records = drain(CsvSource::new(input.as_bytes()));
+        assert_eq!(records[0].timestamp, Some(1_700_000_000_000));
+        assert_eq!(records[0].effective_date, Some(1_700_086_400_000));
+        assert_eq!(records[1].timestamp, None);
+
+        let mut csv = Vec::new();
+        let mut ndjson = Vec::new();
+        {
+            let mut sinks: [Box<dyn Sink>; 2] = [
+                Box::new(CsvSink::new(&mut csv)),
+                Box::new(NdjsonSink::new(&mut ndjson)),
+            ];
+            for sink in &mut sinks {
+                records
+                    .iter()
+                    .for_each(|record| sink.write(record).unwrap());
+                sink.flush().unwrap();
+            }
+        }
+        assert_eq!(String::from_utf8(csv).unwrap(), input);
+        let parsed = drain(NdjsonSource::new(ndjson.as_slice()));
+        assert_eq!(parsed[0].timestamp, Some(1_700_000_000_000));
+        assert_eq!(parsed[0].effective_date, Some(1_700_086_400_000));
+        assert_eq!(parsed[0].signature.as_deref(), Some("c0ffee"));
+        assert_eq!(parsed[1].signature, None);
+        assert_eq!(parsed[0].reference.as_deref(), Some("BANK, 1"));
+        assert_eq!(parsed[0].correlation_id.as_deref(), Some("req-1"));
+    }
+
+    #[test]
+    fn dates_after_undated_records_are_kept() {
+        let mut records = drain(CsvSource::new(INPUT.as_bytes()));
+        let dated = "type,client,tx,amount,timestamp,effective_date\n\
+            deposit,1,4,1.5,1700000000000,1700086400000\n";
+        records.extend(drain(CsvSource::new(dated.as_bytes())));
+
+        let mut csv = Vec::new();
+        {
+            let mut sink = CsvSink::new(&mut csv);
+            records
+                .iter()
+                .for_each(|record| sink.write(record).unwrap());
+            sink.flush().unwrap();
+        }
+        let parsed = drain(CsvSource::new(csv.as_slice()));
+        assert_eq!(parsed[0].timestamp, None);
+        assert_eq!(parsed[3].timestamp, Some(1_700_000_000_000));
+        assert_eq!(parsed[3].effective_date, Some(1_700_086_400_000));
+    }
+
+    #[test]
+    fn records_are_numbered_by_line() {
+        fn lines(mut source: impl Source) -> Vec<u64> {
+            std::iter::from_fn(|| source.next_record().map(|_| source.line())).collect()
+        }
+
+        let csv =
+            "type,client,tx,amount\ndeposit,1,1,1.5\n\"withdrawal\n\",2,2,0.25\ndispute,1,1,\n";
+        assert_eq!(lines(CsvSource::new(csv.as_bytes())), vec![2, 3, 5]);
+        let ndjson = "{\"type\":\"deposit\",\"client\":1,\"tx\":1}\n\n{\"type\":\"dispute\",\"client\":1,\"tx\":1}\n";
+        assert_eq!(lines(NdjsonSource::new(ndjson.as_bytes())), vec![1, 3]);
+
+        let mut binary = Vec::new();
+        {
+            let mut sink = BinarySink::new(&mut binary);
+            for record in drain(CsvSource::new(INPUT.as_bytes())) {
+                sink.write(&record).unwrap();
+            }
+            sink.flush().unwrap();
+        }
+        assert_eq!(lines(BinarySource::new(binary.as_slice())), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn invalid_binary_header_ends_input() {
+        for input in [&b"TR"[..], b"CSV1\x00\x01\x00"] {
+            let mut source = BinarySource::new(input);
+            assert!(source.next_record().unwrap().is_err());
+            assert!(source.next_record().is_none());
+        }
+    }
+}
@@ -0,0 +1,922 @@
+//! Account events over gRPC, enabled with `trp serve --grpc-addr`: clients open a
+//! server-streaming subscription, optionally filtered by client ids, and receive an event
+//! every time a message changes balances of an account, for live dashboards on top of trp.
+//!
+//! ```proto
+//! syntax = "proto3";
+//! package trp;
+//!
+//! service Events {
+//!   rpc Subscribe(SubscribeRequest) returns (stream AccountEvent);
+//! }
+//!
+//! message SubscribeRequest {
+//!   // Clients to receive events of, every client when empty.
+//!   repeated uint32 clients = 1;
+//! }
+//!
+//! message AccountEvent {
+//!   enum Kind {
+//!     BALANCE = 0;
+//!     // The message locked the account.
+//!     LOCKED = 1;
+//!   }
+//!   uint32 client = 1;
+//!   uint32 tx = 2;
+//!   Kind kind = 3;
+//!   float available = 4;
+//!   float held = 5;
+//!   float total = 6;
+//!   bool locked = 7;
+//!   optional uint64 timestamp = 8;
+//!   // Type of the message, e.g. deposit.
+//!   string message = 9;
+//!   // Correlation id of the record of the message, see provenance.
+//!   optional string correlation_id = 10;
+//! }
+//! ```
+//!
+//! The server speaks just enough HTTP/2 for gRPC over cleartext (`h2c`) connections with prior
+//! knowledge. Headers of requests are not decoded, so every call on a port gets the same
+//! [`Answer`], whatever its method. [`flight`](crate::flight) is served the same way.
+//!
+//! Account tasks never wait for subscribers. Every event carries full balances of its
+//! account, so subscribers which fall behind, or don't open their flow control window wide
+//! enough, miss events rather than slowing the engine down, and catch up with the next event
+//! of the account. Once the run is over, every subscription ends with `OK` status.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
+    sync::{broadcast, mpsc},
+};
+
+use crate::{log, state::AccountRecord, Message};
+
+/// Events kept for subscribers which fall behind.
+const CAPACITY: usize = 4096;
+/// How long subscribers get to receive what is left once the run is over.
+const DRAIN: Duration = Duration::from_secs(1);
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// Largest frame peers may send, unless they agreed otherwise, which we never ask for.
+const MAX_FRAME: usize = 16_384;
+const INITIAL_WINDOW: i64 = 65_535;
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+/// `INVALID_ARGUMENT` status of gRPC.
+const INVALID_ARGUMENT: u8 = 3;
+
+static EVENTS: Mutex<Option<broadcast::Sender<Event>>> = Mutex::new(None);
+/// Connections subscribed to events.
+static SUBSCRIBED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Balance = 0,
+    Locked = 1,
+}
+
+/// Balances of an account after a message was applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub kind: Kind,
+    pub tx: u32,
+    pub timestamp: Option<u64>,
+    pub message: &'static str,
+    pub balances: AccountRecord,
+    pub correlation_id: Option<Arc<str>>,
+}
+
+impl Event {
+    /// `AccountEvent` message of the event.
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        varint_field(&mut out, 1, u64::from(self.balances.client));
+        varint_field(&mut out, 2, u64::from(self.tx));
+        varint_field(&mut out, 3, self.kind as u64);
+        for (field, amount) in [
+            (4, self.balances.available),
+            (5, self.balances.held),
+            (6, self.balances.total),
+        ] {
+            varint(&mut out, field << 3 | 5);
+            out.extend_from_slice(&amount.to_le_bytes());
+        }
+        varint_field(&mut out, 7, u64::from(self.balances.locked));
+        if let Some(timestamp) = self.timestamp {
+            varint_field(&mut out, 8, timestamp);
+        }
+        for (field, text) in [
+            (9, Some(self.message)),
+            (10, self.correlation_id.as_deref()),
+        ] {
+            if let Some(text) = text {
+                varint(&mut out, field << 3 | 2);
+                varint(&mut out, text.len() as u64);
+                out.extend_from_slice(text.as_bytes());
+            }
+        }
+        out
+    }
+}
+
+/// Starts keeping events for subscribers.
+pub fn enable() {
+    *EVENTS.lock().unwrap_or_else(|err| err.into_inner()) = Some(broadcast::channel(CAPACITY).0);
+}
+
+/// Whether events are kept for subscribers.
+pub fn enabled() -> bool {
+    EVENTS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .is_some()
+}
+
+/// Publishes balances of `record`, after `msg` with `correlation_id` was applied to its
+/// account. `locked` is set when `msg` locked the account.
+pub fn applied(
+    msg: &Message,
+    correlation_id: Option<&Arc<str>>,
+    record: AccountRecord,
+    locked: bool,
+) {
+    if let Some(events) = EVENTS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .as_ref()
+    {
+        // Nobody may be subscribed, which is fine.
+        let _ = events.send(Event {
+            kind: if locked { Kind::Locked } else { Kind::Balance },
+            tx: msg.transaction_id(),
+            timestamp: msg.timestamp(),
+            message: msg.kind(),
+            balances: record,
+            correlation_id: correlation_id.cloned(),
+        });
+    }
+}
+
+/// Ends subscriptions, once subscribers received what is left or [`DRAIN`] is over.
+pub async fn stop() {
+    EVENTS.lock().unwrap_or_else(|err| err.into_inner()).take();
+    let started = Instant::now();
+    while SUBSCRIBED.load(Ordering::Acquire) > 0 && started.elapsed() < DRAIN {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Accepts subscriptions on `addr` until the runtime shuts down.
+pub async fn serve(addr: String) -> Result<(), anyhow::Error> {
+    listen(addr, "grpc", |request| {
+        subscription(request).map(Reply::Events)
+    })
+    .await
+}
+
+/// How a call is answered, once its request is in.
+#[derive(Debug)]
+pub enum Reply {
+    /// Account events of clients, every client when empty, until the run is over.
+    Events(HashSet<u16>),
+    /// Messages, followed by `OK` status.
+    Messages(Vec<Vec<u8>>),
+}
+
+/// Answers a request, sent as a single gRPC message, or fails it with `INVALID_ARGUMENT`.
+pub type Answer = fn(&[u8]) -> Result<Reply, anyhow::Error>;
+
+/// Accepts calls on `addr`, answered with `answer`, until the runtime shuts down. Logs of
+/// connections go to spans named `name`.
+pub async fn listen(addr: String, name: &'static str, answer: Answer) -> Result<(), anyhow::Error> {
+    let span = log::Span::new(name).with("addr", &addr);
+    let listener = TcpListener::bind(&addr).await?;
+    log::info!(span, "Accepting calls");
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let span = log::Span::new(name).with("peer", peer);
+        tokio::spawn(async move {
+            if let Err(err) = connection(stream, answer, &span).await {
+                log::debug!(span, "Connection failed: {err}");
+            }
+        });
+    }
+}
+
+#[derive(Debug)]
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+/// A call on a stream of a connection.
+#[derive(Debug)]
+struct Call {
+    /// How much more we may send on the stream.
+    window: i64,
+    request: Vec<u8>,
+    /// Clients to send events of, once subscribed, every client when empty.
+    clients: Option<HashSet<u16>>,
+    /// Messages which are yet to be sent, ending the call once they are.
+    pending: Option<Vec<u8>>,
+}
+
+impl Call {
+    fn wants(&self, client: u16) -> bool {
+        self.clients
+            .as_ref()
+            .is_some_and(|clients| clients.is_empty() || clients.contains(&client))
+    }
+}
+
+/// State of a connection, turning frames of the peer and events into frames to send.
+#[derive(Debug)]
+struct Connection {
+    answer: Answer,
+    calls: BTreeMap<u32, Call>,
+    /// How much more we may send on the connection.
+    window: i64,
+    initial_window: i64,
+    max_frame: usize,
+    last_stream: u32,
+    /// Events which did not fit into flow control windows.
+    missed: u64,
+}
+
+impl Connection {
+    fn new(answer: Answer) -> Self {
+        Connection {
+            answer,
+            calls: BTreeMap::new(),
+            window: INITIAL_WINDOW,
+            initial_window: INITIAL_WINDOW,
+            max_frame: MAX_FRAME,
+            last_stream: 0,
+            missed: 0,
+        }
+    }
+
+    /// Whether any call waits for account events.
+    fn subscribed(&self) -> bool {
+        self.calls.values().any(|call| call.clients.is_some())
+    }
+
+    /// Handles `frame` of the peer, returning frames to send back, `None` once the peer is
+    /// going away.
+    fn receive(
+        &mut self,
+        frame: Frame,
+        span: &log::Span,
+    ) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        let mut out = Vec::new();
+        match frame.kind {
+            SETTINGS if frame.flags & ACK == 0 => {
+                for setting in frame.payload.chunks_exact(6) {
+                    let id = u16::from_be_bytes([setting[0], setting[1]]);
+                    let value =
+                        u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+                    match id {
+                        SETTINGS_INITIAL_WINDOW_SIZE => {
+                            let delta = i64::from(value) - self.initial_window;
+                            self.initial_window = i64::from(value);
+                            for call in self.calls.values_mut() {
+                                call.window += delta;
+                            }
+                        }
+                        SETTINGS_MAX_FRAME_SIZE => self.max_frame = value as usize,
+                        _ => {}
+                    }
+                }
+                out.extend(framed(SETTINGS, ACK, 0, &[]));
+            }
+            PING if frame.flags & ACK == 0 => out.extend(framed(PING, ACK, 0, &frame.payload)),
+            WINDOW_UPDATE if frame.payload.len() == 4 => {
+                let increment = u32::from_be_bytes([
+                    frame.payload[0],
+                    frame.payload[1],
+                    frame.payload[2],
+                    frame.payload[3],
+                ]) & 0x7fff_ffff;
+                match frame.stream {
+                    0 => self.window += i64::from(increment),
+                    stream => {
+                        if let Some(call) = self.calls.get_mut(&stream) {
+                            call.window += i64::from(increment);
+                        }
+                    }
+                }
+            }
+            HEADERS if frame.stream > self.last_stream => {
+                self.last_stream = frame.stream;
+                self.calls.insert(
+                    frame.stream,
+                    Call {
+                        window: self.initial_window,
+                        request: Vec::new(),
+                        clients: None,
+                        pending: None,
+                    },
+                );
+            }
+            DATA => {
+                if !frame.payload.is_empty() {
+                    // Hand back what the request took of the window of the connection.
+                    let taken = frame.payload.len() as u32;
+                    out.extend(framed(WINDOW_UPDATE, 0, 0, &taken.to_be_bytes()));
+                }
+                let payload = unpadded(&frame)?;
+                if let Some(call) = self.calls.get_mut(&frame.stream) {
+                    call.request.extend_from_slice(payload);
+                }
+            }
+            RST_STREAM => {
+                self.calls.remove(&frame.stream);
+            }
+            GOAWAY => return Ok(None),
+            // Continuation of headers, priorities and acks.
+            _ => {}
+        }
+
+        if matches!(frame.kind, HEADERS | DATA) && frame.flags & END_STREAM != 0 {
+            if let Some(call) = self.calls.get_mut(&frame.stream) {
+                match (self.answer)(&call.request) {
+                    Ok(Reply::Events(clients)) => {
+                        log::info!(span, stream = frame.stream, clients = clients.len(); "Subscribed to account events");
+                        call.clients = Some(clients);
+                        out.extend(framed(HEADERS, END_HEADERS, frame.stream, &response()));
+                    }
+                    Ok(Reply::Messages(messages)) => {
+                        log::debug!(span, stream = frame.stream, messages = messages.len(); "Answering call");
+                        call.pending = Some(
+                            messages
+                                .iter()
+                                .flat_map(|message| grpc_message(message))
+                                .collect(),
+                        );
+                        out.extend(framed(HEADERS, END_HEADERS, frame.stream, &response()));
+                    }
+                    Err(err) => {
+                        log::debug!(span, stream = frame.stream; "Invalid request: {err}");
+                        self.calls.remove(&frame.stream);
+                        let trailers = trailers(INVALID_ARGUMENT, &err.to_string(), true);
+                        out.extend(framed(
+                            HEADERS,
+                            END_HEADERS | END_STREAM,
+                            frame.stream,
+                            &trailers,
+                        ));
+                    }
+                }
+            }
+        }
+        out.extend(self.flush());
+        Ok(Some(out))
+    }
+
+    /// Frames of pending messages which fit into flow control windows, ending calls which
+    /// have nothing left to send.
+    fn flush(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut done = Vec::new();
+        for (stream, call) in &mut self.calls {
+            let Some(pending) = call.pending.as_mut() else {
+                continue;
+            };
+            while !pending.is_empty() {
+                let room = self.window.min(call.window).min(self.max_frame as i64);
+                if room <= 0 {
+                    break;
+                }
+                let size = pending.len().min(room as usize);
+                out.extend(framed(DATA, 0, *stream, &pending[..size]));
+                pending.drain(..size);
+                self.window -= size as i64;
+                call.window -= size as i64;
+            }
+            if pending.is_empty() {
+                out.extend(framed(
+                    HEADERS,
+                    END_HEADERS | END_STREAM,
+                    *stream,
+                    &trailers(0, "", false),
+                ));
+                done.push(*stream);
+            }
+        }
+        for stream in done {
+            self.calls.remove(&stream);
+        }
+        out
+    }
+
+    /// Frames of `event` for every call subscribed to its client, which has room for it.
+    fn publish(&mut self, event: &Event) -> Vec<u8> {
+        let message = grpc_message(&event.encode());
+        let size = message.len() as i64;
+        let mut out = Vec::new();
+        for (stream, call) in &mut self.calls {
+            if !call.wants(event.balances.client) {
+                continue;
+            }
+            if self.window < size || call.window < size {
+                self.missed += 1;
+                continue;
+            }
+            self.window -= size;
+            call.window -= size;
+            out.extend(framed(DATA, 0, *stream, &message));
+        }
+        out
+    }
+
+    /// Frames ending every subscription with `OK` status, and the connection.
+    fn close(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (stream, call) in &self.calls {
+            if call.clients.is_some() {
+                let trailers = trailers(0, "", false);
+                out.extend(framed(
+                    HEADERS,
+                    END_HEADERS | END_STREAM,
+                    *stream,
+                    &trailers,
+                ));
+            }
+        }
+        let mut goaway = self.last_stream.to_be_bytes().to_vec();
+        goaway.extend_from_slice(&0u32.to_be_bytes());
+        out.extend(framed(GOAWAY, 0, 0, &goaway));
+        out
+    }
+}
+
+/// Next account event, never once there is nothing to subscribe to.
+async fn next(
+    events: &mut Option<broadcast::Receiver<Event>>,
+) -> Result<Event, broadcast::error::RecvError> {
+    match events.as_mut() {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn connection(
+    stream: TcpStream,
+    answer: Answer,
+    span: &log::Span,
+) -> Result<(), anyhow::Error> {
+    let (mut read, mut write) = stream.into_split();
+    let mut preface = [0; PREFACE.len()];
+    read.read_exact(&mut preface).await?;
+    if preface != PREFACE {
+        return Err(anyhow::anyhow!("Not an HTTP/2 connection"));
+    }
+    write.write_all(&framed(SETTINGS, 0, 0, &[])).await?;
+
+    // Frames are read by a task of their own, so reads are never cut short by events.
+    let (frames_tx, mut frames) = mpsc::channel(16);
+    let reader = tokio::spawn(read_frames(read, frames_tx));
+    let mut connection = Connection::new(answer);
+    // Subscribed to once a call asks for events.
+    let mut events = None;
+    let outcome = loop {
+        let out = tokio::select! {
+            frame = frames.recv() => match frame {
+                Some(Ok(frame)) => match connection.receive(frame, span) {
+                    Ok(Some(out)) => out,
+                    Ok(None) => break Ok(false),
+                    Err(err) => break Err(err),
+                },
+                Some(Err(err)) => break Err(err),
+                // Closed by the peer.
+                None => break Ok(false),
+            },
+            event = next(&mut events) => match event {
+                Ok(event) => connection.publish(&event),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    log::warn!(span, missed = skipped; "Subscriber fell behind, events were missed");
+                    continue;
+                }
+                // The run is over.
+                Err(broadcast::error::RecvError::Closed) => break Ok(true),
+            },
+        };
+        if let Err(err) = write.write_all(&out).await {
+            break Err(err.into());
+        }
+        if events.is_none() && connection.subscribed() {
+            let subscribed = EVENTS
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .as_ref()
+                .map(broadcast::Sender::subscribe);
+            match subscribed {
+                Some(subscribed) => {
+                    events = Some(subscribed);
+                    SUBSCRIBED.fetch_add(1, Ordering::AcqRel);
+                }
+                // The run is over, or events were never enabled.
+                None => break Ok(true),
+            }
+        }
+    };
+    reader.abort();
+    if events.is_some() {
+        SUBSCRIBED.fetch_sub(1, Ordering::AcqRel);
+    }
+    if connection.missed > 0 {
+        log::warn!(span, missed = connection.missed; "Events did not fit into flow control windows of subscriber");
+    }
+    if outcome? {
+        write.write_all(&connection.close()).await?;
+        write.shutdown().await?;
+    }
+    Ok(())
+}
+
+async fn read_frames(mut read: OwnedReadHalf, frames: mpsc::Sender<Result<Frame, anyhow::Error>>) {
+    loop {
+        let mut header = [0; 9];
+        if read.read_exact(&mut header).await.is_err() {
+            return;
+        }
+        let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let frame = if length > MAX_FRAME {
+            Err(anyhow::anyhow!("Frame of {length} bytes is too large"))
+        } else {
+            let mut payload = vec![0; length];
+            if read.read_exact(&mut payload).await.is_err() {
+                return;
+            }
+            Ok(Frame {
+                kind: header[3],
+                flags: header[4],
+                stream: u32::from_be_bytes([header[5], header[6], header[7], header[8]])
+                    & 0x7fff_ffff,
+                payload,
+            })
+        };
+        let failed = frame.is_err();
+        if frames.send(frame).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Payload of a data frame, without padding.
+fn unpadded(frame: &Frame) -> Result<&[u8], anyhow::Error> {
+    if frame.flags & PADDED == 0 {
+        return Ok(&frame.payload);
+    }
+    let padding = usize::from(*frame.payload.first().unwrap_or(&0));
+    frame
+        .payload
+        .get(1..frame.payload.len().saturating_sub(padding))
+        .ok_or_else(|| anyhow::anyhow!("Padding is longer than the frame"))
+}
+
+fn framed(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+    let mut out = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+    out.push(kind);
+    out.push(flags);
+    out.extend_from_slice(&stream.to_be_bytes());
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Clients of a `SubscribeRequest`, sent as a single uncompressed gRPC message.
+fn subscription(request: &[u8]) -> Result<HashSet<u16>, anyhow::Error> {
+    let mut clients = HashSet::new();
+    let mut input = message(request)?;
+    while !input.is_empty() {
+        let key = read_varint(&mut input)?;
+        match (key >> 3, key & 7) {
+            (1, 0) => clients.insert(client(read_varint(&mut input)?)?),
+            (1, 2) => {
+                let length = read_varint(&mut input)? as usize;
+                let mut packed = input
+                    .get(..length)
+                    .ok_or_else(|| anyhow::anyhow!("Request is cut short"))?;
+                input = &input[length..];
+                while !packed.is_empty() {
+                    clients.insert(client(read_varint(&mut packed)?)?);
+                }
+                continue;
+            }
+            // Unknown fields are skipped.
+            (_, 0) => {
+                read_varint(&mut input)?;
+                continue;
+            }
+            (_, wire) => {
+                let length = match wire {
+                    1 => 8,
+                    2 => read_varint(&mut input)? as usize,
+                    5 => 4,
+                    _ => return Err(anyhow::anyhow!("Unknown wire type {wire}")),
+                };
+                input = input
+                    .get(length..)
+                    .ok_or_else(|| anyhow::anyhow!("Request is cut short"))?;
+                continue;
+            }
+        };
+    }
+    Ok(clients)
+}
+
+/// Message of `request`, sent as a single uncompressed gRPC message, empty when there is none.
+pub fn message(request: &[u8]) -> Result<&[u8], anyhow::Error> {
+    match request {
+        [] => Ok(&[]),
+        [0, a, b, c, d, rest @ ..] => rest
+            .get(..u32::from_be_bytes([*a, *b, *c, *d]) as usize)
+            .ok_or_else(|| anyhow::anyhow!("Request is cut short")),
+        [1, ..] => Err(anyhow::anyhow!("Compressed requests are not supported")),
+        _ => Err(anyhow::anyhow!("Request is not a gRPC message")),
+    }
+}
+
+fn client(id: u64) -> Result<u16, anyhow::Error> {
+    u16::try_from(id).map_err(|_| anyhow::anyhow!("{id} is not a client id"))
+}
+
+fn read_varint(input: &mut &[u8]) -> Result<u64, anyhow::Error> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = input
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("Request is cut short"))?;
+        *input = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(anyhow::anyhow!("Varint is too long"))
+}
+
+pub fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn varint_field(out: &mut Vec<u8>, field: u64, value: u64) {
+    varint(out, field << 3);
+    varint(out, value);
+}
+
+/// `message` as an uncompressed gRPC message.
+fn grpc_message(message: &[u8]) -> Vec<u8> {
+    let mut out = vec![0];
+    out.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    out.extend_from_slice(message);
+    out
+}
+
+/// Headers of a response, `:status: 200` and `content-type: application/grpc`.
+fn response() -> Vec<u8> {
+    // Indexed `:status: 200` of the static table.
+    let mut out = vec![0x88];
+    // Literal without indexing, with name `content-type` of the static table.
+    hpack_integer(&mut out, 0x00, 4, 31);
+    hpack_string(&mut out, "application/grpc");
+    out
+}
+
+/// Trailers ending a call with `status`, along with headers of a response when `only`, for
+/// calls which fail straight away.
+fn trailers(status: u8, message: &str, only: bool) -> Vec<u8> {
+    let mut out = if only { response() } else { Vec::new() };
+    for (name, value) in [
+        ("grpc-status", status.to_string().as_str()),
+        ("grpc-message", message),
+    ] {
+        if value.is_empty() {
+            continue;
+        }
+        // Literal without indexing, with a new name.
+        out.push(0x00);
+        hpack_string(&mut out, name);
+        hpack_string(&mut out, value);
+    }
+    out
+}
+
+fn hpack_string(out: &mut Vec<u8>, value: &str) {
+    hpack_integer(out, 0x00, 7, value.len());
+    out.extend_from_slice(value.as_bytes());
+}
+
+/// Appends `value` with an N-bit prefix, the rest of the first byte taken by `flags`.
+fn hpack_integer(out: &mut Vec<u8>, flags: u8, bits: u32, mut value: usize) {
+    let max = (1 << bits) - 1;
+    if value < max {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        out.push((value % 0x80) as u8 | 0x80);
+        value /= 0x80;
+    }
+    out.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        grpc_message, hpack_integer, subscription, Connection, Event, Frame, Kind, Reply, ACK,
+        DATA, END_HEADERS, END_STREAM, GOAWAY, HEADERS, SETTINGS, WINDOW_UPDATE,
+    };
+    use crate::{log, state::AccountRecord};
+
+    fn frame(kind: u8, flags: u8, stream: u32, payload: Vec<u8>) -> Frame {
+        Frame {
+            kind,
+            flags,
+            stream,
+            payload,
+        }
+    }
+
+    /// Kinds, flags and streams of frames in `out`.
+    fn sent(mut out: &[u8]) -> Vec<(u8, u8, u32)> {
+        let mut frames = Vec::new();
+        while !out.is_empty() {
+            let length = u32::from_be_bytes([0, out[0], out[1], out[2]]) as usize;
+            let stream = u32::from_be_bytes([out[5], out[6], out[7], out[8]]);
+            frames.push((out[3], out[4], stream));
+            out = &out[9 + length..];
+        }
+        frames
+    }
+
+    fn event(client: u16) -> Event {
+        Event {
+            kind: Kind::Balance,
+            tx: 1,
+            timestamp: None,
+            message: "deposit",
+            balances: AccountRecord {
+                client,
+                available: 1.0,
+                held: 0.0,
+                total: 1.0,
+                locked: false,
+            },
+            correlation_id: None,
+        }
+    }
+
+    #[test]
+    fn subscription_lists_clients_of_request() {
+        // Packed, then one more on its own.
+        let clients =
+            subscription(&grpc_message(&[0x0a, 0x03, 0x01, 0x80, 0x01, 0x08, 0x07])).unwrap();
+        let mut clients: Vec<_> = clients.into_iter().collect();
+        clients.sort();
+        assert_eq!(clients, vec![1, 7, 128]);
+
+        assert!(subscription(&grpc_message(&[])).unwrap().is_empty());
+        assert!(subscription(&[]).unwrap().is_empty());
+        // Not a client id.
+        assert!(subscription(&grpc_message(&[0x08, 0x80, 0x80, 0x04])).is_err());
+        assert!(subscription(&[1, 0, 0, 0, 0]).is_err());
+        assert!(subscription(&[0, 0, 0, 0, 3, 0x08]).is_err());
+
+        let mut out = Vec::new();
+        hpack_integer(&mut out, 0x00, 4, 31);
+        hpack_integer(&mut out, 0x00, 5, 1337);
+        assert_eq!(out, vec![0x0f, 0x10, 0x1f, 0x9a, 0x0a]);
+    }
+
+    #[test]
+    fn correlation_ids_follow_the_message() {
+        assert!(event(1).encode().ends_with(b"\x4a\x07deposit"));
+        let event = Event {
+            correlation_id: Some("req-1".into()),
+            ..event(1)
+        };
+        assert!(event.encode().ends_with(b"\x4a\x07deposit\x52\x05req-1"));
+    }
+
+    #[test]
+    fn events_reach_subscribers_of_their_clients() {
+        let span = log::Span::new("grpc");
+        let mut connection = Connection::new(|request| subscription(request).map(Reply::Events));
+        let request = grpc_message(&[0x08, 0x02]);
+        connection
+            .receive(frame(HEADERS, END_HEADERS, 1, vec![]), &span)
+            .unwrap();
+        let out = connection
+            .receive(frame(DATA, END_STREAM, 1, request), &span)
+            .unwrap()
+            .unwrap();
+        assert_eq!(sent(&out)[1], (HEADERS, END_HEADERS, 1));
+        // Every client, without a request at all.
+        connection
+            .receive(frame(HEADERS, END_HEADERS | END_STREAM, 3, vec![]), &span)
+            .unwrap();
+
+        assert_eq!(sent(&connection.publish(&event(1))), vec![(DATA, 0, 3)]);
+        let out = connection.publish(&event(2));
+        assert_eq!(sent(&out), vec![(DATA, 0, 1), (DATA, 0, 3)]);
+        // Header of the frame, then the gRPC message with the client first.
+        assert_eq!(&out[9..16], &[0, 0, 0, 0, 32, 0x08, 0x02]);
+
+        // Events which don't fit into the window are missed.
+        let mut settings = 0x4u16.to_be_bytes().to_vec();
+        settings.extend_from_slice(&0u32.to_be_bytes());
+        connection
+            .receive(frame(SETTINGS, 0, 0, settings), &span)
+            .unwrap();
+        assert!(connection.publish(&event(2)).is_empty());
+        assert_eq!(connection.missed, 2);
+
+        let mut closed = sent(&connection.close());
+        closed.sort();
+        assert_eq!(
+            closed,
+            vec![
+                (HEADERS, END_HEADERS | END_STREAM, 1),
+                (HEADERS, END_HEADERS | END_STREAM, 3),
+                (GOAWAY, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn messages_are_sent_as_windows_allow() {
+        let span = log::Span::new("grpc");
+        let mut connection = Connection::new(|_| Ok(Reply::Messages(vec![vec![7; 20]])));
+        let mut settings = 0x4u16.to_be_bytes().to_vec();
+        settings.extend_from_slice(&10u32.to_be_bytes());
+        connection
+            .receive(frame(SETTINGS, 0, 0, settings), &span)
+            .unwrap();
+        let out = connection
+            .receive(frame(HEADERS, END_HEADERS | END_STREAM, 1, vec![]), &span)
+            .unwrap()
+            .unwrap();
+        assert_eq!(sent(&out), vec![(HEADERS, END_HEADERS, 1), (DATA, 0, 1)]);
+        // Headers, then the first 10 of 25 bytes, 5 of them the length prefix.
+        assert_eq!(out.len(), 9 + 20 + 9 + 10);
+
+        let out = connection
+            .receive(
+                frame(WINDOW_UPDATE, 0, 1, 100u32.to_be_bytes().to_vec()),
+                &span,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            sent(&out),
+            vec![(DATA, 0, 1), (HEADERS, END_HEADERS | END_STREAM, 1)]
+        );
+        assert_eq!(&out[..3], &[0, 0, 15]);
+        assert!(connection.calls.is_empty());
+        assert_eq!(
+            sent(
+                &connection
+                    .receive(frame(SETTINGS, ACK, 0, vec![]), &span)
+                    .unwrap()
+                    .unwrap()
+            ),
+            vec![]
+        );
+    }
+}
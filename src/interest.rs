@@ -0,0 +1,220 @@
+//! Interest accrual, enabled with `--interest-rates`: every account earns interest on its
+//! available balance, posted as deposits at the end of every posting period, so savings
+//! products can be simulated entirely inside the engine.
+//!
+//! Like everything else in the engine, days pass with timestamps of messages of the client.
+//! Once the client has seen a timestamp past the end of a UTC day, interest of that day is
+//! accrued on available balance at its end, at the annual rate in effect when the day started,
+//! over 365 days. Accrued interest is posted once a posting period ends, rounded down to four
+//! decimal places, as a deposit with transaction id [`TX`], timestamped with the end of the
+//! period. Periods which are still open when input ends are not posted.
+
+use std::{str::FromStr, sync::OnceLock};
+
+use crate::Message;
+
+/// Transaction id of posted interest.
+pub const TX: u32 = u32::MAX;
+
+const DAY: u64 = 24 * 60 * 60 * 1000;
+
+static INTEREST: OnceLock<Interest> = OnceLock::new();
+
+/// Rates and posting period of interest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interest {
+    pub schedule: Schedule,
+    pub posting: Posting,
+}
+
+/// Annual rates, along with the moment they take effect, in milliseconds since unix epoch.
+///
+/// Written as comma separated `FROM:RATE` entries ordered by `FROM`, e.g.
+/// `0.02,1798761600000:0.025`, where a `RATE` alone is in effect from the start. Rates are
+/// fractions, `0.02` is 2%.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule(Vec<(u64, f64)>);
+
+impl Schedule {
+    /// Rate in effect at `moment`, none before the first entry takes effect.
+    fn rate(&self, moment: u64) -> f64 {
+        self.0
+            .iter()
+            .rev()
+            .find(|(from, _)| *from <= moment)
+            .map_or(0.0, |(_, rate)| *rate)
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rates: Vec<(u64, f64)> = Vec::new();
+        for entry in s.split(',').map(str::trim) {
+            let (from, rate) = match entry.split_once(':') {
+                Some((from, rate)) => (from.trim().parse()?, rate.trim()),
+                None => (0, entry),
+            };
+            let rate: f64 = rate.parse()?;
+            if !rate.is_finite() || rate < 0.0 {
+                return Err(anyhow::anyhow!(
+                    "Interest rate {rate} is not a non-negative rate"
+                ));
+            }
+            if rates.last().is_some_and(|(last, _)| *last >= from) {
+                return Err(anyhow::anyhow!(
+                    "Interest rates must be ordered by the moment they take effect"
+                ));
+            }
+            rates.push((from, rate));
+        }
+        Ok(Schedule(rates))
+    }
+}
+
+/// How often accrued interest is posted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Posting {
+    Day,
+    /// On the first day of every month.
+    #[default]
+    Month,
+}
+
+impl FromStr for Posting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" | "daily" => Ok(Posting::Day),
+            "month" | "monthly" => Ok(Posting::Month),
+            other => Err(anyhow::anyhow!(
+                "Unknown interest posting {other}, expected day or month"
+            )),
+        }
+    }
+}
+
+/// Starts accruing interest in account tasks started from now on. Only the first call has
+/// effect.
+pub fn enable(interest: Interest) {
+    let _ = INTEREST.set(interest);
+}
+
+/// Interest to accrue, `None` when it is off.
+pub fn get() -> Option<Interest> {
+    INTEREST.get().cloned()
+}
+
+/// Interest of a single client.
+#[derive(Debug)]
+pub struct Accrual {
+    interest: Interest,
+    /// Start of the first day which is not accrued yet.
+    day: Option<u64>,
+    /// Kept as `f64`, so small daily amounts add up.
+    accrued: f64,
+}
+
+impl Accrual {
+    /// Nothing accrued yet of `interest`.
+    pub fn new(interest: Interest) -> Self {
+        Accrual {
+            interest,
+            day: None,
+            accrued: 0.0,
+        }
+    }
+
+    /// Accrues interest of every day which ended by `timestamp`, with `available` balance at
+    /// the end of each. Returns interest to post, along with the end of its period, in order.
+    pub fn advance(&mut self, timestamp: Option<u64>, available: f32) -> Vec<(u64, f32)> {
+        let Some(now) = timestamp else {
+            return Vec::new();
+        };
+        let mut day = *self.day.get_or_insert(now - now % DAY);
+        let mut balance = f64::from(available.max(0.0));
+        let mut posted = Vec::new();
+        while day + DAY <= now {
+            self.accrued += balance * self.interest.schedule.rate(day) / 365.0;
+            day += DAY;
+            if self.interest.posting == Posting::Day || day_of_month(day) == 1 {
+                let amount = (self.accrued * 10_000.0).floor() / 10_000.0;
+                if amount > 0.0 {
+                    self.accrued -= amount;
+                    balance += amount;
+                    posted.push((day, amount as f32));
+                }
+            }
+        }
+        self.day = Some(day);
+        posted
+    }
+}
+
+/// Deposit of interest `amount` to `client`, posted at `timestamp`.
+pub fn deposit(client: u16, timestamp: u64, amount: f32) -> Message {
+    Message::Deposit {
+        client,
+        tx: TX,
+        amount,
+        timestamp: Some(timestamp),
+        effective_date: None,
+    }
+}
+
+/// Day of month of `moment`, in UTC, see <https://howardhinnant.github.io/date_algorithms.html>.
+fn day_of_month(moment: u64) -> u64 {
+    let days = moment / DAY + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    day_of_year - (153 * month + 2) / 5 + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{day_of_month, Accrual, Interest, Posting, Schedule, DAY};
+
+    #[test]
+    fn interest_is_accrued_daily_and_posted_per_period() {
+        // 2026-01-30T00:00:00Z
+        let start = 1_769_731_200_000;
+        assert_eq!(day_of_month(start), 30);
+        assert_eq!(day_of_month(start + 2 * DAY), 1);
+
+        let schedule: Schedule = "0.365,1769817600000:0.73".parse().unwrap();
+        let mut accrual = Accrual::new(Interest {
+            schedule,
+            posting: Posting::Month,
+        });
+        assert_eq!(accrual.advance(Some(start + 1000), 100.0), vec![]);
+        // 0.1 on the 30th, 0.2 on the 31st, posted on the 1st of February.
+        assert_eq!(
+            accrual.advance(Some(start + 2 * DAY + 5), 100.0),
+            vec![(start + 2 * DAY, 0.3)]
+        );
+        // Nothing is posted before the next period ends.
+        assert_eq!(accrual.advance(Some(start + 4 * DAY), 100.3), vec![]);
+
+        let mut daily = Accrual::new(Interest {
+            schedule: "0.365".parse().unwrap(),
+            posting: Posting::Day,
+        });
+        assert_eq!(daily.advance(None, 100.0), vec![]);
+        assert_eq!(daily.advance(Some(start), 100.0), vec![]);
+        // Posted interest earns interest too.
+        assert_eq!(
+            daily.advance(Some(start + 2 * DAY), 100.0),
+            vec![(start + DAY, 0.1), (start + 2 * DAY, 0.1001)]
+        );
+
+        assert!("0.02,5:0.03,4:0.01".parse::<Schedule>().is_err());
+        assert!("-0.02".parse::<Schedule>().is_err());
+        assert!("weekly".parse::<Posting>().is_err());
+    }
+}
@@ -0,0 +1,65 @@
+//! Internal consistency checks of the engine, asserted only when built with the
+//! `debug-invariants` feature. They are meant to catch refactors which break the engine in
+//! ways its output does not show right away, such as a message silently going missing:
+//!
+//! `cargo test --features debug-invariants`
+//!
+//! Without the feature, checks are compiled but never evaluated.
+
+/// Prefix of the panic message of a violated invariant, which lets supervision tell it from
+/// panics it should recover from.
+pub const VIOLATED: &str = "Invariant violated";
+
+/// Tolerance of balance checks, `f32` sums drift from exact ones.
+pub const TOLERANCE: f32 = 0.01;
+
+/// Panics with a message starting with [`VIOLATED`] when `cond` does not hold, if
+/// `debug-invariants` feature is enabled.
+macro_rules! invariant {
+    ($cond:expr, $($arg:tt)+) => {
+        if cfg!(feature = "debug-invariants") {
+            assert!($cond, "{}: {}", $crate::invariants::VIOLATED, format_args!($($arg)+));
+        }
+    };
+}
+
+pub(crate) use invariant;
+
+/// Returns `true` if `panic` payload comes from a violated invariant.
+pub fn is_violation(panic: &(dyn std::any::Any + Send)) -> bool {
+    let message = panic
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| panic.downcast_ref::<&str>().copied());
+    message.is_some_and(|message| message.starts_with(VIOLATED))
+}
+
+/// Counts messages received by a stage, and those it is done with. Each path a message can
+/// take through the stage settles it on its own, so a path which loses messages shows up as
+/// a difference once the stage is finished.
+#[derive(Debug, Default)]
+pub struct Ledger {
+    received: u64,
+    settled: u64,
+}
+
+impl Ledger {
+    pub fn received(&mut self) {
+        self.received += 1;
+    }
+
+    pub fn settled(&mut self) {
+        self.settled += 1;
+    }
+
+    /// Checks that every received message was settled exactly once, `stage` names the
+    /// stage in the panic message.
+    pub fn close(&self, stage: impl std::fmt::Display) {
+        invariant!(
+            self.received == self.settled,
+            "{stage} received {} messages but settled {}",
+            self.received,
+            self.settled
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! Detection of account channels which stay close to capacity. A client whose task can't
+//! keep up makes router block on send, which stalls every other client as well.
+//!
+//! Once detected, the router switches the task of the client into batched apply mode, see
+//! [`Batching`]: the task drains its whole channel at a time, applies the messages in order,
+//! and updates metrics, held funds, Redis and Flight once per batch rather than once per
+//! message. The task leaves the mode by itself once it caught up.
+
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Share of channel capacity above which a channel is considered saturated.
+const SATURATION: f32 = 0.9;
+/// How long a channel has to stay saturated before it's reported.
+const SUSTAINED: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+struct Saturated {
+    since: Instant,
+    reported: bool,
+}
+
+#[derive(Debug)]
+pub struct LagDetector {
+    threshold: usize,
+    sustained: Duration,
+    saturated: HashMap<u16, Saturated>,
+}
+
+impl LagDetector {
+    pub fn new(capacity: usize) -> Self {
+        Self::with_limits(capacity, SATURATION, SUSTAINED)
+    }
+
+    fn with_limits(capacity: usize, saturation: f32, sustained: Duration) -> Self {
+        LagDetector {
+            threshold: ((capacity as f32 * saturation).ceil() as usize).max(1),
+            sustained,
+            saturated: HashMap::new(),
+        }
+    }
+
+    /// Records channel `depth` of `client` observed at `now`. Returns for how long the channel
+    /// has been saturated, the first time it exceeds the sustained period. Each episode of
+    /// saturation is reported once.
+    pub fn observe(&mut self, client: u16, depth: usize, now: Instant) -> Option<Duration> {
+        if depth < self.threshold {
+            self.saturated.remove(&client);
+            return None;
+        }
+
+        let state = self.saturated.entry(client).or_insert(Saturated {
+            since: now,
+            reported: false,
+        });
+        let lag = now.duration_since(state.since);
+        if state.reported || lag < self.sustained {
+            return None;
+        }
+
+        state.reported = true;
+        Some(lag)
+    }
+}
+
+/// Batched apply mode of an account task, shared by the router and the task.
+#[derive(Debug, Default)]
+pub struct Batching(AtomicBool);
+
+impl Batching {
+    /// Switches the task into batched mode, from its next message on.
+    pub fn start(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Switches the task back to applying messages one at a time.
+    pub fn stop(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    pub fn active(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LagDetector;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn sustained_saturation_is_reported_once() {
+        let mut detector = LagDetector::with_limits(10, 0.9, Duration::from_secs(5));
+        let start = Instant::now();
+
+        assert_eq!(detector.observe(1, 9, start), None);
+        assert_eq!(
+            detector.observe(1, 10, start + Duration::from_secs(3)),
+            None
+        );
+        assert_eq!(
+            detector.observe(1, 9, start + Duration::from_secs(6)),
+            Some(Duration::from_secs(6))
+        );
+        assert_eq!(detector.observe(1, 9, start + Duration::from_secs(7)), None);
+    }
+
+    #[test]
+    fn draining_resets_episode() {
+        let mut detector = LagDetector::with_limits(10, 0.9, Duration::from_secs(5));
+        let start = Instant::now();
+
+        assert_eq!(detector.observe(1, 9, start), None);
+        assert_eq!(detector.observe(1, 2, start + Duration::from_secs(4)), None);
+        assert_eq!(detector.observe(1, 9, start + Duration::from_secs(6)), None);
+        assert_eq!(
+            detector.observe(1, 9, start + Duration::from_secs(11)),
+            Some(Duration::from_secs(5))
+        );
+    }
+}
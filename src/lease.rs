@@ -0,0 +1,129 @@
+//! Lease of ingestion shared by a `trp serve` and a hot standby, `trp replica --lease`, so
+//! that the standby takes over ingestion once the primary is gone, see
+//! [`replica`](crate::commands::replica).
+//!
+//! The lease is a file holding who holds it and when they last renewed it, in milliseconds
+//! since unix epoch, as `<renewed> <holder>`. Its holder renews it every
+//! [`FLUSH`](crate::follow::FLUSH), and releases it by deleting the file once the run is over.
+//! A lease which was not renewed for its time to live, or was released, may be acquired by
+//! anyone else. Unlike the rest of the engine, it goes by the wall clock, so primary and standby
+//! need clocks which agree to within a fraction of the time to live.
+//!
+//! The file is replaced as a whole when it's written, but nothing keeps two standbys from
+//! acquiring it at the same moment: a primary should have a single standby.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Default time to live of a lease.
+pub const TTL: Duration = Duration::from_secs(5);
+
+/// Lease held, or to be held, by `holder`.
+#[derive(Debug, Clone)]
+pub struct Lease {
+    path: PathBuf,
+    holder: String,
+    ttl: Duration,
+}
+
+impl Lease {
+    /// Lease kept in `path` on behalf of this process, serving on `addr`.
+    pub fn new(path: &Path, addr: &str, ttl: Duration) -> Self {
+        Lease {
+            path: path.to_path_buf(),
+            holder: format!("{addr}/{}", std::process::id()),
+            ttl,
+        }
+    }
+
+    /// Holder of the lease other than this process, along with how long ago they renewed it,
+    /// while it's live.
+    pub fn held(&self) -> Result<Option<(String, Duration)>, anyhow::Error> {
+        let contents = match std::fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(anyhow::anyhow!(
+                    "Failed to read lease {}: {err}",
+                    self.path.display()
+                ))
+            }
+        };
+        let (renewed, holder) = contents
+            .trim_end()
+            .split_once(' ')
+            .ok_or_else(|| anyhow::anyhow!("Invalid lease {}", self.path.display()))?;
+        let age = Duration::from_millis(now().saturating_sub(renewed.parse()?));
+        Ok((holder != self.holder && age < self.ttl).then(|| (holder.to_string(), age)))
+    }
+
+    /// Acquires the lease unless someone else holds it, returns whether it did.
+    pub fn acquire(&self) -> Result<bool, anyhow::Error> {
+        if self.held()?.is_some() {
+            return Ok(false);
+        }
+        self.renew()?;
+        Ok(true)
+    }
+
+    /// Marks the lease as held by this process as of now.
+    pub fn renew(&self) -> Result<(), anyhow::Error> {
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, format!("{} {}\n", now(), self.holder))?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    /// Releases the lease, if this process holds it.
+    pub fn release(&self) -> Result<(), anyhow::Error> {
+        let contents = std::fs::read_to_string(&self.path).unwrap_or_default();
+        if contents
+            .trim_end()
+            .split_once(' ')
+            .map(|(_, holder)| holder)
+            == Some(&self.holder)
+        {
+            std::fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Lease;
+    use std::time::Duration;
+
+    #[test]
+    fn lease_is_acquired_once_released_or_expired() {
+        let path = std::env::temp_dir().join(format!("trp-lease-{}", std::process::id()));
+        let primary = Lease::new(&path, "primary:7878", Duration::from_secs(60));
+        let mut standby = Lease::new(&path, "standby:7878", Duration::from_secs(60));
+
+        assert!(primary.acquire().unwrap());
+        assert!(primary.acquire().unwrap());
+        assert_eq!(standby.held().unwrap().unwrap().0, primary.holder);
+        assert!(!standby.acquire().unwrap());
+        // Only its holder releases a lease.
+        standby.release().unwrap();
+        assert!(!standby.acquire().unwrap());
+        primary.release().unwrap();
+        assert!(standby.acquire().unwrap());
+        assert!(!primary.acquire().unwrap());
+
+        standby.ttl = Duration::ZERO;
+        primary.renew().unwrap();
+        assert!(standby.acquire().unwrap());
+        standby.release().unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,71 @@
+//! Toy transaction engine, split from the `trp` binary so the ingest path can be exercised
+//! by fuzz targets in `fuzz/`, and the [`engine`] can be embedded.
+
+use crate::message::Message;
+
+mod alerts;
+pub mod backfill;
+mod chaos;
+pub mod cli;
+pub mod commands;
+pub mod config;
+mod creation;
+mod dashboard;
+mod dlq;
+mod dormant;
+pub mod engine;
+mod event_log;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod flight;
+mod follow;
+pub mod format;
+mod grpc;
+mod interest;
+mod invariants;
+mod lag;
+mod lease;
+pub mod log;
+mod memory;
+pub mod message;
+mod metrics;
+pub mod ordering;
+mod orphans;
+#[cfg(feature = "otel")]
+mod otel;
+mod outbox;
+mod pacing;
+mod parallel;
+pub mod parse_errors;
+pub mod parser;
+pub mod pipeline;
+mod processor;
+mod progress;
+mod protocol;
+pub mod provenance;
+mod readahead;
+mod redis;
+mod reference;
+mod reorder;
+mod report;
+mod reserve;
+mod retention;
+mod rng;
+mod sample;
+pub mod schema;
+pub mod screening;
+mod send_errors;
+mod settlement;
+mod signature;
+#[cfg(test)]
+mod sim;
+mod snapshots;
+pub mod state;
+mod tiers;
+mod top;
+mod two_pass;
+mod velocity;
+mod writer;
+
+#[doc(hidden)]
+pub use processor::apply_deposits;
@@ -0,0 +1,452 @@
+//! Structured, stage-scoped logging in the spirit of `tracing`.
+//!
+//! Every event is emitted within a [`Span`] naming the pipeline stage it belongs to
+//! (`parse`, `route`, `apply`, `write`) and carrying long-lived fields such as the client id.
+//! Events add their own fields (`tx`, `kind`, ...) on top of that.
+//!
+//! Which events are printed is controlled by the `TRP_LOG` environment variable, using
+//! `env-filter`-like directives: a default level optionally followed by per-stage overrides,
+//! e.g. `TRP_LOG=warn,apply=debug`.
+//!
+//! Events are written as human-readable lines, or as one JSON object per line when
+//! [`Format::Json`] is selected, for consumption by log pipelines.
+//!
+//! Logs may be shipped somewhere less trusted than the primary output. With [`Redact`] set,
+//! `client` and `tx` fields are hashed or truncated, as are ids written to side outputs with
+//! [`id`].
+
+use std::{
+    fmt::{self, Display},
+    io::Write,
+    str::FromStr,
+    sync::OnceLock,
+};
+
+const FILTER_ENV: &str = "TRP_LOG";
+const DEFAULT_LEVEL: Level = Level::Warn;
+/// Keys of fields holding identifiers.
+const IDS: [&str; 2] = ["client", "tx"];
+
+static FILTER: OnceLock<Filter> = OnceLock::new();
+static FORMAT: OnceLock<Format> = OnceLock::new();
+static REDACT: OnceLock<(Redact, [u8; 16])> = OnceLock::new();
+
+/// Output format of log events.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            other => Err(anyhow::anyhow!("Unknown log format: {other}")),
+        }
+    }
+}
+
+/// How identifiers are written to logs and side outputs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Redact {
+    /// As they are.
+    #[default]
+    Off,
+    /// As a hash salted per run: the same id hashes the same throughout a run, but hashes
+    /// can't be matched across runs, nor turned back into ids.
+    Hash,
+    /// Only the last two digits.
+    Truncate,
+}
+
+impl Redact {
+    /// Redacted `value` of identifier `key`.
+    fn apply(self, salt: &[u8], key: &str, value: &str) -> String {
+        match self {
+            Redact::Off => value.to_string(),
+            Redact::Hash => {
+                let digest = crate::signature::hmac(salt, format!("{key}={value}").as_bytes());
+                digest[..4]
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect()
+            }
+            Redact::Truncate => {
+                let start = value.len().saturating_sub(2);
+                format!("*{}", &value[start..])
+            }
+        }
+    }
+}
+
+impl FromStr for Redact {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Redact::Off),
+            "hash" => Ok(Redact::Hash),
+            "truncate" => Ok(Redact::Truncate),
+            other => Err(anyhow::anyhow!("Unknown redaction: {other}")),
+        }
+    }
+}
+
+/// Redacts identifiers from now on. Only the first call has effect.
+pub fn redact(redact: Redact) {
+    let mut salt = [0; 16];
+    let mut rng = crate::rng::Rng::new(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64
+            ^ u64::from(std::process::id()),
+    );
+    for chunk in salt.chunks_exact_mut(8) {
+        chunk.copy_from_slice(&rng.next().to_le_bytes());
+    }
+    let _ = REDACT.set((redact, salt));
+}
+
+/// Identifier `key` as it should be written outside of primary output.
+pub fn id(key: &str, value: impl Display) -> String {
+    let value = value.to_string();
+    match REDACT.get() {
+        Some((redact, salt)) => redact.apply(salt, key, &value),
+        None => value,
+    }
+}
+
+/// `value` of field `key`, redacted when it is an identifier.
+fn field(key: &str, value: &dyn Display) -> String {
+    if IDS.contains(&key) {
+        id(key, value)
+    } else {
+        value.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            other => Err(anyhow::anyhow!("Unknown log level: {other}")),
+        }
+    }
+}
+
+/// Parsed filter directives. `None` as a level means the stage is switched off.
+#[derive(Debug)]
+pub struct Filter {
+    default: Option<Level>,
+    stages: Vec<(String, Option<Level>)>,
+}
+
+impl Filter {
+    fn parse_level(s: &str) -> Result<Option<Level>, anyhow::Error> {
+        if s.eq_ignore_ascii_case("off") {
+            Ok(None)
+        } else {
+            s.parse().map(Some)
+        }
+    }
+
+    pub fn parse(spec: &str) -> Result<Self, anyhow::Error> {
+        let mut filter = Filter {
+            default: Some(DEFAULT_LEVEL),
+            stages: Vec::new(),
+        };
+
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((stage, level)) => filter
+                    .stages
+                    .push((stage.trim().to_owned(), Self::parse_level(level.trim())?)),
+                None => filter.default = Self::parse_level(directive)?,
+            }
+        }
+
+        Ok(filter)
+    }
+
+    fn enabled(&self, stage: &str, level: Level) -> bool {
+        let max = self
+            .stages
+            .iter()
+            .rev()
+            .find(|(name, _)| name == stage)
+            .map_or(self.default, |(_, level)| *level);
+
+        max.is_some_and(|max| level <= max)
+    }
+}
+
+/// Installs the filter from `TRP_LOG` and selects output format. Falls back to the default
+/// level when the variable is absent or malformed. When given, `level` replaces the default
+/// level, while per-stage directives from `TRP_LOG` still apply. Events emitted before `init`
+/// use the default filter and format.
+pub fn init(format: Format, level: Option<Level>) {
+    let _ = FORMAT.set(format);
+
+    let mut filter = match std::env::var(FILTER_ENV) {
+        Ok(spec) => Filter::parse(&spec).unwrap_or_else(|err| {
+            eprintln!("Ignoring {FILTER_ENV}={spec:?}: {err}");
+            Filter::parse("").expect("Empty filter is valid")
+        }),
+        Err(_) => Filter::parse("").expect("Empty filter is valid"),
+    };
+    if let Some(level) = level {
+        filter.default = Some(level);
+    }
+
+    let _ = FILTER.set(filter);
+}
+
+fn filter() -> &'static Filter {
+    FILTER.get_or_init(|| Filter::parse("").expect("Empty filter is valid"))
+}
+
+/// Named pipeline stage with fields shared by every event emitted within it.
+#[derive(Debug, Clone)]
+pub struct Span {
+    name: &'static str,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Span {
+    pub fn new(name: &'static str) -> Self {
+        Span {
+            name,
+            fields: Vec::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn with(mut self, key: &'static str, value: impl Display) -> Self {
+        self.fields.push((key, value.to_string()));
+        self
+    }
+
+    pub fn enabled(&self, level: Level) -> bool {
+        filter().enabled(self.name, level)
+    }
+
+    /// Writes a single event to stderr. Prefer the level macros, which skip formatting
+    /// entirely for disabled events.
+    pub fn emit(&self, level: Level, fields: &[(&str, &dyn Display)], message: fmt::Arguments) {
+        let line = match FORMAT.get().copied().unwrap_or_default() {
+            Format::Text => self.text(level, fields, message),
+            Format::Json => self.json(level, fields, message),
+        };
+
+        let _ = writeln!(std::io::stderr().lock(), "{line}");
+    }
+
+    fn text(
+        &self,
+        level: Level,
+        fields: &[(&str, &dyn Display)],
+        message: fmt::Arguments,
+    ) -> String {
+        let mut line = format!("{:>5} {}", level.as_str(), self.name);
+        if !self.fields.is_empty() {
+            line.push('{');
+            for (i, (key, value)) in self.fields.iter().enumerate() {
+                if i > 0 {
+                    line.push(' ');
+                }
+                line.push_str(&format!("{key}={}", field(key, value)));
+            }
+            line.push('}');
+        }
+        line.push_str(&format!(": {message}"));
+        for (key, value) in fields {
+            line.push_str(&format!(" {key}={}", field(key, value)));
+        }
+
+        line
+    }
+
+    /// Span and event fields are flattened into the top-level object, next to `level`,
+    /// `stage` and `event`. All values are written as strings.
+    fn json(
+        &self,
+        level: Level,
+        fields: &[(&str, &dyn Display)],
+        message: fmt::Arguments,
+    ) -> String {
+        let mut line = format!(
+            "{{\"level\":\"{}\",\"stage\":\"{}\",\"event\":{}",
+            level.as_str(),
+            self.name,
+            json_string(&message.to_string())
+        );
+        let span_fields = self
+            .fields
+            .iter()
+            .map(|(key, value)| (*key, value as &dyn Display));
+        for (key, value) in span_fields.chain(fields.iter().copied()) {
+            line.push_str(&format!(
+                ",{}:{}",
+                json_string(key),
+                json_string(&field(key, value))
+            ));
+        }
+        line.push('}');
+
+        line
+    }
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// `event!(level, span, key = value, ...; "format", args...)`
+macro_rules! event {
+    ($level:expr, $span:expr, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {{
+        let span: &$crate::log::Span = &$span;
+        if span.enabled($level) {
+            span.emit(
+                $level,
+                &[$((stringify!($key), &$value as &dyn std::fmt::Display)),+],
+                format_args!($($arg)+),
+            );
+        }
+    }};
+    ($level:expr, $span:expr, $($arg:tt)+) => {{
+        let span: &$crate::log::Span = &$span;
+        if span.enabled($level) {
+            span.emit($level, &[], format_args!($($arg)+));
+        }
+    }};
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log::event!($crate::log::Level::Error, $($arg)+) };
+}
+
+// Named with a trailing underscore, `warn` on its own clashes with the builtin lint attribute.
+macro_rules! warn_ {
+    ($($arg:tt)+) => { $crate::log::event!($crate::log::Level::Warn, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log::event!($crate::log::Level::Info, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log::event!($crate::log::Level::Debug, $($arg)+) };
+}
+
+pub(crate) use {debug, error, event, info, warn_ as warn};
+
+#[cfg(test)]
+mod tests {
+    use super::{json_string, Filter, Level, Redact, Span};
+
+    #[test]
+    fn default_filter_is_warn() {
+        let filter = Filter::parse("").unwrap();
+        assert!(filter.enabled("apply", Level::Warn));
+        assert!(!filter.enabled("apply", Level::Info));
+    }
+
+    #[test]
+    fn stage_directives_override_default() {
+        let filter = Filter::parse("error, apply=debug, parse=off").unwrap();
+        assert!(filter.enabled("apply", Level::Debug));
+        assert!(!filter.enabled("route", Level::Warn));
+        assert!(filter.enabled("route", Level::Error));
+        assert!(!filter.enabled("parse", Level::Error));
+    }
+
+    #[test]
+    fn invalid_level_is_rejected() {
+        assert!(Filter::parse("apply=loud").is_err());
+    }
+
+    #[test]
+    fn json_event_contains_span_and_event_fields() {
+        let span = Span::new("apply").with("client", 7);
+        let line = span.json(
+            Level::Warn,
+            &[("tx", &12), ("reason", &"PE_ACCLCK")],
+            format_args!("Failed to apply \"message\""),
+        );
+
+        assert_eq!(
+            line,
+            r#"{"level":"WARN","stage":"apply","event":"Failed to apply \"message\"","client":"7","tx":"12","reason":"PE_ACCLCK"}"#
+        );
+    }
+
+    #[test]
+    fn identifiers_are_redacted() {
+        let salt = [1; 16];
+        let hash = |key, value| Redact::Hash.apply(&salt, key, value);
+        assert_eq!(hash("client", "7"), hash("client", "7"));
+        assert_ne!(hash("client", "7"), hash("client", "8"));
+        assert_ne!(hash("client", "7"), hash("tx", "7"));
+        assert_ne!(
+            hash("client", "7"),
+            Redact::Hash.apply(&[2; 16], "client", "7")
+        );
+        assert_eq!(hash("client", "7").len(), 8);
+
+        assert_eq!(Redact::Truncate.apply(&salt, "tx", "123456"), "*56");
+        assert_eq!(Redact::Truncate.apply(&salt, "client", "7"), "*7");
+        assert_eq!(Redact::Off.apply(&salt, "client", "7"), "7");
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("a\nb\\c\u{1}"), r#""a\nb\\c\u0001""#);
+    }
+}
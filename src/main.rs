@@ -1,38 +1,36 @@
-use crate::message::Message;
-use std::thread;
-
-const RESULT_CHAN_SIZE: usize = 100;
-
-mod message;
-mod parser;
-mod processor;
+use trp::{
+    cli::{self, Command},
+    commands, config, format, log, schema,
+};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let fname = std::env::args()
-        .nth(1)
-        .expect("Must provide input file to read");
-
-    let rx = parser::start(fname)?;
-    let (done_tx, mut done_rx) = tokio::sync::mpsc::channel(RESULT_CHAN_SIZE);
-
-    let writer_handle = thread::spawn(move || {
-        let mut out = csv::Writer::from_writer(std::io::stdout());
-
-        while let Some(account) = done_rx.blocking_recv() {
-            out.serialize(account)?;
-        }
-
-        Ok::<(), csv::Error>(())
-    });
-
-    let rt = tokio::runtime::Runtime::new()?;
-    rt.block_on(async move {
-        processor::start(rx, done_tx).await;
-    });
-
-    writer_handle
-        .join()
-        .map_err(|err| anyhow::anyhow!("Writer panic: {err:?}"))??;
+    let cli = cli::Cli::parse()?;
+    log::init(cli.global.log_format, cli.global.log_level());
+    log::redact(cli.global.redact);
+    config::set_engine(cli.global.engine)?;
+    format::set_mapping(cli.global.mapping.clone());
+    schema::set(cli.global.schema.clone());
+
+    match cli.command {
+        Command::Process(args) => commands::process::run(&cli.global, args)?,
+        Command::Serve(args) => commands::serve::run(&cli.global, args)?,
+        Command::Replica(args) => commands::replica::run(&cli.global, args)?,
+        Command::Merge(args) => commands::merge::run(args)?,
+        Command::Diff(args) => commands::diff::run(&cli.global, args)?,
+        Command::Query(args) => commands::query::run(args)?,
+        Command::Rollback(args) => commands::rollback::run(args)?,
+        Command::Compact(args) => commands::compact::run(&cli.global, args)?,
+        Command::State(args) => commands::state::run(&cli.global, args)?,
+        Command::Corrections(args) => commands::corrections::run(&cli.global, args)?,
+        Command::Convert(args) => commands::convert::run(args)?,
+        Command::Replay(args) => commands::replay::run(&cli.global, args)?,
+        Command::Statement(args) => commands::statement::run(args)?,
+        Command::Validate(args) => commands::validate::run(args)?,
+        Command::Inspect(args) => commands::inspect::run(&cli.global, args)?,
+        Command::Generate(args) => commands::generate::run(args)?,
+        Command::Bench(args) => commands::bench::run(&cli.global, args)?,
+        Command::Help(usage) => print!("{usage}"),
+    }
 
     Ok(())
 }
@@ -0,0 +1,148 @@
+//! Approximate accounting of memory held by the engine, exposed as `trp_memory_bytes` in
+//! [`metrics`](crate::metrics), and limited with `--max-memory <SIZE>`.
+//!
+//! Account tasks track the size of their account and of its transaction history as it grows,
+//! and the router the size of its registry of transaction ids, see [`track`]. Sizes are
+//! estimates from capacity of maps and size of their entries, so they leave out the allocator,
+//! buffers of channels and whatever else the process holds, and actual resident memory is
+//! higher by a margin.
+//!
+//! Once the estimate goes over the limit, the router of `trp serve` with `--evict-after`
+//! spills the least recently active half of resident accounts to disk, as it does with idle
+//! ones, see [`dormant`](crate::dormant). Otherwise, or when there is nothing left to spill,
+//! reading input stops, accounts applied so far are written and the run fails with a clear
+//! error, rather than being killed by the OOM killer halfway through.
+
+use std::{
+    mem::size_of,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+
+/// Estimated overhead of a running account task, aside from its account: the task, its inbox
+/// and its entry in the router.
+pub const TASK: u64 = 1024;
+
+const UNITS: [(&str, u64); 7] = [
+    ("KiB", 1 << 10),
+    ("MiB", 1 << 20),
+    ("GiB", 1 << 30),
+    ("K", 1 << 10),
+    ("M", 1 << 20),
+    ("G", 1 << 30),
+    ("B", 1),
+];
+
+static USED: AtomicU64 = AtomicU64::new(0);
+static PEAK: AtomicU64 = AtomicU64::new(0);
+static LIMIT: OnceLock<u64> = OnceLock::new();
+static EXCEEDED: OnceLock<String> = OnceLock::new();
+
+/// Parses size of `--max-memory`, in bytes or with a unit such as `512MiB` or `2G`.
+pub fn size(text: &str) -> Result<u64, anyhow::Error> {
+    let (number, unit) = UNITS
+        .iter()
+        .find_map(|(suffix, unit)| Some((text.strip_suffix(suffix)?, *unit)))
+        .unwrap_or((text, 1));
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .filter(|size| *size > 0)
+        .ok_or_else(|| anyhow::anyhow!("Invalid size {text}, expected bytes or such as 512MiB"))
+}
+
+/// Limits memory to `limit` bytes from now on. Only the first call has effect.
+pub fn enable(limit: u64) {
+    let _ = LIMIT.set(limit);
+}
+
+/// Estimated size of a map or set with `capacity` for entries of `T`, along with the control
+/// byte every bucket has.
+pub fn table<T>(capacity: usize) -> u64 {
+    (capacity * (size_of::<T>() + 1)) as u64
+}
+
+/// Accounts for something which took `before` bytes taking `after` now, returns `after`.
+pub fn track(before: u64, after: u64) -> u64 {
+    if after > before {
+        let used = USED.fetch_add(after - before, Ordering::Relaxed) + after - before;
+        PEAK.fetch_max(used, Ordering::Relaxed);
+    } else {
+        USED.fetch_sub(before - after, Ordering::Relaxed);
+    }
+    after
+}
+
+/// Estimated bytes in use.
+pub fn used() -> u64 {
+    USED.load(Ordering::Relaxed)
+}
+
+/// Highest estimate of bytes in use so far.
+pub fn peak() -> u64 {
+    PEAK.load(Ordering::Relaxed)
+}
+
+/// Whether the estimate is over `--max-memory`, never unless it was [`enable`]d.
+pub fn over() -> bool {
+    LIMIT.get().is_some_and(|limit| used() > *limit)
+}
+
+/// Stops reading input, as memory went over the limit with nothing left to spill. Returns
+/// `true` for the first call only.
+pub fn exceed() -> bool {
+    let limit = LIMIT.get().copied().unwrap_or_default();
+    EXCEEDED
+        .set(format!(
+            "Stopped reading input as estimated memory use of {} went over --max-memory {}",
+            human(used()),
+            human(limit)
+        ))
+        .is_ok()
+}
+
+/// Returns `true` once reading was stopped, so that sources stop too.
+pub fn exceeded() -> bool {
+    EXCEEDED.get().is_some()
+}
+
+/// Fails when reading was stopped, so that the process exits with non-zero code.
+pub fn check() -> Result<(), anyhow::Error> {
+    match EXCEEDED.get() {
+        Some(reason) => Err(anyhow::anyhow!("{reason}")),
+        None => Ok(()),
+    }
+}
+
+/// `bytes` in the largest unit they make at least one of, for people to read.
+pub fn human(bytes: u64) -> String {
+    let (unit, size) = [("GiB", 1 << 30), ("MiB", 1 << 20), ("KiB", 1 << 10)]
+        .into_iter()
+        .find(|(_, size)| bytes >= *size)
+        .unwrap_or(("KiB", 1 << 10));
+    format!("{:.1} {unit}", bytes as f64 / size as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{human, size, table};
+
+    #[test]
+    fn sizes_are_parsed() {
+        assert_eq!(size("1024").unwrap(), 1024);
+        assert_eq!(size("512MiB").unwrap(), 512 << 20);
+        assert_eq!(size("2G").unwrap(), 2 << 30);
+        assert_eq!(size("64 KiB").unwrap(), 64 << 10);
+        assert!(size("0").is_err());
+        assert!(size("lots").is_err());
+        assert!(size("1TiB").is_err());
+
+        assert_eq!(table::<(u32, u16)>(16), 16 * 9);
+        assert_eq!(human(64 << 10), "64.0 KiB");
+        assert_eq!(human(3 << 29), "1.5 GiB");
+    }
+}
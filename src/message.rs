@@ -6,13 +6,45 @@
 ///
 /// [Internally-tagged enums]: https://serde.rs/enum-representations.html#internally-tagged
 /// [can't]: https://github.com/BurntSushi/rust-csv/issues/211
+///
+/// Every message may carry the `timestamp` of its record, see [`Message::timestamp`].
+/// Deposits may also be value-dated, see [`Message::effective_date`].
 #[derive(Debug)]
 pub enum Message {
-    Deposit { client: u16, tx: u32, amount: f32 },
-    Withdraw { client: u16, tx: u32, amount: f32 },
-    Dispute { client: u16, tx: u32 },
-    Resolve { client: u16, tx: u32 },
-    Chargeback { client: u16, tx: u32 },
+    Deposit {
+        client: u16,
+        tx: u32,
+        amount: f32,
+        timestamp: Option<u64>,
+        effective_date: Option<u64>,
+    },
+    Withdraw {
+        client: u16,
+        tx: u32,
+        amount: f32,
+        timestamp: Option<u64>,
+    },
+    Dispute {
+        client: u16,
+        tx: u32,
+        timestamp: Option<u64>,
+    },
+    Resolve {
+        client: u16,
+        tx: u32,
+        timestamp: Option<u64>,
+    },
+    Chargeback {
+        client: u16,
+        tx: u32,
+        timestamp: Option<u64>,
+    },
+    /// Settles a pending withdrawal, see [`settlement`](crate::settlement).
+    Settle {
+        client: u16,
+        tx: u32,
+        timestamp: Option<u64>,
+    },
 }
 
 impl Message {
@@ -23,10 +55,10 @@ impl Message {
             Message::Dispute { client, .. } => *client,
             Message::Resolve { client, .. } => *client,
             Message::Chargeback { client, .. } => *client,
+            Message::Settle { client, .. } => *client,
         }
     }
 
-    #[allow(dead_code)]
     pub fn transaction_id(&self) -> u32 {
         match self {
             Message::Deposit { tx, .. } => *tx,
@@ -34,6 +66,50 @@ impl Message {
             Message::Dispute { tx, .. } => *tx,
             Message::Resolve { tx, .. } => *tx,
             Message::Chargeback { tx, .. } => *tx,
+            Message::Settle { tx, .. } => *tx,
+        }
+    }
+
+    /// Milliseconds since unix epoch from the `timestamp` column of the input, `None` when
+    /// the input has none.
+    pub fn timestamp(&self) -> Option<u64> {
+        match self {
+            Message::Deposit { timestamp, .. }
+            | Message::Withdraw { timestamp, .. }
+            | Message::Dispute { timestamp, .. }
+            | Message::Resolve { timestamp, .. }
+            | Message::Chargeback { timestamp, .. }
+            | Message::Settle { timestamp, .. } => *timestamp,
+        }
+    }
+
+    /// Milliseconds since unix epoch from the `effective_date` column of the input, when funds
+    /// of a deposit become available. `None` for deposits available right away, and for
+    /// other messages.
+    pub fn effective_date(&self) -> Option<u64> {
+        match self {
+            Message::Deposit { effective_date, .. } => *effective_date,
+            _ => None,
+        }
+    }
+
+    /// Amount of deposits and withdrawals, `None` for messages referencing a transaction.
+    pub fn amount(&self) -> Option<f32> {
+        match self {
+            Message::Deposit { amount, .. } | Message::Withdraw { amount, .. } => Some(*amount),
+            _ => None,
+        }
+    }
+
+    /// Name of the transaction type, as it appears in the `type` column of the input.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::Deposit { .. } => "deposit",
+            Message::Withdraw { .. } => "withdrawal",
+            Message::Dispute { .. } => "dispute",
+            Message::Resolve { .. } => "resolve",
+            Message::Chargeback { .. } => "chargeback",
+            Message::Settle { .. } => "settle",
         }
     }
 
@@ -44,4 +120,20 @@ impl Message {
     pub fn is_deposit(&self) -> bool {
         matches!(self, Self::Deposit { .. })
     }
+
+    /// Returns `true` if the message is [`Dispute`].
+    ///
+    /// [`Dispute`]: Message::Dispute
+    #[must_use]
+    pub fn is_dispute(&self) -> bool {
+        matches!(self, Self::Dispute { .. })
+    }
+
+    /// Returns `true` if the message is [`Settle`].
+    ///
+    /// [`Settle`]: Message::Settle
+    #[must_use]
+    pub fn is_settle(&self) -> bool {
+        matches!(self, Self::Settle { .. })
+    }
 }
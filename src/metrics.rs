@@ -0,0 +1,828 @@
+//! Process-wide counters, gauges and histograms, rendered in the Prometheus text exposition
+//! format. Metrics are either scraped from [`serve`] while the run is in progress, or
+//! written once to a textfile via [`write_textfile`] when a batch run is over.
+//! The same endpoint reports input progress on `/health`.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Write as _},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{log, memory, ordering, progress, Message};
+
+/// Upper bounds of latency buckets, in microseconds.
+const LATENCY_BUCKETS_US: [u64; 13] = [
+    1, 5, 10, 50, 100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000,
+];
+
+/// Steps a message goes through, timed separately.
+#[derive(Debug, Clone, Copy)]
+pub enum Stage {
+    /// Reading and deserializing a row into a message.
+    Parse,
+    /// Finding or spawning account task for a message.
+    Route,
+    /// Waiting to be sent to account task and sitting in its channel.
+    Queue,
+    /// Applying message to account state.
+    Apply,
+}
+
+impl Stage {
+    const ALL: [Stage; 4] = [Stage::Parse, Stage::Route, Stage::Queue, Stage::Apply];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Parse => "parse",
+            Stage::Route => "route",
+            Stage::Queue => "queue",
+            Stage::Apply => "apply",
+        }
+    }
+}
+
+/// Channels between pipeline stages.
+#[derive(Debug, Clone, Copy)]
+pub enum Channel {
+    Parser,
+    Account,
+    Writer,
+}
+
+impl Channel {
+    const ALL: [Channel; 3] = [Channel::Parser, Channel::Account, Channel::Writer];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Channel::Parser => "parser",
+            Channel::Account => "account",
+            Channel::Writer => "writer",
+        }
+    }
+}
+
+struct Gauge {
+    current: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Gauge {
+    const fn new() -> Self {
+        Gauge {
+            current: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    fn set(&self, value: u64) {
+        self.current.store(value, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+    }
+}
+
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS_US.len()],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS_US.len()],
+            count: AtomicU64::new(0),
+            sum_us: AtomicU64::new(0),
+        }
+    }
+
+    fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.count.store(0, Ordering::Relaxed);
+        self.sum_us.store(0, Ordering::Relaxed);
+    }
+
+    fn observe(&self, elapsed: Duration) {
+        let us = elapsed.as_micros() as u64;
+        if let Some(idx) = LATENCY_BUCKETS_US.iter().position(|bound| us <= *bound) {
+            self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+    }
+
+    /// Upper bound of the bucket holding `q`-th quantile. `None` when there are no
+    /// observations, or quantile lies beyond the largest bucket.
+    fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count.load(Ordering::Relaxed);
+        let rank = (count as f64 * q).ceil() as u64;
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS_US.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            if count > 0 && cumulative >= rank {
+                return Some(Duration::from_micros(*bound));
+            }
+        }
+        None
+    }
+
+    fn latency(&self) -> Latency {
+        let count = self.count.load(Ordering::Relaxed);
+        let sum_us = self.sum_us.load(Ordering::Relaxed);
+        Latency {
+            count,
+            mean: Duration::from_micros(sum_us.checked_div(count).unwrap_or_default()),
+            p99: self.quantile(0.99),
+        }
+    }
+
+    /// Renders series of histogram `name`. `labels` are added to every series, and must
+    /// be either empty or end with a comma.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS_US.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = *bound as f64 / 1_000_000.0;
+            let _ = writeln!(out, "{name}_bucket{{{labels}le=\"{le}\"}} {cumulative}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let labels = labels.trim_end_matches(',');
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{labels}{}le=\"+Inf\"}} {count}",
+            if labels.is_empty() { "" } else { "," }
+        );
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {sum}");
+        let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+    }
+}
+
+/// Latency statistics of a single stage.
+#[derive(Debug, Clone, Copy)]
+struct Latency {
+    count: u64,
+    mean: Duration,
+    p99: Option<Duration>,
+}
+
+impl Display for Latency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mean {:?}, p99 ", self.mean)?;
+        match self.p99 {
+            Some(p99) => write!(f, "<= {p99:?}"),
+            None if self.count == 0 => write!(f, "n/a"),
+            None => write!(
+                f,
+                "> {:?}",
+                Duration::from_micros(LATENCY_BUCKETS_US[LATENCY_BUCKETS_US.len() - 1])
+            ),
+        }
+    }
+}
+
+struct Metrics {
+    messages: [AtomicU64; 6],
+    rejects: Mutex<BTreeMap<&'static str, u64>>,
+    parse_errors: AtomicU64,
+    unroutable: AtomicU64,
+    accounts: AtomicU64,
+    accounts_locked: AtomicU64,
+    lagging_accounts: AtomicU64,
+    alerts: Mutex<BTreeMap<&'static str, u64>>,
+    /// Messages rejected for lack of an account, by source.
+    no_account: Mutex<BTreeMap<Arc<str>, u64>>,
+    channels: [Gauge; 3],
+    send_failures: [AtomicU64; 3],
+    latency: [Histogram; 4],
+}
+
+static METRICS: Metrics = Metrics {
+    messages: [const { AtomicU64::new(0) }; 6],
+    rejects: Mutex::new(BTreeMap::new()),
+    parse_errors: AtomicU64::new(0),
+    unroutable: AtomicU64::new(0),
+    accounts: AtomicU64::new(0),
+    accounts_locked: AtomicU64::new(0),
+    lagging_accounts: AtomicU64::new(0),
+    alerts: Mutex::new(BTreeMap::new()),
+    no_account: Mutex::new(BTreeMap::new()),
+    channels: [Gauge::new(), Gauge::new(), Gauge::new()],
+    send_failures: [const { AtomicU64::new(0) }; 3],
+    latency: [
+        Histogram::new(),
+        Histogram::new(),
+        Histogram::new(),
+        Histogram::new(),
+    ],
+};
+
+const MESSAGE_KINDS: [&str; 6] = [
+    "deposit",
+    "withdrawal",
+    "dispute",
+    "resolve",
+    "chargeback",
+    "settle",
+];
+
+/// Counts a successfully parsed message.
+pub fn message(msg: &Message) {
+    let idx = MESSAGE_KINDS
+        .iter()
+        .position(|kind| *kind == msg.kind())
+        .expect("Every message kind is listed");
+    METRICS.messages[idx].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a record or message rejected at any stage, labelled with the error code.
+pub fn reject(code: &'static str) {
+    let mut rejects = METRICS
+        .rejects
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    *rejects.entry(code).or_default() += 1;
+}
+
+/// Counts a record which could not be turned into a [`Message`].
+pub fn parse_error(code: &'static str) {
+    METRICS.parse_errors.fetch_add(1, Ordering::Relaxed);
+    reject(code);
+}
+
+/// Counts a message for which no account could be found or created.
+pub fn unroutable(code: &'static str) {
+    METRICS.unroutable.fetch_add(1, Ordering::Relaxed);
+    reject(code);
+}
+
+/// Counts a message of `source` rejected since its client has no account, and the message
+/// can't create one, see [`creation`](crate::creation).
+pub fn no_account(source: &Arc<str>) {
+    let mut no_account = METRICS
+        .no_account
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    *no_account.entry(source.clone()).or_default() += 1;
+}
+
+pub fn account_created() {
+    METRICS.accounts.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn account_locked() {
+    METRICS.accounts_locked.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts an account whose channel stayed near capacity for a sustained period.
+pub fn lagging_account() {
+    METRICS.lagging_accounts.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts an alert raised by a [`velocity`](crate::velocity) or [`reserve`](crate::reserve)
+/// rule.
+pub fn alert(rule: &'static str) {
+    let mut alerts = METRICS.alerts.lock().unwrap_or_else(|err| err.into_inner());
+    *alerts.entry(rule).or_default() += 1;
+}
+
+/// Records number of messages queued in `channel`, as observed by its sender.
+pub fn channel_depth(channel: Channel, depth: usize) {
+    METRICS.channels[channel as usize].set(depth as u64);
+}
+
+/// Counts a message or result which could not be sent over `channel`, since its receiver is
+/// gone, see [`send_errors`](crate::send_errors).
+pub fn send_failed(channel: Channel) {
+    METRICS.send_failures[channel as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Current and maximum observed depth of every channel.
+pub fn channel_depths() -> Vec<(&'static str, u64, u64)> {
+    Channel::ALL
+        .iter()
+        .map(|channel| {
+            let gauge = &METRICS.channels[*channel as usize];
+            (
+                channel.as_str(),
+                gauge.current.load(Ordering::Relaxed),
+                gauge.max.load(Ordering::Relaxed),
+            )
+        })
+        .collect()
+}
+
+/// Records time a single message spent in `stage`.
+pub fn latency(stage: Stage, elapsed: Duration) {
+    METRICS.latency[stage as usize].observe(elapsed);
+}
+
+/// Counter value as `(name, label, value)`.
+#[cfg(feature = "otel")]
+pub type Counter = (&'static str, Option<(&'static str, String)>, u64);
+
+/// Current values of all counters.
+#[cfg(feature = "otel")]
+pub fn counters() -> Vec<Counter> {
+    let mut counters = Vec::new();
+    for (kind, count) in MESSAGE_KINDS.iter().zip(&METRICS.messages) {
+        counters.push((
+            "trp_messages_total",
+            Some(("kind", kind.to_string())),
+            count.load(Ordering::Relaxed),
+        ));
+    }
+    let rejects = METRICS
+        .rejects
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    for (code, count) in rejects.iter() {
+        counters.push((
+            "trp_rejects_total",
+            Some(("code", code.to_string())),
+            *count,
+        ));
+    }
+    drop(rejects);
+    let alerts = METRICS.alerts.lock().unwrap_or_else(|err| err.into_inner());
+    for (rule, count) in alerts.iter() {
+        counters.push(("trp_alerts_total", Some(("rule", rule.to_string())), *count));
+    }
+    drop(alerts);
+    let no_account = METRICS
+        .no_account
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    for (source, count) in no_account.iter() {
+        counters.push((
+            "trp_no_account_total",
+            Some(("source", source.to_string())),
+            *count,
+        ));
+    }
+    drop(no_account);
+    counters.extend([
+        (
+            "trp_parse_errors_total",
+            None,
+            METRICS.parse_errors.load(Ordering::Relaxed),
+        ),
+        (
+            "trp_unroutable_total",
+            None,
+            METRICS.unroutable.load(Ordering::Relaxed),
+        ),
+        (
+            "trp_accounts_total",
+            None,
+            METRICS.accounts.load(Ordering::Relaxed),
+        ),
+        (
+            "trp_accounts_locked_total",
+            None,
+            METRICS.accounts_locked.load(Ordering::Relaxed),
+        ),
+        (
+            "trp_lagging_accounts_total",
+            None,
+            METRICS.lagging_accounts.load(Ordering::Relaxed),
+        ),
+    ]);
+    for channel in Channel::ALL {
+        counters.push((
+            "trp_send_failures_total",
+            Some(("channel", channel.as_str().to_string())),
+            METRICS.send_failures[channel as usize].load(Ordering::Relaxed),
+        ));
+    }
+    counters
+}
+
+/// End-of-run statistics, printed once all accounts have reported.
+#[derive(Debug)]
+pub struct Summary {
+    messages: Vec<(&'static str, u64)>,
+    rejects: BTreeMap<&'static str, u64>,
+    parse_errors: u64,
+    accounts: u64,
+    accounts_locked: u64,
+    /// Order messages of every client were applied in, see [`ordering`].
+    ordering: ordering::Policy,
+    /// Highest estimate of memory in use, see [`memory`].
+    memory_peak: u64,
+    alerts: BTreeMap<&'static str, u64>,
+    /// Messages rejected for lack of an account, by source.
+    no_account: BTreeMap<Arc<str>, u64>,
+    latency: Vec<(&'static str, Latency)>,
+}
+
+impl Summary {
+    pub fn rejects(&self) -> u64 {
+        self.rejects.values().sum()
+    }
+
+    /// Share of input rows rejected at any stage, 0 when there was no input.
+    pub fn reject_rate(&self) -> f64 {
+        let messages: u64 = self.messages.iter().map(|(_, count)| count).sum();
+        let rows = messages + self.parse_errors;
+        if rows == 0 {
+            return 0.0;
+        }
+        self.rejects() as f64 / rows as f64
+    }
+}
+
+/// Clears counters and latencies, so that the next [`summary`] only covers what follows, see
+/// [`pipeline`](crate::pipeline). Channel depths are left alone.
+pub fn reset() {
+    for count in METRICS.messages.iter().chain(&METRICS.send_failures) {
+        count.store(0, Ordering::Relaxed);
+    }
+    for count in [
+        &METRICS.parse_errors,
+        &METRICS.unroutable,
+        &METRICS.accounts,
+        &METRICS.accounts_locked,
+        &METRICS.lagging_accounts,
+    ] {
+        count.store(0, Ordering::Relaxed);
+    }
+    for counts in [&METRICS.rejects, &METRICS.alerts] {
+        counts.lock().unwrap_or_else(|err| err.into_inner()).clear();
+    }
+    METRICS
+        .no_account
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .clear();
+    for histogram in &METRICS.latency {
+        histogram.reset();
+    }
+}
+
+pub fn summary() -> Summary {
+    Summary {
+        messages: MESSAGE_KINDS
+            .iter()
+            .zip(&METRICS.messages)
+            .map(|(kind, count)| (*kind, count.load(Ordering::Relaxed)))
+            .collect(),
+        rejects: METRICS
+            .rejects
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone(),
+        parse_errors: METRICS.parse_errors.load(Ordering::Relaxed),
+        accounts: METRICS.accounts.load(Ordering::Relaxed),
+        accounts_locked: METRICS.accounts_locked.load(Ordering::Relaxed),
+        ordering: ordering::policy(),
+        memory_peak: memory::peak(),
+        alerts: METRICS
+            .alerts
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone(),
+        no_account: METRICS
+            .no_account
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone(),
+        latency: Stage::ALL
+            .iter()
+            .map(|stage| (stage.as_str(), METRICS.latency[*stage as usize].latency()))
+            .collect(),
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let messages: u64 = self.messages.iter().map(|(_, count)| count).sum();
+        write!(f, "Messages: {messages} (")?;
+        for (i, (kind, count)) in self.messages.iter().enumerate() {
+            let sep = if i > 0 { ", " } else { "" };
+            write!(f, "{sep}{kind}: {count}")?;
+        }
+        writeln!(f, ")")?;
+
+        write!(f, "Rejects: {}", self.rejects())?;
+        if !self.rejects.is_empty() {
+            write!(f, " (")?;
+            for (i, (code, count)) in self.rejects.iter().enumerate() {
+                let sep = if i > 0 { ", " } else { "" };
+                write!(f, "{sep}{code}: {count}")?;
+            }
+            write!(f, ")")?;
+        }
+        writeln!(f)?;
+
+        write!(
+            f,
+            "Accounts: {} ({} locked)\nOrdering: {}\nMemory: {} at peak, estimated",
+            self.accounts,
+            self.accounts_locked,
+            self.ordering,
+            memory::human(self.memory_peak)
+        )?;
+
+        if !self.alerts.is_empty() {
+            let alerts: u64 = self.alerts.values().sum();
+            write!(f, "\nAlerts: {alerts} (")?;
+            for (i, (rule, count)) in self.alerts.iter().enumerate() {
+                let sep = if i > 0 { ", " } else { "" };
+                write!(f, "{sep}{rule}: {count}")?;
+            }
+            write!(f, ")")?;
+        }
+
+        if !self.no_account.is_empty() {
+            let no_account: u64 = self.no_account.values().sum();
+            write!(f, "\nWithout account: {no_account} (")?;
+            for (i, (source, count)) in self.no_account.iter().enumerate() {
+                let sep = if i > 0 { ", " } else { "" };
+                write!(f, "{sep}{source}: {count}")?;
+            }
+            write!(f, ")")?;
+        }
+
+        for (stage, latency) in &self.latency {
+            write!(f, "\nLatency {stage}: {latency}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Renders all metrics in Prometheus text format.
+pub fn render() -> String {
+    let mut out = String::new();
+
+    out.push_str("# HELP trp_messages_total Parsed messages by transaction type.\n");
+    out.push_str("# TYPE trp_messages_total counter\n");
+    for (kind, count) in MESSAGE_KINDS.iter().zip(&METRICS.messages) {
+        let count = count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "trp_messages_total{{kind=\"{kind}\"}} {count}");
+    }
+
+    out.push_str(
+        "# HELP trp_rejects_total Records and messages rejected at any stage, by error code.\n",
+    );
+    out.push_str("# TYPE trp_rejects_total counter\n");
+    let rejects = METRICS
+        .rejects
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    for (code, count) in rejects.iter() {
+        let _ = writeln!(out, "trp_rejects_total{{code=\"{code}\"}} {count}");
+    }
+    drop(rejects);
+
+    out.push_str("# HELP trp_alerts_total Alerts raised by velocity rules, by rule.\n");
+    out.push_str("# TYPE trp_alerts_total counter\n");
+    let alerts = METRICS.alerts.lock().unwrap_or_else(|err| err.into_inner());
+    for (rule, count) in alerts.iter() {
+        let _ = writeln!(out, "trp_alerts_total{{rule=\"{rule}\"}} {count}");
+    }
+    drop(alerts);
+
+    out.push_str(
+        "# HELP trp_no_account_total Messages rejected since their client has no account, by source.\n",
+    );
+    out.push_str("# TYPE trp_no_account_total counter\n");
+    let no_account = METRICS
+        .no_account
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    for (source, count) in no_account.iter() {
+        let source = source.replace('\\', "\\\\").replace('"', "\\\"");
+        let _ = writeln!(out, "trp_no_account_total{{source=\"{source}\"}} {count}");
+    }
+    drop(no_account);
+
+    let counters = [
+        (
+            "trp_parse_errors_total",
+            "Records which could not be parsed into messages.",
+            &METRICS.parse_errors,
+        ),
+        (
+            "trp_unroutable_total",
+            "Messages dropped because no account could be created for them.",
+            &METRICS.unroutable,
+        ),
+        ("trp_accounts_total", "Accounts created.", &METRICS.accounts),
+        (
+            "trp_accounts_locked_total",
+            "Accounts locked by a chargeback.",
+            &METRICS.accounts_locked,
+        ),
+        (
+            "trp_lagging_accounts_total",
+            "Times an account channel stayed near capacity for a sustained period.",
+            &METRICS.lagging_accounts,
+        ),
+    ];
+    for (name, help, value) in counters {
+        let value = value.load(Ordering::Relaxed);
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        let _ = writeln!(out, "{name} {value}");
+    }
+
+    out.push_str("# HELP trp_channel_depth Messages queued in a channel, as last observed.\n");
+    out.push_str("# TYPE trp_channel_depth gauge\n");
+    for channel in Channel::ALL {
+        let depth = METRICS.channels[channel as usize]
+            .current
+            .load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "trp_channel_depth{{channel=\"{}\"}} {depth}",
+            channel.as_str()
+        );
+    }
+    out.push_str("# HELP trp_channel_depth_max Highest observed channel depth.\n");
+    out.push_str("# TYPE trp_channel_depth_max gauge\n");
+    for channel in Channel::ALL {
+        let depth = METRICS.channels[channel as usize]
+            .max
+            .load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "trp_channel_depth_max{{channel=\"{}\"}} {depth}",
+            channel.as_str()
+        );
+    }
+
+    let gauges = [
+        (
+            "trp_memory_bytes",
+            "Estimated bytes held by accounts, their histories and the router.",
+            memory::used(),
+        ),
+        (
+            "trp_memory_peak_bytes",
+            "Highest estimate of bytes held so far.",
+            memory::peak(),
+        ),
+    ];
+    for (name, help, value) in gauges {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        let _ = writeln!(out, "{name} {value}");
+    }
+
+    out.push_str(
+        "# HELP trp_send_failures_total Messages and results which could not be sent over a channel.\n",
+    );
+    out.push_str("# TYPE trp_send_failures_total counter\n");
+    for channel in Channel::ALL {
+        let failures = METRICS.send_failures[channel as usize].load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "trp_send_failures_total{{channel=\"{}\"}} {failures}",
+            channel.as_str()
+        );
+    }
+
+    out.push_str(
+        "# HELP trp_stage_duration_seconds Time a single message spent in a pipeline stage.\n",
+    );
+    out.push_str("# TYPE trp_stage_duration_seconds histogram\n");
+    for stage in Stage::ALL {
+        METRICS.latency[stage as usize].render(
+            &mut out,
+            "trp_stage_duration_seconds",
+            &format!("stage=\"{}\",", stage.as_str()),
+        );
+    }
+
+    out
+}
+
+/// Dumps metrics into `path`, for collection by node exporter's textfile collector.
+/// Written to a temporary file first, so collector never observes a partial file.
+pub fn write_textfile<P: AsRef<Path>>(path: P) -> Result<(), anyhow::Error> {
+    let path = path.as_ref();
+    let tmp = path.with_extension("prom.tmp");
+    std::fs::write(&tmp, render())?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Serves `GET /metrics` and `GET /health` on `addr` until the runtime shuts down.
+pub async fn serve(addr: String) -> Result<(), anyhow::Error> {
+    let span = log::Span::new("metrics").with("addr", &addr);
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let span = span.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            let read = match stream.read(&mut buf).await {
+                Ok(read) => read,
+                Err(err) => {
+                    log::debug!(span, "Failed to read request: {err}");
+                    return;
+                }
+            };
+
+            let request = String::from_utf8_lossy(&buf[..read]);
+            let response = if request.starts_with("GET /metrics ") {
+                ok("text/plain; version=0.0.4", render())
+            } else if request.starts_with("GET /health ") {
+                ok("application/json", progress::snapshot().json())
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_owned()
+            };
+
+            if let Err(err) = stream.write_all(response.as_bytes()).await {
+                log::debug!(span, "Failed to write response: {err}");
+            }
+        });
+    }
+}
+
+fn ok(content_type: &str, body: String) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Histogram, Latency, Summary, LATENCY_BUCKETS_US};
+    use crate::ordering;
+    use std::{collections::BTreeMap, time::Duration};
+
+    #[test]
+    fn summary_lists_every_reject_code() {
+        let summary = Summary {
+            messages: vec![("deposit", 3), ("dispute", 2)],
+            rejects: BTreeMap::from([("PE_INSF", 1), ("PR_INVLD", 2)]),
+            parse_errors: 2,
+            accounts: 2,
+            accounts_locked: 1,
+            ordering: ordering::Policy::StrictPerClient,
+            memory_peak: 3 << 20,
+            alerts: BTreeMap::from([("velocity", 2)]),
+            no_account: BTreeMap::from([("in.csv".into(), 1)]),
+            latency: vec![(
+                "apply",
+                Latency {
+                    count: 5,
+                    mean: Duration::from_micros(3),
+                    p99: Some(Duration::from_micros(5)),
+                },
+            )],
+        };
+
+        assert_eq!(
+            summary.to_string(),
+            "Messages: 5 (deposit: 3, dispute: 2)\nRejects: 3 (PE_INSF: 1, PR_INVLD: 2)\nAccounts: 2 (1 locked)\nOrdering: strict-per-client\nMemory: 3.0 MiB at peak, estimated\nAlerts: 2 (velocity: 2)\nWithout account: 1 (in.csv: 1)\nLatency apply: mean 3µs, p99 <= 5µs"
+        );
+        assert_eq!(summary.reject_rate(), 3.0 / 7.0);
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new();
+        histogram.observe(Duration::from_micros(3));
+        histogram.observe(Duration::from_micros(70));
+        histogram.observe(Duration::from_secs(2));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "h", "stage=\"apply\",");
+
+        assert!(out.contains("h_bucket{stage=\"apply\",le=\"0.000001\"} 0\n"));
+        assert!(out.contains("h_bucket{stage=\"apply\",le=\"0.000005\"} 1\n"));
+        assert!(out.contains("h_bucket{stage=\"apply\",le=\"0.0001\"} 2\n"));
+        assert!(out.contains("h_bucket{stage=\"apply\",le=\"1\"} 2\n"));
+        assert!(out.contains("h_bucket{stage=\"apply\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("h_count{stage=\"apply\"} 3\n"));
+        assert_eq!(out.lines().count(), LATENCY_BUCKETS_US.len() + 3);
+    }
+
+    #[test]
+    fn quantile_is_upper_bound_of_bucket() {
+        let histogram = Histogram::new();
+        for _ in 0..99 {
+            histogram.observe(Duration::from_micros(3));
+        }
+        assert_eq!(histogram.quantile(0.99), Some(Duration::from_micros(5)));
+
+        histogram.observe(Duration::from_secs(2));
+        histogram.observe(Duration::from_secs(2));
+        assert_eq!(histogram.quantile(0.99), None);
+        assert_eq!(histogram.latency().to_string(), "mean 39.606ms, p99 > 1s");
+    }
+}
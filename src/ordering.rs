@@ -0,0 +1,169 @@
+//! Order in which messages of a client read from several sources at once, such as connections
+//! of `trp serve`, are applied, chosen with `--ordering`. The router enforces it:
+//!
+//! - `best-effort`, the default, applies messages of a client in the order the router receives
+//!   them. Messages of a single source keep their order, messages of different sources are
+//!   interleaved as they happen to arrive.
+//! - `strict-per-client` gives every client to the first source it arrives from, for as long
+//!   as that source is open. Messages of the client from any other source are rejected with
+//!   [`FOREIGN_SOURCE`] and written to the [`dlq`](crate::dlq), so messages of a client are
+//!   always applied in the order of the one source it has.
+//! - `timestamp-merge` applies messages of a client in timestamp order whichever source they
+//!   come from, see [`reorder`]. Messages are held back for `--reorder-lateness`, 0 unless
+//!   given, and messages later than that are rejected.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    str::FromStr,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use crate::{provenance::Provenance, reorder};
+
+/// Error code of messages of a client owned by another source.
+pub const FOREIGN_SOURCE: &str = "RT_SRC";
+
+static POLICY: OnceLock<Policy> = OnceLock::new();
+/// Sources being read at the moment.
+static OPEN: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    StrictPerClient,
+    #[default]
+    BestEffort,
+    TimestampMerge,
+}
+
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict-per-client" => Ok(Policy::StrictPerClient),
+            "best-effort" => Ok(Policy::BestEffort),
+            "timestamp-merge" => Ok(Policy::TimestampMerge),
+            other => Err(anyhow::anyhow!(
+                "Unknown ordering {other}, expected strict-per-client, best-effort or timestamp-merge"
+            )),
+        }
+    }
+}
+
+impl Display for Policy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Policy::StrictPerClient => "strict-per-client",
+            Policy::BestEffort => "best-effort",
+            Policy::TimestampMerge => "timestamp-merge",
+        })
+    }
+}
+
+/// Applies messages in order of `policy` from now on, holding them back for `lateness`
+/// milliseconds with [`Policy::TimestampMerge`]. Only the first call has effect.
+pub fn enable(policy: Policy, lateness: Option<u64>) {
+    if POLICY.set(policy).is_ok() && policy == Policy::TimestampMerge {
+        reorder::enable(lateness.unwrap_or(0));
+    }
+}
+
+/// Policy set with [`enable`], or the default when it wasn't called.
+pub fn policy() -> Policy {
+    POLICY.get().copied().unwrap_or_default()
+}
+
+/// Marks `source` as being read, until [`closed`] is called for it.
+pub fn opened(source: &str) {
+    OPEN.lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .get_or_insert_default()
+        .insert(source.to_string());
+}
+
+/// Marks `source` as read in full, so that its clients can move to other sources.
+pub fn closed(source: &str) {
+    if let Some(open) = OPEN.lock().unwrap_or_else(|err| err.into_inner()).as_mut() {
+        open.remove(source);
+    }
+}
+
+fn is_open(source: &str) -> bool {
+    OPEN.lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .as_ref()
+        .is_some_and(|open| open.contains(source))
+}
+
+/// Sources owning clients under [`Policy::StrictPerClient`], kept by the router.
+#[derive(Debug)]
+pub struct Owners {
+    policy: Policy,
+    sources: HashMap<u16, Arc<str>>,
+}
+
+impl Owners {
+    pub fn new(policy: Policy) -> Self {
+        Owners {
+            policy,
+            sources: HashMap::new(),
+        }
+    }
+
+    /// Returns `false` when message of `client` read from `provenance` must be rejected,
+    /// since another source owns the client.
+    pub fn admit(&mut self, client: u16, provenance: &Provenance) -> bool {
+        if self.policy != Policy::StrictPerClient {
+            return true;
+        }
+        match self.sources.get(&client) {
+            Some(owner) if *owner != provenance.source && is_open(owner) => false,
+            _ => {
+                self.sources.insert(client, provenance.source.clone());
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{closed, opened, Owners, Policy};
+    use crate::provenance::Provenance;
+
+    #[test]
+    fn policies_are_parsed() {
+        for policy in [
+            Policy::StrictPerClient,
+            Policy::BestEffort,
+            Policy::TimestampMerge,
+        ] {
+            assert_eq!(policy.to_string().parse::<Policy>().unwrap(), policy);
+        }
+        assert!("fifo".parse::<Policy>().is_err());
+    }
+
+    #[test]
+    fn clients_stay_with_their_source_while_it_is_open() {
+        let from = |source: &str| Provenance {
+            source: source.into(),
+            line: 1,
+            reference: None,
+            correlation_id: None,
+        };
+        let mut owners = Owners::new(Policy::StrictPerClient);
+        opened("ordering-a");
+        opened("ordering-b");
+        assert!(owners.admit(1, &from("ordering-a")));
+        assert!(owners.admit(2, &from("ordering-b")));
+        assert!(!owners.admit(1, &from("ordering-b")));
+        assert!(owners.admit(1, &from("ordering-a")));
+        closed("ordering-a");
+        assert!(owners.admit(1, &from("ordering-b")));
+        assert!(!owners.admit(1, &from("ordering-a")));
+
+        let mut owners = Owners::new(Policy::BestEffort);
+        assert!(owners.admit(2, &from("ordering-a")));
+    }
+}
@@ -0,0 +1,111 @@
+//! Disputes referencing a transaction their account doesn't know of, dealt with as
+//! `unknown_disputes` of the engine [configuration](crate::config) says, since upstreams differ
+//! in how well they order messages:
+//!
+//! - `ignore`, the default: the dispute is applied, and has no effect.
+//! - `reject`: the dispute is rejected with [`UNKNOWN`] and written to the
+//!   [`dlq`](crate::dlq), so there is a trail of disputes which went nowhere.
+//! - `retry`: the account task holds the dispute back in [`Held`] until a deposit or
+//!   withdrawal with its transaction id comes, and applies it right after that one. Disputes
+//!   still held once the inbox of the account closes are rejected as with `reject`.
+//!
+//! Only disputes are subject to the policy, resolves and chargebacks can't refer to anything
+//! a dispute didn't.
+
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+/// Error code of disputes of transactions unknown to their account.
+pub const UNKNOWN: &str = "PE_UNKTX";
+
+/// What happens to disputes of transactions unknown to their account.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    #[default]
+    Ignore,
+    Reject,
+    Retry,
+}
+
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(Policy::Ignore),
+            "reject" => Ok(Policy::Reject),
+            "retry" => Ok(Policy::Retry),
+            other => Err(anyhow::anyhow!(
+                "Unknown policy of unknown disputes {other}, expected ignore, reject or retry"
+            )),
+        }
+    }
+}
+
+impl Display for Policy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Policy::Ignore => "ignore",
+            Policy::Reject => "reject",
+            Policy::Retry => "retry",
+        })
+    }
+}
+
+/// Disputes of a single client held back until their transaction comes, by its id.
+#[derive(Debug)]
+pub struct Held<T> {
+    held: HashMap<u32, Vec<T>>,
+}
+
+impl<T> Default for Held<T> {
+    fn default() -> Self {
+        Held {
+            held: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Held<T> {
+    /// Holds `item` back until transaction `tx` comes.
+    pub fn hold(&mut self, tx: u32, item: T) {
+        self.held.entry(tx).or_default().push(item);
+    }
+
+    /// Items waiting for transaction `tx`, in the order they were held.
+    pub fn release(&mut self, tx: u32) -> Vec<T> {
+        self.held.remove(&tx).unwrap_or_default()
+    }
+
+    /// Whether nothing is held.
+    pub fn is_empty(&self) -> bool {
+        self.held.is_empty()
+    }
+
+    /// Every item still held, in no particular order.
+    pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        self.held.drain().flat_map(|(_, items)| items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Held, Policy};
+
+    #[test]
+    fn disputes_are_held_until_their_transaction() {
+        let mut held = Held::default();
+        held.hold(7, "first");
+        held.hold(8, "other");
+        held.hold(7, "second");
+        assert!(!held.is_empty());
+        assert!(held.release(1).is_empty());
+        assert_eq!(held.release(7), ["first", "second"]);
+        assert!(held.release(7).is_empty());
+        assert_eq!(held.drain().collect::<Vec<_>>(), ["other"]);
+        assert!(held.is_empty());
+
+        assert_eq!("retry".parse::<Policy>().unwrap(), Policy::Retry);
+        assert_eq!(Policy::Reject.to_string(), "reject");
+        assert!("buffer".parse::<Policy>().is_err());
+    }
+}
@@ -0,0 +1,282 @@
+//! OpenTelemetry export over OTLP/HTTP with JSON encoding, enabled by the `otel` feature.
+//!
+//! A run produces a root `trp.run` span. Records carrying a W3C `traceparent` column get a
+//! `trp.parse` span of their own, parented to the upstream producer's trace, so they show
+//! up next to it in the tracing backend. Counters are exported as OTLP sums once the run is
+//! over.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io::{Read, Write},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{log, log::json_string, metrics};
+
+const SERVICE_NAME: &str = "trp";
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SPANS: Mutex<Vec<SpanData>> = Mutex::new(Vec::new());
+
+/// Starts recording spans. Until called, ended spans are discarded.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Parent of a span, as carried by the W3C `traceparent` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    trace_id: u128,
+    span_id: u64,
+}
+
+impl TraceContext {
+    /// Parses `00-<trace-id>-<parent-id>-<flags>`.
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let (version, trace_id, span_id, _flags) =
+            (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        if version != "00" || trace_id.len() != 32 || span_id.len() != 16 {
+            return None;
+        }
+
+        let trace_id = u128::from_str_radix(trace_id, 16).ok()?;
+        let span_id = u64::from_str_radix(span_id, 16).ok()?;
+        (trace_id != 0 && span_id != 0).then_some(TraceContext { trace_id, span_id })
+    }
+
+    /// Context for a new trace with no upstream parent.
+    pub fn root() -> Self {
+        TraceContext {
+            trace_id: (u128::from(random_id()) << 64) | u128::from(random_id()),
+            span_id: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SpanData {
+    name: &'static str,
+    trace_id: u128,
+    span_id: u64,
+    parent_span_id: u64,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+}
+
+/// Span in progress, recorded for export when [`Span::end`] is called.
+#[derive(Debug)]
+pub struct Span {
+    data: SpanData,
+}
+
+impl Span {
+    pub fn start(name: &'static str, parent: TraceContext) -> Self {
+        let now = SystemTime::now();
+        Span {
+            data: SpanData {
+                name,
+                trace_id: parent.trace_id,
+                span_id: random_id(),
+                parent_span_id: parent.span_id,
+                start: now,
+                end: now,
+                attributes: Vec::new(),
+            },
+        }
+    }
+
+    pub fn attribute(&mut self, key: &'static str, value: impl ToString) {
+        self.data.attributes.push((key, value.to_string()));
+    }
+
+    pub fn end(mut self) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        self.data.end = SystemTime::now();
+        SPANS
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push(self.data);
+    }
+}
+
+/// Ids only need to be unique, `RandomState` is seeded randomly per instance.
+fn random_id() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(nanos(SystemTime::now()));
+    hasher.finish().max(1)
+}
+
+fn nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+fn resource() -> String {
+    format!(
+        "{{\"attributes\":[{{\"key\":\"service.name\",\"value\":{{\"stringValue\":{}}}}}]}}",
+        json_string(SERVICE_NAME)
+    )
+}
+
+fn traces_body(spans: &[SpanData]) -> String {
+    let spans = spans
+        .iter()
+        .map(|span| {
+            let attributes = span
+                .attributes
+                .iter()
+                .map(|(key, value)| {
+                    format!(
+                        "{{\"key\":{},\"value\":{{\"stringValue\":{}}}}}",
+                        json_string(key),
+                        json_string(value)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(",");
+            let parent = if span.parent_span_id == 0 {
+                String::new()
+            } else {
+                format!("\"parentSpanId\":\"{:016x}\",", span.parent_span_id)
+            };
+            format!(
+                "{{\"traceId\":\"{:032x}\",\"spanId\":\"{:016x}\",{parent}\"name\":{},\"kind\":1,\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[{attributes}]}}",
+                span.trace_id,
+                span.span_id,
+                json_string(span.name),
+                nanos(span.start),
+                nanos(span.end),
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!(
+        "{{\"resourceSpans\":[{{\"resource\":{},\"scopeSpans\":[{{\"scope\":{{\"name\":\"trp\"}},\"spans\":[{spans}]}}]}}]}}",
+        resource()
+    )
+}
+
+fn metrics_body(start: SystemTime) -> String {
+    let now = nanos(SystemTime::now());
+    let start = nanos(start);
+
+    // Counters sharing a name become data points of a single metric.
+    let mut points: Vec<(&str, Vec<String>)> = Vec::new();
+    for (name, label, value) in metrics::counters() {
+        let attributes = label.map_or(String::new(), |(key, value)| {
+            format!(
+                "{{\"key\":{},\"value\":{{\"stringValue\":{}}}}}",
+                json_string(key),
+                json_string(&value)
+            )
+        });
+        let point = format!(
+            "{{\"asInt\":\"{value}\",\"startTimeUnixNano\":\"{start}\",\"timeUnixNano\":\"{now}\",\"attributes\":[{attributes}]}}"
+        );
+        match points.last_mut() {
+            Some((last, series)) if *last == name => series.push(point),
+            _ => points.push((name, vec![point])),
+        }
+    }
+
+    let metrics = points
+        .into_iter()
+        .map(|(name, series)| {
+            format!(
+                "{{\"name\":{},\"sum\":{{\"aggregationTemporality\":2,\"isMonotonic\":true,\"dataPoints\":[{}]}}}}",
+                json_string(name),
+                series.join(",")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!(
+        "{{\"resourceMetrics\":[{{\"resource\":{},\"scopeMetrics\":[{{\"scope\":{{\"name\":\"trp\"}},\"metrics\":[{metrics}]}}]}}]}}",
+        resource()
+    )
+}
+
+/// Sends `body` to `<endpoint><path>`. Only plain `http://` collectors are supported.
+fn post(endpoint: &str, path: &str, body: &str) -> Result<(), anyhow::Error> {
+    let authority = endpoint
+        .strip_prefix("http://")
+        .ok_or_else(|| anyhow::anyhow!("OTLP endpoint must start with http://: {endpoint}"))?
+        .trim_end_matches('/');
+    let mut stream = TcpStream::connect(authority)?;
+    stream.set_read_timeout(Some(EXPORT_TIMEOUT))?;
+    stream.set_write_timeout(Some(EXPORT_TIMEOUT))?;
+
+    write!(
+        stream,
+        "POST {path} HTTP/1.1\r\nHost: {authority}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response.split_whitespace().nth(1).unwrap_or_default();
+    if !status.starts_with('2') {
+        return Err(anyhow::anyhow!("Collector responded with {status:?}"));
+    }
+
+    Ok(())
+}
+
+/// Exports spans recorded so far and current counter values to the collector at
+/// `endpoint`, e.g. `http://localhost:4318`.
+pub fn export(endpoint: &str, run_started: SystemTime) {
+    let span = log::Span::new("otel").with("endpoint", endpoint);
+    let spans = std::mem::take(&mut *SPANS.lock().unwrap_or_else(|err| err.into_inner()));
+
+    if let Err(err) = post(endpoint, "/v1/traces", &traces_body(&spans)) {
+        log::error!(span, "Failed to export traces: {err}");
+    }
+    if let Err(err) = post(endpoint, "/v1/metrics", &metrics_body(run_started)) {
+        log::error!(span, "Failed to export metrics: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TraceContext;
+
+    #[test]
+    fn traceparent_is_parsed() {
+        let ctx = TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+        assert_eq!(
+            ctx,
+            Some(TraceContext {
+                trace_id: 0x4bf92f3577b34da6a3ce929d0e0e4736,
+                span_id: 0x00f067aa0ba902b7,
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_traceparent_is_ignored() {
+        assert_eq!(TraceContext::parse(""), None);
+        assert_eq!(
+            TraceContext::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            None
+        );
+        assert_eq!(
+            TraceContext::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+            None
+        );
+        assert_eq!(TraceContext::parse("00-xyz-00f067aa0ba902b7-01"), None);
+    }
+}
@@ -0,0 +1,207 @@
+//! Transactional outbox of balances for Redis, enabled with `trp serve --outbox` along with
+//! `--state` and `--redis`, so that across crashes Redis neither misses balances the state
+//! has, nor gets balances the state never had.
+//!
+//! [`redis`](crate::redis) holds back live balances. Once the run is over, balances of every
+//! account are staged in `outbox.csv.pending` of the state directory as part of
+//! [`state::save`], ahead of the accounts file, and replace `outbox.csv` once the accounts
+//! file is in place. Every entry is tagged with the SHA-256 of the accounts file it goes
+//! with, so replacing the accounts file commits both at once: entries tagged otherwise
+//! belong to a save which never completed, and are dropped. Until then, `outbox.csv` keeps
+//! entries of the last save which did, and a save which stopped right after replacing the
+//! accounts file is finished by the next run. The Redis thread then delivers the outbox, and
+//! whatever it could not deliver by the time it's stopped stays in the outbox, to be
+//! delivered first thing by the next run.
+//!
+//! Delivery is at least once: entries delivered right before a crash are delivered again by
+//! the next run, which `HSET` doesn't mind.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    log,
+    state::{self, AccountRecord},
+};
+
+const FILE: &str = "outbox.csv";
+/// Outbox of a save, until the accounts file it goes with is in place.
+const STAGED: &str = "outbox.csv.pending";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Balances of an account to deliver, along with the accounts file they go with.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// Hex encoded SHA-256 of the accounts file.
+    commit: String,
+    client: u16,
+    available: f32,
+    held: f32,
+    total: f32,
+    locked: bool,
+}
+
+/// Holds balances back from Redis until they are saved, see [module](self).
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Replaces the outbox of state directory `dir` with `accounts`, which go with the accounts
+/// file hashing to `commit`.
+pub fn write(dir: &Path, commit: &str, accounts: &[AccountRecord]) -> Result<(), anyhow::Error> {
+    write_to(&dir.join(FILE), commit, accounts)
+}
+
+/// Stages `accounts` of state directory `dir` for the accounts file hashing to `commit`,
+/// leaving the outbox as it is until they are [`promote`]d.
+pub fn stage(dir: &Path, commit: &str, accounts: &[AccountRecord]) -> Result<(), anyhow::Error> {
+    write_to(&dir.join(STAGED), commit, accounts)
+}
+
+/// Replaces the outbox of state directory `dir` with what was [`stage`]d, once the accounts
+/// file it goes with is in place.
+pub fn promote(dir: &Path) -> Result<(), anyhow::Error> {
+    let staged = dir.join(STAGED);
+    if staged.exists() {
+        std::fs::rename(staged, dir.join(FILE))?;
+    }
+    Ok(())
+}
+
+fn write_to(path: &Path, commit: &str, accounts: &[AccountRecord]) -> Result<(), anyhow::Error> {
+    let tmp = path.with_extension("tmp");
+    let mut out = csv::Writer::from_path(&tmp)?;
+    for account in accounts {
+        out.serialize(Entry {
+            commit: commit.to_string(),
+            client: account.client,
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.locked,
+        })?;
+    }
+    out.flush()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Commit of the first entry staged in state directory `dir`, if there is one.
+fn staged_commit(dir: &Path) -> Result<Option<String>, anyhow::Error> {
+    let staged = dir.join(STAGED);
+    if !staged.exists() {
+        return Ok(None);
+    }
+    let entry = csv::Reader::from_path(staged)?
+        .deserialize::<Entry>()
+        .next();
+    Ok(entry.transpose()?.map(|entry| entry.commit))
+}
+
+/// Balances in the outbox of state directory `dir` which are yet to be delivered, leaving
+/// out those of a save which never completed.
+pub fn pending(dir: &Path) -> Result<Vec<AccountRecord>, anyhow::Error> {
+    let commit = state::commit(dir)?;
+    // Staged by a save which replaced the accounts file, but stopped short of the outbox.
+    if commit.is_some() && staged_commit(dir)? == commit {
+        promote(dir)?;
+    }
+    let path = dir.join(FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut pending = Vec::new();
+    let mut dropped = 0;
+    for entry in csv::Reader::from_path(&path)?.deserialize() {
+        let entry: Entry = entry?;
+        if commit.as_deref() != Some(entry.commit.as_str()) {
+            dropped += 1;
+            continue;
+        }
+        pending.push(AccountRecord {
+            client: entry.client,
+            available: entry.available,
+            held: entry.held,
+            total: entry.total,
+            locked: entry.locked,
+        });
+    }
+    if dropped > 0 {
+        log::warn!(log::Span::new("outbox"), dropped = dropped; "Dropped balances of a save of state which never completed");
+    }
+    Ok(pending)
+}
+
+/// Leaves `undelivered` balances in the outbox of state directory `dir`, removing it once
+/// everything was delivered.
+pub fn settle(dir: &Path, undelivered: &[AccountRecord]) -> Result<(), anyhow::Error> {
+    let path = dir.join(FILE);
+    if undelivered.is_empty() {
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        return Ok(());
+    }
+    match state::commit(dir)? {
+        Some(commit) => write(dir, &commit, undelivered),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pending, promote, settle, stage, write};
+    use crate::state::{self, AccountRecord};
+
+    #[test]
+    fn only_balances_of_saved_state_are_pending() {
+        let dir = std::env::temp_dir().join(format!("trp-outbox-{}", std::process::id()));
+        let account = |client, total| AccountRecord {
+            client,
+            available: total,
+            held: 0.0,
+            total,
+            locked: false,
+        };
+        let mut accounts = vec![account(1, 2.0), account(2, 3.0)];
+        state::replace(&dir, &mut accounts, &mut []).unwrap();
+        let commit = state::commit(&dir).unwrap().unwrap();
+        write(&dir, &commit, &accounts).unwrap();
+        assert_eq!(pending(&dir).unwrap(), accounts);
+
+        // A save which never got to replace the accounts file.
+        write(&dir, "0123", &[account(1, 9.0)]).unwrap();
+        assert!(pending(&dir).unwrap().is_empty());
+
+        // A save staged, but stopped before the accounts file was replaced, leaves the outbox
+        // as it was.
+        write(&dir, &commit, &accounts).unwrap();
+        stage(&dir, "0123", &[account(1, 9.0)]).unwrap();
+        assert_eq!(pending(&dir).unwrap(), accounts);
+
+        // A save which replaced the accounts file, but stopped before promoting the outbox.
+        let mut saved = vec![account(1, 4.0), account(2, 3.0)];
+        state::replace(&dir, &mut saved, &mut []).unwrap();
+        stage(&dir, &state::commit(&dir).unwrap().unwrap(), &saved).unwrap();
+        assert_eq!(pending(&dir).unwrap(), saved);
+        promote(&dir).unwrap();
+        assert!(!dir.join("outbox.csv.pending").exists());
+
+        settle(&dir, &saved[1..]).unwrap();
+        assert_eq!(pending(&dir).unwrap(), &saved[1..]);
+        assert_eq!(pending(&dir).unwrap(), &accounts[1..]);
+        settle(&dir, &[]).unwrap();
+        assert!(pending(&dir).unwrap().is_empty());
+        assert!(!dir.join("outbox.csv").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,76 @@
+//! Pacing of messages, enabled with `--rate`: the parser hands messages on to the processor no
+//! faster than the given number of messages per second, so that historical input can be
+//! replayed at production-like speed, e.g. for load testing of what is downstream of trp.
+//! Sources read at once share the rate.
+//!
+//! Messages are spread evenly rather than sent in bursts, and time lost while the processor
+//! pushes back is not made up for later.
+
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+static PACER: Mutex<Option<Pacer>> = Mutex::new(None);
+
+#[derive(Debug)]
+struct Pacer {
+    interval: Duration,
+    /// Earliest moment the next message may be handed on.
+    next: Instant,
+}
+
+/// Parses rate of `--rate`, a positive number of messages per second.
+pub fn rate(text: &str) -> Result<f64, anyhow::Error> {
+    text.parse::<f64>()
+        .ok()
+        .filter(|rate| rate.is_finite() && *rate > 0.0)
+        .ok_or_else(|| anyhow::anyhow!("Invalid rate {text}, expected messages per second above 0"))
+}
+
+/// Paces messages at `rate` messages per second from now on.
+pub fn enable(rate: f64) {
+    *PACER.lock().unwrap_or_else(|err| err.into_inner()) = Some(Pacer {
+        interval: Duration::from_secs_f64(1.0 / rate),
+        next: Instant::now(),
+    });
+}
+
+/// Blocks until the next message may be handed on, returns right away unless pacing is
+/// enabled.
+pub fn wait() {
+    let slot = {
+        let mut pacer = PACER.lock().unwrap_or_else(|err| err.into_inner());
+        let Some(pacer) = pacer.as_mut() else {
+            return;
+        };
+        let slot = pacer.next.max(Instant::now());
+        pacer.next = slot + pacer.interval;
+        slot
+    };
+    let now = Instant::now();
+    if slot > now {
+        thread::sleep(slot - now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{enable, rate, wait};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn messages_are_spread_over_time() {
+        assert_eq!(rate("2.5").unwrap(), 2.5);
+        assert!(rate("0").is_err());
+        assert!(rate("fast").is_err());
+
+        enable(200.0);
+        let started = Instant::now();
+        for _ in 0..11 {
+            wait();
+        }
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+}
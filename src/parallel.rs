@@ -0,0 +1,181 @@
+//! Deserialization of csv input on several threads, enabled with `parser_threads` of the
+//! engine [configuration](crate::config), for inputs which come in faster than a single
+//! thread deserializes them.
+//!
+//! A reading thread splits input into raw records, and hands them out in [`BATCH`]es to
+//! worker threads in turn, which deserialize them into [`Record`]s. Batches are taken back
+//! from workers in the same turn, so records come out of [`ParallelCsvSource`] in input order,
+//! and the parser converts and sends them on to the router as it does records of any other
+//! source: every client keeps the order of its messages, as does the input as a whole.
+
+use std::{
+    io::Read,
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Arc,
+    },
+    thread,
+};
+
+use crate::{
+    format::{self, Source},
+    parser::Record,
+};
+
+/// Records handed to a worker at once.
+pub const BATCH: usize = 256;
+/// Batches queued for and by every worker.
+const QUEUED: usize = 2;
+
+/// Raw record along with the line it starts on and the bytes of input read once it was.
+type Raw = (Result<csv::ByteRecord, csv::Error>, u64, u64);
+/// Deserialized record along with the line it starts on and the bytes of input read once it
+/// was.
+type Parsed = (Result<Record, anyhow::Error>, u64, u64);
+
+/// Csv input deserialized by `threads` workers, see [module](self).
+pub struct ParallelCsvSource {
+    batches: Vec<Receiver<Vec<Parsed>>>,
+    /// Worker the next batch comes from.
+    turn: usize,
+    batch: std::vec::IntoIter<Parsed>,
+    line: u64,
+    position: u64,
+}
+
+impl ParallelCsvSource {
+    /// Starts reading `reader` and deserializing its records on `threads` workers. Threads stop
+    /// once input is read, or this is dropped.
+    pub fn new<R: Read + Send + 'static>(reader: R, threads: usize) -> Self {
+        let mut reader = format::csv_reader(reader);
+        let headers = reader.byte_headers().cloned().map(Arc::new);
+        let mut jobs = Vec::new();
+        let mut batches = Vec::new();
+        for _ in 0..threads.max(1) {
+            let (job_tx, job_rx) = mpsc::sync_channel(QUEUED);
+            let (batch_tx, batch_rx) = mpsc::sync_channel(QUEUED);
+            let headers = headers.as_ref().ok().cloned();
+            thread::spawn(move || work(job_rx, batch_tx, headers));
+            jobs.push(job_tx);
+            batches.push(batch_rx);
+        }
+        thread::spawn(move || match headers {
+            Ok(_) => split(reader, jobs),
+            // Broken headers are reported as the first record, and nothing else is read.
+            Err(err) => {
+                let _ = jobs[0].send(vec![(Err(err), 1, 0)]);
+            }
+        });
+        ParallelCsvSource {
+            batches,
+            turn: 0,
+            batch: Vec::new().into_iter(),
+            line: 0,
+            position: 0,
+        }
+    }
+}
+
+impl Source for ParallelCsvSource {
+    fn next_record(&mut self) -> Option<Result<Record, anyhow::Error>> {
+        let (record, line, position) = match self.batch.next() {
+            Some(parsed) => parsed,
+            None => {
+                // Once a worker is gone without a batch of its turn, input is read.
+                self.batch = self.batches[self.turn].recv().ok()?.into_iter();
+                self.turn = (self.turn + 1) % self.batches.len();
+                self.batch.next()?
+            }
+        };
+        self.line = line;
+        self.position = position;
+        Some(record)
+    }
+
+    fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn line(&self) -> u64 {
+        self.line
+    }
+}
+
+/// Reads raw records of `reader`, handing them out to `jobs` in turn, a batch at a time.
+fn split<R: Read>(mut reader: csv::Reader<R>, jobs: Vec<SyncSender<Vec<Raw>>>) {
+    for job in jobs.iter().cycle() {
+        let mut batch = Vec::with_capacity(BATCH);
+        while batch.len() < BATCH {
+            let line = reader.position().line();
+            let mut record = csv::ByteRecord::new();
+            match reader.read_byte_record(&mut record) {
+                Ok(false) => break,
+                Ok(true) => batch.push((Ok(record), line, reader.position().byte())),
+                Err(err) => batch.push((Err(err), line, reader.position().byte())),
+            }
+        }
+        let last = batch.len() < BATCH;
+        if (!batch.is_empty() && job.send(batch).is_err()) || last {
+            return;
+        }
+    }
+}
+
+/// Deserializes batches of `jobs` with `headers`, sending them on to `batches`.
+fn work(
+    jobs: Receiver<Vec<Raw>>,
+    batches: SyncSender<Vec<Parsed>>,
+    headers: Option<Arc<csv::ByteRecord>>,
+) {
+    for job in jobs {
+        let batch = job
+            .into_iter()
+            .map(|(record, line, position)| {
+                let record = match (record, headers.as_deref()) {
+                    (Ok(record), Some(headers)) => format::deserialize(headers, &record),
+                    (Ok(record), None) => record.deserialize(None).map_err(Into::into),
+                    (Err(err), _) => Err(err.into()),
+                };
+                (record, line, position)
+            })
+            .collect();
+        if batches.send(batch).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ParallelCsvSource, BATCH};
+    use crate::format::{CsvSource, Source};
+
+    fn records(source: &mut dyn Source) -> Vec<(String, u64)> {
+        std::iter::from_fn(|| {
+            let record = source.next_record()?;
+            Some((
+                record.map_or_else(|err| err.to_string(), |record| record.tx.to_string()),
+                source.line(),
+            ))
+        })
+        .collect()
+    }
+
+    #[test]
+    fn records_keep_input_order() {
+        let mut input = String::from("type,client,tx,amount\n");
+        for tx in 1..=BATCH * 5 + 7 {
+            input.push_str(&format!("deposit,{},{tx},1.0\n", tx % 3));
+            if tx % 100 == 0 {
+                input.push_str("deposit,1,oops,1.0\n");
+            }
+        }
+        let expected = records(&mut CsvSource::new(std::io::Cursor::new(input.clone())));
+        let mut parallel = ParallelCsvSource::new(std::io::Cursor::new(input.clone()), 3);
+        assert_eq!(records(&mut parallel), expected);
+        assert_eq!(parallel.position(), input.len() as u64);
+
+        let empty = ParallelCsvSource::new(std::io::Cursor::new(""), 2);
+        assert_eq!(records(&mut { empty }), []);
+    }
+}
@@ -0,0 +1,213 @@
+//! What the parser does with rows it can't turn into a message, chosen with
+//! `--on-parse-error`: rows which can't be decoded (`PR_CSV`) and records which don't make up
+//! a valid message (`PR_INVLD`) are treated the same way.
+//!
+//! - `skip`, the default, logs and counts them, and reads on.
+//! - `quarantine` also appends them to the file given with `--quarantine`: csv of `source`
+//!   and `line` of the row, `reason` and `error`, followed by `type`, `client`, `tx` and
+//!   `amount` when the row could be decoded. The file is recreated by every run.
+//! - `abort` stops reading input at the first of them.
+//! - `abort-after:N` skips up to `N` of them, and stops reading input at the next one.
+//!
+//! Once reading is stopped, messages parsed so far are still applied, but the run fails, see
+//! [`check`].
+
+use serde::Serialize;
+use std::{
+    fmt::Display,
+    fs::File,
+    io::BufWriter,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+};
+
+use crate::{log, parser::Record, provenance::Provenance};
+
+static POLICY: OnceLock<Policy> = OnceLock::new();
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+/// Why reading was stopped, once it was.
+static ABORTED: Mutex<Option<String>> = Mutex::new(None);
+/// Whether [`ABORTED`] holds a reason, checked for every row.
+static STOPPED: AtomicBool = AtomicBool::new(false);
+static QUARANTINE: Mutex<Option<csv::Writer<BufWriter<File>>>> = Mutex::new(None);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    #[default]
+    Skip,
+    Quarantine,
+    Abort,
+    /// Stop reading once more than this many rows failed.
+    AbortAfter(u64),
+}
+
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Policy::Skip),
+            "quarantine" => Ok(Policy::Quarantine),
+            "abort" => Ok(Policy::Abort),
+            other => match other.strip_prefix("abort-after:").map(str::parse) {
+                Some(Ok(count)) => Ok(Policy::AbortAfter(count)),
+                _ => Err(anyhow::anyhow!(
+                    "Unknown parse error policy {other}, expected skip, quarantine, abort or abort-after:N"
+                )),
+            },
+        }
+    }
+}
+
+impl Display for Policy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Policy::Skip => f.write_str("skip"),
+            Policy::Quarantine => f.write_str("quarantine"),
+            Policy::Abort => f.write_str("abort"),
+            Policy::AbortAfter(count) => write!(f, "abort-after:{count}"),
+        }
+    }
+}
+
+/// Row of the quarantine file.
+#[derive(Debug, Serialize)]
+struct Quarantined<'a> {
+    source: &'a str,
+    line: u64,
+    reason: &'static str,
+    error: String,
+    #[serde(rename = "type")]
+    kind: Option<&'a str>,
+    client: Option<String>,
+    tx: Option<String>,
+    amount: Option<f32>,
+}
+
+/// Applies `policy` to rows failing to parse from now on, quarantining them to `quarantine`
+/// when it's given. Only the first call has effect.
+pub fn enable(policy: Policy, quarantine: Option<&Path>) -> Result<(), anyhow::Error> {
+    if let Some(path) = quarantine {
+        let out = csv::Writer::from_writer(BufWriter::new(File::create(path)?));
+        *QUARANTINE.lock().unwrap_or_else(|err| err.into_inner()) = Some(out);
+    }
+    let _ = POLICY.set(policy);
+    Ok(())
+}
+
+/// Handles row read from `provenance` which failed to parse with `reason` code, along with
+/// its `record` when it could be decoded. Returns `false` when reading must stop.
+pub fn failed(
+    provenance: &Provenance,
+    reason: &'static str,
+    error: impl Display,
+    record: Option<&Record>,
+) -> bool {
+    let errors = ERRORS.fetch_add(1, Ordering::Relaxed) + 1;
+    let policy = POLICY.get().copied().unwrap_or_default();
+    let stop = match policy {
+        Policy::Skip => false,
+        Policy::Quarantine => {
+            if let Err(err) = quarantine(provenance, reason, &error, record) {
+                log::error!(
+                    log::Span::new("parse"),
+                    "Failed to append to quarantine: {err}"
+                );
+            }
+            false
+        }
+        Policy::Abort => true,
+        Policy::AbortAfter(count) => errors > count,
+    };
+    if stop {
+        ABORTED
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .get_or_insert_with(|| {
+                format!(
+                    "Stopped reading input at {}:{} with --on-parse-error {policy}, {reason} {error}",
+                    provenance.source, provenance.line
+                )
+            });
+        STOPPED.store(true, Ordering::Relaxed);
+    }
+    !stop
+}
+
+fn quarantine(
+    provenance: &Provenance,
+    reason: &'static str,
+    error: &impl Display,
+    record: Option<&Record>,
+) -> Result<(), anyhow::Error> {
+    let mut quarantine = QUARANTINE.lock().unwrap_or_else(|err| err.into_inner());
+    let Some(out) = quarantine.as_mut() else {
+        return Ok(());
+    };
+    out.serialize(Quarantined {
+        source: &provenance.source,
+        line: provenance.line,
+        reason,
+        error: error.to_string(),
+        kind: record.map(|record| record.kind.as_str()),
+        client: record.map(|record| log::id("client", record.client)),
+        tx: record.map(|record| log::id("tx", record.tx)),
+        amount: record.and_then(|record| record.amount),
+    })?;
+    Ok(())
+}
+
+/// Returns `true` once reading was stopped, so that other sources stop too.
+pub fn aborted() -> bool {
+    STOPPED.load(Ordering::Relaxed)
+}
+
+/// Fails when reading was stopped, so that the process exits with non-zero code.
+pub fn check() -> Result<(), anyhow::Error> {
+    match &*ABORTED.lock().unwrap_or_else(|err| err.into_inner()) {
+        Some(reason) => Err(anyhow::anyhow!("{reason}")),
+        None => Ok(()),
+    }
+}
+
+/// Forgets rows which failed so far, and why reading was stopped, so that the next run reads
+/// its input in full, see [`pipeline`](crate::pipeline).
+pub fn reset() {
+    ERRORS.store(0, Ordering::Relaxed);
+    *ABORTED.lock().unwrap_or_else(|err| err.into_inner()) = None;
+    STOPPED.store(false, Ordering::Relaxed);
+}
+
+/// Flushes and closes the quarantine file.
+pub fn close() -> Result<(), anyhow::Error> {
+    if let Some(mut out) = QUARANTINE
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .take()
+    {
+        out.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Policy;
+
+    #[test]
+    fn policies_are_parsed() {
+        assert_eq!("skip".parse::<Policy>().unwrap(), Policy::Skip);
+        assert_eq!("abort".parse::<Policy>().unwrap(), Policy::Abort);
+        assert_eq!(
+            "abort-after:10".parse::<Policy>().unwrap(),
+            Policy::AbortAfter(10)
+        );
+        assert_eq!(Policy::AbortAfter(10).to_string(), "abort-after:10");
+        assert!("abort-after:".parse::<Policy>().is_err());
+        assert!("ignore".parse::<Policy>().is_err());
+    }
+}
@@ -1,12 +1,200 @@
 //! Parses input from csv in a separate thread via [`parser::start`](start).
 
-const PARSER_CHAN_SIZE: usize = 100;
+/// Error code of rows which can't be decoded as [`Record`].
+pub const CSV_ERROR: &str = "PR_CSV";
+/// Error code of records which do not make up a valid [`Message`].
+pub const INVALID_RECORD: &str = "PR_INVLD";
 
-use serde::Deserialize;
-use std::path::Path;
-use tokio::sync::mpsc::Receiver;
+use serde::{Deserialize, Deserializer};
+use std::{
+    fmt::Display,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+use tokio::sync::mpsc::{Receiver, Sender};
 
-use crate::Message;
+#[cfg(feature = "otel")]
+use crate::otel;
+use crate::{
+    backfill::{self, Side},
+    config::{self, Engine},
+    dlq, event_log,
+    format::{self, Format, Source},
+    log, memory,
+    metrics::{self, Channel, Stage},
+    ordering, pacing, parse_errors, progress,
+    provenance::Provenance,
+    sample, send_errors, signature, two_pass, Message,
+};
+
+/// Currency symbols lenient amounts may start or end with.
+const CURRENCY_SYMBOLS: [char; 4] = ['$', '€', '£', '¥'];
+
+/// Minor units in a major one, e.g. cents in a dollar.
+const MINOR_UNITS: f64 = 100.0;
+
+static LENIENT_AMOUNTS: AtomicBool = AtomicBool::new(false);
+static AMOUNTS_IN_MINOR_UNITS: AtomicBool = AtomicBool::new(false);
+
+/// Unit amounts of input are given in, chosen with `--amount-unit`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AmountUnit {
+    /// Decimal numbers of major units, e.g. `12.34` dollars.
+    #[default]
+    Major,
+    /// Integers of minor units, e.g. `1234` cents.
+    Minor,
+}
+
+impl FromStr for AmountUnit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "major" => Ok(AmountUnit::Major),
+            "minor" => Ok(AmountUnit::Minor),
+            other => Err(anyhow::anyhow!(
+                "Unknown amount unit {other}, expected major or minor"
+            )),
+        }
+    }
+}
+
+impl Display for AmountUnit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AmountUnit::Major => "major",
+            AmountUnit::Minor => "minor",
+        })
+    }
+}
+
+/// Accepts amounts as spreadsheets export them from now on, see [`amount`].
+pub fn lenient_amounts() {
+    LENIENT_AMOUNTS.store(true, Ordering::Relaxed);
+}
+
+/// Reads amounts in `unit` from now on, see [`amount`].
+pub fn amount_unit(unit: AmountUnit) {
+    AMOUNTS_IN_MINOR_UNITS.store(unit == AmountUnit::Minor, Ordering::Relaxed);
+}
+
+/// Parses amount of a deposit or withdrawal. Once [`lenient_amounts`] are enabled, it may
+/// also have a currency symbol, e.g. `$1234.56` or `1234.56 €`, and thousands separated by
+/// commas, e.g. `1,234.56`. Commas must separate groups of three digits before the decimal
+/// point, so `1,5` is not taken for one and a half.
+///
+/// With amounts in [`AmountUnit::Minor`], it must be an integer, e.g. `1234` for 12.34, and is
+/// never parsed as a float. Amounts too large to keep to the minor unit, e.g. `123456789`, are
+/// rejected rather than rounded.
+pub fn amount(text: &str) -> Result<f32, anyhow::Error> {
+    let unit = match AMOUNTS_IN_MINOR_UNITS.load(Ordering::Relaxed) {
+        true => AmountUnit::Minor,
+        false => AmountUnit::Major,
+    };
+    parse_amount(text, LENIENT_AMOUNTS.load(Ordering::Relaxed), unit)
+}
+
+fn parse_amount(text: &str, lenient: bool, unit: AmountUnit) -> Result<f32, anyhow::Error> {
+    let invalid = || anyhow::anyhow!("Invalid amount {text:?}");
+    let parse = |number: &str| match unit {
+        AmountUnit::Major => number.parse().map_err(|_| invalid()),
+        AmountUnit::Minor => {
+            let minor = number.parse::<i64>().map_err(|_| invalid())?;
+            let amount = (minor as f64 / MINOR_UNITS) as f32;
+            match (f64::from(amount) * MINOR_UNITS).round() == minor as f64 {
+                true => Ok(amount),
+                false => Err(anyhow::anyhow!(
+                    "Amount {text:?} can't be kept to the minor unit"
+                )),
+            }
+        }
+    };
+    if !lenient {
+        return parse(text);
+    }
+    let number = text
+        .trim()
+        .trim_start_matches(CURRENCY_SYMBOLS)
+        .trim_end_matches(CURRENCY_SYMBOLS)
+        .trim();
+    let (integer, fraction) = match number.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (number, None),
+    };
+    let mut groups = integer.split(',');
+    let first = groups.next().unwrap_or_default();
+    let grouped = groups.all(|group| group.len() == 3);
+    if integer.contains(',') && !(grouped && (1..=3).contains(&first.len())) {
+        return Err(invalid());
+    }
+    let mut normalized = integer.replace(',', "");
+    if let Some(fraction) = fraction {
+        normalized.push('.');
+        normalized.push_str(fraction);
+    }
+    parse(&normalized)
+}
+
+/// Deserializes optional amount with [`amount`], empty when there is none.
+fn deserialize_amount<'de, D>(deserializer: D) -> Result<Option<f32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .filter(|text| !text.is_empty())
+        .map(|text| amount(&text).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Transaction types, as they appear in the `type` column of the input.
+const KINDS: [&str; 6] = [
+    "deposit",
+    "withdrawal",
+    "dispute",
+    "resolve",
+    "chargeback",
+    "settle",
+];
+
+/// Type of transaction named `kind`, regardless of case and surrounding whitespace, as
+/// hand-edited files have them. Unknown types fail with the closest known one, if any is
+/// close enough to be a typo.
+pub(crate) fn kind(kind: &str) -> Result<&'static str, anyhow::Error> {
+    let normalized = kind.trim().to_ascii_lowercase();
+    if let Some(known) = KINDS.iter().find(|known| **known == normalized) {
+        return Ok(known);
+    }
+    let closest = KINDS
+        .iter()
+        .map(|known| (distance(&normalized, known), known))
+        .filter(|(distance, _)| *distance <= 2)
+        .min();
+    Err(match closest {
+        Some((_, known)) => anyhow::anyhow!("Unknown type {kind:?}, did you mean {known:?}?"),
+        None => anyhow::anyhow!("Unknown type {kind:?}"),
+    })
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
 
 impl TryFrom<&Record> for Message {
     type Error = anyhow::Error;
@@ -17,60 +205,355 @@ impl TryFrom<&Record> for Message {
             client,
             tx,
             amount,
+            timestamp,
+            effective_date,
+            ..
         } = record;
         let client = *client;
         let tx = *tx;
         let amount = *amount;
+        let timestamp = *timestamp;
+        let effective_date = *effective_date;
 
-        match (kind.as_str(), amount) {
-            ("deposit", Some(amount)) => Ok(Message::Deposit { client, tx, amount }),
-            ("withdrawal", Some(amount)) => Ok(Message::Withdraw { client, tx, amount }),
-            ("dispute", None) => Ok(Message::Dispute { client, tx }),
-            ("resolve", None) => Ok(Message::Resolve { client, tx }),
-            ("chargeback", None) => Ok(Message::Chargeback { client, tx }),
+        match (self::kind(kind)?, amount) {
+            ("deposit", Some(amount)) => Ok(Message::Deposit {
+                client,
+                tx,
+                amount,
+                timestamp,
+                effective_date,
+            }),
+            // Only deposits can be value-dated.
+            (_, _) if effective_date.is_some() => Err(anyhow::anyhow!("Invalid record")),
+            ("withdrawal", Some(amount)) => Ok(Message::Withdraw {
+                client,
+                tx,
+                amount,
+                timestamp,
+            }),
+            ("dispute", None) => Ok(Message::Dispute {
+                client,
+                tx,
+                timestamp,
+            }),
+            ("resolve", None) => Ok(Message::Resolve {
+                client,
+                tx,
+                timestamp,
+            }),
+            ("chargeback", None) => Ok(Message::Chargeback {
+                client,
+                tx,
+                timestamp,
+            }),
+            ("settle", None) => Ok(Message::Settle {
+                client,
+                tx,
+                timestamp,
+            }),
             _ => Err(anyhow::anyhow!("Invalid record")),
         }
     }
 }
 
+/// Message of a single csv row without header, `<type>,<client>,<tx>[,<amount>]`, e.g.
+/// `withdrawal,7,42,50.0`.
+pub fn message(row: &str) -> Result<Message, anyhow::Error> {
+    let csv = format!("type,client,tx,amount\n{row}");
+    let record: Record = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(csv.as_bytes())
+        .deserialize()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Missing transaction"))?
+        .map_err(|err| anyhow::anyhow!("Invalid transaction {row}: {err}"))?;
+    Message::try_from(&record).map_err(|err| anyhow::anyhow!("Invalid transaction {row}: {err}"))
+}
+
+/// Row of the input, as it appears in csv, or any other [`Format`](crate::format::Format).
 #[derive(Debug, Deserialize)]
-struct Record {
+pub struct Record {
     #[serde(rename = "type")]
-    kind: String,
-    client: u16,
-    tx: u32,
-    amount: Option<f32>,
+    pub kind: String,
+    pub client: u16,
+    pub tx: u32,
+    #[serde(default, deserialize_with = "deserialize_amount")]
+    pub amount: Option<f32>,
+    /// Milliseconds since unix epoch, when the input carries them.
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// Milliseconds since unix epoch when funds of a deposit become available, when it is
+    /// value-dated.
+    #[serde(default)]
+    pub effective_date: Option<u64>,
+    /// HMAC of the other fields, see [`signature`](crate::signature).
+    #[serde(default)]
+    pub signature: Option<String>,
+    /// Text the signature covers, as the source read it, kept only while signatures are
+    /// verified.
+    #[serde(skip)]
+    pub signed: Option<String>,
+    /// Free-text reference of the upstream system, see [`Provenance`].
+    #[serde(default)]
+    pub reference: Option<String>,
+    /// Id the upstream system correlates its submissions with, see [`Provenance`].
+    #[serde(default)]
+    pub correlation_id: Option<String>,
+    /// W3C trace context of the upstream producer, when the input carries one.
+    #[cfg(feature = "otel")]
+    #[serde(default)]
+    pub traceparent: Option<String>,
+}
+
+/// Message on its way from parser to processor, along with where it was read from.
+pub type Parsed = (Message, Provenance);
+
+/// Creates channel from parser to processor, as large as `engine` says.
+pub fn channel(engine: Engine) -> (Sender<Parsed>, Receiver<Parsed>) {
+    tokio::sync::mpsc::channel(engine.parser_channel_size)
 }
 
-/// Spawns separate thread for reading csv.
+/// Spawns separate thread for reading input, in [`Format`] given by its extension.
 /// Simpler design would be to `read -> parse -> handle transaction` in a single loop,
 /// chosen approach scales better for concurrent handling of parsed transactions, as well as
 /// larger data sets (i.e. transaction history does not have to be stored in one place).
-pub fn start<P>(input: P) -> Result<Receiver<Message>, anyhow::Error>
+pub fn start<P>(input: P) -> Result<Receiver<Parsed>, anyhow::Error>
 where
     P: AsRef<Path>,
 {
-    let mut rdr = csv::ReaderBuilder::new().from_path(input)?;
+    let span = log::Span::new("parse").with("file", input.as_ref().display());
+    let total_bytes = std::fs::metadata(&input)?.len();
+    let engine = config::engine();
+    let mut source = format::source(input.as_ref(), Format::of(input.as_ref())?, engine)?;
+    let origin = input.as_ref().display().to_string();
 
-    let (tx, rx) = tokio::sync::mpsc::channel(PARSER_CHAN_SIZE);
+    let (tx, rx) = channel(engine);
 
     std::thread::spawn(move || {
-        for result in rdr.deserialize() {
-            let record: Record = if let Err(err) = result {
-                eprintln!("Failed to parse record: {err}");
+        progress::start(total_bytes);
+        read(source.as_mut(), &origin, None, &span, &tx, engine);
+        progress::finish();
+        log::info!(span, rows = progress::snapshot().rows(); "Finished reading input");
+    });
+
+    Ok(rx)
+}
+
+/// Reads `source` until it's exhausted, sending every valid message to `tx`, a [`channel`] of
+/// `engine`, with `origin` as the source of its [`Provenance`]. Messages `side` of a
+/// [`backfill`] does not take are skipped. Stops early when [`parse_errors`] policy says so.
+/// Blocks, so should be called outside of async context.
+pub fn read(
+    source: &mut dyn Source,
+    origin: &str,
+    side: Option<Side>,
+    span: &log::Span,
+    tx: &Sender<Parsed>,
+    engine: Engine,
+) {
+    let chan_size = engine.parser_channel_size;
+    let origin: Arc<str> = Arc::from(origin);
+    ordering::opened(&origin);
+    loop {
+        let started = Instant::now();
+        if parse_errors::aborted() || memory::exceeded() {
+            break;
+        }
+        let Some(result) = source.next_record() else {
+            break;
+        };
+        progress::row(source.position());
+        let mut provenance = Provenance {
+            source: origin.clone(),
+            line: source.line(),
+            reference: None,
+            correlation_id: None,
+        };
+        let record = match result {
+            Ok(record) => record,
+            Err(err) => {
+                log::warn!(span, line = provenance.line, reason = CSV_ERROR; "Failed to parse record: {err}");
+                metrics::parse_error(CSV_ERROR);
+                if !parse_errors::failed(&provenance, CSV_ERROR, err, None) {
+                    log::error!(span, line = provenance.line; "Stopped reading input");
+                    break;
+                }
                 continue;
-            } else {
-                result.unwrap()
-            };
-
-            if let Ok(message) = Message::try_from(&record) {
-                tx.blocking_send(message)
-                    .unwrap_or_else(|err| eprintln!("Failed to send from csv: {err}"));
-            } else {
-                eprintln!("Parsed record, but it is invalid: {record:?}");
+            }
+        };
+        provenance.reference = record.reference.as_deref().map(Arc::from);
+        provenance.correlation_id = record.correlation_id.as_deref().map(Arc::from);
+        let correlated;
+        let span = match &provenance.correlation_id {
+            Some(id) => {
+                correlated = span.clone().with("correlation_id", id);
+                &correlated
+            }
+            None => span,
+        };
+
+        #[cfg(feature = "otel")]
+        let otel_span = record
+            .traceparent
+            .as_deref()
+            .and_then(otel::TraceContext::parse)
+            .map(|parent| otel::Span::start("trp.parse", parent));
+
+        match Message::try_from(&record) {
+            Ok(message) => {
+                if let Err(reason) = signature::verify(&record) {
+                    log::warn!(span, line = provenance.line, client = record.client, tx = record.tx, kind = record.kind, reason = signature::INVALID; "Rejected record, {reason}");
+                    metrics::parse_error(signature::INVALID);
+                    if let Err(err) = dlq::append(&message, &provenance, signature::INVALID) {
+                        log::error!(span, "Failed to append to dead letter queue: {err}");
+                    }
+                    continue;
+                }
+                if side.is_some_and(|side| !backfill::takes(side, &message)) {
+                    log::debug!(span, line = provenance.line, client = message.client_id(), tx = message.transaction_id(), kind = message.kind(); "Skipped message on the other side of cutover");
+                    continue;
+                }
+                if !sample::takes(message.client_id()) {
+                    continue;
+                }
+                metrics::latency(Stage::Parse, started.elapsed());
+                log::debug!(span, client = message.client_id(), tx = message.transaction_id(), kind = message.kind(); "Parsed message");
+                metrics::message(&message);
+                if let Err(code) = two_pass::check(&message, &provenance) {
+                    log::warn!(span, line = provenance.line, client = message.client_id(), tx = message.transaction_id(), kind = message.kind(), reason = code; "Rejected message, first pass of input contradicts it");
+                    metrics::reject(code);
+                    if let Err(err) = dlq::append(&message, &provenance, code) {
+                        log::error!(span, "Failed to append to dead letter queue: {err}");
+                    }
+                    continue;
+                }
+                if let Err(err) = event_log::append(&message, &provenance) {
+                    log::error!(span, "Failed to append to event log: {err}");
+                }
+                pacing::wait();
+                if let Err(err) = tx.blocking_send((message, provenance)) {
+                    let (message, provenance) = err.0;
+                    log::error!(span, line = provenance.line, client = message.client_id(), tx = message.transaction_id(), kind = message.kind(), reason = send_errors::SEND_FAILED; "Failed to send message, router is gone");
+                    metrics::reject(send_errors::SEND_FAILED);
+                    if let Err(err) = dlq::append(&message, &provenance, send_errors::SEND_FAILED) {
+                        log::error!(span, "Failed to append to dead letter queue: {err}");
+                    }
+                    if !send_errors::failed(
+                        Channel::Parser,
+                        format_args!("message read from {provenance}"),
+                    ) {
+                        log::error!(span, line = provenance.line; "Stopped reading input");
+                        break;
+                    }
+                    continue;
+                }
+                metrics::channel_depth(Channel::Parser, chan_size - tx.capacity());
+                #[cfg(feature = "otel")]
+                if let Some(mut otel_span) = otel_span {
+                    otel_span.attribute("client", record.client);
+                    otel_span.attribute("tx", record.tx);
+                    otel_span.attribute("kind", &record.kind);
+                    otel_span.end();
+                }
+            }
+            Err(err) => {
+                log::warn!(span, line = provenance.line, client = record.client, tx = record.tx, kind = record.kind, amount = record.amount.map(|amount| amount.to_string()).unwrap_or_default(), reason = INVALID_RECORD; "Parsed record, but it is invalid: {err}");
+                metrics::parse_error(INVALID_RECORD);
+                if !parse_errors::failed(&provenance, INVALID_RECORD, err, Some(&record)) {
+                    log::error!(span, line = provenance.line; "Stopped reading input");
+                    break;
+                }
             }
         }
-    });
+    }
+    ordering::closed(&origin);
+}
 
-    Ok(rx)
+#[cfg(test)]
+mod tests {
+    use super::Record;
+    use crate::Message;
+
+    fn record(kind: &str) -> Record {
+        Record {
+            kind: kind.to_string(),
+            client: 1,
+            tx: 1,
+            amount: Some(1.0),
+            timestamp: None,
+            effective_date: None,
+            signature: None,
+            signed: None,
+            reference: None,
+            correlation_id: None,
+            #[cfg(feature = "otel")]
+            traceparent: None,
+        }
+    }
+
+    #[test]
+    fn lenient_amounts_are_normalized() {
+        use super::{parse_amount, AmountUnit};
+
+        let lenient = |text| parse_amount(text, true, AmountUnit::Major);
+        assert!(parse_amount("$1,234.56", false, AmountUnit::Major).is_err());
+        for (text, expected) in [
+            ("$1,234.56", 1234.56),
+            ("1234.56 €", 1234.56),
+            ("£1,000,000", 1_000_000.0),
+            (" 12.5 ", 12.5),
+            ("0.5", 0.5),
+        ] {
+            assert_eq!(lenient(text).unwrap(), expected, "{text:?}");
+        }
+        for text in ["1,5", "12,34.5", ",123", "$", "1.2.3", "USD 5"] {
+            assert!(lenient(text).is_err(), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn minor_units_are_integers() {
+        use super::{parse_amount, AmountUnit};
+
+        for (text, lenient, expected) in [
+            ("1234", false, 12.34),
+            ("5", false, 0.05),
+            ("$1,234", true, 12.34),
+            ("1677721", false, 16777.21),
+        ] {
+            assert_eq!(
+                parse_amount(text, lenient, AmountUnit::Minor).unwrap(),
+                expected,
+                "{text:?}"
+            );
+        }
+        for text in ["12.34", "1e3", "", "$5", "123456789", "9223372036854775807"] {
+            assert!(
+                parse_amount(text, false, AmountUnit::Minor).is_err(),
+                "{text:?}"
+            );
+        }
+        assert_eq!("minor".parse::<AmountUnit>().unwrap(), AmountUnit::Minor);
+        assert!("cents".parse::<AmountUnit>().is_err());
+    }
+
+    #[test]
+    fn kinds_are_normalized() {
+        for kind in ["deposit", "Deposit", " DEPOSIT ", "\tdeposit"] {
+            assert!(
+                Message::try_from(&record(kind)).unwrap().is_deposit(),
+                "{kind:?}"
+            );
+        }
+        let err = Message::try_from(&record("Depost")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"Unknown type "Depost", did you mean "deposit"?"#
+        );
+        let err = Message::try_from(&record("withdraw")).unwrap_err();
+        assert!(err.to_string().ends_with(r#"did you mean "withdrawal"?"#));
+        let err = Message::try_from(&record("transfer")).unwrap_err();
+        assert_eq!(err.to_string(), r#"Unknown type "transfer""#);
+    }
 }
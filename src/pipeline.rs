@@ -0,0 +1,174 @@
+//! Assembly of a run from its parts, for library users who need a topology of their own
+//! rather than one of the commands, e.g. several sources or accounts written to several
+//! sinks at once:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), anyhow::Error> {
+//! use trp::{config::Engine, pipeline::{Pipeline, Stdout}};
+//!
+//! let summary = Pipeline::builder()
+//!     .input("in.csv")
+//!     .input("late.ndjson")
+//!     .engine(Engine::default())
+//!     .sink(Stdout::new())
+//!     .run()
+//!     .await?;
+//! eprintln!("{summary}");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Every source is read on a thread of its own, as `trp serve` reads its connections, and
+//! messages of all of them go to the same accounts, ordered as `ordering` of the
+//! [configuration](crate::config) says. Accounts are written to every sink, to stdout when
+//! there is none. The run goes on whatever runtime `run` is awaited on, which needs the
+//! multi-threaded scheduler.
+//!
+//! The engine given to a run is only its own, so runs of one process may each have another
+//! one. Other settings live in global state, as they do for commands. Runs of one process
+//! take turns, and each starts from fresh counters, so that its summary, and the parse and
+//! send errors it fails for, are only its own.
+
+use std::{
+    path::{Path, PathBuf},
+    thread,
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    config,
+    format::{self, Format, Source},
+    log, metrics, parse_errors, parser, processor, send_errors,
+    state::Saved,
+    writer,
+};
+pub use crate::{
+    metrics::Summary,
+    writer::{Row, Sharded, Shards, Sink, Stdout, Tee},
+};
+
+/// Held for as long as a run goes, see [module](self).
+static RUN: Mutex<()> = Mutex::const_new(());
+
+/// Input of a run, opened once it starts.
+enum Input {
+    Path(PathBuf),
+    Source(String, Box<dyn Source + Send>),
+}
+
+/// Run assembled from its parts, see [module](self).
+pub struct Pipeline;
+
+impl Pipeline {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+}
+
+/// Parts of a [`Pipeline`], which [`run`](Builder::run)s once they are all given.
+#[derive(Default)]
+pub struct Builder {
+    inputs: Vec<Input>,
+    engine: Option<config::Engine>,
+    sinks: Vec<Box<dyn Sink>>,
+    extended: bool,
+}
+
+impl Builder {
+    /// Reads the file at `path`, in the format of its extension.
+    pub fn input(mut self, path: impl AsRef<Path>) -> Self {
+        self.inputs.push(Input::Path(path.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Reads `source`, with `origin` as the source of its messages in logs, dead letters and
+    /// metrics.
+    pub fn source(mut self, origin: &str, source: impl Source + Send + 'static) -> Self {
+        self.inputs
+            .push(Input::Source(origin.to_string(), Box::new(source)));
+        self
+    }
+
+    /// Tunables of the run, those of the configuration unless given.
+    pub fn engine(mut self, engine: config::Engine) -> Self {
+        self.engine = Some(engine);
+        self
+    }
+
+    /// Writes accounts to `sink`, along with every other sink given.
+    pub fn sink(mut self, sink: impl Sink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// Writes extended rows, see [`writer`](crate::writer).
+    pub fn extended(mut self, extended: bool) -> Self {
+        self.extended = extended;
+        self
+    }
+
+    /// Applies every input, and writes accounts once they are all read. Waits for other runs
+    /// of the process to finish first. Fails when input is missing, when it was not read in
+    /// full, or when accounts could not be written.
+    pub async fn run(self) -> Result<Summary, anyhow::Error> {
+        let _run = RUN.lock().await;
+        metrics::reset();
+        parse_errors::reset();
+        send_errors::reset();
+        let engine = self.engine.unwrap_or_else(config::engine);
+        writer::check_schema(self.extended)?;
+        let mut sources = Vec::with_capacity(self.inputs.len());
+        for input in self.inputs {
+            sources.push(match input {
+                Input::Path(path) => (
+                    path.display().to_string(),
+                    format::source(&path, Format::of(&path)?, engine)?,
+                ),
+                Input::Source(origin, source) => (origin, source),
+            });
+        }
+
+        let (tx, rx) = parser::channel(engine);
+        let readers: Vec<_> = sources
+            .into_iter()
+            .map(|(origin, mut source)| {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    let span = log::Span::new("parse").with("file", &origin);
+                    parser::read(source.as_mut(), &origin, None, &span, &tx, engine);
+                    log::info!(span, "Finished reading input");
+                })
+            })
+            .collect();
+        drop(tx);
+
+        let mut sinks = self.sinks;
+        let sink: Box<dyn Sink> = match sinks.len() {
+            0 => Box::new(Stdout::new()),
+            1 => sinks.remove(0),
+            _ => Box::new(Tee::new(sinks)),
+        };
+        let (done_tx, done_rx) = writer::channel(engine);
+        let writer_handle = writer::start(done_rx, self.extended, sink, engine);
+        processor::start(
+            rx,
+            done_tx,
+            processor::Settings::of(engine),
+            Saved::default(),
+        )
+        .await;
+        tokio::task::spawn_blocking(move || {
+            for reader in readers {
+                reader
+                    .join()
+                    .map_err(|err| anyhow::anyhow!("Reader panic: {err:?}"))?;
+            }
+            writer::join(writer_handle)
+        })
+        .await??;
+
+        parse_errors::check()?;
+        send_errors::check()?;
+        Ok(metrics::summary())
+    }
+}
@@ -1,69 +1,487 @@
 //! Deals with everything related to management of client transactions.
 
-const ACCOUNT_CHAN_SIZE: usize = 100;
-
-use crate::Message;
-use serde::Serialize;
-use std::{collections::HashMap, fmt::Display};
-use tokio::sync::mpsc::{self, Receiver, Sender};
-
-/// Given message is for client who does not have an account yet:
+/// Error code of messages for which account task could not be started.
+const SPAWN_FAILED: &str = "RT_SPAWN";
+/// Error code of messages for clients whose account task is gone.
+const QUARANTINED: &str = "RT_QUAR";
+
+use crate::{
+    alerts, chaos,
+    cli::Velocity,
+    config::{self, Engine},
+    creation, dashboard, dlq,
+    dormant::{self, Eviction, Parked},
+    engine::{Book, Effect, History, Registry, Rejection, Transaction, NO_ACCOUNT},
+    event_log::{self, Lifecycle},
+    flight, grpc,
+    interest::{self, Accrual, Interest},
+    invariants::{self, invariant, Ledger},
+    lag::{Batching, LagDetector},
+    log, memory,
+    metrics::{self, Channel, Stage},
+    ordering::{self, Owners},
+    orphans::{self, Held, Policy},
+    parser::Parsed,
+    protocol::Router,
+    provenance::Provenance,
+    redis,
+    reorder::{self, Buffer},
+    report, reserve,
+    retention::{self, Retention},
+    screening::{self, Screening},
+    send_errors, settlement, snapshots,
+    state::{
+        self, AccountRecord, AccountSnapshot, Saved, TransactionRecord, TransactionState,
+        SNAPSHOT_VERSION,
+    },
+    tiers, top,
+    velocity::{self, Window},
+    Message,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Display,
+    panic::AssertUnwindSafe,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::{error::TryRecvError, Receiver, Sender};
+
+/// Given message is for client who does not have an account yet, whether it creates one, as
+/// [`creation`] policy says. By default:
 /// - When message is [`Message::Withdraw`] - then op would fail, since starting account balance is 0.
 /// - When message is [`Message::Dispute`] | [`Message::Resolve`] | [`Message::Chargeback`] - then op would fail since there is
-/// no previous deposit to dispute/resolve/chargeback.
+///   no previous deposit to dispute/resolve/chargeback.
 /// - When message is [`Message::Deposit`] - then op would succeed.
 pub fn should_create_account(msg: &Message) -> bool {
-    msg.is_deposit()
+    creation::creates(msg)
+}
+
+/// How often the router looks for idle accounts to evict, at most.
+const SWEEP: Duration = Duration::from_secs(1);
+
+/// Settings of a run, taken by the router and handed to every account task it starts.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Messages queued for every account task.
+    pub account_channel_size: usize,
+    /// Final account states queued for the writer.
+    pub result_channel_size: usize,
+    /// Which messages create accounts, see [`creation`].
+    pub account_creation: creation::Policy,
+    /// What account tasks do with disputes of transactions they don't know of.
+    pub unknown_disputes: Policy,
+    /// What account tasks keep in transaction history, see [`retention`].
+    pub retention: Retention,
+    /// How long an account stays idle before it is parked, see [`dormant`].
+    pub ttl: Option<Duration>,
+    /// Which source may send messages of a client, see [`ordering`].
+    pub ordering: ordering::Policy,
+    /// Milliseconds messages are held back to be applied in order, see [`reorder`].
+    pub lateness: Option<u64>,
+    /// Whether messages held back for clients which went quiet are released, for input which
+    /// never ends, see [`reorder`].
+    pub release_idle: bool,
+    /// Rules raising alerts, see [`velocity`](crate::velocity).
+    pub velocity: Option<Velocity>,
+    pub interest: Option<Interest>,
+    /// Whether accounts report snapshots along the way, see [`snapshots`].
+    pub snapshots: bool,
+    /// Whether applied messages are streamed, see [`grpc`].
+    pub grpc: bool,
+    /// Whether faults are injected, see [`chaos`].
+    pub chaos: bool,
+    /// Whether final accounts are recorded in state, see [`state`].
+    pub state: bool,
+}
+
+impl Settings {
+    /// Settings as the configuration and options of the command set them.
+    pub fn current() -> Self {
+        Settings::of(config::engine())
+    }
+
+    /// Settings with tunables of `engine`, rather than the configuration, and options of the
+    /// command.
+    pub fn of(engine: Engine) -> Self {
+        Settings {
+            account_channel_size: engine.account_channel_size,
+            result_channel_size: engine.result_channel_size,
+            account_creation: engine.account_creation,
+            unknown_disputes: engine.unknown_disputes,
+            retention: engine.retention,
+            ttl: dormant::ttl(),
+            ordering: ordering::policy(),
+            lateness: reorder::lateness(),
+            release_idle: false,
+            velocity: velocity::rules(),
+            interest: interest::get(),
+            snapshots: snapshots::enabled(),
+            grpc: grpc::enabled(),
+            chaos: chaos::enabled(),
+            state: state::enabled(),
+        }
+    }
 }
 
 /// Functions as a router for the [`Account`] tasks. Spawns task if there is no task for
-/// client, then forwards message to appropriate task.
+/// client, then forwards message to appropriate task. When a task turns out to be gone, its
+/// client is quarantined: the rest of its messages go to the [`dlq`].
+/// With eviction enabled, tasks of idle accounts are stopped and their accounts parked, see
+/// [`dormant`], to be reloaded by the next message of their client.
+/// Accounts `saved` by earlier runs are resumed by the first message of their client, see
+/// [`state`].
 /// When there is no more input from [`parser::start`](crate::parser::start), exits, causing `clients` to be dropped.
 /// This in return causes all tasks to stop listening for messages and report their stats to
-/// writer thread.
-pub async fn start(mut rx: Receiver<Message>, done_tx: Sender<Account<Running>>) {
-    let mut clients = HashMap::new();
-
-    while let Some(msg) = rx.recv().await {
+/// writer thread, see [`protocol`](crate::protocol).
+pub async fn start(
+    mut rx: Receiver<Parsed>,
+    done_tx: Sender<Account<Running>>,
+    settings: Settings,
+    mut saved: Saved,
+) {
+    let span = log::Span::new("route");
+    let mut clients = Router::new(settings.account_channel_size, settings.result_channel_size);
+    let mut quarantined = HashSet::new();
+    let mut lag = LagDetector::new(settings.account_channel_size);
+    let mut ledger = Ledger::default();
+    let mut owners = Owners::new(settings.ordering);
+    let mut registry = Registry::default();
+    for (tx, client) in saved.owners() {
+        registry.register(tx, client);
+    }
+    let ttl = settings.ttl;
+    // Accounts with a running task, along with the moment they got their last message.
+    let mut resident: HashMap<u16, (Instant, Arc<Eviction>)> = HashMap::new();
+    let mut evicted: HashMap<u16, Arc<Eviction>> = HashMap::new();
+    let mut batching: HashMap<u16, Arc<Batching>> = HashMap::new();
+    let mut swept = Instant::now();
+    // Bytes the registry was last known to hold, see [`memory`].
+    let mut registered = 0;
+
+    while let Some((msg, provenance)) = rx.recv().await {
+        ledger.received();
+        let received = Instant::now();
         let client_id = msg.client_id();
-        if clients.get(&client_id).is_none() {
-            if !should_create_account(&msg) {
-                eprintln!("Got out of order message: {msg:?}, ignoring");
-                continue;
+        let over = memory::over();
+        if over && ttl.is_none() {
+            exceed_memory(&span);
+        }
+        if let Some(ttl) = ttl.filter(|ttl| swept.elapsed() >= SWEEP.min(*ttl)) {
+            swept = received;
+            // Over --max-memory, the least recently active half of accounts goes along with
+            // idle ones, until there is nothing left to spill.
+            let mut idle = ttl;
+            if over && resident.is_empty() {
+                exceed_memory(&span);
+            } else if over {
+                log::warn!(span, used = memory::used(), accounts = resident.len(); "Memory went over --max-memory, spilling accounts to disk");
+                let mut idles = resident
+                    .values()
+                    .map(|(last, _)| received.duration_since(*last))
+                    .collect::<Vec<_>>();
+                let half = idles.len() / 2;
+                idle = idle.min(*idles.select_nth_unstable(half).1);
             }
-
-            let account = Account::new(client_id);
-            match account.start(done_tx.clone()) {
-                Ok(client_tx) => {
-                    clients.insert(client_id, client_tx);
+            resident.retain(|client, (last, eviction)| {
+                if received.duration_since(*last) < idle {
+                    return true;
                 }
+                eviction.start();
+                clients.close(client);
+                batching.remove(client);
+                evicted.insert(*client, eviction.clone());
+                log::debug!(span, client = *client; "Evicting idle account");
+                false
+            });
+        }
+        if chaos::should_drop() {
+            log::warn!(span, client = client_id, tx = msg.transaction_id(), kind = msg.kind(), source = provenance, reason = chaos::DROPPED; "Dropped message");
+            metrics::unroutable(chaos::DROPPED);
+            ledger.settled();
+            continue;
+        }
+        let screening = screening::screen(&msg);
+        if let Some(code) = screening.code() {
+            log::warn!(span, client = client_id, tx = msg.transaction_id(), kind = msg.kind(), source = provenance, reason = code; "Screened out message");
+            metrics::unroutable(code);
+            dashboard::rejected(code, client_id, msg.transaction_id());
+            top::rejected(client_id);
+            if screening == Screening::Review {
+                if let Err(err) = dlq::REVIEW.append(&msg, &provenance, code) {
+                    log::error!(span, "Failed to append to review queue: {err}");
+                }
+            }
+            ledger.settled();
+            continue;
+        }
+        if quarantined.contains(&client_id) {
+            dead_letter(&span, &msg, &provenance, QUARANTINED);
+            ledger.settled();
+            continue;
+        }
+        if !owners.admit(client_id, &provenance) {
+            log::warn!(span, client = client_id, tx = msg.transaction_id(), kind = msg.kind(), source = provenance, reason = ordering::FOREIGN_SOURCE; "Client belongs to another source");
+            dead_letter(&span, &msg, &provenance, ordering::FOREIGN_SOURCE);
+            ledger.settled();
+            continue;
+        }
+        let checked = registry.check(&msg);
+        registered = memory::track(registered, registry.footprint());
+        if let Err(rejection) = checked {
+            let code = rejection.code();
+            log::warn!(span, client = client_id, tx = msg.transaction_id(), kind = msg.kind(), source = provenance, reason = code; "Transaction belongs to another client, ignoring");
+            metrics::unroutable(code);
+            dashboard::rejected(code, client_id, msg.transaction_id());
+            top::rejected(client_id);
+            ledger.settled();
+            continue;
+        }
+        if !clients.contains(&client_id) {
+            let (account, history, origin) = match (
+                evicted.remove(&client_id),
+                saved.take(client_id),
+            ) {
+                (Some(eviction), _) => match reload(client_id, &eviction).await {
+                    Ok((account, history)) => (account, history, Some("Reloaded evicted account")),
+                    Err(err) => {
+                        log::error!(span, client = client_id, reason = QUARANTINED; "Failed to reload evicted account, quarantining client: {err}");
+                        quarantined.insert(client_id);
+                        dead_letter(&span, &msg, &provenance, QUARANTINED);
+                        ledger.settled();
+                        continue;
+                    }
+                },
+                (None, Some((record, transactions))) => {
+                    let (account, history) = Account::restored(&record, transactions);
+                    (account, history, Some("Resumed account from state"))
+                }
+                (None, None) if !creation::creates_under(settings.account_creation, &msg) => {
+                    log::warn!(span, client = client_id, tx = msg.transaction_id(), kind = msg.kind(), source = provenance, reason = NO_ACCOUNT; "Got out of order message, ignoring");
+                    metrics::no_account(&provenance.source);
+                    dead_letter(&span, &msg, &provenance, NO_ACCOUNT);
+                    ledger.settled();
+                    continue;
+                }
+                (None, None) => (Account::new(client_id), History::new(), None),
+            };
+
+            let eviction = Arc::new(Eviction::default());
+            let batched = Arc::new(Batching::default());
+            match account.start(
+                &mut clients,
+                done_tx.clone(),
+                history,
+                &settings,
+                origin.is_none(),
+                eviction.clone(),
+                batched.clone(),
+            ) {
+                Ok(()) => match origin {
+                    Some(origin) => log::debug!(span, client = client_id; "{origin}"),
+                    None => {
+                        log::debug!(span, client = client_id; "Spawned account task");
+                        metrics::account_created();
+                    }
+                },
                 Err(err) => {
-                    eprintln!("Failed to spawn task for account({client_id}) : {err}");
+                    log::error!(span, client = client_id, reason = SPAWN_FAILED; "Failed to spawn account task: {err}");
+                    metrics::unroutable(SPAWN_FAILED);
+                    ledger.settled();
                     continue;
                 }
             };
+            if ttl.is_some() {
+                resident.insert(client_id, (received, eviction));
+            }
+            batching.insert(client_id, batched);
         }
 
-        let tx = clients.get(&client_id).unwrap();
-        if let Err(msg) = tx.send(msg).await {
-            eprintln!("Failed to send {msg} to task for account({client_id})");
+        metrics::latency(Stage::Route, received.elapsed());
+        chaos::delay().await;
+        match clients
+            .send(client_id, (msg, provenance, Instant::now()))
+            .await
+        {
+            Ok(depth) => {
+                ledger.settled();
+                if let Some((last, _)) = resident.get_mut(&client_id) {
+                    *last = Instant::now();
+                }
+                metrics::channel_depth(Channel::Account, depth);
+                if let Some(lagging) = lag.observe(client_id, depth, Instant::now()) {
+                    log::warn!(span, client = client_id, depth = depth, lagging_secs = lagging.as_secs(); "Account channel stays near capacity, switching to batched apply");
+                    metrics::lagging_account();
+                    if let Some(batched) = batching.get(&client_id) {
+                        batched.start();
+                    }
+                }
+            }
+            Err((msg, provenance, _)) => {
+                log::error!(span, client = client_id, reason = QUARANTINED; "Account task is gone, quarantining client");
+                quarantined.insert(client_id);
+                resident.remove(&client_id);
+                batching.remove(&client_id);
+                dead_letter(&span, &msg, &provenance, QUARANTINED);
+                ledger.settled();
+                if !send_errors::failed(
+                    Channel::Account,
+                    format_args!("message read from {provenance} to account of client {client_id}"),
+                ) {
+                    log::error!(span, "Stopped routing messages");
+                    break;
+                }
+            }
+        }
+    }
+
+    ledger.close("Router");
+    memory::track(registered, 0);
+
+    // Evicted accounts are reported like any other, one at a time.
+    for (client, eviction) in evicted {
+        let started = reload(client, &eviction)
+            .await
+            .and_then(|(account, history)| {
+                account.start(
+                    &mut clients,
+                    done_tx.clone(),
+                    history,
+                    &settings,
+                    false,
+                    Arc::default(),
+                    Arc::default(),
+                )
+            });
+        match started {
+            Ok(()) => {
+                clients.close(&client);
+            }
+            Err(err) => {
+                log::error!(span, client = client; "Failed to reload evicted account: {err}");
+            }
+        }
+    }
+
+    log::info!(span, accounts = clients.len(); "Input exhausted, closing account channels");
+}
+
+/// Whether `msg` may be applied in bulk, see [`Account::apply_batch`]: a deposit whose funds
+/// are available right away.
+fn is_bulk_deposit(msg: &Message) -> bool {
+    msg.is_deposit() && msg.effective_date().is_none()
+}
+
+/// Applies `deposits` to an account of their client in batches of `batch`, in bulk as batched
+/// apply does, or one at a time under supervision otherwise. Returns available funds of the
+/// account. Only meant for `benches/bulk_deposits.rs`.
+pub fn apply_deposits(deposits: &[Message], batch: usize, bulk: bool) -> f32 {
+    let mut account = Account {
+        client: deposits.first().map_or(0, Message::client_id),
+        book: Book::default(),
+        activity: None,
+        counters: Counters::default(),
+        pruned: HashSet::new(),
+        _state: Running,
+    };
+    let mut history = History::new();
+    for batch in deposits.chunks(batch) {
+        if bulk {
+            account.supervised_batch(batch, &mut history, Account::apply_batch);
+        } else {
+            for deposit in batch {
+                let _ = account.supervised_apply(deposit, &mut history);
+            }
         }
     }
+    account.book.available
+}
+
+/// Stops reading input once memory went over the limit with nothing left to spill, see
+/// [`memory`].
+fn exceed_memory(span: &log::Span) {
+    if memory::exceed() {
+        log::error!(span, used = memory::used(); "Memory went over --max-memory, stopping reading input");
+    }
+}
+
+/// Loads account of `client` back once its task parked it, see [`dormant`].
+async fn reload(
+    client: u16,
+    eviction: &Eviction,
+) -> Result<(Account<Ready>, History), anyhow::Error> {
+    if !eviction.wait().await {
+        return Err(anyhow::anyhow!("account could not be parked"));
+    }
+    let (parked, transactions) = dormant::unpark(client)?;
+    Account::unparked(parked, transactions)
+}
+
+/// Gives up on `msg` read from `provenance`, counting it as rejected with `code` and appending
+/// it to the [`dlq`].
+fn dead_letter(span: &log::Span, msg: &Message, provenance: &Provenance, code: &'static str) {
+    metrics::unroutable(code);
+    dashboard::rejected(code, msg.client_id(), msg.transaction_id());
+    top::rejected(msg.client_id());
+    if let Err(err) = dlq::append(msg, provenance, code) {
+        log::error!(span, "Failed to append to dead letter queue: {err}");
+    }
 }
 
+/// Rejects dispute `msg` of a transaction its account doesn't know of, see [`orphans`].
+fn unknown_dispute(span: &log::Span, msg: &Message, provenance: &Provenance) {
+    log::warn!(span, tx = msg.transaction_id(), kind = msg.kind(), source = provenance, reason = orphans::UNKNOWN; "Dispute of unknown transaction");
+    metrics::reject(orphans::UNKNOWN);
+    dashboard::rejected(orphans::UNKNOWN, msg.client_id(), msg.transaction_id());
+    top::rejected(msg.client_id());
+    if let Err(err) = dlq::append(msg, provenance, orphans::UNKNOWN) {
+        log::error!(span, "Failed to append to dead letter queue: {err}");
+    }
+}
+
+/// Rejects dispute `msg` of a transaction pruned from history, see [`retention`].
+fn pruned_dispute(span: &log::Span, msg: &Message, provenance: &Provenance) {
+    log::warn!(span, tx = msg.transaction_id(), kind = msg.kind(), source = provenance, reason = retention::PRUNED; "Dispute of transaction pruned from history");
+    metrics::reject(retention::PRUNED);
+    dashboard::rejected(retention::PRUNED, msg.client_id(), msg.transaction_id());
+    top::rejected(msg.client_id());
+    if let Err(err) = event_log::lifecycle(Lifecycle::DisputeOfPruned, msg, provenance) {
+        log::error!(span, "Failed to append to event log: {err}");
+    }
+    if let Err(err) = dlq::append(msg, provenance, retention::PRUNED) {
+        log::error!(span, "Failed to append to dead letter queue: {err}");
+    }
+}
+
+/// Message sent to account task, along with where it was read from and the moment router
+/// started sending it.
+type Queued = (Message, Provenance, Instant);
+
 /// Represents state of the clients account. Generic attribute is used for typestate checks,
 /// to ensure task for account is started only once.
-#[derive(Debug, Serialize)]
+#[derive(Debug)]
 pub struct Account<T> {
     client: u16,
-    available: f32,
-    held: f32,
-    total: f32,
-    locked: bool,
-    #[serde(skip)]
+    /// Balances, applied to by rules of the [`engine`](crate::engine). Minimum balance comes
+    /// from [`reserve`], rules of withdrawals from [`tiers`].
+    book: Book,
+    /// Earliest and latest timestamps of messages which reached the account, applied or not.
+    activity: Option<(u64, u64)>,
+    counters: Counters,
+    /// Transactions pruned from history, see [`retention`].
+    pruned: HashSet<u32>,
     _state: T,
 }
 
+/// Messages of the client which reached the account, see [`Account::zombie`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counters {
+    pub applied: u64,
+    pub rejected: u64,
+    /// Deposits and withdrawals among applied messages.
+    pub transfers: u64,
+}
+
 /// Typestate ZST
 #[derive(Debug)]
 pub struct Running;
@@ -76,192 +494,1072 @@ impl Account<Ready> {
     pub fn new(client: u16) -> Self {
         Account {
             client,
-            available: 0.0,
-            held: 0.0,
-            total: 0.0,
-            locked: false,
+            book: Book::new(
+                settlement::get(),
+                reserve::minimum(client),
+                tiers::rules(client),
+            ),
+            activity: None,
+            counters: Counters::default(),
+            pruned: HashSet::new(),
             _state: Ready,
         }
     }
 
-    /// Starts the task for the account.
+    /// Starts the task for the account, see [`Task`].
     ///
     /// # Panics
     ///
     /// Since function spawns a task, it would panic when called outside of
-    /// runtime context.
+    /// runtime context, unless a [`sim`](crate::sim) is running.
+    /// Task carries on with transaction `history`, logs the account as created if it was just
+    /// `opened`, rather than resumed or reloaded, parks the account instead of reporting it
+    /// once its inbox is closed for `eviction`, and applies messages in batches while
+    /// `batching` is active.
+    #[allow(clippy::too_many_arguments)]
     fn start(
         self,
-        done: mpsc::Sender<Account<Running>>,
-    ) -> Result<mpsc::Sender<Message>, anyhow::Error> {
-        let (tx, mut rx) = mpsc::channel(ACCOUNT_CHAN_SIZE);
-        let mut history: TXHistory = HashMap::new();
+        router: &mut Router<u16, Queued>,
+        done: Sender<Account<Running>>,
+        history: History,
+        settings: &Settings,
+        opened: bool,
+        eviction: Arc<Eviction>,
+        batching: Arc<Batching>,
+    ) -> Result<(), anyhow::Error> {
         let Self {
             client,
-            available,
-            held,
-            total,
-            locked,
+            book,
+            activity,
+            counters,
+            pruned,
             _state,
         } = self;
-        let mut account = Account {
+        let account = Account {
             client,
-            available,
-            held,
-            total,
-            locked,
+            book,
+            activity,
+            counters,
+            pruned,
             _state: Running,
         };
 
-        tokio::spawn(async move {
-            while let Some(msg) = rx.recv().await {
-                account
-                    .apply(&msg, &mut history)
-                    .unwrap_or_else(|err| eprintln!("Failed to apply message {msg:?}: {err}"));
+        let window = settings.velocity.map(Window::new);
+        let accrual = settings.interest.clone().map(Accrual::new);
+        let reports = settings.snapshots.then(|| done.clone());
+        // Deposits may be applied in bulk as long as nothing needs to see the account after
+        // every one of them.
+        let bulk = reports.is_none()
+            && window.is_none()
+            && accrual.is_none()
+            && !settings.grpc
+            && !settings.chaos;
+        let mut task = Task {
+            account,
+            history,
+            span: log::Span::new("apply").with("client", client),
+            ledger: Ledger::default(),
+            ready: VecDeque::new(),
+            held: Held::default(),
+            buffer: settings.lateness.map(Buffer::new),
+            idle: settings
+                .lateness
+                .filter(|_| settings.release_idle)
+                .map(|lateness| Duration::from_millis(lateness.max(1))),
+            window,
+            accrual,
+            reports,
+            bulk,
+            opened,
+            unknown_disputes: settings.unknown_disputes,
+            retention: settings.retention,
+            state: settings.state,
+            swept: None,
+            footprint: 0,
+        };
+        router.start(client, done, |mut rx| async move {
+            let mut open = true;
+            while open {
+                let batched = batching.active();
+                let mut next = match task.idle() {
+                    Some(idle) => match tokio::time::timeout(idle, rx.recv()).await {
+                        Ok(next) => next,
+                        Err(_) => {
+                            task.release_idle();
+                            task.apply_ready(false, 0).await;
+                            continue;
+                        }
+                    },
+                    None => rx.recv().await,
+                };
+                let mut received = 0;
+                loop {
+                    received += usize::from(next.is_some());
+                    open = task.receive(next);
+                    // In batched mode, whatever else is queued is taken along.
+                    if !batched || !open {
+                        break;
+                    }
+                    next = match rx.try_recv() {
+                        Ok(queued) => Some(queued),
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => None,
+                    };
+                }
+                if batched && received <= 1 {
+                    log::info!(task.span, "Account caught up, leaving batched apply");
+                    batching.stop();
+                }
+                task.apply_ready(batched, received).await;
             }
-
-            done.send(account)
-                .await
-                .unwrap_or_else(|err| eprintln!("Failed to send results: {err}"));
+            task.finish(&eviction)
         });
 
-        Ok(tx)
+        Ok(())
+    }
+
+    /// Account parked by [`dormant`], along with its transaction history.
+    fn unparked(
+        parked: Parked,
+        transactions: Vec<TransactionRecord>,
+    ) -> Result<(Self, History), anyhow::Error> {
+        let mut account = Account::new(parked.client);
+        account.book.available = parked.available;
+        account.book.held = parked.held;
+        account.book.total = parked.total;
+        account.book.locked = parked.locked;
+        account.book.pending = parked.pending;
+        account.book.authorized = parked.authorized;
+        account.book.maturing.clock = parked.clock;
+        account.book.maturing.due = dormant::parse_due(&parked.due)?.into_iter().collect();
+        account.activity = parked.first_activity.zip(parked.last_activity);
+        account.counters = Counters {
+            applied: parked.applied,
+            rejected: parked.rejected,
+            transfers: parked.transfers,
+        };
+        account.pruned = parked
+            .pruned
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        let history = transactions
+            .into_iter()
+            .map(|record| {
+                let transaction = transaction(record.state, record.amount);
+                (record.tx, (transaction, record.timestamp))
+            })
+            .collect();
+        Ok((account, history))
+    }
+
+    /// Account of `record` kept in state, along with its transaction history, as far as state
+    /// keeps it: counters, activity and value-dated deposits are not kept.
+    pub fn restored(
+        record: &AccountRecord,
+        transactions: Vec<TransactionRecord>,
+    ) -> (Self, History) {
+        let mut account = Account::new(record.client);
+        account.book.available = record.available;
+        account.book.held = record.held;
+        account.book.total = record.total;
+        account.book.locked = record.locked;
+        let history = transactions
+            .into_iter()
+            .map(|record| {
+                match record.state {
+                    TransactionState::Pending => account.book.pending += record.amount,
+                    TransactionState::Authorized => account.book.authorized += record.amount,
+                    _ => {}
+                }
+                let transaction = transaction(record.state, record.amount);
+                (record.tx, (transaction, record.timestamp))
+            })
+            .collect();
+        (account, history)
     }
 }
 
-/// State of transaction in transaction history.
-enum Transaction<T = f32> {
-    Deposited(T),
-    Disputed(T),
-    Reversed(T),
+/// Account task, applying messages of its client as they come, see [`Account::start`].
+struct Task {
+    account: Account<Running>,
+    history: History,
+    span: log::Span,
+    ledger: Ledger,
+    /// Messages to apply next, more than one at a time once reordered.
+    ready: VecDeque<Queued>,
+    /// Disputes waiting for their transaction, see [`orphans`].
+    held: Held<Queued>,
+    buffer: Option<Buffer<Queued>>,
+    /// How long the client may stay quiet while the buffer holds messages back, see
+    /// [`reorder`].
+    idle: Option<Duration>,
+    window: Option<Window>,
+    accrual: Option<Accrual>,
+    /// Snapshots go the way of the final state, ahead of it.
+    reports: Option<Sender<Account<Running>>>,
+    /// Whether deposits at the head of a batch are applied at once, see
+    /// [`Account::supervised_batch`].
+    bulk: bool,
+    /// Whether the router opened the account for the task and the creation is yet to be
+    /// logged, see [`event_log`].
+    opened: bool,
+    unknown_disputes: Policy,
+    retention: Retention,
+    /// Whether the account is recorded in state once the task is done, see [`state`].
+    state: bool,
+    /// Day of timestamps of the client history was last pruned on, see [`retention`].
+    swept: Option<u64>,
+    /// Bytes the account was last known to hold, see [`memory`].
+    footprint: u64,
 }
 
-impl<T> Transaction<T> {
-    /// Returns `true` if the transaction is [`Deposited`].
-    ///
-    /// [`Deposited`]: Transaction::Deposited
-    #[must_use]
-    fn is_deposited(&self) -> bool {
-        matches!(self, Self::Deposited(..))
+impl Task {
+    /// Takes `queued` in, `None` once the inbox is closed. It is ready to be applied right
+    /// away, or once the reorder buffer releases it. Returns whether the inbox is still open.
+    fn receive(&mut self, queued: Option<Queued>) -> bool {
+        let Some((msg, provenance, queued)) = queued else {
+            if let Some(buffer) = self.buffer.as_mut() {
+                self.ready.extend(std::iter::from_fn(|| buffer.drain()));
+            }
+            return false;
+        };
+        self.ledger.received();
+        self.account.observe(msg.timestamp());
+        let Some(buffer) = self.buffer.as_mut() else {
+            self.ready.push_back((msg, provenance, queued));
+            return true;
+        };
+        let late = buffer
+            .push(msg.timestamp(), (msg, provenance, queued))
+            .err();
+        self.ready.extend(std::iter::from_fn(|| buffer.pop()));
+        if let Some((msg, provenance, _)) = late {
+            self.reject_late(&msg, &provenance);
+        }
+        true
     }
 
-    /// Returns `true` if the transaction is [`Disputed`].
-    ///
-    /// [`Disputed`]: Transaction::Disputed
-    #[must_use]
-    fn is_disputed(&self) -> bool {
-        matches!(self, Self::Disputed(..))
+    /// How long to wait for the next message before held messages are released, `None` when
+    /// nothing is held back or they wait for more messages of the client.
+    fn idle(&self) -> Option<Duration> {
+        self.idle.filter(|_| {
+            self.buffer
+                .as_ref()
+                .is_some_and(|buffer| !buffer.is_empty())
+        })
+    }
+
+    /// Releases every message held back, since the client went quiet, see [`reorder`].
+    fn release_idle(&mut self) {
+        if let Some(buffer) = self.buffer.as_mut() {
+            log::debug!(self.span, "Client went quiet, releasing held messages");
+            buffer.advance();
+            self.ready.extend(std::iter::from_fn(|| buffer.pop()));
+        }
+    }
+
+    /// Rejects `msg`, which came too late to be applied in order, see [`reorder`].
+    fn reject_late(&mut self, msg: &Message, provenance: &Provenance) {
+        let (span, client) = (&self.span, self.account.client);
+        self.ledger.settled();
+        self.account.count(msg, false);
+        log::warn!(span, tx = msg.transaction_id(), kind = msg.kind(), source = provenance, reason = reorder::LATE; "Message is too late to be applied in order");
+        metrics::reject(reorder::LATE);
+        dashboard::rejected(reorder::LATE, client, msg.transaction_id());
+        top::rejected(client);
+        if let Err(err) = event_log::lifecycle(Lifecycle::Late, msg, provenance) {
+            log::error!(span, "Failed to append to event log: {err}");
+        }
+        if let Err(err) = dlq::append(msg, provenance, reorder::LATE) {
+            log::error!(span, "Failed to append to dead letter queue: {err}");
+        }
+    }
+
+    /// Applies every message which is ready, `batched` ones taken along in a batch of
+    /// `received`.
+    async fn apply_ready(&mut self, batched: bool, received: usize) {
+        let batch = Instant::now();
+        let run = if batched && self.bulk && self.held.is_empty() {
+            self.ready
+                .iter()
+                .take_while(|(msg, ..)| is_bulk_deposit(msg))
+                .count()
+        } else {
+            0
+        };
+        let mut changed = run > 1 && self.apply_bulk(run, batch);
+        for index in run.min(1).. {
+            let Some((msg, provenance, queued)) = self.ready.pop_front() else {
+                break;
+            };
+            let started = Instant::now();
+            // Batches observe latency of their oldest message only.
+            if !batched || index == 0 {
+                metrics::latency(Stage::Queue, started.duration_since(queued));
+            }
+            self.post_interest(msg.timestamp());
+            self.account.book.mature(msg.timestamp(), &mut self.history);
+            self.prune(msg.timestamp());
+            let Some((msg, provenance, _)) = self.check_dispute((msg, provenance, queued)) else {
+                continue;
+            };
+            changed |= self.apply(msg, provenance, batched, started).await;
+        }
+        if batched && received > 0 {
+            let client = self.account.client;
+            metrics::latency(Stage::Apply, batch.elapsed());
+            dashboard::held(client, self.account.book.held);
+            top::held(client, self.account.book.held);
+            if changed {
+                redis::update(AccountRecord::from(&self.account));
+                flight::update(AccountRecord::from(&self.account));
+            }
+        }
+        self.footprint = memory::track(self.footprint, self.account.footprint(&self.history));
+    }
+
+    /// Applies deposits at the head of a batch at once, `run` of them, see
+    /// [`Account::supervised_batch`]. Returns whether any of them was applied.
+    fn apply_bulk(&mut self, run: usize, batch: Instant) -> bool {
+        let (span, client) = (&self.span, self.account.client);
+        let (messages, sources): (Vec<_>, Vec<_>) = self
+            .ready
+            .drain(..run)
+            .map(|(msg, provenance, queued)| (msg, (provenance, queued)))
+            .unzip();
+        metrics::latency(Stage::Queue, batch.duration_since(sources[0].1));
+        let created = std::mem::take(&mut self.opened);
+        for msg in &messages {
+            self.account.book.mature(msg.timestamp(), &mut self.history);
+        }
+        let outcomes =
+            self.account
+                .supervised_batch(&messages, &mut self.history, Account::apply_batch);
+        let mut changed = false;
+        for (index, ((msg, (provenance, _)), outcome)) in
+            messages.iter().zip(sources).zip(outcomes).enumerate()
+        {
+            self.ledger.settled();
+            self.account.count(msg, outcome.is_ok());
+            if created && index == 0 {
+                if let Err(err) = event_log::lifecycle(Lifecycle::Created, msg, &provenance) {
+                    log::error!(span, "Failed to append to event log: {err}");
+                }
+            }
+            match outcome {
+                Ok(()) => {
+                    log::debug!(span, tx = msg.transaction_id(), kind = msg.kind(); "Applied message");
+                    changed = true;
+                    report::record(msg, msg.amount().unwrap_or_default(), true);
+                    top::applied(msg);
+                }
+                Err(err) => {
+                    log::warn!(span, tx = msg.transaction_id(), kind = msg.kind(), source = provenance, reason = err; "Failed to apply message");
+                    metrics::reject(err.code());
+                    dashboard::rejected(err.code(), client, msg.transaction_id());
+                    top::rejected(client);
+                }
+            }
+        }
+        self.prune(messages.iter().filter_map(Message::timestamp).max());
+        changed
+    }
+
+    /// Posts interest accrued by `timestamp`, see [`interest`].
+    fn post_interest(&mut self, timestamp: Option<u64>) {
+        let Some(accrual) = self.accrual.as_mut() else {
+            return;
+        };
+        let (span, client) = (&self.span, self.account.client);
+        for (at, amount) in accrual.advance(timestamp, self.account.book.available) {
+            let posting = interest::deposit(client, at, amount);
+            match self.account.supervised_apply(&posting, &mut self.history) {
+                Ok(()) => {
+                    log::debug!(span, tx = interest::TX, amount = amount; "Posted interest");
+                    report::record(&posting, amount, true);
+                    top::applied(&posting);
+                    flight::update(AccountRecord::from(&self.account));
+                    grpc::applied(&posting, None, AccountRecord::from(&self.account), false);
+                }
+                Err(err) => {
+                    log::warn!(span, tx = interest::TX, amount = amount, reason = err; "Failed to post interest");
+                }
+            }
+        }
+    }
+
+    /// Prunes history once timestamps reach another day, see [`retention`].
+    fn prune(&mut self, timestamp: Option<u64>) {
+        let day = timestamp.map(|now| now / retention::DAY);
+        if self.retention == Retention::All || day <= self.swept {
+            return;
+        }
+        self.swept = day;
+        let pruned = self
+            .account
+            .prune(&mut self.history, self.retention, timestamp);
+        if pruned > 0 {
+            log::debug!(self.span, pruned = pruned; "Pruned history");
+        }
+    }
+
+    /// Rejects or holds back a dispute of a transaction missing from history, as
+    /// [`retention`] and [`orphans`] policy say. Returns any other message back, to be
+    /// applied.
+    fn check_dispute(&mut self, queued: Queued) -> Option<Queued> {
+        let (msg, provenance, _) = &queued;
+        let tx = msg.transaction_id();
+        if !msg.is_dispute() || self.history.contains_key(&tx) {
+            return Some(queued);
+        }
+        if self.account.pruned.contains(&tx) {
+            self.ledger.settled();
+            self.account.count(msg, false);
+            pruned_dispute(&self.span, msg, provenance);
+            return None;
+        }
+        match self.unknown_disputes {
+            Policy::Ignore => Some(queued),
+            Policy::Reject => {
+                self.ledger.settled();
+                self.account.count(msg, false);
+                unknown_dispute(&self.span, msg, provenance);
+                None
+            }
+            Policy::Retry => {
+                log::debug!(self.span, tx = tx; "Holding dispute back until its transaction comes");
+                self.held.hold(tx, queued);
+                None
+            }
+        }
+    }
+
+    /// Applies `msg` read from `provenance`, and lets everything which follows the account
+    /// know of the outcome. Returns whether it was applied.
+    async fn apply(
+        &mut self,
+        msg: Message,
+        provenance: Provenance,
+        batched: bool,
+        started: Instant,
+    ) -> bool {
+        let (span, client) = (&self.span, self.account.client);
+        let account = &mut self.account;
+        // Funds of pending withdrawals have left the account, as far as the report is
+        // concerned.
+        let before = (
+            account.book.available,
+            account.book.held,
+            account.book.total - account.book.authorized,
+        );
+        let was_locked = account.book.locked;
+        let was_total = account.book.total;
+        let created = std::mem::take(&mut self.opened);
+        let outcome = account.supervised_apply(&msg, &mut self.history);
+        if !batched {
+            metrics::latency(Stage::Apply, started.elapsed());
+        }
+        let applied = outcome.is_ok();
+        account.count(&msg, applied);
+        let changes = [
+            (created, Lifecycle::Created),
+            (!was_locked && account.book.locked, Lifecycle::Locked),
+            (was_locked && !account.book.locked, Lifecycle::Unlocked),
+        ];
+        for (_, change) in changes.into_iter().filter(|(changed, _)| *changed) {
+            if let Err(err) = event_log::lifecycle(change, &msg, &provenance) {
+                log::error!(span, "Failed to append to event log: {err}");
+            }
+        }
+        let after = (
+            account.book.available,
+            account.book.held,
+            account.book.total - account.book.authorized,
+        );
+
+        self.ledger.settled();
+        match outcome {
+            Ok(()) => {
+                log::debug!(span, tx = msg.transaction_id(), kind = msg.kind(); "Applied message");
+                if account.book.locked {
+                    metrics::account_locked();
+                }
+            }
+            Err(err) if err.is_failure() => {
+                log::error!(span, tx = msg.transaction_id(), kind = msg.kind(), source = provenance, reason = err; "Account task failed, restarted with last known state");
+                metrics::reject(err.code());
+                dashboard::rejected(err.code(), client, msg.transaction_id());
+                top::rejected(client);
+                if let Err(err) = dlq::append(&msg, &provenance, err.code()) {
+                    log::error!(span, "Failed to append to dead letter queue: {err}");
+                }
+            }
+            Err(err) => {
+                log::warn!(span, tx = msg.transaction_id(), kind = msg.kind(), source = provenance, reason = err; "Failed to apply message");
+                metrics::reject(err.code());
+                dashboard::rejected(err.code(), client, msg.transaction_id());
+                top::rejected(client);
+            }
+        }
+        if msg.amount().is_some() {
+            for dispute in self.held.release(msg.transaction_id()).into_iter().rev() {
+                self.ready.push_front(dispute);
+            }
+        }
+        if !batched {
+            dashboard::held(client, account.book.held);
+            top::held(client, account.book.held);
+        }
+        if applied && !batched {
+            redis::update(AccountRecord::from(&*account));
+            flight::update(AccountRecord::from(&*account));
+        }
+        if applied {
+            grpc::applied(
+                &msg,
+                provenance.correlation_id.as_ref(),
+                AccountRecord::from(&*account),
+                !was_locked && account.book.locked,
+            );
+            report::record(&msg, after.2 - before.2, after != before);
+            top::applied(&msg);
+        }
+
+        if let Some(reports) = self.reports.as_ref() {
+            let now = (account.book.total, account.book.locked);
+            if snapshots::significant((was_total, was_locked), now) {
+                log::debug!(span, tx = msg.transaction_id(); "Reporting snapshot of account");
+                let _ = reports.send(account.snapshot()).await;
+            }
+        }
+        self.check_rules(&msg, applied, before.0);
+        applied
+    }
+
+    /// Raises alerts of velocity rules and of the reserve which `msg` breached, see
+    /// [`velocity`](crate::velocity) and [`reserve`]. Available funds were `available` before
+    /// it.
+    fn check_rules(&mut self, msg: &Message, applied: bool, available: f32) {
+        let (span, account) = (&self.span, &self.account);
+        for alert in self
+            .window
+            .iter_mut()
+            .flat_map(|window| window.observe(msg, applied))
+        {
+            log::warn!(span, tx = alert.tx, rule = alert.rule, withdrawals = alert.withdrawals.unwrap_or_default(), withdrawn = alert.withdrawn.unwrap_or_default(); "Velocity rule breached");
+            metrics::alert(alert.rule);
+            if let Err(err) = alerts::append(&alert) {
+                log::error!(span, "Failed to append to alerts: {err}");
+            }
+        }
+        let book = &account.book;
+        if let Some(alert) = reserve::observe(
+            account.client,
+            msg.transaction_id(),
+            book.minimum,
+            available,
+            book.available,
+        ) {
+            log::warn!(span, tx = alert.tx, rule = alert.rule, available = book.available, minimum = book.minimum; "Account dipped into reserve");
+            metrics::alert(alert.rule);
+            if let Err(err) = alerts::append(&alert) {
+                log::error!(span, "Failed to append to alerts: {err}");
+            }
+        }
+    }
+
+    /// Rejects disputes still waiting for their transaction once the inbox is closed, then
+    /// parks the account for `eviction`, or hands it over to be reported.
+    fn finish(mut self, eviction: &Eviction) -> Option<Account<Running>> {
+        let (span, client) = (&self.span, self.account.client);
+        for (msg, provenance, _) in self.held.drain() {
+            self.ledger.settled();
+            self.account.count(&msg, false);
+            unknown_dispute(span, &msg, &provenance);
+        }
+        self.ledger
+            .close(format_args!("Account task of client {client}"));
+        memory::track(self.footprint, 0);
+        // Neither the state nor the dormant directory keep what retention doesn't.
+        if self.retention != Retention::All {
+            let now = self.account.activity.map(|(_, last)| last);
+            self.account.prune(&mut self.history, self.retention, now);
+        }
+
+        if eviction.evicting() {
+            let transactions = records(client, &self.history).collect::<Vec<_>>();
+            match dormant::park(&self.account.parked(), &transactions) {
+                Ok(()) => {
+                    log::debug!(span, transactions = transactions.len(); "Parked account");
+                    eviction.finish(true);
+                    return None;
+                }
+                Err(err) => {
+                    log::error!(span, "Failed to park account, reporting it instead: {err}");
+                    eviction.finish(false);
+                }
+            }
+        }
+        if self.state {
+            state::record(
+                AccountRecord::from(&self.account),
+                records(client, &self.history),
+            );
+        }
+        Some(self.account)
     }
 }
 
-impl<T: Copy> Transaction<T> {
-    fn amount(&self) -> T {
-        match self {
-            Transaction::Deposited(x) => *x,
-            Transaction::Disputed(x) => *x,
-            Transaction::Reversed(x) => *x,
+impl<T> Account<T> {
+    /// What applying `msg` would do to balances of the account, without applying it, e.g. to
+    /// tell whether a withdrawal would succeed.
+    pub fn simulate(&self, msg: &Message, history: &History) -> Result<Effect, ProcessingError> {
+        self.book
+            .simulate(msg, history)
+            .map_err(ProcessingError::Rejected)
+    }
+
+    /// Earliest and latest timestamps of messages of the client, `None` when they had none.
+    pub fn activity(&self) -> Option<(u64, u64)> {
+        self.activity
+    }
+
+    /// Funds of value-dated deposits which are not available yet.
+    pub fn pending(&self) -> f32 {
+        self.book.pending
+    }
+
+    pub fn counters(&self) -> Counters {
+        self.counters
+    }
+
+    /// Returns `true` for accounts which never moved funds past the deposit which opened them,
+    /// and are locked or hold nothing, e.g. locked by a chargeback of that deposit, or opened
+    /// with nothing and then only rejected. Nothing can be done with them any more, so
+    /// downstream jobs may clean them up.
+    pub fn zombie(&self) -> bool {
+        self.counters.transfers <= 1 && (self.book.locked || self.book.total == 0.0)
+    }
+}
+
+impl From<&Account<Running>> for AccountSnapshot {
+    fn from(account: &Account<Running>) -> Self {
+        AccountSnapshot {
+            schema_version: SNAPSHOT_VERSION,
+            client: account.client,
+            available: account.book.available,
+            held: account.book.held,
+            total: account.book.total,
+            locked: account.book.locked,
+            applied: account.counters.applied,
+            rejected: account.counters.rejected,
+        }
+    }
+}
+
+impl From<&Account<Running>> for AccountRecord {
+    fn from(account: &Account<Running>) -> Self {
+        AccountRecord::from(&AccountSnapshot::from(account))
+    }
+}
+
+impl Account<Running> {
+    /// Estimated bytes the task of the account holds along with transaction `history`, see
+    /// [`memory`].
+    fn footprint(&self, history: &History) -> u64 {
+        memory::TASK
+            + std::mem::size_of::<Self>() as u64
+            + memory::table::<(u32, (Transaction, Option<u64>))>(history.capacity())
+            + memory::table::<u32>(self.pruned.capacity())
+    }
+
+    /// Prunes from `history` what `retention` doesn't keep as of `now`, remembering what was
+    /// pruned. Returns how many transactions were.
+    fn prune(&mut self, history: &mut History, retention: Retention, now: Option<u64>) -> usize {
+        let before = history.len();
+        history.retain(|tx, (transaction, timestamp)| {
+            let kept = retention.keeps(state(transaction), *timestamp, now);
+            if !kept {
+                self.pruned.insert(*tx);
+            }
+            kept
+        });
+        before - history.len()
+    }
+
+    /// Account as [`dormant`] parks it.
+    fn parked(&self) -> Parked {
+        Parked {
+            client: self.client,
+            available: self.book.available,
+            held: self.book.held,
+            total: self.book.total,
+            locked: self.book.locked,
+            pending: self.book.pending,
+            authorized: self.book.authorized,
+            clock: self.book.maturing.clock,
+            due: dormant::format_due(self.book.maturing.due.iter().copied()),
+            first_activity: self.activity.map(|(first, _)| first),
+            last_activity: self.activity.map(|(_, last)| last),
+            applied: self.counters.applied,
+            rejected: self.counters.rejected,
+            transfers: self.counters.transfers,
+            pruned: self
+                .pruned
+                .iter()
+                .map(|tx| tx.to_string())
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
+    }
+
+    /// Copy of what is written of the account, for [`snapshots`].
+    fn snapshot(&self) -> Self {
+        Account {
+            client: self.client,
+            book: Book {
+                available: self.book.available,
+                held: self.book.held,
+                total: self.book.total,
+                locked: self.book.locked,
+                pending: self.book.pending,
+                authorized: self.book.authorized,
+                ..Book::default()
+            },
+            activity: self.activity,
+            counters: self.counters,
+            pruned: HashSet::new(),
+            _state: Running,
         }
     }
 }
 
-/// Simple in-memory storage for transaction history.
-/// Used by account task to lookup [`Message::Deposit`] amounts.
-/// In a real world situation this could also be a remote store.
-type TXHistory = HashMap<u32, Transaction>;
+/// Transaction `history` of `client`, as recorded to [`state`].
+fn records(client: u16, history: &History) -> impl Iterator<Item = TransactionRecord> + '_ {
+    history
+        .iter()
+        .map(move |(tx, (transaction, timestamp))| TransactionRecord {
+            tx: *tx,
+            client,
+            state: state(transaction),
+            amount: transaction.amount(),
+            timestamp: *timestamp,
+        })
+}
+
+/// State of transaction `transaction` of history, as recorded to [`state`].
+fn state(transaction: &Transaction) -> TransactionState {
+    match transaction {
+        Transaction::Pending(_) => TransactionState::Pending,
+        Transaction::Deposited(_) => TransactionState::Deposited,
+        Transaction::Disputed(_) => TransactionState::Disputed,
+        Transaction::Reversed(_) => TransactionState::Reversed,
+        Transaction::Authorized(_) => TransactionState::Authorized,
+        Transaction::Withdrawn(_) => TransactionState::Withdrawn,
+    }
+}
+
+/// Transaction of history recorded as `state` with `amount`, see [`fn@state`].
+fn transaction(state: TransactionState, amount: f32) -> Transaction {
+    match state {
+        TransactionState::Pending => Transaction::Pending(amount),
+        TransactionState::Deposited => Transaction::Deposited(amount),
+        TransactionState::Disputed => Transaction::Disputed(amount),
+        TransactionState::Reversed => Transaction::Reversed(amount),
+        TransactionState::Authorized => Transaction::Authorized(amount),
+        TransactionState::Withdrawn => Transaction::Withdrawn(amount),
+    }
+}
 
 #[derive(Debug)]
-enum ProcessingError {
-    InsufficientFunds,
-    AccountLocked,
+pub enum ProcessingError {
+    /// Message was rejected by the rules of the [`engine`](crate::engine).
+    Rejected(Rejection),
+    /// Applying the message panicked.
+    Panicked,
+    /// Account task was killed by [`chaos`] before applying the message.
+    Killed,
 }
 
-impl Display for ProcessingError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl ProcessingError {
+    /// Short stable code, used in logs and as a metrics label.
+    pub fn code(&self) -> &'static str {
         match self {
-            ProcessingError::InsufficientFunds => f.write_str("PE_INSF"),
-            ProcessingError::AccountLocked => f.write_str("PE_ACCLCK"),
+            ProcessingError::Rejected(rejection) => rejection.code(),
+            ProcessingError::Panicked => "PE_PANIC",
+            ProcessingError::Killed => chaos::KILLED,
         }
     }
+
+    /// Returns `true` if the task failed, rather than the message being rejected by the rules.
+    fn is_failure(&self) -> bool {
+        matches!(self, ProcessingError::Panicked | ProcessingError::Killed)
+    }
+}
+
+impl Display for ProcessingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.code())
+    }
 }
 
 impl std::error::Error for ProcessingError {}
 
 impl Account<Running> {
-    fn apply(
+    /// Counts `message` of the client as `applied` or rejected.
+    fn count(&mut self, message: &Message, applied: bool) {
+        if !applied {
+            self.counters.rejected += 1;
+            return;
+        }
+        self.counters.applied += 1;
+        if message.amount().is_some() {
+            self.counters.transfers += 1;
+        }
+    }
+
+    /// Widens activity of the account to `timestamp`, if there is one.
+    fn observe(&mut self, timestamp: Option<u64>) {
+        let Some(timestamp) = timestamp else {
+            return;
+        };
+        self.activity = Some(match self.activity {
+            Some((first, last)) => (first.min(timestamp), last.max(timestamp)),
+            None => (timestamp, timestamp),
+        });
+    }
+
+    /// Applies `message` like [`apply`](Self::apply), but survives a panic, so the task
+    /// carries on with its last known state.
+    fn supervised_apply(
         &mut self,
         message: &Message,
-        tx_history: &mut TXHistory,
+        tx_history: &mut History,
     ) -> Result<(), ProcessingError> {
-        if self.locked {
-            return Err(ProcessingError::AccountLocked);
-        }
-        match message {
-            Message::Deposit { tx, amount, .. } => {
-                self.available += amount;
-                self.total += amount;
-                tx_history.insert(*tx, Transaction::Deposited(*amount));
-            }
-            Message::Withdraw { amount, .. } => {
-                if self.available < *amount {
-                    return Err(ProcessingError::InsufficientFunds);
-                }
-                self.available -= amount;
-                self.total -= amount;
-            }
-            Message::Dispute { tx, .. } => {
-                if let Some(existing) = tx_history
-                    .get_mut(tx)
-                    .filter(|existing| existing.is_deposited())
-                    .filter(|existing| self.available >= existing.amount())
-                {
-                    let amount = existing.amount();
-                    self.available -= amount;
-                    self.held += amount;
-                    *existing = Transaction::Disputed(amount);
-                }
+        let killed = chaos::should_kill(self.client);
+        let outcome = self.supervised(message.transaction_id(), tx_history, |account, history| {
+            if killed {
+                panic!("Account task of client {} killed by chaos", account.client);
             }
-            Message::Resolve { tx, .. } => {
-                if let Some(existing) = tx_history
-                    .get_mut(tx)
-                    .filter(|existing| existing.is_disputed())
-                {
-                    let amount = existing.amount();
-                    self.available += amount;
-                    self.held -= amount;
-                    *existing = Transaction::Deposited(amount);
-                }
+            account.apply(message, history)
+        });
+        match outcome {
+            Err(ProcessingError::Panicked) if killed => Err(ProcessingError::Killed),
+            outcome => outcome,
+        }
+    }
+
+    /// Runs `f`. When it panics, balances and transaction `tx` are rolled back to what they
+    /// were before.
+    fn supervised<F>(
+        &mut self,
+        tx: u32,
+        tx_history: &mut History,
+        f: F,
+    ) -> Result<(), ProcessingError>
+    where
+        F: FnOnce(&mut Self, &mut History) -> Result<(), ProcessingError>,
+    {
+        let balances = (
+            self.book.available,
+            self.book.held,
+            self.book.total,
+            self.book.locked,
+            self.book.pending,
+            self.book.authorized,
+        );
+        let transaction = tx_history.get(&tx).copied();
+
+        std::panic::catch_unwind(AssertUnwindSafe(|| f(self, tx_history))).unwrap_or_else(|panic| {
+            if invariants::is_violation(panic.as_ref()) {
+                std::panic::resume_unwind(panic);
             }
-            Message::Chargeback { tx, .. } => {
-                if let Some(existing) = tx_history
-                    .get_mut(tx)
-                    .filter(|existing| existing.is_disputed())
-                {
-                    let amount = existing.amount();
-                    self.held -= amount;
-                    self.total -= amount;
-                    self.locked = true;
-                    *existing = Transaction::Reversed(amount);
+            (
+                self.book.available,
+                self.book.held,
+                self.book.total,
+                self.book.locked,
+                self.book.pending,
+                self.book.authorized,
+            ) = balances;
+            match transaction {
+                Some(transaction) => tx_history.insert(tx, transaction),
+                None => tx_history.remove(&tx),
+            };
+            Err(ProcessingError::Panicked)
+        })
+    }
+
+    /// Applies `messages` with `f`, [`apply_batch`](Self::apply_batch) outside of tests, but
+    /// survives a panic: balances and transactions of the batch are rolled back, and its
+    /// messages are applied again one at a time with [`supervised_apply`](Self::supervised_apply),
+    /// so only the one which panics fails.
+    fn supervised_batch<F>(
+        &mut self,
+        messages: &[Message],
+        tx_history: &mut History,
+        f: F,
+    ) -> Vec<Result<(), ProcessingError>>
+    where
+        F: FnOnce(&mut Self, &[Message], &mut History) -> Vec<Result<(), ProcessingError>>,
+    {
+        let book = self.book.clone();
+        let transactions: Vec<_> = messages
+            .iter()
+            .map(|message| {
+                let tx = message.transaction_id();
+                (tx, tx_history.get(&tx).copied())
+            })
+            .collect();
+
+        std::panic::catch_unwind(AssertUnwindSafe(|| f(self, messages, tx_history))).unwrap_or_else(
+            |panic| {
+                if invariants::is_violation(panic.as_ref()) {
+                    std::panic::resume_unwind(panic);
                 }
-            }
-        }
+                self.book = book;
+                // Latest first, so a tx repeated in the batch gets what it had before it.
+                for (tx, transaction) in transactions.into_iter().rev() {
+                    match transaction {
+                        Some(transaction) => tx_history.insert(tx, transaction),
+                        None => tx_history.remove(&tx),
+                    };
+                }
+                messages
+                    .iter()
+                    .map(|message| self.supervised_apply(message, tx_history))
+                    .collect()
+            },
+        )
+    }
 
-        Ok(())
+    /// Applies `messages` in order like [`apply`](Self::apply), reserving history for all of
+    /// them at once, and checking [`invariants`] once all of them are applied rather than after
+    /// every one. Meant for runs of deposits of batched apply, see [`Batching`].
+    fn apply_batch(
+        &mut self,
+        messages: &[Message],
+        tx_history: &mut History,
+    ) -> Vec<Result<(), ProcessingError>> {
+        tx_history.reserve(messages.len());
+        let outcomes: Vec<_> = messages
+            .iter()
+            .map(|message| {
+                self.book
+                    .apply(message, tx_history)
+                    .map_err(ProcessingError::Rejected)
+            })
+            .collect();
+
+        let Book {
+            available,
+            held,
+            total,
+            pending,
+            ..
+        } = self.book;
+        invariant!(
+            (total - (available + held + pending)).abs()
+                <= invariants::TOLERANCE * total.abs().max(1.0),
+            "client {} totals {total} apart from its funds after {} txs",
+            self.client,
+            messages.len()
+        );
+        invariant!(
+            held >= -invariants::TOLERANCE,
+            "client {} holds {held} after {} txs",
+            self.client,
+            messages.len()
+        );
+        outcomes
+    }
+
+    /// Applies `message` to the account, checking [`invariants`] of the outcome.
+    fn apply(
+        &mut self,
+        message: &Message,
+        tx_history: &mut History,
+    ) -> Result<(), ProcessingError> {
+        let tx = message.transaction_id();
+        let before = (
+            self.book.available,
+            self.book.held,
+            self.book.total,
+            self.book.locked,
+            self.book.pending,
+            self.book.authorized,
+        );
+        let transaction = tx_history.get(&tx).copied();
+
+        let outcome = self
+            .book
+            .apply(message, tx_history)
+            .map_err(ProcessingError::Rejected);
+
+        let after = (
+            self.book.available,
+            self.book.held,
+            self.book.total,
+            self.book.locked,
+            self.book.pending,
+            self.book.authorized,
+        );
+        let transaction = transaction.map(|(transaction, _)| transaction);
+        invariant!(
+            !before.3
+                || message.is_settle()
+                || matches!(
+                    outcome,
+                    Err(ProcessingError::Rejected(Rejection::AccountLocked))
+                ),
+            "locked account of client {} accepted tx {tx}",
+            self.client
+        );
+        invariant!(
+            outcome.is_ok()
+                || (after == before
+                    && tx_history.get(&tx).map(|(transaction, _)| *transaction) == transaction),
+            "client {} changed by rejected tx {tx}: {before:?} -> {after:?}",
+            self.client
+        );
+        invariant!(
+            self.book.held >= -invariants::TOLERANCE,
+            "client {} holds {} after tx {tx}",
+            self.client,
+            self.book.held
+        );
+        outcome
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Account, Running, Transaction};
-    use crate::{message::Message, processor::ProcessingError};
-    use std::collections::HashMap;
+    use super::{records, Account, Counters, Running, Settings};
+    use crate::{
+        engine::{Book, History, Rejection, Rules, Settlement, Transaction},
+        lag::Batching,
+        message::Message,
+        processor::ProcessingError,
+        protocol::Router,
+        provenance::Provenance,
+        retention::{self, Retention},
+        sim,
+    };
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+    use tokio::sync::mpsc;
 
     fn running(id: u16) -> Account<Running> {
         Account {
             client: id,
-            available: 0.0,
-            held: 0.0,
-            total: 0.0,
-            locked: false,
+            book: Book::default(),
+            activity: None,
+            counters: Counters::default(),
+            pruned: HashSet::new(),
             _state: Running,
         }
     }
@@ -274,21 +1572,211 @@ mod tests {
             client: 42,
             amount: 1.1,
             tx: 123,
+            timestamp: None,
+            effective_date: None,
         };
 
         let outcome = account.apply(&msg, &mut history);
         assert!(outcome.is_ok());
-        assert_eq!(account.total, 1.1);
-        assert_eq!(account.available, 1.1);
-        assert_eq!(account.held, 0.0);
-        assert!(!account.locked);
+        assert_eq!(account.book.total, 1.1);
+        assert_eq!(account.book.available, 1.1);
+        assert_eq!(account.book.held, 0.0);
+        assert!(!account.book.locked);
 
-        let saved = history.get(&msg.transaction_id());
+        let saved = history.get(&msg.transaction_id()).map(|(saved, _)| saved);
         assert!(saved.is_some());
         let saved = saved.unwrap();
         assert!(saved.is_deposited());
     }
 
+    #[test]
+    fn value_dated_deposit_is_pending_until_due() {
+        let mut account = running(42);
+        let mut history = HashMap::new();
+        let deposit = Message::Deposit {
+            client: 42,
+            amount: 2.0,
+            tx: 1,
+            timestamp: Some(100),
+            effective_date: Some(200),
+        };
+        let withdrawal = |timestamp| Message::Withdraw {
+            client: 42,
+            amount: 1.0,
+            tx: 2,
+            timestamp: Some(timestamp),
+        };
+
+        account.book.mature(deposit.timestamp(), &mut history);
+        assert!(account.apply(&deposit, &mut history).is_ok());
+        assert_eq!(
+            (
+                account.book.available,
+                account.book.pending,
+                account.book.total
+            ),
+            (0.0, 2.0, 2.0)
+        );
+        assert!(history[&1].0.is_pending());
+
+        account.book.mature(Some(150), &mut history);
+        assert!(matches!(
+            account.apply(&withdrawal(150), &mut history),
+            Err(ProcessingError::Rejected(Rejection::InsufficientFunds))
+        ));
+
+        account.book.mature(Some(200), &mut history);
+        assert!(history[&1].0.is_deposited());
+        assert!(account.apply(&withdrawal(200), &mut history).is_ok());
+        assert_eq!(
+            (
+                account.book.available,
+                account.book.pending,
+                account.book.total
+            ),
+            (1.0, 0.0, 1.0)
+        );
+    }
+
+    #[test]
+    fn withdrawal_below_minimum_balance_is_rejected() {
+        let mut account = running(42);
+        account.book.minimum = 10.0;
+        let mut history = HashMap::new();
+        let deposit = Message::Deposit {
+            client: 42,
+            amount: 15.0,
+            tx: 1,
+            timestamp: None,
+            effective_date: None,
+        };
+        let withdrawal = |tx, amount| Message::Withdraw {
+            client: 42,
+            amount,
+            tx,
+            timestamp: None,
+        };
+
+        assert!(account.apply(&deposit, &mut history).is_ok());
+        assert!(matches!(
+            account.apply(&withdrawal(2, 20.0), &mut history),
+            Err(ProcessingError::Rejected(Rejection::InsufficientFunds))
+        ));
+        assert!(matches!(
+            account.apply(&withdrawal(3, 6.0), &mut history),
+            Err(ProcessingError::Rejected(Rejection::BelowMinimum))
+        ));
+        assert!(account.apply(&withdrawal(4, 5.0), &mut history).is_ok());
+        assert_eq!((account.book.available, account.book.total), (10.0, 10.0));
+    }
+
+    #[test]
+    fn withdrawal_follows_rules_of_tier() {
+        let mut account = running(42);
+        account.book.rules = Rules {
+            max_withdrawal: Some(8.0),
+            withdrawal_fee: 0.5,
+            overdraft: 2.0,
+        };
+        let mut history = HashMap::new();
+        let deposit = Message::Deposit {
+            client: 42,
+            amount: 10.0,
+            tx: 1,
+            timestamp: None,
+            effective_date: None,
+        };
+        let withdrawal = |tx, amount| Message::Withdraw {
+            client: 42,
+            amount,
+            tx,
+            timestamp: None,
+        };
+
+        assert!(account.apply(&deposit, &mut history).is_ok());
+        assert!(matches!(
+            account.apply(&withdrawal(2, 9.0), &mut history),
+            Err(ProcessingError::Rejected(Rejection::OverLimit))
+        ));
+        assert!(account.apply(&withdrawal(3, 7.5), &mut history).is_ok());
+        assert_eq!((account.book.available, account.book.total), (2.0, 2.0));
+        // Fee counts against the overdraft too.
+        assert!(matches!(
+            account.apply(&withdrawal(4, 4.0), &mut history),
+            Err(ProcessingError::Rejected(Rejection::InsufficientFunds))
+        ));
+        assert!(account.apply(&withdrawal(5, 3.5), &mut history).is_ok());
+        assert_eq!((account.book.available, account.book.total), (-2.0, -2.0));
+    }
+
+    #[test]
+    fn pending_withdrawal_is_held_until_settled() {
+        let mut account = running(42);
+        account.book.maturing.settlement = Some(Settlement { after: Some(100) });
+        let mut history = HashMap::new();
+        let messages = [
+            Message::Deposit {
+                client: 42,
+                amount: 5.0,
+                tx: 1,
+                timestamp: Some(0),
+                effective_date: None,
+            },
+            Message::Withdraw {
+                client: 42,
+                amount: 2.0,
+                tx: 2,
+                timestamp: Some(10),
+            },
+            Message::Withdraw {
+                client: 42,
+                amount: 1.0,
+                tx: 3,
+                timestamp: None,
+            },
+        ];
+        for message in &messages {
+            account.book.mature(message.timestamp(), &mut history);
+            assert!(account.apply(message, &mut history).is_ok());
+        }
+        assert_eq!(
+            (
+                account.book.available,
+                account.book.held,
+                account.book.total
+            ),
+            (2.0, 3.0, 5.0)
+        );
+        assert!(history[&2].0.is_authorized());
+
+        // Settled by time, and by a settle message even though the account is locked.
+        account.book.mature(Some(110), &mut history);
+        assert_eq!(
+            (
+                account.book.available,
+                account.book.held,
+                account.book.total
+            ),
+            (2.0, 1.0, 3.0)
+        );
+        account.book.locked = true;
+        let settle = Message::Settle {
+            client: 42,
+            tx: 3,
+            timestamp: None,
+        };
+        assert!(account.apply(&settle, &mut history).is_ok());
+        assert_eq!(
+            (
+                account.book.available,
+                account.book.held,
+                account.book.total
+            ),
+            (2.0, 0.0, 2.0)
+        );
+        assert_eq!(history[&3].0, Transaction::Withdrawn(1.0));
+    }
+
     #[test]
     fn valid_withdrawal_is_handled() {
         let client = 42;
@@ -298,21 +1786,24 @@ mod tests {
             amount: 10.0,
             tx: 123,
             client,
+            timestamp: None,
+            effective_date: None,
         };
 
         let withdrawal = Message::Withdraw {
             client,
             tx: 144,
             amount: 3.0,
+            timestamp: None,
         };
 
         assert!(account.apply(&deposit, &mut history).is_ok());
         assert!(account.apply(&withdrawal, &mut history).is_ok());
 
-        assert_eq!(account.available, 7.0);
-        assert_eq!(account.held, 0.0);
-        assert_eq!(account.total, 7.0);
-        assert!(!account.locked);
+        assert_eq!(account.book.available, 7.0);
+        assert_eq!(account.book.held, 0.0);
+        assert_eq!(account.book.total, 7.0);
+        assert!(!account.book.locked);
     }
 
     #[test]
@@ -324,12 +1815,15 @@ mod tests {
             amount: 1.0,
             tx: 123,
             client,
+            timestamp: None,
+            effective_date: None,
         };
 
         let withdrawal = Message::Withdraw {
             client,
             tx: 144,
             amount: 3.0,
+            timestamp: None,
         };
 
         assert!(account.apply(&deposit, &mut history).is_ok());
@@ -337,11 +1831,14 @@ mod tests {
         let outcome = account.apply(&withdrawal, &mut history);
         assert!(outcome.is_err());
         let outcome = outcome.unwrap_err();
-        assert!(matches!(outcome, ProcessingError::InsufficientFunds));
-        assert_eq!(account.available, 1.0);
-        assert_eq!(account.held, 0.0);
-        assert_eq!(account.total, 1.0);
-        assert!(!account.locked);
+        assert!(matches!(
+            outcome,
+            ProcessingError::Rejected(Rejection::InsufficientFunds)
+        ));
+        assert_eq!(account.book.available, 1.0);
+        assert_eq!(account.book.held, 0.0);
+        assert_eq!(account.book.total, 1.0);
+        assert!(!account.book.locked);
     }
 
     #[test]
@@ -354,17 +1851,23 @@ mod tests {
             amount: 1.0,
             tx,
             client,
+            timestamp: None,
+            effective_date: None,
         };
 
-        let dispute = Message::Dispute { client, tx };
+        let dispute = Message::Dispute {
+            client,
+            tx,
+            timestamp: None,
+        };
 
         assert!(account.apply(&deposit, &mut history).is_ok());
         assert!(account.apply(&dispute, &mut history).is_ok());
-        assert_eq!(account.held, 1.0);
-        assert_eq!(account.total, 1.0);
-        assert_eq!(account.available, 0.0);
-        assert!(!account.locked);
-        let saved = history.get(&tx);
+        assert_eq!(account.book.held, 1.0);
+        assert_eq!(account.book.total, 1.0);
+        assert_eq!(account.book.available, 0.0);
+        assert!(!account.book.locked);
+        let saved = history.get(&tx).map(|(saved, _)| saved);
         assert!(saved.is_some());
         let saved = saved.unwrap();
         assert!(matches!(saved, Transaction::Disputed(_)));
@@ -380,16 +1883,22 @@ mod tests {
             amount: 1.0,
             tx,
             client,
+            timestamp: None,
+            effective_date: None,
         };
 
-        let dispute = Message::Dispute { client, tx: 124 };
+        let dispute = Message::Dispute {
+            client,
+            tx: 124,
+            timestamp: None,
+        };
 
         assert!(account.apply(&deposit, &mut history).is_ok());
         assert!(account.apply(&dispute, &mut history).is_ok());
-        assert_eq!(account.held, 0.0);
-        assert_eq!(account.total, 1.0);
-        assert_eq!(account.available, 1.0);
-        assert!(!account.locked);
+        assert_eq!(account.book.held, 0.0);
+        assert_eq!(account.book.total, 1.0);
+        assert_eq!(account.book.available, 1.0);
+        assert!(!account.book.locked);
     }
 
     #[test]
@@ -402,30 +1911,40 @@ mod tests {
             amount: 1.0,
             tx,
             client,
+            timestamp: None,
+            effective_date: None,
         };
 
-        let dispute = Message::Dispute { client, tx };
+        let dispute = Message::Dispute {
+            client,
+            tx,
+            timestamp: None,
+        };
 
         assert!(account.apply(&deposit, &mut history).is_ok());
         assert!(account.apply(&dispute, &mut history).is_ok());
-        assert_eq!(account.held, 1.0);
-        assert_eq!(account.total, 1.0);
-        assert_eq!(account.available, 0.0);
-        assert!(!account.locked);
+        assert_eq!(account.book.held, 1.0);
+        assert_eq!(account.book.total, 1.0);
+        assert_eq!(account.book.available, 0.0);
+        assert!(!account.book.locked);
 
-        let saved = history.get(&tx);
+        let saved = history.get(&tx).map(|(saved, _)| saved);
         assert!(saved.is_some());
         let saved = saved.unwrap();
         assert!(matches!(saved, Transaction::Disputed(_)));
 
-        let resolve = Message::Resolve { client, tx };
+        let resolve = Message::Resolve {
+            client,
+            tx,
+            timestamp: None,
+        };
         assert!(account.apply(&resolve, &mut history).is_ok());
-        assert_eq!(account.held, 0.0);
-        assert_eq!(account.total, 1.0);
-        assert_eq!(account.available, 1.0);
-        assert!(!account.locked);
+        assert_eq!(account.book.held, 0.0);
+        assert_eq!(account.book.total, 1.0);
+        assert_eq!(account.book.available, 1.0);
+        assert!(!account.book.locked);
 
-        let saved = history.get(&tx);
+        let saved = history.get(&tx).map(|(saved, _)| saved);
         assert!(saved.is_some());
         let saved = saved.unwrap();
         assert!(matches!(saved, Transaction::Deposited(_)));
@@ -441,18 +1960,24 @@ mod tests {
             amount: 1.0,
             tx,
             client,
+            timestamp: None,
+            effective_date: None,
         };
 
         assert!(account.apply(&deposit, &mut history).is_ok());
 
-        let resolve = Message::Resolve { client, tx };
+        let resolve = Message::Resolve {
+            client,
+            tx,
+            timestamp: None,
+        };
         assert!(account.apply(&resolve, &mut history).is_ok());
-        assert_eq!(account.held, 0.0);
-        assert_eq!(account.total, 1.0);
-        assert_eq!(account.available, 1.0);
-        assert!(!account.locked);
+        assert_eq!(account.book.held, 0.0);
+        assert_eq!(account.book.total, 1.0);
+        assert_eq!(account.book.available, 1.0);
+        assert!(!account.book.locked);
 
-        let saved = history.get(&tx);
+        let saved = history.get(&tx).map(|(saved, _)| saved);
         assert!(saved.is_some());
         let saved = saved.unwrap();
         assert!(matches!(saved, Transaction::Deposited(_)));
@@ -468,35 +1993,317 @@ mod tests {
             amount: 1.0,
             tx,
             client,
+            timestamp: None,
+            effective_date: None,
         };
 
-        let dispute = Message::Dispute { client, tx };
+        let dispute = Message::Dispute {
+            client,
+            tx,
+            timestamp: None,
+        };
 
         assert!(account.apply(&deposit, &mut history).is_ok());
         assert!(account.apply(&dispute, &mut history).is_ok());
-        assert_eq!(account.held, 1.0);
-        assert_eq!(account.total, 1.0);
-        assert_eq!(account.available, 0.0);
-        assert!(!account.locked);
+        assert_eq!(account.book.held, 1.0);
+        assert_eq!(account.book.total, 1.0);
+        assert_eq!(account.book.available, 0.0);
+        assert!(!account.book.locked);
 
-        let saved = history.get(&tx);
+        let saved = history.get(&tx).map(|(saved, _)| saved);
         assert!(saved.is_some());
         let saved = saved.unwrap();
         assert!(matches!(saved, Transaction::Disputed(_)));
 
-        let chargeback = Message::Chargeback { client, tx };
+        let chargeback = Message::Chargeback {
+            client,
+            tx,
+            timestamp: None,
+        };
         assert!(account.apply(&chargeback, &mut history).is_ok());
-        assert_eq!(account.held, 0.0);
-        assert_eq!(account.total, 0.0);
-        assert_eq!(account.available, 0.0);
-        assert!(account.locked);
+        assert_eq!(account.book.held, 0.0);
+        assert_eq!(account.book.total, 0.0);
+        assert_eq!(account.book.available, 0.0);
+        assert!(account.book.locked);
 
-        let saved = history.get(&tx);
+        let saved = history.get(&tx).map(|(saved, _)| saved);
         assert!(saved.is_some());
         let saved = saved.unwrap();
         assert!(matches!(saved, Transaction::Reversed(_)));
     }
 
+    #[test]
+    fn accounts_locked_right_away_are_zombies() {
+        let client = 42;
+        let deposit = |tx| Message::Deposit {
+            amount: 1.0,
+            tx,
+            client,
+            timestamp: None,
+            effective_date: None,
+        };
+        let withdraw = Message::Withdraw {
+            amount: 5.0,
+            tx: 3,
+            client,
+            timestamp: None,
+        };
+        let apply = |account: &mut Account<Running>, history: &mut History, message: &Message| {
+            let applied = account.apply(message, history).is_ok();
+            account.count(message, applied);
+        };
+
+        let mut account = running(client);
+        let mut history = HashMap::new();
+        apply(&mut account, &mut history, &deposit(1));
+        apply(&mut account, &mut history, &withdraw);
+        assert!(!account.zombie());
+        for message in [
+            Message::Dispute {
+                client,
+                tx: 1,
+                timestamp: None,
+            },
+            Message::Chargeback {
+                client,
+                tx: 1,
+                timestamp: None,
+            },
+        ] {
+            apply(&mut account, &mut history, &message);
+        }
+        apply(&mut account, &mut history, &deposit(2));
+        assert!(account.zombie());
+        assert_eq!(
+            account.counters(),
+            Counters {
+                applied: 3,
+                rejected: 2,
+                transfers: 1,
+            }
+        );
+
+        // Funds which were moved before locking make an account of some use.
+        let mut account = running(client + 1);
+        let mut history = HashMap::new();
+        apply(&mut account, &mut history, &deposit(1));
+        apply(&mut account, &mut history, &deposit(2));
+        account.book.locked = true;
+        assert!(!account.zombie());
+    }
+
+    #[test]
+    fn batched_account_applies_every_message() {
+        for seed in 0..20 {
+            let batching = Arc::new(Batching::default());
+            batching.start();
+            sim::run(seed, async move {
+                let (done_tx, mut done_rx) = mpsc::channel(1);
+                let mut router = Router::new(8, 1);
+                Account::new(42)
+                    .start(
+                        &mut router,
+                        done_tx,
+                        History::new(),
+                        &Settings::current(),
+                        true,
+                        Arc::default(),
+                        batching,
+                    )
+                    .unwrap();
+                for tx in 1..=4 {
+                    let deposit = Message::Deposit {
+                        client: 42,
+                        amount: 1.0,
+                        tx,
+                        timestamp: None,
+                        effective_date: None,
+                    };
+                    let provenance = Provenance {
+                        source: "in.csv".into(),
+                        line: tx as u64,
+                        reference: None,
+                        correlation_id: None,
+                    };
+                    router
+                        .send(42, (deposit, provenance, Instant::now()))
+                        .await
+                        .unwrap();
+                }
+                drop(router);
+                let account = done_rx.recv().await.unwrap();
+                assert_eq!((account.book.available, account.book.total), (4.0, 4.0));
+                assert_eq!(account.counters.applied, 4);
+            });
+        }
+    }
+
+    #[test]
+    fn batched_account_prunes_history() {
+        let batching = Arc::new(Batching::default());
+        batching.start();
+        sim::run(0, async move {
+            let (done_tx, mut done_rx) = mpsc::channel(1);
+            let mut router = Router::new(8, 1);
+            let settings = Settings {
+                retention: Retention::Days(1),
+                ..Settings::current()
+            };
+            Account::new(42)
+                .start(
+                    &mut router,
+                    done_tx,
+                    History::new(),
+                    &settings,
+                    true,
+                    Arc::default(),
+                    batching,
+                )
+                .unwrap();
+            // Deposits of a batch, the first one days older than the rest, and a dispute of
+            // it, which comes too late.
+            let deposit = |tx, day| Message::Deposit {
+                client: 42,
+                amount: 1.0,
+                tx,
+                timestamp: Some(day * retention::DAY),
+                effective_date: None,
+            };
+            let dispute = Message::Dispute {
+                client: 42,
+                tx: 1,
+                timestamp: None,
+            };
+            let messages = [deposit(1, 0), deposit(2, 3), deposit(3, 3), dispute];
+            for (line, message) in (1..).zip(messages) {
+                let provenance = Provenance {
+                    source: "in.csv".into(),
+                    line,
+                    reference: None,
+                    correlation_id: None,
+                };
+                router
+                    .send(42, (message, provenance, Instant::now()))
+                    .await
+                    .unwrap();
+            }
+            drop(router);
+            let account = done_rx.recv().await.unwrap();
+            assert_eq!((account.book.held, account.book.total), (0.0, 3.0));
+            assert_eq!(account.pruned, HashSet::from([1]));
+        });
+    }
+
+    #[test]
+    fn quiet_client_releases_held_messages() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let (done_tx, mut done_rx) = mpsc::channel(1);
+            let mut router = Router::new(8, 1);
+            let settings = Settings {
+                lateness: Some(20),
+                release_idle: true,
+                ..Settings::current()
+            };
+            Account::new(42)
+                .start(
+                    &mut router,
+                    done_tx,
+                    History::new(),
+                    &settings,
+                    true,
+                    Arc::default(),
+                    Arc::default(),
+                )
+                .unwrap();
+            let deposit = |tx, timestamp| {
+                let deposit = Message::Deposit {
+                    client: 42,
+                    amount: 1.0,
+                    tx,
+                    timestamp: Some(timestamp),
+                    effective_date: None,
+                };
+                let provenance = Provenance {
+                    source: "in.csv".into(),
+                    line: tx as u64,
+                    reference: None,
+                    correlation_id: None,
+                };
+                (deposit, provenance, Instant::now())
+            };
+            router.send(42, deposit(1, 100)).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            // The first deposit is applied by now, so the second is late, though within
+            // lateness of it.
+            router.send(42, deposit(2, 90)).await.unwrap();
+            drop(router);
+            let account = done_rx.recv().await.unwrap();
+            assert_eq!(account.book.available, 1.0);
+            assert_eq!(
+                (account.counters.applied, account.counters.rejected),
+                (1, 1)
+            );
+        });
+    }
+
+    #[test]
+    fn parked_account_is_reloaded_as_it_was() {
+        let mut account = running(42);
+        account.activity = Some((100, 300));
+        account.counters = Counters {
+            applied: 2,
+            rejected: 1,
+            transfers: 2,
+        };
+        let mut history = HashMap::new();
+        for message in [
+            Message::Deposit {
+                client: 42,
+                amount: 2.0,
+                tx: 1,
+                timestamp: Some(100),
+                effective_date: None,
+            },
+            Message::Deposit {
+                client: 42,
+                amount: 3.0,
+                tx: 2,
+                timestamp: Some(300),
+                effective_date: Some(500),
+            },
+            Message::Dispute {
+                client: 42,
+                tx: 1,
+                timestamp: None,
+            },
+        ] {
+            account.book.mature(message.timestamp(), &mut history);
+            assert!(account.apply(&message, &mut history).is_ok());
+        }
+
+        let transactions = records(42, &history).collect();
+        let (reloaded, reloaded_history) =
+            Account::unparked(account.parked(), transactions).unwrap();
+        assert_eq!(reloaded_history, history);
+        assert_eq!(reloaded.activity, account.activity);
+        assert_eq!(reloaded.counters, account.counters);
+        assert_eq!(
+            (
+                reloaded.book.available,
+                reloaded.book.held,
+                reloaded.book.total,
+                reloaded.book.pending
+            ),
+            (0.0, 2.0, 5.0, 3.0)
+        );
+        assert_eq!(reloaded.book.maturing.clock, Some(300));
+        assert!(reloaded.book.maturing.due.contains(&(500, 2)));
+    }
+
     #[test]
     fn invalid_chargeback_is_handled() {
         let client = 42;
@@ -507,20 +2314,338 @@ mod tests {
             amount: 1.0,
             tx,
             client,
+            timestamp: None,
+            effective_date: None,
         };
 
         assert!(account.apply(&deposit, &mut history).is_ok());
 
-        let resolve = Message::Chargeback { client, tx };
+        let resolve = Message::Chargeback {
+            client,
+            tx,
+            timestamp: None,
+        };
         assert!(account.apply(&resolve, &mut history).is_ok());
-        assert_eq!(account.held, 0.0);
-        assert_eq!(account.total, 1.0);
-        assert_eq!(account.available, 1.0);
-        assert!(!account.locked);
+        assert_eq!(account.book.held, 0.0);
+        assert_eq!(account.book.total, 1.0);
+        assert_eq!(account.book.available, 1.0);
+        assert!(!account.book.locked);
 
-        let saved = history.get(&tx);
+        let saved = history.get(&tx).map(|(saved, _)| saved);
         assert!(saved.is_some());
         let saved = saved.unwrap();
         assert!(matches!(saved, Transaction::Deposited(_)));
     }
+
+    #[test]
+    fn panic_rolls_back_to_last_known_state() {
+        let mut account = running(42);
+        let mut history = HashMap::new();
+        let deposit = Message::Deposit {
+            client: 42,
+            tx: 1,
+            amount: 2.0,
+            timestamp: None,
+            effective_date: None,
+        };
+        assert!(account.apply(&deposit, &mut history).is_ok());
+
+        let outcome = account.supervised(1, &mut history, |account, history| {
+            account.book.available = 0.0;
+            account.book.held = 2.0;
+            history.insert(1, (Transaction::Disputed(2.0), None));
+            panic!("bug in apply");
+        });
+
+        assert!(matches!(outcome, Err(ProcessingError::Panicked)));
+        assert_eq!(
+            (
+                account.book.available,
+                account.book.held,
+                account.book.total
+            ),
+            (2.0, 0.0, 2.0)
+        );
+        assert_eq!(history.get(&1), Some(&(Transaction::Deposited(2.0), None)));
+    }
+
+    fn deposits(count: u32) -> Vec<Message> {
+        (1..=count)
+            .map(|tx| Message::Deposit {
+                client: 42,
+                tx,
+                amount: 0.5,
+                timestamp: None,
+                effective_date: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn deposits_in_bulk_match_one_at_a_time() {
+        let messages = deposits(100);
+        let (mut one, mut bulk) = (running(42), running(42));
+        let (mut one_history, mut bulk_history) = (HashMap::new(), HashMap::new());
+        for message in &messages {
+            one.apply(message, &mut one_history).unwrap();
+        }
+        let outcomes = bulk.apply_batch(&messages, &mut bulk_history);
+        assert!(outcomes.iter().all(Result::is_ok));
+        assert_eq!(bulk.book.total, one.book.total);
+        assert_eq!(bulk.book.available, 50.0);
+        assert_eq!(bulk_history, one_history);
+
+        bulk.book.locked = true;
+        let outcomes = bulk.apply_batch(&deposits(2), &mut bulk_history);
+        assert!(matches!(
+            outcomes[..],
+            [
+                Err(ProcessingError::Rejected(Rejection::AccountLocked)),
+                Err(ProcessingError::Rejected(Rejection::AccountLocked))
+            ]
+        ));
+        assert_eq!(bulk.book.total, 50.0);
+    }
+
+    #[test]
+    fn panic_in_batch_falls_back_to_one_at_a_time() {
+        let mut account = running(42);
+        let mut history = HashMap::new();
+        account.apply(&deposits(1)[0], &mut history).unwrap();
+
+        let messages = deposits(3);
+        let outcomes = account.supervised_batch(
+            &messages[1..],
+            &mut history,
+            |account, messages, history| {
+                account.apply_batch(messages, history);
+                panic!("bug in apply_batch");
+            },
+        );
+        // Deposits of the batch are rolled back before they are applied on their own.
+        assert!(matches!(outcomes[..], [Ok(()), Ok(())]));
+        assert_eq!(account.book.total, 1.5);
+        assert_eq!(history.len(), 3);
+    }
+
+    #[test]
+    #[cfg(feature = "debug-invariants")]
+    #[should_panic(expected = "Invariant violated: client 42 holds")]
+    fn violated_invariant_is_not_supervised() {
+        let mut account = running(42);
+        let mut history = HashMap::new();
+        account.book.held = -1.0;
+        let deposit = Message::Deposit {
+            client: 42,
+            tx: 1,
+            amount: 2.0,
+            timestamp: None,
+            effective_date: None,
+        };
+        let _ = account.supervised_apply(&deposit, &mut history);
+    }
+}
+
+/// Randomized tests: seeded message sequences are applied to an account and to a plain
+/// sequential model of the rules, every step is checked against invariants and the model.
+/// A failure reports its seed, which reproduces the sequence with [`check`].
+#[cfg(test)]
+mod properties {
+    use super::{Account, Counters, Running};
+    use crate::{engine::Book, message::Message, rng::Rng, state::TransactionState};
+    use std::collections::{HashMap, HashSet};
+
+    const CASES: u64 = 500;
+    const STEPS: usize = 64;
+    const CLIENT: u16 = 7;
+
+    /// Account rules in quarters of a unit, with exact integer arithmetic.
+    #[derive(Debug, Default)]
+    struct Model {
+        available: i64,
+        held: i64,
+        locked: bool,
+        deposits: HashMap<u32, (i64, TransactionState)>,
+    }
+
+    impl Model {
+        /// Applies `message`, returns `false` if it is rejected.
+        fn apply(&mut self, message: &Message) -> bool {
+            if self.locked && !message.is_settle() {
+                return false;
+            }
+            match *message {
+                Message::Deposit { tx, amount, .. } => {
+                    let amount = quarters(amount);
+                    self.available += amount;
+                    self.deposits
+                        .insert(tx, (amount, TransactionState::Deposited));
+                }
+                Message::Withdraw { amount, .. } => {
+                    let amount = quarters(amount);
+                    if self.available < amount {
+                        return false;
+                    }
+                    self.available -= amount;
+                }
+                Message::Dispute { tx, .. } => {
+                    if let Some((amount, state)) = self.deposits.get_mut(&tx) {
+                        if *state == TransactionState::Deposited && self.available >= *amount {
+                            self.available -= *amount;
+                            self.held += *amount;
+                            *state = TransactionState::Disputed;
+                        }
+                    }
+                }
+                Message::Resolve { tx, .. } => {
+                    if let Some((amount, state)) = self.deposits.get_mut(&tx) {
+                        if *state == TransactionState::Disputed {
+                            self.available += *amount;
+                            self.held -= *amount;
+                            *state = TransactionState::Deposited;
+                        }
+                    }
+                }
+                Message::Chargeback { tx, .. } => {
+                    if let Some((amount, state)) = self.deposits.get_mut(&tx) {
+                        if *state == TransactionState::Disputed {
+                            self.held -= *amount;
+                            self.locked = true;
+                            *state = TransactionState::Reversed;
+                        }
+                    }
+                }
+                // Withdrawals are settled as they are applied.
+                Message::Settle { .. } => {}
+            }
+            true
+        }
+    }
+
+    fn quarters(amount: f32) -> i64 {
+        (amount * 4.0) as i64
+    }
+
+    fn units(quarters: i64) -> f32 {
+        quarters as f32 / 4.0
+    }
+
+    /// Amounts are whole quarters, which `f32` holds exactly at these magnitudes, so the engine
+    /// and the model must agree to the last bit. Transaction ids come from a small pool, so
+    /// disputes hit existing deposits and ids get reused.
+    fn message(rng: &mut Rng) -> Message {
+        let client = CLIENT;
+        let tx = rng.below(8) as u32;
+        let amount = units(1 + rng.below(40) as i64);
+        match rng.below(10) {
+            0..=2 => Message::Deposit {
+                client,
+                tx,
+                amount,
+                timestamp: None,
+                effective_date: None,
+            },
+            3..=4 => Message::Withdraw {
+                client,
+                tx,
+                amount,
+                timestamp: None,
+            },
+            5..=6 => Message::Dispute {
+                client,
+                tx,
+                timestamp: None,
+            },
+            7..=8 => Message::Resolve {
+                client,
+                tx,
+                timestamp: None,
+            },
+            _ => Message::Chargeback {
+                client,
+                tx,
+                timestamp: None,
+            },
+        }
+    }
+
+    /// Runs the case of `seed`, returns the failing step and what went wrong.
+    fn check(seed: u64) -> Result<(), String> {
+        let mut rng = Rng::new(seed);
+        let mut account = Account {
+            client: CLIENT,
+            book: Book::default(),
+            activity: None,
+            counters: Counters::default(),
+            pruned: HashSet::new(),
+            _state: Running,
+        };
+        let mut history = HashMap::new();
+        let mut model = Model::default();
+
+        for step in 0..STEPS {
+            let message = message(&mut rng);
+            let before = (
+                account.book.available,
+                account.book.held,
+                account.book.total,
+            );
+            let was_locked = account.book.locked;
+            let accepted = account.apply(&message, &mut history).is_ok();
+            let after = (
+                account.book.available,
+                account.book.held,
+                account.book.total,
+            );
+            let fail = |what: String| Err(format!("step {step}, {message:?}: {what}"));
+
+            if account.book.total != account.book.available + account.book.held {
+                return fail("total is not available + held".into());
+            }
+            if account.book.held < 0.0 {
+                return fail("held is negative".into());
+            }
+            if was_locked && (accepted || before != after) {
+                return fail("locked account has changed".into());
+            }
+            if accepted != model.apply(&message) {
+                return fail(format!("engine accepted: {accepted}, model did not"));
+            }
+
+            let expected = (
+                units(model.available),
+                units(model.held),
+                units(model.available + model.held),
+            );
+            if (after, account.book.locked) != (expected, model.locked) {
+                return fail(format!(
+                    "engine {after:?} locked: {}, model {expected:?} locked: {}",
+                    account.book.locked, model.locked
+                ));
+            }
+            let tx = message.transaction_id();
+            let engine = history
+                .get(&tx)
+                .map(|(transaction, _)| (transaction.amount(), super::state(transaction)));
+            let expected = model
+                .deposits
+                .get(&tx)
+                .map(|&(amount, state)| (units(amount), state));
+            if engine != expected {
+                return fail(format!(
+                    "transaction is {engine:?} in engine, {expected:?} in model"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn engine_agrees_with_model() {
+        for seed in 0..CASES {
+            if let Err(err) = check(seed) {
+                panic!("seed {seed}: {err}");
+            }
+        }
+    }
 }
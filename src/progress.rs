@@ -0,0 +1,185 @@
+//! Tracks how far the parser got through the input, for progress reporting on long batch
+//! runs. Updated by the parser after every record, read by [`report`] and the `/health`
+//! endpoint.
+
+use std::{
+    fmt::Display,
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        OnceLock,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+struct Progress {
+    rows: AtomicU64,
+    bytes: AtomicU64,
+    total_bytes: AtomicU64,
+    done: AtomicBool,
+    started: OnceLock<Instant>,
+}
+
+static PROGRESS: Progress = Progress {
+    rows: AtomicU64::new(0),
+    bytes: AtomicU64::new(0),
+    total_bytes: AtomicU64::new(0),
+    done: AtomicBool::new(false),
+    started: OnceLock::new(),
+};
+
+/// Marks the beginning of input, `total_bytes` being the size of the input file.
+pub fn start(total_bytes: u64) {
+    PROGRESS.total_bytes.store(total_bytes, Ordering::Relaxed);
+    let _ = PROGRESS.started.set(Instant::now());
+}
+
+/// Records that another row was read, ending at byte offset `position`.
+pub fn row(position: u64) {
+    PROGRESS.rows.fetch_add(1, Ordering::Relaxed);
+    PROGRESS.bytes.store(position, Ordering::Relaxed);
+}
+
+/// Marks the end of input.
+pub fn finish() {
+    PROGRESS.done.store(true, Ordering::Release);
+}
+
+pub fn snapshot() -> Snapshot {
+    Snapshot {
+        rows: PROGRESS.rows.load(Ordering::Relaxed),
+        bytes: PROGRESS.bytes.load(Ordering::Relaxed),
+        total_bytes: PROGRESS.total_bytes.load(Ordering::Relaxed),
+        elapsed: PROGRESS
+            .started
+            .get()
+            .map(Instant::elapsed)
+            .unwrap_or_default(),
+        done: PROGRESS.done.load(Ordering::Acquire),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot {
+    rows: u64,
+    bytes: u64,
+    total_bytes: u64,
+    elapsed: Duration,
+    done: bool,
+}
+
+impl Snapshot {
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    fn rows_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.rows as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    fn fraction(&self) -> Option<f64> {
+        (self.total_bytes > 0).then(|| self.bytes as f64 / self.total_bytes as f64)
+    }
+
+    /// Remaining time, extrapolated from the share of input bytes consumed so far.
+    fn eta(&self) -> Option<Duration> {
+        let fraction = self.fraction().filter(|fraction| *fraction > 0.0)?;
+        let total = self.elapsed.as_secs_f64() / fraction;
+        Some(Duration::from_secs_f64(
+            (total - self.elapsed.as_secs_f64()).max(0.0),
+        ))
+    }
+
+    pub fn json(&self) -> String {
+        let eta = self
+            .eta()
+            .map_or("null".to_owned(), |eta| eta.as_secs().to_string());
+        format!(
+            "{{\"rows\":{},\"rows_per_sec\":{:.0},\"bytes\":{},\"total_bytes\":{},\"eta_secs\":{eta},\"done\":{}}}",
+            self.rows,
+            self.rows_per_sec(),
+            self.bytes,
+            self.total_bytes,
+            self.done
+        )
+    }
+}
+
+impl Display for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} rows, {:.0} rows/s", self.rows, self.rows_per_sec())?;
+        if let Some(fraction) = self.fraction() {
+            write!(f, ", {:.1}%", fraction * 100.0)?;
+        }
+        match self.eta() {
+            Some(eta) if !self.done => {
+                let secs = eta.as_secs();
+                write!(
+                    f,
+                    ", ETA {:02}:{:02}:{:02}",
+                    secs / 3600,
+                    secs / 60 % 60,
+                    secs % 60
+                )
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Spawns a thread redrawing progress line on stderr every `interval`, until input is
+/// exhausted.
+pub fn report(interval: Duration) -> JoinHandle<()> {
+    std::thread::spawn(move || loop {
+        let snapshot = snapshot();
+        let _ = write!(std::io::stderr().lock(), "\r\x1b[2K{snapshot}");
+        if snapshot.done {
+            let _ = writeln!(std::io::stderr().lock());
+            break;
+        }
+        std::thread::sleep(interval);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Snapshot;
+    use std::time::Duration;
+
+    #[test]
+    fn eta_is_extrapolated_from_bytes_read() {
+        let snapshot = Snapshot {
+            rows: 100,
+            bytes: 250,
+            total_bytes: 1000,
+            elapsed: Duration::from_secs(10),
+            done: false,
+        };
+
+        assert_eq!(snapshot.eta(), Some(Duration::from_secs(30)));
+        assert_eq!(
+            snapshot.to_string(),
+            "100 rows, 10 rows/s, 25.0%, ETA 00:00:30"
+        );
+    }
+
+    #[test]
+    fn eta_is_unknown_before_first_row() {
+        let snapshot = Snapshot {
+            rows: 0,
+            bytes: 0,
+            total_bytes: 1000,
+            elapsed: Duration::ZERO,
+            done: false,
+        };
+
+        assert_eq!(snapshot.eta(), None);
+        assert!(snapshot.json().contains("\"eta_secs\":null"));
+    }
+}
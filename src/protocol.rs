@@ -0,0 +1,180 @@
+//! Channel protocol between the router, its workers and the collector of their results, kept
+//! apart from account logic so that every interleaving of it can be checked in tests.
+//!
+//! - Router owns the only sender of every worker inbox, dropping [`Router`] closes them all.
+//! - A worker runs until its inbox is closed and drained, then reports its result on the done
+//!   channel, if it has one. Its clone of the done sender is dropped only once the result is
+//!   sent. Router may close a single inbox with [`Router::close`], e.g. to evict a worker.
+//! - Collector receives results until every done sender is dropped: the router's own once it
+//!   returns, and those of workers once they reported. So it sees every result, and does not
+//!   wait on anything after the last one.
+
+use std::{collections::HashMap, fmt::Display, future::Future, hash::Hash};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use crate::{
+    log,
+    metrics::{self, Channel},
+    send_errors,
+};
+
+/// Inboxes of running workers, by key.
+pub struct Router<K, M> {
+    inboxes: HashMap<K, Sender<M>>,
+    /// Messages every inbox holds.
+    capacity: usize,
+    /// Results the `done` channel of every worker holds, to tell how many it's queuing.
+    results: usize,
+}
+
+impl<K, M> Router<K, M>
+where
+    K: Copy + Eq + Hash + Display + Send + 'static,
+    M: Send + 'static,
+{
+    pub fn new(capacity: usize, results: usize) -> Self {
+        Router {
+            inboxes: HashMap::new(),
+            capacity,
+            results,
+        }
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.inboxes.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inboxes.len()
+    }
+
+    /// Spawns worker for `key`: future returned by `work` runs over the worker's inbox, and
+    /// its output, unless it's `None`, is sent on `done`.
+    pub fn start<W, F, R>(&mut self, key: K, done: Sender<R>, work: W)
+    where
+        W: FnOnce(Receiver<M>) -> F,
+        F: Future<Output = Option<R>> + Send + 'static,
+        R: Send + 'static,
+    {
+        let (inbox, rx) = mpsc::channel(self.capacity);
+        let results = self.results;
+        let worker = work(rx);
+        spawn(async move {
+            let Some(result) = worker.await else {
+                return;
+            };
+            if done.send(result).await.is_err() {
+                log::error!(
+                    log::Span::new("apply").with("client", key),
+                    "Failed to send results, collector is gone"
+                );
+                send_errors::failed(Channel::Writer, format_args!("results of worker {key}"));
+                return;
+            }
+            metrics::channel_depth(Channel::Writer, results - done.capacity());
+        });
+        self.inboxes.insert(key, inbox);
+    }
+
+    /// Closes inbox of worker for `key`, so that it stops once it's drained. Returns `false`
+    /// when there is no worker for `key`.
+    pub fn close(&mut self, key: &K) -> bool {
+        self.inboxes.remove(key).is_some()
+    }
+
+    /// Sends `message` to worker of `key`, returns how many messages its inbox holds then.
+    /// Gives the message back when there is no worker for `key`, or when the worker is gone,
+    /// in which case it is forgotten.
+    pub async fn send(&mut self, key: K, message: M) -> Result<usize, M> {
+        let Some(inbox) = self.inboxes.get(&key) else {
+            return Err(message);
+        };
+        match inbox.send(message).await {
+            Ok(()) => Ok(self.capacity - inbox.capacity()),
+            Err(err) => {
+                self.inboxes.remove(&key);
+                Err(err.0)
+            }
+        }
+    }
+}
+
+/// Spawns a worker on the runtime, or on the [`sim`](crate::sim) executor when a test runs
+/// under one.
+fn spawn<F>(task: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    #[cfg(test)]
+    if crate::sim::active() {
+        crate::sim::spawn(task);
+        return;
+    }
+    tokio::spawn(task);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Router;
+    use crate::sim;
+    use std::collections::BTreeMap;
+    use tokio::sync::mpsc;
+
+    const LIMIT: usize = 200_000;
+
+    /// Routes `input` to workers summing values of their key, under every interleaving of the
+    /// producer, router, workers and collector, with room for a single message in every
+    /// channel. Each schedule must end with a result from every worker.
+    fn check(input: &'static [(u16, u32)]) -> usize {
+        let mut expected = BTreeMap::new();
+        for &(key, value) in input {
+            *expected.entry(key).or_insert(0) += value;
+        }
+
+        sim::explore(LIMIT, move || {
+            let expected = expected.clone();
+            async move {
+                let (tx, mut rx) = mpsc::channel::<(u16, u32)>(1);
+                let (done_tx, mut done_rx) = mpsc::channel(1);
+                sim::spawn(async move {
+                    for &message in input {
+                        tx.send(message).await.unwrap();
+                    }
+                });
+                sim::spawn(async move {
+                    let mut router = Router::new(1, 1);
+                    while let Some((key, value)) = rx.recv().await {
+                        if !router.contains(&key) {
+                            router.start(key, done_tx.clone(), move |mut inbox| async move {
+                                let mut sum = 0;
+                                while let Some(value) = inbox.recv().await {
+                                    sum += value;
+                                }
+                                Some((key, sum))
+                            });
+                        }
+                        router.send(key, value).await.unwrap();
+                    }
+                });
+
+                let mut results = BTreeMap::new();
+                while let Some((key, sum)) = done_rx.recv().await {
+                    assert!(results.insert(key, sum).is_none(), "{key} reported twice");
+                }
+                assert_eq!(results, expected);
+            }
+        })
+    }
+
+    #[test]
+    fn no_interleaving_loses_results() {
+        let schedules = check(&[(1, 1), (2, 10), (1, 100)]);
+        assert!(schedules < LIMIT, "explored only {schedules} schedules");
+    }
+
+    #[test]
+    fn no_interleaving_deadlocks_without_input() {
+        let schedules = check(&[]);
+        assert!(schedules < LIMIT, "explored only {schedules} schedules");
+    }
+}
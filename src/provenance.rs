@@ -0,0 +1,42 @@
+//! Where messages come from, so a rejected or suspicious message can be traced back to the
+//! record it was read from. Provenance travels with every message from the parser, and is
+//! written to rejection logs, the [`dlq`](crate::dlq) and the [`event_log`](crate::event_log).
+//!
+//! Source of a message is the input file of `trp process`, the peer address of the
+//! connection of `trp serve`, or the event log of `trp replay`. Line is the one the record
+//! started on in csv and ndjson input, and the position of the record in binary input, both
+//! counting from 1.
+//!
+//! Records may also carry a free-text `reference` of the system they come from, e.g. of a
+//! bank transfer. It isn't used by the engine, and is written along with the rest of the
+//! provenance, so outputs can be matched back to it.
+//!
+//! Likewise, a `correlation_id` of the submission, e.g. the request id of the upstream service
+//! which sent it, is echoed wherever the outcome of the message is: in the event log, the
+//! dead letter queues, account events over [`grpc`](crate::grpc) and log lines of the
+//! message: as a field of the span while it's parsed, and following the source once it's
+//! routed and applied, as `in.csv:3 [req-42]`.
+
+use std::{fmt::Display, sync::Arc};
+
+/// Origin of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    /// Shared by every message of the source.
+    pub source: Arc<str>,
+    pub line: u64,
+    /// Reference of the record, if it has one.
+    pub reference: Option<Arc<str>>,
+    /// Correlation id of the record, if it has one.
+    pub correlation_id: Option<Arc<str>>,
+}
+
+impl Display for Provenance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.source, self.line)?;
+        match &self.correlation_id {
+            Some(id) => write!(f, " [{id}]"),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Backends input files are read with, picked with `input_backend` of the engine
+//! [configuration](crate::config):
+//!
+//! - `buffered`, the default: the parser reads the file itself, through the small buffer of
+//!   its decoder.
+//! - `readahead`: a thread of its own reads the file in [`CHUNK`]s, up to [`DEPTH`] of them
+//!   ahead of the parser, so that reading from disk and parsing overlap, and on Linux the
+//!   kernel is told the file is read sequentially, which doubles its own readahead. This
+//!   speeds up cold-cache reads of large files from fast disks, where a single small read at
+//!   a time leaves the disk idle while records are parsed.
+//!
+//! Both go through `read(2)`: io_uring needs a crate this build doesn't have, and large
+//! sequential reads are what it would do for a single file anyway.
+
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{self, Read},
+    str::FromStr,
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+/// Bytes read at once by the reading thread.
+pub const CHUNK: usize = 4 << 20;
+/// Chunks read ahead of the parser.
+pub const DEPTH: usize = 4;
+
+/// How input files are read.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    Buffered,
+    ReadAhead,
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "buffered" => Ok(Backend::Buffered),
+            "readahead" => Ok(Backend::ReadAhead),
+            other => Err(anyhow::anyhow!(
+                "Unknown input backend {other}, expected buffered or readahead"
+            )),
+        }
+    }
+}
+
+impl Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Backend::Buffered => "buffered",
+            Backend::ReadAhead => "readahead",
+        })
+    }
+}
+
+/// Opens `file` for reading with `backend`.
+pub fn open(file: File, backend: Backend) -> Box<dyn Read + Send> {
+    match backend {
+        Backend::Buffered => Box::new(file),
+        Backend::ReadAhead => Box::new(ReadAhead::new(file)),
+    }
+}
+
+/// Reads a file on a thread of its own, ahead of whoever reads from it.
+pub struct ReadAhead {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ReadAhead {
+    /// Starts reading `file`. The thread stops once the file is read, or this is dropped.
+    pub fn new(file: File) -> Self {
+        sequential(&file);
+        ReadAhead::spawn(file, CHUNK)
+    }
+
+    fn spawn<R: Read + Send + 'static>(mut reader: R, size: usize) -> Self {
+        let (tx, rx) = mpsc::sync_channel(DEPTH);
+        thread::spawn(move || loop {
+            let mut chunk = vec![0; size];
+            let read = fill(&mut reader, &mut chunk);
+            let last = !matches!(read, Ok(read) if read == size);
+            let sent = tx.send(read.map(|read| {
+                chunk.truncate(read);
+                chunk
+            }));
+            if last || sent.is_err() {
+                break;
+            }
+        });
+        ReadAhead {
+            chunks: rx,
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ReadAhead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            // Once the thread is gone, its last chunk was read.
+            let Ok(chunk) = self.chunks.recv() else {
+                return Ok(0);
+            };
+            self.chunk = chunk?;
+            self.pos = 0;
+        }
+        let read = buf.len().min(self.chunk.len() - self.pos);
+        buf[..read].copy_from_slice(&self.chunk[self.pos..self.pos + read]);
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+/// Reads into `chunk` until it's full or `reader` is exhausted, returns bytes read.
+fn fill<R: Read>(reader: &mut R, chunk: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < chunk.len() {
+        match reader.read(&mut chunk[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+/// Tells the kernel `file` is read from start to end, so it reads further ahead.
+#[cfg(target_os = "linux")]
+fn sequential(file: &File) {
+    use std::os::unix::io::AsRawFd;
+    // Only advice, reading works the same when it's not taken.
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sequential(_: &File) {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backend, ReadAhead};
+    use std::io::Read;
+
+    #[test]
+    fn chunks_are_read_in_order() {
+        let input: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let mut read = Vec::new();
+        ReadAhead::spawn(std::io::Cursor::new(input.clone()), 1000)
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, input);
+
+        let mut read = Vec::new();
+        ReadAhead::spawn(std::io::Cursor::new(input[..999].to_vec()), 1000)
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, input[..999]);
+
+        assert_eq!("readahead".parse::<Backend>().unwrap(), Backend::ReadAhead);
+        assert_eq!(Backend::Buffered.to_string(), "buffered");
+        assert!("io_uring".parse::<Backend>().is_err());
+    }
+}
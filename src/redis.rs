@@ -0,0 +1,308 @@
+//! Live balances in Redis, enabled with `trp serve --redis`: a hash per client, at
+//! `<prefix><client>`, with `available`, `held`, `total` and `locked` fields, kept up to date
+//! as messages are applied, so other services can read balances without asking trp.
+//!
+//! Account tasks never wait for Redis. They leave the latest balances of their client with
+//! the sink, and a thread writes whatever changed every [`INTERVAL`], pipelining up to
+//! [`BATCH`] `HSET` commands at a time. While Redis is unreachable, balances keep piling up,
+//! one entry per client, and the thread reconnects with growing backoff, so nothing but
+//! freshness is lost until Redis is back. Whatever could not be written once the run is over
+//! is logged as lost.
+//!
+//! With `--outbox`, live balances are held back, and balances are only written once they are
+//! saved to the state, from its [`outbox`](crate::outbox), which keeps whatever could not be
+//! written for the next run instead.
+
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crate::{log, outbox, state::AccountRecord};
+
+/// Prefix of keys of hashes, unless set otherwise.
+pub const DEFAULT_PREFIX: &str = "trp:client:";
+/// How often changed balances are written.
+pub const INTERVAL: Duration = Duration::from_millis(100);
+/// Most commands sent in one pipeline.
+pub const BATCH: usize = 1000;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STOPPED: AtomicBool = AtomicBool::new(false);
+/// Latest balances of clients which are not written yet.
+static PENDING: Mutex<BTreeMap<u16, AccountRecord>> = Mutex::new(BTreeMap::new());
+
+/// Connection to Redis, opened on first use and dropped on failure.
+#[derive(Debug)]
+struct Sink {
+    addr: String,
+    prefix: String,
+    stream: Option<BufReader<TcpStream>>,
+}
+
+impl Sink {
+    fn new(addr: String, prefix: String) -> Self {
+        Sink {
+            addr,
+            prefix,
+            stream: None,
+        }
+    }
+
+    /// Writes `records` in a single pipeline. Commands Redis refused are logged and dropped,
+    /// since sending them again would not help.
+    fn write(&mut self, records: &[AccountRecord]) -> Result<(), anyhow::Error> {
+        let mut pipeline = Vec::new();
+        for record in records {
+            // Debug keeps the decimal point of whole amounts, like output does.
+            command(
+                &mut pipeline,
+                &[
+                    "HSET",
+                    &format!("{}{}", self.prefix, record.client),
+                    "available",
+                    &format!("{:?}", record.available),
+                    "held",
+                    &format!("{:?}", record.held),
+                    "total",
+                    &format!("{:?}", record.total),
+                    "locked",
+                    &record.locked.to_string(),
+                ],
+            );
+        }
+
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => {
+                let stream = TcpStream::connect(&self.addr)?;
+                stream.set_read_timeout(Some(TIMEOUT))?;
+                stream.set_write_timeout(Some(TIMEOUT))?;
+                self.stream.insert(BufReader::new(stream))
+            }
+        };
+        let outcome = (|| {
+            stream.get_mut().write_all(&pipeline)?;
+            let mut reply = String::new();
+            for record in records {
+                reply.clear();
+                if stream.read_line(&mut reply)? == 0 {
+                    return Err(anyhow::anyhow!("Connection closed by Redis"));
+                }
+                if let Some(err) = reply.strip_prefix('-') {
+                    log::error!(log::Span::new("redis"), client = record.client; "Redis refused balances: {}", err.trim_end());
+                }
+            }
+            Ok(())
+        })();
+        if outcome.is_err() {
+            self.stream = None;
+        }
+        outcome
+    }
+
+    /// Writes everything in `pending`, [`BATCH`] at a time. Balances which could not be
+    /// written are put back, unless newer ones arrived meanwhile.
+    fn flush(
+        &mut self,
+        pending: &Mutex<BTreeMap<u16, AccountRecord>>,
+    ) -> Result<(), anyhow::Error> {
+        loop {
+            let batch: Vec<_> = {
+                let mut pending = pending.lock().unwrap_or_else(|err| err.into_inner());
+                let mut batch = Vec::new();
+                while batch.len() < BATCH {
+                    match pending.pop_first() {
+                        Some((_, record)) => batch.push(record),
+                        None => break,
+                    }
+                }
+                batch
+            };
+            if batch.is_empty() {
+                return Ok(());
+            }
+            if let Err(err) = self.write(&batch) {
+                let mut pending = pending.lock().unwrap_or_else(|err| err.into_inner());
+                for record in batch {
+                    pending.entry(record.client).or_insert(record);
+                }
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// Appends `args` to `out` as a RESP array of bulk strings.
+fn command(out: &mut Vec<u8>, args: &[&str]) {
+    out.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n{arg}\r\n", arg.len()).as_bytes());
+    }
+}
+
+/// Starts keeping balances reported with [`update`].
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Leaves `record`, the latest balances of its client, to be written, unless they are held
+/// back for the [`outbox`].
+pub fn update(record: AccountRecord) {
+    if outbox::enabled() {
+        return;
+    }
+    deliver([record]);
+}
+
+/// Leaves `records` to be written, balances of the outbox among them.
+pub fn deliver(records: impl IntoIterator<Item = AccountRecord>) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut pending = PENDING.lock().unwrap_or_else(|err| err.into_inner());
+    for record in records {
+        pending.insert(record.client, record);
+    }
+}
+
+/// Takes balances which were not written by the time the thread stopped, for the outbox.
+pub fn undelivered() -> Vec<AccountRecord> {
+    std::mem::take(&mut *PENDING.lock().unwrap_or_else(|err| err.into_inner()))
+        .into_values()
+        .collect()
+}
+
+/// Spawns a thread writing balances to Redis at `addr`, under keys starting with `prefix`,
+/// until [`stop`] is called.
+pub fn run(addr: String, prefix: String) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let span = log::Span::new("redis").with("addr", &addr);
+        let mut sink = Sink::new(addr, prefix);
+        let mut backoff = INTERVAL;
+        let mut failing = false;
+        loop {
+            let stopped = STOPPED.load(Ordering::Acquire);
+            match sink.flush(&PENDING) {
+                Ok(()) => {
+                    if failing {
+                        log::info!(span, "Redis is back, balances are up to date");
+                    }
+                    failing = false;
+                    backoff = INTERVAL;
+                }
+                Err(err) => {
+                    if !failing {
+                        log::warn!(span, "Failed to write balances to Redis, retrying: {err}");
+                    }
+                    failing = true;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+            if stopped {
+                let lost = PENDING.lock().unwrap_or_else(|err| err.into_inner()).len();
+                if lost > 0 && outbox::enabled() {
+                    log::warn!(span, clients = lost; "Balances of clients were not written to Redis, leaving them in the outbox");
+                } else if lost > 0 {
+                    log::error!(span, clients = lost; "Balances of clients were not written to Redis");
+                }
+                break;
+            }
+            std::thread::sleep(backoff);
+        }
+    })
+}
+
+/// Writes what is left and stops the thread.
+pub fn stop() {
+    STOPPED.store(true, Ordering::Release);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{command, Sink};
+    use crate::state::AccountRecord;
+    use std::{
+        collections::BTreeMap,
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::Mutex,
+    };
+
+    fn record(client: u16, available: f32) -> AccountRecord {
+        AccountRecord {
+            client,
+            available,
+            held: 0.0,
+            total: available,
+            locked: false,
+        }
+    }
+
+    #[test]
+    fn commands_are_encoded_as_resp() {
+        let mut out = Vec::new();
+        command(&mut out, &["HSET", "trp:client:1", "held", "0.0"]);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "*4\r\n$4\r\nHSET\r\n$12\r\ntrp:client:1\r\n$4\r\nheld\r\n$3\r\n0.0\r\n"
+        );
+    }
+
+    #[test]
+    fn balances_are_kept_until_redis_is_back() {
+        // Nothing listens on a port which was just released.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let pending = Mutex::new(BTreeMap::from([(1, record(1, 1.0)), (2, record(2, 2.0))]));
+        let mut sink = Sink::new(addr.clone(), "trp:".to_string());
+        assert!(sink.flush(&pending).is_err());
+        // Newer balances win over the ones put back.
+        pending.lock().unwrap().insert(1, record(1, 5.0));
+        assert!(sink.flush(&pending).is_err());
+        assert_eq!(pending.lock().unwrap()[&1].available, 5.0);
+
+        let listener = TcpListener::bind(&addr).unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut keys = Vec::new();
+            for _ in 0..2 {
+                // Every HSET is an array of 10 bulk strings, the key second and available fourth.
+                let mut lines = Vec::new();
+                for _ in 0..21 {
+                    let mut line = String::new();
+                    stream.read_line(&mut line).unwrap();
+                    lines.push(line.trim_end().to_string());
+                }
+                keys.push((lines[4].clone(), lines[8].clone()));
+                stream.get_mut().write_all(b":4\r\n").unwrap();
+            }
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).unwrap();
+            keys
+        });
+        sink.flush(&pending).unwrap();
+        assert!(pending.lock().unwrap().is_empty());
+        drop(sink);
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                ("trp:1".to_string(), "5.0".to_string()),
+                ("trp:2".to_string(), "2.0".to_string()),
+            ]
+        );
+    }
+}
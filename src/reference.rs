@@ -0,0 +1,286 @@
+//! Reference engine, used by `trp process --reference`: applies messages one by one on a
+//! single thread, with no channels or tasks in between.
+//!
+//! It is deliberately kept simple rather than fast, so results of the sharded engine can be
+//! checked against it. Balances are updated in the same order and with the same `f32`
+//! operations as in [`processor`](crate::processor), so both engines agree to the last bit.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use crate::{
+    config, creation,
+    format::{self, Format, Source},
+    reserve, schema,
+    settlement::{self, Settlement},
+    state::AccountRecord,
+    tiers::{self, Rules},
+    writer::Row,
+    Message,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transaction {
+    Pending(f32),
+    Settled(f32),
+    Disputed(f32),
+    Reversed,
+    /// Withdrawal pending settlement.
+    Authorized(f32),
+    Withdrawn,
+}
+
+#[derive(Debug, Default)]
+struct Client {
+    available: f32,
+    held: f32,
+    total: f32,
+    locked: bool,
+    pending: f32,
+    /// Latest timestamp of messages of the client.
+    clock: Option<u64>,
+    /// Value dates of pending deposits and settlement dates of pending withdrawals, with
+    /// their ids.
+    due: BTreeSet<(u64, u32)>,
+    settlement: Option<Settlement>,
+    minimum: f32,
+    rules: Rules,
+    transactions: BTreeMap<u32, Transaction>,
+}
+
+impl Client {
+    /// Applies `message`, ignoring it when it is rejected. Deposits and withdrawals due by
+    /// its timestamp become available and settled first.
+    fn apply(&mut self, message: &Message) {
+        if let Some(timestamp) = message.timestamp() {
+            self.clock = Some(self.clock.map_or(timestamp, |clock| clock.max(timestamp)));
+        }
+        while let Some(&(date, tx)) = self.due.first() {
+            if self.clock.is_none_or(|clock| date > clock) {
+                break;
+            }
+            self.due.pop_first();
+            match self.transactions.get(&tx) {
+                Some(&Transaction::Pending(amount)) => {
+                    self.pending -= amount;
+                    self.available += amount;
+                    self.transactions.insert(tx, Transaction::Settled(amount));
+                }
+                Some(&Transaction::Authorized(amount)) => self.settle(tx, amount),
+                _ => {}
+            }
+        }
+
+        if self.locked && !message.is_settle() {
+            return;
+        }
+        match *message {
+            Message::Deposit {
+                tx,
+                amount,
+                effective_date,
+                ..
+            } => match effective_date.filter(|date| self.clock.is_none_or(|clock| clock < *date)) {
+                Some(date) => {
+                    self.pending += amount;
+                    self.total += amount;
+                    self.due.insert((date, tx));
+                    self.transactions.insert(tx, Transaction::Pending(amount));
+                }
+                None => {
+                    self.available += amount;
+                    self.total += amount;
+                    self.transactions.insert(tx, Transaction::Settled(amount));
+                }
+            },
+            Message::Withdraw {
+                tx,
+                amount,
+                timestamp,
+                ..
+            } => {
+                let Rules {
+                    max_withdrawal,
+                    withdrawal_fee: fee,
+                    overdraft,
+                } = self.rules;
+                if max_withdrawal.is_none_or(|max| amount <= max)
+                    && self.available + overdraft >= amount + fee
+                    && (self.minimum <= 0.0 || self.available - amount - fee >= self.minimum)
+                {
+                    self.available -= amount;
+                    self.available -= fee;
+                    self.total -= fee;
+                    match self.settlement {
+                        None => self.total -= amount,
+                        Some(settlement) => {
+                            self.held += amount;
+                            if let Some(due) = timestamp
+                                .zip(settlement.after)
+                                .map(|(timestamp, after)| timestamp.saturating_add(after))
+                            {
+                                self.due.insert((due, tx));
+                            }
+                            self.transactions
+                                .insert(tx, Transaction::Authorized(amount));
+                        }
+                    }
+                }
+            }
+            Message::Dispute { tx, .. } => {
+                if let Some(&Transaction::Settled(amount)) = self.transactions.get(&tx) {
+                    if self.available >= amount {
+                        self.available -= amount;
+                        self.held += amount;
+                        self.transactions.insert(tx, Transaction::Disputed(amount));
+                    }
+                }
+            }
+            Message::Resolve { tx, .. } => {
+                if let Some(&Transaction::Disputed(amount)) = self.transactions.get(&tx) {
+                    self.available += amount;
+                    self.held -= amount;
+                    self.transactions.insert(tx, Transaction::Settled(amount));
+                }
+            }
+            Message::Chargeback { tx, .. } => {
+                if let Some(&Transaction::Disputed(amount)) = self.transactions.get(&tx) {
+                    self.held -= amount;
+                    self.total -= amount;
+                    self.locked = true;
+                    self.transactions.insert(tx, Transaction::Reversed);
+                }
+            }
+            Message::Settle { tx, .. } => {
+                if let Some(&Transaction::Authorized(amount)) = self.transactions.get(&tx) {
+                    self.settle(tx, amount);
+                }
+            }
+        }
+    }
+
+    fn record(&self, client: u16) -> AccountRecord {
+        AccountRecord {
+            client,
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
+        }
+    }
+
+    /// Settles pending withdrawal `tx` of `amount`.
+    fn settle(&mut self, tx: u32, amount: f32) {
+        self.held -= amount;
+        self.total -= amount;
+        self.transactions.insert(tx, Transaction::Withdrawn);
+    }
+}
+
+/// Processes `input` and prints final account states to stdout, ordered by client.
+pub fn run(input: &Path) -> Result<(), anyhow::Error> {
+    let mut source = format::source(input, Format::of(input)?, config::engine())?;
+    let accounts = process(source.as_mut());
+
+    let mut out = schema::Writer::new(csv::Writer::from_writer(std::io::stdout()));
+    for account in accounts {
+        out.serialize(&Row::Plain(account))?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Final state of every account created by messages of `source`. Rows which are not valid
+/// messages are skipped.
+pub fn process(source: &mut dyn Source) -> Vec<AccountRecord> {
+    let mut accounts = Accounts::default();
+    while let Some(record) = source.next_record() {
+        if let Ok(message) = record.and_then(|record| Message::try_from(&record)) {
+            accounts.apply(&message);
+        }
+    }
+    accounts.into_records()
+}
+
+/// Accounts of the reference engine, for applying messages one at a time.
+#[derive(Debug, Default)]
+pub struct Accounts {
+    clients: BTreeMap<u16, Client>,
+}
+
+impl Accounts {
+    /// Applies `message`, skipping it when it is for a client without an account, unless it
+    /// creates one, see [`creation`](crate::creation).
+    pub fn apply(&mut self, message: &Message) {
+        let id = message.client_id();
+        if !self.clients.contains_key(&id) && !creation::creates(message) {
+            return;
+        }
+        self.clients
+            .entry(id)
+            .or_insert_with(|| Client {
+                settlement: settlement::get(),
+                minimum: reserve::minimum(id),
+                rules: tiers::rules(id),
+                ..Client::default()
+            })
+            .apply(message);
+    }
+
+    /// Current state of the account of `client`, if there is one.
+    pub fn get(&self, client: u16) -> Option<AccountRecord> {
+        self.clients
+            .get(&client)
+            .map(|account| account.record(client))
+    }
+
+    /// Current state of every account, ordered by client.
+    pub fn into_records(self) -> Vec<AccountRecord> {
+        self.clients
+            .iter()
+            .map(|(client, account)| account.record(*client))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::process;
+    use crate::{format::CsvSource, state::AccountRecord};
+
+    #[test]
+    fn messages_are_applied_in_order() {
+        let input = "type,client,tx,amount
+dispute,2,1,
+deposit,1,1,3.0
+deposit,1,2,1.0
+withdrawal,1,3,5.0
+dispute,1,1,
+chargeback,1,1,
+deposit,1,4,1.0
+deposit,2,5,2.0
+";
+        let accounts = process(&mut CsvSource::new(input.as_bytes()));
+        assert_eq!(
+            accounts,
+            vec![
+                AccountRecord {
+                    client: 1,
+                    available: 1.0,
+                    held: 0.0,
+                    total: 1.0,
+                    locked: true,
+                },
+                AccountRecord {
+                    client: 2,
+                    available: 2.0,
+                    held: 0.0,
+                    total: 2.0,
+                    locked: false,
+                },
+            ]
+        );
+    }
+}
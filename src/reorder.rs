@@ -0,0 +1,209 @@
+//! Reordering of messages of a client by their timestamps, enabled with `--reorder-lateness`,
+//! for input which is only approximately ordered, such as merged shards.
+//!
+//! Every account task holds its messages back in a [`Buffer`] until the latest timestamp the
+//! client has seen is `lateness` past them, then applies them in timestamp order. A message
+//! more than `lateness` behind the latest timestamp is late: messages after it may have been
+//! applied already, so it is rejected with [`LATE`], written to the [`dlq`](crate::dlq), and
+//! leaves a `message_late` entry in the [event log](crate::event_log) for audit.
+//! Messages without a timestamp keep their place among the others, and are not held back
+//! before the client has seen any timestamp.
+//!
+//! Input of `serve` never ends, so messages held back for a client which goes quiet would
+//! wait for good. There, once a client has sent nothing for `lateness` milliseconds, its
+//! buffer is [advanced](Buffer::advance) as if the client had seen a timestamp `lateness`
+//! past its latest one, which releases every message held.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    sync::OnceLock,
+};
+
+/// Error code of messages which arrived too late to be applied in order.
+pub const LATE: &str = "PE_LATE";
+
+static LATENESS: OnceLock<u64> = OnceLock::new();
+
+/// Reorders messages of account tasks started from now on, holding them back for `lateness`
+/// milliseconds. Only the first call has effect.
+pub fn enable(lateness: u64) {
+    let _ = LATENESS.set(lateness);
+}
+
+/// Milliseconds messages are held back for, `None` when reordering is off.
+pub fn lateness() -> Option<u64> {
+    LATENESS.get().copied()
+}
+
+/// Messages of a single client, waiting to be released in timestamp order.
+#[derive(Debug)]
+pub struct Buffer<T> {
+    lateness: u64,
+    /// Latest timestamp seen.
+    watermark: Option<u64>,
+    /// Counts pushed messages, so messages with equal timestamps keep their order.
+    pushed: u64,
+    held: BinaryHeap<Reverse<Held<T>>>,
+}
+
+#[derive(Debug)]
+struct Held<T> {
+    key: (u64, u64),
+    item: T,
+}
+
+impl<T> PartialEq for Held<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl<T> Eq for Held<T> {}
+
+impl<T> PartialOrd for Held<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Held<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+impl<T> Buffer<T> {
+    /// Empty buffer, holding messages back for `lateness` milliseconds.
+    pub fn new(lateness: u64) -> Self {
+        Buffer {
+            lateness,
+            watermark: None,
+            pushed: 0,
+            held: BinaryHeap::new(),
+        }
+    }
+
+    /// Holds `item` back until it can be released in order. Returns it back when it is late.
+    pub fn push(&mut self, timestamp: Option<u64>, item: T) -> Result<(), T> {
+        let timestamp = match (timestamp, self.watermark) {
+            (Some(timestamp), Some(watermark)) => {
+                if timestamp < self.released(watermark) {
+                    return Err(item);
+                }
+                self.watermark = Some(watermark.max(timestamp));
+                timestamp
+            }
+            (Some(timestamp), None) => {
+                self.watermark = Some(timestamp);
+                timestamp
+            }
+            (None, watermark) => watermark.unwrap_or_default(),
+        };
+        self.held.push(Reverse(Held {
+            key: (timestamp, self.pushed),
+            item,
+        }));
+        self.pushed += 1;
+        Ok(())
+    }
+
+    /// Next item which is `lateness` behind the latest timestamp, in timestamp order.
+    pub fn pop(&mut self) -> Option<T> {
+        let released = self
+            .watermark
+            .map_or(0, |watermark| self.released(watermark));
+        match self.held.peek() {
+            Some(Reverse(held)) if held.key.0 <= released => {
+                self.held.pop().map(|Reverse(held)| held.item)
+            }
+            _ => None,
+        }
+    }
+
+    /// Moves the latest timestamp `lateness` ahead, so that everything held is released by
+    /// [`pop`](Buffer::pop), and messages behind what was held are late from now on.
+    pub fn advance(&mut self) {
+        self.watermark = self
+            .watermark
+            .map(|watermark| watermark.saturating_add(self.lateness));
+    }
+
+    /// Whether no message is held back.
+    pub fn is_empty(&self) -> bool {
+        self.held.is_empty()
+    }
+
+    /// Next item regardless of lateness, for when no more messages are coming.
+    pub fn drain(&mut self) -> Option<T> {
+        self.held.pop().map(|Reverse(held)| held.item)
+    }
+
+    /// Timestamp up to which messages are released.
+    fn released(&self, watermark: u64) -> u64 {
+        watermark.saturating_sub(self.lateness)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Buffer;
+
+    fn released(buffer: &mut Buffer<&'static str>) -> Vec<&'static str> {
+        std::iter::from_fn(|| buffer.pop()).collect()
+    }
+
+    #[test]
+    fn messages_are_released_in_order_within_lateness() {
+        let mut buffer = Buffer::new(10);
+        buffer.push(Some(100), "a").unwrap();
+        buffer.push(Some(105), "c").unwrap();
+        buffer.push(Some(102), "b").unwrap();
+        assert_eq!(released(&mut buffer), Vec::<&str>::new());
+
+        buffer.push(Some(112), "e").unwrap();
+        assert_eq!(released(&mut buffer), vec!["a", "b"]);
+
+        // Past the bound of released messages.
+        assert_eq!(buffer.push(Some(101), "late"), Err("late"));
+        buffer.push(Some(102), "d").unwrap();
+        buffer.push(None, "f").unwrap();
+        assert_eq!(released(&mut buffer), vec!["d"]);
+
+        assert_eq!(
+            std::iter::from_fn(|| buffer.drain()).collect::<Vec<_>>(),
+            vec!["c", "e", "f"]
+        );
+    }
+
+    #[test]
+    fn advancing_releases_everything_held() {
+        let mut buffer = Buffer::new(10);
+        buffer.push(Some(100), "a").unwrap();
+        buffer.push(Some(105), "b").unwrap();
+        buffer.push(None, "c").unwrap();
+        assert_eq!(released(&mut buffer), Vec::<&str>::new());
+
+        buffer.advance();
+        assert_eq!(released(&mut buffer), vec!["a", "b", "c"]);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.push(Some(104), "late"), Err("late"));
+        // Held until the client sees a timestamp past it, or goes quiet again.
+        buffer.push(Some(106), "d").unwrap();
+        assert_eq!(released(&mut buffer), Vec::<&str>::new());
+        buffer.push(Some(116), "e").unwrap();
+        assert_eq!(released(&mut buffer), vec!["d"]);
+    }
+
+    #[test]
+    fn messages_without_timestamps_keep_their_order() {
+        let mut buffer = Buffer::new(0);
+        buffer.push(None, "a").unwrap();
+        buffer.push(None, "b").unwrap();
+        assert_eq!(released(&mut buffer), vec!["a", "b"]);
+
+        buffer.push(Some(5), "c").unwrap();
+        buffer.push(None, "d").unwrap();
+        assert_eq!(released(&mut buffer), vec!["c", "d"]);
+    }
+}
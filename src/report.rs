@@ -0,0 +1,241 @@
+//! Time-windowed report of a run, written with `--report`: totals of applied messages per
+//! hour or day of their timestamps, for backtesting and capacity planning.
+//!
+//! The report is csv, a row for every period with any messages, ordered by time: `start` and
+//! `end` of the period in milliseconds since unix epoch, number of `deposits` and amount
+//! `deposited`, number of `withdrawals` and amount `withdrawn`, number of `disputes` opened,
+//! `resolves` and `chargebacks`, and `net_flow`, the change of total funds of all clients.
+//! Messages which were rejected, took no effect, or have no timestamp are not counted.
+//! Withdrawals pending [`settlement`](crate::settlement) count as withdrawn once they are
+//! applied, and `settle` messages are not counted.
+
+use serde::Serialize;
+use std::{collections::BTreeMap, path::Path, str::FromStr, sync::Mutex};
+
+use crate::Message;
+
+static REPORT: Mutex<Option<Report>> = Mutex::new(None);
+
+/// Length of reported periods.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Hour,
+    #[default]
+    Day,
+}
+
+impl Period {
+    fn millis(self) -> u64 {
+        match self {
+            Period::Hour => 60 * 60 * 1000,
+            Period::Day => 24 * 60 * 60 * 1000,
+        }
+    }
+}
+
+impl FromStr for Period {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hour" | "hourly" => Ok(Period::Hour),
+            "day" | "daily" => Ok(Period::Day),
+            other => Err(anyhow::anyhow!(
+                "Unknown report period {other}, expected hour or day"
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Report {
+    period: Period,
+    /// Totals by start of their period.
+    totals: BTreeMap<u64, Totals>,
+}
+
+/// Kept as `f64`, so sums of many amounts do not drift.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Totals {
+    deposits: u64,
+    deposited: f64,
+    withdrawals: u64,
+    withdrawn: f64,
+    disputes: u64,
+    resolves: u64,
+    chargebacks: u64,
+    net_flow: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct Row {
+    start: u64,
+    end: u64,
+    deposits: u64,
+    deposited: f32,
+    withdrawals: u64,
+    withdrawn: f32,
+    disputes: u64,
+    resolves: u64,
+    chargebacks: u64,
+    net_flow: f32,
+}
+
+impl Report {
+    fn new(period: Period) -> Self {
+        Report {
+            period,
+            totals: BTreeMap::new(),
+        }
+    }
+
+    /// Counts `message`, which changed total funds of its client by `flow`.
+    fn record(&mut self, message: &Message, flow: f32) {
+        let Some(timestamp) = message.timestamp() else {
+            return;
+        };
+        let start = timestamp - timestamp % self.period.millis();
+        let totals = self.totals.entry(start).or_default();
+        let flow = f64::from(flow);
+        match message {
+            Message::Deposit { .. } => {
+                totals.deposits += 1;
+                totals.deposited += flow;
+            }
+            Message::Withdraw { .. } => {
+                totals.withdrawals += 1;
+                totals.withdrawn -= flow;
+            }
+            Message::Dispute { .. } => totals.disputes += 1,
+            Message::Resolve { .. } => totals.resolves += 1,
+            Message::Chargeback { .. } => totals.chargebacks += 1,
+            Message::Settle { .. } => {}
+        }
+        totals.net_flow += flow;
+    }
+
+    fn rows(&self) -> impl Iterator<Item = Row> + '_ {
+        self.totals.iter().map(|(start, totals)| Row {
+            start: *start,
+            end: start + self.period.millis(),
+            deposits: totals.deposits,
+            deposited: totals.deposited as f32,
+            withdrawals: totals.withdrawals,
+            withdrawn: totals.withdrawn as f32,
+            disputes: totals.disputes,
+            resolves: totals.resolves,
+            chargebacks: totals.chargebacks,
+            net_flow: totals.net_flow as f32,
+        })
+    }
+}
+
+/// Starts counting messages in periods of `period`.
+pub fn enable(period: Period) {
+    *REPORT.lock().unwrap_or_else(|err| err.into_inner()) = Some(Report::new(period));
+}
+
+/// Counts `message`, which was applied and changed total funds of its client by `flow`, if
+/// the report is enabled. `effective` tells whether it changed the account at all.
+pub fn record(message: &Message, flow: f32, effective: bool) {
+    if !effective {
+        return;
+    }
+    if let Some(report) = REPORT
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .as_mut()
+    {
+        report.record(message, flow);
+    }
+}
+
+/// Writes everything counted so far to `path`, replacing its contents.
+pub fn write(path: &Path) -> Result<(), anyhow::Error> {
+    let report = REPORT.lock().unwrap_or_else(|err| err.into_inner());
+    let mut out = csv::Writer::from_path(path)?;
+    if let Some(report) = report.as_ref() {
+        for row in report.rows() {
+            out.serialize(row)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Period, Report};
+    use crate::Message;
+
+    const HOUR: u64 = 60 * 60 * 1000;
+
+    #[test]
+    fn messages_are_totalled_per_period() {
+        let mut report = Report::new(Period::Hour);
+        let at = |hour: u64, minute: u64| Some(hour * HOUR + minute * 60 * 1000);
+        let deposit = |tx, timestamp| Message::Deposit {
+            client: 1,
+            tx,
+            amount: 5.0,
+            timestamp,
+            effective_date: None,
+        };
+
+        report.record(&deposit(1, at(0, 0)), 5.0);
+        report.record(&deposit(2, at(0, 59)), 5.0);
+        report.record(
+            &Message::Withdraw {
+                client: 1,
+                tx: 3,
+                amount: 2.0,
+                timestamp: at(0, 30),
+            },
+            -2.0,
+        );
+        report.record(
+            &Message::Dispute {
+                client: 1,
+                tx: 1,
+                timestamp: at(2, 0),
+            },
+            0.0,
+        );
+        report.record(
+            &Message::Chargeback {
+                client: 1,
+                tx: 1,
+                timestamp: at(2, 1),
+            },
+            -5.0,
+        );
+        report.record(&deposit(4, None), 5.0);
+
+        let rows: Vec<_> = report
+            .rows()
+            .map(|row| {
+                (
+                    row.start,
+                    row.end,
+                    row.deposits,
+                    row.deposited,
+                    row.withdrawals,
+                    row.withdrawn,
+                    row.disputes,
+                    row.chargebacks,
+                    row.net_flow,
+                )
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                (0, HOUR, 2, 10.0, 1, 2.0, 0, 0, 8.0),
+                (2 * HOUR, 3 * HOUR, 0, 0.0, 0, 0.0, 1, 1, -5.0),
+            ]
+        );
+
+        assert_eq!("daily".parse::<Period>().unwrap(), Period::Day);
+        assert!("weekly".parse::<Period>().is_err());
+    }
+}
@@ -0,0 +1,112 @@
+//! Minimum balances, enabled with `--min-balance`: part of available funds of every client is
+//! a reserve which withdrawals can't touch, as savings products require. Withdrawals which
+//! would leave less than the minimum available are rejected with
+//! [`BELOW_MINIMUM`](crate::engine::BELOW_MINIMUM).
+//!
+//! The minimum may differ per tier of accounts, see [`tiers`](crate::tiers). Clients without a
+//! tier, or whose tier has no minimum of its own, get the default one.
+//!
+//! Disputes still move funds out of available, so an account may dip into its reserve. That
+//! raises a [`RESERVE`] alert, like [`velocity`](crate::velocity) rules do.
+
+use std::{collections::HashMap, str::FromStr, sync::OnceLock};
+
+use crate::{alerts::Alert, tiers};
+
+/// Rule of accounts whose available funds fell below the minimum balance.
+pub const RESERVE: &str = "reserve";
+
+static MINIMUMS: OnceLock<Minimums> = OnceLock::new();
+
+/// Minimum balance of accounts, by tier.
+///
+/// Written as comma separated `TIER:AMOUNT` entries, e.g. `100,gold:1000,basic:0`, where an
+/// `AMOUNT` alone is the minimum of every other account.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Minimums {
+    default: f32,
+    tiers: HashMap<String, f32>,
+}
+
+impl FromStr for Minimums {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut minimums = Minimums::default();
+        for entry in s.split(',').map(str::trim) {
+            let (tier, amount) = match entry.split_once(':') {
+                Some((tier, amount)) => (Some(tier.trim()), amount.trim()),
+                None => (None, entry),
+            };
+            let amount: f32 = amount.parse()?;
+            if !amount.is_finite() || amount < 0.0 {
+                return Err(anyhow::anyhow!(
+                    "Minimum balance {amount} is not a non-negative amount"
+                ));
+            }
+            match tier {
+                Some(tier) => {
+                    minimums.tiers.insert(tier.to_string(), amount);
+                }
+                None => minimums.default = amount,
+            }
+        }
+        Ok(minimums)
+    }
+}
+
+impl Minimums {
+    /// Minimum balance of accounts of `tier`.
+    fn of(&self, tier: Option<&str>) -> f32 {
+        tier.and_then(|tier| self.tiers.get(tier))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Keeps minimum balances of accounts created from now on. Only the first call has effect.
+pub fn enable(minimums: Minimums) {
+    let _ = MINIMUMS.set(minimums);
+}
+
+/// Minimum balance of `client`, none when minimum balances are off.
+pub fn minimum(client: u16) -> f32 {
+    MINIMUMS
+        .get()
+        .map_or(0.0, |minimums| minimums.of(tiers::tier(client)))
+}
+
+/// Alert of `tx` taking available funds of `client` from `before` to `after`, when that dips
+/// into reserve of `minimum`.
+pub fn observe(client: u16, tx: u32, minimum: f32, before: f32, after: f32) -> Option<Alert> {
+    (before >= minimum && after < minimum).then_some(Alert {
+        client,
+        tx,
+        rule: RESERVE,
+        withdrawals: None,
+        withdrawn: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{observe, Minimums};
+
+    #[test]
+    fn minimum_is_the_one_of_the_tier_of_the_client() {
+        let minimums: Minimums = "100,gold:1000,basic:0".parse().unwrap();
+        let of: Vec<_> = [Some("gold"), Some("basic"), Some("silver"), None]
+            .into_iter()
+            .map(|tier| minimums.of(tier))
+            .collect();
+        assert_eq!(of, vec![1000.0, 0.0, 100.0, 100.0]);
+
+        assert!("gold:-1".parse::<Minimums>().is_err());
+        assert!("lots".parse::<Minimums>().is_err());
+
+        assert!(observe(1, 7, 100.0, 150.0, 50.0).is_some());
+        // Only dipping in alerts, not staying below.
+        assert!(observe(1, 7, 100.0, 50.0, 40.0).is_none());
+        assert!(observe(1, 7, 100.0, 150.0, 100.0).is_none());
+    }
+}
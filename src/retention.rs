@@ -0,0 +1,158 @@
+//! Retention of transaction history, set with `retention` of the engine
+//! [configuration](crate::config), so that history of long-lived daemons doesn't grow forever:
+//!
+//! - `all`, the default: every transaction is kept.
+//! - `days:N`: transactions are kept for N days, then pruned unless something can still
+//!   happen to them.
+//! - `undisputed:M`: deposits which are not disputed are kept for M days, charged back
+//!   deposits and settled withdrawals are kept for good.
+//!
+//! Disputed deposits, funds pending their value date and withdrawals pending settlement are
+//! never pruned, since they count towards balances, nor are transactions without a timestamp,
+//! whose age can't be told. Days pass with timestamps of messages, as everywhere else in the
+//! engine.
+//!
+//! Account tasks prune their history once a day of timestamps of their client, as the first
+//! message of the day comes, and once more before it's written to `--state` or parked by
+//! `--evict-after`, so neither holds pruned transactions. They remember ids of what they pruned: a dispute of one of them is rejected
+//! with [`PRUNED`], and logged as a `dispute_of_pruned` entry of the
+//! [`event_log`](crate::event_log), whatever `unknown_disputes` says. `trp compact` prunes state
+//! kept by earlier runs the same way, aging transactions from the latest timestamp of the
+//! state, see [`compact`](crate::commands::compact).
+
+use std::{fmt::Display, str::FromStr};
+
+use crate::state::{TransactionRecord, TransactionState};
+
+/// Milliseconds in a day.
+pub const DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// Error code of disputes of transactions pruned from history.
+pub const PRUNED: &str = "PE_PRUNED";
+
+/// What history is kept, see [module](self).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Retention {
+    #[default]
+    All,
+    /// Days every transaction is kept for.
+    Days(u64),
+    /// Days deposits which are not disputed are kept for.
+    Undisputed(u64),
+}
+
+impl FromStr for Retention {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || anyhow::anyhow!("Invalid retention {s}, expected all, days:<N> or undisputed:<M>");
+        match s.split_once(':') {
+            None if s == "all" => Ok(Retention::All),
+            Some(("days", days)) => Ok(Retention::Days(days.parse().map_err(|_| invalid())?)),
+            Some(("undisputed", days)) => {
+                Ok(Retention::Undisputed(days.parse().map_err(|_| invalid())?))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Display for Retention {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Retention::All => f.write_str("all"),
+            Retention::Days(days) => write!(f, "days:{days}"),
+            Retention::Undisputed(days) => write!(f, "undisputed:{days}"),
+        }
+    }
+}
+
+impl Retention {
+    /// Whether a transaction in `state` which happened at `timestamp` is kept, `now` being
+    /// the latest timestamp known.
+    pub fn keeps(&self, state: TransactionState, timestamp: Option<u64>, now: Option<u64>) -> bool {
+        let days = match (self, state) {
+            (Retention::All, _) => return true,
+            (
+                _,
+                TransactionState::Pending
+                | TransactionState::Disputed
+                | TransactionState::Authorized,
+            ) => return true,
+            (
+                Retention::Undisputed(_),
+                TransactionState::Reversed | TransactionState::Withdrawn,
+            ) => return true,
+            (Retention::Days(days) | Retention::Undisputed(days), _) => *days,
+        };
+        match (timestamp, now) {
+            (Some(timestamp), Some(now)) => {
+                timestamp.saturating_add(days.saturating_mul(DAY)) >= now
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Latest timestamp of `transactions`, `None` when none of them has one.
+pub fn now<'a>(transactions: impl IntoIterator<Item = &'a TransactionRecord>) -> Option<u64> {
+    transactions
+        .into_iter()
+        .filter_map(|transaction| transaction.timestamp)
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{now, Retention, DAY};
+    use crate::state::{TransactionRecord, TransactionState};
+
+    #[test]
+    fn only_settled_history_is_pruned() {
+        let transaction = |tx, state, day: Option<u64>| TransactionRecord {
+            tx,
+            client: 1,
+            state,
+            amount: 1.0,
+            timestamp: day.map(|day| day * DAY),
+        };
+        let history = vec![
+            transaction(1, TransactionState::Deposited, Some(1)),
+            transaction(2, TransactionState::Deposited, None),
+            transaction(3, TransactionState::Disputed, Some(1)),
+            transaction(4, TransactionState::Reversed, Some(3)),
+            transaction(5, TransactionState::Withdrawn, Some(9)),
+            transaction(6, TransactionState::Authorized, Some(2)),
+            transaction(7, TransactionState::Pending, Some(2)),
+            transaction(8, TransactionState::Deposited, Some(8)),
+            transaction(9, TransactionState::Deposited, Some(10)),
+        ];
+        let now = now(&history);
+        assert_eq!(now, Some(10 * DAY));
+        let kept = |retention: &str| {
+            let retention: Retention = retention.parse().unwrap();
+            assert_eq!(
+                retention.to_string().parse::<Retention>().unwrap(),
+                retention
+            );
+            history
+                .iter()
+                .filter(|transaction| {
+                    retention.keeps(transaction.state, transaction.timestamp, now)
+                })
+                .map(|transaction| transaction.tx)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(kept("all"), [1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(kept("days:2"), [2, 3, 5, 6, 7, 8, 9]);
+        assert_eq!(kept("days:0"), [2, 3, 6, 7, 9]);
+        assert_eq!(kept("undisputed:2"), [2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(kept("undisputed:30"), [1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(Retention::Undisputed(30).to_string(), "undisputed:30");
+        for invalid in ["", "days", "days:-1", "forever:1", "all:1"] {
+            assert!(invalid.parse::<Retention>().is_err(), "{invalid:?}");
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Seeded pseudo-random numbers, for generated workloads and randomized tests.
+//!
+//! Randomness of a run, such as faults of `--chaos`, is drawn from generators derived from
+//! the seed of the run, see [`seed`], given with `--seed` or picked at random and logged.
+//! Every consumer draws from a stream of its own, e.g. of a client, so what it draws doesn't
+//! depend on how tasks were scheduled, and the same seed reproduces the run exactly.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::OnceLock,
+};
+
+static SEED: OnceLock<u64> = OnceLock::new();
+
+/// Seeds the run with `seed`, or a random one when not given, and returns it. Only the first
+/// call has effect.
+pub fn seed(seed: Option<u64>) -> u64 {
+    *SEED.get_or_init(|| seed.unwrap_or_else(random))
+}
+
+/// Seed which differs from run to run.
+fn random() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    hasher.finish()
+}
+
+/// SplitMix64, good enough for workloads and stable across platforms, so a seed always
+/// reproduces the same sequence.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    /// Generator of `stream` of `seed`, independent of generators of other streams.
+    pub fn derive(seed: u64, stream: u64) -> Self {
+        Rng(seed ^ Rng(stream).next())
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0..n`, `n` must not be 0.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// `true` with probability `p`.
+    pub fn chance(&mut self, p: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rng;
+
+    #[test]
+    fn streams_are_reproduced() {
+        let draws = |mut rng: Rng| (0..4).map(|_| rng.next()).collect::<Vec<_>>();
+        assert_eq!(draws(Rng::derive(7, 1)), draws(Rng::derive(7, 1)));
+        assert_ne!(draws(Rng::derive(7, 1)), draws(Rng::derive(7, 2)));
+        assert_ne!(draws(Rng::derive(7, 1)), draws(Rng::derive(8, 1)));
+    }
+}
@@ -0,0 +1,71 @@
+//! Sampling of clients, enabled with `trp process --sample 1%`, for a quick look at a new
+//! input before committing to a full run of it.
+//!
+//! The parser skips messages of clients out of the sample before anything else sees them, so
+//! the sample goes through the run end to end, and output, metrics and every other file only
+//! have clients of the sample. Clients are picked by a hash of their id and the seed of the
+//! run, see [`rng`](crate::rng), so the same seed picks the same clients, whatever the input.
+
+use std::sync::OnceLock;
+
+use crate::rng::Rng;
+
+/// Kept apart from streams of clients other randomness of the run draws from.
+const SALT: u64 = 0x5a3d_1e0f_c4b2_9687;
+
+static SAMPLE: OnceLock<(f64, u64)> = OnceLock::new();
+
+/// Parses share of `--sample`, a percentage such as `1%` or a fraction such as `0.01`, above
+/// 0 and up to all of the clients.
+pub fn share(text: &str) -> Result<f64, anyhow::Error> {
+    let share = match text.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().map(|percent| percent / 100.0),
+        None => text.parse::<f64>(),
+    };
+    share
+        .ok()
+        .filter(|share| *share > 0.0 && *share <= 1.0)
+        .ok_or_else(|| {
+            anyhow::anyhow!("Invalid sample {text}, expected a percentage such as 1% above 0")
+        })
+}
+
+/// Takes only `share` of clients from now on, picked with `seed`. Only the first call has
+/// effect.
+pub fn enable(share: f64, seed: u64) {
+    let _ = SAMPLE.set((share, seed));
+}
+
+/// Whether messages of `client` are taken, always unless sampling is enabled.
+pub fn takes(client: u16) -> bool {
+    match SAMPLE.get() {
+        Some((share, seed)) => picks(*share, *seed, client),
+        None => true,
+    }
+}
+
+fn picks(share: f64, seed: u64, client: u16) -> bool {
+    Rng::derive(seed ^ SALT, u64::from(client)).chance(share)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{picks, share};
+
+    #[test]
+    fn share_of_clients_is_picked() {
+        assert_eq!(share("1%").unwrap(), 0.01);
+        assert_eq!(share("0.25").unwrap(), 0.25);
+        assert_eq!(share("100%").unwrap(), 1.0);
+        assert!(share("0%").is_err());
+        assert!(share("150%").is_err());
+        assert!(share("some").is_err());
+
+        let picked = |seed| (0..=u16::MAX).filter(move |client| picks(0.1, seed, *client));
+        let count = picked(7).count();
+        assert!((6000..7100).contains(&count), "{count}");
+        assert!(picked(7).eq(picked(7)));
+        assert!(!picked(7).eq(picked(8)));
+        assert!((0..=u16::MAX).all(|client| picks(1.0, 7, client)));
+    }
+}
@@ -0,0 +1,199 @@
+//! Columns of output, set from the `[output]` tables of the configuration:
+//!
+//! ```toml
+//! [output]
+//! columns = "client,available,held,total,currency"  # which columns, in which order
+//!
+//! [output.names]
+//! client = "client_id"
+//!
+//! [output.values]  # columns rows don't have, with the same value in every row
+//! currency = "EUR"
+//! ```
+//!
+//! Rows are serialized as usual, and their fields are then picked, renamed and ordered by the
+//! [`Schema`], so the layer works the same for plain and extended output.
+
+use std::{collections::BTreeMap, io::Write, sync::OnceLock};
+
+use crate::writer::Row;
+
+static SCHEMA: OnceLock<Schema> = OnceLock::new();
+
+/// Columns of output, as they differ from the ones trp writes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schema {
+    /// Columns in order of output, every column of rows in their own order when empty.
+    pub columns: Vec<String>,
+    /// Header of every renamed column.
+    pub names: BTreeMap<String, String>,
+    /// Value of every column rows don't have.
+    pub values: BTreeMap<String, String>,
+}
+
+impl Schema {
+    /// Whether rows are written as they are.
+    pub fn is_default(&self) -> bool {
+        self.columns.is_empty() && self.names.is_empty() && self.values.is_empty()
+    }
+
+    /// Fails when columns, names or values refer to columns of neither `headers` of rows nor
+    /// the values.
+    pub fn check(&self, headers: &csv::StringRecord) -> Result<(), anyhow::Error> {
+        let known = |column: &str| {
+            headers.iter().any(|header| header == column) || self.values.contains_key(column)
+        };
+        for column in self.columns.iter().chain(self.names.keys()) {
+            if !known(column) {
+                return Err(anyhow::anyhow!(
+                    "Output has no column {column}, expected one of {} or of [output.values]",
+                    headers.iter().collect::<Vec<_>>().join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Columns of output of rows with `headers`.
+    fn columns(&self, headers: &csv::StringRecord) -> Vec<String> {
+        if !self.columns.is_empty() {
+            return self.columns.clone();
+        }
+        headers
+            .iter()
+            .map(str::to_string)
+            .chain(self.values.keys().cloned())
+            .collect()
+    }
+
+    fn header(&self, headers: &csv::StringRecord) -> csv::StringRecord {
+        self.columns(headers)
+            .iter()
+            .map(|column| self.names.get(column).unwrap_or(column))
+            .collect()
+    }
+
+    fn record(&self, headers: &csv::StringRecord, fields: &csv::StringRecord) -> csv::StringRecord {
+        self.columns(headers)
+            .iter()
+            .map(
+                |column| match headers.iter().position(|header| header == column) {
+                    Some(index) => &fields[index],
+                    None => self.values.get(column).map_or("", String::as_str),
+                },
+            )
+            .collect()
+    }
+}
+
+/// Writes rows with `schema` from now on. Only the first call has effect.
+pub fn set(schema: Schema) {
+    let _ = SCHEMA.set(schema);
+}
+
+/// Schema set with [`set`], or the default when it wasn't called.
+pub fn schema() -> Schema {
+    SCHEMA.get().cloned().unwrap_or_default()
+}
+
+/// Headers and fields of `row` as serialized.
+pub(crate) fn fields(row: &Row) -> Result<(csv::StringRecord, csv::StringRecord), csv::Error> {
+    let mut out = csv::Writer::from_writer(Vec::new());
+    out.serialize(row)?;
+    let serialized = out
+        .into_inner()
+        .map_err(|err| std::io::Error::from(err.error().kind()))?;
+    let mut rdr = csv::Reader::from_reader(serialized.as_slice());
+    let headers = rdr.headers()?.clone();
+    let fields = rdr.records().next().transpose()?.unwrap_or_default();
+    Ok((headers, fields))
+}
+
+/// Csv writer of rows, with the [`Schema`] set.
+pub(crate) struct Writer<W: Write> {
+    out: csv::Writer<W>,
+    schema: Schema,
+    /// Whether the header was written, ahead of the first row.
+    started: bool,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(out: csv::Writer<W>) -> Self {
+        Writer {
+            out,
+            schema: schema(),
+            started: false,
+        }
+    }
+
+    pub fn serialize(&mut self, row: &Row) -> Result<(), csv::Error> {
+        if self.schema.is_default() {
+            return self.out.serialize(row);
+        }
+        let (headers, fields) = fields(row)?;
+        if !self.started {
+            self.out.write_record(&self.schema.header(&headers))?;
+            self.started = true;
+        }
+        self.out
+            .write_record(&self.schema.record(&headers, &fields))
+    }
+
+    pub fn flush(&mut self) -> Result<(), csv::Error> {
+        Ok(self.out.flush()?)
+    }
+
+    pub fn into_inner(self) -> Result<W, csv::Error> {
+        self.out
+            .into_inner()
+            .map_err(|err| std::io::Error::from(err.error().kind()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fields, Schema, Writer};
+    use crate::{state::AccountRecord, writer::Row};
+    use std::collections::BTreeMap;
+
+    fn row(client: u16) -> Row {
+        Row::Plain(AccountRecord {
+            client,
+            available: 1.5,
+            held: 0.0,
+            total: 1.5,
+            locked: false,
+        })
+    }
+
+    #[test]
+    fn columns_are_picked_renamed_and_added() {
+        let schema = Schema {
+            columns: ["client", "total", "available", "currency"]
+                .map(String::from)
+                .to_vec(),
+            names: BTreeMap::from([("client".to_string(), "client_id".to_string())]),
+            values: BTreeMap::from([("currency".to_string(), "EUR".to_string())]),
+        };
+        let (headers, _) = fields(&row(1)).unwrap();
+        schema.check(&headers).unwrap();
+
+        let mut out = Writer {
+            out: csv::Writer::from_writer(Vec::new()),
+            schema,
+            started: false,
+        };
+        out.serialize(&row(1)).unwrap();
+        out.serialize(&row(2)).unwrap();
+        assert_eq!(
+            String::from_utf8(out.into_inner().unwrap()).unwrap(),
+            "client_id,total,available,currency\n1,1.5,1.5,EUR\n2,1.5,1.5,EUR\n"
+        );
+
+        let unknown = Schema {
+            columns: vec!["pending".to_string()],
+            ..Schema::default()
+        };
+        assert!(unknown.check(&headers).is_err());
+    }
+}
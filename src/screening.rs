@@ -0,0 +1,165 @@
+//! Screening of messages before they are routed to accounts, enabled with `--watchlist` or
+//! by a [`Policy`] of an embedding application. Messages of blocked clients are rejected,
+//! those of flagged clients are held for review: rejected as well, and written to the
+//! [`REVIEW`](crate::dlq::REVIEW) queue, which can be processed once they are cleared.
+//!
+//! A watchlist is csv of `client,action` rows, where action is `block` or `review`:
+//!
+//! ```csv
+//! client,action
+//! 7,block
+//! 12,review
+//! ```
+
+use serde::Deserialize;
+use std::{collections::HashMap, io::Read, path::Path, sync::OnceLock};
+
+use crate::Message;
+
+/// Error code of messages of blocked clients.
+pub const BLOCKED: &str = "RT_BLOCK";
+/// Error code of messages held for review.
+pub const REVIEW: &str = "RT_REVIEW";
+
+static POLICY: OnceLock<Box<dyn Policy>> = OnceLock::new();
+
+/// What to do with a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Screening {
+    /// Route it as usual.
+    Clear,
+    /// Reject it.
+    Block,
+    /// Reject it and hold it for review.
+    Review,
+}
+
+impl Screening {
+    /// Error code the message is rejected with, `None` when it is not.
+    pub fn code(&self) -> Option<&'static str> {
+        match self {
+            Screening::Clear => None,
+            Screening::Block => Some(BLOCKED),
+            Screening::Review => Some(REVIEW),
+        }
+    }
+}
+
+/// Decides whether a message can be routed to its account. Called by the router for every
+/// message, so it should be quick, and must not block.
+pub trait Policy: Send + Sync {
+    fn screen(&self, message: &Message) -> Screening;
+}
+
+impl<F> Policy for F
+where
+    F: Fn(&Message) -> Screening + Send + Sync,
+{
+    fn screen(&self, message: &Message) -> Screening {
+        self(message)
+    }
+}
+
+/// Clients which are blocked or flagged for review.
+#[derive(Debug, Default)]
+pub struct Watchlist {
+    clients: HashMap<u16, Screening>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Entry {
+    client: u16,
+    action: String,
+}
+
+impl Watchlist {
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        std::fs::File::open(path)
+            .map_err(anyhow::Error::from)
+            .and_then(Self::read)
+            .map_err(|err| anyhow::anyhow!("Failed to load {}: {err}", path.display()))
+    }
+
+    fn read<R: Read>(reader: R) -> Result<Self, anyhow::Error> {
+        let mut clients = HashMap::new();
+        for entry in csv::Reader::from_reader(reader).deserialize() {
+            let Entry { client, action } = entry?;
+            let screening = match action.trim() {
+                "block" => Screening::Block,
+                "review" => Screening::Review,
+                other => {
+                    return Err(anyhow::anyhow!(
+                        "client {client}: unknown action {other}, expected block or review"
+                    ))
+                }
+            };
+            clients.insert(client, screening);
+        }
+        Ok(Watchlist { clients })
+    }
+}
+
+impl Policy for Watchlist {
+    fn screen(&self, message: &Message) -> Screening {
+        self.clients
+            .get(&message.client_id())
+            .copied()
+            .unwrap_or(Screening::Clear)
+    }
+}
+
+/// Screens messages with `policy` from now on. Only the first call has effect.
+pub fn enable(policy: impl Policy + 'static) {
+    let _ = POLICY.set(Box::new(policy));
+}
+
+/// Screens `message`, clearing it when screening is off.
+pub fn screen(message: &Message) -> Screening {
+    POLICY
+        .get()
+        .map_or(Screening::Clear, |policy| policy.screen(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Policy, Screening, Watchlist};
+    use crate::Message;
+
+    fn deposit(client: u16) -> Message {
+        Message::Deposit {
+            client,
+            tx: 1,
+            amount: 1.0,
+            timestamp: None,
+            effective_date: None,
+        }
+    }
+
+    #[test]
+    fn watchlist_screens_listed_clients() {
+        let watchlist = Watchlist::read("client,action\n7,block\n12, review\n".as_bytes()).unwrap();
+        assert_eq!(watchlist.screen(&deposit(7)), Screening::Block);
+        assert_eq!(watchlist.screen(&deposit(12)), Screening::Review);
+        assert_eq!(watchlist.screen(&deposit(1)), Screening::Clear);
+
+        assert!(Watchlist::read("client,action\n7,freeze\n".as_bytes()).is_err());
+        assert!(Watchlist::read("client,action\nseven,block\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn closures_are_policies() {
+        let policy = |message: &Message| match message.amount() {
+            Some(amount) if amount > 100.0 => Screening::Review,
+            _ => Screening::Clear,
+        };
+        assert_eq!(policy.screen(&deposit(1)), Screening::Clear);
+        let large = Message::Deposit {
+            client: 1,
+            tx: 2,
+            amount: 500.0,
+            timestamp: None,
+            effective_date: None,
+        };
+        assert_eq!(policy.screen(&large), Screening::Review);
+    }
+}
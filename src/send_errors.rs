@@ -0,0 +1,60 @@
+//! What happens when a stage of the pipeline can't hand a message or a result to the next
+//! one, because the next one is gone.
+//!
+//! - Messages the parser can't send to the router are rejected with [`SEND_FAILED`] and
+//!   written to the [`dlq`](crate::dlq).
+//! - Messages the router can't send to an account task are rejected with `RT_QUAR` and
+//!   written to the dead letter queue, see [`processor`](crate::processor).
+//! - Final states account tasks can't send to the writer are missing from the output.
+//!
+//! Each of them is counted by `trp_send_failures_total`, by channel. With `--strict`, the
+//! first of them stops the run, and the run fails, see [`check`].
+
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use crate::metrics::{self, Channel};
+
+/// Error code of messages which could not be sent to the router.
+pub const SEND_FAILED: &str = "RT_SEND";
+
+static STRICT: AtomicBool = AtomicBool::new(false);
+/// First failure, once there was one.
+static FAILED: Mutex<Option<String>> = Mutex::new(None);
+
+/// Stops the run at the first failure from now on.
+pub fn strict() {
+    STRICT.store(true, Ordering::Relaxed);
+}
+
+/// Counts failure to send `what` over `channel`. Returns `false` when the stage must stop,
+/// since the run is strict.
+pub fn failed(channel: Channel, what: impl Display) -> bool {
+    metrics::send_failed(channel);
+    FAILED
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .get_or_insert_with(|| format!("Failed to send {what}"));
+    !STRICT.load(Ordering::Relaxed)
+}
+
+/// Fails when sending failed in a strict run, so that the process exits with non-zero code.
+pub fn check() -> Result<(), anyhow::Error> {
+    match &*FAILED.lock().unwrap_or_else(|err| err.into_inner()) {
+        Some(reason) if STRICT.load(Ordering::Relaxed) => {
+            Err(anyhow::anyhow!("{reason}, stopped with --strict"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Forgets failures so far, so that the next run only fails for its own, see
+/// [`pipeline`](crate::pipeline).
+pub fn reset() {
+    *FAILED.lock().unwrap_or_else(|err| err.into_inner()) = None;
+}
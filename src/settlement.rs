@@ -0,0 +1,26 @@
+//! Pending settlement of withdrawals, enabled with `--pending-withdrawals`, for input which
+//! follows card authorizations: funds are authorized first and captured later.
+//!
+//! Applied withdrawals are then only authorized: their funds move from available to held,
+//! and count towards total until the withdrawal is settled, either by a `settle` message
+//! referencing it, or once the client has seen a timestamp [`Settlement::after`] past the one
+//! of the withdrawal. Withdrawals without a timestamp are only settled by `settle` messages.
+//! Without pending settlement, withdrawals are settled as they are applied and `settle`
+//! messages take no effect.
+
+use std::sync::OnceLock;
+
+pub use crate::engine::Settlement;
+
+static SETTLEMENT: OnceLock<Settlement> = OnceLock::new();
+
+/// Keeps withdrawals of account tasks started from now on pending until they are settled.
+/// Only the first call has effect.
+pub fn enable(settlement: Settlement) {
+    let _ = SETTLEMENT.set(settlement);
+}
+
+/// Settlement of withdrawals, `None` when they are settled as they are applied.
+pub fn get() -> Option<Settlement> {
+    SETTLEMENT.get().copied()
+}
@@ -0,0 +1,348 @@
+//! Integrity of input records, enabled with `--signature-key-file`: every record must carry
+//! a `signature` column, HMAC-SHA256 of its fields keyed with the contents of the key file,
+//! or it is rejected before reaching the engine.
+//!
+//! Signed text is a csv row of [`COLUMNS`], every column but `signature`, in that order and
+//! as the record has them, e.g. `deposit,1,7,1.50,,,,` for a record of
+//! `type,client,tx,amount` columns only. Columns a record has none of are empty, values
+//! with a comma, quote or line break are quoted, with quotes doubled, as csv writers do.
+//! Values are signed as text, so `1.50` and `1.5` have different signatures. Signature is
+//! hex encoded, in either case. Binary input has no room for it, so it can't be verified.
+
+use std::{fmt::Write, path::Path, sync::OnceLock};
+
+use crate::parser::Record;
+
+/// Error code of records whose signature is missing or does not match.
+pub const INVALID: &str = "PR_SIG";
+
+/// Columns signatures cover, in the order they are signed.
+pub const COLUMNS: [&str; 8] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "timestamp",
+    "effective_date",
+    "reference",
+    "correlation_id",
+];
+
+const BLOCK: usize = 64;
+
+static KEY: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Starts verifying records with key read from `path`. Trailing newline of the file is not
+/// part of the key.
+pub fn enable(path: &Path) -> Result<(), anyhow::Error> {
+    let mut key = std::fs::read(path)
+        .map_err(|err| anyhow::anyhow!("Failed to read {}: {err}", path.display()))?;
+    while key
+        .last()
+        .is_some_and(|byte| *byte == b'\n' || *byte == b'\r')
+    {
+        key.pop();
+    }
+    if key.is_empty() {
+        return Err(anyhow::anyhow!("Signature key {} is empty", path.display()));
+    }
+    let _ = KEY.set(key);
+    Ok(())
+}
+
+/// Whether records are verified, so sources keep the text their signatures cover.
+pub fn enabled() -> bool {
+    KEY.get().is_some()
+}
+
+/// Text signature of a record covers, see [module](self), with `field` giving the text of
+/// each of its columns as read.
+pub fn text<'a>(field: impl Fn(&str) -> Option<&'a str>) -> String {
+    let mut text = String::new();
+    for (index, column) in COLUMNS.iter().enumerate() {
+        if index > 0 {
+            text.push(',');
+        }
+        let value = field(column).unwrap_or_default();
+        if value.contains([',', '"', '\n', '\r']) {
+            text.push('"');
+            text.push_str(&value.replace('"', "\"\""));
+            text.push('"');
+        } else {
+            text.push_str(value);
+        }
+    }
+    text
+}
+
+/// Text signature of csv `row` covers, with columns named by `headers`.
+pub fn csv_text(headers: &csv::ByteRecord, row: &csv::ByteRecord) -> String {
+    text(|column| {
+        let index = headers
+            .iter()
+            .position(|header| header == column.as_bytes())?;
+        std::str::from_utf8(row.get(index)?).ok()
+    })
+}
+
+/// Checks signature of `record`, always passes when verification is off.
+pub fn verify(record: &Record) -> Result<(), &'static str> {
+    let Some(key) = KEY.get() else {
+        return Ok(());
+    };
+    let signature = record.signature.as_deref().ok_or("missing signature")?;
+    let text = record.signed.as_deref().ok_or("input can't be signed")?;
+    let expected = sign(key, text);
+    // Every byte is compared, so time taken does not tell how much of a forgery is right.
+    let matches = signature.len() == expected.len()
+        && signature
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a.to_ascii_lowercase() ^ b))
+            == 0;
+    matches.then_some(()).ok_or("signature mismatch")
+}
+
+/// Hex encoded signature of `text` with `key`.
+pub fn sign(key: &[u8], text: &str) -> String {
+    hex(&hmac(key, text.as_bytes()))
+}
+
+/// HMAC-SHA256 of `message`, see RFC 2104.
+pub fn hmac(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0; BLOCK];
+    if key.len() > BLOCK {
+        block[..32].copy_from_slice(&sha256(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner: Vec<u8> = block.iter().map(|byte| byte ^ 0x36).collect();
+    inner.extend_from_slice(message);
+    let mut outer: Vec<u8> = block.iter().map(|byte| byte ^ 0x5c).collect();
+    outer.extend_from_slice(&sha256(&inner));
+    sha256(&outer)
+}
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 digest of `data`, see FIPS 180-4.
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::default();
+    hasher.update(data);
+    hasher.finish()
+}
+
+/// SHA-256 of data fed in parts, for input too large to read at once.
+#[derive(Debug, Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    /// Start of a block, until the rest of it is fed.
+    buffer: Vec<u8>,
+    /// Bytes fed so far.
+    length: u64,
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Sha256 {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            buffer: Vec::with_capacity(BLOCK),
+            length: 0,
+        }
+    }
+}
+
+impl Sha256 {
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        if !self.buffer.is_empty() {
+            let (head, rest) = data.split_at(data.len().min(BLOCK - self.buffer.len()));
+            self.buffer.extend_from_slice(head);
+            data = rest;
+            if self.buffer.len() < BLOCK {
+                return;
+            }
+            compress(&mut self.state, &self.buffer);
+            self.buffer.clear();
+        }
+        let mut blocks = data.chunks_exact(BLOCK);
+        for block in &mut blocks {
+            compress(&mut self.state, block);
+        }
+        self.buffer.extend_from_slice(blocks.remainder());
+    }
+
+    pub fn finish(mut self) -> [u8; 32] {
+        let mut padded = std::mem::take(&mut self.buffer);
+        padded.push(0x80);
+        while padded.len() % BLOCK != BLOCK - 8 {
+            padded.push(0);
+        }
+        padded.extend_from_slice(&(self.length * 8).to_be_bytes());
+        for block in padded.chunks_exact(BLOCK) {
+            compress(&mut self.state, block);
+        }
+
+        let mut digest = [0; 32];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+}
+
+/// Hex encoding of `bytes`, lowercase.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+/// Feeds a 64 byte `block` to `state`.
+fn compress(state: &mut [u32; 8], block: &[u8]) {
+    let mut w = [0u32; 64];
+    for (i, word) in block.chunks_exact(4).enumerate() {
+        w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16]
+            .wrapping_add(s0)
+            .wrapping_add(w[i - 7])
+            .wrapping_add(s1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for i in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h
+            .wrapping_add(s1)
+            .wrapping_add(ch)
+            .wrapping_add(K[i])
+            .wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+    for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *word = word.wrapping_add(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{csv_text, hex, hmac, sha256, sign, Sha256};
+    use csv::ByteRecord;
+
+    #[test]
+    fn sha256_matches_known_digests() {
+        assert_eq!(
+            hex(&sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex(&sha256(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn sha256_of_parts_matches_digest_of_whole() {
+        let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        for size in [1, 7, 63, 64, 65, 500] {
+            let mut hasher = Sha256::default();
+            for part in data.chunks(size) {
+                hasher.update(part);
+            }
+            assert_eq!(hasher.finish(), sha256(&data), "parts of {size}");
+        }
+    }
+
+    /// Test cases 2 and 6 of RFC 4231, the latter with a key longer than a block.
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        assert_eq!(
+            hex(&hmac(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex(&hmac(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn signature_covers_every_field() {
+        let headers = ByteRecord::from(vec![
+            "type",
+            "client",
+            "tx",
+            "amount",
+            "signature",
+            "timestamp",
+            "effective_date",
+            "reference",
+            "correlation_id",
+        ]);
+        let row = [
+            "deposit", "1", "7", "1.50", "ignored", "1000", "2000", "BANK, 1", "req-1",
+        ];
+        let signed = |row: &[&str]| csv_text(&headers, &ByteRecord::from(row.to_vec()));
+        assert_eq!(signed(&row), "deposit,1,7,1.50,1000,2000,\"BANK, 1\",req-1");
+        // Columns may come in any order, or not at all.
+        assert_eq!(
+            csv_text(
+                &ByteRecord::from(vec!["tx", "type", "client"]),
+                &ByteRecord::from(vec!["7", "dispute", "1"])
+            ),
+            "dispute,1,7,,,,,"
+        );
+
+        let key = b"secret";
+        let signature = sign(key, &signed(&row));
+        for (index, column) in headers.iter().enumerate() {
+            let mut tampered = row;
+            tampered[index] = "2";
+            let changed = sign(key, &signed(&tampered)) != signature;
+            assert_eq!(changed, column != b"signature", "{tampered:?}");
+        }
+        let mut reformatted = row;
+        reformatted[3] = "1.5";
+        assert_ne!(sign(key, &signed(&reformatted)), signature);
+    }
+}
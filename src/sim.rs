@@ -0,0 +1,301 @@
+//! Deterministic executor for tests: runs tasks on the current thread, picking which of the
+//! ready tasks is polled next with a seeded [`Rng`]. The same seed always produces the same
+//! interleaving of router and account tasks, so a failing schedule can be replayed exactly.
+//! [`explore`] instead runs a small test under every possible interleaving, as loom would.
+//!
+//! Tasks spawned by [`processor`](crate::processor) while [`run`] is in progress are spawned
+//! here instead of on the tokio runtime. Set `SIM_SEED` to run simulation tests with a
+//! single seed.
+
+use std::{
+    cell::RefCell,
+    future::Future,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Wake, Waker},
+};
+
+use crate::rng::Rng;
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+thread_local! {
+    /// Tasks spawned since the last poll, while a simulation is running.
+    static SPAWNED: RefCell<Option<Vec<Task>>> = RefCell::new(None);
+}
+
+/// Wakes a task by queueing its id.
+struct Wakeup {
+    task: usize,
+    woken: Arc<Mutex<Vec<usize>>>,
+}
+
+impl Wake for Wakeup {
+    fn wake(self: Arc<Self>) {
+        self.woken
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push(self.task);
+    }
+}
+
+/// Returns `true` when called from within [`run`].
+pub fn active() -> bool {
+    SPAWNED.with(|spawned| spawned.borrow().is_some())
+}
+
+/// Spawns `task` on the running simulation.
+///
+/// # Panics
+///
+/// When called outside of [`run`].
+pub fn spawn(task: impl Future<Output = ()> + 'static) {
+    SPAWNED.with(|spawned| {
+        spawned
+            .borrow_mut()
+            .as_mut()
+            .expect("sim::spawn called outside of simulation")
+            .push(Box::pin(task));
+    });
+}
+
+/// Runs `main` and every task it spawns to completion, in an order picked by `seed`.
+///
+/// # Panics
+///
+/// When tasks are still pending but none of them can make progress.
+pub fn run(seed: u64, main: impl Future<Output = ()> + 'static) {
+    let mut rng = Rng::new(seed);
+    let pending = execute(&mut |ready| rng.below(ready as u64) as usize, main);
+    assert!(
+        pending == 0,
+        "seed {seed}: {pending} tasks are pending, but none can make progress"
+    );
+}
+
+/// Runs future made by `main` under every order in which ready tasks can be polled, up to
+/// `limit` schedules. Returns the number of schedules run.
+///
+/// Schedules are explored depth first: each run replays choices of the previous one up to
+/// its last choice with untried alternatives, and takes the next alternative there.
+///
+/// # Panics
+///
+/// When tasks are left pending but none of them can make progress, or when `main` panics
+/// under some schedule. The schedule is printed, as indexes of the tasks picked among ready
+/// ones.
+pub fn explore<M, F>(limit: usize, main: M) -> usize
+where
+    M: Fn() -> F,
+    F: Future<Output = ()> + 'static,
+{
+    let mut prefix: Vec<usize> = Vec::new();
+    for schedule in 1..=limit {
+        // Choices made, along with the number of tasks there were to pick from.
+        let mut trace: Vec<(usize, usize)> = Vec::new();
+        let outcome = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            execute(
+                &mut |ready| {
+                    let picked = prefix.get(trace.len()).copied().unwrap_or(0);
+                    trace.push((picked, ready));
+                    picked
+                },
+                main(),
+            )
+        }));
+        let picks: Vec<usize> = trace.iter().map(|&(picked, _)| picked).collect();
+        match outcome {
+            Ok(0) => {}
+            Ok(pending) => panic!(
+                "schedule {picks:?}: {pending} tasks are pending, but none can make progress"
+            ),
+            Err(panic) => {
+                SPAWNED.with(|spawned| *spawned.borrow_mut() = None);
+                eprintln!("schedule {picks:?} failed");
+                std::panic::resume_unwind(panic);
+            }
+        }
+
+        while let Some((picked, ready)) = trace.pop() {
+            if picked + 1 < ready {
+                trace.push((picked + 1, ready));
+                break;
+            }
+        }
+        if trace.is_empty() {
+            return schedule;
+        }
+        prefix = trace.iter().map(|&(picked, _)| picked).collect();
+    }
+    limit
+}
+
+/// Runs `main` and every task it spawns until none can make progress, polling the task at
+/// index returned by `pick` among the ready ones, given their count. Returns the number of
+/// tasks left pending.
+fn execute(
+    pick: &mut dyn FnMut(usize) -> usize,
+    main: impl Future<Output = ()> + 'static,
+) -> usize {
+    let woken = Arc::new(Mutex::new(Vec::new()));
+    let mut tasks: Vec<Option<Task>> = vec![Some(Box::pin(main))];
+    let mut ready = vec![0];
+    SPAWNED.with(|spawned| *spawned.borrow_mut() = Some(Vec::new()));
+
+    loop {
+        ready.extend(
+            woken
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .drain(..),
+        );
+        ready.sort_unstable();
+        ready.dedup();
+        ready.retain(|&task| tasks[task].is_some());
+        if ready.is_empty() {
+            break;
+        }
+
+        let task = ready.remove(pick(ready.len()));
+        let waker = Waker::from(Arc::new(Wakeup {
+            task,
+            woken: woken.clone(),
+        }));
+        let future = tasks[task].as_mut().expect("only pending tasks are ready");
+        if future
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_ready()
+        {
+            tasks[task] = None;
+        }
+
+        let spawned = SPAWNED.with(|spawned| spawned.borrow_mut().as_mut().map(std::mem::take));
+        for future in spawned.unwrap_or_default() {
+            ready.push(tasks.len());
+            tasks.push(Some(future));
+        }
+    }
+
+    SPAWNED.with(|spawned| *spawned.borrow_mut() = None);
+    tasks.iter().filter(|task| task.is_some()).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, spawn};
+    use crate::{
+        format::{CsvSource, Source},
+        processor,
+        provenance::Provenance,
+        reference,
+        rng::Rng,
+        state::{AccountRecord, Saved},
+        Message,
+    };
+    use std::{cell::RefCell, rc::Rc};
+    use tokio::sync::mpsc;
+
+    const CASES: u64 = 64;
+    const ROWS: usize = 400;
+
+    /// Seeds to simulate, `SIM_SEED` narrows them down to one.
+    fn seeds() -> Vec<u64> {
+        match std::env::var("SIM_SEED") {
+            Ok(seed) => vec![seed.parse().expect("SIM_SEED must be a number")],
+            Err(_) => (0..CASES).collect(),
+        }
+    }
+
+    /// Random rows for a handful of clients, so their messages are interleaved closely.
+    fn input(rng: &mut Rng) -> String {
+        let mut csv = String::from("type,client,tx,amount\n");
+        for _ in 0..ROWS {
+            let client = 1 + rng.below(6);
+            let tx = 1 + rng.below(60);
+            let amount = (1 + rng.below(400)) as f32 / 4.0;
+            let row = match rng.below(10) {
+                0..=3 => format!("deposit,{client},{tx},{amount}"),
+                4..=5 => format!("withdrawal,{client},{tx},{amount}"),
+                6..=7 => format!("dispute,{client},{tx},"),
+                8 => format!("resolve,{client},{tx},"),
+                _ => format!("chargeback,{client},{tx},"),
+            };
+            csv.push_str(&row);
+            csv.push('\n');
+        }
+        csv
+    }
+
+    /// Runs the sharded engine over `input` under simulation, returns accounts in the order
+    /// their tasks finished.
+    fn simulate(seed: u64, input: &str) -> Vec<AccountRecord> {
+        let mut source = CsvSource::new(input.as_bytes());
+        let mut messages = Vec::new();
+        while let Some(record) = source.next_record() {
+            if let Some(message) = record
+                .ok()
+                .and_then(|record| Message::try_from(&record).ok())
+            {
+                let provenance = Provenance {
+                    source: "sim".into(),
+                    line: source.line(),
+                    reference: None,
+                    correlation_id: None,
+                };
+                messages.push((message, provenance));
+            }
+        }
+        let finished = Rc::new(RefCell::new(Vec::new()));
+
+        let collected = finished.clone();
+        run(seed, async move {
+            let (tx, rx) = mpsc::channel(4);
+            let (done_tx, mut done_rx) = mpsc::channel(4);
+            spawn(async move {
+                for message in messages {
+                    tx.send(message).await.unwrap();
+                }
+            });
+            spawn(async move {
+                while let Some(account) = done_rx.recv().await {
+                    collected.borrow_mut().push(AccountRecord::from(&account));
+                }
+            });
+            processor::start(
+                rx,
+                done_tx,
+                processor::Settings::current(),
+                Saved::default(),
+            )
+            .await;
+        });
+
+        finished.take()
+    }
+
+    #[test]
+    fn simulated_runs_match_reference() {
+        for seed in seeds() {
+            let input = input(&mut Rng::new(seed));
+            let mut accounts = simulate(seed, &input);
+            accounts.sort_by_key(|account| account.client);
+            let expected = reference::process(&mut CsvSource::new(input.as_bytes()));
+            assert_eq!(accounts, expected, "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn schedule_is_reproduced_from_seed() {
+        let input = input(&mut Rng::new(0));
+        let order = |seed| {
+            simulate(seed, &input)
+                .iter()
+                .map(|account| account.client)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(order(7), order(7));
+        assert!((0..8).any(|seed| order(seed) != order(7)));
+    }
+}
@@ -0,0 +1,53 @@
+//! Rows of accounts written while the run goes on, enabled with `--stream`, rather than only
+//! once every message of the client was applied.
+//!
+//! Account tasks report a snapshot of their account as soon as a message makes a significant
+//! change to it: locks or unlocks it, or moves its `total` across the amount given with
+//! `--stream-threshold`, either way. Snapshots go to the writer along with final states, so
+//! output has a row for every snapshot, and one for the final state of every account, last.
+//! The writer keeps only the latest row of a client within a batch, and sharded output only
+//! the latest of the run, so rows of a client later in output always supersede earlier ones.
+
+use std::sync::OnceLock;
+
+static THRESHOLD: OnceLock<Option<f32>> = OnceLock::new();
+
+/// Reports snapshots of accounts from now on, also when their total crosses `threshold`, if
+/// given. Only the first call has effect.
+pub fn enable(threshold: Option<f32>) {
+    let _ = THRESHOLD.set(threshold);
+}
+
+/// Whether snapshots are reported.
+pub fn enabled() -> bool {
+    THRESHOLD.get().is_some()
+}
+
+/// Whether an account which went from `before` to `after`, as total and lock, is worth a
+/// snapshot.
+pub fn significant(before: (f32, bool), after: (f32, bool)) -> bool {
+    match THRESHOLD.get() {
+        Some(threshold) => changed(*threshold, before, after),
+        None => false,
+    }
+}
+
+fn changed(threshold: Option<f32>, before: (f32, bool), after: (f32, bool)) -> bool {
+    let crossed = |threshold: f32| (before.0 < threshold) != (after.0 < threshold);
+    before.1 != after.1 || threshold.is_some_and(crossed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::changed;
+
+    #[test]
+    fn locks_and_crossings_are_significant() {
+        assert!(changed(None, (1.0, false), (1.0, true)));
+        assert!(changed(None, (1.0, true), (1.0, false)));
+        assert!(!changed(None, (1.0, false), (1000.0, false)));
+        assert!(changed(Some(100.0), (99.0, false), (100.0, false)));
+        assert!(changed(Some(100.0), (150.0, false), (50.0, false)));
+        assert!(!changed(Some(100.0), (150.0, false), (120.0, false)));
+    }
+}
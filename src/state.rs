@@ -0,0 +1,801 @@
+//! Persisted state of a run: final accounts along with their transaction history, kept in a
+//! directory given with `--state`, so it can be inspected offline with `trp query`.
+//!
+//! Account tasks only report their state once persistence is [`enable`]d. A run resumes from
+//! what earlier runs left in the directory, see [`load`], and [`save`] merges accounts it
+//! touched into it once it's over. The directory holds two csv files:
+//! - `accounts.csv`, same columns as the output of the engine.
+//! - `transactions.csv`, with `tx`, `client`, `state`, `amount` and `timestamp` of every
+//!   deposit. Funds of `pending` deposits count towards `total` of their account, but are
+//!   neither available nor held. Withdrawals are kept too when they are pending settlement,
+//!   `authorized` ones are held until they are `withdrawn`.
+//!
+//! Along with `inputs.csv`, which only grows: `hash`, `path`, `applied` time and `seed` of the
+//! run of every input file applied to the state, so a file is not applied twice by accident,
+//! and a run can be reproduced. Files are told apart by SHA-256 of their contents, see
+//! [`digest`].
+//!
+//! A run may also keep its state as a snapshot with an as-of label, e.g. `2024-06-30`, in
+//! `as-of/<LABEL>/` of the directory, laid out the same way. Snapshots stay when later runs
+//! update the state, so they can be queried and compared afterwards, see [`snapshot`].
+//!
+//! Before applying an experimental batch, such as a corrections file, a run may keep the state
+//! as it was in a named savepoint, `savepoints/<NAME>/`, inputs included. If the results turn
+//! out to be wrong, [`rollback`] puts the state back, and the batch can be applied again.
+//!
+//! Manual corrections, applied with `trp apply-corrections`, are kept in `audit.csv`, which
+//! only grows: rollbacks leave it as it is, so it also tells corrections which were undone.
+//!
+//! The directory is the only backend state is kept in. To move it elsewhere, e.g. to debug
+//! production state locally, [`export`] writes all of it to a single file, which [`import`]
+//! turns back into a directory: a first line of [`EXPORT_HEADER`], then every file as a
+//! `== <path> <bytes>` line followed by exactly as many bytes of its contents, and last an
+//! `== end <sha256>` line, with the hex encoded SHA-256 of everything before it, so that a
+//! truncated or altered export is refused as a whole.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Read, Write},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{
+    outbox,
+    signature::{self, Sha256},
+};
+
+const ACCOUNTS_FILE: &str = "accounts.csv";
+const TRANSACTIONS_FILE: &str = "transactions.csv";
+const INPUTS_FILE: &str = "inputs.csv";
+const AUDIT_FILE: &str = "audit.csv";
+const SNAPSHOTS_DIR: &str = "as-of";
+const SAVEPOINTS_DIR: &str = "savepoints";
+/// First line of an [`export`] of state.
+pub const EXPORT_HEADER: &str = "trp-state 1";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STATE: Mutex<State> = Mutex::new(State {
+    accounts: Vec::new(),
+    transactions: Vec::new(),
+});
+
+#[derive(Debug)]
+struct State {
+    accounts: Vec<AccountRecord>,
+    transactions: Vec<TransactionRecord>,
+}
+
+/// Account balances, as written by [`writer`](crate::writer).
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountRecord {
+    pub client: u16,
+    pub available: f32,
+    pub held: f32,
+    pub total: f32,
+    pub locked: bool,
+}
+
+/// Version of [`AccountSnapshot`], bumped whenever one of its fields is renamed, removed or
+/// changes meaning. Fields may be added to the end without a bump, so consumers should ignore
+/// fields they don't know.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Stable snapshot of an account, which output, state, Redis, gRPC and Flight are built from,
+/// rather than from account tasks, so that they keep their contract as the engine changes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountSnapshot {
+    /// [`SNAPSHOT_VERSION`] the snapshot was taken with.
+    pub schema_version: u32,
+    pub client: u16,
+    pub available: f32,
+    pub held: f32,
+    pub total: f32,
+    pub locked: bool,
+    /// Messages of the client applied to the account.
+    pub applied: u64,
+    /// Messages of the client which reached the account and were rejected.
+    pub rejected: u64,
+}
+
+impl Default for AccountSnapshot {
+    fn default() -> Self {
+        AccountSnapshot {
+            schema_version: SNAPSHOT_VERSION,
+            client: 0,
+            available: 0.0,
+            held: 0.0,
+            total: 0.0,
+            locked: false,
+            applied: 0,
+            rejected: 0,
+        }
+    }
+}
+
+impl From<&AccountSnapshot> for AccountRecord {
+    fn from(snapshot: &AccountSnapshot) -> Self {
+        AccountRecord {
+            client: snapshot.client,
+            available: snapshot.available,
+            held: snapshot.held,
+            total: snapshot.total,
+            locked: snapshot.locked,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionState {
+    /// Value-dated, funds are not available yet.
+    Pending,
+    Deposited,
+    Disputed,
+    /// Charged back.
+    Reversed,
+    /// Withdrawal pending settlement.
+    Authorized,
+    /// Settled withdrawal.
+    Withdrawn,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransactionRecord {
+    pub tx: u32,
+    pub client: u16,
+    pub state: TransactionState,
+    pub amount: f32,
+    /// Missing from state saved before messages had timestamps.
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+}
+
+/// Input file applied to the state.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputRecord {
+    /// Hex encoded SHA-256 of contents of the file.
+    pub hash: String,
+    pub path: String,
+    /// Milliseconds since unix epoch, when the run was over.
+    pub applied: u64,
+    /// Seed of the run, see [`rng`](crate::rng). Missing from state saved before runs were
+    /// seeded, and for files applied by hand.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+/// Correction applied to the state by hand, see
+/// [`corrections`](crate::commands::corrections).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Milliseconds since unix epoch, when the corrections were applied.
+    pub applied: u64,
+    /// Corrections file, and line of the correction in it.
+    pub source: String,
+    pub line: u64,
+    pub kind: String,
+    pub client: u16,
+    pub tx: Option<u32>,
+    pub amount: Option<f32>,
+    pub reason: String,
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Accounts kept by earlier runs, which a run resumes from: an account is taken once a message
+/// of its client comes, along with its transactions.
+#[derive(Debug, Default)]
+pub struct Saved {
+    accounts: HashMap<u16, AccountRecord>,
+    transactions: HashMap<u16, Vec<TransactionRecord>>,
+}
+
+impl Saved {
+    /// Transactions of every account, along with their client.
+    pub fn owners(&self) -> impl Iterator<Item = (u32, u16)> + '_ {
+        self.transactions.iter().flat_map(|(client, transactions)| {
+            transactions.iter().map(|record| (record.tx, *client))
+        })
+    }
+
+    /// Takes the account of `client` out, along with its transactions, if one was kept.
+    pub fn take(&mut self, client: u16) -> Option<(AccountRecord, Vec<TransactionRecord>)> {
+        let account = self.accounts.remove(&client)?;
+        let transactions = self.transactions.remove(&client).unwrap_or_default();
+        Some((account, transactions))
+    }
+}
+
+/// State kept in `dir` by earlier runs, nothing when there is none yet.
+pub fn load(dir: &Path) -> Result<Saved, anyhow::Error> {
+    let mut saved = Saved::default();
+    if !dir.join(ACCOUNTS_FILE).exists() {
+        return Ok(saved);
+    }
+    for account in accounts(dir)? {
+        saved.accounts.insert(account.client, account);
+    }
+    for transaction in all_transactions(dir)? {
+        saved
+            .transactions
+            .entry(transaction.client)
+            .or_default()
+            .push(transaction);
+    }
+    Ok(saved)
+}
+
+/// Records final state of an account and its transactions, to be written by [`save`].
+pub fn record(account: AccountRecord, transactions: impl Iterator<Item = TransactionRecord>) {
+    if !enabled() {
+        return;
+    }
+    let mut state = STATE.lock().unwrap_or_else(|err| err.into_inner());
+    state.accounts.push(account);
+    state.transactions.extend(transactions);
+}
+
+/// Merges everything recorded so far into state kept in `dir`: accounts recorded replace
+/// those of the same client along with their transactions, others are kept as they were.
+/// Writes the [`outbox`] of balances of the merged state when enabled.
+pub fn save(dir: &Path) -> Result<(), anyhow::Error> {
+    let mut state = STATE.lock().unwrap_or_else(|err| err.into_inner());
+    let State {
+        accounts,
+        transactions,
+    } = &mut *state;
+    let recorded: HashSet<u16> = accounts.iter().map(|account| account.client).collect();
+    if dir.join(ACCOUNTS_FILE).exists() {
+        accounts.extend(read(dir, ACCOUNTS_FILE, |account: &AccountRecord| {
+            !recorded.contains(&account.client)
+        })?);
+    }
+    if dir.join(TRANSACTIONS_FILE).exists() {
+        transactions.extend(read(
+            dir,
+            TRANSACTIONS_FILE,
+            |transaction: &TransactionRecord| !recorded.contains(&transaction.client),
+        )?);
+    }
+    if outbox::enabled() {
+        std::fs::create_dir_all(dir)?;
+        accounts.sort_by_key(|account| account.client);
+        // Staged ahead of the accounts file, which commits it, and promoted once it's in place,
+        // so that the outbox keeps entries of the last save until then.
+        let mut out = csv::Writer::from_writer(Vec::new());
+        for account in accounts.iter() {
+            out.serialize(account)?;
+        }
+        let mut hasher = Sha256::default();
+        hasher.update(&out.into_inner()?);
+        outbox::stage(dir, &signature::hex(&hasher.finish()), accounts)?;
+    }
+    replace(dir, accounts, transactions)?;
+    if outbox::enabled() {
+        outbox::promote(dir)?;
+    }
+    Ok(())
+}
+
+/// Replaces state kept in `dir` with `accounts` and `transactions`, instead of what was
+/// recorded.
+pub fn replace(
+    dir: &Path,
+    accounts: &mut [AccountRecord],
+    transactions: &mut [TransactionRecord],
+) -> Result<(), anyhow::Error> {
+    std::fs::create_dir_all(dir)?;
+    accounts.sort_by_key(|account| account.client);
+    transactions.sort_by_key(|transaction| transaction.tx);
+    write(&dir.join(ACCOUNTS_FILE), accounts)?;
+    write(&dir.join(TRANSACTIONS_FILE), transactions)?;
+    Ok(())
+}
+
+/// Checks `label` can name a snapshot or a savepoint: letters, digits, `-`, `_` and `.`, not
+/// starting with a `.`.
+pub fn label(label: &str) -> Result<String, anyhow::Error> {
+    let valid = label
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if label.is_empty() || label.starts_with('.') || !valid {
+        return Err(anyhow::anyhow!("Invalid label {label:?}"));
+    }
+    Ok(label.to_string())
+}
+
+/// Directory of the snapshot labeled `label` in state directory `dir`, the latest state when
+/// there is no label.
+pub fn snapshot(dir: &Path, label: Option<&str>) -> PathBuf {
+    match label {
+        Some(label) => dir.join(SNAPSHOTS_DIR).join(label),
+        None => dir.to_path_buf(),
+    }
+}
+
+/// Accounts file of the snapshot labeled `label` in `dir`, see [`snapshot`].
+pub fn accounts_file(dir: &Path, label: Option<&str>) -> PathBuf {
+    snapshot(dir, label).join(ACCOUNTS_FILE)
+}
+
+/// Keeps state saved in `dir` as the snapshot labeled `label`, replacing one with the same
+/// label.
+pub fn tag(dir: &Path, label: &str) -> Result<(), anyhow::Error> {
+    copy(
+        dir,
+        &snapshot(dir, Some(label)),
+        &[ACCOUNTS_FILE, TRANSACTIONS_FILE],
+    )
+}
+
+/// Directory of the savepoint `name` in state directory `dir`.
+pub fn savepoint_dir(dir: &Path, name: &str) -> PathBuf {
+    dir.join(SAVEPOINTS_DIR).join(name)
+}
+
+/// Keeps state in `dir` as it is now in the savepoint `name`, replacing one with the same
+/// name. State which was never saved is kept as such.
+pub fn savepoint(dir: &Path, name: &str) -> Result<(), anyhow::Error> {
+    copy(
+        dir,
+        &savepoint_dir(dir, name),
+        &[ACCOUNTS_FILE, TRANSACTIONS_FILE, INPUTS_FILE],
+    )
+}
+
+/// Puts state in `dir` back to the savepoint `name`, which is removed. Snapshots are left as
+/// they are.
+pub fn rollback(dir: &Path, name: &str) -> Result<(), anyhow::Error> {
+    let savepoint = savepoint_dir(dir, name);
+    if !savepoint.is_dir() {
+        return Err(anyhow::anyhow!("No savepoint {name} in {}", dir.display()));
+    }
+    copy(
+        &savepoint,
+        dir,
+        &[ACCOUNTS_FILE, TRANSACTIONS_FILE, INPUTS_FILE],
+    )?;
+    std::fs::remove_dir_all(savepoint)?;
+    Ok(())
+}
+
+/// Replaces `files` in `to` with those in `from`, removing the ones `from` doesn't have.
+fn copy(from: &Path, to: &Path, files: &[&str]) -> Result<(), anyhow::Error> {
+    std::fs::create_dir_all(to)?;
+    for file in files {
+        let path = to.join(file);
+        if !from.join(file).exists() {
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+            continue;
+        }
+        let tmp = path.with_extension("csv.tmp");
+        std::fs::copy(from.join(file), &tmp)?;
+        std::fs::rename(&tmp, path)?;
+    }
+    Ok(())
+}
+
+/// Writes next to `path` first, so readers never see a partially written file.
+fn write<T: Serialize>(path: &Path, rows: &[T]) -> Result<(), anyhow::Error> {
+    let tmp = path.with_extension("csv.tmp");
+    let mut out = csv::Writer::from_path(&tmp)?;
+    for row in rows {
+        out.serialize(row)?;
+    }
+    out.flush()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Reads every record of `file` in `dir` for which `filter` returns `true`.
+fn read<T, F>(dir: &Path, file: &str, filter: F) -> Result<Vec<T>, anyhow::Error>
+where
+    T: for<'de> Deserialize<'de>,
+    F: Fn(&T) -> bool,
+{
+    let path = dir.join(file);
+    let mut rdr = csv::Reader::from_path(&path)
+        .map_err(|err| anyhow::anyhow!("Failed to read {}: {err}", path.display()))?;
+    let mut found = Vec::new();
+    for record in rdr.deserialize() {
+        let record = record?;
+        if filter(&record) {
+            found.push(record);
+        }
+    }
+    Ok(found)
+}
+
+/// Writes every file of state kept in `dir`, snapshots, savepoints and parked accounts
+/// included, to `out` as a single export, see [module](self). Returns how many files it has.
+pub fn export<W: Write>(dir: &Path, out: W) -> Result<usize, anyhow::Error> {
+    if !dir.join(ACCOUNTS_FILE).exists() {
+        return Err(anyhow::anyhow!("No state in {}", dir.display()));
+    }
+    let mut files = Vec::new();
+    walk(dir, Path::new(""), &mut files)?;
+    files.sort();
+
+    let mut out = std::io::BufWriter::new(out);
+    let mut hasher = Sha256::default();
+    let mut write = |bytes: &[u8]| -> Result<(), anyhow::Error> {
+        hasher.update(bytes);
+        out.write_all(bytes)?;
+        Ok(())
+    };
+    write(format!("{EXPORT_HEADER}\n").as_bytes())?;
+    for file in &files {
+        let contents = std::fs::read(dir.join(file))?;
+        write(format!("== {} {}\n", file, contents.len()).as_bytes())?;
+        write(&contents)?;
+    }
+    let digest = signature::hex(&hasher.finish());
+    out.write_all(format!("== end {digest}\n").as_bytes())?;
+    out.flush()?;
+    Ok(files.len())
+}
+
+/// Adds paths of files in `dir`, relative to the state directory as `prefix`, to `files`.
+/// Files half way through being written are left out.
+fn walk(dir: &Path, prefix: &Path, files: &mut Vec<String>) -> Result<(), anyhow::Error> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = prefix.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            walk(&entry.path(), &path, files)?;
+        } else if !path.to_string_lossy().ends_with(".tmp") {
+            let name = path.to_str().ok_or_else(|| {
+                anyhow::anyhow!("Can't export {}, its name isn't UTF-8", path.display())
+            })?;
+            files.push(name.replace(std::path::MAIN_SEPARATOR, "/"));
+        }
+    }
+    Ok(())
+}
+
+/// Writes state of `export`, made by [`export`], to `dir`. Nothing is written unless the
+/// whole export checks out. Returns how many files it had.
+pub fn import(dir: &Path, export: &[u8]) -> Result<usize, anyhow::Error> {
+    let invalid = |reason: &str| anyhow::anyhow!("Invalid export of state, {reason}");
+    let mut rest = export
+        .strip_prefix(format!("{EXPORT_HEADER}\n").as_bytes())
+        .ok_or_else(|| invalid("it doesn't start with a trp-state 1 line"))?;
+    let mut files = Vec::new();
+    loop {
+        let end = rest
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .ok_or_else(|| invalid("it ends half way through"))?;
+        let line = std::str::from_utf8(&rest[..end]).map_err(|_| invalid("a line isn't UTF-8"))?;
+        rest = &rest[end + 1..];
+        let (name, size) = line
+            .strip_prefix("== ")
+            .and_then(|line| line.rsplit_once(' '))
+            .ok_or_else(|| invalid(&format!("expected a file, got {line:?}")))?;
+        if name == "end" {
+            let digest = &export[..export.len() - rest.len() - line.len() - 1];
+            let mut hasher = Sha256::default();
+            hasher.update(digest);
+            if signature::hex(&hasher.finish()) != size || !rest.is_empty() {
+                return Err(invalid("its checksum doesn't match its contents"));
+            }
+            break;
+        }
+        let relative = Path::new(name);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(invalid(&format!(
+                "{name} is outside of the state directory"
+            )));
+        }
+        let size: usize = size
+            .parse()
+            .map_err(|_| invalid(&format!("invalid size of {name}")))?;
+        if size > rest.len() {
+            return Err(invalid("it ends half way through"));
+        }
+        files.push((relative, &rest[..size]));
+        rest = &rest[size..];
+    }
+    if !files
+        .iter()
+        .any(|(name, _)| *name == Path::new(ACCOUNTS_FILE))
+    {
+        return Err(invalid("it has no accounts"));
+    }
+
+    for (name, contents) in &files {
+        let path = dir.join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, path)?;
+    }
+    Ok(files.len())
+}
+
+/// Hex encoded SHA-256 of contents of the file at `path`.
+pub fn digest(path: &Path) -> Result<String, anyhow::Error> {
+    let mut file = std::fs::File::open(path)
+        .map_err(|err| anyhow::anyhow!("Failed to read {}: {err}", path.display()))?;
+    let mut hasher = Sha256::default();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+    Ok(signature::hex(&hasher.finish()))
+}
+
+/// Hex encoded SHA-256 of the accounts file of state kept in `dir`, `None` when there is no
+/// state yet.
+pub fn commit(dir: &Path) -> Result<Option<String>, anyhow::Error> {
+    let path = dir.join(ACCOUNTS_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    digest(&path).map(Some)
+}
+
+/// Inputs applied to state kept in `dir`, none when nothing was applied yet.
+fn inputs(dir: &Path) -> Result<Vec<InputRecord>, anyhow::Error> {
+    if !dir.join(INPUTS_FILE).exists() {
+        return Ok(Vec::new());
+    }
+    read(dir, INPUTS_FILE, |_: &InputRecord| true)
+}
+
+/// Input whose contents hash to `hash`, if it was applied to state kept in `dir`.
+pub fn input(dir: &Path, hash: &str) -> Result<Option<InputRecord>, anyhow::Error> {
+    Ok(inputs(dir)?.into_iter().find(|input| input.hash == hash))
+}
+
+/// Adds `input` to inputs applied to state kept in `dir`.
+pub fn applied(dir: &Path, input: InputRecord) -> Result<(), anyhow::Error> {
+    let mut inputs = inputs(dir)?;
+    inputs.push(input);
+    write(&dir.join(INPUTS_FILE), &inputs)
+}
+
+/// Adds `records` to the audit trail of state kept in `dir`.
+pub fn audit(dir: &Path, records: &[AuditRecord]) -> Result<(), anyhow::Error> {
+    let mut trail = if dir.join(AUDIT_FILE).exists() {
+        read(dir, AUDIT_FILE, |_: &AuditRecord| true)?
+    } else {
+        Vec::new()
+    };
+    trail.extend_from_slice(records);
+    write(&dir.join(AUDIT_FILE), &trail)
+}
+
+/// Every account kept in `dir`, ordered by client.
+pub fn accounts(dir: &Path) -> Result<Vec<AccountRecord>, anyhow::Error> {
+    read(dir, ACCOUNTS_FILE, |_: &AccountRecord| true)
+}
+
+/// Transactions of every client kept in `dir`, ordered by id.
+pub fn all_transactions(dir: &Path) -> Result<Vec<TransactionRecord>, anyhow::Error> {
+    read(dir, TRANSACTIONS_FILE, |_: &TransactionRecord| true)
+}
+
+/// Account of `client` kept in `dir`, if there is one.
+pub fn account(dir: &Path, client: u16) -> Result<Option<AccountRecord>, anyhow::Error> {
+    let found = read(dir, ACCOUNTS_FILE, |account: &AccountRecord| {
+        account.client == client
+    })?;
+    Ok(found.into_iter().next())
+}
+
+/// Transactions of `client` kept in `dir`, ordered by id.
+pub fn transactions(dir: &Path, client: u16) -> Result<Vec<TransactionRecord>, anyhow::Error> {
+    read(dir, TRANSACTIONS_FILE, |transaction: &TransactionRecord| {
+        transaction.client == client
+    })
+}
+
+/// Transaction `tx` kept in `dir`, if there is one.
+pub fn transaction(dir: &Path, tx: u32) -> Result<Option<TransactionRecord>, anyhow::Error> {
+    let found = read(dir, TRANSACTIONS_FILE, |transaction: &TransactionRecord| {
+        transaction.tx == tx
+    })?;
+    Ok(found.into_iter().next())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        account, applied, digest, enable, export, import, input, label, record, save, snapshot,
+        tag, transaction, transactions, AccountRecord, AccountSnapshot, InputRecord,
+        TransactionRecord, TransactionState,
+    };
+
+    #[test]
+    fn saved_state_is_queried() {
+        let dir = std::env::temp_dir().join(format!("trp-state-{}", std::process::id()));
+        let balance = |client, available| AccountRecord {
+            client,
+            available,
+            held: 0.0,
+            total: available,
+            locked: false,
+        };
+        let deposit = |tx, client, state| TransactionRecord {
+            tx,
+            client,
+            state,
+            amount: 1.0,
+            timestamp: Some(u64::from(tx)),
+        };
+
+        enable();
+        record(
+            balance(2, 2.0),
+            [
+                deposit(3, 2, TransactionState::Deposited),
+                deposit(2, 2, TransactionState::Disputed),
+            ]
+            .into_iter(),
+        );
+        record(
+            balance(1, 1.0),
+            [deposit(1, 1, TransactionState::Reversed)].into_iter(),
+        );
+        save(&dir).unwrap();
+
+        assert_eq!(account(&dir, 2).unwrap(), Some(balance(2, 2.0)));
+        assert_eq!(account(&dir, 3).unwrap(), None);
+        assert_eq!(
+            transactions(&dir, 2).unwrap(),
+            vec![
+                deposit(2, 2, TransactionState::Disputed),
+                deposit(3, 2, TransactionState::Deposited)
+            ]
+        );
+        assert_eq!(
+            transaction(&dir, 1).unwrap(),
+            Some(deposit(1, 1, TransactionState::Reversed))
+        );
+
+        // Snapshots are laid out like the state itself.
+        let label = label("2024-06-30").unwrap();
+        tag(&dir, &label).unwrap();
+        let tagged = snapshot(&dir, Some(&label));
+        assert_eq!(account(&tagged, 2).unwrap(), Some(balance(2, 2.0)));
+        assert_eq!(
+            transactions(&tagged, 2).unwrap(),
+            transactions(&dir, 2).unwrap()
+        );
+        assert!(account(&snapshot(&dir, Some("2024-07-31")), 2).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(account(&dir, 1).is_err());
+    }
+
+    #[test]
+    fn applied_inputs_are_found_by_contents() {
+        let dir = std::env::temp_dir().join(format!("trp-inputs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |name, contents| {
+            let path = dir.join(name);
+            std::fs::write(&path, contents).unwrap();
+            digest(&path).unwrap()
+        };
+        let first = file("first.csv", "type,client,tx,amount\n");
+        assert_eq!(
+            first,
+            "0c4bb2c522b6691f4c8e807cc5ba1e464fb93b298a7c3fad6b54cf850a09a987"
+        );
+        assert_eq!(input(&dir, &first).unwrap(), None);
+
+        let record = InputRecord {
+            hash: first.clone(),
+            path: "first.csv".to_string(),
+            applied: 1,
+            seed: Some(7),
+        };
+        applied(&dir, record.clone()).unwrap();
+        // Same contents under another name.
+        assert_eq!(
+            input(&dir, &file("copy.csv", "type,client,tx,amount\n")).unwrap(),
+            Some(record)
+        );
+        assert_eq!(
+            input(&dir, &file("other.csv", "type,client,tx\n")).unwrap(),
+            None
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn exported_state_is_imported() {
+        let dir = std::env::temp_dir().join(format!("trp-export-{}", std::process::id()));
+        let from = dir.join("from");
+        std::fs::create_dir_all(from.join("dormant")).unwrap();
+        std::fs::write(from.join("accounts.csv"), "client\n1\n").unwrap();
+        std::fs::write(from.join("dormant/7.csv"), "== end\n\"a\nb\"\n").unwrap();
+        std::fs::write(from.join("accounts.csv.tmp"), "half").unwrap();
+
+        let mut exported = Vec::new();
+        assert_eq!(export(&from, &mut exported).unwrap(), 2);
+        let to = dir.join("to");
+        assert_eq!(import(&to, &exported).unwrap(), 2);
+        assert_eq!(
+            std::fs::read(to.join("dormant/7.csv")).unwrap(),
+            std::fs::read(from.join("dormant/7.csv")).unwrap()
+        );
+        assert!(!to.join("accounts.csv.tmp").exists());
+
+        // Nothing is written from a truncated or altered export.
+        let other = dir.join("other");
+        assert!(import(&other, &exported[..exported.len() - 10]).is_err());
+        let mut altered = exported.clone();
+        let at = altered.iter().position(|byte| *byte == b'1').unwrap();
+        altered[at] = b'2';
+        assert!(import(&other, &altered).is_err());
+        let escaping = String::from_utf8(exported.clone())
+            .unwrap()
+            .replace("== accounts.csv", "== ../accounts.csv");
+        assert!(import(&other, escaping.as_bytes()).is_err());
+        assert!(!other.exists());
+        assert!(export(&other, Vec::new()).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn labels_are_names_of_directories() {
+        assert!(label("2024-06-30").is_ok());
+        assert!(label("q2_2024.final").is_ok());
+        for invalid in ["", "..", ".hidden", "2024/06/30", "a b"] {
+            assert!(label(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn snapshots_keep_their_columns() {
+        let snapshot = AccountSnapshot {
+            client: 3,
+            available: 1.5,
+            total: 1.5,
+            applied: 2,
+            rejected: 1,
+            ..AccountSnapshot::default()
+        };
+        let mut out = csv::Writer::from_writer(Vec::new());
+        out.serialize(&snapshot).unwrap();
+        assert_eq!(
+            String::from_utf8(out.into_inner().unwrap()).unwrap(),
+            "schema_version,client,available,held,total,locked,applied,rejected\n1,3,1.5,0.0,1.5,false,2,1\n"
+        );
+        assert_eq!(
+            AccountRecord::from(&snapshot),
+            AccountRecord {
+                client: 3,
+                available: 1.5,
+                held: 0.0,
+                total: 1.5,
+                locked: false,
+            }
+        );
+    }
+}
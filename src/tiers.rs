@@ -0,0 +1,175 @@
+//! Tiers of accounts, read with `--tiers`, so one run can model customers of different
+//! products. The tier of a client picks its [`reserve`](crate::reserve) minimum balance, and
+//! its [`Rules`] from the table read with `--tier-rules`.
+//!
+//! Tiers of clients are csv of `client,tier` rows, and rules are csv with a row per tier,
+//! where every rule may be left empty:
+//!
+//! ```csv
+//! tier,max_withdrawal,withdrawal_fee,overdraft
+//! gold,10000,0,500
+//! basic,1000,0.5,
+//! ```
+//!
+//! Clients which are not in the tiers file, or whose tier has no row, have no rules.
+
+use serde::Deserialize;
+use std::{collections::HashMap, io::Read, path::Path, sync::OnceLock};
+
+pub use crate::engine::Rules;
+
+static TIERS: OnceLock<Tiers> = OnceLock::new();
+
+/// Tiers of clients, along with rules of every tier.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Tiers {
+    clients: HashMap<u16, String>,
+    rules: HashMap<String, Rules>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Client {
+    client: u16,
+    tier: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Row {
+    tier: String,
+    #[serde(default)]
+    max_withdrawal: Option<f32>,
+    #[serde(default)]
+    withdrawal_fee: Option<f32>,
+    #[serde(default)]
+    overdraft: Option<f32>,
+}
+
+impl Tiers {
+    /// Tiers of clients from `clients`, with rules from `rules`, either of which may be left out.
+    pub fn load(clients: Option<&Path>, rules: Option<&Path>) -> Result<Self, anyhow::Error> {
+        let mut tiers = Tiers::default();
+        if let Some(path) = clients {
+            std::fs::File::open(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|file| tiers.read_clients(file))
+                .map_err(|err| anyhow::anyhow!("Failed to load {}: {err}", path.display()))?;
+        }
+        if let Some(path) = rules {
+            std::fs::File::open(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|file| tiers.read_rules(file))
+                .map_err(|err| anyhow::anyhow!("Failed to load {}: {err}", path.display()))?;
+        }
+        Ok(tiers)
+    }
+
+    fn read_clients<R: Read>(&mut self, reader: R) -> Result<(), anyhow::Error> {
+        for entry in csv::Reader::from_reader(reader).deserialize() {
+            let Client { client, tier } = entry?;
+            self.clients.insert(client, tier.trim().to_string());
+        }
+        Ok(())
+    }
+
+    fn read_rules<R: Read>(&mut self, reader: R) -> Result<(), anyhow::Error> {
+        for row in csv::Reader::from_reader(reader).deserialize() {
+            let row: Row = row?;
+            for amount in [row.max_withdrawal, row.withdrawal_fee, row.overdraft]
+                .into_iter()
+                .flatten()
+            {
+                if !amount.is_finite() || amount < 0.0 {
+                    return Err(anyhow::anyhow!(
+                        "tier {}: {amount} is not a non-negative amount",
+                        row.tier
+                    ));
+                }
+            }
+            self.rules.insert(
+                row.tier.trim().to_string(),
+                Rules {
+                    max_withdrawal: row.max_withdrawal,
+                    withdrawal_fee: row.withdrawal_fee.unwrap_or_default(),
+                    overdraft: row.overdraft.unwrap_or_default(),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn rules(&self, client: u16) -> Rules {
+        self.clients
+            .get(&client)
+            .and_then(|tier| self.rules.get(tier))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Keeps `tiers` for accounts created from now on. Only the first call has effect.
+pub fn enable(tiers: Tiers) {
+    let _ = TIERS.set(tiers);
+}
+
+/// Tier of `client`, if it has one.
+pub fn tier(client: u16) -> Option<&'static str> {
+    TIERS
+        .get()
+        .and_then(|tiers| tiers.clients.get(&client))
+        .map(String::as_str)
+}
+
+/// Rules of the tier of `client`, none when it has no tier.
+pub fn rules(client: u16) -> Rules {
+    TIERS
+        .get()
+        .map(|tiers| tiers.rules(client))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rules, Tiers};
+
+    #[test]
+    fn rules_are_the_ones_of_the_tier_of_the_client() {
+        let mut tiers = Tiers::default();
+        tiers
+            .read_clients("client,tier\n1,gold\n2,basic\n3,silver\n".as_bytes())
+            .unwrap();
+        tiers
+            .read_rules(
+                "tier,max_withdrawal,withdrawal_fee,overdraft\ngold,,,500\nbasic,1000,0.5,\n"
+                    .as_bytes(),
+            )
+            .unwrap();
+        assert_eq!(
+            (1..=4)
+                .map(|client| tiers.rules(client))
+                .collect::<Vec<_>>(),
+            vec![
+                Rules {
+                    max_withdrawal: None,
+                    withdrawal_fee: 0.0,
+                    overdraft: 500.0,
+                },
+                Rules {
+                    max_withdrawal: Some(1000.0),
+                    withdrawal_fee: 0.5,
+                    overdraft: 0.0,
+                },
+                Rules::default(),
+                Rules::default(),
+            ]
+        );
+
+        // Rules left out of the header are none.
+        tiers
+            .read_rules("tier,overdraft\nsilver,5\n".as_bytes())
+            .unwrap();
+        assert_eq!(tiers.rules(3).overdraft, 5.0);
+        assert!(Tiers::default()
+            .read_rules("tier,withdrawal_fee\nbasic,-1\n".as_bytes())
+            .is_err());
+    }
+}
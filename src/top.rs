@@ -0,0 +1,188 @@
+//! Top accounts report of a run, written with `--top`: accounts ranked by volume, held funds
+//! and rejected messages, so analysts don't have to join output with logs.
+//!
+//! The report is csv of `ranking,rank,client,value` rows, the first `--top-n` accounts of
+//! every ranking in order:
+//!
+//! - `volume` - amount of deposits and withdrawals which were applied, posted interest included.
+//! - `held` - funds held once the run is over.
+//! - `rejects` - messages rejected by the router or the account, from screening to the rules
+//!   of the engine. Rows rejected by the parser have no account to count towards.
+//!
+//! Accounts with nothing to rank are left out, ties go to the lower client id.
+
+use serde::Serialize;
+use std::{cmp::Ordering, collections::HashMap, path::Path, sync::Mutex};
+
+use crate::Message;
+
+/// Accounts in every ranking, unless set otherwise.
+pub const DEFAULT_N: usize = 10;
+
+static TOP: Mutex<Option<Top>> = Mutex::new(None);
+
+/// Name of a ranking, along with the value it ranks accounts by.
+type Ranking = (&'static str, fn(&Stats) -> Value);
+
+const RANKINGS: [Ranking; 3] = [
+    ("volume", |stats| Value::Amount(stats.volume as f32)),
+    ("held", |stats| Value::Amount(stats.held)),
+    ("rejects", |stats| Value::Count(stats.rejects)),
+];
+
+#[derive(Debug, Default)]
+struct Top {
+    n: usize,
+    accounts: HashMap<u16, Stats>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Stats {
+    /// Kept as `f64`, so sums of many amounts do not drift.
+    volume: f64,
+    held: f32,
+    rejects: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Row {
+    ranking: &'static str,
+    rank: usize,
+    client: u16,
+    value: Value,
+}
+
+/// Value accounts are ranked by, amounts are written like in output, counts as integers.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(untagged)]
+enum Value {
+    Amount(f32),
+    Count(u64),
+}
+
+impl Value {
+    fn key(self) -> f64 {
+        match self {
+            Value::Amount(amount) => f64::from(amount),
+            Value::Count(count) => count as f64,
+        }
+    }
+}
+
+impl Top {
+    fn new(n: usize) -> Self {
+        Top {
+            n,
+            accounts: HashMap::new(),
+        }
+    }
+
+    fn rows(&self) -> Vec<Row> {
+        let mut rows = Vec::new();
+        for (ranking, value) in RANKINGS {
+            let mut ranked: Vec<_> = self
+                .accounts
+                .iter()
+                .map(|(client, stats)| (*client, value(stats)))
+                .filter(|(_, value)| value.key() > 0.0)
+                .collect();
+            ranked.sort_by(|a, b| match b.1.key().total_cmp(&a.1.key()) {
+                Ordering::Equal => a.0.cmp(&b.0),
+                other => other,
+            });
+            rows.extend(ranked.into_iter().take(self.n).enumerate().map(
+                |(rank, (client, value))| Row {
+                    ranking,
+                    rank: rank + 1,
+                    client,
+                    value,
+                },
+            ));
+        }
+        rows
+    }
+}
+
+/// Starts ranking accounts, `n` of them in every ranking.
+pub fn enable(n: usize) {
+    *TOP.lock().unwrap_or_else(|err| err.into_inner()) = Some(Top::new(n));
+}
+
+fn with(client: u16, f: impl FnOnce(&mut Stats)) {
+    if let Some(top) = TOP.lock().unwrap_or_else(|err| err.into_inner()).as_mut() {
+        f(top.accounts.entry(client).or_default());
+    }
+}
+
+/// Counts `message`, which was applied.
+pub fn applied(message: &Message) {
+    if let Some(amount) = message.amount() {
+        with(message.client_id(), |stats| {
+            stats.volume += f64::from(amount)
+        });
+    }
+}
+
+/// Records current held funds of `client`.
+pub fn held(client: u16, held: f32) {
+    with(client, |stats| stats.held = held);
+}
+
+/// Counts a rejected message of `client`.
+pub fn rejected(client: u16) {
+    with(client, |stats| stats.rejects += 1);
+}
+
+/// Writes rankings of accounts so far to `path`, replacing its contents.
+pub fn write(path: &Path) -> Result<(), anyhow::Error> {
+    let top = TOP.lock().unwrap_or_else(|err| err.into_inner());
+    let mut out = csv::Writer::from_path(path)?;
+    if let Some(top) = top.as_ref() {
+        for row in top.rows() {
+            out.serialize(row)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Stats, Top, Value};
+
+    #[test]
+    fn accounts_are_ranked_with_ties_to_lower_client() {
+        let mut top = Top::new(2);
+        for (client, volume, held, rejects) in [
+            (1, 10.0, 0.0, 1),
+            (2, 30.0, 5.0, 0),
+            (3, 10.0, 1.0, 1),
+            (4, 20.0, 0.0, 3),
+        ] {
+            top.accounts.insert(
+                client,
+                Stats {
+                    volume,
+                    held,
+                    rejects,
+                },
+            );
+        }
+        let rows: Vec<_> = top
+            .rows()
+            .into_iter()
+            .map(|row| (row.ranking, row.rank, row.client, row.value))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                ("volume", 1, 2, Value::Amount(30.0)),
+                ("volume", 2, 4, Value::Amount(20.0)),
+                ("held", 1, 2, Value::Amount(5.0)),
+                ("held", 2, 3, Value::Amount(1.0)),
+                ("rejects", 1, 4, Value::Count(3)),
+                ("rejects", 2, 1, Value::Count(1)),
+            ]
+        );
+    }
+}
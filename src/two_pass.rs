@@ -0,0 +1,151 @@
+//! Two-pass processing, enabled with `trp process --two-pass`, for runs which value accuracy
+//! over speed, such as month-end reconciliation.
+//!
+//! The first pass reads the whole input, and indexes the line every transaction id is first
+//! used on by a deposit or withdrawal. The second pass is the usual run, except that the
+//! parser checks every message against the index before passing it on:
+//!
+//! - deposits and withdrawals reusing a transaction id used earlier in the input, by any
+//!   client, are rejected with [`DUPLICATE`].
+//! - disputes, resolves, chargebacks and settles referencing a transaction which only comes
+//!   later in the input are rejected with [`FORWARD_REFERENCE`].
+//!
+//! Both are written to the [`dlq`](crate::dlq). References to transactions the input does not
+//! have at all are left to the engine, since they may be part of persisted state.
+
+use std::{collections::HashMap, path::Path, sync::OnceLock};
+
+use crate::{
+    config,
+    format::{self, Format, Source},
+    provenance::Provenance,
+    Message,
+};
+
+/// Error code of deposits and withdrawals whose transaction id was used before.
+pub const DUPLICATE: &str = "PR_DUP";
+/// Error code of messages referencing a transaction which comes later in the input.
+pub const FORWARD_REFERENCE: &str = "PR_FWD";
+
+static INDEX: OnceLock<Index> = OnceLock::new();
+
+/// What the first pass learned of an input.
+#[derive(Debug, Default)]
+pub struct Index {
+    /// Source the index is of, see [`Provenance`].
+    source: String,
+    /// Line every transaction id is first used on by a deposit or withdrawal.
+    first: HashMap<u32, u64>,
+}
+
+impl Index {
+    /// Reads `source` to the end, naming it `origin` like the parser does.
+    pub fn build(source: &mut dyn Source, origin: &str) -> Self {
+        let mut first = HashMap::new();
+        while let Some(result) = source.next_record() {
+            let Ok(message) = result.and_then(|record| Message::try_from(&record)) else {
+                continue;
+            };
+            if message.amount().is_some() {
+                first
+                    .entry(message.transaction_id())
+                    .or_insert(source.line());
+            }
+        }
+        Index {
+            source: origin.to_string(),
+            first,
+        }
+    }
+
+    /// Checks `message` read from `provenance` against the index, returning the error code
+    /// it must be rejected with, if any.
+    pub fn check(&self, message: &Message, provenance: &Provenance) -> Result<(), &'static str> {
+        if *provenance.source != *self.source {
+            return Ok(());
+        }
+        match self.first.get(&message.transaction_id()) {
+            Some(line) if message.amount().is_some() && *line != provenance.line => Err(DUPLICATE),
+            Some(line) if message.amount().is_none() && *line > provenance.line => {
+                Err(FORWARD_REFERENCE)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Indexes `input` in a first pass, so that messages read from it later are checked, see
+/// [`check`]. Returns number of transactions indexed.
+pub fn enable(input: &Path) -> Result<usize, anyhow::Error> {
+    let mut source = format::source(input, Format::of(input)?, config::engine())?;
+    let index = Index::build(source.as_mut(), &input.display().to_string());
+    let transactions = index.first.len();
+    let _ = INDEX.set(index);
+    Ok(transactions)
+}
+
+/// Checks `message` read from `provenance` against the index of the first pass, if there was
+/// one.
+pub fn check(message: &Message, provenance: &Provenance) -> Result<(), &'static str> {
+    match INDEX.get() {
+        Some(index) => index.check(message, provenance),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Index, DUPLICATE, FORWARD_REFERENCE};
+    use crate::{
+        format::{CsvSource, Source},
+        provenance::Provenance,
+        Message,
+    };
+
+    #[test]
+    fn duplicates_and_forward_references_are_caught() {
+        let input = "type,client,tx,amount\n\
+                     dispute,1,2,\n\
+                     deposit,1,1,1.0\n\
+                     deposit,1,2,1.0\n\
+                     deposit,2,1,5.0\n\
+                     dispute,1,2,\n";
+        let mut source = CsvSource::new(input.as_bytes());
+        let index = Index::build(&mut source, "in.csv");
+        let mut source = CsvSource::new(input.as_bytes());
+        let mut checks = Vec::new();
+        while let Some(record) = source.next_record() {
+            let message = Message::try_from(&record.unwrap()).unwrap();
+            let provenance = Provenance {
+                source: "in.csv".into(),
+                line: source.line(),
+                reference: None,
+                correlation_id: None,
+            };
+            checks.push(index.check(&message, &provenance));
+        }
+        assert_eq!(
+            checks,
+            [
+                Err(FORWARD_REFERENCE),
+                Ok(()),
+                Ok(()),
+                Err(DUPLICATE),
+                Ok(())
+            ]
+        );
+
+        let elsewhere = Provenance {
+            source: "other.csv".into(),
+            line: 2,
+            reference: None,
+            correlation_id: None,
+        };
+        let dispute = Message::Dispute {
+            client: 1,
+            tx: 2,
+            timestamp: None,
+        };
+        assert_eq!(index.check(&dispute, &elsewhere), Ok(()));
+    }
+}
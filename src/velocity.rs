@@ -0,0 +1,195 @@
+//! Velocity rules, enabled with `--max-withdrawals` or `--max-withdrawn`: flag clients which
+//! withdraw too often, or split large sums into many smaller withdrawals, as an AML system
+//! would. Breaching a rule raises an [`Alert`], messages are applied regardless.
+//!
+//! Not every input carries timestamps, so the window rules look at is the most recent messages of
+//! a client, `--velocity-window` of them. Only withdrawals which were applied count. A rule
+//! alerts once when it is breached, and again only after the client has been back within it.
+
+use std::{collections::VecDeque, sync::OnceLock};
+
+use crate::{alerts::Alert, cli::Velocity, Message};
+
+/// Rule of the number of withdrawals.
+pub const VELOCITY: &str = "velocity";
+/// Rule of the amount withdrawn.
+pub const STRUCTURING: &str = "structuring";
+
+static RULES: OnceLock<Velocity> = OnceLock::new();
+
+/// Starts checking `rules` in account tasks started from now on.
+pub fn enable(rules: Velocity) {
+    let _ = RULES.set(rules);
+}
+
+/// Rules to check, `None` when they are off.
+pub fn rules() -> Option<Velocity> {
+    RULES.get().copied().filter(Velocity::enabled)
+}
+
+/// Recent messages of a single client.
+#[derive(Debug)]
+pub struct Window {
+    rules: Velocity,
+    /// Amount of every message in the window which is an applied withdrawal.
+    recent: VecDeque<Option<f32>>,
+    withdrawals: u64,
+    /// Kept as `f64`, so it does not drift as amounts enter and leave the window.
+    withdrawn: f64,
+    /// Whether [`VELOCITY`] and [`STRUCTURING`] rules were breached after the last message.
+    breached: (bool, bool),
+}
+
+impl Window {
+    /// Empty window of `rules`.
+    pub fn new(rules: Velocity) -> Self {
+        Window {
+            rules,
+            recent: VecDeque::with_capacity(rules.window),
+            withdrawals: 0,
+            withdrawn: 0.0,
+            breached: (false, false),
+        }
+    }
+
+    /// Moves the window past `message`, returns alerts of rules it breached.
+    pub fn observe(&mut self, message: &Message, applied: bool) -> Vec<Alert> {
+        if self.recent.len() == self.rules.window {
+            if let Some(Some(amount)) = self.recent.pop_front() {
+                self.withdrawals -= 1;
+                self.withdrawn -= f64::from(amount);
+            }
+        }
+        let withdrawn = match *message {
+            Message::Withdraw { amount, .. } if applied => Some(amount),
+            _ => None,
+        };
+        if let Some(amount) = withdrawn {
+            self.withdrawals += 1;
+            self.withdrawn += f64::from(amount);
+        }
+        self.recent.push_back(withdrawn);
+
+        let velocity = self
+            .rules
+            .max_withdrawals
+            .is_some_and(|max| self.withdrawals > max);
+        let structuring = self
+            .rules
+            .max_withdrawn
+            .is_some_and(|max| self.withdrawn > max);
+        let mut alerts = Vec::new();
+        for (rule, breached, was) in [
+            (VELOCITY, velocity, self.breached.0),
+            (STRUCTURING, structuring, self.breached.1),
+        ] {
+            if breached && !was {
+                alerts.push(Alert {
+                    client: message.client_id(),
+                    tx: message.transaction_id(),
+                    rule,
+                    withdrawals: Some(self.withdrawals),
+                    withdrawn: Some(self.withdrawn as f32),
+                });
+            }
+        }
+        self.breached = (velocity, structuring);
+        alerts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Window, STRUCTURING, VELOCITY};
+    use crate::{cli::Velocity, Message};
+
+    fn withdraw(tx: u32, amount: f32) -> Message {
+        Message::Withdraw {
+            client: 1,
+            tx,
+            amount,
+            timestamp: None,
+        }
+    }
+
+    fn deposit(tx: u32) -> Message {
+        Message::Deposit {
+            client: 1,
+            tx,
+            amount: 1.0,
+            timestamp: None,
+            effective_date: None,
+        }
+    }
+
+    /// Rules alerted by each message, in order.
+    fn alerts(window: &mut Window, messages: &[(Message, bool)]) -> Vec<Vec<&'static str>> {
+        messages
+            .iter()
+            .map(|(message, applied)| {
+                window
+                    .observe(message, *applied)
+                    .into_iter()
+                    .map(|alert| alert.rule)
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn breach_alerts_once_until_back_within_rule() {
+        let mut window = Window::new(Velocity {
+            window: 3,
+            max_withdrawals: Some(1),
+            max_withdrawn: None,
+        });
+        let alerts = alerts(
+            &mut window,
+            &[
+                (withdraw(1, 1.0), true),
+                (withdraw(2, 1.0), true),
+                (withdraw(3, 1.0), true),
+                (deposit(4), true),
+                (deposit(5), true),
+                (withdraw(6, 1.0), false),
+                (withdraw(7, 1.0), true),
+                (withdraw(8, 1.0), true),
+            ],
+        );
+        let none: Vec<&str> = vec![];
+        assert_eq!(
+            alerts,
+            vec![
+                none.clone(),
+                vec![VELOCITY],
+                none.clone(),
+                none.clone(),
+                none.clone(),
+                none.clone(),
+                none.clone(),
+                vec![VELOCITY],
+            ]
+        );
+    }
+
+    #[test]
+    fn small_withdrawals_add_up_to_structuring() {
+        let mut window = Window::new(Velocity {
+            window: 4,
+            max_withdrawals: None,
+            max_withdrawn: Some(100.0),
+        });
+        let breached: Vec<_> = (1..=6)
+            .map(|tx| window.observe(&withdraw(tx, 30.0), true))
+            .collect();
+        assert!(breached[..3].iter().all(Vec::is_empty));
+        assert_eq!(breached[3].len(), 1);
+        assert_eq!(breached[3][0].rule, STRUCTURING);
+        assert_eq!(
+            (breached[3][0].tx, breached[3][0].withdrawals),
+            (4, Some(4))
+        );
+        assert_eq!(breached[3][0].withdrawn, Some(120.0));
+        assert!(breached[4..].iter().all(Vec::is_empty));
+    }
+}
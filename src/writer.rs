@@ -0,0 +1,555 @@
+//! Writes final account states reported by account tasks to a [`Sink`], stdout as csv
+//! unless the output is split between [`Shards`].
+//!
+//! Rows are handed to the sink in batches of up to `writer_batch_size` rows of the engine
+//! [configuration](crate::config), and a batch is flushed early once `writer_flush_interval_ms`
+//! passed since the previous one, when the next row comes. Stdout is flushed once per batch
+//! rather than once per row.
+//!
+//! Extended output adds `pending` column, with funds of value-dated deposits which are not
+//! available yet but count towards `total`, along with `first_activity` and `last_activity`
+//! columns, with the earliest and latest timestamps of messages of every client, empty when
+//! its messages had none. `applied` and `rejected` count messages of the client, and `zombie`
+//! flags accounts which are of no use any more, see [`Account::zombie`].
+//!
+//! With [`snapshots`], accounts are also reported while the run goes on, and a batch keeps
+//! only the latest row of every client, in place of the first one.
+//!
+//! Columns can be picked, renamed, reordered and added with a [`Schema`](schema::Schema).
+//!
+//! With [`Shards`], accounts are written to a file per shard instead, clients being split
+//! between shards by their id modulo the number of shards. Rows of every shard are ordered by
+//! client, so the same input split into the same number of shards yields byte-identical files.
+//! A `manifest.csv` next to them lists `shard`, `file`, number of `clients` and `sha256` of
+//! contents of every file, so that parts of a distributed run can be verified one by one.
+//!
+//! Writes which fail are retried, see [`Retrying`]: after `writer_retry_backoff_ms`, twice
+//! that, and so on, for as long as `writer_retry_budget_ms` of the engine configuration
+//! allows. Rows keep coming meanwhile, held in the result channel. Rows which still can't be
+//! written are appended to the file given with `--unwritten`, csv of the output columns, and
+//! the writer moves on to the next batch. Without the file, the run fails as soon as retries
+//! are used up.
+
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use crate::{
+    config::{self, Engine},
+    log,
+    processor::{Account, Running},
+    schema,
+    signature::{self, Sha256},
+    snapshots,
+    state::{AccountRecord, AccountSnapshot},
+};
+
+const MANIFEST_FILE: &str = "manifest.csv";
+
+/// Output split between files of `count` shards in `dir`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shards {
+    pub count: u16,
+    pub dir: PathBuf,
+}
+
+impl Shards {
+    /// Shard of the account of `client`.
+    pub fn of(&self, client: u16) -> u16 {
+        client % self.count
+    }
+
+    fn file(&self, shard: u16) -> String {
+        format!("shard-{shard}-of-{}.csv", self.count)
+    }
+}
+
+/// Row of the manifest of [`Shards`].
+#[derive(Debug, Serialize)]
+struct ManifestRecord {
+    shard: u16,
+    file: String,
+    clients: usize,
+    sha256: String,
+}
+
+/// Row of output, plain or extended.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Row {
+    Plain(AccountRecord),
+    Extended(Extended),
+}
+
+impl Row {
+    fn new(account: &Account<Running>, extended: bool) -> Self {
+        if extended {
+            Row::Extended(Extended::from(account))
+        } else {
+            Row::Plain(AccountRecord::from(account))
+        }
+    }
+
+    pub fn client(&self) -> u16 {
+        match self {
+            Row::Plain(record) => record.client,
+            Row::Extended(record) => record.client,
+        }
+    }
+}
+
+/// Fails when the [`Schema`](schema::Schema) set refers to columns which rows of plain or `extended` output
+/// don't have.
+pub fn check_schema(extended: bool) -> Result<(), anyhow::Error> {
+    let schema = schema::schema();
+    if schema.is_default() {
+        return Ok(());
+    }
+    let row = if extended {
+        Row::Extended(Extended::default())
+    } else {
+        Row::Plain(AccountRecord::default())
+    };
+    let (headers, _) = schema::fields(&row)?;
+    schema.check(&headers)
+}
+
+/// Row of extended output.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Extended {
+    pub client: u16,
+    pub available: f32,
+    pub held: f32,
+    pub total: f32,
+    pub locked: bool,
+    pub pending: f32,
+    pub first_activity: Option<u64>,
+    pub last_activity: Option<u64>,
+    pub applied: u64,
+    pub rejected: u64,
+    pub zombie: bool,
+}
+
+impl From<&Account<Running>> for Extended {
+    fn from(account: &Account<Running>) -> Self {
+        let AccountSnapshot {
+            client,
+            available,
+            held,
+            total,
+            locked,
+            applied,
+            rejected,
+            ..
+        } = AccountSnapshot::from(account);
+        let activity = account.activity();
+        Extended {
+            client,
+            available,
+            held,
+            total,
+            locked,
+            pending: account.pending(),
+            first_activity: activity.map(|(first, _)| first),
+            last_activity: activity.map(|(_, last)| last),
+            applied,
+            rejected,
+            zombie: account.zombie(),
+        }
+    }
+}
+
+/// Creates channel from account tasks to writer, as large as `engine` says.
+pub fn channel(engine: Engine) -> (Sender<Account<Running>>, Receiver<Account<Running>>) {
+    mpsc::channel(engine.result_channel_size)
+}
+
+/// Destination of rows of the writer thread.
+pub trait Sink: Send {
+    /// Writes `batch` of rows, in the order account tasks reported them.
+    fn write(&mut self, batch: Vec<Row>) -> Result<(), csv::Error>;
+
+    /// Called once every row was written.
+    fn finish(&mut self) -> Result<(), csv::Error>;
+}
+
+/// Csv on stdout, flushed after every batch.
+pub struct Stdout {
+    out: schema::Writer<std::io::Stdout>,
+}
+
+impl Stdout {
+    pub fn new() -> Self {
+        Stdout {
+            out: schema::Writer::new(
+                csv::WriterBuilder::new()
+                    .buffer_capacity(BUFFER_SIZE)
+                    .from_writer(std::io::stdout()),
+            ),
+        }
+    }
+}
+
+impl Default for Stdout {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Bytes buffered by [`Stdout`] before they are written out, regardless of batches.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+impl Sink for Stdout {
+    fn write(&mut self, batch: Vec<Row>) -> Result<(), csv::Error> {
+        for row in &batch {
+            self.out.serialize(row)?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), csv::Error> {
+        self.out.flush()?;
+        std::io::stdout().flush()?;
+        Ok(())
+    }
+}
+
+/// Every row written to each of a number of sinks, in order.
+pub struct Tee {
+    sinks: Vec<Box<dyn Sink>>,
+}
+
+impl Tee {
+    pub fn new(sinks: Vec<Box<dyn Sink>>) -> Self {
+        Tee { sinks }
+    }
+}
+
+impl Sink for Tee {
+    fn write(&mut self, batch: Vec<Row>) -> Result<(), csv::Error> {
+        for sink in &mut self.sinks {
+            sink.write(batch.clone())?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), csv::Error> {
+        for sink in &mut self.sinks {
+            sink.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// Files of [`Shards`], written along with the manifest once every row was received.
+pub struct Sharded {
+    shards: Shards,
+    /// Rows of every shard, ordered by client.
+    rows: BTreeMap<u16, BTreeMap<u16, Row>>,
+}
+
+impl Sharded {
+    pub fn new(shards: Shards) -> Self {
+        Sharded {
+            shards,
+            rows: BTreeMap::new(),
+        }
+    }
+}
+
+impl Sink for Sharded {
+    fn write(&mut self, batch: Vec<Row>) -> Result<(), csv::Error> {
+        for row in batch {
+            let client = row.client();
+            self.rows
+                .entry(self.shards.of(client))
+                .or_default()
+                .insert(client, row);
+        }
+        Ok(())
+    }
+
+    /// Writes file of every shard along with the manifest, files of shards without accounts
+    /// are empty.
+    fn finish(&mut self) -> Result<(), csv::Error> {
+        let shards = &self.shards;
+        std::fs::create_dir_all(&shards.dir)?;
+        let mut manifest = csv::Writer::from_path(shards.dir.join(MANIFEST_FILE))?;
+        for shard in 0..shards.count {
+            let rows = self.rows.get(&shard);
+            let mut out = schema::Writer::new(csv::Writer::from_writer(Vec::new()));
+            for row in rows.into_iter().flat_map(BTreeMap::values) {
+                out.serialize(row)?;
+            }
+            let contents = out.into_inner()?;
+            let file = shards.file(shard);
+            std::fs::write(shards.dir.join(&file), &contents)?;
+            let mut hasher = Sha256::default();
+            hasher.update(&contents);
+            manifest.serialize(ManifestRecord {
+                shard,
+                file,
+                clients: rows.map_or(0, BTreeMap::len),
+                sha256: signature::hex(&hasher.finish()),
+            })?;
+        }
+        manifest.flush()?;
+        Ok(())
+    }
+}
+
+/// Sink retrying writes of another one with backoff, until the retry budget is used up.
+/// Rows of a batch which failed part way may be written more than once.
+pub struct Retrying {
+    sink: Box<dyn Sink>,
+    budget: Duration,
+    backoff: Duration,
+    /// Rows which could not be written, once retries are used up.
+    unwritten: Option<schema::Writer<BufWriter<File>>>,
+}
+
+impl Retrying {
+    pub fn new(sink: Box<dyn Sink>, budget: Duration, backoff: Duration) -> Self {
+        Retrying {
+            sink,
+            budget,
+            backoff,
+            unwritten: None,
+        }
+    }
+
+    /// Appends rows which could not be written to `path` rather than failing, replacing its
+    /// contents.
+    pub fn unwritten(mut self, path: &Path) -> Result<Self, anyhow::Error> {
+        self.unwritten = Some(schema::Writer::new(csv::Writer::from_writer(
+            BufWriter::new(File::create(path)?),
+        )));
+        Ok(self)
+    }
+
+    /// Calls `attempt` until it succeeds or the budget is used up, returning its last error.
+    fn retry(
+        &mut self,
+        mut attempt: impl FnMut(&mut dyn Sink) -> Result<(), csv::Error>,
+    ) -> Result<(), csv::Error> {
+        let span = log::Span::new("write");
+        let started = Instant::now();
+        let mut backoff = self.backoff;
+        loop {
+            match attempt(self.sink.as_mut()) {
+                Err(err) if started.elapsed() + backoff <= self.budget => {
+                    log::warn!(
+                        span,
+                        "Failed to write accounts, retrying in {backoff:?}: {err}"
+                    );
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Sink for Retrying {
+    fn write(&mut self, batch: Vec<Row>) -> Result<(), csv::Error> {
+        let Err(err) = self.retry(|sink| sink.write(batch.clone())) else {
+            return Ok(());
+        };
+        let Some(out) = self.unwritten.as_mut() else {
+            return Err(err);
+        };
+        log::error!(
+            log::Span::new("write"),
+            accounts = batch.len();
+            "Gave up writing accounts, appending them to unwritten: {err}"
+        );
+        for row in &batch {
+            out.serialize(row)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), csv::Error> {
+        self.retry(|sink| sink.finish())?;
+        if let Some(out) = self.unwritten.as_mut() {
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Sink of a run, files of `shards` if there are any, stdout otherwise. Failed writes are
+/// retried, and rows which still can't be written are appended to `unwritten`, if given.
+pub fn sink(
+    shards: Option<Shards>,
+    unwritten: Option<&Path>,
+) -> Result<Box<dyn Sink>, anyhow::Error> {
+    let sink: Box<dyn Sink> = match shards {
+        Some(shards) => Box::new(Sharded::new(shards)),
+        None => Box::new(Stdout::new()),
+    };
+    let engine = config::engine();
+    let retrying = Retrying::new(
+        sink,
+        Duration::from_millis(engine.writer_retry_budget_ms),
+        Duration::from_millis(engine.writer_retry_backoff_ms),
+    );
+    Ok(Box::new(match unwritten {
+        Some(path) => retrying.unwritten(path)?,
+        None => retrying,
+    }))
+}
+
+/// Spawns writer thread, which exits once every sender of `done_rx` is dropped, writing
+/// accounts to `sink` in batches as `engine` says.
+pub fn start(
+    mut done_rx: Receiver<Account<Running>>,
+    extended: bool,
+    mut sink: Box<dyn Sink>,
+    engine: Engine,
+) -> JoinHandle<Result<(), csv::Error>> {
+    thread::spawn(move || {
+        let span = log::Span::new("write");
+        let interval = Duration::from_millis(engine.writer_flush_interval_ms);
+        let mut batch = Vec::with_capacity(engine.writer_batch_size);
+        let mut flushed = Instant::now();
+
+        let mut written = 0;
+        let mut write = |batch: &mut Vec<Row>| {
+            written += batch.len();
+            let full = std::mem::replace(batch, Vec::with_capacity(engine.writer_batch_size));
+            sink.write(full).map_err(|err| {
+                log::error!(span, "Failed to write accounts: {err}");
+                err
+            })
+        };
+        // Position of the row of every client in the batch, when snapshots may come first.
+        let mut positions = HashMap::new();
+        while let Some(account) = done_rx.blocking_recv() {
+            let row = Row::new(&account, extended);
+            if !snapshots::enabled() {
+                batch.push(row);
+            } else if let Some(&position) = positions.get(&row.client()) {
+                batch[position] = row;
+            } else {
+                positions.insert(row.client(), batch.len());
+                batch.push(row);
+            }
+            if batch.len() >= engine.writer_batch_size || flushed.elapsed() >= interval {
+                write(&mut batch)?;
+                positions.clear();
+                flushed = Instant::now();
+            }
+        }
+        write(&mut batch)?;
+        sink.finish().map_err(|err| {
+            log::error!(span, "Failed to finish writing accounts: {err}");
+            err
+        })?;
+        log::info!(span, accounts = written; "Wrote all accounts");
+
+        Ok(())
+    })
+}
+
+/// Waits for writer thread to finish.
+pub fn join(handle: JoinHandle<Result<(), csv::Error>>) -> Result<(), anyhow::Error> {
+    handle
+        .join()
+        .map_err(|err| anyhow::anyhow!("Writer panic: {err:?}"))??;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Retrying, Row, Sink};
+    use crate::state::AccountRecord;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    /// Sink failing the first `failures` writes, keeping clients of rows written after.
+    struct Flaky {
+        failures: usize,
+        written: Arc<Mutex<Vec<u16>>>,
+    }
+
+    impl Sink for Flaky {
+        fn write(&mut self, batch: Vec<Row>) -> Result<(), csv::Error> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(std::io::Error::other("connection reset").into());
+            }
+            let mut written = self.written.lock().unwrap();
+            written.extend(batch.iter().map(Row::client));
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), csv::Error> {
+            Ok(())
+        }
+    }
+
+    fn batch(client: u16) -> Vec<Row> {
+        vec![Row::Plain(AccountRecord {
+            client,
+            available: 1.0,
+            held: 0.0,
+            total: 1.0,
+            locked: false,
+        })]
+    }
+
+    #[test]
+    fn failed_writes_are_retried_within_budget() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let flaky = |failures| Flaky {
+            failures,
+            written: written.clone(),
+        };
+        let backoff = Duration::from_millis(1);
+
+        let mut sink = Retrying::new(Box::new(flaky(2)), Duration::from_millis(100), backoff);
+        sink.write(batch(1)).unwrap();
+        sink.write(batch(2)).unwrap();
+        assert_eq!(*written.lock().unwrap(), [1, 2]);
+
+        let mut sink = Retrying::new(Box::new(flaky(1)), Duration::ZERO, backoff);
+        assert!(sink.write(batch(3)).is_err());
+        sink.write(batch(4)).unwrap();
+        assert_eq!(*written.lock().unwrap(), [1, 2, 4]);
+    }
+
+    #[test]
+    fn rows_are_set_aside_once_retries_are_used_up() {
+        let dir = std::env::temp_dir().join(format!("trp-unwritten-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("unwritten.csv");
+        let flaky = Flaky {
+            failures: usize::MAX,
+            written: Arc::default(),
+        };
+        let mut sink = Retrying::new(Box::new(flaky), Duration::ZERO, Duration::from_millis(1))
+            .unwritten(&path)
+            .unwrap();
+        sink.write(batch(1)).unwrap();
+        sink.write(batch(2)).unwrap();
+        sink.finish().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "client,available,held,total,locked\n1,1.0,0.0,1.0,false\n2,1.0,0.0,1.0,false\n"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
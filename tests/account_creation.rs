@@ -0,0 +1,125 @@
+//! Runs `trp process` with every `account_creation` policy of the engine configuration, over
+//! clients whose first message is not a deposit, and checks what is rejected for lack of an
+//! account.
+
+mod common;
+
+use std::process::Command;
+
+use common::{normalize, trp, TempDir};
+
+#[test]
+fn accounts_are_created_as_configured() {
+    let dir = TempDir::new("account-creation");
+    let input = dir.write_input(
+        "in.csv",
+        "\
+type,client,tx,amount
+withdrawal,1,1,1.0
+deposit,1,2,2.0
+deposit,2,3,3.0
+dispute,3,9,
+",
+    );
+    let seed = dir.write_input("accounts.csv", "client\n1\n3\n");
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    let config = |policy: &str| {
+        let config = path(&format!("{policy}.toml"));
+        std::fs::write(
+            &config,
+            format!("[engine]\naccount_creation = \"{policy}\"\n"),
+        )
+        .unwrap();
+        config
+    };
+    let run = |policy: &str, seed: &[&str]| {
+        let config = config(policy);
+        let args = [&["process", "--quiet", "--config", &config], seed].concat();
+        normalize(&trp(&[&args[..], &[input.to_str().unwrap()]].concat()))
+    };
+
+    assert_eq!(
+        run("deposit", &[]),
+        "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n2,3.0,0.0,3.0,false\n"
+    );
+    assert_eq!(
+        run("any", &[]),
+        "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n2,3.0,0.0,3.0,false\n3,0.0,0.0,0.0,false\n"
+    );
+    let seed = seed.to_str().unwrap();
+    assert_eq!(
+        run("seeded", &["--seed-accounts", seed]),
+        "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n3,0.0,0.0,0.0,false\n"
+    );
+
+    // Seeded accounts need a seed file, which other policies have no use for.
+    let fails = |policy: &str, seed: &[&str]| {
+        let config = config(policy);
+        !Command::new(env!("CARGO_BIN_EXE_trp"))
+            .args(["process", "--quiet", "--config", &config])
+            .args(seed)
+            .arg(&input)
+            .output()
+            .unwrap()
+            .status
+            .success()
+    };
+    assert!(fails("seeded", &[]));
+    assert!(fails("any", &["--seed-accounts", seed]));
+
+    // Simulated validation opens accounts the same way.
+    let simulated = |policy: &str, seed: &[&str]| {
+        let config = config(policy);
+        let output = Command::new(env!("CARGO_BIN_EXE_trp"))
+            .args(["validate", "--simulate", "--config", &config])
+            .args(seed)
+            .arg(&input)
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| line.starts_with("line "))
+            .map(|line| line.split(' ').take(3).collect::<Vec<_>>().join(" "))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        simulated("deposit", &[]),
+        ["line 2: RT_NOACC", "line 5: VL_NOTX"]
+    );
+    assert_eq!(
+        simulated("any", &[]),
+        ["line 2: PE_INSF", "line 5: VL_NOTX"]
+    );
+    assert_eq!(
+        simulated("seeded", &["--seed-accounts", seed]),
+        ["line 2: PE_INSF", "line 4: RT_NOACC", "line 5: VL_NOTX"]
+    );
+}
+
+#[test]
+fn messages_without_account_are_dead_lettered() {
+    let dir = TempDir::new("no-account");
+    let input = dir.write_input(
+        "in.csv",
+        "type,client,tx,amount\nwithdrawal,1,1,1.0\ndeposit,1,2,2.0\ndispute,3,9,\n",
+    );
+    let dlq = dir.join("dlq.csv");
+
+    trp(&[
+        "process",
+        "--quiet",
+        "--dlq",
+        dlq.to_str().unwrap(),
+        input.to_str().unwrap(),
+    ]);
+    let rejected: Vec<String> = std::fs::read_to_string(&dlq)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|line| line.split(',').take(5).collect::<Vec<_>>().join(","))
+        .collect();
+    assert_eq!(
+        rejected,
+        ["withdrawal,1,1,1.0,RT_NOACC", "dispute,3,9,,RT_NOACC"]
+    );
+}
@@ -0,0 +1,63 @@
+//! Runs `trp process --state --as-of` twice, the second run resuming from the first, then
+//! queries and compares the snapshots.
+
+mod common;
+
+use common::{trp, TempDir};
+
+#[test]
+fn labeled_snapshots_are_queried_and_compared() {
+    let dir = TempDir::new("as-of");
+    let state = dir.join("state");
+    let state = state.to_str().unwrap();
+    let run = |name: &str, rows: &str, label: &str| {
+        let input = dir.join(name);
+        std::fs::write(&input, format!("type,client,tx,amount\n{rows}")).unwrap();
+        trp(&[
+            "process",
+            "--quiet",
+            "--state",
+            state,
+            "--as-of",
+            label,
+            input.to_str().unwrap(),
+        ]);
+    };
+
+    run("june.csv", "deposit,1,1,5.0\n", "2024-06-30");
+    run(
+        "july.csv",
+        "deposit,1,3,2.0\ndeposit,2,2,1.0\n",
+        "2024-07-31",
+    );
+
+    let query = |label| trp(&["query", "--state", state, "--as-of", label, "--client", "1"]);
+    assert_eq!(
+        query("2024-06-30"),
+        "client,available,held,total,locked\n1,5.0,0.0,5.0,false\n"
+    );
+    assert_eq!(
+        query("2024-07-31"),
+        "client,available,held,total,locked\n1,7.0,0.0,7.0,false\n"
+    );
+    assert_eq!(
+        trp(&[
+            "diff",
+            "--quiet",
+            "--state",
+            state,
+            "2024-06-30",
+            "2024-07-31"
+        ]),
+        "\
+client,status,available,held,total
+1,changed,2.0000,0.0000,2.0000
+2,appeared,1.0000,0.0000,1.0000
+"
+    );
+    // Latest state is the July one.
+    assert_eq!(
+        trp(&["diff", "--quiet", "--state", state, "2024-07-31"]),
+        ""
+    );
+}
@@ -0,0 +1,41 @@
+//! Runs `trp bench` over two configurations.
+
+mod common;
+
+use common::{trp, TempDir};
+
+#[test]
+fn configurations_are_compared() {
+    let dir = TempDir::new("bench-test");
+    let input = dir.write_input(
+        "in.csv",
+        "type,client,tx,amount\ndeposit,1,1,3.0\nwithdrawal,1,2,1.0\ndeposit,2,3,2.0\n",
+    );
+    let small = dir.write_input("small.toml", "[engine]\nwriter_batch_size = 1\n");
+    let large = dir.write_input("large.toml", "[engine]\nwriter_batch_size = 4096\n");
+
+    let output = trp(&[
+        "bench",
+        "--quiet",
+        "--config",
+        small.to_str().unwrap(),
+        "--config",
+        large.to_str().unwrap(),
+        "--input",
+        input.to_str().unwrap(),
+        "--runs",
+        "2",
+        "--",
+        "--extended",
+    ]);
+    let mut lines = output.lines();
+    assert_eq!(
+        lines.next(),
+        Some("config,seconds,messages,messages_per_second,peak_rss_kib")
+    );
+    let rows: Vec<Vec<&str>> = lines.map(|line| line.split(',').collect()).collect();
+    assert_eq!(rows.len(), 2, "{output}");
+    assert_eq!(rows[0][0], small.to_str().unwrap());
+    assert_eq!(rows[1][0], large.to_str().unwrap());
+    assert!(rows.iter().all(|row| row[2] == "3"), "{output}");
+}
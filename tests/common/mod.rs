@@ -0,0 +1,94 @@
+//! Helpers shared by integration tests.
+
+// Every test crate compiles its own copy, and uses only some of the helpers.
+#![allow(dead_code)]
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+pub const EXPECTED: &str = ".expected.csv";
+
+/// Directory for files of a test, `trp-<name>-<pid>` under the temporary directory, removed
+/// once the test is over, whether it passed or not.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("trp-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, name: P) -> PathBuf {
+        self.0.join(name)
+    }
+
+    /// Writes `contents` to file `name` of the directory, returns its path.
+    pub fn write_input<C: AsRef<[u8]>>(&self, name: &str, contents: C) -> PathBuf {
+        let path = self.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Inputs in `tests/fixtures`, ordered by name.
+pub fn fixtures() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut inputs: Vec<PathBuf> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            name.ends_with(".csv") && !name.ends_with(EXPECTED)
+        })
+        .collect();
+    inputs.sort();
+    inputs
+}
+
+/// Runs the `trp` binary with `args`, returns its stdout. Panics if it exits with an error.
+pub fn trp(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_trp"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "trp {} failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Header followed by rows ordered by client, without trailing whitespace.
+pub fn normalize(output: &str) -> String {
+    let mut lines = output.lines().map(str::trim_end);
+    let header = lines.next().unwrap_or_default();
+    let mut rows: Vec<&str> = lines.filter(|line| !line.is_empty()).collect();
+    rows.sort_by_key(|row| {
+        row.split(',')
+            .next()
+            .and_then(|client| client.parse::<u16>().ok())
+    });
+
+    let mut normalized = String::from(header);
+    normalized.push('\n');
+    for row in rows {
+        normalized.push_str(row);
+        normalized.push('\n');
+    }
+    normalized
+}
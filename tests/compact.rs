@@ -0,0 +1,70 @@
+//! Runs `trp process --state` over input with timestamps, then `trp compact` over the state.
+
+mod common;
+
+use common::{trp, TempDir};
+
+#[test]
+fn settled_history_is_pruned() {
+    let dir = TempDir::new("compact");
+    let input = dir.write_input(
+        "input.csv",
+        "\
+type,client,tx,amount,timestamp
+deposit,1,1,1.0,0
+deposit,1,2,2.0,86400000
+dispute,1,2,,86400000
+deposit,2,3,3.0,172800000
+dispute,2,3,,172800000
+chargeback,2,3,,172800000
+deposit,1,4,4.0,864000000
+",
+    );
+    let state = dir.join("state");
+    let state = state.to_str().unwrap();
+    trp(&[
+        "process",
+        "--quiet",
+        "--state",
+        state,
+        input.to_str().unwrap(),
+    ]);
+    let history = || std::fs::read_to_string(dir.join("state/transactions.csv")).unwrap();
+    let balances = trp(&["query", "--state", state, "--client", "1"]);
+    let before = history();
+
+    trp(&[
+        "compact",
+        "--quiet",
+        "--state",
+        state,
+        "--keep-days",
+        "5",
+        "--dry-run",
+    ]);
+    assert_eq!(history(), before);
+
+    // The chargeback is pruned, deposits are kept for as long as their age can't be told.
+    trp(&["compact", "--quiet", "--state", state]);
+    assert_eq!(
+        history(),
+        "\
+tx,client,state,amount,timestamp
+1,1,deposited,1.0,0
+2,1,disputed,2.0,86400000
+4,1,deposited,4.0,864000000
+"
+    );
+
+    // Deposits older than 5 days before the latest timestamp go too, unless disputed.
+    trp(&["compact", "--quiet", "--state", state, "--keep-days", "5"]);
+    assert_eq!(
+        history(),
+        "\
+tx,client,state,amount,timestamp
+2,1,disputed,2.0,86400000
+4,1,deposited,4.0,864000000
+"
+    );
+    assert_eq!(trp(&["query", "--state", state, "--client", "1"]), balances);
+}
@@ -0,0 +1,27 @@
+//! Runs `trp convert` from csv to ndjson and back, which must keep every column the reader
+//! accepts.
+
+mod common;
+
+use common::{trp, TempDir};
+
+#[test]
+fn conversion_round_trips() {
+    let dir = TempDir::new("convert");
+    let rows =
+        "type,client,tx,amount,timestamp,effective_date,signature,reference,correlation_id\n\
+        deposit,1,1,1.5,1700000000000,1700086400000,c0ffee,\"BANK, 0001\",req-1\n\
+        withdrawal,1,2,0.5,1700000001000,,,,\n\
+        dispute,1,1,,,,,BANK-0003,req-3\n";
+    let input = dir.write_input("in.csv", rows);
+    let ndjson = dir.join("out.ndjson");
+    let output = dir.join("out.csv");
+
+    trp(&["convert", input.to_str().unwrap(), ndjson.to_str().unwrap()]);
+    trp(&[
+        "convert",
+        ndjson.to_str().unwrap(),
+        output.to_str().unwrap(),
+    ]);
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), rows);
+}
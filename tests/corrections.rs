@@ -0,0 +1,86 @@
+//! Runs `trp apply-corrections` over state persisted by `trp process`, which a later run
+//! resumes from.
+
+mod common;
+
+use std::process::Command;
+
+use common::{normalize, trp, TempDir};
+
+#[test]
+fn corrections_are_applied_with_audit_trail() {
+    let dir = TempDir::new("corrections");
+    let input = dir.write_input(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,3.0\ndispute,2,2,\nchargeback,2,2,\n",
+    );
+    let corrections = dir.write_input(
+        "corrections.csv",
+        "kind,client,tx,amount,reason\nadjustment,1,,-1.5,fee refund\nunlock,2,,,ticket 7\n",
+    );
+    let invalid = dir.write_input(
+        "invalid.csv",
+        "kind,client,tx,amount,reason\nunlock,2,,,ticket 8\nreversal,1,9,,ticket 8\n",
+    );
+    let state = dir.join("state");
+    let state = state.to_str().unwrap();
+    let corrections = corrections.to_str().unwrap();
+    let fails = |args: &[&str]| {
+        !Command::new(env!("CARGO_BIN_EXE_trp"))
+            .args(args)
+            .output()
+            .unwrap()
+            .status
+            .success()
+    };
+    trp(&[
+        "process",
+        "--quiet",
+        "--state",
+        state,
+        input.to_str().unwrap(),
+    ]);
+
+    let diff = "client,status,available,held,total\n\
+                1,changed,-1.5000,0.0000,-1.5000\n\
+                2,unlocked,0.0000,0.0000,0.0000\n";
+    let apply = ["apply-corrections", "--quiet", "--state", state];
+    assert_eq!(
+        trp(&[&apply[..], &["--dry-run", corrections]].concat()),
+        diff
+    );
+    assert_eq!(
+        trp(&["query", "--state", state, "--client", "2"]),
+        "client,available,held,total,locked\n2,0.0,0.0,0.0,true\n"
+    );
+    assert_eq!(trp(&[&apply[..], &[corrections]].concat()), diff);
+    assert_eq!(
+        trp(&["query", "--state", state, "--client", "1"]),
+        "client,available,held,total,locked\n1,3.5,0.0,3.5,false\n"
+    );
+    assert!(fails(&[&apply[..], &[corrections]].concat()));
+    // Nothing of a file applies when any of its rows doesn't.
+    assert!(fails(&[&apply[..], &[invalid.to_str().unwrap()]].concat()));
+
+    let audit = std::fs::read_to_string(dir.join("state/audit.csv")).unwrap();
+    let rows: Vec<&str> = audit.lines().collect();
+    assert_eq!(rows.len(), 3);
+    assert!(rows[1].ends_with(",2,adjustment,1,,-1.5,fee refund"));
+    assert!(rows[2].ends_with(",3,unlock,2,,,ticket 7"));
+
+    // A later run carries on from the corrected state.
+    let later = dir.write_input(
+        "later.csv",
+        "type,client,tx,amount\ndeposit,2,4,1.0\nwithdrawal,1,5,0.5\n",
+    );
+    assert_eq!(
+        normalize(&trp(&[
+            "process",
+            "--quiet",
+            "--state",
+            state,
+            later.to_str().unwrap(),
+        ])),
+        "client,available,held,total,locked\n1,3.0,0.0,3.0,false\n2,1.0,0.0,1.0,false\n"
+    );
+}
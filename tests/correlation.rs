@@ -0,0 +1,53 @@
+//! Runs `trp process` over input with a `correlation_id` column, which is echoed in the event
+//! log, the dead letter queue and log lines of rejected messages.
+
+mod common;
+
+use std::process::Command;
+
+use common::{normalize, TempDir};
+
+#[test]
+fn correlation_ids_are_echoed_with_outcomes() {
+    let dir = TempDir::new("correlation");
+    let input = dir.write_input("in.csv", "type,client,tx,amount,correlation_id\ndeposit,1,1,3.0,req-1\ndeposit,1,1,1.0,req-2\nwithdrawal,1,2,5.0,req-3\nwithdrawal,1,3,1.0,\n");
+    let events = dir.join("events.csv");
+    let dlq = dir.join("dlq.csv");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_trp"))
+        .args([
+            "process",
+            "--two-pass",
+            "--event-log",
+            events.to_str().unwrap(),
+            "--dlq",
+            dlq.to_str().unwrap(),
+            input.to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        normalize(&String::from_utf8_lossy(&output.stdout)),
+        "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("correlation_id=req-2}"), "{stderr}");
+    assert!(stderr.contains(":4 [req-3] reason=PE_INSF"), "{stderr}");
+
+    let column = |path: &std::path::Path| -> Vec<String> {
+        let contents = std::fs::read_to_string(path).unwrap();
+        let mut lines = contents.lines();
+        let header: Vec<_> = lines.next().unwrap().split(',').collect();
+        let index = header
+            .iter()
+            .position(|column| *column == "correlation_id")
+            .unwrap();
+        lines
+            .filter(|line| !line.contains(",account_"))
+            .map(|line| line.split(',').nth(index).unwrap().to_string())
+            .collect()
+    };
+    assert_eq!(column(&events), ["req-1", "req-3", ""]);
+    assert_eq!(column(&dlq), ["req-2"]);
+}
@@ -0,0 +1,41 @@
+//! Runs `trp process` over every `tests/fixtures/<name>.csv` and compares its output with
+//! `tests/fixtures/<name>.expected.csv`.
+//!
+//! Accounts are written in whatever order account tasks finish, so rows are sorted by client
+//! before comparing. Set `UPDATE_SNAPSHOTS=1` to rewrite expected files from current output.
+
+mod common;
+
+use common::{fixtures, normalize, trp, EXPECTED};
+
+#[test]
+fn output_matches_snapshots() {
+    let update = std::env::var_os("UPDATE_SNAPSHOTS").is_some();
+    let inputs = fixtures();
+    assert!(!inputs.is_empty(), "no fixtures found");
+
+    let mut mismatches = Vec::new();
+    for input in inputs {
+        let actual = normalize(&trp(&["process", "--quiet", input.to_str().unwrap()]));
+
+        let expected_path = input.with_extension(&EXPECTED[1..]);
+        if update {
+            std::fs::write(&expected_path, &actual).unwrap();
+            continue;
+        }
+        let expected = std::fs::read_to_string(&expected_path)
+            .unwrap_or_else(|err| panic!("{}: {err}", expected_path.display()));
+        if normalize(&expected) != actual {
+            mismatches.push(format!(
+                "{}\n--- expected\n{expected}--- actual\n{actual}",
+                input.display()
+            ));
+        }
+    }
+
+    assert!(
+        mismatches.is_empty(),
+        "output differs from snapshots, rerun with UPDATE_SNAPSHOTS=1 if expected:\n{}",
+        mismatches.join("\n")
+    );
+}
@@ -0,0 +1,32 @@
+//! Runs `trp process --state` over the same input twice.
+
+mod common;
+
+use std::process::Command;
+
+use common::{trp, TempDir};
+
+#[test]
+fn input_is_applied_to_state_once() {
+    let dir = TempDir::new("inputs");
+    let input = dir.write_input("input.csv", "type,client,tx,amount\ndeposit,1,1,1.0\n");
+    let state = dir.join("state");
+    let args = ["process", "--quiet", "--state", state.to_str().unwrap()];
+
+    trp(&[&args[..], &[input.to_str().unwrap()]].concat());
+    // Same contents under another name are refused too.
+    let copy = dir.join("copy.csv");
+    std::fs::copy(&input, &copy).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_trp"))
+        .args(args)
+        .arg(&copy)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--force"));
+
+    trp(&[&args[..], &["--force", copy.to_str().unwrap()]].concat());
+    let inputs = std::fs::read_to_string(state.join("inputs.csv")).unwrap();
+    assert_eq!(inputs.lines().count(), 3, "{inputs}");
+}
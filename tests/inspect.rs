@@ -0,0 +1,27 @@
+//! Runs `trp process` over a csv from another system with the mapping `trp inspect` wrote.
+
+mod common;
+
+use common::{normalize, trp, TempDir};
+
+#[test]
+fn inspected_mapping_is_processed() {
+    let dir = TempDir::new("inspect");
+    let input = dir.write_input(
+        "export.csv",
+        "Kind;Customer ID;Reference;Value\ndeposit;1;1;3.0\nWithdrawal;1;2;1.0\ndeposit;2;3;2.0\n",
+    );
+    let input = input.to_str().unwrap();
+    let mapping = dir.join("mapping.toml");
+    let mapping = mapping.to_str().unwrap();
+
+    assert_eq!(
+        trp(&["inspect", "--quiet", input]),
+        "[input]\ndelimiter = \";\"\n\n[columns]\ntype = \"Kind\"\nclient = \"Customer ID\"\ntx = \"Reference\"\namount = \"Value\"\n"
+    );
+    trp(&["inspect", "--quiet", "--output", mapping, input]);
+    assert_eq!(
+        normalize(&trp(&["process", "--quiet", "--config", mapping, input])),
+        "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n2,2.0,0.0,2.0,false\n"
+    );
+}
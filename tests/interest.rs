@@ -0,0 +1,50 @@
+//! Runs `trp process --interest-rates` over input spanning the end of a month.
+
+mod common;
+
+use common::{normalize, trp, TempDir};
+
+#[test]
+fn interest_is_posted_at_period_end() {
+    let dir = TempDir::new("interest");
+    // 2026-01-30, then 2026-02-01 and 2026-02-02.
+    let input = dir.write_input(
+        "input.csv",
+        "\
+type,client,tx,amount,timestamp
+deposit,1,1,100.0,1769731200000
+deposit,1,2,1.0,1769904001000
+deposit,2,3,100.0,1769731200000
+deposit,2,4,1.0,1769990400000
+deposit,3,5,100.0,
+",
+    );
+    let run = |extra: &[&str]| {
+        let mut args = vec!["process", "--quiet", "--interest-rates", "0.9125"];
+        args.extend_from_slice(extra);
+        args.push(input.to_str().unwrap());
+        normalize(&trp(&args))
+    };
+
+    // 0.25 a day for the 30th and 31st of January. Interest of February 1st is not posted
+    // before the month ends, and clients without timestamps earn none.
+    assert_eq!(
+        run(&[]),
+        "\
+client,available,held,total,locked
+1,101.5,0.0,101.5,false
+2,101.5,0.0,101.5,false
+3,100.0,0.0,100.0,false
+"
+    );
+    // Interest posted daily earns interest from the next day on.
+    assert_eq!(
+        run(&["--interest-posting", "day"]),
+        "\
+client,available,held,total,locked
+1,101.5006,0.0,101.5006,false
+2,101.7518,0.0,101.7518,false
+3,100.0,0.0,100.0,false
+"
+    );
+}
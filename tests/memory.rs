@@ -0,0 +1,51 @@
+//! Runs `trp process --max-memory` over input of many clients.
+
+mod common;
+
+use common::{trp, TempDir};
+use std::process::Command;
+
+#[test]
+fn run_stops_once_memory_goes_over_limit() {
+    let dir = TempDir::new("memory");
+    let rows: String = (1..=20_000)
+        .map(|tx| format!("deposit,{},{tx},1.0\n", tx % 2000))
+        .collect();
+    let input = dir.write_input("in.csv", format!("type,client,tx,amount\n{rows}"));
+    let metrics = dir.join("metrics.prom");
+    let input = input.to_str().unwrap();
+
+    trp(&[
+        "process",
+        "--quiet",
+        "--max-memory",
+        "1GiB",
+        "--metrics-file",
+        metrics.to_str().unwrap(),
+        input,
+    ]);
+    let rendered = std::fs::read_to_string(&metrics).unwrap();
+    let peak: u64 = rendered
+        .lines()
+        .find_map(|line| line.strip_prefix("trp_memory_peak_bytes "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(peak > 2000 * 1024, "{rendered}");
+    assert!(rendered.contains("trp_memory_bytes 0\n"), "{rendered}");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_trp"))
+        .args(["process", "--max-memory", "64KiB", input])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("went over --max-memory 64.0 KiB"),
+        "{stderr}"
+    );
+    // Accounts applied before reading stopped are still written.
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let written = stdout.lines().skip(1).count();
+    assert!((1..2000).contains(&written), "{stdout}");
+}
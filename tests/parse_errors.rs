@@ -0,0 +1,83 @@
+//! Runs `trp process` over an input with broken rows under every `--on-parse-error` policy.
+
+mod common;
+
+use std::process::Command;
+
+use common::{trp, TempDir};
+
+#[test]
+fn broken_rows_follow_policy() {
+    let dir = TempDir::new("parse-errors");
+    let input = dir.write_input("input.csv", "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,x,2,1.0\nwithdrawal,1,3,\ndeposit,1,4,2.0\n");
+    let input = input.to_str().unwrap();
+    let quarantine = dir.join("quarantine.csv");
+    let fails = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_trp"))
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "trp {} succeeded", args.join(" "));
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+    let process = ["process", "--quiet", "--on-parse-error"];
+    let applied = "client,available,held,total,locked\n1,3.0,0.0,3.0,false\n";
+
+    assert_eq!(trp(&[&process[..], &["skip", input]].concat()), applied);
+    assert_eq!(
+        trp(&[&process[..], &["abort-after:2", input]].concat()),
+        applied
+    );
+    let quarantined = [
+        "quarantine",
+        "--quarantine",
+        quarantine.to_str().unwrap(),
+        input,
+    ];
+    assert_eq!(trp(&[&process[..], &quarantined].concat()), applied);
+    let rows = std::fs::read_to_string(&quarantine).unwrap();
+    let rows: Vec<&str> = rows.lines().collect();
+    assert_eq!(rows[0], "source,line,reason,error,type,client,tx,amount");
+    assert!(rows[1].contains(",3,PR_CSV,"), "{}", rows[1]);
+    assert!(
+        rows[2].ends_with(",4,PR_INVLD,Invalid record,withdrawal,1,3,"),
+        "{}",
+        rows[2]
+    );
+
+    assert!(fails(&[&process[..], &["abort", input]].concat())
+        .contains(":3 with --on-parse-error abort"));
+    assert!(fails(&[&process[..], &["abort-after:1", input]].concat()).contains(":4"));
+}
+
+#[test]
+fn lenient_amounts_are_accepted() {
+    let dir = TempDir::new("lenient");
+    let input = dir.write_input(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,1,1,\"$1,234.50\"\nwithdrawal,1,2,34.50 €\n",
+    );
+    let input = input.to_str().unwrap();
+
+    assert_eq!(trp(&["process", "--quiet", input]), "");
+    assert_eq!(
+        trp(&["process", "--quiet", "--lenient-amounts", input]),
+        "client,available,held,total,locked\n1,1200.0,0.0,1200.0,false\n"
+    );
+    trp(&["validate", "--lenient-amounts", input]);
+}
+
+#[test]
+fn amounts_in_minor_units_are_accepted() {
+    let dir = TempDir::new("minor-units");
+    let input = dir.write_input(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,1,1,123450\nwithdrawal,1,2,3450\ndeposit,1,3,1.5\n",
+    );
+    let input = input.to_str().unwrap();
+
+    assert_eq!(
+        trp(&["process", "--quiet", "--amount-unit", "minor", input]),
+        "client,available,held,total,locked\n1,1200.0,0.0,1200.0,false\n"
+    );
+}
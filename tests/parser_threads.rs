@@ -0,0 +1,63 @@
+//! Runs `trp process` with csv input deserialized on several threads, see `parser_threads`.
+
+mod common;
+
+use common::{fixtures, normalize, TempDir, EXPECTED};
+use std::process::Command;
+
+/// Runs `trp` with `args` and input deserialized on `threads` threads, returns its stdout.
+fn trp(threads: usize, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_trp"))
+        .args(args)
+        .env("TRP_ENGINE_PARSER_THREADS", threads.to_string())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn output_matches_snapshots() {
+    for input in fixtures() {
+        let actual = normalize(&trp(3, &["process", "--quiet", input.to_str().unwrap()]));
+        let expected = std::fs::read_to_string(input.with_extension(&EXPECTED[1..])).unwrap();
+        assert_eq!(actual, normalize(&expected), "{}", input.display());
+    }
+}
+
+#[test]
+fn messages_keep_input_order() {
+    let dir = TempDir::new("parser-threads");
+    let rows: String = (1..=5000)
+        .map(|tx| match tx % 7 {
+            0 => format!("withdrawal,{},{tx},0.5\n", tx % 13),
+            3 => "deposit,1,broken,1.0\n".to_string(),
+            _ => format!("deposit,{},{tx},1.0\n", tx % 13),
+        })
+        .collect();
+    let input = dir.write_input("in.csv", format!("type,client,tx,amount\n{rows}"));
+
+    let run = |threads| {
+        let log = dir.join(format!("events-{threads}.csv"));
+        let output = trp(
+            threads,
+            &[
+                "process",
+                "--quiet",
+                "--event-log",
+                log.to_str().unwrap(),
+                input.to_str().unwrap(),
+            ],
+        );
+        // Messages in the order they were read, without wall clock time or account lifecycle.
+        let events: Vec<String> = std::fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .filter(|line| !line.contains(",account_"))
+            .map(|line| line.splitn(3, ',').nth(2).unwrap_or_default().to_string())
+            .collect();
+        (normalize(&output), events)
+    };
+    let (output, events) = run(1);
+    assert_eq!(run(4), (output, events));
+}
@@ -0,0 +1,104 @@
+//! Assembles a run with `trp::pipeline`, over several sources and sinks.
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use common::TempDir;
+use trp::{
+    config::Engine,
+    format::CsvSource,
+    pipeline::{Pipeline, Row, Sink},
+};
+
+/// Sink keeping balances of the rows it's given.
+#[derive(Clone, Default)]
+struct Collect(Arc<Mutex<Vec<(u16, String)>>>);
+
+impl Sink for Collect {
+    fn write(&mut self, batch: Vec<Row>) -> Result<(), csv::Error> {
+        let mut rows = self.0.lock().unwrap();
+        for row in batch {
+            if let Row::Plain(record) = row {
+                rows.push((record.client, record.total.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), csv::Error> {
+        Ok(())
+    }
+}
+
+#[test]
+fn pipeline_reads_every_source_into_every_sink() {
+    let dir = TempDir::new("pipeline");
+    let input = dir.write_input(
+        "in.csv",
+        "type,client,tx,amount\ndeposit,1,1,2.0\ndeposit,2,2,3.0\n",
+    );
+    let late = CsvSource::new(&b"type,client,tx,amount\ndeposit,3,3,0.5\n"[..]);
+    let (first, second) = (Collect::default(), Collect::default());
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let summary = rt
+        .block_on(
+            Pipeline::builder()
+                .input(&input)
+                .source("late", late)
+                .sink(first.clone())
+                .sink(second.clone())
+                .run(),
+        )
+        .unwrap();
+
+    for sink in [first, second] {
+        let mut rows = sink.0.lock().unwrap().clone();
+        rows.sort();
+        assert_eq!(
+            rows,
+            [
+                (1, "2".to_string()),
+                (2, "3".to_string()),
+                (3, "0.5".to_string())
+            ]
+        );
+    }
+    assert_eq!(summary.rejects(), 0);
+}
+
+#[test]
+fn runs_have_engines_and_summaries_of_their_own() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let run = |engine: Engine| {
+        // Withdrawal without funds is rejected by every run.
+        let source =
+            CsvSource::new(&b"type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,5.0\n"[..]);
+        let sink = Collect::default();
+        let summary = rt
+            .block_on(
+                Pipeline::builder()
+                    .source("in", source)
+                    .engine(engine)
+                    .sink(sink.clone())
+                    .run(),
+            )
+            .unwrap();
+        let rows = sink.0.lock().unwrap().clone();
+        (rows, summary.rejects())
+    };
+
+    let expected = (vec![(1, "1".to_string())], 1);
+    assert_eq!(run(Engine::default()), expected);
+    assert_eq!(
+        run(Engine {
+            parser_channel_size: 1,
+            account_channel_size: 1,
+            result_channel_size: 1,
+            writer_batch_size: 1,
+            ..Engine::default()
+        }),
+        expected
+    );
+}
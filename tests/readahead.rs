@@ -0,0 +1,27 @@
+//! Runs `trp process` over input larger than a chunk of the `readahead` input backend.
+
+mod common;
+
+use common::{normalize, trp, TempDir};
+use std::process::Command;
+
+#[test]
+fn readahead_backend_reads_whole_input() {
+    let dir = TempDir::new("readahead");
+    let rows: String = (1..=250_000)
+        .map(|tx| format!("deposit,{},{tx},1.5\n", tx % 100))
+        .collect();
+    let input = dir.write_input("in.csv", format!("type,client,tx,amount\n{rows}"));
+    let input = input.to_str().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_trp"))
+        .args(["process", "--quiet", input])
+        .env("TRP_ENGINE_INPUT_BACKEND", "readahead")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        normalize(&String::from_utf8(output.stdout).unwrap()),
+        normalize(&trp(&["process", "--quiet", input]))
+    );
+}
@@ -0,0 +1,51 @@
+//! Runs `trp process --log-redact` and checks ids are kept out of the dead letter queue, but
+//! not out of output.
+
+mod common;
+
+use common::{normalize, trp, TempDir};
+
+#[test]
+fn ids_are_redacted_outside_of_output() {
+    let dir = TempDir::new("redaction");
+    let review = dir.join("review.csv");
+    let watchlist = dir.write_input("watchlist.csv", "client,action\n1234,review\n");
+    let input = dir.write_input(
+        "input.csv",
+        "\
+type,client,tx,amount
+deposit,1,1,1.0
+deposit,1234,5678,2.0
+",
+    );
+
+    let output = trp(&[
+        "--log-redact",
+        "truncate",
+        "process",
+        "--quiet",
+        "--watchlist",
+        watchlist.to_str().unwrap(),
+        "--review",
+        review.to_str().unwrap(),
+        input.to_str().unwrap(),
+    ]);
+
+    assert_eq!(
+        normalize(&output),
+        "\
+client,available,held,total,locked
+1,1.0,0.0,1.0,false
+"
+    );
+    assert_eq!(
+        std::fs::read_to_string(&review).unwrap(),
+        format!(
+            "\
+type,client,tx,amount,reason,source,line,correlation_id,reference
+deposit,*34,*78,2.0,RT_REVIEW,{input},3,,
+",
+            input = input.display()
+        )
+    );
+}
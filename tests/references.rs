@@ -0,0 +1,40 @@
+//! Runs `trp process` over input with a `reference` column, which ends up in the event log
+//! and the dead letter queue, but not in balances.
+
+mod common;
+
+use common::{normalize, trp, TempDir};
+
+#[test]
+fn references_are_passed_through() {
+    let dir = TempDir::new("references");
+    let input = dir.write_input("in.csv", "type,client,tx,amount,reference\ndeposit,1,1,3.0,BANK-0001\ndeposit,1,1,1.0,BANK-0002\nwithdrawal,1,2,1.0,\n");
+    let input = input.to_str().unwrap();
+    let events = dir.join("events.csv");
+    let dlq = dir.join("dlq.csv");
+
+    assert_eq!(
+        normalize(&trp(&[
+            "process",
+            "--quiet",
+            "--two-pass",
+            "--event-log",
+            events.to_str().unwrap(),
+            "--dlq",
+            dlq.to_str().unwrap(),
+            input
+        ])),
+        "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n"
+    );
+    let references = |path: &std::path::Path| -> Vec<String> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .filter(|line| !line.contains(",account_"))
+            .map(|line| line.rsplit(',').next().unwrap().to_string())
+            .collect()
+    };
+    assert_eq!(references(&events), ["BANK-0001", ""]);
+    assert_eq!(references(&dlq), ["BANK-0002"]);
+}
@@ -0,0 +1,57 @@
+//! Runs `trp process --min-balance` with tiers of clients.
+
+mod common;
+
+use common::{normalize, trp, TempDir};
+
+#[test]
+fn withdrawals_keep_minimum_balance_of_tier() {
+    let dir = TempDir::new("reserve");
+    let alerts = dir.join("alerts.csv");
+    let input = dir.write_input(
+        "input.csv",
+        "\
+type,client,tx,amount
+deposit,1,1,100.0
+withdrawal,1,2,95.0
+withdrawal,1,3,90.0
+deposit,2,4,100.0
+withdrawal,2,5,60.0
+withdrawal,2,6,50.0
+deposit,3,7,20.0
+deposit,3,8,5.0
+dispute,3,7,
+",
+    );
+    let tiers = dir.write_input("tiers.csv", "client,tier\n2,gold\n");
+    let run = |extra: &[&str]| {
+        let mut args = vec![
+            "process",
+            "--quiet",
+            "--min-balance",
+            "10,gold:50",
+            "--tiers",
+            tiers.to_str().unwrap(),
+        ];
+        args.extend_from_slice(extra);
+        args.push(input.to_str().unwrap());
+        normalize(&trp(&args))
+    };
+
+    // Client 2 is gold, client 3 dips into reserve with a dispute.
+    let expected = "\
+client,available,held,total,locked
+1,10.0,0.0,10.0,false
+2,50.0,0.0,50.0,false
+3,5.0,20.0,25.0,false
+";
+    assert_eq!(run(&["--alerts", alerts.to_str().unwrap()]), expected);
+    assert_eq!(run(&["--reference"]), expected);
+    assert_eq!(
+        std::fs::read_to_string(&alerts).unwrap(),
+        "\
+client,tx,rule,withdrawals,withdrawn
+3,7,reserve,,
+"
+    );
+}
@@ -0,0 +1,113 @@
+//! Runs `trp process --state` twice, the second run resuming accounts of the first.
+
+mod common;
+
+use common::{normalize, trp, TempDir};
+
+#[test]
+fn second_run_resumes_state_of_the_first() {
+    let dir = TempDir::new("resume");
+    let state = dir.join("state");
+    let state = state.to_str().unwrap();
+    let run = |name: &str, rows: &str| {
+        let input = dir.join(name);
+        std::fs::write(&input, format!("type,client,tx,amount\n{rows}")).unwrap();
+        normalize(&trp(&[
+            "process",
+            "--quiet",
+            "--state",
+            state,
+            input.to_str().unwrap(),
+        ]))
+    };
+
+    assert_eq!(
+        run("first.csv", "deposit,1,1,5.0\ndeposit,2,2,3.0\n"),
+        "client,available,held,total,locked\n1,5.0,0.0,5.0,false\n2,3.0,0.0,3.0,false\n"
+    );
+    // Client 1 withdraws from its earlier deposit, client 2 is left alone.
+    assert_eq!(
+        run("second.csv", "withdrawal,1,3,1.5\ndeposit,3,4,1.0\n"),
+        "client,available,held,total,locked\n1,3.5,0.0,3.5,false\n3,1.0,0.0,1.0,false\n"
+    );
+
+    let query = |args: &[&str]| trp(&[&["query", "--state", state], args].concat());
+    assert_eq!(
+        query(&["--client", "1"]),
+        "client,available,held,total,locked\n1,3.5,0.0,3.5,false\n"
+    );
+    assert_eq!(
+        query(&["--client", "2"]),
+        "client,available,held,total,locked\n2,3.0,0.0,3.0,false\n"
+    );
+    assert_eq!(
+        query(&["--client", "2", "--history"]),
+        "tx,client,state,amount,timestamp\n2,2,deposited,3.0,\n"
+    );
+    assert_eq!(
+        query(&["--client", "1", "--history"]),
+        "tx,client,state,amount,timestamp\n1,1,deposited,5.0,\n"
+    );
+}
+
+#[test]
+fn disputes_reach_transactions_of_earlier_runs() {
+    let dir = TempDir::new("resume-disputes");
+    let state = dir.join("state");
+    let state = state.to_str().unwrap();
+    let run = |name: &str, rows: &str| {
+        let input = dir.join(name);
+        std::fs::write(&input, format!("type,client,tx,amount\n{rows}")).unwrap();
+        normalize(&trp(&[
+            "process",
+            "--quiet",
+            "--two-pass",
+            "--state",
+            state,
+            input.to_str().unwrap(),
+        ]))
+    };
+
+    run("deposits.csv", "deposit,1,1,5.0\ndeposit,1,2,1.0\n");
+    // Neither pass finds tx 1 in the input, the engine finds it in state. Client 2 can't
+    // dispute it, since state tells it belongs to client 1.
+    assert_eq!(
+        run("disputes.csv", "dispute,1,1,\ndispute,2,1,\n"),
+        "client,available,held,total,locked\n1,1.0,5.0,6.0,false\n"
+    );
+    assert_eq!(
+        run("chargebacks.csv", "chargeback,1,1,\n"),
+        "client,available,held,total,locked\n1,1.0,0.0,1.0,true\n"
+    );
+}
+
+#[test]
+fn resumed_accounts_are_not_logged_as_created() {
+    let dir = TempDir::new("resume-created");
+    let state = dir.join("state");
+    let events = dir.join("events.csv");
+    let run = |name: &str, rows: &str| {
+        let input = dir.write_input(name, format!("type,client,tx,amount\n{rows}"));
+        trp(&[
+            "process",
+            "--quiet",
+            "--state",
+            state.to_str().unwrap(),
+            "--event-log",
+            events.to_str().unwrap(),
+            input.to_str().unwrap(),
+        ]);
+        let log = std::fs::read_to_string(&events).unwrap();
+        log.lines()
+            .filter(|line| line.contains(",account_created,"))
+            .map(|line| line.split(',').nth(3).unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(run("first.csv", "deposit,1,1,5.0\n"), ["1"]);
+    // Client 1 comes back from state, client 2 is new.
+    assert_eq!(
+        run("second.csv", "deposit,1,2,1.0\ndeposit,2,3,1.0\n"),
+        ["2"]
+    );
+}
@@ -0,0 +1,92 @@
+//! Runs `trp process` with `retention` of the engine configuration over disputes of history
+//! old enough to be pruned.
+
+mod common;
+
+use common::{normalize, trp, TempDir};
+
+const DAY: u64 = 24 * 60 * 60 * 1000;
+
+#[test]
+fn disputes_of_pruned_history_are_rejected() {
+    let dir = TempDir::new("retention");
+    let input = dir.write_input(
+        "in.csv",
+        format!(
+            "\
+type,client,tx,amount,timestamp
+deposit,1,1,1.0,0
+deposit,1,2,2.0,{day}
+dispute,1,2,,{day}
+deposit,1,3,3.0,{five}
+dispute,1,1,,{five}
+dispute,1,3,,{five}
+",
+            day = DAY,
+            five = 5 * DAY
+        ),
+    );
+
+    let run = |retention: &str| {
+        let run = dir.join(retention.replace(':', "-"));
+        std::fs::create_dir_all(&run).unwrap();
+        let config = run.join("trp.toml");
+        std::fs::write(&config, format!("[engine]\nretention = \"{retention}\"\n")).unwrap();
+        let path = |name: &str| run.join(name).to_str().unwrap().to_string();
+        let output = normalize(&trp(&[
+            "process",
+            "--quiet",
+            "--config",
+            &path("trp.toml"),
+            "--state",
+            &path("state"),
+            "--dlq",
+            &path("dlq.csv"),
+            "--event-log",
+            &path("events.csv"),
+            input.to_str().unwrap(),
+        ]));
+        let history = std::fs::read_to_string(run.join("state/transactions.csv")).unwrap();
+        let rejected: Vec<String> = std::fs::read_to_string(run.join("dlq.csv"))
+            .unwrap_or_default()
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').take(5).collect::<Vec<_>>().join(","))
+            .collect();
+        let audited = std::fs::read_to_string(run.join("events.csv"))
+            .unwrap()
+            .lines()
+            .filter(|line| line.contains(",dispute_of_pruned,"))
+            .count();
+        (output, history, rejected, audited)
+    };
+
+    let (output, history, rejected, audited) = run("all");
+    assert_eq!(
+        output,
+        "client,available,held,total,locked\n1,0.0,6.0,6.0,false\n"
+    );
+    assert_eq!(history.lines().count(), 4);
+    assert!(rejected.is_empty());
+    assert_eq!(audited, 0);
+
+    // Deposit 1 is pruned as deposit 3 comes, funds it brought stay.
+    let (output, history, rejected, audited) = run("undisputed:2");
+    assert_eq!(
+        output,
+        "client,available,held,total,locked\n1,1.0,5.0,6.0,false\n"
+    );
+    assert_eq!(
+        history,
+        format!(
+            "\
+tx,client,state,amount,timestamp
+2,1,disputed,2.0,{DAY}
+3,1,disputed,3.0,{}
+",
+            5 * DAY
+        )
+    );
+    assert_eq!(rejected, ["dispute,1,1,,PE_PRUNED"]);
+    assert_eq!(audited, 1);
+}
@@ -0,0 +1,28 @@
+//! Runs `trp process --sample` over input of many clients.
+
+mod common;
+
+use common::{normalize, trp, TempDir};
+
+#[test]
+fn sample_of_clients_is_processed() {
+    let dir = TempDir::new("sample");
+    let rows: String = (1..=400)
+        .map(|tx| format!("deposit,{},{tx},1.0\n", tx % 40))
+        .collect();
+    let input = dir.write_input("in.csv", format!("type,client,tx,amount\n{rows}"));
+    let input = input.to_str().unwrap();
+
+    let full = normalize(&trp(&["process", "--quiet", input]));
+    let sample = |seed| {
+        normalize(&trp(&[
+            "process", "--quiet", "--sample", "25%", "--seed", seed, input,
+        ]))
+    };
+    let first = sample("1");
+    assert_eq!(first, sample("1"));
+    let rows: Vec<&str> = first.lines().skip(1).collect();
+    assert!((3..=20).contains(&rows.len()), "{first}");
+    // Clients of the sample are processed in full.
+    assert!(rows.iter().all(|row| full.lines().any(|line| line == *row)));
+}
@@ -0,0 +1,59 @@
+//! Runs `trp process --savepoint` over a corrections file resuming state of an earlier run,
+//! then `trp rollback`.
+
+mod common;
+
+use std::process::Command;
+
+use common::{trp, TempDir};
+
+#[test]
+fn state_is_rolled_back_to_savepoint() {
+    let dir = TempDir::new("savepoint");
+    let input = dir.write_input("input.csv", "type,client,tx,amount\ndeposit,1,1,1.0\n");
+    let corrections = dir.write_input(
+        "corrections.csv",
+        "type,client,tx,amount\ndeposit,1,3,0.5\ndeposit,2,2,2.0\n",
+    );
+    let state = dir.join("state");
+    let state = state.to_str().unwrap();
+    let process = |extra: &[&str]| {
+        trp(&[&["process", "--quiet", "--state", state][..], extra].concat());
+    };
+    let fails = |args: &[&str]| {
+        !Command::new(env!("CARGO_BIN_EXE_trp"))
+            .args(args)
+            .output()
+            .unwrap()
+            .status
+            .success()
+    };
+    let before = "client,available,held,total,locked\n1,1.0,0.0,1.0,false\n";
+
+    process(&[input.to_str().unwrap()]);
+    let corrected = ["--savepoint", "fix", corrections.to_str().unwrap()];
+    process(&corrected);
+    // Client 1 carries on from the first run.
+    assert_eq!(
+        trp(&["query", "--state", state, "--client", "1"]),
+        "client,available,held,total,locked\n1,1.5,0.0,1.5,false\n"
+    );
+    assert_eq!(
+        trp(&["query", "--state", state, "--client", "2"]),
+        "client,available,held,total,locked\n2,2.0,0.0,2.0,false\n"
+    );
+    assert!(fails(
+        &[&["process", "--quiet", "--state", state][..], &corrected].concat()
+    ));
+
+    trp(&["rollback", "--state", state, "fix"]);
+    assert_eq!(trp(&["query", "--state", state, "--client", "1"]), before);
+    assert!(fails(&["query", "--state", state, "--client", "2"]));
+    // Savepoint is gone, and corrections were forgotten along with the rest of the run.
+    assert!(fails(&["rollback", "--state", state, "fix"]));
+    process(&corrected);
+    assert_eq!(
+        trp(&["query", "--state", state, "--client", "1"]),
+        "client,available,held,total,locked\n1,1.5,0.0,1.5,false\n"
+    );
+}
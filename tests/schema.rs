@@ -0,0 +1,34 @@
+//! Runs `trp process` with columns of output set in the configuration.
+
+mod common;
+
+use common::{normalize, trp, TempDir};
+
+#[test]
+fn output_columns_are_configured() {
+    let dir = TempDir::new("schema");
+    let input = dir.write_input(
+        "in.csv",
+        "type,client,tx,amount\ndeposit,1,1,3.0\nwithdrawal,1,2,1.0\ndeposit,2,3,2.0\n",
+    );
+    let input = input.to_str().unwrap();
+    let config = dir.write_input("trp.toml", "[output]\ncolumns = \"client,total,available,currency\"\n\n[output.names]\nclient = \"client_id\"\n\n[output.values]\ncurrency = \"EUR\"\n");
+    let config = config.to_str().unwrap();
+
+    let expected = "client_id,total,available,currency\n1,2.0,2.0,EUR\n2,2.0,2.0,EUR\n";
+    assert_eq!(
+        normalize(&trp(&["process", "--quiet", "--config", config, input])),
+        expected
+    );
+    assert_eq!(
+        normalize(&trp(&[
+            "process",
+            "--quiet",
+            "--reference",
+            "--config",
+            config,
+            input
+        ])),
+        expected
+    );
+}
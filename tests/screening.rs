@@ -0,0 +1,59 @@
+//! Runs `trp process --watchlist` over clients which are blocked or flagged for review.
+
+mod common;
+
+use common::{normalize, trp, TempDir};
+
+#[test]
+fn watchlisted_clients_are_not_processed() {
+    let dir = TempDir::new("screening");
+    let review = dir.join("review.csv");
+    let watchlist = dir.write_input("watchlist.csv", "client,action\n2,block\n3,review\n");
+    let input = dir.write_input(
+        "input.csv",
+        "\
+type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,2.0
+deposit,3,3,3.0
+withdrawal,3,4,1.0
+",
+    );
+
+    let output = trp(&[
+        "process",
+        "--quiet",
+        "--watchlist",
+        watchlist.to_str().unwrap(),
+        "--review",
+        review.to_str().unwrap(),
+        input.to_str().unwrap(),
+    ]);
+
+    assert_eq!(
+        normalize(&output),
+        "\
+client,available,held,total,locked
+1,1.0,0.0,1.0,false
+"
+    );
+    // Held messages can be processed as they are once cleared.
+    assert_eq!(
+        std::fs::read_to_string(&review).unwrap(),
+        format!(
+            "\
+type,client,tx,amount,reason,source,line,correlation_id,reference
+deposit,3,3,3.0,RT_REVIEW,{input},4,,
+withdrawal,3,4,1.0,RT_REVIEW,{input},5,,
+",
+            input = input.display()
+        )
+    );
+    assert_eq!(
+        normalize(&trp(&["process", "--quiet", review.to_str().unwrap()])),
+        "\
+client,available,held,total,locked
+3,2.0,0.0,2.0,false
+"
+    );
+}
@@ -0,0 +1,44 @@
+//! Runs `trp process --chaos` twice with the same `--seed`.
+
+mod common;
+
+use common::{normalize, trp, TempDir};
+
+#[test]
+fn seed_reproduces_faults() {
+    let dir = TempDir::new("seed");
+    let rows: String = (1..=200)
+        .map(|tx| format!("deposit,{},{tx},1.0\n", tx % 10))
+        .collect();
+    let input = dir.write_input("in.csv", format!("type,client,tx,amount\n{rows}"));
+    let input = input.to_str().unwrap();
+
+    let run = |name: &str, seed: &str| {
+        let state = dir.join(name);
+        let output = trp(&[
+            "process",
+            "--quiet",
+            "--chaos-drop-rate",
+            "0.2",
+            "--chaos-kill-rate",
+            "0.2",
+            "--chaos-max-delay",
+            "0",
+            "--seed",
+            seed,
+            "--state",
+            state.to_str().unwrap(),
+            input,
+        ]);
+        let inputs = std::fs::read_to_string(state.join("inputs.csv")).unwrap();
+        (normalize(&output), inputs)
+    };
+
+    let (first, inputs) = run("first", "42");
+    let (second, _) = run("second", "42");
+    assert_eq!(first, second);
+    assert!(inputs.starts_with("hash,path,applied,seed\n"), "{inputs}");
+    assert!(inputs.trim_end().ends_with(",42"), "{inputs}");
+    let (other, _) = run("other", "43");
+    assert_ne!(first, other);
+}
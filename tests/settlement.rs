@@ -0,0 +1,49 @@
+//! Runs `trp process --pending-withdrawals` over input with `settle` messages.
+
+mod common;
+
+use common::{normalize, trp, TempDir};
+
+#[test]
+fn withdrawals_are_held_until_settled() {
+    let dir = TempDir::new("settlement");
+    let input = dir.write_input(
+        "input.csv",
+        "\
+type,client,tx,amount,timestamp
+deposit,1,1,10.0,1000
+withdrawal,1,2,4.0,1000
+withdrawal,1,3,3.0,2000
+settle,1,2,,2500
+withdrawal,1,4,5.0,3000
+deposit,2,5,5.0,1000
+withdrawal,2,6,1.0,
+deposit,2,7,1.0,9000
+",
+    );
+    let run = |extra: &[&str]| {
+        let mut args = vec!["process", "--quiet"];
+        args.extend_from_slice(extra);
+        args.push(input.to_str().unwrap());
+        normalize(&trp(&args))
+    };
+
+    // Client 1 can't withdraw funds held by pending withdrawals, tx 3 settles on its own once
+    // the client sees 3000. Withdrawals without a timestamp wait for a settle message.
+    let expected = "\
+client,available,held,total,locked
+1,3.0,0.0,3.0,false
+2,5.0,1.0,6.0,false
+";
+    assert_eq!(run(&["--settle-after", "1000"]), expected);
+    assert_eq!(run(&["--settle-after", "1000", "--reference"]), expected);
+    // Settle messages take no effect otherwise.
+    assert_eq!(
+        run(&[]),
+        "\
+client,available,held,total,locked
+1,3.0,0.0,3.0,false
+2,5.0,0.0,5.0,false
+"
+    );
+}
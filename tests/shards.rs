@@ -0,0 +1,54 @@
+//! Runs `trp process --shards` twice over the same input, and merges the shards back.
+
+mod common;
+
+use common::{normalize, trp, TempDir};
+
+#[test]
+fn shards_are_deterministic() {
+    let dir = TempDir::new("shards");
+    let generated = trp(&[
+        "generate",
+        "--rows",
+        "2000",
+        "--clients",
+        "50",
+        "--seed",
+        "7",
+        "--consistent",
+    ]);
+    let input = dir.write_input("input.csv", generated);
+    let input = input.to_str().unwrap();
+    let run = |name: &str| {
+        let out = dir.join(name);
+        let printed = trp(&[
+            "process",
+            "--quiet",
+            "--shards",
+            "4",
+            "--shard-dir",
+            out.to_str().unwrap(),
+            input,
+        ]);
+        assert_eq!(printed, "");
+        out
+    };
+
+    let (first, second) = (run("first"), run("second"));
+    let read = |dir: &std::path::Path, file: &str| std::fs::read(dir.join(file)).unwrap();
+    assert_eq!(read(&first, "manifest.csv"), read(&second, "manifest.csv"));
+    let shards: Vec<String> = (0..4)
+        .map(|shard| format!("shard-{shard}-of-4.csv"))
+        .collect();
+    for shard in &shards {
+        assert_eq!(read(&first, shard), read(&second, shard), "{shard} differs");
+    }
+
+    let paths: Vec<String> = shards
+        .iter()
+        .map(|shard| first.join(shard).to_str().unwrap().to_string())
+        .collect();
+    let mut merge = vec!["merge"];
+    merge.extend(paths.iter().map(String::as_str));
+    assert_eq!(trp(&merge), normalize(&trp(&["process", "--quiet", input])));
+}
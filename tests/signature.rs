@@ -0,0 +1,57 @@
+//! Runs `trp process --signature-key-file` over signed input, with signatures computed
+//! independently of `trp`.
+
+mod common;
+
+use common::{normalize, trp, TempDir};
+
+#[test]
+fn tampered_and_unsigned_records_are_dead_lettered() {
+    let dir = TempDir::new("signature");
+    let dlq = dir.join("dlq.csv");
+    let key = dir.write_input("key", "secret\n");
+    // Withdrawal was signed with amount of 2, the deposit after it with timestamp of 1000,
+    // the last deposit is not signed at all.
+    let input = dir.write_input(
+        "input.csv",
+        "\
+type,client,tx,amount,timestamp,signature
+deposit,1,1,10.0,,d2c748c1b24640ae41f37656437e9868d9c2b60f836380e0c70a958397bffb0c
+deposit,2,2,5.5,,BDD4635E6C70175F800FBF4C9D5C9CBCAAC24E45D03BD33899563CC7D29FEA7B
+withdrawal,1,3,20,,9b509f7f2b506a47a9e9f507f98498389b5ce8e30d01aeb89da42a5576c8a599
+deposit,1,5,1.0,2000,46f22a299259e825d961f47d850c0bba3416ddac4c940c4ff393d50651b585f0
+deposit,2,4,1,,
+",
+    );
+
+    let output = trp(&[
+        "process",
+        "--quiet",
+        "--signature-key-file",
+        key.to_str().unwrap(),
+        "--dlq",
+        dlq.to_str().unwrap(),
+        input.to_str().unwrap(),
+    ]);
+
+    assert_eq!(
+        normalize(&output),
+        "\
+client,available,held,total,locked
+1,10.0,0.0,10.0,false
+2,5.5,0.0,5.5,false
+"
+    );
+    assert_eq!(
+        std::fs::read_to_string(&dlq).unwrap(),
+        format!(
+            "\
+type,client,tx,amount,reason,source,line,correlation_id,reference
+withdrawal,1,3,20.0,PR_SIG,{input},4,,
+deposit,1,5,1.0,PR_SIG,{input},5,,
+deposit,2,4,1.0,PR_SIG,{input},6,,
+",
+            input = input.display()
+        )
+    );
+}
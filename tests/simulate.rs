@@ -0,0 +1,56 @@
+//! Runs `trp process --state`, then `trp query --simulate` of transactions against the state,
+//! which must be left as it was.
+
+mod common;
+
+use common::{trp, TempDir};
+
+#[test]
+fn simulated_transactions_change_nothing() {
+    let dir = TempDir::new("simulate");
+    let input = dir.write_input(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2,2.0\ndispute,1,2,\ndeposit,3,4,1.0\n",
+    );
+    let state = dir.join("state");
+    let state = state.to_str().unwrap();
+    trp(&[
+        "process",
+        "--quiet",
+        "--state",
+        state,
+        input.to_str().unwrap(),
+    ]);
+    let simulate = |row| trp(&["query", "--state", state, "--simulate", row]);
+    let header = "client,accepted,code,available,held,total,locked\n";
+
+    assert_eq!(
+        simulate("withdrawal,1,3,4.0"),
+        format!("{header}1,true,,1.0,2.0,3.0,false\n")
+    );
+    assert_eq!(
+        simulate("withdrawal,1,3,6.0"),
+        format!("{header}1,false,PE_INSF,5.0,2.0,7.0,false\n")
+    );
+    assert_eq!(
+        simulate("chargeback,1,2"),
+        format!("{header}1,true,,5.0,0.0,5.0,true\n")
+    );
+    // Transaction 2 is client 1's, not client 3's.
+    assert_eq!(
+        simulate("dispute,3,2"),
+        format!("{header}3,false,RT_CLIENT,1.0,0.0,1.0,false\n")
+    );
+    assert_eq!(
+        simulate("withdrawal,2,3,1.0"),
+        format!("{header}2,false,RT_NOACC,0.0,0.0,0.0,false\n")
+    );
+    assert_eq!(
+        simulate("deposit,2,3,1.0"),
+        format!("{header}2,true,,1.0,0.0,1.0,false\n")
+    );
+    assert_eq!(
+        trp(&["query", "--state", state, "--client", "1"]),
+        "client,available,held,total,locked\n1,5.0,2.0,7.0,false\n"
+    );
+}
@@ -0,0 +1,82 @@
+//! Runs `trp process --state`, then `trp state export` and `trp state import` of the state
+//! into another directory.
+
+mod common;
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use common::{trp, TempDir};
+
+#[test]
+fn exported_state_is_queried_after_import() {
+    let dir = TempDir::new("state-export");
+    let input = dir.write_input(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,2.0\ndispute,2,2,\n",
+    );
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    let (production, local, export) = (path("production"), path("local"), path("state.trp"));
+    trp(&[
+        "process",
+        "--quiet",
+        "--state",
+        &production,
+        "--as-of",
+        "2024-06-30",
+        input.to_str().unwrap(),
+    ]);
+    let queries = |state: &str| {
+        [
+            trp(&["query", "--state", state, "--client", "2", "--history"]),
+            trp(&[
+                "query",
+                "--state",
+                state,
+                "--as-of",
+                "2024-06-30",
+                "--client",
+                "1",
+            ]),
+        ]
+        .concat()
+    };
+
+    trp(&[
+        "state",
+        "export",
+        "--quiet",
+        "--state",
+        &production,
+        &export,
+    ]);
+    trp(&["state", "import", "--quiet", "--state", &local, &export]);
+    assert_eq!(queries(&local), queries(&production));
+    let import = |args: &[&str], stdin: &[u8]| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_trp"))
+            .args(args)
+            .stdin(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        // Refused imports may exit before reading all of it.
+        let _ = child.stdin.take().unwrap().write_all(stdin);
+        child.wait().unwrap().success()
+    };
+    let exported = std::fs::read(&export).unwrap();
+    assert!(!import(
+        &["state", "import", "--state", &local, "-"],
+        &exported
+    ));
+    assert!(import(
+        &["state", "import", "--force", "--state", &local, "-"],
+        &exported
+    ));
+    assert!(!import(
+        &["state", "import", "--state", &path("truncated"), "-"],
+        &exported[..exported.len() / 2]
+    ));
+    assert!(!dir.join("truncated").exists());
+}
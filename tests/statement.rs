@@ -0,0 +1,57 @@
+//! Runs `trp statement` over event logs written by `trp process --event-log`.
+
+mod common;
+
+use common::{trp, TempDir};
+
+#[test]
+fn statement_has_running_balances_of_the_period() {
+    let dir = TempDir::new("statement");
+    let events = dir.join("events.csv");
+    let input = dir.write_input(
+        "input.csv",
+        "\
+type,client,tx,amount,timestamp
+deposit,1,1,10.0,1000
+deposit,2,2,3.0,1500
+withdrawal,1,3,4.0,2000
+withdrawal,1,4,50.0,2500
+dispute,1,1,,3000
+deposit,1,5,1.0,4000
+",
+    );
+    trp(&[
+        "process",
+        "--quiet",
+        "--event-log",
+        events.to_str().unwrap(),
+        input.to_str().unwrap(),
+    ]);
+    let statement = |extra: &[&str]| {
+        let mut args = vec!["statement", "--client", "1"];
+        args.extend_from_slice(extra);
+        args.push(events.to_str().unwrap());
+        trp(&args)
+    };
+
+    // Rows are in order of the log. Rejected withdrawal and messages of other clients are left
+    // out.
+    assert_eq!(
+        statement(&["--from", "1500", "--to", "3000"]),
+        "\
+timestamp,type,tx,amount,available,held,total,locked
+1500,opening,,,10.0,0.0,10.0,false
+2000,withdrawal,3,4.0,6.0,0.0,6.0,false
+3000,closing,,,6.0,0.0,6.0,false
+"
+    );
+    assert_eq!(
+        statement(&["--from", "2500"]),
+        "\
+timestamp,type,tx,amount,available,held,total,locked
+2500,opening,,,6.0,0.0,6.0,false
+4000,deposit,5,1.0,7.0,0.0,7.0,false
+,closing,,,7.0,0.0,7.0,false
+"
+    );
+}
@@ -0,0 +1,36 @@
+//! Runs `trp process --stream`, which writes accounts as messages lock them or move their
+//! total across a threshold, ahead of their final state.
+
+mod common;
+
+use common::{trp, TempDir};
+
+#[test]
+fn snapshots_come_ahead_of_final_state() {
+    let dir = TempDir::new("streaming");
+    let input = dir.write_input("in.csv", "type,client,tx,amount\ndeposit,1,1,50.0\ndeposit,1,2,100.0\ndispute,1,1,\nchargeback,1,1,\n");
+    let input = input.to_str().unwrap();
+    let config = dir.write_input("trp.toml", "[engine]\nwriter_batch_size = 1\n");
+    let config = config.to_str().unwrap();
+    let stream = ["--stream", "--stream-threshold", "100"];
+
+    let args = [
+        &["process", "--quiet", "--config", config][..],
+        &stream,
+        &[input],
+    ]
+    .concat();
+    assert_eq!(
+        trp(&args),
+        "client,available,held,total,locked\n\
+         1,150.0,0.0,150.0,false\n\
+         1,100.0,0.0,100.0,true\n\
+         1,100.0,0.0,100.0,true\n"
+    );
+    // Within a batch, the final state replaces snapshots.
+    let args = [&["process", "--quiet"][..], &stream, &[input]].concat();
+    assert_eq!(
+        trp(&args),
+        "client,available,held,total,locked\n1,100.0,0.0,100.0,true\n"
+    );
+}
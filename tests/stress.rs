@@ -0,0 +1,102 @@
+//! Stress test: processes a large generated input and checks aggregate invariants of the
+//! output, along with completion within a time budget. Ignored by default, run it against a
+//! release build with
+//!
+//! `cargo test --release --test stress -- --ignored --nocapture`
+//!
+//! `STRESS_ROWS` sets the number of generated rows [default: 20000000], `STRESS_BUDGET_SECS`
+//! the time processing may take [default: 300]. Every possible client id is used, ids are
+//! `u16`.
+
+mod common;
+
+use std::{
+    fs::File,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use common::TempDir;
+
+const CLIENTS: u16 = u16::MAX;
+/// Balances are `f32`, which drift from exact sums over many updates.
+const TOLERANCE: f32 = 0.01;
+
+fn env_or(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .map(|value| value.parse().expect("must be a number"))
+        .unwrap_or(default)
+}
+
+/// Count following the first `label: ` of the run summary.
+fn summary_count(summary: &str, label: &str) -> u64 {
+    summary
+        .split(&format!("{label}: "))
+        .nth(1)
+        .and_then(|rest| {
+            let digits = rest.split(|c: char| !c.is_ascii_digit()).next()?;
+            digits.parse().ok()
+        })
+        .unwrap_or_else(|| panic!("no {label} in summary:\n{summary}"))
+}
+
+#[test]
+#[ignore]
+fn large_input_is_processed_within_budget() {
+    let rows = env_or("STRESS_ROWS", 20_000_000);
+    let budget = Duration::from_secs(env_or("STRESS_BUDGET_SECS", 300));
+    let dir = TempDir::new("stress");
+    let input = dir.join("input.csv");
+
+    // Few disputes, so most clients stay unlocked and the generator does not stop early.
+    let generated = Command::new(env!("CARGO_BIN_EXE_trp"))
+        .args(["--quiet", "generate", "--consistent", "--seed", "1"])
+        .args([
+            "--rows",
+            &rows.to_string(),
+            "--clients",
+            &CLIENTS.to_string(),
+        ])
+        .args(["--dispute-ratio", "0.01", "--chargeback-ratio", "0.05"])
+        .stdout(File::create(&input).unwrap())
+        .status()
+        .unwrap();
+    assert!(generated.success());
+
+    let started = Instant::now();
+    let output = Command::new(env!("CARGO_BIN_EXE_trp"))
+        .arg("process")
+        .arg(&input)
+        .stderr(Stdio::piped())
+        .output()
+        .unwrap();
+    let elapsed = started.elapsed();
+    let summary = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{summary}");
+    println!("Processed {rows} rows in {elapsed:?}\n{summary}");
+
+    let mut accounts = 0;
+    let mut locked = 0;
+    let mut rdr = csv::Reader::from_reader(output.stdout.as_slice());
+    for row in rdr.deserialize() {
+        let (client, available, held, total, is_locked): (u16, f32, f32, f32, bool) = row.unwrap();
+        assert!(
+            (total - (available + held)).abs() <= TOLERANCE,
+            "client {client}: total {total} is not available {available} + held {held}"
+        );
+        assert!(held >= -TOLERANCE, "client {client}: held {held}");
+        accounts += 1;
+        locked += u64::from(is_locked);
+    }
+
+    // Consistent input creates every client and locks one per chargeback, rows are only
+    // rejected when float rounding makes a withdrawal overdraw.
+    assert_eq!(summary_count(&summary, "Messages"), rows);
+    assert_eq!(accounts, u64::from(CLIENTS));
+    assert_eq!(locked, summary_count(&summary, "chargeback"));
+    assert!(summary_count(&summary, "Rejects") <= rows / 10_000);
+    assert!(
+        elapsed <= budget,
+        "processing took {elapsed:?}, over budget of {budget:?}"
+    );
+}
@@ -0,0 +1,59 @@
+//! Runs `trp process --tiers --tier-rules` over clients of different tiers.
+
+mod common;
+
+use common::{normalize, trp, TempDir};
+
+#[test]
+fn withdrawals_follow_rules_of_tier() {
+    let dir = TempDir::new("tiers");
+    let input = dir.write_input(
+        "input.csv",
+        "\
+type,client,tx,amount
+deposit,1,1,100.0
+withdrawal,1,2,140.0
+withdrawal,1,3,20.0
+deposit,2,4,500.0
+withdrawal,2,5,150.0
+withdrawal,2,6,100.0
+deposit,3,7,100.0
+withdrawal,3,8,95.0
+",
+    );
+    let tiers = dir.write_input("tiers.csv", "client,tier\n1,gold\n2,basic\n");
+    let rules = dir.write_input(
+        "rules.csv",
+        "\
+tier,max_withdrawal,withdrawal_fee,overdraft
+gold,,,50
+basic,100,1.5,
+",
+    );
+    let run = |extra: &[&str]| {
+        let mut args = vec![
+            "process",
+            "--quiet",
+            "--min-balance",
+            "10,gold:0",
+            "--tiers",
+            tiers.to_str().unwrap(),
+            "--tier-rules",
+            rules.to_str().unwrap(),
+        ];
+        args.extend_from_slice(extra);
+        args.push(input.to_str().unwrap());
+        normalize(&trp(&args))
+    };
+
+    // Gold overdraws up to 50, basic withdraws at most 100 at a time and pays a fee, clients
+    // without a tier only keep the minimum balance.
+    let expected = "\
+client,available,held,total,locked
+1,-40.0,0.0,-40.0,false
+2,398.5,0.0,398.5,false
+3,100.0,0.0,100.0,false
+";
+    assert_eq!(run(&[]), expected);
+    assert_eq!(run(&["--reference"]), expected);
+}
@@ -0,0 +1,261 @@
+//! Runs `trp` over input with a timestamp column: `--extended` output, value-dated deposits,
+//! `--reorder-lateness` and `--ordering`, `--report` and `replay --until`.
+
+mod common;
+
+use common::{normalize, trp, TempDir};
+
+#[test]
+fn extended_output_has_activity_of_clients() {
+    let dir = TempDir::new("timestamps");
+    let input = dir.write_input(
+        "input.csv",
+        "\
+type,client,tx,amount,timestamp
+deposit,1,1,5.0,1000
+withdrawal,1,2,9.0,3000
+deposit,2,3,1.0,
+dispute,1,1,,2000
+",
+    );
+
+    let output = trp(&["process", "--quiet", "--extended", input.to_str().unwrap()]);
+
+    // Rejected messages are activity too, and timestamps need not be ordered.
+    assert_eq!(
+        normalize(&output),
+        "\
+client,available,held,total,locked,pending,first_activity,last_activity,applied,rejected,zombie
+1,0.0,5.0,5.0,false,0.0,1000,3000,2,1,false
+2,1.0,0.0,1.0,false,0.0,,,1,0,false
+"
+    );
+    // Output is as before without --extended.
+    assert_eq!(
+        normalize(&trp(&["process", "--quiet", input.to_str().unwrap()])),
+        "\
+client,available,held,total,locked
+1,0.0,5.0,5.0,false
+2,1.0,0.0,1.0,false
+"
+    );
+}
+
+#[test]
+fn messages_are_reordered_within_lateness() {
+    let dir = TempDir::new("reorder");
+    let dlq = dir.join("dlq.csv");
+    let events = dir.join("events.csv");
+    // Withdrawal precedes the deposit it needs in the file, but not in time. The dispute is
+    // further behind the latest timestamp than lateness allows. Accounts are still only
+    // created by deposits coming first in the file.
+    let input = dir.write_input(
+        "input.csv",
+        "\
+type,client,tx,amount,timestamp
+deposit,1,4,1.0,500
+withdrawal,1,2,3.0,1500
+deposit,1,1,5.0,1000
+deposit,1,3,1.0,9000
+dispute,1,1,,2000
+",
+    );
+
+    let output = trp(&[
+        "process",
+        "--quiet",
+        "--reorder-lateness",
+        "1000",
+        "--dlq",
+        dlq.to_str().unwrap(),
+        "--event-log",
+        events.to_str().unwrap(),
+        input.to_str().unwrap(),
+    ]);
+
+    assert_eq!(
+        normalize(&output),
+        "\
+client,available,held,total,locked
+1,4.0,0.0,4.0,false
+"
+    );
+    // Late messages are logged for audit.
+    let late: Vec<_> = std::fs::read_to_string(&events)
+        .unwrap()
+        .lines()
+        .filter(|line| line.contains(",message_late,"))
+        .map(|line| {
+            line.split(',')
+                .skip(3)
+                .take(2)
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .collect();
+    assert_eq!(late, ["1,1"]);
+    assert_eq!(
+        std::fs::read_to_string(&dlq).unwrap(),
+        format!(
+            "\
+type,client,tx,amount,reason,source,line,correlation_id,reference
+dispute,1,1,,PE_LATE,{input},6,,
+",
+            input = input.display()
+        )
+    );
+
+    // Without lateness, messages behind the latest timestamp of the client are late. The
+    // summary names the ordering the run applied messages in.
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_trp"))
+        .args(["process", "--ordering", "timestamp-merge"])
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        normalize(&String::from_utf8_lossy(&output.stdout)),
+        "\
+client,available,held,total,locked
+1,2.0,0.0,2.0,false
+"
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Ordering: timestamp-merge"));
+}
+
+#[test]
+fn report_has_totals_per_period() {
+    let dir = TempDir::new("report");
+    let report = dir.join("report.csv");
+    // Hours from 2024-01-01T00:00Z. The second withdrawal is rejected, the resolve takes no
+    // effect, and the last deposit has no timestamp.
+    let input = dir.write_input(
+        "input.csv",
+        "\
+type,client,tx,amount,timestamp
+deposit,1,1,5.0,1704067200000
+withdrawal,1,2,1.5,1704069000000
+deposit,2,3,2.0,1704070800000
+withdrawal,2,4,9.0,1704070800000
+dispute,2,3,,1704074400000
+resolve,1,1,,1704074400000
+deposit,2,5,1.0,
+",
+    );
+
+    trp(&[
+        "process",
+        "--quiet",
+        "--report",
+        report.to_str().unwrap(),
+        "--report-period",
+        "hour",
+        input.to_str().unwrap(),
+    ]);
+
+    assert_eq!(
+        std::fs::read_to_string(&report).unwrap(),
+        "\
+start,end,deposits,deposited,withdrawals,withdrawn,disputes,resolves,chargebacks,net_flow
+1704067200000,1704070800000,1,5.0,1,1.5,0,0,0,3.5
+1704070800000,1704074400000,1,2.0,0,0.0,0,0,0,2.0
+1704074400000,1704078000000,0,0.0,0,0.0,1,0,0,0.0
+"
+    );
+}
+
+#[test]
+fn value_dated_deposits_are_pending_until_due() {
+    let dir = TempDir::new("value-dated");
+    let input = dir.write_input(
+        "input.csv",
+        "\
+type,client,tx,amount,timestamp,effective_date
+deposit,1,1,5.0,1000,
+deposit,1,2,3.0,1000,2000
+withdrawal,1,3,7.0,1500,
+withdrawal,1,4,7.0,2000,
+deposit,2,5,4.0,1000,9000
+dispute,2,5,,1000,
+",
+    );
+
+    // Pending deposits can't be withdrawn nor disputed before their value date.
+    assert_eq!(
+        normalize(&trp(&[
+            "process",
+            "--quiet",
+            "--extended",
+            input.to_str().unwrap()
+        ])),
+        "\
+client,available,held,total,locked,pending,first_activity,last_activity,applied,rejected,zombie
+1,1.0,0.0,1.0,false,0.0,1000,2000,3,1,false
+2,0.0,0.0,4.0,false,4.0,1000,1000,2,0,false
+"
+    );
+    assert_eq!(
+        normalize(&trp(&["process", "--quiet", input.to_str().unwrap()])),
+        normalize(&trp(&[
+            "process",
+            "--quiet",
+            "--reference",
+            input.to_str().unwrap()
+        ]))
+    );
+}
+
+#[test]
+fn replay_rebuilds_balances_as_of_a_moment() {
+    let dir = TempDir::new("as-of");
+    let events = dir.join("events.csv");
+    let input = dir.write_input(
+        "input.csv",
+        "\
+type,client,tx,amount,timestamp
+deposit,1,1,5.0,1000
+deposit,1,2,3.0,3000
+withdrawal,1,3,4.0,2000
+",
+    );
+    trp(&[
+        "process",
+        "--quiet",
+        "--event-log",
+        events.to_str().unwrap(),
+        input.to_str().unwrap(),
+    ]);
+    let log = std::fs::read_to_string(&events).unwrap();
+    let created: Vec<&str> = log
+        .lines()
+        .filter(|line| line.contains(",account_created,"))
+        .collect();
+    assert_eq!(created.len(), 1);
+    assert!(created[0].contains(",account_created,1,1,,1000,"));
+
+    // What was available when the withdrawal was approved.
+    assert_eq!(
+        normalize(&trp(&[
+            "replay",
+            "--quiet",
+            "--until",
+            "2000",
+            events.to_str().unwrap()
+        ])),
+        "\
+client,available,held,total,locked
+1,1.0,0.0,1.0,false
+"
+    );
+    let accounts = trp::commands::replay::snapshot(&events, 1500).unwrap();
+    assert_eq!(
+        accounts,
+        vec![trp::state::AccountRecord {
+            client: 1,
+            available: 5.0,
+            held: 0.0,
+            total: 5.0,
+            locked: false,
+        }]
+    );
+}
@@ -0,0 +1,49 @@
+//! Runs `trp process --top` and checks rankings of accounts.
+
+mod common;
+
+use common::{trp, TempDir};
+
+#[test]
+fn accounts_are_ranked_by_volume_held_funds_and_rejects() {
+    let dir = TempDir::new("top");
+    let top = dir.join("top.csv");
+    let input = dir.write_input(
+        "input.csv",
+        "\
+type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,4.0
+withdrawal,1,3,40.0
+deposit,2,4,30.0
+dispute,2,4,
+deposit,3,5,5.0
+withdrawal,3,6,6.0
+withdrawal,3,7,7.0
+dispute,4,8,
+",
+    );
+
+    trp(&[
+        "process",
+        "--quiet",
+        "--top",
+        top.to_str().unwrap(),
+        "--top-n",
+        "2",
+        input.to_str().unwrap(),
+    ]);
+    // Client 4 has no account, so the router rejects its dispute. It ties with client 1 and
+    // is cut off.
+    assert_eq!(
+        std::fs::read_to_string(&top).unwrap(),
+        "\
+ranking,rank,client,value
+volume,1,2,30.0
+volume,2,1,14.0
+held,1,2,30.0
+rejects,1,3,2
+rejects,2,1,1
+"
+    );
+}
@@ -0,0 +1,32 @@
+//! Runs `trp process --two-pass` over input with reused transaction ids and forward references.
+
+mod common;
+
+use common::{normalize, trp, TempDir};
+
+#[test]
+fn first_pass_rejects_duplicates_and_forward_references() {
+    let dir = TempDir::new("two-pass");
+    let input = dir.write_input("in.csv", "type,client,tx,amount\ndispute,1,2,\ndeposit,1,1,3.0\ndeposit,1,2,1.0\ndeposit,2,1,5.0\ndeposit,2,3,1.0\n");
+    let input = input.to_str().unwrap();
+    let dlq = dir.join("dlq.csv");
+
+    assert_eq!(
+        normalize(&trp(&[
+            "process",
+            "--quiet",
+            "--two-pass",
+            "--dlq",
+            dlq.to_str().unwrap(),
+            input
+        ])),
+        "client,available,held,total,locked\n1,4.0,0.0,4.0,false\n2,1.0,0.0,1.0,false\n"
+    );
+    let dlq = std::fs::read_to_string(&dlq).unwrap();
+    let reasons: Vec<&str> = dlq
+        .lines()
+        .skip(1)
+        .map(|line| line.split(',').nth(4).unwrap())
+        .collect();
+    assert_eq!(reasons, ["PR_FWD", "PR_DUP"]);
+}
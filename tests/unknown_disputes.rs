@@ -0,0 +1,62 @@
+//! Runs `trp process` over disputes of transactions which come later or not at all, with
+//! every policy of `unknown_disputes`.
+
+mod common;
+
+use common::{normalize, trp, TempDir};
+
+#[test]
+fn unknown_disputes_follow_policy() {
+    let dir = TempDir::new("unknown-disputes");
+    let input = dir.write_input(
+        "in.csv",
+        "type,client,tx,amount\ndeposit,1,1,10.0\ndispute,1,2,\ndeposit,1,2,5.0\ndispute,1,3,\n",
+    );
+    let input = input.to_str().unwrap();
+
+    let run = |policy: &str| {
+        let config = dir.write_input(
+            &format!("{policy}.toml"),
+            format!("[engine]\nunknown_disputes = \"{policy}\"\n"),
+        );
+        let dlq = dir.join(format!("{policy}-dlq.csv"));
+        let output = normalize(&trp(&[
+            "process",
+            "--quiet",
+            "--config",
+            config.to_str().unwrap(),
+            "--dlq",
+            dlq.to_str().unwrap(),
+            input,
+        ]));
+        let rejected: Vec<String> = std::fs::read_to_string(&dlq)
+            .unwrap_or_default()
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').take(5).collect::<Vec<_>>().join(","))
+            .collect();
+        (output, rejected)
+    };
+
+    let (output, rejected) = run("ignore");
+    assert_eq!(
+        output,
+        "client,available,held,total,locked\n1,15.0,0.0,15.0,false\n"
+    );
+    assert!(rejected.is_empty());
+
+    let (output, rejected) = run("reject");
+    assert_eq!(
+        output,
+        "client,available,held,total,locked\n1,15.0,0.0,15.0,false\n"
+    );
+    assert_eq!(rejected, ["dispute,1,2,,PE_UNKTX", "dispute,1,3,,PE_UNKTX"]);
+
+    // Dispute of transaction 2 is applied once the deposit comes, the one of 3 never is.
+    let (output, rejected) = run("retry");
+    assert_eq!(
+        output,
+        "client,available,held,total,locked\n1,10.0,5.0,15.0,false\n"
+    );
+    assert_eq!(rejected, ["dispute,1,3,,PE_UNKTX"]);
+}
@@ -0,0 +1,38 @@
+//! Processing core of trp, built from the sources of the [`engine`] and [`message`] modules
+//! alone, so it compiles for `wasm32` targets: `cargo build --target wasm32-unknown-unknown`.
+//! Neither module may depend on anything but `std`.
+
+#[path = "../../src/message.rs"]
+pub mod message;
+
+#[path = "../../src/engine.rs"]
+pub mod engine;
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        engine::{Engine, Rejection},
+        message::Message,
+    };
+
+    #[test]
+    fn core_builds_without_runtime() {
+        let mut engine = Engine::new(None);
+        let deposit = Message::Deposit {
+            client: 1,
+            tx: 1,
+            amount: 2.0,
+            timestamp: None,
+            effective_date: None,
+        };
+        let withdraw = Message::Withdraw {
+            client: 1,
+            tx: 2,
+            amount: 3.0,
+            timestamp: None,
+        };
+        engine.apply(&deposit).unwrap();
+        assert_eq!(engine.apply(&withdraw), Err(Rejection::InsufficientFunds));
+        assert_eq!(engine.account(1).map(|book| book.total), Some(2.0));
+    }
+}